@@ -0,0 +1,59 @@
+//! Parsing and dispatch throughput over recorded feed messages.
+//!
+//! Run with `cargo bench`, add `--features rust_decimal` to compare the decimal backends.
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use coinbase_client::order_book::OrderBooks;
+use coinbase_client::web_socket::{dispatch, parse_response, BorrowedMessages, MessageHeader};
+
+const FEED: &str = include_str!("data/feed.jsonl");
+const STATUS: &str = include_str!("data/status.json");
+
+/// Recorded messages, one for every message type, paired with their type.
+fn messages() -> Vec<(&'static str, &'static str)> {
+  FEED.lines()
+    .chain(std::iter::once(STATUS.trim_end()))
+    .map(|json| {
+      let header: MessageHeader = serde_json::from_str(json).unwrap();
+      (header.kind, json)
+    })
+    .collect()
+}
+
+fn parse(c: &mut Criterion) {
+  let mut group = c.benchmark_group("parse");
+  for (kind, json) in messages() {
+    group.throughput(Throughput::Bytes(json.len() as u64));
+    group.bench_with_input(BenchmarkId::new("owned", kind), json, |b, json| {
+      b.iter(|| parse_response(black_box(json)).unwrap())
+    });
+    group.bench_with_input(BenchmarkId::new("header", kind), json, |b, json| {
+      b.iter(|| serde_json::from_str::<MessageHeader>(black_box(json)).unwrap().kind)
+    });
+    if !matches!(serde_json::from_str(json).unwrap(), BorrowedMessages::Other) {
+      group.bench_with_input(BenchmarkId::new("borrowed", kind), json, |b, json| {
+        b.iter(|| serde_json::from_str::<BorrowedMessages>(black_box(json)).unwrap())
+      });
+    }
+  }
+  group.finish();
+}
+
+fn parse_and_dispatch(c: &mut Criterion) {
+  let feed: Vec<&str> = FEED.lines().collect();
+  let mut group = c.benchmark_group("dispatch");
+  group.throughput(Throughput::Elements(feed.len() as u64));
+  group.bench_function("order_books", |b| {
+    let mut books = OrderBooks::new();
+    b.iter(|| {
+      for json in feed.iter() {
+        let message = parse_response(black_box(json)).unwrap();
+        dispatch(&mut books, &message).unwrap();
+      }
+    })
+  });
+  group.finish();
+}
+
+criterion_group!(benches, parse, parse_and_dispatch);
+criterion_main!(benches);
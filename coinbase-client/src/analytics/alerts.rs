@@ -0,0 +1,312 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use num_traits::ToPrimitive;
+use serde::{Deserialize, Serialize};
+
+use crate::web_socket::response::{MatchResponse, TickerResponse};
+use crate::web_socket::{CoinBaseWebSocketMessageHandler, Terminate};
+
+const ALERTS_ID: &str = "Alerts";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Condition {
+  /// Trade price moved by at least `percent` in either direction within `window`.
+  PriceChange { percent: f64, window: Duration },
+  /// Spread of the ticker wider than `bps` of the mid price.
+  Spread { bps: f64 },
+  /// Traded volume within `window` at least `factor` times the average volume of a window
+  /// over the preceding `baseline`.
+  VolumeSpike { factor: f64, window: Duration, baseline: Duration },
+}
+
+impl Condition {
+  /// How far back trades must be kept to evaluate the condition.
+  fn history(&self) -> Duration {
+    match self {
+      Condition::PriceChange { window, .. } => *window,
+      Condition::Spread { .. } => Duration::from_secs(0),
+      Condition::VolumeSpike { window, baseline, .. } => *window + *baseline,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AlertRule {
+  pub name: String,
+  /// Product the rule applies to, all products when `None`.
+  pub product_id: Option<String>,
+  pub condition: Condition,
+  /// Minimal time between two alerts of the rule for the same product.
+  pub cooldown: Duration,
+}
+
+impl AlertRule {
+  pub fn new(name: &str, condition: Condition) -> Self {
+    AlertRule { name: name.into(), product_id: None, condition, cooldown: Duration::from_secs(60) }
+  }
+
+  pub fn product(mut self, product_id: &str) -> Self {
+    self.product_id = Some(product_id.into());
+    self
+  }
+
+  /// One minute by default.
+  pub fn cooldown(mut self, cooldown: Duration) -> Self {
+    self.cooldown = cooldown;
+    self
+  }
+
+  fn applies_to(&self, product_id: &str) -> bool {
+    self.product_id.as_deref().is_none_or(|rule_product| rule_product == product_id)
+  }
+}
+
+/// Fired rule with the observed value, e.g. the price change in percent, and the threshold
+/// it crossed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Alert {
+  pub rule: String,
+  pub product_id: String,
+  pub time: DateTime<Utc>,
+  pub value: f64,
+  pub threshold: f64,
+}
+
+pub trait AlertSink {
+  fn on_alert(&mut self, alert: &Alert) -> Result<(), Terminate>;
+}
+
+impl<F: FnMut(&Alert) -> Result<(), Terminate>> AlertSink for F {
+  fn on_alert(&mut self, alert: &Alert) -> Result<(), Terminate> {
+    self(alert)
+  }
+}
+
+/// Posts every alert as JSON to the URL. Failed requests are logged and the alert is lost,
+/// the feed is never held back by the endpoint.
+pub struct Webhook {
+  url: String,
+  agent: ureq::Agent,
+}
+
+impl Webhook {
+  pub fn new(url: &str) -> Self {
+    let agent = ureq::AgentBuilder::new().timeout(Duration::from_secs(5)).build();
+    Webhook { url: url.into(), agent }
+  }
+}
+
+impl AlertSink for Webhook {
+  fn on_alert(&mut self, alert: &Alert) -> Result<(), Terminate> {
+    let body = serde_json::to_string(alert).expect("Alerts are always serializable.");
+    let response = self.agent.post(self.url.as_str())
+      .set("Content-Type", "application/json")
+      .send_string(body.as_str());
+    if let Err(err) = response {
+      tracing::warn!(target: ALERTS_ID, "Could not post alert {} for {}: {}", alert.rule, alert.product_id, err);
+    }
+    Ok(())
+  }
+}
+
+#[derive(Default)]
+struct ProductHistory {
+  // Trades within the longest history of the rules, oldest first.
+  trades: VecDeque<(DateTime<Utc>, f64, f64)>,
+  first_trade: Option<DateTime<Utc>>,
+}
+
+impl ProductHistory {
+  fn volume_between(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> f64 {
+    self.trades.iter().filter(|(time, _, _)| *time > from && *time <= to).map(|(_, _, size)| size).sum()
+  }
+
+  /// Oldest trade price within the window ending at `time`.
+  fn price_at(&self, since: DateTime<Utc>) -> Option<f64> {
+    self.trades.iter().find(|(time, _, _)| *time >= since).map(|(_, price, _)| *price)
+  }
+}
+
+/// Evaluates alert rules on trades from the `matches` channel and tickers, using message
+/// times, and keeps enough trade history to rank the top movers of the feed.
+pub struct AlertHandler<S: AlertSink> {
+  rules: Vec<AlertRule>,
+  retain: Duration,
+  products: HashMap<String, ProductHistory>,
+  // Time of the last alert per rule index and product.
+  fired: HashMap<(usize, String), DateTime<Utc>>,
+  sink: S,
+}
+
+impl<S: AlertSink> AlertHandler<S> {
+  pub fn new(rules: Vec<AlertRule>, sink: S) -> Self {
+    let retain = rules.iter().map(|rule| rule.condition.history()).max().unwrap_or_default();
+    AlertHandler { rules, retain, products: HashMap::new(), fired: HashMap::new(), sink }
+  }
+
+  /// Keeps trades at least this long, for `top_movers` over windows longer than the rules need.
+  pub fn retain(mut self, retain: Duration) -> Self {
+    self.retain = self.retain.max(retain);
+    self
+  }
+
+  /// Products with the largest absolute price change in percent within `window` of their last
+  /// trade, largest first. Window is capped by the retained history.
+  pub fn top_movers(&self, n: usize, window: Duration) -> Vec<(String, f64)> {
+    let mut movers: Vec<(String, f64)> = self.products.iter()
+      .filter_map(|(product_id, history)| {
+        let (last_time, last_price, _) = *history.trades.back()?;
+        let start = history.price_at(last_time - to_chrono(window))?;
+        Some((product_id.clone(), (last_price - start) / start * 100.0))
+      })
+      .collect();
+    movers.sort_by(|(_, a), (_, b)| b.abs().total_cmp(&a.abs()));
+    movers.truncate(n);
+    movers
+  }
+
+  fn fire(&mut self, index: usize, product_id: &str, time: DateTime<Utc>, value: f64, threshold: f64) -> Result<(), Terminate> {
+    let rule = &self.rules[index];
+    let key = (index, product_id.to_string());
+    if let Some(last) = self.fired.get(&key) {
+      if time < *last + to_chrono(rule.cooldown) {
+        return Ok(());
+      }
+    }
+    let alert = Alert { rule: rule.name.clone(), product_id: product_id.into(), time, value, threshold };
+    self.fired.insert(key, time);
+    self.sink.on_alert(&alert)
+  }
+
+  fn add_trade(&mut self, product_id: &str, time: DateTime<Utc>, price: f64, size: f64) -> Result<(), Terminate> {
+    let retain = to_chrono(self.retain);
+    let history = self.products.entry(product_id.into()).or_default();
+    history.first_trade.get_or_insert(time);
+    history.trades.push_back((time, price, size));
+    while history.trades.front().is_some_and(|(oldest, _, _)| *oldest < time - retain) {
+      history.trades.pop_front();
+    }
+
+    let mut fired = Vec::new();
+    for (index, rule) in self.rules.iter().enumerate().filter(|(_, rule)| rule.applies_to(product_id)) {
+      match rule.condition {
+        Condition::PriceChange { percent, window } => {
+          if let Some(start) = history.price_at(time - to_chrono(window)) {
+            let change = (price - start) / start * 100.0;
+            if change.abs() >= percent {
+              fired.push((index, change, percent));
+            }
+          }
+        }
+        Condition::VolumeSpike { factor, window, baseline } => {
+          let (window, baseline) = (to_chrono(window), to_chrono(baseline));
+          // No alerts until the whole baseline was observed.
+          if history.first_trade.is_none_or(|first| first > time - window - baseline) {
+            continue;
+          }
+          let volume = history.volume_between(time - window, time);
+          let average = history.volume_between(time - window - baseline, time - window)
+            * window.num_microseconds().unwrap_or(1) as f64 / baseline.num_microseconds().unwrap_or(1) as f64;
+          if average > 0.0 && volume >= factor * average {
+            fired.push((index, volume / average, factor));
+          }
+        }
+        Condition::Spread { .. } => {}
+      }
+    }
+    for (index, value, threshold) in fired {
+      self.fire(index, product_id, time, value, threshold)?;
+    }
+    Ok(())
+  }
+}
+
+// Capped at ten years, so subtracting it from message times never overflows.
+fn to_chrono(duration: Duration) -> chrono::Duration {
+  chrono::Duration::from_std(duration.min(Duration::from_secs(10 * 365 * 86_400))).unwrap()
+}
+
+impl<S: AlertSink> CoinBaseWebSocketMessageHandler for AlertHandler<S> {
+  fn on_ticker(&mut self, resp: &TickerResponse) -> Result<(), Terminate> {
+    let bid = resp.best_bid.to_f64().unwrap_or_default();
+    let ask = resp.best_ask.to_f64().unwrap_or_default();
+    if bid <= 0.0 || ask <= 0.0 {
+      return Ok(());
+    }
+    let spread_bps = (ask - bid) / ((ask + bid) / 2.0) * 10_000.0;
+    let fired: Vec<(usize, f64)> = self.rules.iter().enumerate()
+      .filter(|(_, rule)| rule.applies_to(&resp.product_id))
+      .filter_map(|(index, rule)| match rule.condition {
+        Condition::Spread { bps } if spread_bps > bps => Some((index, bps)),
+        _ => None,
+      })
+      .collect();
+    for (index, threshold) in fired {
+      self.fire(index, &resp.product_id, resp.time, spread_bps, threshold)?;
+    }
+    Ok(())
+  }
+
+  fn on_match(&mut self, resp: &MatchResponse) -> Result<(), Terminate> {
+    let price = resp.price.to_f64().unwrap_or_default();
+    let size = resp.size.to_f64().unwrap_or_default();
+    if price <= 0.0 {
+      return Ok(());
+    }
+    self.add_trade(&resp.product_id, resp.time, price, size)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::time::Duration;
+
+  use chrono::{DateTime, Utc};
+
+  use super::{Alert, AlertHandler, AlertRule, Condition};
+  use crate::web_socket::response::MatchResponse;
+  use crate::web_socket::CoinBaseWebSocketMessageHandler;
+
+  fn trade(product_id: &str, seconds: i64, price: &str, size: &str) -> MatchResponse {
+    let time = "2020-08-31T15:00:00Z".parse::<DateTime<Utc>>().unwrap() + chrono::Duration::seconds(seconds);
+    serde_json::from_str(&format!(r#"{{
+      "trade_id": 1, "maker_order_id": "125f1d3d-3100-41ce-9341-fc330bdcebcb",
+      "taker_order_id": "5b0a9f2d-3388-4fd4-a106-b96b1e6d302f", "side": "buy", "size": "{}",
+      "price": "{}", "product_id": "{}", "sequence": 1, "time": "{}"
+    }}"#, size, price, product_id, time.to_rfc3339())).unwrap()
+  }
+
+  #[test]
+  fn fire_rules_and_rank_movers() {
+    let mut alerts: Vec<Alert> = Vec::new();
+    let rules = vec![
+      AlertRule::new("jump", Condition::PriceChange { percent: 5.0, window: Duration::from_secs(60) }).product("BTC-USD"),
+      AlertRule::new("volume", Condition::VolumeSpike { factor: 3.0, window: Duration::from_secs(10), baseline: Duration::from_secs(60) }),
+    ];
+    let mut handler = AlertHandler::new(rules, |alert: &Alert| {
+      alerts.push(alert.clone());
+      Ok(())
+    }).retain(Duration::from_secs(300));
+
+    for second in 0..=70 {
+      handler.on_match(&trade("ETH-USD", second, "400", "1")).unwrap();
+    }
+    handler.on_match(&trade("BTC-USD", 0, "10000", "1")).unwrap();
+    handler.on_match(&trade("BTC-USD", 30, "10400", "1")).unwrap();
+    handler.on_match(&trade("BTC-USD", 50, "10600", "1")).unwrap();
+    // Cooldown of a minute holds back the second alert.
+    handler.on_match(&trade("BTC-USD", 55, "10700", "1")).unwrap();
+    handler.on_match(&trade("ETH-USD", 71, "404", "40")).unwrap();
+    let movers = handler.top_movers(1, Duration::from_secs(120));
+    drop(handler);
+
+    assert_eq!(alerts.iter().map(|alert| (alert.rule.as_str(), alert.product_id.as_str())).collect::<Vec<_>>(),
+               vec![("jump", "BTC-USD"), ("volume", "ETH-USD")]);
+    assert!((alerts[0].value - 6.0).abs() < 1e-9);
+    assert_eq!(movers.len(), 1);
+    assert_eq!(movers[0].0, "BTC-USD");
+    assert!((movers[0].1 - 7.0).abs() < 1e-9);
+  }
+}
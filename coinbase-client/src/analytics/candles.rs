@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use chrono::{DateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::decimal::Decimal;
+use crate::web_socket::response::{HeartBeatResponse, MatchResponse, TickerResponse};
+use crate::web_socket::{CoinBaseWebSocketMessageHandler, Terminate};
+
+/// OHLCV bar of a product over `[start, start + interval)`, with the spread at the last ticker
+/// received in that period.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct Candle {
+  pub product_id: String,
+  pub start: DateTime<Utc>,
+  pub open: Decimal,
+  pub high: Decimal,
+  pub low: Decimal,
+  pub close: Decimal,
+  pub volume: Decimal,
+  pub trades: u64,
+  pub spread: Option<Decimal>,
+}
+
+impl Candle {
+  fn new(product_id: &str, start: DateTime<Utc>, price: Decimal, size: Decimal, spread: Option<Decimal>) -> Self {
+    Candle {
+      product_id: product_id.into(),
+      start,
+      open: price.clone(),
+      high: price.clone(),
+      low: price.clone(),
+      close: price,
+      volume: size,
+      trades: 1,
+      spread,
+    }
+  }
+
+  fn add_trade(&mut self, price: Decimal, size: Decimal) {
+    if price > self.high {
+      self.high = price.clone();
+    }
+    if price < self.low {
+      self.low = price.clone();
+    }
+    self.close = price;
+    self.volume += size;
+    self.trades += 1;
+  }
+}
+
+pub trait CandleSink {
+  fn on_candle(&mut self, candle: &Candle) -> Result<(), Terminate>;
+}
+
+impl<F: FnMut(&Candle) -> Result<(), Terminate>> CandleSink for F {
+  fn on_candle(&mut self, candle: &Candle) -> Result<(), Terminate> {
+    self(candle)
+  }
+}
+
+/// Aggregates trades from the `matches` channel into candles aligned to wall-clock multiples
+/// of `interval` (e.g. every full minute), using the message times.
+///
+/// A candle is emitted once a message of the product with a time past its end arrives, subscribe
+/// to `heartbeat` to close candles of quiet products on time. Periods without trades produce no
+/// candle. Spreads come from the `ticker` channel when subscribed.
+pub struct CandleAggregator<S: CandleSink> {
+  interval_micros: i64,
+  candles: HashMap<String, Candle>,
+  spreads: HashMap<String, Decimal>,
+  sink: S,
+}
+
+impl<S: CandleSink> CandleAggregator<S> {
+  pub fn new(interval: Duration, sink: S) -> Self {
+    let interval_micros = (interval.as_micros() as i64).max(1);
+    CandleAggregator { interval_micros, candles: HashMap::new(), spreads: HashMap::new(), sink }
+  }
+
+  /// Candle that is still being built for the product.
+  pub fn current(&self, product_id: &str) -> Option<&Candle> {
+    self.candles.get(product_id)
+  }
+
+  fn candle_start(&self, time: DateTime<Utc>) -> DateTime<Utc> {
+    let micros = time.timestamp_nanos_opt().unwrap_or_default() / 1_000;
+    let start = micros - micros.rem_euclid(self.interval_micros);
+    Utc.timestamp_opt(start.div_euclid(1_000_000), (start.rem_euclid(1_000_000) * 1_000) as u32).unwrap()
+  }
+
+  /// Emits the current candle of the product if `time` is past its end.
+  fn roll(&mut self, product_id: &str, time: DateTime<Utc>) -> Result<(), Terminate> {
+    let start = self.candle_start(time);
+    let finished = self.candles.get(product_id).map(|candle| candle.start < start).unwrap_or(false);
+    if finished {
+      let candle = self.candles.remove(product_id).unwrap();
+      self.sink.on_candle(&candle)?;
+    }
+    Ok(())
+  }
+
+  fn add_trade(&mut self, product_id: &str, time: DateTime<Utc>, price: &Decimal, size: &Decimal) -> Result<(), Terminate> {
+    self.roll(product_id, time)?;
+    let start = self.candle_start(time);
+    match self.candles.get_mut(product_id) {
+      // Late trade of an already emitted candle is dropped.
+      Some(candle) if candle.start > start => {}
+      Some(candle) => candle.add_trade(price.clone(), size.clone()),
+      None => {
+        let spread = self.spreads.get(product_id).cloned();
+        let candle = Candle::new(product_id, start, price.clone(), size.clone(), spread);
+        self.candles.insert(product_id.into(), candle);
+      }
+    }
+    Ok(())
+  }
+
+  /// Emits all candles that are still being built.
+  pub fn flush(&mut self) -> Result<(), Terminate> {
+    for (_, candle) in self.candles.drain() {
+      self.sink.on_candle(&candle)?;
+    }
+    Ok(())
+  }
+}
+
+impl<S: CandleSink> CoinBaseWebSocketMessageHandler for CandleAggregator<S> {
+  fn on_heartbeat(&mut self, resp: &HeartBeatResponse) -> Result<(), Terminate> {
+    self.roll(&resp.product_id, resp.time)
+  }
+
+  fn on_ticker(&mut self, resp: &TickerResponse) -> Result<(), Terminate> {
+    self.roll(&resp.product_id, resp.time)?;
+    let spread = &resp.best_ask - &resp.best_bid;
+    if let Some(candle) = self.candles.get_mut(&resp.product_id) {
+      candle.spread = Some(spread.clone());
+    }
+    self.spreads.insert(resp.product_id.clone(), spread);
+    Ok(())
+  }
+
+  fn on_match(&mut self, resp: &MatchResponse) -> Result<(), Terminate> {
+    self.add_trade(&resp.product_id, resp.time, &resp.price, &resp.size)
+  }
+
+  fn close(&mut self) -> Result<(), Terminate> {
+    self.flush()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::time::Duration;
+
+  use super::{Candle, CandleAggregator};
+  use crate::web_socket::response::MatchResponse;
+  use crate::web_socket::CoinBaseWebSocketMessageHandler;
+
+  fn trade(time: &str, price: &str, size: &str) -> Result<MatchResponse, serde_json::error::Error> {
+    serde_json::from_str(&format!(r#"{{
+      "trade_id": 62995921, "maker_order_id": "125f1d3d-3100-41ce-9341-fc330bdcebcb",
+      "taker_order_id": "5b0a9f2d-3388-4fd4-a106-b96b1e6d302f", "side": "buy", "size": "{}",
+      "price": "{}", "product_id": "ETH-USD", "sequence": 10182385681, "time": "{}"
+    }}"#, size, price, time))
+  }
+
+  #[test]
+  fn aggregate_minute_candles() -> Result<(), serde_json::error::Error> {
+    let mut candles: Vec<Candle> = Vec::new();
+    let mut aggregator = CandleAggregator::new(Duration::from_secs(60), |candle: &Candle| {
+      candles.push(candle.clone());
+      Ok(())
+    });
+    aggregator.on_match(&trade("2020-08-31T15:05:14.336755Z", "434.19", "1.9")?).unwrap();
+    aggregator.on_match(&trade("2020-08-31T15:05:40.000000Z", "435.00", "0.1")?).unwrap();
+    aggregator.on_match(&trade("2020-08-31T15:05:59.999999Z", "433.50", "1")?).unwrap();
+    aggregator.on_match(&trade("2020-08-31T15:06:00.000000Z", "433.00", "2")?).unwrap();
+    assert_eq!(aggregator.current("ETH-USD").unwrap().start.to_rfc3339(), "2020-08-31T15:06:00+00:00");
+    drop(aggregator);
+
+    assert_eq!(candles.len(), 1);
+    let candle = &candles[0];
+    assert_eq!(candle.start.to_rfc3339(), "2020-08-31T15:05:00+00:00");
+    assert_eq!(candle.open, "434.19".parse().unwrap());
+    assert_eq!(candle.high, "435.00".parse().unwrap());
+    assert_eq!(candle.low, "433.50".parse().unwrap());
+    assert_eq!(candle.close, "433.50".parse().unwrap());
+    assert_eq!(candle.volume, "3.0".parse().unwrap());
+    assert_eq!(candle.trades, 3);
+    Ok(())
+  }
+}
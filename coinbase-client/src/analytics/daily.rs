@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+
+use crate::decimal::{Decimal, Zero};
+use crate::web_socket::response::{
+  ChangeResponse, DoneResponse, HeartBeatResponse, L2UpdateResponse, MatchResponse, OpenResponse, ReceivedResponse,
+  SnapshotResponse, TickerResponse,
+};
+use crate::web_socket::{CoinBaseWebSocketMessageHandler, Terminate};
+
+/// Trading statistics of a product over one day. Prices are `None` on days without trades.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct DailySummary {
+  pub product_id: String,
+  pub date: NaiveDate,
+  pub open: Option<Decimal>,
+  pub high: Option<Decimal>,
+  pub low: Option<Decimal>,
+  pub close: Option<Decimal>,
+  pub volume: Decimal,
+  /// Volume weighted average price of the day's trades.
+  pub vwap: Option<Decimal>,
+  pub trades: u64,
+  /// Messages received for the product, heartbeats included.
+  pub messages: u64,
+}
+
+pub trait DailySummarySink {
+  fn on_daily_summary(&mut self, summary: &DailySummary) -> Result<(), Terminate>;
+}
+
+impl<F: FnMut(&DailySummary) -> Result<(), Terminate>> DailySummarySink for F {
+  fn on_daily_summary(&mut self, summary: &DailySummary) -> Result<(), Terminate> {
+    self(summary)
+  }
+}
+
+#[derive(Default, Clone)]
+struct DayStats {
+  open: Option<Decimal>,
+  high: Option<Decimal>,
+  low: Option<Decimal>,
+  close: Option<Decimal>,
+  volume: Decimal,
+  notional: Decimal,
+  trades: u64,
+  messages: u64,
+}
+
+impl DayStats {
+  fn add_trade(&mut self, price: &Decimal, size: &Decimal) {
+    if self.open.is_none() {
+      self.open = Some(price.clone());
+    }
+    if self.high.as_ref().is_none_or(|high| price > high) {
+      self.high = Some(price.clone());
+    }
+    if self.low.as_ref().is_none_or(|low| price < low) {
+      self.low = Some(price.clone());
+    }
+    self.close = Some(price.clone());
+    self.volume += size.clone();
+    self.notional += price * size;
+    self.trades += 1;
+  }
+
+  fn summary(self, product_id: String, date: NaiveDate) -> DailySummary {
+    let vwap = if Zero::is_zero(&self.volume) { None } else { Some(&self.notional / self.volume.clone()) };
+    DailySummary {
+      product_id,
+      date,
+      open: self.open,
+      high: self.high,
+      low: self.low,
+      close: self.close,
+      volume: self.volume,
+      vwap,
+      trades: self.trades,
+      messages: self.messages,
+    }
+  }
+}
+
+/// Collects the trades (from the `matches` channel) and message counts of every product and
+/// emits a `DailySummary` per product when the client reports the end of the day, see
+/// `CoinbaseWebSocketClient::day_rollover`.
+pub struct DailySummaryHandler<S: DailySummarySink> {
+  products: HashMap<String, DayStats>,
+  sink: S,
+}
+
+impl<S: DailySummarySink> DailySummaryHandler<S> {
+  pub fn new(sink: S) -> Self {
+    DailySummaryHandler { products: HashMap::new(), sink }
+  }
+
+  /// Summary of the day so far.
+  pub fn current(&self, product_id: &str, date: NaiveDate) -> Option<DailySummary> {
+    let stats = self.products.get(product_id)?;
+    Some(stats.clone().summary(product_id.into(), date))
+  }
+
+  fn count(&mut self, product_id: &str) -> &mut DayStats {
+    if !self.products.contains_key(product_id) {
+      self.products.insert(product_id.into(), DayStats::default());
+    }
+    let stats = self.products.get_mut(product_id).unwrap();
+    stats.messages += 1;
+    stats
+  }
+}
+
+impl<S: DailySummarySink> CoinBaseWebSocketMessageHandler for DailySummaryHandler<S> {
+  fn on_heartbeat(&mut self, resp: &HeartBeatResponse) -> Result<(), Terminate> {
+    self.count(&resp.product_id);
+    Ok(())
+  }
+
+  fn on_ticker(&mut self, resp: &TickerResponse) -> Result<(), Terminate> {
+    self.count(&resp.product_id);
+    Ok(())
+  }
+
+  fn on_snapshot(&mut self, resp: &SnapshotResponse) -> Result<(), Terminate> {
+    self.count(&resp.product_id);
+    Ok(())
+  }
+
+  fn on_l2_update(&mut self, resp: &L2UpdateResponse) -> Result<(), Terminate> {
+    self.count(&resp.product_id);
+    Ok(())
+  }
+
+  fn on_match(&mut self, resp: &MatchResponse) -> Result<(), Terminate> {
+    self.count(&resp.product_id).add_trade(&resp.price, &resp.size);
+    Ok(())
+  }
+
+  fn on_received(&mut self, resp: &ReceivedResponse) -> Result<(), Terminate> {
+    self.count(&resp.product_id);
+    Ok(())
+  }
+
+  fn on_open(&mut self, resp: &OpenResponse) -> Result<(), Terminate> {
+    self.count(&resp.product_id);
+    Ok(())
+  }
+
+  fn on_change(&mut self, resp: &ChangeResponse) -> Result<(), Terminate> {
+    self.count(&resp.product_id);
+    Ok(())
+  }
+
+  fn on_done(&mut self, resp: &DoneResponse) -> Result<(), Terminate> {
+    self.count(&resp.product_id);
+    Ok(())
+  }
+
+  fn on_day_rollover(&mut self, date: NaiveDate) -> Result<(), Terminate> {
+    let mut products: Vec<_> = self.products.drain().collect();
+    products.sort_by(|(a, _), (b, _)| a.cmp(b));
+    for (product_id, stats) in products {
+      self.sink.on_daily_summary(&stats.summary(product_id, date))?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use chrono::NaiveDate;
+
+  use super::{DailySummary, DailySummaryHandler};
+  use crate::web_socket::{dispatch, CoinBaseWebSocketMessageHandler, ResponseMessages};
+
+  #[test]
+  fn summarize_day_on_rollover() -> Result<(), serde_json::error::Error> {
+    let messages = [
+      r#"{"type":"heartbeat","last_trade_id":1,"product_id":"BTC-USD","sequence":1,"time":"2020-08-31T15:15:01.044966Z"}"#,
+      r#"{"type":"match","trade_id":2,"maker_order_id":"68e6a28f-ae28-4788-8d4f-5ab4e5e5ae08","taker_order_id":"1aa8fb1d-4d2d-4b8e-9a09-3e5e55bb1a4a",
+        "side":"sell","size":"1","price":"100","product_id":"BTC-USD","sequence":2,"time":"2020-08-31T15:15:02Z"}"#,
+      r#"{"type":"match","trade_id":3,"maker_order_id":"68e6a28f-ae28-4788-8d4f-5ab4e5e5ae08","taker_order_id":"1aa8fb1d-4d2d-4b8e-9a09-3e5e55bb1a4a",
+        "side":"buy","size":"3","price":"104","product_id":"BTC-USD","sequence":3,"time":"2020-08-31T15:15:03Z"}"#,
+    ];
+    let mut summaries: Vec<DailySummary> = Vec::new();
+    let mut handler = DailySummaryHandler::new(|summary: &DailySummary| {
+      summaries.push(summary.clone());
+      Ok(())
+    });
+    for message in messages.iter() {
+      dispatch(&mut handler, &serde_json::from_str::<ResponseMessages>(message)?).unwrap();
+    }
+    let date = NaiveDate::from_ymd_opt(2020, 8, 31).unwrap();
+    handler.on_day_rollover(date).unwrap();
+    // The next day starts empty.
+    handler.on_day_rollover(date.succ_opt().unwrap()).unwrap();
+    drop(handler);
+
+    assert_eq!(summaries.len(), 1);
+    let summary = &summaries[0];
+    assert_eq!((summary.date, summary.trades, summary.messages), (date, 2, 3));
+    assert_eq!(summary.high, Some("104".parse().unwrap()));
+    assert_eq!(summary.low, Some("100".parse().unwrap()));
+    assert_eq!(summary.vwap, Some("103".parse().unwrap()));
+    Ok(())
+  }
+}
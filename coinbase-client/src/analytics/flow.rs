@@ -0,0 +1,207 @@
+use std::collections::{HashMap, VecDeque};
+
+use chrono::{DateTime, Utc};
+use num_traits::ToPrimitive;
+use serde::{Deserialize, Serialize};
+
+use crate::decimal::{Decimal, Zero};
+use crate::web_socket::response::{MatchResponse, Side};
+use crate::web_socket::{CoinBaseWebSocketMessageHandler, Terminate};
+
+/// Order flow of a product over the last completed volume buckets.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OrderFlowMetrics {
+  pub product_id: String,
+  /// Time of the trade that completed the last bucket.
+  pub time: DateTime<Utc>,
+  /// Number of buckets the metrics are computed over, at most the configured window.
+  pub buckets: usize,
+  /// Volume bought and sold by takers within the buckets.
+  pub buy_volume: Decimal,
+  pub sell_volume: Decimal,
+  /// Taker buy minus taker sell volume of all trades seen so far.
+  pub signed_volume: Decimal,
+  /// `(buy - sell) / (buy + sell)` within the buckets, in `[-1, 1]`.
+  pub imbalance: f64,
+  /// Volume-synchronized probability of informed trading, mean absolute imbalance of the
+  /// buckets relative to the bucket volume. `None` until the window is full.
+  pub vpin: Option<f64>,
+}
+
+pub trait OrderFlowSink {
+  fn on_order_flow(&mut self, metrics: &OrderFlowMetrics) -> Result<(), Terminate>;
+}
+
+impl<F: FnMut(&OrderFlowMetrics) -> Result<(), Terminate>> OrderFlowSink for F {
+  fn on_order_flow(&mut self, metrics: &OrderFlowMetrics) -> Result<(), Terminate> {
+    self(metrics)
+  }
+}
+
+#[derive(Clone)]
+struct Bucket {
+  buy: Decimal,
+  sell: Decimal,
+}
+
+impl Bucket {
+  fn empty() -> Self {
+    Bucket { buy: Decimal::zero(), sell: Decimal::zero() }
+  }
+
+  fn volume(&self) -> Decimal {
+    &self.buy + &self.sell
+  }
+}
+
+struct ProductFlow {
+  current: Bucket,
+  completed: VecDeque<Bucket>,
+  signed_volume: Decimal,
+  last: Option<OrderFlowMetrics>,
+}
+
+/// Splits trades from the `matches` channel into buckets of equal traded volume and computes
+/// the signed volume imbalance and VPIN over the last `window` buckets. Trades are classified
+/// by the taker side, which is opposite to the maker `side` of the match. A trade larger than
+/// the room left in a bucket is split over the following buckets.
+///
+/// Metrics are emitted every time a bucket is completed, and the last ones stay available
+/// through `metrics`.
+pub struct OrderFlowHandler<S: OrderFlowSink> {
+  bucket_volume: Decimal,
+  window: usize,
+  products: HashMap<String, ProductFlow>,
+  sink: S,
+}
+
+impl<S: OrderFlowSink> OrderFlowHandler<S> {
+  /// `bucket_volume` is in the base currency, e.g. a fiftieth of the average daily volume
+  /// with a `window` of 50 buckets.
+  pub fn new(bucket_volume: Decimal, window: usize, sink: S) -> Self {
+    OrderFlowHandler { bucket_volume, window: window.max(1), products: HashMap::new(), sink }
+  }
+
+  /// Metrics as of the last completed bucket of the product.
+  pub fn metrics(&self, product_id: &str) -> Option<&OrderFlowMetrics> {
+    self.products.get(product_id)?.last.as_ref()
+  }
+
+  fn add_trade(&mut self, product_id: &str, time: DateTime<Utc>, taker_side: Side, size: &Decimal) -> Result<(), Terminate> {
+    if Zero::is_zero(&self.bucket_volume) || *size <= Decimal::zero() {
+      return Ok(());
+    }
+    let flow = self.products.entry(product_id.into()).or_insert_with(|| ProductFlow {
+      current: Bucket::empty(),
+      completed: VecDeque::new(),
+      signed_volume: Decimal::zero(),
+      last: None,
+    });
+    flow.signed_volume = match taker_side {
+      Side::BUY => &flow.signed_volume + size,
+      Side::SELL => &flow.signed_volume - size,
+    };
+
+    let mut remaining = size.clone();
+    while !Zero::is_zero(&remaining) {
+      let room = &self.bucket_volume - &flow.current.volume();
+      let fill = if remaining < room { remaining.clone() } else { room.clone() };
+      match taker_side {
+        Side::BUY => flow.current.buy += fill.clone(),
+        Side::SELL => flow.current.sell += fill.clone(),
+      }
+      remaining -= fill.clone();
+      if fill == room {
+        let bucket = std::mem::replace(&mut flow.current, Bucket::empty());
+        flow.completed.push_back(bucket);
+        if flow.completed.len() > self.window {
+          flow.completed.pop_front();
+        }
+        let metrics = compute(product_id, time, flow, &self.bucket_volume, self.window);
+        self.sink.on_order_flow(&metrics)?;
+        flow.last = Some(metrics);
+      }
+    }
+    Ok(())
+  }
+}
+
+fn compute(product_id: &str, time: DateTime<Utc>, flow: &ProductFlow, bucket_volume: &Decimal, window: usize) -> OrderFlowMetrics {
+  let mut buy_volume = Decimal::zero();
+  let mut sell_volume = Decimal::zero();
+  let mut absolute_imbalance = Decimal::zero();
+  for bucket in flow.completed.iter() {
+    buy_volume += bucket.buy.clone();
+    sell_volume += bucket.sell.clone();
+    absolute_imbalance += (&bucket.buy - &bucket.sell).abs();
+  }
+  let total = (&buy_volume + &sell_volume).to_f64().unwrap_or_default();
+  let imbalance = if total > 0.0 { (&buy_volume - &sell_volume).to_f64().unwrap_or_default() / total } else { 0.0 };
+  let buckets = flow.completed.len();
+  let vpin = if buckets == window {
+    Some(absolute_imbalance.to_f64().unwrap_or_default() / (buckets as f64 * bucket_volume.to_f64().unwrap_or(f64::MAX)))
+  } else {
+    None
+  };
+  OrderFlowMetrics {
+    product_id: product_id.into(),
+    time,
+    buckets,
+    buy_volume,
+    sell_volume,
+    signed_volume: flow.signed_volume.clone(),
+    imbalance,
+    vpin,
+  }
+}
+
+impl<S: OrderFlowSink> CoinBaseWebSocketMessageHandler for OrderFlowHandler<S> {
+  fn on_match(&mut self, resp: &MatchResponse) -> Result<(), Terminate> {
+    let taker_side = match resp.side {
+      Side::BUY => Side::SELL,
+      Side::SELL => Side::BUY,
+    };
+    self.add_trade(&resp.product_id, resp.time, taker_side, &resp.size)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{OrderFlowHandler, OrderFlowMetrics};
+  use crate::web_socket::response::MatchResponse;
+  use crate::web_socket::CoinBaseWebSocketMessageHandler;
+
+  fn trade(maker_side: &str, size: &str) -> Result<MatchResponse, serde_json::error::Error> {
+    serde_json::from_str(&format!(r#"{{
+      "trade_id": 62995921, "maker_order_id": "125f1d3d-3100-41ce-9341-fc330bdcebcb",
+      "taker_order_id": "5b0a9f2d-3388-4fd4-a106-b96b1e6d302f", "side": "{}", "size": "{}",
+      "price": "434.19", "product_id": "ETH-USD", "sequence": 10182385681, "time": "2020-08-31T15:05:14.336755Z"
+    }}"#, maker_side, size))
+  }
+
+  #[test]
+  fn compute_vpin_over_volume_buckets() -> Result<(), serde_json::error::Error> {
+    let mut emitted: Vec<OrderFlowMetrics> = Vec::new();
+    let mut handler = OrderFlowHandler::new("10".parse().unwrap(), 2, |metrics: &OrderFlowMetrics| {
+      emitted.push(metrics.clone());
+      Ok(())
+    });
+    // Taker buys 8, then a taker sell of 7 completes the first bucket and starts the second.
+    handler.on_match(&trade("sell", "8")?).unwrap();
+    handler.on_match(&trade("buy", "7")?).unwrap();
+    assert!(handler.metrics("ETH-USD").unwrap().vpin.is_none());
+    handler.on_match(&trade("buy", "5")?).unwrap();
+    let metrics = handler.metrics("ETH-USD").unwrap().clone();
+    drop(handler);
+
+    assert_eq!(emitted.len(), 2);
+    assert_eq!(metrics.buckets, 2);
+    assert_eq!(metrics.buy_volume, "8".parse().unwrap());
+    assert_eq!(metrics.sell_volume, "12".parse().unwrap());
+    assert_eq!(metrics.signed_volume, "-4".parse().unwrap());
+    assert!((metrics.imbalance + 0.2).abs() < 1e-9);
+    // Buckets of 8/2 and 0/10.
+    assert!((metrics.vpin.unwrap() - 0.8).abs() < 1e-9);
+    Ok(())
+  }
+}
@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::decimal::{Decimal, Zero};
+use crate::web_socket::response::TickerResponse;
+use crate::web_socket::{CoinBaseWebSocketMessageHandler, Terminate};
+
+/// Product contributing to an index. Its price is multiplied by `fx_rate` to convert it
+/// into the index currency, e.g. EUR->USD rate for `BTC-EUR` in a USD denominated index.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IndexComponent {
+  pub product_id: String,
+  pub weight: Decimal,
+  pub fx_rate: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IndexDefinition {
+  pub name: String,
+  pub components: Vec<IndexComponent>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IndexUpdate {
+  pub name: String,
+  pub time: DateTime<Utc>,
+  pub price: Decimal,
+  /// Number of components that had a price, weights are renormalized over them.
+  pub components: usize,
+}
+
+pub trait IndexSink {
+  fn on_index_update(&mut self, update: &IndexUpdate) -> Result<(), Terminate>;
+}
+
+impl<F: FnMut(&IndexUpdate) -> Result<(), Terminate>> IndexSink for F {
+  fn on_index_update(&mut self, update: &IndexUpdate) -> Result<(), Terminate> {
+    self(update)
+  }
+}
+
+/// Computes weighted average of component mid prices (from the `ticker` channel) converted
+/// into the index currency. Every ticker of a component recomputes the indices containing it.
+pub struct IndexPriceHandler<S: IndexSink> {
+  indices: Vec<IndexDefinition>,
+  mid_prices: HashMap<String, Decimal>,
+  sink: S,
+}
+
+impl<S: IndexSink> IndexPriceHandler<S> {
+  pub fn new(indices: Vec<IndexDefinition>, sink: S) -> Self {
+    IndexPriceHandler { indices, mid_prices: HashMap::new(), sink }
+  }
+
+  /// Updates conversion rate of the component in the given index.
+  pub fn set_fx_rate(&mut self, index: &str, product_id: &str, fx_rate: Decimal) {
+    let components = self.indices.iter_mut()
+      .filter(|definition| definition.name == index)
+      .flat_map(|definition| definition.components.iter_mut())
+      .filter(|component| component.product_id == product_id);
+    for component in components {
+      component.fx_rate = fx_rate.clone();
+    }
+  }
+
+  /// Current index price, `None` until at least one component has a price.
+  pub fn price(&self, index: &str) -> Option<Decimal> {
+    self.indices.iter()
+      .find(|definition| definition.name == index)
+      .and_then(|definition| self.compute(definition))
+      .map(|(price, _)| price)
+  }
+
+  fn compute(&self, definition: &IndexDefinition) -> Option<(Decimal, usize)> {
+    let mut weighted = Decimal::zero();
+    let mut total_weight = Decimal::zero();
+    let mut count = 0;
+    for component in definition.components.iter() {
+      if let Some(mid) = self.mid_prices.get(&component.product_id) {
+        weighted += mid.clone() * component.fx_rate.clone() * component.weight.clone();
+        total_weight += component.weight.clone();
+        count += 1;
+      }
+    }
+    if count == 0 || total_weight.is_zero() {
+      return None;
+    }
+    Some((weighted / total_weight, count))
+  }
+}
+
+impl<S: IndexSink> CoinBaseWebSocketMessageHandler for IndexPriceHandler<S> {
+  fn on_ticker(&mut self, resp: &TickerResponse) -> Result<(), Terminate> {
+    let mid = (resp.best_bid.clone() + resp.best_ask.clone()) / Decimal::from(2);
+    self.mid_prices.insert(resp.product_id.clone(), mid);
+
+    let updates: Vec<IndexUpdate> = self.indices.iter()
+      .filter(|definition| definition.components.iter().any(|component| component.product_id == resp.product_id))
+      .filter_map(|definition| {
+        self.compute(definition).map(|(price, components)| IndexUpdate {
+          name: definition.name.clone(),
+          time: resp.time,
+          price,
+          components,
+        })
+      })
+      .collect();
+    for update in updates.iter() {
+      self.sink.on_index_update(update)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::str::FromStr;
+
+  use super::{IndexComponent, IndexDefinition, IndexPriceHandler, IndexUpdate};
+  use crate::decimal::Decimal;
+  use crate::web_socket::response::TickerResponse;
+  use crate::web_socket::CoinBaseWebSocketMessageHandler;
+
+  fn decimal(value: &str) -> Decimal {
+    Decimal::from_str(value).unwrap()
+  }
+
+  fn ticker(product_id: &str, bid: &str, ask: &str) -> Result<TickerResponse, serde_json::error::Error> {
+    serde_json::from_str(&format!(r#"{{
+      "trade_id": 1, "sequence": 1, "time": "2017-09-02T17:05:49.250000Z", "product_id": "{}",
+      "price": "{}", "side": "buy", "last_size": "0.03", "best_bid": "{}", "best_ask": "{}"
+    }}"#, product_id, bid, bid, ask))
+  }
+
+  #[test]
+  fn weighted_index_in_usd() -> Result<(), serde_json::error::Error> {
+    let definition = IndexDefinition {
+      name: "BTC".into(),
+      components: vec![
+        IndexComponent { product_id: "BTC-USD".into(), weight: decimal("3"), fx_rate: decimal("1") },
+        IndexComponent { product_id: "BTC-EUR".into(), weight: decimal("1"), fx_rate: decimal("1.2") },
+      ],
+    };
+    let mut updates: Vec<IndexUpdate> = Vec::new();
+    let mut handler = IndexPriceHandler::new(vec![definition], |update: &IndexUpdate| {
+      updates.push(update.clone());
+      Ok(())
+    });
+    handler.on_ticker(&ticker("BTC-USD", "9999", "10001")?).unwrap();
+    handler.on_ticker(&ticker("BTC-EUR", "8000", "8000")?).unwrap();
+    handler.on_ticker(&ticker("ETH-USD", "400", "401")?).unwrap();
+
+    assert_eq!(handler.price("BTC"), Some(decimal("9900")));
+    drop(handler);
+    assert_eq!(updates.len(), 2);
+    assert_eq!(updates[0].price, decimal("10000"));
+    assert_eq!(updates[1].components, 2);
+    Ok(())
+  }
+}
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::decimal::Decimal;
+use crate::order_book::{Level, OrderBook, OrderBooks};
+use crate::web_socket::response::{HeartBeatResponse, L2UpdateResponse, Side, SnapshotResponse};
+use crate::web_socket::{Clock, CoinBaseWebSocketMessageHandler, SystemClock, Terminate};
+
+/// Liquidity resting within `bps` basis points of the mid price, cumulative from the touch.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct LiquidityBand {
+  pub bps: u32,
+  pub bid_size: Decimal,
+  pub ask_size: Decimal,
+  /// Sum of price times size of the levels, in the quote currency.
+  pub bid_notional: Decimal,
+  pub ask_notional: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LiquidityProfile {
+  pub product_id: String,
+  pub time: DateTime<Utc>,
+  pub best_bid: Level,
+  pub best_ask: Level,
+  pub mid: Decimal,
+  /// In the order of the configured bands.
+  pub bands: Vec<LiquidityBand>,
+}
+
+impl LiquidityProfile {
+  /// Computes the profile of the book, `None` when either side is empty.
+  pub fn of(book: &OrderBook, bands_bps: &[u32]) -> Option<Self> {
+    LiquidityProfile::at(book, bands_bps, Utc::now())
+  }
+
+  /// Profile of the book taken at `time`.
+  pub fn at(book: &OrderBook, bands_bps: &[u32], time: DateTime<Utc>) -> Option<Self> {
+    let best_bid = book.best_bid()?;
+    let best_ask = book.best_ask()?;
+    let mid = (best_bid.price.clone() + best_ask.price.clone()) / Decimal::from(2);
+    let bands = bands_bps.iter().map(|&bps| {
+      let offset = &mid * Decimal::from(bps) / Decimal::from(10_000);
+      let (bid_size, bid_notional) = book.depth_within(Side::BUY, &(&mid - &offset));
+      let (ask_size, ask_notional) = book.depth_within(Side::SELL, &(&mid + &offset));
+      LiquidityBand { bps, bid_size, ask_size, bid_notional, ask_notional }
+    }).collect();
+    Some(LiquidityProfile { product_id: book.product_id().into(), time, best_bid, best_ask, mid, bands })
+  }
+
+  pub fn band(&self, bps: u32) -> Option<&LiquidityBand> {
+    self.bands.iter().find(|band| band.bps == bps)
+  }
+}
+
+pub trait LiquidityProfileSink {
+  fn on_liquidity_profile(&mut self, profile: &LiquidityProfile) -> Result<(), Terminate>;
+}
+
+impl<F: FnMut(&LiquidityProfile) -> Result<(), Terminate>> LiquidityProfileSink for F {
+  fn on_liquidity_profile(&mut self, profile: &LiquidityProfile) -> Result<(), Terminate> {
+    self(profile)
+  }
+}
+
+/// Maintains order books from the `level2` channel and emits the cumulative size and notional
+/// on each side within the configured bands around the mid (e.g. 10, 25 and 50 bps) every
+/// `interval`.
+///
+/// Like `DepthSnapshotHandler`, due profiles are emitted when the next message of the product
+/// arrives, subscribe to `heartbeat` to sample quiet products. Books with an empty side are
+/// skipped.
+pub struct LiquidityProfileHandler<S: LiquidityProfileSink> {
+  books: OrderBooks,
+  bands_bps: Vec<u32>,
+  interval: Duration,
+  next_emit: HashMap<String, Instant>,
+  sink: S,
+  clock: Arc<dyn Clock>,
+}
+
+impl<S: LiquidityProfileSink> LiquidityProfileHandler<S> {
+  pub fn new(mut bands_bps: Vec<u32>, interval: Duration, sink: S) -> Self {
+    bands_bps.sort_unstable();
+    bands_bps.dedup();
+    LiquidityProfileHandler { books: OrderBooks::new(), bands_bps, interval, next_emit: HashMap::new(), sink, clock: Arc::new(SystemClock) }
+  }
+
+  /// Clock the emission schedule and profile times are taken from, e.g. the clock of a replay.
+  pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+    self.clock = clock;
+    self
+  }
+
+  pub fn books(&self) -> &OrderBooks {
+    &self.books
+  }
+
+  /// Profile of the product's current book, regardless of the emission schedule.
+  pub fn profile(&self, product_id: &str) -> Option<LiquidityProfile> {
+    LiquidityProfile::at(self.books.get(product_id)?, &self.bands_bps, self.clock.utc_now())
+  }
+
+  fn emit_if_due(&mut self, product_id: &str, now: Instant) -> Result<(), Terminate> {
+    let next_emit = match self.next_emit.get_mut(product_id) {
+      Some(next_emit) => next_emit,
+      None => return Ok(()),
+    };
+    if now < *next_emit {
+      return Ok(());
+    }
+    while *next_emit <= now {
+      *next_emit += self.interval.max(Duration::from_nanos(1));
+    }
+
+    match self.profile(product_id) {
+      Some(profile) => self.sink.on_liquidity_profile(&profile),
+      None => Ok(()),
+    }
+  }
+}
+
+impl<S: LiquidityProfileSink> CoinBaseWebSocketMessageHandler for LiquidityProfileHandler<S> {
+  fn on_heartbeat(&mut self, resp: &HeartBeatResponse) -> Result<(), Terminate> {
+    self.emit_if_due(resp.product_id.as_str(), self.clock.now())
+  }
+
+  fn on_snapshot(&mut self, resp: &SnapshotResponse) -> Result<(), Terminate> {
+    self.books.on_snapshot(resp)?;
+    let now = self.clock.now();
+    self.next_emit.entry(resp.product_id.clone()).or_insert(now);
+    self.emit_if_due(resp.product_id.as_str(), now)
+  }
+
+  fn on_l2_update(&mut self, resp: &L2UpdateResponse) -> Result<(), Terminate> {
+    self.books.on_l2_update(resp)?;
+    self.emit_if_due(resp.product_id.as_str(), self.clock.now())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::time::Duration;
+
+  use crate::web_socket::response::SnapshotResponse;
+  use crate::web_socket::{CoinBaseWebSocketMessageHandler, Terminate};
+
+  use super::{LiquidityProfile, LiquidityProfileHandler};
+
+  #[test]
+  fn sum_liquidity_within_bands() -> Result<(), serde_json::error::Error> {
+    let snapshot: SnapshotResponse = serde_json::from_str(r#"{
+      "product_id": "BTC-USD",
+      "bids": [["9999", "1.0"], ["9990", "2.0"], ["9960", "4.0"], ["9900", "8.0"]],
+      "asks": [["10001", "1.5"], ["10020", "2.5"], ["10060", "3.0"]]
+    }"#)?;
+    let mut profiles = Vec::new();
+    let mut handler = LiquidityProfileHandler::new(vec![50, 10, 25], Duration::from_secs(60), |profile: &LiquidityProfile| -> Result<(), Terminate> {
+      profiles.push(profile.clone());
+      Ok(())
+    });
+    handler.on_snapshot(&snapshot).unwrap();
+    let profile = handler.profile("BTC-USD").unwrap();
+    drop(handler);
+
+    assert_eq!(profiles.len(), 1);
+    assert_eq!(profile.mid, "10000".parse().unwrap());
+    assert_eq!(profile.bands.iter().map(|band| band.bps).collect::<Vec<_>>(), vec![10, 25, 50]);
+    // Bids from 9990 and asks up to 10010.
+    let band = profile.band(10).unwrap();
+    assert_eq!(band.bid_size, "3.0".parse().unwrap());
+    assert_eq!(band.ask_size, "1.5".parse().unwrap());
+    assert_eq!(band.bid_notional, "29979".parse().unwrap());
+    let band = profile.band(50).unwrap();
+    assert_eq!(band.bid_size, "7.0".parse().unwrap());
+    assert_eq!(band.ask_size, "4.0".parse().unwrap());
+    Ok(())
+  }
+}
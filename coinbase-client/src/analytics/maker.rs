@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::decimal::{Decimal, Zero};
+use crate::web_socket::response::{DoneResponse, MatchResponse, OpenResponse, OrderId, ReceivedResponse, Side};
+use crate::web_socket::{CoinBaseWebSocketMessageHandler, Terminate};
+
+/// Consecutive matches of a product against the same maker order.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MakerOrderSummary {
+  pub product_id: String,
+  pub maker_order_id: OrderId,
+  /// Side of the maker order.
+  pub side: Side,
+  pub price: Decimal,
+  pub first_time: DateTime<Utc>,
+  pub last_time: DateTime<Utc>,
+  pub matches: u64,
+  /// Number of taker orders that matched, a single sweep counts once.
+  pub taker_orders: u64,
+  pub filled_size: Decimal,
+  /// Size the order was placed with, known from the `received` or `open` message of the
+  /// `full` channel.
+  pub displayed_size: Option<Decimal>,
+  /// Resting size of the order, the displayed size or the filled size when more was filled.
+  pub estimated_size: Decimal,
+  /// Number of maker orders at the same price and side consumed right before this one.
+  pub refills: u32,
+  /// Whether the order filled more than it displayed, or the price was refilled at least
+  /// `min_refills` times.
+  pub iceberg: bool,
+}
+
+pub trait MakerOrderSink {
+  fn on_maker_order(&mut self, summary: &MakerOrderSummary) -> Result<(), Terminate>;
+}
+
+impl<F: FnMut(&MakerOrderSummary) -> Result<(), Terminate>> MakerOrderSink for F {
+  fn on_maker_order(&mut self, summary: &MakerOrderSummary) -> Result<(), Terminate> {
+    self(summary)
+  }
+}
+
+struct Group {
+  summary: MakerOrderSummary,
+  last_taker_order_id: OrderId,
+}
+
+#[derive(Default)]
+struct ProductMakers {
+  current: Option<Group>,
+  last: Option<MakerOrderSummary>,
+}
+
+/// Groups consecutive matches of the `matches` or `full` channel by maker order and emits a
+/// `MakerOrderSummary` once a match against another maker order of the product, or the `done`
+/// message of the order, ends the group. Call `flush` to emit the groups still open.
+///
+/// A maker order at the same price and side as the previous summary, starting within
+/// `refill_window` after it, is counted as a refill of it. Orders repeatedly refilling a level
+/// hint at an iceberg or a refreshing strategy. With the `full` channel the displayed size of
+/// the orders is known too, and orders filling beyond it are flagged.
+pub struct MakerOrderHandler<S: MakerOrderSink> {
+  refill_window: Duration,
+  min_refills: u32,
+  displayed: HashMap<OrderId, Decimal>,
+  products: HashMap<String, ProductMakers>,
+  sink: S,
+}
+
+impl<S: MakerOrderSink> MakerOrderHandler<S> {
+  /// Refills within a second are counted, two of them flag an iceberg.
+  pub fn new(sink: S) -> Self {
+    MakerOrderHandler {
+      refill_window: Duration::seconds(1),
+      min_refills: 2,
+      displayed: HashMap::new(),
+      products: HashMap::new(),
+      sink,
+    }
+  }
+
+  pub fn refill_window(mut self, window: std::time::Duration) -> Self {
+    self.refill_window = Duration::from_std(window).unwrap_or(Duration::MAX);
+    self
+  }
+
+  pub fn min_refills(mut self, refills: u32) -> Self {
+    self.min_refills = refills.max(1);
+    self
+  }
+
+  /// Emits the groups of all products that are still open.
+  pub fn flush(&mut self) -> Result<(), Terminate> {
+    let product_ids: Vec<String> = self.products.keys().cloned().collect();
+    for product_id in product_ids {
+      self.close(&product_id)?;
+    }
+    Ok(())
+  }
+
+  fn close(&mut self, product_id: &str) -> Result<(), Terminate> {
+    let makers = match self.products.get_mut(product_id) {
+      Some(makers) => makers,
+      None => return Ok(()),
+    };
+    let mut summary = match makers.current.take() {
+      Some(group) => group.summary,
+      None => return Ok(()),
+    };
+    summary.displayed_size = self.displayed.remove(&summary.maker_order_id);
+    summary.estimated_size = match &summary.displayed_size {
+      Some(displayed) if *displayed > summary.filled_size => displayed.clone(),
+      _ => summary.filled_size.clone(),
+    };
+    let overfilled = summary.displayed_size.as_ref().is_some_and(|displayed| summary.filled_size > *displayed);
+    summary.iceberg = overfilled || summary.refills >= self.min_refills;
+    self.sink.on_maker_order(&summary)?;
+    makers.last = Some(summary);
+    Ok(())
+  }
+
+  fn add_match(&mut self, resp: &MatchResponse) -> Result<(), Terminate> {
+    let same_order = self.products.get(&resp.product_id)
+      .and_then(|makers| makers.current.as_ref())
+      .is_some_and(|group| group.summary.maker_order_id == resp.maker_order_id);
+    if !same_order {
+      self.close(&resp.product_id)?;
+    }
+    let makers = self.products.entry(resp.product_id.clone()).or_default();
+    match &mut makers.current {
+      Some(group) => {
+        let summary = &mut group.summary;
+        summary.last_time = resp.time;
+        summary.matches += 1;
+        summary.filled_size += resp.size.clone();
+        if group.last_taker_order_id != resp.taker_order_id {
+          summary.taker_orders += 1;
+          group.last_taker_order_id = resp.taker_order_id;
+        }
+      }
+      None => {
+        let refills = match &makers.last {
+          Some(last) if last.side == resp.side && last.price == resp.price && resp.time - last.last_time <= self.refill_window => last.refills + 1,
+          _ => 0,
+        };
+        let summary = MakerOrderSummary {
+          product_id: resp.product_id.clone(),
+          maker_order_id: resp.maker_order_id,
+          side: resp.side,
+          price: resp.price.clone(),
+          first_time: resp.time,
+          last_time: resp.time,
+          matches: 1,
+          taker_orders: 1,
+          filled_size: resp.size.clone(),
+          displayed_size: None,
+          estimated_size: Decimal::zero(),
+          refills,
+          iceberg: false,
+        };
+        makers.current = Some(Group { summary, last_taker_order_id: resp.taker_order_id });
+      }
+    }
+    Ok(())
+  }
+}
+
+impl<S: MakerOrderSink> CoinBaseWebSocketMessageHandler for MakerOrderHandler<S> {
+  fn on_received(&mut self, resp: &ReceivedResponse) -> Result<(), Terminate> {
+    if let Some(size) = &resp.size {
+      self.displayed.insert(resp.order_id, size.clone());
+    }
+    Ok(())
+  }
+
+  fn on_open(&mut self, resp: &OpenResponse) -> Result<(), Terminate> {
+    // Orders received before the subscription are only known once they rest on the book.
+    self.displayed.entry(resp.order_id).or_insert_with(|| resp.remaining_size.clone());
+    Ok(())
+  }
+
+  fn on_match(&mut self, resp: &MatchResponse) -> Result<(), Terminate> {
+    self.add_match(resp)
+  }
+
+  fn on_done(&mut self, resp: &DoneResponse) -> Result<(), Terminate> {
+    let current = self.products.get(&resp.product_id)
+      .and_then(|makers| makers.current.as_ref())
+      .is_some_and(|group| group.summary.maker_order_id == resp.order_id);
+    if current {
+      return self.close(&resp.product_id);
+    }
+    self.displayed.remove(&resp.order_id);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{MakerOrderHandler, MakerOrderSummary};
+  use crate::web_socket::response::{MatchResponse, ReceivedResponse};
+  use crate::web_socket::CoinBaseWebSocketMessageHandler;
+
+  fn trade(maker: u8, taker: u8, price: &str, size: &str, millis: u32) -> MatchResponse {
+    serde_json::from_str(&format!(r#"{{
+      "trade_id": 1, "maker_order_id": "00000000-0000-0000-0000-0000000000{:02}",
+      "taker_order_id": "00000000-0000-0000-0000-0000000001{:02}", "side": "sell", "size": "{}",
+      "price": "{}", "product_id": "ETH-USD", "sequence": 1, "time": "2020-08-31T15:05:14.{:03}Z"
+    }}"#, maker, taker, size, price, millis)).unwrap()
+  }
+
+  #[test]
+  fn detect_refilled_price_level() {
+    let mut summaries: Vec<MakerOrderSummary> = Vec::new();
+    let mut handler = MakerOrderHandler::new(|summary: &MakerOrderSummary| {
+      summaries.push(summary.clone());
+      Ok(())
+    });
+    let received: ReceivedResponse = serde_json::from_str(r#"{
+      "time": "2020-08-31T15:05:14.000Z", "product_id": "ETH-USD", "sequence": 1, "order_id": "00000000-0000-0000-0000-000000000001",
+      "side": "sell", "order_type": "limit", "size": "1", "price": "100"
+    }"#).unwrap();
+    handler.on_received(&received).unwrap();
+    // A sweep of two matches and another taker, then the level is refilled twice.
+    handler.on_match(&trade(1, 1, "100", "0.5", 100)).unwrap();
+    handler.on_match(&trade(1, 1, "100", "0.5", 100)).unwrap();
+    handler.on_match(&trade(1, 2, "100", "0.25", 200)).unwrap();
+    handler.on_match(&trade(2, 3, "100", "1", 300)).unwrap();
+    handler.on_match(&trade(3, 4, "100", "1", 400)).unwrap();
+    handler.on_match(&trade(4, 5, "101", "1", 500)).unwrap();
+    handler.flush().unwrap();
+    drop(handler);
+
+    let refills: Vec<(u64, u64, u32, bool)> = summaries.iter()
+      .map(|summary| (summary.matches, summary.taker_orders, summary.refills, summary.iceberg))
+      .collect();
+    assert_eq!(refills, vec![(3, 2, 0, true), (1, 1, 1, false), (1, 1, 2, true), (1, 1, 0, false)]);
+    assert_eq!(summaries[0].displayed_size, Some("1".parse().unwrap()));
+    assert_eq!(summaries[0].estimated_size, "1.25".parse().unwrap());
+  }
+}
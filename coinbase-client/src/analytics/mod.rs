@@ -0,0 +1,23 @@
+pub mod candles;
+pub use candles::{Candle, CandleAggregator, CandleSink};
+
+pub mod daily;
+pub use daily::{DailySummary, DailySummaryHandler, DailySummarySink};
+
+pub mod index;
+pub use index::{IndexComponent, IndexDefinition, IndexPriceHandler, IndexSink, IndexUpdate};
+
+pub mod liquidity;
+pub use liquidity::{LiquidityBand, LiquidityProfile, LiquidityProfileHandler, LiquidityProfileSink};
+
+pub mod flow;
+pub use flow::{OrderFlowHandler, OrderFlowMetrics, OrderFlowSink};
+
+pub mod alerts;
+pub use alerts::{Alert, AlertHandler, AlertRule, AlertSink, Condition, Webhook};
+
+pub mod smoothing;
+pub use smoothing::{Ewma, FilteredPrice, FilteredPriceSink, Kalman, PriceFilter, PriceFilterHandler, PriceSource};
+
+pub mod maker;
+pub use maker::{MakerOrderHandler, MakerOrderSink, MakerOrderSummary};
@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use num_traits::ToPrimitive;
+use serde::{Deserialize, Serialize};
+
+use crate::web_socket::response::TickerResponse;
+use crate::web_socket::{CoinBaseWebSocketMessageHandler, Terminate};
+
+/// Filter applied to the price stream of one product, a fresh copy is used for every product.
+pub trait PriceFilter: Clone {
+  /// Adds a price observed at `time` and returns the filtered price.
+  fn update(&mut self, time: DateTime<Utc>, price: f64) -> f64;
+}
+
+/// Exponentially weighted moving average. The weight of a price halves every `half_life`,
+/// so irregularly spaced ticks are weighted by the time between them rather than their count.
+#[derive(Debug, Clone)]
+pub struct Ewma {
+  half_life: Duration,
+  last: Option<(DateTime<Utc>, f64)>,
+}
+
+impl Ewma {
+  pub fn new(half_life: Duration) -> Self {
+    Ewma { half_life, last: None }
+  }
+}
+
+impl PriceFilter for Ewma {
+  fn update(&mut self, time: DateTime<Utc>, price: f64) -> f64 {
+    let filtered = match self.last {
+      Some((last_time, average)) => {
+        let elapsed = (time - last_time).to_std().unwrap_or_default().as_secs_f64();
+        let half_life = self.half_life.as_secs_f64();
+        let alpha = if half_life > 0.0 { 1.0 - 0.5f64.powf(elapsed / half_life) } else { 1.0 };
+        average + alpha * (price - average)
+      }
+      None => price,
+    };
+    self.last = Some((time, filtered));
+    filtered
+  }
+}
+
+/// Kalman filter of a price following a random walk. `process_noise` is the variance the true
+/// price gains between two ticks and `measurement_noise` the variance of the observed prices
+/// around it, their ratio sets how quickly the estimate follows the ticks.
+#[derive(Debug, Clone)]
+pub struct Kalman {
+  process_noise: f64,
+  measurement_noise: f64,
+  // Estimate and its variance.
+  state: Option<(f64, f64)>,
+}
+
+impl Kalman {
+  pub fn new(process_noise: f64, measurement_noise: f64) -> Self {
+    Kalman { process_noise, measurement_noise, state: None }
+  }
+}
+
+impl PriceFilter for Kalman {
+  fn update(&mut self, _time: DateTime<Utc>, price: f64) -> f64 {
+    let (estimate, variance) = match self.state {
+      Some((estimate, variance)) => {
+        let predicted = variance + self.process_noise;
+        let gain = predicted / (predicted + self.measurement_noise);
+        (estimate + gain * (price - estimate), (1.0 - gain) * predicted)
+      }
+      None => (price, self.measurement_noise),
+    };
+    self.state = Some((estimate, variance));
+    estimate
+  }
+}
+
+/// Price of a ticker that is filtered.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PriceSource {
+  /// Midpoint of the best bid and ask.
+  Mid,
+  /// Price of the last trade.
+  Last,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FilteredPrice {
+  pub product_id: String,
+  pub time: DateTime<Utc>,
+  pub raw: f64,
+  pub filtered: f64,
+}
+
+pub trait FilteredPriceSink {
+  fn on_filtered_price(&mut self, price: &FilteredPrice) -> Result<(), Terminate>;
+}
+
+impl<F: FnMut(&FilteredPrice) -> Result<(), Terminate>> FilteredPriceSink for F {
+  fn on_filtered_price(&mut self, price: &FilteredPrice) -> Result<(), Terminate> {
+    self(price)
+  }
+}
+
+/// Runs the mid or last prices of the `ticker` channel through a filter, per product, and emits
+/// the filtered price on every ticker.
+pub struct PriceFilterHandler<F: PriceFilter, S: FilteredPriceSink> {
+  filter: F,
+  source: PriceSource,
+  products: HashMap<String, (F, f64)>,
+  sink: S,
+}
+
+impl<S: FilteredPriceSink> PriceFilterHandler<Ewma, S> {
+  /// EWMA of the mid price.
+  pub fn ewma_mid(half_life: Duration, sink: S) -> Self {
+    PriceFilterHandler::new(Ewma::new(half_life), PriceSource::Mid, sink)
+  }
+}
+
+impl<S: FilteredPriceSink> PriceFilterHandler<Kalman, S> {
+  /// Kalman filter of the last trade price.
+  pub fn kalman(process_noise: f64, measurement_noise: f64, sink: S) -> Self {
+    PriceFilterHandler::new(Kalman::new(process_noise, measurement_noise), PriceSource::Last, sink)
+  }
+}
+
+impl<F: PriceFilter, S: FilteredPriceSink> PriceFilterHandler<F, S> {
+  pub fn new(filter: F, source: PriceSource, sink: S) -> Self {
+    PriceFilterHandler { filter, source, products: HashMap::new(), sink }
+  }
+
+  /// Last filtered price of the product.
+  pub fn filtered(&self, product_id: &str) -> Option<f64> {
+    self.products.get(product_id).map(|(_, filtered)| *filtered)
+  }
+}
+
+impl<F: PriceFilter, S: FilteredPriceSink> CoinBaseWebSocketMessageHandler for PriceFilterHandler<F, S> {
+  fn on_ticker(&mut self, resp: &TickerResponse) -> Result<(), Terminate> {
+    let raw = match self.source {
+      PriceSource::Mid => (resp.best_bid.to_f64().unwrap_or_default() + resp.best_ask.to_f64().unwrap_or_default()) / 2.0,
+      PriceSource::Last => resp.price.to_f64().unwrap_or_default(),
+    };
+    if raw <= 0.0 {
+      return Ok(());
+    }
+    let filter = &self.filter;
+    let (product_filter, last) = self.products.entry(resp.product_id.clone()).or_insert_with(|| (filter.clone(), raw));
+    let filtered = product_filter.update(resp.time, raw);
+    *last = filtered;
+    self.sink.on_filtered_price(&FilteredPrice { product_id: resp.product_id.clone(), time: resp.time, raw, filtered })
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::time::Duration;
+
+  use super::{FilteredPrice, PriceFilterHandler};
+  use crate::web_socket::response::TickerResponse;
+  use crate::web_socket::CoinBaseWebSocketMessageHandler;
+
+  fn ticker(time: &str, price: &str, bid: &str, ask: &str) -> Result<TickerResponse, serde_json::error::Error> {
+    serde_json::from_str(&format!(r#"{{
+      "trade_id": 1, "sequence": 1, "time": "{}", "product_id": "BTC-USD", "price": "{}",
+      "side": "buy", "last_size": "0.03", "best_bid": "{}", "best_ask": "{}"
+    }}"#, time, price, bid, ask))
+  }
+
+  #[test]
+  fn ewma_weights_by_elapsed_time() -> Result<(), serde_json::error::Error> {
+    let mut emitted: Vec<FilteredPrice> = Vec::new();
+    let mut handler = PriceFilterHandler::ewma_mid(Duration::from_secs(1), |price: &FilteredPrice| {
+      emitted.push(price.clone());
+      Ok(())
+    });
+    handler.on_ticker(&ticker("2020-08-31T15:00:00Z", "100", "99", "101")?).unwrap();
+    // One half-life later the average moves half way to the new mid.
+    handler.on_ticker(&ticker("2020-08-31T15:00:01Z", "110", "109", "111")?).unwrap();
+    // Two more half-lives move it three quarters of the rest.
+    handler.on_ticker(&ticker("2020-08-31T15:00:03Z", "110", "109", "111")?).unwrap();
+    assert!((handler.filtered("BTC-USD").unwrap() - 108.75).abs() < 1e-9);
+    drop(handler);
+
+    let filtered: Vec<f64> = emitted.iter().map(|price| price.filtered).collect();
+    assert!((filtered[0] - 100.0).abs() < 1e-9 && (filtered[1] - 105.0).abs() < 1e-9);
+    assert_eq!(emitted[2].raw, 110.0);
+    Ok(())
+  }
+
+  #[test]
+  fn kalman_converges_to_level() -> Result<(), serde_json::error::Error> {
+    let mut handler = PriceFilterHandler::kalman(0.01, 1.0, |_: &FilteredPrice| Ok(()));
+    handler.on_ticker(&ticker("2020-08-31T15:00:00Z", "100", "99", "101")?).unwrap();
+    handler.on_ticker(&ticker("2020-08-31T15:00:01Z", "110", "109", "111")?).unwrap();
+    let first = handler.filtered("BTC-USD").unwrap();
+    assert!(first > 100.0 && first < 106.0);
+    for _ in 0..200 {
+      handler.on_ticker(&ticker("2020-08-31T15:00:02Z", "110", "109", "111")?).unwrap();
+    }
+    assert!((handler.filtered("BTC-USD").unwrap() - 110.0).abs() < 0.01);
+    Ok(())
+  }
+}
@@ -0,0 +1,158 @@
+//! API key profiles and request signing for the private REST endpoints and the `user` channel.
+//!
+//! Several profiles can be registered under own names, e.g. one per portfolio of a market making
+//! setup, and selected per REST client (`CoinbaseRestClient::with_profile`) or per web socket
+//! connection (`CoinbaseWebSocketClient::authenticate`).
+use std::collections::HashMap;
+use std::fmt;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use thiserror::Error;
+
+// Request that is signed to authenticate a web socket subscription.
+pub(crate) const VERIFY_METHOD: &str = "GET";
+pub(crate) const VERIFY_PATH: &str = "/users/self/verify";
+
+#[derive(Error, Debug)]
+pub enum AuthError {
+  #[error("API secret is not valid base64: {0}")]
+  InvalidSecret(#[from] base64::DecodeError),
+
+  #[error("No profile registered under {0}")]
+  UnknownProfile(String),
+}
+
+/// API key with its decoded secret and passphrase.
+#[derive(Clone)]
+pub struct Credentials {
+  key: String,
+  secret: Vec<u8>,
+  passphrase: String,
+}
+
+impl Credentials {
+  /// Takes the secret base64 encoded, as shown by Coinbase when the key is created.
+  pub fn new(key: &str, secret: &str, passphrase: &str) -> Result<Self, AuthError> {
+    Ok(Credentials { key: key.into(), secret: BASE64.decode(secret.trim())?, passphrase: passphrase.into() })
+  }
+
+  pub fn key(&self) -> &str {
+    &self.key
+  }
+
+  /// Signs the request made at `timestamp` (seconds since the Unix epoch). `path` includes the
+  /// query string.
+  pub fn sign_at(&self, timestamp: i64, method: &str, path: &str, body: &str) -> Signature {
+    let timestamp = timestamp.to_string();
+    let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts keys of any length.");
+    mac.update(timestamp.as_bytes());
+    mac.update(method.to_uppercase().as_bytes());
+    mac.update(path.as_bytes());
+    mac.update(body.as_bytes());
+    Signature {
+      key: self.key.clone(),
+      passphrase: self.passphrase.clone(),
+      timestamp,
+      signature: BASE64.encode(mac.finalize().into_bytes()),
+    }
+  }
+
+  pub fn sign(&self, method: &str, path: &str, body: &str) -> Signature {
+    self.sign_at(Utc::now().timestamp(), method, path, body)
+  }
+}
+
+impl fmt::Debug for Credentials {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Credentials").field("key", &self.key).finish_non_exhaustive()
+  }
+}
+
+/// Signature fields, sent as `CB-ACCESS-*` headers to the REST API and as part of the subscribe
+/// message to the web socket feed.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct Signature {
+  pub key: String,
+  pub passphrase: String,
+  pub timestamp: String,
+  pub signature: String,
+}
+
+/// API key of a portfolio. `profile_id` is the id of the portfolio the key belongs to, it is
+/// used to tell apart messages of several profiles, e.g. with `Profiles::by_profile_id`.
+#[derive(Debug, Clone)]
+pub struct Profile {
+  pub credentials: Credentials,
+  pub profile_id: Option<String>,
+}
+
+impl Profile {
+  pub fn new(credentials: Credentials) -> Self {
+    Profile { credentials, profile_id: None }
+  }
+
+  pub fn profile_id(mut self, profile_id: &str) -> Self {
+    self.profile_id = Some(profile_id.into());
+    self
+  }
+}
+
+/// Named API key profiles.
+#[derive(Debug, Clone, Default)]
+pub struct Profiles {
+  profiles: HashMap<String, Profile>,
+}
+
+impl Profiles {
+  pub fn new() -> Self {
+    Profiles::default()
+  }
+
+  pub fn add(mut self, name: &str, profile: Profile) -> Self {
+    self.profiles.insert(name.into(), profile);
+    self
+  }
+
+  pub fn get(&self, name: &str) -> Result<&Profile, AuthError> {
+    self.profiles.get(name).ok_or_else(|| AuthError::UnknownProfile(name.into()))
+  }
+
+  /// Name and profile registered for the portfolio, e.g. to route `user` channel messages.
+  pub fn by_profile_id(&self, profile_id: &str) -> Option<(&str, &Profile)> {
+    self.profiles.iter()
+      .find(|(_, profile)| profile.profile_id.as_deref() == Some(profile_id))
+      .map(|(name, profile)| (name.as_str(), profile))
+  }
+
+  pub fn names(&self) -> impl Iterator<Item=&str> {
+    self.profiles.keys().map(|name| name.as_str())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{Credentials, Profile, Profiles, VERIFY_METHOD, VERIFY_PATH};
+
+  #[test]
+  fn sign_requests_of_profiles() {
+    let credentials = Credentials::new("key-1", "Y29pbmJhc2UtdGVzdC1zZWNyZXQ=", "phrase").unwrap();
+    let signature = credentials.sign_at(1598886314, VERIFY_METHOD, VERIFY_PATH, "");
+    assert_eq!(signature.timestamp, "1598886314");
+    assert_eq!(signature.signature, "CjC1rX0SpEuMHy3Upsl8uErgzQw/ouR3Q6AECEU0tbY=");
+    let signature = credentials.sign_at(1598886314, "post", "/orders", r#"{"size":"1.0"}"#);
+    assert_eq!(signature.signature, "bvPMv+PEJayNICjILGXcb7rhGl5dM06i+/XnD50mip4=");
+    assert!(Credentials::new("key-1", "not base64!", "phrase").is_err());
+
+    let profiles = Profiles::new()
+      .add("maker", Profile::new(credentials.clone()).profile_id("8058d771-2d88-4f0f-ab6e-299c153d4308"))
+      .add("taker", Profile::new(credentials));
+    assert_eq!(profiles.by_profile_id("8058d771-2d88-4f0f-ab6e-299c153d4308").unwrap().0, "maker");
+    assert!(profiles.get("taker").is_ok());
+    assert!(profiles.get("other").is_err());
+  }
+}
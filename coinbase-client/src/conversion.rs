@@ -0,0 +1,155 @@
+//! Currency conversion over the graph of traded products.
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::decimal::{Decimal, Zero};
+use crate::web_socket::response::{Product, StatusResponse, TickerResponse};
+use crate::web_socket::{CoinBaseWebSocketMessageHandler, Terminate};
+
+/// Edge of the currency graph, converting through `product_id` in either direction.
+#[derive(Debug, Clone)]
+struct Edge {
+  currency: String,
+  product_id: String,
+  /// True when converting from the base to the quote currency of the product.
+  sell_base: bool,
+}
+
+/// Converts amounts between currencies using mid prices of the traded products, going
+/// through cross pairs when there is no direct product (e.g. EUR -> BTC -> USD). The path
+/// with the fewest conversions, among products with a known price, is used.
+///
+/// Products come from the `status` channel (or `set_products`) and prices from the `ticker`
+/// channel (or `set_rate`).
+#[derive(Debug, Default)]
+pub struct CurrencyConverter {
+  graph: HashMap<String, Vec<Edge>>,
+  rates: HashMap<String, Decimal>,
+}
+
+impl CurrencyConverter {
+  pub fn new() -> Self {
+    CurrencyConverter::default()
+  }
+
+  /// Rebuilds the currency graph from the online products.
+  pub fn set_products(&mut self, products: &[Product]) {
+    self.graph.clear();
+    for product in products.iter().filter(|product| product.is_online()) {
+      self.graph.entry(product.base_currency.clone()).or_default().push(Edge {
+        currency: product.quote_currency.clone(),
+        product_id: product.id.clone(),
+        sell_base: true,
+      });
+      self.graph.entry(product.quote_currency.clone()).or_default().push(Edge {
+        currency: product.base_currency.clone(),
+        product_id: product.id.clone(),
+        sell_base: false,
+      });
+    }
+  }
+
+  /// Sets price of the product, in quote currency per unit of the base currency.
+  pub fn set_rate(&mut self, product_id: &str, price: Decimal) {
+    self.rates.insert(product_id.into(), price);
+  }
+
+  /// Rate to multiply amounts in `from` with to get amounts in `to`.
+  pub fn rate(&self, from: &str, to: &str) -> Option<Decimal> {
+    self.convert(Decimal::from(1), from, to)
+  }
+
+  pub fn convert(&self, amount: Decimal, from: &str, to: &str) -> Option<Decimal> {
+    if from == to {
+      return Some(amount);
+    }
+    let path = self.path(from, to)?;
+    let mut amount = amount;
+    for edge in path {
+      let price = self.rates.get(&edge.product_id)?;
+      amount = if edge.sell_base { amount * price.clone() } else { amount / price.clone() };
+    }
+    Some(amount)
+  }
+
+  /// Breadth first search for the shortest path over products with a usable price.
+  fn path(&self, from: &str, to: &str) -> Option<Vec<&Edge>> {
+    let mut previous: HashMap<&str, (&str, &Edge)> = HashMap::new();
+    let mut visited: HashSet<&str> = HashSet::new();
+    let mut queue = VecDeque::new();
+    visited.insert(from);
+    queue.push_back(from);
+
+    while let Some(currency) = queue.pop_front() {
+      if currency == to {
+        break;
+      }
+      let edges = match self.graph.get(currency) {
+        Some(edges) => edges,
+        None => continue,
+      };
+      for edge in edges {
+        let usable = self.rates.get(&edge.product_id).map(|rate| !Zero::is_zero(rate)).unwrap_or(false);
+        if usable && visited.insert(edge.currency.as_str()) {
+          previous.insert(edge.currency.as_str(), (currency, edge));
+          queue.push_back(edge.currency.as_str());
+        }
+      }
+    }
+
+    let mut path = Vec::new();
+    let mut currency = to;
+    while currency != from {
+      let (from_currency, edge) = previous.get(currency)?;
+      path.push(*edge);
+      currency = from_currency;
+    }
+    path.reverse();
+    Some(path)
+  }
+}
+
+impl CoinBaseWebSocketMessageHandler for CurrencyConverter {
+  fn on_status(&mut self, resp: &StatusResponse) -> Result<(), Terminate> {
+    self.set_products(&resp.products);
+    Ok(())
+  }
+
+  fn on_ticker(&mut self, resp: &TickerResponse) -> Result<(), Terminate> {
+    let mid = (resp.best_bid.clone() + resp.best_ask.clone()) / Decimal::from(2);
+    self.set_rate(&resp.product_id, mid);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::str::FromStr;
+
+  use super::CurrencyConverter;
+  use crate::decimal::Decimal;
+  use crate::web_socket::response::Product;
+
+  fn product(base: &str, quote: &str) -> Result<Product, serde_json::error::Error> {
+    serde_json::from_str(&format!(r#"{{
+      "id": "{0}-{1}", "base_currency": "{0}", "quote_currency": "{1}", "display_name": "{0}/{1}",
+      "status": "online", "post_only": false, "limit_only": false, "cancel_only": false
+    }}"#, base, quote))
+  }
+
+  #[test]
+  fn convert_through_cross_pair() -> Result<(), serde_json::error::Error> {
+    let mut converter = CurrencyConverter::new();
+    converter.set_products(&[product("BTC", "USD")?, product("BTC", "EUR")?, product("ETH", "BTC")?]);
+    converter.set_rate("BTC-USD", Decimal::from(10000));
+    converter.set_rate("BTC-EUR", Decimal::from(8000));
+
+    let usd = converter.convert(Decimal::from(80), "EUR", "USD").unwrap();
+    assert_eq!(usd, Decimal::from(100));
+    assert_eq!(converter.rate("BTC", "USD"), Some(Decimal::from(10000)));
+    // ETH-BTC has no price yet.
+    assert_eq!(converter.rate("ETH", "USD"), None);
+    converter.set_rate("ETH-BTC", Decimal::from_str("0.04").unwrap());
+    assert_eq!(converter.rate("ETH", "USD"), Some(Decimal::from(400)));
+    Ok(())
+  }
+}
@@ -0,0 +1,269 @@
+//! Links orders placed through the REST API with the messages of the user channel about them,
+//! so every order gets a single stream of events:
+//!
+//! ```no_run
+//! use coinbase_client::correlation::{OrderEvent, OrderRegistry};
+//! use coinbase_client::rest::{CoinbaseRestClient, OrderBuilder};
+//! use coinbase_client::web_socket::response::Side;
+//!
+//! let registry = OrderRegistry::new();
+//! // Register `registry.clone()` as a handler of a client subscribed to the user channel.
+//! let client = CoinbaseRestClient::production().with_profile("main").unwrap();
+//! let order = OrderBuilder::limit("BTC-USD", Side::BUY, "10000".parse().unwrap(), "0.01".parse().unwrap())
+//!   .build()
+//!   .unwrap();
+//! let (_, events) = registry.place_order(&client, order).unwrap();
+//! for event in events {
+//!   if let OrderEvent::Done(_) = event {
+//!     break;
+//!   }
+//! }
+//! ```
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crossbeam::{Receiver, RecvTimeoutError, Sender};
+use uuid::Uuid;
+
+use crate::rest::{CoinbaseRestClient, NewOrder, Order, RestError};
+use crate::web_socket::response::{
+  ActiveResponse, ChangeResponse, DoneResponse, MatchResponse, OpenResponse, OrderId, ReceivedResponse,
+};
+use crate::web_socket::{CoinBaseWebSocketMessageHandler, Terminate};
+
+/// Internal id of an order, assigned when the order is registered.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct OrderHandle(pub u64);
+
+#[derive(Debug, Clone)]
+pub enum OrderEvent {
+  /// REST response to the order.
+  Placed(Order),
+  /// The REST API refused the order, no further events follow.
+  Rejected(String),
+  Received(ReceivedResponse),
+  Open(OpenResponse),
+  /// Trade in which the order was the maker or the taker.
+  Match(MatchResponse),
+  Change(ChangeResponse),
+  /// Stop order was triggered.
+  Activated(ActiveResponse),
+  /// The order is filled or canceled, no further events follow.
+  Done(DoneResponse),
+}
+
+/// Events of a single order in the order they arrived. Iterating blocks until the next event,
+/// and ends once the order is done or rejected.
+pub struct OrderEvents {
+  receiver: Receiver<OrderEvent>,
+}
+
+impl OrderEvents {
+  pub fn try_next(&self) -> Option<OrderEvent> {
+    self.receiver.try_recv().ok()
+  }
+
+  /// Waits up to `timeout` for the next event, `Err(true)` when no more events will come.
+  pub fn next_timeout(&self, timeout: Duration) -> Result<OrderEvent, bool> {
+    self.receiver.recv_timeout(timeout).map_err(|err| matches!(err, RecvTimeoutError::Disconnected))
+  }
+}
+
+impl Iterator for OrderEvents {
+  type Item = OrderEvent;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    self.receiver.recv().ok()
+  }
+}
+
+#[derive(Default)]
+struct Registry {
+  next_handle: u64,
+  handles: HashMap<String, OrderHandle>,
+  order_ids: HashMap<OrderId, OrderHandle>,
+  senders: HashMap<OrderHandle, Sender<OrderEvent>>,
+}
+
+impl Registry {
+  fn link(&mut self, client_oid: &str, order_id: OrderId) -> Option<OrderHandle> {
+    let handle = *self.handles.get(client_oid)?;
+    self.order_ids.insert(order_id, handle);
+    Some(handle)
+  }
+
+  // Sends the event, forgets the order when it is the last one or nobody listens anymore.
+  fn send(&mut self, handle: OrderHandle, event: OrderEvent) {
+    let last = matches!(event, OrderEvent::Done(_) | OrderEvent::Rejected(_));
+    let delivered = self.senders.get(&handle).is_some_and(|sender| sender.send(event).is_ok());
+    if last || !delivered {
+      self.senders.remove(&handle);
+      self.handles.retain(|_, registered| *registered != handle);
+      self.order_ids.retain(|_, registered| *registered != handle);
+    }
+  }
+
+  fn send_to_order(&mut self, order_id: &OrderId, event: OrderEvent) {
+    if let Some(handle) = self.order_ids.get(order_id).copied() {
+      self.send(handle, event);
+    }
+  }
+}
+
+/// Maps `client_oid`s of own orders to handles and routes REST responses and user channel
+/// messages to the event stream of the order. The `received` message echoes the `client_oid`,
+/// later messages are matched by the order id learned from it or from the REST response.
+/// Clones share the registered orders.
+#[derive(Clone, Default)]
+pub struct OrderRegistry {
+  registry: Arc<Mutex<Registry>>,
+}
+
+impl OrderRegistry {
+  pub fn new() -> Self {
+    OrderRegistry::default()
+  }
+
+  /// Registers an order that is about to be placed with `client_oid`.
+  pub fn register(&self, client_oid: &str) -> (OrderHandle, OrderEvents) {
+    let mut registry = self.registry.lock().unwrap();
+    let handle = OrderHandle(registry.next_handle);
+    registry.next_handle += 1;
+    let (sender, receiver) = crossbeam::unbounded();
+    registry.handles.insert(client_oid.into(), handle);
+    registry.senders.insert(handle, sender);
+    (handle, OrderEvents { receiver })
+  }
+
+  /// Places the order through the client, with a random `client_oid` when it has none. The
+  /// REST response or error is the first event of the stream unless the user channel was faster.
+  pub fn place_order(&self, client: &CoinbaseRestClient, mut order: NewOrder) -> Result<(OrderHandle, OrderEvents), RestError> {
+    let client_oid = order.client_oid.get_or_insert_with(|| Uuid::new_v4().to_string()).clone();
+    let (handle, events) = self.register(&client_oid);
+    match client.place_order(&order) {
+      Ok(placed) => self.placed(&client_oid, placed),
+      Err(err) => {
+        self.registry.lock().unwrap().send(handle, OrderEvent::Rejected(err.to_string()));
+        return Err(err);
+      }
+    }
+    Ok((handle, events))
+  }
+
+  /// Delivers the REST response to an order registered with `client_oid`.
+  pub fn placed(&self, client_oid: &str, order: Order) {
+    let mut registry = self.registry.lock().unwrap();
+    if let Some(handle) = registry.link(client_oid, order.id) {
+      registry.send(handle, OrderEvent::Placed(order));
+    }
+  }
+
+  pub fn handle(&self, client_oid: &str) -> Option<OrderHandle> {
+    self.registry.lock().unwrap().handles.get(client_oid).copied()
+  }
+
+  /// Number of orders that are not done yet.
+  pub fn len(&self) -> usize {
+    self.registry.lock().unwrap().senders.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+}
+
+impl CoinBaseWebSocketMessageHandler for OrderRegistry {
+  fn on_received(&mut self, resp: &ReceivedResponse) -> Result<(), Terminate> {
+    let mut registry = self.registry.lock().unwrap();
+    let handle = match &resp.client_oid {
+      Some(client_oid) => registry.link(client_oid, resp.order_id),
+      None => registry.order_ids.get(&resp.order_id).copied(),
+    };
+    if let Some(handle) = handle {
+      registry.send(handle, OrderEvent::Received(resp.clone()));
+    }
+    Ok(())
+  }
+
+  fn on_open(&mut self, resp: &OpenResponse) -> Result<(), Terminate> {
+    self.registry.lock().unwrap().send_to_order(&resp.order_id, OrderEvent::Open(resp.clone()));
+    Ok(())
+  }
+
+  fn on_match(&mut self, resp: &MatchResponse) -> Result<(), Terminate> {
+    let mut registry = self.registry.lock().unwrap();
+    registry.send_to_order(&resp.maker_order_id, OrderEvent::Match(resp.clone()));
+    registry.send_to_order(&resp.taker_order_id, OrderEvent::Match(resp.clone()));
+    Ok(())
+  }
+
+  fn on_change(&mut self, resp: &ChangeResponse) -> Result<(), Terminate> {
+    self.registry.lock().unwrap().send_to_order(&resp.order_id, OrderEvent::Change(resp.clone()));
+    Ok(())
+  }
+
+  fn on_active(&mut self, resp: &ActiveResponse) -> Result<(), Terminate> {
+    self.registry.lock().unwrap().send_to_order(&resp.order_id, OrderEvent::Activated(resp.clone()));
+    Ok(())
+  }
+
+  fn on_done(&mut self, resp: &DoneResponse) -> Result<(), Terminate> {
+    self.registry.lock().unwrap().send_to_order(&resp.order_id, OrderEvent::Done(resp.clone()));
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{OrderEvent, OrderRegistry};
+  use crate::rest::Order;
+  use crate::web_socket::response::ResponseMessages;
+  use crate::web_socket::dispatch;
+
+  const ORDER_ID: &str = "d0c5340b-6d6c-49d9-b567-48c4bfca13d2";
+
+  fn message(json: &str) -> ResponseMessages {
+    ResponseMessages::from_json(&json.replace("ORDER_ID", ORDER_ID)).unwrap()
+  }
+
+  #[test]
+  fn route_messages_to_order_stream() {
+    let mut registry = OrderRegistry::new();
+    let (handle, events) = registry.register("oid-1");
+    let (_, other) = registry.register("oid-2");
+    assert_eq!(registry.handle("oid-1"), Some(handle));
+
+    let messages = [
+      r#"{"type": "received", "time": "2020-08-31T15:00:00Z", "product_id": "BTC-USD", "sequence": 10,
+          "order_id": "ORDER_ID", "client_oid": "oid-1", "side": "buy", "order_type": "limit", "size": "1", "price": "100"}"#,
+      r#"{"type": "open", "time": "2020-08-31T15:00:00Z", "product_id": "BTC-USD", "sequence": 11,
+          "order_id": "ORDER_ID", "price": "100", "side": "buy", "remaining_size": "1"}"#,
+      r#"{"type": "match", "time": "2020-08-31T15:00:01Z", "product_id": "BTC-USD", "sequence": 12, "trade_id": 5,
+          "maker_order_id": "ORDER_ID", "taker_order_id": "5b0a9f2d-3388-4fd4-a106-b96b1e6d302f", "side": "buy",
+          "size": "1", "price": "100"}"#,
+      r#"{"type": "done", "time": "2020-08-31T15:00:01Z", "product_id": "BTC-USD", "sequence": 13,
+          "order_id": "ORDER_ID", "reason": "filled", "side": "buy"}"#,
+    ];
+    for json in messages.iter() {
+      dispatch(&mut registry, &message(json)).unwrap();
+    }
+    // The REST response arrived late, after the order is done it is dropped.
+    let placed: Order = serde_json::from_str(&format!(r#"{{
+      "id": "{}", "product_id": "BTC-USD", "side": "buy", "type": "limit", "price": "100", "size": "1",
+      "created_at": "2020-08-31T15:00:00Z", "status": "pending"
+    }}"#, ORDER_ID)).unwrap();
+    registry.placed("oid-1", placed);
+
+    let kinds: Vec<&str> = events.map(|event| match event {
+      OrderEvent::Received(_) => "received",
+      OrderEvent::Open(_) => "open",
+      OrderEvent::Match(_) => "match",
+      OrderEvent::Done(_) => "done",
+      _ => "other",
+    }).collect();
+    assert_eq!(kinds, vec!["received", "open", "match", "done"]);
+    assert!(other.try_next().is_none());
+    assert_eq!(registry.len(), 1);
+  }
+}
@@ -0,0 +1,159 @@
+//! Decimal type used for prices and sizes across the crate.
+//!
+//! `BigDecimal` is used by default. With the `rust_decimal` feature enabled numeric fields
+//! switch to the fixed size `rust_decimal::Decimal`, which doesn't allocate and is a lot
+//! faster to parse, at the cost of being limited to 28 decimal places.
+
+#[cfg(not(feature = "rust_decimal"))]
+pub use bigdecimal::BigDecimal as Decimal;
+
+#[cfg(feature = "rust_decimal")]
+pub use rust_decimal::Decimal;
+
+pub use num_traits::Zero;
+
+/// Splits the value into an integer mantissa and the number of decimal places,
+/// `None` when the mantissa doesn't fit into `i64`.
+#[cfg(not(feature = "rust_decimal"))]
+pub fn to_mantissa_and_scale(value: &Decimal) -> Option<(i64, u32)> {
+  use num_traits::{Pow, ToPrimitive};
+
+  let (mantissa, exponent) = value.as_bigint_and_exponent();
+  if exponent < 0 {
+    let mantissa = mantissa * num_bigint::BigInt::from(10).pow((-exponent) as u32);
+    return mantissa.to_i64().map(|mantissa| (mantissa, 0));
+  }
+  mantissa.to_i64().map(|mantissa| (mantissa, exponent as u32))
+}
+
+#[cfg(not(feature = "rust_decimal"))]
+pub fn from_mantissa_and_scale(mantissa: i64, scale: u32) -> Decimal {
+  Decimal::new(num_bigint::BigInt::from(mantissa), scale as i64)
+}
+
+/// Splits the value into an integer mantissa and the number of decimal places,
+/// `None` when the mantissa doesn't fit into `i64`.
+#[cfg(feature = "rust_decimal")]
+pub fn to_mantissa_and_scale(value: &Decimal) -> Option<(i64, u32)> {
+  use std::convert::TryFrom;
+
+  i64::try_from(value.mantissa()).ok().map(|mantissa| (mantissa, value.scale()))
+}
+
+#[cfg(feature = "rust_decimal")]
+pub fn from_mantissa_and_scale(mantissa: i64, scale: u32) -> Decimal {
+  Decimal::new(mantissa, scale)
+}
+
+// Lenient parsing of numeric fields the feed occasionally sends as empty strings or nulls.
+// The default is per thread, so it is set by the web socket worker of a client without
+// affecting other clients or parsing done elsewhere.
+thread_local! {
+  static MALFORMED_DEFAULT: std::cell::RefCell<Option<Decimal>> = const { std::cell::RefCell::new(None) };
+  static REPLACED: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Value that required decimal fields get when they arrive empty or null on the current thread,
+/// `None` (the default) fails the message instead. Optional fields become `None` either way.
+pub fn set_malformed_default(default: Option<Decimal>) {
+  MALFORMED_DEFAULT.with(|current| *current.borrow_mut() = default);
+}
+
+/// Number of empty or null values replaced on the current thread since the last call.
+pub fn take_replaced() -> usize {
+  REPLACED.with(|replaced| replaced.replace(0))
+}
+
+/// Decimal that may be sent as an empty string or null.
+pub(crate) enum LenientDecimal {
+  Value(Decimal),
+  Empty,
+  Null,
+}
+
+impl<'de> serde::Deserialize<'de> for LenientDecimal {
+  fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    use serde::de::{Error, Visitor};
+    use std::str::FromStr;
+
+    struct LenientVisitor;
+    impl<'de> Visitor<'de> for LenientVisitor {
+      type Value = LenientDecimal;
+
+      fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a decimal number, an empty string or null")
+      }
+
+      fn visit_str<E: Error>(self, value: &str) -> Result<Self::Value, E> {
+        if value.trim().is_empty() {
+          return Ok(LenientDecimal::Empty);
+        }
+        Decimal::from_str(value).map(LenientDecimal::Value).map_err(E::custom)
+      }
+
+      fn visit_i64<E: Error>(self, value: i64) -> Result<Self::Value, E> {
+        Ok(LenientDecimal::Value(Decimal::from(value)))
+      }
+
+      fn visit_u64<E: Error>(self, value: u64) -> Result<Self::Value, E> {
+        Ok(LenientDecimal::Value(Decimal::from(value)))
+      }
+
+      fn visit_f64<E: Error>(self, value: f64) -> Result<Self::Value, E> {
+        self.visit_str(value.to_string().as_str())
+      }
+
+      fn visit_unit<E: Error>(self) -> Result<Self::Value, E> {
+        Ok(LenientDecimal::Null)
+      }
+
+      fn visit_none<E: Error>(self) -> Result<Self::Value, E> {
+        Ok(LenientDecimal::Null)
+      }
+
+      fn visit_some<D: serde::Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_any(self)
+      }
+    }
+
+    // Binary formats can't describe themselves and never carry malformed values anyway.
+    if !deserializer.is_human_readable() {
+      return <Decimal as serde::Deserialize>::deserialize(deserializer).map(LenientDecimal::Value);
+    }
+    deserializer.deserialize_any(LenientVisitor)
+  }
+}
+
+impl LenientDecimal {
+  /// Replaces a missing value with the default of the thread, if there is one.
+  pub(crate) fn required<E: serde::de::Error>(self) -> Result<Decimal, E> {
+    if let LenientDecimal::Value(value) = self {
+      return Ok(value);
+    }
+    REPLACED.with(|replaced| replaced.set(replaced.get() + 1));
+    MALFORMED_DEFAULT.with(|default| default.borrow().clone())
+      .ok_or_else(|| E::custom("empty or null decimal"))
+  }
+
+  /// Null is a regular value of an optional field, only an empty string is recorded.
+  pub(crate) fn optional(self) -> Option<Decimal> {
+    match self {
+      LenientDecimal::Value(value) => Some(value),
+      LenientDecimal::Empty => {
+        REPLACED.with(|replaced| replaced.set(replaced.get() + 1));
+        None
+      }
+      LenientDecimal::Null => None,
+    }
+  }
+}
+
+/// For required decimal fields, `#[serde(deserialize_with = "crate::decimal::lenient")]`.
+pub(crate) fn lenient<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Decimal, D::Error> {
+  <LenientDecimal as serde::Deserialize>::deserialize(deserializer)?.required()
+}
+
+/// For optional decimal fields, together with `#[serde(default)]`.
+pub(crate) fn lenient_option<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<Decimal>, D::Error> {
+  Ok(<LenientDecimal as serde::Deserialize>::deserialize(deserializer)?.optional())
+}
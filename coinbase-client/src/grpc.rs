@@ -0,0 +1,474 @@
+//! gRPC gateway that streams the feed received over a single coinbase connection to remote
+//! clients as typed protobuf events, see `proto/market_data.proto` for the schema. The handler
+//! runs on the web socket thread and the server on a tokio runtime of the application:
+//!
+//! ```no_run
+//! use coinbase_client::grpc::GrpcHandler;
+//!
+//! # async fn run() -> Result<(), tonic::transport::Error> {
+//! let handler = GrpcHandler::new(10_000);
+//! let service = handler.service();
+//! // Pass the handler to the web socket client, then serve the events.
+//! service.serve("127.0.0.1:50051".parse().unwrap()).await
+//! # }
+//! ```
+//!
+//! Events are not buffered for clients that aren't connected, clients subscribing to `level2`
+//! receive updates from the time they subscribe and need a book snapshot from elsewhere.
+//! The messages below are written by hand to match the schema, so building the crate doesn't
+//! need `protoc`.
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use chrono::{DateTime, Utc};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::Stream;
+use tonic::codegen::{http, BoxFuture, Service, StdError};
+use tonic::server::{NamedService, ServerStreamingService};
+use tonic::{Request, Response, Status};
+
+use crate::decimal::Decimal;
+use crate::web_socket::{response, CoinBaseWebSocketMessageHandler, Terminate};
+
+const GRPC_ID: &str = "Grpc";
+
+const SUBSCRIBE_PATH: &str = "/coinbase.MarketData/Subscribe";
+
+// @formatter:off
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SubscribeRequest {
+  #[prost(string, repeated, tag = "1")] pub product_ids: Vec<String>,
+  #[prost(string, repeated, tag = "2")] pub channels   : Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum Side { Unspecified = 0, Buy = 1, Sell = 2 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum OrderType { Unspecified = 0, Limit = 1, Market = 2, Stop = 3 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum FinishReason { Unspecified = 0, Filled = 1, Canceled = 2 }
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct MarketEvent {
+  #[prost(string, tag = "1")] pub product_id: String,
+  /// Microseconds since the unix epoch, 0 for snapshots.
+  #[prost(int64 , tag = "2")] pub time_us   : i64,
+  #[prost(int64 , tag = "3")] pub sequence  : i64,
+  #[prost(oneof = "Event", tags = "10, 11, 12, 13, 14, 15, 16, 17, 18")]
+  pub event: Option<Event>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum Event {
+  #[prost(message, tag = "10")] Heartbeat(Heartbeat),
+  #[prost(message, tag = "11")] Ticker   (Ticker   ),
+  #[prost(message, tag = "12")] Snapshot (Snapshot ),
+  #[prost(message, tag = "13")] L2Update (L2Update ),
+  #[prost(message, tag = "14")] Trade    (Trade    ),
+  #[prost(message, tag = "15")] Received (Received ),
+  #[prost(message, tag = "16")] Open     (Open     ),
+  #[prost(message, tag = "17")] Change   (Change   ),
+  #[prost(message, tag = "18")] Done     (Done     ),
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Heartbeat {
+  #[prost(int64, tag = "1")] pub last_trade_id: i64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Ticker {
+  #[prost(int64                      , tag = "1")] pub trade_id : i64,
+  #[prost(string                     , tag = "2")] pub price    : String,
+  #[prost(enumeration = "Side"       , tag = "3")] pub side     : i32,
+  #[prost(string                     , tag = "4")] pub last_size: String,
+  #[prost(string                     , tag = "5")] pub best_bid : String,
+  #[prost(string                     , tag = "6")] pub best_ask : String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PriceLevel {
+  #[prost(string, tag = "1")] pub price: String,
+  #[prost(string, tag = "2")] pub size : String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Snapshot {
+  #[prost(message, repeated, tag = "1")] pub bids: Vec<PriceLevel>,
+  #[prost(message, repeated, tag = "2")] pub asks: Vec<PriceLevel>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct L2Change {
+  #[prost(enumeration = "Side", tag = "1")] pub side : i32,
+  #[prost(string              , tag = "2")] pub price: String,
+  #[prost(string              , tag = "3")] pub size : String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct L2Update {
+  #[prost(message, repeated, tag = "1")] pub changes: Vec<L2Change>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Trade {
+  #[prost(int64               , tag = "1")] pub trade_id      : i64,
+  #[prost(string              , tag = "2")] pub maker_order_id: String,
+  #[prost(string              , tag = "3")] pub taker_order_id: String,
+  #[prost(enumeration = "Side", tag = "4")] pub side          : i32,
+  #[prost(string              , tag = "5")] pub price         : String,
+  #[prost(string              , tag = "6")] pub size          : String,
+  #[prost(bool                , tag = "7")] pub last_match    : bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Received {
+  #[prost(string                   , tag = "1")] pub order_id  : String,
+  #[prost(enumeration = "Side"     , tag = "2")] pub side      : i32,
+  #[prost(enumeration = "OrderType", tag = "3")] pub order_type: i32,
+  #[prost(string                   , tag = "4")] pub size      : String,
+  #[prost(string                   , tag = "5")] pub price     : String,
+  #[prost(string                   , tag = "6")] pub funds     : String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Open {
+  #[prost(string              , tag = "1")] pub order_id      : String,
+  #[prost(enumeration = "Side", tag = "2")] pub side          : i32,
+  #[prost(string              , tag = "3")] pub price         : String,
+  #[prost(string              , tag = "4")] pub remaining_size: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Change {
+  #[prost(string              , tag = "1")] pub order_id: String,
+  #[prost(enumeration = "Side", tag = "2")] pub side    : i32,
+  #[prost(string              , tag = "3")] pub new_size: String,
+  #[prost(string              , tag = "4")] pub old_size: String,
+  #[prost(string              , tag = "5")] pub price   : String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Done {
+  #[prost(string                      , tag = "1")] pub order_id: String,
+  #[prost(enumeration = "Side"        , tag = "2")] pub side    : i32,
+  #[prost(enumeration = "FinishReason", tag = "3")] pub reason  : i32,
+}
+// @formatter:on
+
+fn side(side: response::Side) -> i32 {
+  match side {
+    response::Side::BUY => Side::Buy as i32,
+    response::Side::SELL => Side::Sell as i32,
+  }
+}
+
+fn optional(value: &Option<Decimal>) -> String {
+  value.as_ref().map(|value| value.to_string()).unwrap_or_default()
+}
+
+fn levels(levels: &[Vec<Decimal>]) -> Vec<PriceLevel> {
+  levels.iter()
+    .filter(|level| level.len() >= 2)
+    .map(|level| PriceLevel { price: level[0].to_string(), size: level[1].to_string() })
+    .collect()
+}
+
+/// Event with the coinbase channel it was received on, shared by all subscribers.
+struct Published {
+  channel: &'static str,
+  event: MarketEvent,
+}
+
+/// Handler that converts the feed into `MarketEvent`s for the subscribers of its services.
+/// Subscribers that fall behind by more than `capacity` events are disconnected, so they don't
+/// hold back the feed.
+pub struct GrpcHandler {
+  events: broadcast::Sender<Arc<Published>>,
+}
+
+impl GrpcHandler {
+  pub fn new(capacity: usize) -> Self {
+    let (events, _) = broadcast::channel(capacity);
+    GrpcHandler { events }
+  }
+
+  /// Service streaming the events of this handler, add it to a tonic server or `serve` it.
+  pub fn service(&self) -> MarketDataService {
+    MarketDataService { events: self.events.clone() }
+  }
+
+  fn publish(&self, channel: &'static str, product_id: &str, time: Option<DateTime<Utc>>, sequence: i64, event: Event) -> Result<(), Terminate> {
+    let event = MarketEvent {
+      product_id: product_id.into(),
+      time_us: time.map_or(0, |time| time.timestamp_micros()),
+      sequence,
+      event: Some(event),
+    };
+    // Fails only when nobody is subscribed.
+    let _ = self.events.send(Arc::new(Published { channel, event }));
+    Ok(())
+  }
+}
+
+impl CoinBaseWebSocketMessageHandler for GrpcHandler {
+  fn on_heartbeat(&mut self, resp: &response::HeartBeatResponse) -> Result<(), Terminate> {
+    let event = Event::Heartbeat(Heartbeat { last_trade_id: resp.last_trade_id });
+    self.publish("heartbeat", &resp.product_id, Some(resp.time), resp.sequence, event)
+  }
+
+  fn on_ticker(&mut self, resp: &response::TickerResponse) -> Result<(), Terminate> {
+    let event = Event::Ticker(Ticker {
+      trade_id: resp.trade_id,
+      price: resp.price.to_string(),
+      side: side(resp.side),
+      last_size: resp.last_size.to_string(),
+      best_bid: resp.best_bid.to_string(),
+      best_ask: resp.best_ask.to_string(),
+    });
+    self.publish("ticker", &resp.product_id, Some(resp.time), resp.sequence, event)
+  }
+
+  fn on_snapshot(&mut self, resp: &response::SnapshotResponse) -> Result<(), Terminate> {
+    let event = Event::Snapshot(Snapshot { bids: levels(&resp.bids), asks: levels(&resp.asks) });
+    self.publish("level2", &resp.product_id, None, 0, event)
+  }
+
+  fn on_l2_update(&mut self, resp: &response::L2UpdateResponse) -> Result<(), Terminate> {
+    let changes = resp.changes.iter()
+      .map(|change| L2Change { side: side(change.side), price: change.price.to_string(), size: change.size.to_string() })
+      .collect();
+    self.publish("level2", &resp.product_id, Some(resp.time), 0, Event::L2Update(L2Update { changes }))
+  }
+
+  fn on_match(&mut self, resp: &response::MatchResponse) -> Result<(), Terminate> {
+    let event = Event::Trade(Trade {
+      trade_id: resp.trade_id,
+      maker_order_id: resp.maker_order_id.to_string(),
+      taker_order_id: resp.taker_order_id.to_string(),
+      side: side(resp.side),
+      price: resp.price.to_string(),
+      size: resp.size.to_string(),
+      last_match: false,
+    });
+    self.publish("matches", &resp.product_id, Some(resp.time), resp.sequence, event)
+  }
+
+  fn on_received(&mut self, resp: &response::ReceivedResponse) -> Result<(), Terminate> {
+    let order_type = match resp.order_type {
+      response::OrderType::LIMIT => OrderType::Limit,
+      response::OrderType::MARKET => OrderType::Market,
+      response::OrderType::STOP => OrderType::Stop,
+    };
+    let event = Event::Received(Received {
+      order_id: resp.order_id.to_string(),
+      side: side(resp.side),
+      order_type: order_type as i32,
+      size: optional(&resp.size),
+      price: optional(&resp.price),
+      funds: optional(&resp.funds),
+    });
+    self.publish("full", &resp.product_id, Some(resp.time), resp.sequence, event)
+  }
+
+  fn on_open(&mut self, resp: &response::OpenResponse) -> Result<(), Terminate> {
+    let event = Event::Open(Open {
+      order_id: resp.order_id.to_string(),
+      side: side(resp.side),
+      price: resp.price.to_string(),
+      remaining_size: resp.remaining_size.to_string(),
+    });
+    self.publish("full", &resp.product_id, Some(resp.time), resp.sequence, event)
+  }
+
+  fn on_change(&mut self, resp: &response::ChangeResponse) -> Result<(), Terminate> {
+    let event = Event::Change(Change {
+      order_id: resp.order_id.to_string(),
+      side: side(resp.side),
+      new_size: resp.new_size.to_string(),
+      old_size: resp.old_size.to_string(),
+      price: optional(&resp.price),
+    });
+    self.publish("full", &resp.product_id, Some(resp.time), resp.sequence, event)
+  }
+
+  fn on_done(&mut self, resp: &response::DoneResponse) -> Result<(), Terminate> {
+    let reason = match resp.reason {
+      response::FinishReason::FILLED => FinishReason::Filled,
+      response::FinishReason::CANCELED => FinishReason::Canceled,
+    };
+    let event = Event::Done(Done { order_id: resp.order_id.to_string(), side: side(resp.side), reason: reason as i32 });
+    self.publish("full", &resp.product_id, Some(resp.time), resp.sequence, event)
+  }
+
+  fn on_last_match(&mut self, resp: &response::LastMatchResponse) -> Result<(), Terminate> {
+    let event = Event::Trade(Trade {
+      trade_id: resp.trade_id,
+      maker_order_id: resp.maker_order_id.to_string(),
+      taker_order_id: resp.taker_order_id.to_string(),
+      side: side(resp.side),
+      price: resp.price.to_string(),
+      size: resp.size.to_string(),
+      last_match: true,
+    });
+    self.publish("matches", &resp.product_id, Some(resp.time), resp.sequence, event)
+  }
+}
+
+/// Events of one subscriber, filtered by its request.
+pub struct EventStream {
+  events: BroadcastStream<Arc<Published>>,
+  product_ids: HashSet<String>,
+  channels: HashSet<String>,
+  done: bool,
+}
+
+impl Stream for EventStream {
+  type Item = Result<MarketEvent, Status>;
+
+  fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    if self.done {
+      return Poll::Ready(None);
+    }
+    loop {
+      match ready!(Pin::new(&mut self.events).poll_next(cx)) {
+        Some(Ok(published)) => {
+          let accepted = (self.channels.is_empty() || self.channels.contains(published.channel))
+            && (self.product_ids.is_empty() || self.product_ids.contains(&published.event.product_id));
+          if accepted {
+            return Poll::Ready(Some(Ok(published.event.clone())));
+          }
+        }
+        Some(Err(BroadcastStreamRecvError::Lagged(missed))) => {
+          tracing::warn!(target: GRPC_ID, "Disconnecting subscriber that missed {} events.", missed);
+          self.done = true;
+          return Poll::Ready(Some(Err(Status::resource_exhausted(format!("Subscriber fell behind by {} events", missed)))));
+        }
+        None => return Poll::Ready(None),
+      }
+    }
+  }
+}
+
+/// The `coinbase.MarketData` gRPC service.
+#[derive(Clone)]
+pub struct MarketDataService {
+  events: broadcast::Sender<Arc<Published>>,
+}
+
+impl MarketDataService {
+  pub fn subscribe(&self, request: SubscribeRequest) -> EventStream {
+    EventStream {
+      events: BroadcastStream::new(self.events.subscribe()),
+      product_ids: request.product_ids.into_iter().collect(),
+      channels: request.channels.into_iter().map(|channel| channel.to_lowercase()).collect(),
+      done: false,
+    }
+  }
+
+  /// Serves only this service on the address until the server fails.
+  pub async fn serve(self, addr: SocketAddr) -> Result<(), tonic::transport::Error> {
+    tracing::info!(target: GRPC_ID, "Serving market data on {}", addr);
+    tonic::transport::Server::builder().add_service(self).serve(addr).await
+  }
+}
+
+impl NamedService for MarketDataService {
+  const NAME: &'static str = "coinbase.MarketData";
+}
+
+struct Subscribe(MarketDataService);
+
+impl ServerStreamingService<SubscribeRequest> for Subscribe {
+  type Response = MarketEvent;
+  type ResponseStream = EventStream;
+  type Future = BoxFuture<Response<EventStream>, Status>;
+
+  fn call(&mut self, request: Request<SubscribeRequest>) -> Self::Future {
+    let stream = self.0.subscribe(request.into_inner());
+    Box::pin(async move { Ok(Response::new(stream)) })
+  }
+}
+
+impl<B> Service<http::Request<B>> for MarketDataService
+  where
+    B: tonic::codegen::Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+  type Response = http::Response<tonic::body::BoxBody>;
+  type Error = Infallible;
+  type Future = BoxFuture<Self::Response, Self::Error>;
+
+  fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    Poll::Ready(Ok(()))
+  }
+
+  fn call(&mut self, request: http::Request<B>) -> Self::Future {
+    if request.uri().path() != SUBSCRIBE_PATH {
+      return Box::pin(async move { Ok(Status::unimplemented("Unknown method").into_http()) });
+    }
+    let service = Subscribe(self.clone());
+    Box::pin(async move {
+      let mut grpc = tonic::server::Grpc::new(tonic::codec::ProstCodec::default());
+      Ok(grpc.server_streaming(service, request).await)
+    })
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use tonic::codegen::http::uri::PathAndQuery;
+
+  use super::{Event, GrpcHandler, MarketEvent, Side, SubscribeRequest, SUBSCRIBE_PATH};
+  use crate::web_socket::CoinBaseWebSocketMessageHandler;
+
+  #[tokio::test]
+  async fn stream_subscribed_events() {
+    let mut handler = GrpcHandler::new(16);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let incoming = tokio_stream::wrappers::TcpListenerStream::new(listener);
+    tokio::spawn(tonic::transport::Server::builder().add_service(handler.service()).serve_with_incoming(incoming));
+
+    let channel = tonic::transport::Endpoint::from_shared(format!("http://{}", addr)).unwrap().connect().await.unwrap();
+    let mut client = tonic::client::Grpc::new(channel);
+    client.ready().await.unwrap();
+    let request = SubscribeRequest { product_ids: vec!["BTC-USD".into()], channels: vec!["matches".into()] };
+    let codec = tonic::codec::ProstCodec::<SubscribeRequest, MarketEvent>::default();
+    let response = client.server_streaming(tonic::Request::new(request), PathAndQuery::from_static(SUBSCRIBE_PATH), codec);
+    let mut events = response.await.unwrap().into_inner();
+
+    let trade = |product_id: &str, trade_id: i64| serde_json::from_str(&format!(r#"{{
+      "trade_id": {}, "sequence": 50, "maker_order_id": "ac928c66-ca53-498f-9c13-a110027a60e8",
+      "taker_order_id": "132fb6ae-456b-4654-b4e0-d681ac05cea1", "time": "2014-11-07T08:19:27.028459Z",
+      "product_id": "{}", "size": "5.23512", "price": "400.23", "side": "sell"
+    }}"#, trade_id, product_id)).unwrap();
+    let heartbeat = serde_json::from_str(r#"{
+      "sequence": 90, "last_trade_id": 20, "product_id": "BTC-USD", "time": "2014-11-07T08:19:28.464459Z"
+    }"#).unwrap();
+    handler.on_match(&trade("ETH-USD", 1)).unwrap();
+    handler.on_heartbeat(&heartbeat).unwrap();
+    handler.on_match(&trade("BTC-USD", 2)).unwrap();
+
+    let event = events.message().await.unwrap().unwrap();
+    assert_eq!((event.product_id.as_str(), event.sequence, event.time_us), ("BTC-USD", 50, 1_415_348_367_028_459));
+    match event.event {
+      Some(Event::Trade(trade)) => {
+        assert_eq!((trade.trade_id, trade.price.as_str(), trade.size.as_str()), (2, "400.23", "5.23512"));
+        assert_eq!(trade.side, Side::Sell as i32);
+      }
+      _ => panic!("Unexpected event"),
+    }
+  }
+}
@@ -0,0 +1,229 @@
+//! Local store of candles downloaded from the REST API, so backtests read the same history on
+//! every run and only the candles missing from the store are downloaded:
+//!
+//! ```no_run
+//! use std::path::Path;
+//! use chrono::{Duration, Utc};
+//! use coinbase_client::history::CandleHistory;
+//! use coinbase_client::rest::{CoinbaseRestClient, Granularity};
+//!
+//! let mut history = CandleHistory::open(Path::new("candles"), CoinbaseRestClient::production()).unwrap();
+//! let now = Utc::now();
+//! let candles = history.get("BTC-USD", Granularity::OneDay, now - Duration::days(365)..now).unwrap();
+//! ```
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::rest::{CoinbaseRestClient, Granularity, HistoricalCandle, RestError};
+
+const CANDLE_HISTORY_ID: &str = "CandleHistory";
+
+// Most candles the endpoint returns per request.
+const CANDLES_PER_REQUEST: i64 = 300;
+
+/// Where candles are downloaded from, `CoinbaseRestClient::get_candles` unless replaced.
+pub trait CandleSource {
+  /// Candles starting within `[start, end)`, ordered from oldest to newest.
+  fn candles(&mut self, product_id: &str, granularity: Granularity, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<HistoricalCandle>, RestError>;
+}
+
+impl CandleSource for CoinbaseRestClient {
+  fn candles(&mut self, product_id: &str, granularity: Granularity, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<HistoricalCandle>, RestError> {
+    self.get_candles(product_id, granularity, start, end)
+  }
+}
+
+impl<F> CandleSource for F
+  where F: FnMut(&str, Granularity, DateTime<Utc>, DateTime<Utc>) -> Result<Vec<HistoricalCandle>, RestError> {
+  fn candles(&mut self, product_id: &str, granularity: Granularity, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<HistoricalCandle>, RestError> {
+    self(product_id, granularity, start, end)
+  }
+}
+
+#[derive(Error, Debug)]
+pub enum HistoryError {
+  #[error("Could not download candles: {0}")]
+  Rest(#[from] RestError),
+
+  #[error("Could not read or write the candle store: {0}")]
+  Io(#[from] io::Error),
+}
+
+/// Candles of one product and granularity stored in a file, with the period they cover. Periods
+/// without trades have no candle, the covered period tells them apart from missing data.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct StoredCandles {
+  from: DateTime<Utc>,
+  to: DateTime<Utc>,
+  candles: Vec<HistoricalCandle>,
+}
+
+/// Directory with a `candles_<product>_<granularity seconds>.json` file per product and
+/// granularity. Every store covers a single continuous period, requests before or after it
+/// download the missing candles and extend it. Only finished candles are stored and returned.
+pub struct CandleHistory<S: CandleSource = CoinbaseRestClient> {
+  directory: PathBuf,
+  source: S,
+  request_interval: Duration,
+  last_request: Option<Instant>,
+}
+
+impl CandleHistory<CoinbaseRestClient> {
+  pub fn open(directory: &Path, client: CoinbaseRestClient) -> io::Result<Self> {
+    CandleHistory::with_source(directory, client)
+  }
+}
+
+impl<S: CandleSource> CandleHistory<S> {
+  pub fn with_source(directory: &Path, source: S) -> io::Result<Self> {
+    fs::create_dir_all(directory)?;
+    Ok(CandleHistory { directory: directory.to_path_buf(), source, request_interval: Duration::from_millis(350), last_request: None })
+  }
+
+  /// Time between two downloads, 350ms by default to stay within the public rate limit.
+  pub fn request_interval(mut self, interval: Duration) -> Self {
+    self.request_interval = interval;
+    self
+  }
+
+  /// Candles of the product starting within the range, ordered from oldest to newest. Candles
+  /// that are not stored yet are downloaded and stored first.
+  pub fn get(&mut self, product_id: &str, granularity: Granularity, range: Range<DateTime<Utc>>) -> Result<Vec<HistoricalCandle>, HistoryError> {
+    let now = granularity.floor(Utc::now());
+    let start = granularity.floor(range.start).min(now);
+    let end = ceil(granularity, range.end).min(now);
+    let mut stored = self.load(product_id, granularity)?;
+    let missing = match &stored {
+      None => vec![(start, end)],
+      Some(stored) => vec![(start, stored.from), (stored.to, end)],
+    };
+    for (from, to) in missing.into_iter().filter(|(from, to)| from < to) {
+      let candles = self.download(product_id, granularity, from, to)?;
+      let merged = match stored.take() {
+        Some(mut stored) => {
+          stored.candles.extend(candles);
+          stored.candles.sort_by_key(|candle| candle.time);
+          stored.candles.dedup_by_key(|candle| candle.time);
+          StoredCandles { from: stored.from.min(from), to: stored.to.max(to), candles: stored.candles }
+        }
+        None => StoredCandles { from, to, candles },
+      };
+      self.save(product_id, granularity, &merged)?;
+      stored = Some(merged);
+    }
+    let candles = stored.map(|stored| stored.candles).unwrap_or_default();
+    Ok(candles.into_iter().filter(|candle| candle.time >= range.start && candle.time < range.end).collect())
+  }
+
+  /// Period covered by the stored candles of the product.
+  pub fn stored_range(&self, product_id: &str, granularity: Granularity) -> io::Result<Option<Range<DateTime<Utc>>>> {
+    Ok(self.load(product_id, granularity)?.map(|stored| stored.from..stored.to))
+  }
+
+  fn download(&mut self, product_id: &str, granularity: Granularity, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<Vec<HistoricalCandle>, RestError> {
+    tracing::info!(target: CANDLE_HISTORY_ID, "Downloading {:?} candles of {} from {} to {}", granularity, product_id, from, to);
+    let page = chrono::Duration::seconds(granularity.seconds() * CANDLES_PER_REQUEST);
+    let mut candles = Vec::new();
+    let mut page_start = from;
+    while page_start < to {
+      let page_end = (page_start + page).min(to);
+      if let Some(last_request) = self.last_request {
+        let elapsed = last_request.elapsed();
+        if elapsed < self.request_interval {
+          thread::sleep(self.request_interval - elapsed);
+        }
+      }
+      self.last_request = Some(Instant::now());
+      candles.extend(self.source.candles(product_id, granularity, page_start, page_end)?);
+      page_start = page_end;
+    }
+    Ok(candles)
+  }
+
+  fn path(&self, product_id: &str, granularity: Granularity) -> PathBuf {
+    self.directory.join(format!("candles_{}_{}.json", product_id, granularity.seconds()))
+  }
+
+  fn load(&self, product_id: &str, granularity: Granularity) -> io::Result<Option<StoredCandles>> {
+    match fs::read(self.path(product_id, granularity)) {
+      Ok(content) => Ok(Some(serde_json::from_slice(&content)?)),
+      Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+      Err(err) => Err(err),
+    }
+  }
+
+  /// Writes into a temporary file that is renamed over the previous one, so a crash while
+  /// saving leaves the previous candles.
+  fn save(&self, product_id: &str, granularity: Granularity, stored: &StoredCandles) -> io::Result<()> {
+    let path = self.path(product_id, granularity);
+    let tmp_path = path.with_extension("json.tmp");
+    let mut file = File::create(&tmp_path)?;
+    serde_json::to_writer(&mut file, stored)?;
+    file.flush()?;
+    fs::rename(tmp_path, path)
+  }
+}
+
+/// Start of the first candle at or after `time`.
+fn ceil(granularity: Granularity, time: DateTime<Utc>) -> DateTime<Utc> {
+  let floor = granularity.floor(time);
+  if floor == time { floor } else { floor + chrono::Duration::seconds(granularity.seconds()) }
+}
+
+#[cfg(test)]
+mod test {
+  use std::cell::RefCell;
+  use std::fs;
+  use std::time::Duration;
+
+  use chrono::{DateTime, Utc};
+
+  use super::CandleHistory;
+  use crate::decimal::Decimal;
+  use crate::rest::{Granularity, HistoricalCandle, RestError};
+
+  fn day(day: u32) -> DateTime<Utc> {
+    format!("2020-08-{:02}T00:00:00Z", day).parse().unwrap()
+  }
+
+  #[test]
+  fn download_only_missing_candles() {
+    let directory = std::env::temp_dir().join(format!("coinbase-history-{}", std::process::id()));
+    let requests = RefCell::new(Vec::new());
+    let source = |_: &str, granularity: Granularity, start: DateTime<Utc>, end: DateTime<Utc>| -> Result<Vec<HistoricalCandle>, RestError> {
+      requests.borrow_mut().push((start, end));
+      let mut candles = Vec::new();
+      let mut time = start;
+      while time < end {
+        // No trades on the 3rd.
+        if time != day(3) {
+          let price = Decimal::from(time.timestamp() / 86400);
+          candles.push(HistoricalCandle { time, low: price.clone(), high: price.clone(), open: price.clone(), close: price, volume: Decimal::from(1) });
+        }
+        time += chrono::Duration::seconds(granularity.seconds());
+      }
+      Ok(candles)
+    };
+    let mut history = CandleHistory::with_source(&directory, source).unwrap().request_interval(Duration::ZERO);
+
+    assert_eq!(history.get("BTC-USD", Granularity::OneDay, day(2)..day(6)).unwrap().len(), 3);
+    // Extends the store on both sides, the day without a candle is not downloaded again.
+    let candles = history.get("BTC-USD", Granularity::OneDay, day(1)..day(7)).unwrap();
+    assert_eq!(candles.iter().map(|candle| candle.time).collect::<Vec<_>>(), vec![day(1), day(2), day(4), day(5), day(6)]);
+    // Time within a candle rounds to the candle.
+    assert_eq!(history.get("BTC-USD", Granularity::OneDay, day(3)..day(4) + chrono::Duration::hours(1)).unwrap().len(), 1);
+    assert_eq!(history.stored_range("BTC-USD", Granularity::OneDay).unwrap(), Some(day(1)..day(7)));
+    drop(history);
+
+    assert_eq!(requests.into_inner(), vec![(day(2), day(6)), (day(1), day(2)), (day(6), day(7))]);
+    fs::remove_dir_all(directory).unwrap();
+  }
+}
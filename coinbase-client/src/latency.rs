@@ -0,0 +1,274 @@
+//! Exchange latency of own orders: the time from submitting an order until the REST response
+//! and the `received` and `open` messages of the user channel arrive. The tracker is shared by
+//! the thread placing orders and the web socket client:
+//!
+//! ```no_run
+//! use coinbase_client::latency::OrderLatencyTracker;
+//! use coinbase_client::rest::{CoinbaseRestClient, OrderBuilder};
+//! use coinbase_client::web_socket::response::Side;
+//!
+//! let tracker = OrderLatencyTracker::new();
+//! // Register `tracker.clone()` as a handler of a client subscribed to the user channel.
+//! let client = CoinbaseRestClient::production().with_profile("main").unwrap();
+//! let order = OrderBuilder::limit("BTC-USD", Side::BUY, "10000".parse().unwrap(), "0.01".parse().unwrap())
+//!   .client_oid("9fd3bd09-3a2f-4fd8-9a31-4f0d1d5ab0c3")
+//!   .build()
+//!   .unwrap();
+//! tracker.place_order(&client, &order).unwrap();
+//! println!("{:?}", tracker.report().received.quantile(0.99));
+//! ```
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+
+use crate::rest::{CoinbaseRestClient, NewOrder, Order, RestError};
+use crate::web_socket::response::{DoneResponse, OpenResponse, OrderId, ReceivedResponse};
+use crate::web_socket::{CoinBaseWebSocketMessageHandler, Histogram, Terminate};
+
+/// Latencies of one order, measured with the local monotonic clock from its submission.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderLatency {
+  pub client_oid: String,
+  pub order_id: Option<OrderId>,
+  pub product_id: String,
+  pub submitted_at: DateTime<Utc>,
+  /// Until the REST response arrived.
+  pub acknowledged: Option<Duration>,
+  /// Until the `received` message arrived.
+  pub received: Option<Duration>,
+  /// Until the `open` message arrived, `None` for orders that never rested on the book.
+  pub open: Option<Duration>,
+}
+
+/// Distributions of the latencies of completed orders.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LatencyReport {
+  pub orders: u64,
+  pub acknowledged: Histogram,
+  pub received: Histogram,
+  pub open: Histogram,
+}
+
+struct Pending {
+  submitted: Instant,
+  latency: OrderLatency,
+  // Whether the order opened or was done before the REST response arrived.
+  settled: bool,
+}
+
+#[derive(Default)]
+struct Tracker {
+  pending: HashMap<String, Pending>,
+  client_oids: HashMap<OrderId, String>,
+  completed: Vec<OrderLatency>,
+  report: LatencyReport,
+}
+
+impl Tracker {
+  fn link(&mut self, client_oid: &str, order_id: OrderId) {
+    if let Some(pending) = self.pending.get_mut(client_oid) {
+      pending.latency.order_id = Some(order_id);
+      self.client_oids.insert(order_id, client_oid.into());
+    }
+  }
+
+  // Completes the order once it settled and the REST response arrived.
+  fn complete_if_done(&mut self, client_oid: &str) {
+    let done = self.pending.get(client_oid).is_some_and(|pending| pending.settled && pending.latency.acknowledged.is_some());
+    if !done {
+      return;
+    }
+    let latency = self.pending.remove(client_oid).unwrap().latency;
+    if let Some(order_id) = latency.order_id {
+      self.client_oids.remove(&order_id);
+    }
+    self.report.orders += 1;
+    let histograms = [
+      (&mut self.report.acknowledged, latency.acknowledged),
+      (&mut self.report.received, latency.received),
+      (&mut self.report.open, latency.open),
+    ];
+    for (histogram, duration) in histograms {
+      if let Some(duration) = duration {
+        histogram.record(duration);
+      }
+    }
+    self.completed.push(latency);
+  }
+}
+
+/// Correlates submitted orders with their REST response and user channel messages by
+/// `client_oid`, which the `received` message echoes. Later messages only carry the order id,
+/// which is learned from the `received` message or the REST response. Orders without a
+/// `client_oid` are not tracked. An order completes once the REST response arrived and it
+/// opened or was done, clones share the same orders.
+#[derive(Clone, Default)]
+pub struct OrderLatencyTracker {
+  tracker: Arc<Mutex<Tracker>>,
+}
+
+impl OrderLatencyTracker {
+  pub fn new() -> Self {
+    OrderLatencyTracker::default()
+  }
+
+  /// Places the order through the client and measures its latencies.
+  pub fn place_order(&self, client: &CoinbaseRestClient, order: &NewOrder) -> Result<Order, RestError> {
+    self.submitted(order, Instant::now());
+    let result = client.place_order(order);
+    let now = Instant::now();
+    if let Some(client_oid) = &order.client_oid {
+      match &result {
+        Ok(placed) => self.acknowledged(client_oid, placed, now),
+        Err(_) => {
+          self.tracker.lock().unwrap().pending.remove(client_oid);
+        }
+      }
+    }
+    result
+  }
+
+  /// Starts measuring an order submitted at `now`, for orders placed without `place_order`.
+  pub fn submitted(&self, order: &NewOrder, now: Instant) {
+    let client_oid = match &order.client_oid {
+      Some(client_oid) => client_oid,
+      None => return,
+    };
+    let latency = OrderLatency {
+      client_oid: client_oid.clone(),
+      order_id: None,
+      product_id: order.product_id.clone(),
+      submitted_at: Utc::now(),
+      acknowledged: None,
+      received: None,
+      open: None,
+    };
+    let pending = Pending { submitted: now, latency, settled: false };
+    self.tracker.lock().unwrap().pending.insert(client_oid.clone(), pending);
+  }
+
+  /// Records the REST response to the order submitted with `client_oid`, arrived at `now`.
+  pub fn acknowledged(&self, client_oid: &str, order: &Order, now: Instant) {
+    let mut tracker = self.tracker.lock().unwrap();
+    if let Some(pending) = tracker.pending.get_mut(client_oid) {
+      pending.latency.acknowledged = Some(now.duration_since(pending.submitted));
+    }
+    tracker.link(client_oid, order.id);
+    tracker.complete_if_done(client_oid);
+  }
+
+  pub(crate) fn received_at(&self, resp: &ReceivedResponse, now: Instant) {
+    let client_oid = match &resp.client_oid {
+      Some(client_oid) => client_oid,
+      None => return,
+    };
+    let mut tracker = self.tracker.lock().unwrap();
+    if let Some(pending) = tracker.pending.get_mut(client_oid) {
+      pending.latency.received = Some(now.duration_since(pending.submitted));
+    }
+    tracker.link(client_oid, resp.order_id);
+  }
+
+  pub(crate) fn settled_at(&self, order_id: &OrderId, opened: bool, now: Instant) {
+    let mut tracker = self.tracker.lock().unwrap();
+    let client_oid = match tracker.client_oids.get(order_id) {
+      Some(client_oid) => client_oid.clone(),
+      None => return,
+    };
+    if let Some(pending) = tracker.pending.get_mut(&client_oid) {
+      if opened {
+        pending.latency.open = Some(now.duration_since(pending.submitted));
+      }
+      pending.settled = true;
+    }
+    tracker.complete_if_done(&client_oid);
+  }
+
+  /// Number of orders still waiting for their REST response or user channel messages.
+  pub fn pending(&self) -> usize {
+    self.tracker.lock().unwrap().pending.len()
+  }
+
+  pub fn report(&self) -> LatencyReport {
+    self.tracker.lock().unwrap().report.clone()
+  }
+
+  /// Latencies of the orders completed since the previous call.
+  pub fn take_completed(&self) -> Vec<OrderLatency> {
+    std::mem::take(&mut self.tracker.lock().unwrap().completed)
+  }
+}
+
+impl CoinBaseWebSocketMessageHandler for OrderLatencyTracker {
+  fn on_received(&mut self, resp: &ReceivedResponse) -> Result<(), Terminate> {
+    self.received_at(resp, Instant::now());
+    Ok(())
+  }
+
+  fn on_open(&mut self, resp: &OpenResponse) -> Result<(), Terminate> {
+    self.settled_at(&resp.order_id, true, Instant::now());
+    Ok(())
+  }
+
+  fn on_done(&mut self, resp: &DoneResponse) -> Result<(), Terminate> {
+    self.settled_at(&resp.order_id, false, Instant::now());
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::time::{Duration, Instant};
+
+  use super::OrderLatencyTracker;
+  use crate::rest::{Order, OrderBuilder};
+  use crate::web_socket::response::{ReceivedResponse, Side};
+
+  const ORDER_ID: &str = "d0c5340b-6d6c-49d9-b567-48c4bfca13d2";
+
+  fn received(client_oid: &str) -> ReceivedResponse {
+    serde_json::from_str(&format!(r#"{{
+      "time": "2020-08-31T15:00:00Z", "product_id": "BTC-USD", "sequence": 10, "order_id": "{}",
+      "client_oid": "{}", "side": "buy", "order_type": "limit", "size": "0.01", "price": "10000"
+    }}"#, ORDER_ID, client_oid)).unwrap()
+  }
+
+  fn placed() -> Order {
+    serde_json::from_str(&format!(r#"{{
+      "id": "{}", "product_id": "BTC-USD", "side": "buy", "type": "limit", "price": "10000", "size": "0.01",
+      "created_at": "2020-08-31T15:00:00Z", "status": "pending"
+    }}"#, ORDER_ID)).unwrap()
+  }
+
+  #[test]
+  fn correlate_order_messages() {
+    let tracker = OrderLatencyTracker::new();
+    let order = OrderBuilder::limit("BTC-USD", Side::BUY, "10000".parse().unwrap(), "0.01".parse().unwrap())
+      .client_oid("oid-1")
+      .build()
+      .unwrap();
+    let start = Instant::now();
+    let at = |millis: u64| start + Duration::from_millis(millis);
+
+    tracker.submitted(&order, start);
+    tracker.received_at(&received("oid-1"), at(3));
+    tracker.settled_at(&ORDER_ID.parse().unwrap(), true, at(4));
+    // Not complete until the REST response arrives, even though it comes after the open message.
+    assert_eq!(tracker.pending(), 1);
+    tracker.acknowledged("oid-1", &placed(), at(6));
+    assert_eq!(tracker.pending(), 0);
+
+    let completed = tracker.take_completed();
+    assert_eq!(completed.len(), 1);
+    assert_eq!(completed[0].order_id, Some(ORDER_ID.parse().unwrap()));
+    assert_eq!(
+      (completed[0].acknowledged, completed[0].received, completed[0].open),
+      (Some(Duration::from_millis(6)), Some(Duration::from_millis(3)), Some(Duration::from_millis(4))),
+    );
+    let report = tracker.report();
+    assert_eq!((report.orders, report.open.count()), (1, 1));
+    assert!(tracker.take_completed().is_empty());
+  }
+}
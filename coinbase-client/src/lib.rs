@@ -1,2 +1,22 @@
+// Decimal values are cloned and borrowed for arithmetic generically, which is
+// unnecessary with the `Copy` decimal of `rust_decimal`.
+#![cfg_attr(feature = "rust_decimal", allow(clippy::clone_on_copy, clippy::op_ref))]
+
+pub mod analytics;
+pub mod auth;
+pub mod conversion;
+pub mod correlation;
+pub mod decimal;
+pub mod history;
+pub mod latency;
 pub mod web_socket;
-pub mod rest;
\ No newline at end of file
+pub mod rest;
+pub mod order_book;
+pub mod sinks;
+pub mod portfolio;
+pub mod rebroadcast;
+pub mod reconcile;
+pub mod replay;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod testing;
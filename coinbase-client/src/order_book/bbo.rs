@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::decimal::Decimal;
+use crate::web_socket::response::TickerResponse;
+use crate::web_socket::{CoinBaseWebSocketMessageHandler, Terminate};
+
+/// Best bid and offer of a product together with the last trade price.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct Bbo {
+  pub product_id: String,
+  pub time: DateTime<Utc>,
+  pub bid: Decimal,
+  pub ask: Decimal,
+  pub last_price: Decimal,
+}
+
+impl Bbo {
+  pub fn spread(&self) -> Decimal {
+    &self.ask - &self.bid
+  }
+}
+
+pub trait BboSink {
+  fn on_bbo_change(&mut self, bbo: &Bbo) -> Result<(), Terminate>;
+}
+
+impl<F: FnMut(&Bbo) -> Result<(), Terminate>> BboSink for F {
+  fn on_bbo_change(&mut self, bbo: &Bbo) -> Result<(), Terminate> {
+    self(bbo)
+  }
+}
+
+/// Spread statistics over all BBO changes seen for a product.
+#[derive(Debug, Clone)]
+pub struct SpreadStats {
+  pub count: u64,
+  pub min: Decimal,
+  pub max: Decimal,
+  pub last: Decimal,
+  sum: Decimal,
+}
+
+impl SpreadStats {
+  fn new(spread: Decimal) -> Self {
+    SpreadStats { count: 1, min: spread.clone(), max: spread.clone(), last: spread.clone(), sum: spread }
+  }
+
+  fn add(&mut self, spread: Decimal) {
+    self.count += 1;
+    if spread < self.min {
+      self.min = spread.clone();
+    }
+    if spread > self.max {
+      self.max = spread.clone();
+    }
+    self.sum = &self.sum + &spread;
+    self.last = spread;
+  }
+
+  pub fn mean(&self) -> Decimal {
+    &self.sum / Decimal::from(self.count)
+  }
+}
+
+/// Tracks best bid and offer of every product from the `ticker` channel and notifies the sink
+/// when either side changes. Much cheaper than maintaining full level2 books when only the top
+/// of the book is needed, with the caveat that ticker messages are only sent on trades.
+pub struct BboTracker<S: BboSink> {
+  bbos: HashMap<String, Bbo>,
+  spreads: HashMap<String, SpreadStats>,
+  sink: S,
+}
+
+impl<S: BboSink> BboTracker<S> {
+  pub fn new(sink: S) -> Self {
+    BboTracker { bbos: HashMap::new(), spreads: HashMap::new(), sink }
+  }
+
+  pub fn bbo(&self, product_id: &str) -> Option<&Bbo> {
+    self.bbos.get(product_id)
+  }
+
+  pub fn spread_stats(&self, product_id: &str) -> Option<&SpreadStats> {
+    self.spreads.get(product_id)
+  }
+}
+
+impl<S: BboSink> CoinBaseWebSocketMessageHandler for BboTracker<S> {
+  fn on_ticker(&mut self, resp: &TickerResponse) -> Result<(), Terminate> {
+    let bbo = Bbo {
+      product_id: resp.product_id.clone(),
+      time: resp.time,
+      bid: resp.best_bid.clone(),
+      ask: resp.best_ask.clone(),
+      last_price: resp.price.clone(),
+    };
+    let changed = match self.bbos.get(&resp.product_id) {
+      Some(previous) => previous.bid != bbo.bid || previous.ask != bbo.ask,
+      None => true,
+    };
+    if !changed {
+      // Keep the last trade price up to date even though the sink isn't notified.
+      self.bbos.insert(resp.product_id.clone(), bbo);
+      return Ok(());
+    }
+
+    let spread = bbo.spread();
+    match self.spreads.get_mut(&resp.product_id) {
+      Some(stats) => stats.add(spread),
+      None => {
+        self.spreads.insert(resp.product_id.clone(), SpreadStats::new(spread));
+      }
+    }
+    self.sink.on_bbo_change(&bbo)?;
+    self.bbos.insert(resp.product_id.clone(), bbo);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::str::FromStr;
+
+  use super::{Bbo, BboTracker};
+  use crate::decimal::Decimal;
+  use crate::web_socket::response::TickerResponse;
+  use crate::web_socket::CoinBaseWebSocketMessageHandler;
+
+  fn ticker(bid: &str, ask: &str) -> Result<TickerResponse, serde_json::error::Error> {
+    serde_json::from_str(&format!(r#"{{
+      "trade_id": 20153558, "sequence": 3262786978, "time": "2017-09-02T17:05:49.250000Z",
+      "product_id": "BTC-USD", "price": "4388.01", "side": "buy", "last_size": "0.03",
+      "best_bid": "{}", "best_ask": "{}"
+    }}"#, bid, ask))
+  }
+
+  #[test]
+  fn notify_on_bbo_change() -> Result<(), serde_json::error::Error> {
+    let mut changes: Vec<Bbo> = Vec::new();
+    let mut tracker = BboTracker::new(|bbo: &Bbo| {
+      changes.push(bbo.clone());
+      Ok(())
+    });
+    tracker.on_ticker(&ticker("4388", "4388.01")?).unwrap();
+    tracker.on_ticker(&ticker("4388", "4388.01")?).unwrap();
+    tracker.on_ticker(&ticker("4387", "4388.01")?).unwrap();
+
+    let stats = tracker.spread_stats("BTC-USD").unwrap();
+    assert_eq!(stats.count, 2);
+    assert_eq!(stats.max, Decimal::from_str("1.01").unwrap());
+    assert_eq!(stats.min, Decimal::from_str("0.01").unwrap());
+    assert_eq!(stats.mean(), Decimal::from_str("0.51").unwrap());
+    drop(tracker);
+    assert_eq!(changes.len(), 2);
+    Ok(())
+  }
+}
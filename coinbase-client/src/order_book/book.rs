@@ -0,0 +1,338 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::decimal::{Decimal, Zero};
+use crate::web_socket::response::{Change, L2UpdateResponse, Side, SnapshotResponse};
+use crate::web_socket::{CoinBaseWebSocketMessageHandler, Terminate};
+
+use super::ladder::Ladder;
+use super::memory::{EvictionPolicy, MemoryLimits, MemoryUsage};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct Level {
+  pub price: Decimal,
+  pub size: Decimal,
+}
+
+/// Level 2 order book of a single product, aggregated by price.
+/// Built from the `snapshot` message and kept up to date with `l2update` messages.
+#[derive(Debug, Clone)]
+pub struct OrderBook {
+  product_id: String,
+  bids: BTreeMap<Decimal, Decimal>,
+  asks: BTreeMap<Decimal, Decimal>,
+}
+
+impl OrderBook {
+  pub fn new(product_id: &str) -> Self {
+    OrderBook { product_id: product_id.into(), bids: BTreeMap::new(), asks: BTreeMap::new() }
+  }
+
+  pub fn from_snapshot(snapshot: &SnapshotResponse) -> Self {
+    let mut book = OrderBook::new(snapshot.product_id.as_str());
+    book.bids = to_levels(&snapshot.bids);
+    book.asks = to_levels(&snapshot.asks);
+    book
+  }
+
+  pub fn apply(&mut self, update: &L2UpdateResponse) {
+    for change in update.changes.iter() {
+      self.set_level(change.side, change.price.clone(), change.size.clone());
+    }
+  }
+
+  /// Sets size of the price level, level is removed when size is zero.
+  pub fn set_level(&mut self, side: Side, price: Decimal, size: Decimal) {
+    let levels = match side {
+      Side::BUY => &mut self.bids,
+      Side::SELL => &mut self.asks,
+    };
+    if Zero::is_zero(&size) {
+      levels.remove(&price);
+    } else {
+      levels.insert(price, size);
+    }
+  }
+
+  /// Current state of the book in the form of the `snapshot` message.
+  pub fn to_snapshot(&self) -> SnapshotResponse {
+    SnapshotResponse {
+      product_id: self.product_id.clone(),
+      bids: self.bids.iter().rev().map(|(price, size)| vec![price.clone(), size.clone()]).collect(),
+      asks: self.asks.iter().map(|(price, size)| vec![price.clone(), size.clone()]).collect(),
+    }
+  }
+
+  /// Applies snapshot of only the best levels, like the REST book. Levels priced within the
+  /// snapshot's range are replaced by it and deeper levels kept. Returns the changes applied.
+  pub fn overlay(&mut self, snapshot: &SnapshotResponse) -> Vec<Change> {
+    let mut merged = self.clone();
+    let bids = to_levels(&snapshot.bids);
+    match bids.keys().next() {
+      Some(lowest) => merged.bids.retain(|price, _| price < lowest),
+      None => merged.bids.clear(),
+    }
+    merged.bids.extend(bids);
+    let asks = to_levels(&snapshot.asks);
+    match asks.keys().next_back() {
+      Some(highest) => merged.asks.retain(|price, _| price > highest),
+      None => merged.asks.clear(),
+    }
+    merged.asks.extend(asks);
+    let changes = self.diff(&merged);
+    *self = merged;
+    changes
+  }
+
+  /// Changes that turn this book into `other`, removed levels are reported with zero size.
+  pub fn diff(&self, other: &OrderBook) -> Vec<Change> {
+    let mut changes = diff_levels(Side::BUY, &self.bids, &other.bids);
+    changes.extend(diff_levels(Side::SELL, &self.asks, &other.asks));
+    changes
+  }
+
+  pub fn product_id(&self) -> &str {
+    self.product_id.as_str()
+  }
+
+  pub fn best_bid(&self) -> Option<Level> {
+    self.bids.iter().next_back().map(to_level)
+  }
+
+  pub fn best_ask(&self) -> Option<Level> {
+    self.asks.iter().next().map(to_level)
+  }
+
+  /// Best `depth` bid levels, from the highest price down.
+  pub fn top_bids(&self, depth: usize) -> Vec<Level> {
+    self.bids.iter().rev().take(depth).map(to_level).collect()
+  }
+
+  /// Best `depth` ask levels, from the lowest price up.
+  pub fn top_asks(&self, depth: usize) -> Vec<Level> {
+    self.asks.iter().take(depth).map(to_level).collect()
+  }
+
+  /// Total size and notional of the side's levels priced at `limit` or better.
+  pub fn depth_within(&self, side: Side, limit: &Decimal) -> (Decimal, Decimal) {
+    let levels: Box<dyn Iterator<Item=(&Decimal, &Decimal)>> = match side {
+      Side::BUY => Box::new(self.bids.range(limit.clone()..)),
+      Side::SELL => Box::new(self.asks.range(..=limit.clone())),
+    };
+    levels.fold((Decimal::zero(), Decimal::zero()), |(size, notional), (price, level_size)| {
+      (size + level_size, notional + price * level_size)
+    })
+  }
+
+  /// Total size of the side's levels priced within `[low, high)`.
+  pub fn size_between(&self, side: Side, low: &Decimal, high: &Decimal) -> Decimal {
+    let levels = match side {
+      Side::BUY => &self.bids,
+      Side::SELL => &self.asks,
+    };
+    if low >= high {
+      return Decimal::zero();
+    }
+    levels.range(low.clone()..high.clone()).fold(Decimal::zero(), |total, (_, size)| total + size)
+  }
+
+  /// Ladder of `bins` price bins of `bin_size` centered on the mid price, `None` while the book
+  /// is empty. See `LadderHandler` for a ladder kept up to date with the book.
+  pub fn ladder(&self, bin_size: &Decimal, bins: usize) -> Option<Ladder> {
+    Ladder::around(self, bin_size, bins)
+  }
+
+  pub fn memory_usage(&self) -> MemoryUsage {
+    MemoryUsage::of_book(self.levels())
+  }
+
+  fn levels(&self) -> usize {
+    self.bids.len() + self.asks.len()
+  }
+
+  /// Removes the levels of each side beyond the best `depth`.
+  pub fn truncate(&mut self, depth: usize) {
+    while self.bids.len() > depth {
+      self.bids.pop_first();
+    }
+    while self.asks.len() > depth {
+      self.asks.pop_last();
+    }
+  }
+
+  /// Removes the deepest level of the longer side, false when the book is empty.
+  fn pop_deepest(&mut self) -> bool {
+    if self.bids.len() > self.asks.len() {
+      self.bids.pop_first().is_some()
+    } else {
+      self.asks.pop_last().is_some()
+    }
+  }
+}
+
+fn to_levels(levels: &[Vec<Decimal>]) -> BTreeMap<Decimal, Decimal> {
+  levels.iter()
+    .filter(|level| level.len() >= 2)
+    .map(|level| (level[0].clone(), level[1].clone()))
+    .collect()
+}
+
+fn diff_levels(side: Side, old: &BTreeMap<Decimal, Decimal>, new: &BTreeMap<Decimal, Decimal>) -> Vec<Change> {
+  let removed = old.keys()
+    .filter(|price| !new.contains_key(price))
+    .map(|price| Change { side, price: price.clone(), size: Decimal::zero() });
+  let changed = new.iter()
+    .filter(|(price, size)| old.get(price) != Some(size))
+    .map(|(price, size)| Change { side, price: price.clone(), size: size.clone() });
+  removed.chain(changed).collect()
+}
+
+fn to_level((price, size): (&Decimal, &Decimal)) -> Level {
+  Level { price: price.clone(), size: size.clone() }
+}
+
+/// Handler that maintains order books for all products on the `level2` channel.
+#[derive(Debug, Default)]
+pub struct OrderBooks {
+  books: HashMap<String, OrderBook>,
+  limits: MemoryLimits,
+  // Price levels of all books, kept up to date so the limits are checked without walking the books.
+  levels: usize,
+  evictions: u64,
+  awaiting_snapshot: BTreeSet<String>,
+}
+
+impl OrderBooks {
+  pub fn new() -> Self {
+    OrderBooks::default()
+  }
+
+  /// Caps the books are kept within, checked after every snapshot and update.
+  pub fn with_limits(mut self, limits: MemoryLimits) -> Self {
+    self.limits = limits;
+    self
+  }
+
+  pub fn get(&self, product_id: &str) -> Option<&OrderBook> {
+    self.books.get(product_id)
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item=&OrderBook> {
+    self.books.values()
+  }
+
+  /// Replaces the book of its product, e.g. with one restored from disk.
+  pub fn insert(&mut self, book: OrderBook) {
+    self.awaiting_snapshot.remove(&book.product_id);
+    self.levels += book.levels();
+    if let Some(replaced) = self.books.insert(book.product_id.clone(), book) {
+      self.levels -= replaced.levels();
+    }
+  }
+
+  pub fn memory_usage(&self) -> MemoryUsage {
+    MemoryUsage::of_books(self.books.len(), self.levels)
+  }
+
+  /// Number of times a book went over the limits and was trimmed or dropped.
+  pub fn evictions(&self) -> u64 {
+    self.evictions
+  }
+
+  /// Products whose books were dropped by `EvictionPolicy::Resync`, until their next snapshot.
+  pub fn awaiting_snapshot(&self) -> impl Iterator<Item=&str> {
+    self.awaiting_snapshot.iter().map(String::as_str)
+  }
+
+  fn enforce_limits(&mut self, product_id: &str) {
+    let limits = self.limits;
+    let books = self.books.len();
+    let book = match self.books.get_mut(product_id) {
+      Some(book) => book,
+      None => return,
+    };
+    let levels = book.levels();
+    let others = MemoryUsage::of_books(books - 1, self.levels - levels).bytes;
+    let too_deep = limits.max_levels_per_side.is_some_and(|depth| book.bids.len() > depth || book.asks.len() > depth);
+    let too_big = |book: &OrderBook| limits.max_bytes.is_some_and(|max| others + book.memory_usage().bytes > max);
+    if !too_deep && !too_big(book) {
+      return;
+    }
+    self.evictions += 1;
+    match limits.policy {
+      EvictionPolicy::TrimDeepLevels => {
+        if let Some(depth) = limits.max_levels_per_side {
+          book.truncate(depth);
+        }
+        while too_big(book) && book.pop_deepest() {}
+        self.levels = self.levels - levels + book.levels();
+      }
+      EvictionPolicy::Resync => {
+        tracing::warn!("Dropped book of {} over the memory limits until the next snapshot.", product_id);
+        self.levels -= levels;
+        self.books.remove(product_id);
+        self.awaiting_snapshot.insert(product_id.into());
+      }
+    }
+  }
+}
+
+impl CoinBaseWebSocketMessageHandler for OrderBooks {
+  fn on_snapshot(&mut self, resp: &SnapshotResponse) -> Result<(), Terminate> {
+    self.insert(OrderBook::from_snapshot(resp));
+    self.enforce_limits(&resp.product_id);
+    Ok(())
+  }
+
+  fn on_l2_update(&mut self, resp: &L2UpdateResponse) -> Result<(), Terminate> {
+    match self.books.get_mut(&resp.product_id) {
+      Some(book) => {
+        let levels = book.levels();
+        book.apply(resp);
+        self.levels = self.levels - levels + book.levels();
+      }
+      None if self.awaiting_snapshot.contains(&resp.product_id) => return Ok(()),
+      None => tracing::debug!("Got l2update for {} before snapshot.", resp.product_id),
+    }
+    self.enforce_limits(&resp.product_id);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::web_socket::response::{L2UpdateResponse, SnapshotResponse};
+
+  use super::OrderBook;
+
+  #[test]
+  fn apply_updates_to_snapshot() -> Result<(), serde_json::error::Error> {
+    let snapshot: SnapshotResponse = serde_json::from_str(r#"
+      {
+        "product_id": "BTC-USD",
+        "bids": [["10101.10", "0.45054140"], ["10101.00", "1.5"]],
+        "asks": [["10102.55", "0.57753524"], ["10103.00", "2.0"]]
+      }
+    "#)?;
+    let update: L2UpdateResponse = serde_json::from_str(r#"
+      {
+        "product_id": "BTC-USD",
+        "time": "2019-08-14T20:42:27.265Z",
+        "changes": [["buy", "10101.10", "0.0"], ["sell", "10102.00", "1.0"]]
+      }
+    "#)?;
+
+    let mut book = OrderBook::from_snapshot(&snapshot);
+    book.apply(&update);
+
+    assert_eq!(book.best_bid().unwrap().price, "10101.00".parse().unwrap());
+    assert_eq!(book.best_ask().unwrap().price, "10102.00".parse().unwrap());
+    assert_eq!(book.top_asks(5).len(), 3);
+    assert_eq!(book.top_bids(5).len(), 1);
+
+    let changes = OrderBook::from_snapshot(&snapshot).diff(&book);
+    assert_eq!(changes.len(), 2);
+    Ok(())
+  }
+}
@@ -0,0 +1,277 @@
+//! Compact binary encoding of level2 data.
+//!
+//! A stream is a sequence of frames, every frame starts with a tag byte:
+//!
+//! - `PRODUCT` assigns the next product index to a product id (length prefixed UTF-8),
+//! - `SNAPSHOT` product index followed by bid and ask levels,
+//! - `UPDATE` product index, time and changes.
+//!
+//! Integers are LEB128 varints, signed ones zigzag encoded. Time is stored in microseconds
+//! as the difference to the previous update. Decimals are stored as mantissa and scale, which
+//! takes 4 to 6 bytes for typical prices and sizes instead of their 10+ character JSON strings.
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+use chrono::{DateTime, TimeZone, Utc};
+
+use crate::decimal::{from_mantissa_and_scale, to_mantissa_and_scale, Decimal};
+use crate::web_socket::response::{Change, L2UpdateResponse, ResponseMessages, Side, SnapshotResponse};
+
+const PRODUCT: u8 = 0;
+const SNAPSHOT: u8 = 1;
+const UPDATE: u8 = 2;
+
+pub struct DeltaWriter<W: Write> {
+  writer: W,
+  products: HashMap<String, u64>,
+  last_time: i64,
+}
+
+impl<W: Write> DeltaWriter<W> {
+  pub fn new(writer: W) -> Self {
+    DeltaWriter { writer, products: HashMap::new(), last_time: 0 }
+  }
+
+  pub fn write_snapshot(&mut self, snapshot: &SnapshotResponse) -> io::Result<()> {
+    let product = self.product_index(&snapshot.product_id)?;
+    self.writer.write_all(&[SNAPSHOT])?;
+    write_unsigned(&mut self.writer, product)?;
+    for levels in &[&snapshot.bids, &snapshot.asks] {
+      write_unsigned(&mut self.writer, levels.len() as u64)?;
+      for level in levels.iter() {
+        if level.len() < 2 {
+          return Err(io::Error::new(io::ErrorKind::InvalidInput, "Snapshot level without price and size."));
+        }
+        write_decimal(&mut self.writer, &level[0])?;
+        write_decimal(&mut self.writer, &level[1])?;
+      }
+    }
+    Ok(())
+  }
+
+  pub fn write_update(&mut self, update: &L2UpdateResponse) -> io::Result<()> {
+    let product = self.product_index(&update.product_id)?;
+    let time = update.time.timestamp_nanos_opt().unwrap_or_default() / 1_000;
+    self.writer.write_all(&[UPDATE])?;
+    write_unsigned(&mut self.writer, product)?;
+    write_signed(&mut self.writer, time - self.last_time)?;
+    self.last_time = time;
+    write_unsigned(&mut self.writer, update.changes.len() as u64)?;
+    for change in update.changes.iter() {
+      let side = match change.side {
+        Side::BUY => 0,
+        Side::SELL => 1,
+      };
+      self.writer.write_all(&[side])?;
+      write_decimal(&mut self.writer, &change.price)?;
+      write_decimal(&mut self.writer, &change.size)?;
+    }
+    Ok(())
+  }
+
+  pub fn flush(&mut self) -> io::Result<()> {
+    self.writer.flush()
+  }
+
+  pub fn into_inner(self) -> W {
+    self.writer
+  }
+
+  fn product_index(&mut self, product_id: &str) -> io::Result<u64> {
+    if let Some(index) = self.products.get(product_id) {
+      return Ok(*index);
+    }
+    let index = self.products.len() as u64;
+    self.writer.write_all(&[PRODUCT])?;
+    write_unsigned(&mut self.writer, product_id.len() as u64)?;
+    self.writer.write_all(product_id.as_bytes())?;
+    self.products.insert(product_id.into(), index);
+    Ok(index)
+  }
+}
+
+/// Decodes frames written by `DeltaWriter` back into `snapshot` and `l2update` messages.
+pub struct DeltaReader<R: Read> {
+  reader: R,
+  products: Vec<String>,
+  last_time: i64,
+}
+
+impl<R: Read> DeltaReader<R> {
+  pub fn new(reader: R) -> Self {
+    DeltaReader { reader, products: Vec::new(), last_time: 0 }
+  }
+
+  /// Reads next message, `None` at the end of the stream.
+  pub fn read_message(&mut self) -> io::Result<Option<ResponseMessages>> {
+    loop {
+      let mut tag = [0u8];
+      if self.reader.read(&mut tag)? == 0 {
+        return Ok(None);
+      }
+      match tag[0] {
+        PRODUCT => {
+          let len = read_unsigned(&mut self.reader)? as usize;
+          let mut product_id = vec![0u8; len];
+          self.reader.read_exact(&mut product_id)?;
+          let product_id = String::from_utf8(product_id)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+          self.products.push(product_id);
+        }
+        SNAPSHOT => {
+          let product_id = self.read_product()?;
+          let bids = self.read_levels()?;
+          let asks = self.read_levels()?;
+          let resp = SnapshotResponse { product_id, bids, asks };
+          return Ok(Some(ResponseMessages::Snapshot { resp }));
+        }
+        UPDATE => {
+          let product_id = self.read_product()?;
+          let time = self.last_time + read_signed(&mut self.reader)?;
+          self.last_time = time;
+          let count = read_unsigned(&mut self.reader)?;
+          let mut changes = Vec::with_capacity(count as usize);
+          for _ in 0..count {
+            let mut side = [0u8];
+            self.reader.read_exact(&mut side)?;
+            let side = if side[0] == 0 { Side::BUY } else { Side::SELL };
+            let price = read_decimal(&mut self.reader)?;
+            let size = read_decimal(&mut self.reader)?;
+            changes.push(Change { side, price, size });
+          }
+          let resp = L2UpdateResponse { product_id, time: micros_to_time(time), changes };
+          return Ok(Some(ResponseMessages::L2Update { resp }));
+        }
+        tag => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown frame tag {}", tag))),
+      }
+    }
+  }
+
+  fn read_product(&mut self) -> io::Result<String> {
+    let index = read_unsigned(&mut self.reader)? as usize;
+    self.products.get(index)
+      .cloned()
+      .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Frame refers to an undefined product."))
+  }
+
+  fn read_levels(&mut self) -> io::Result<Vec<Vec<Decimal>>> {
+    let count = read_unsigned(&mut self.reader)?;
+    let mut levels = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+      let price = read_decimal(&mut self.reader)?;
+      let size = read_decimal(&mut self.reader)?;
+      levels.push(vec![price, size]);
+    }
+    Ok(levels)
+  }
+}
+
+impl<R: Read> Iterator for DeltaReader<R> {
+  type Item = io::Result<ResponseMessages>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    self.read_message().transpose()
+  }
+}
+
+fn micros_to_time(micros: i64) -> DateTime<Utc> {
+  Utc.timestamp_opt(micros.div_euclid(1_000_000), (micros.rem_euclid(1_000_000) * 1_000) as u32)
+    .single()
+    .unwrap_or_else(|| Utc.timestamp_opt(0, 0).unwrap())
+}
+
+fn write_decimal<W: Write>(writer: &mut W, value: &Decimal) -> io::Result<()> {
+  let (mantissa, scale) = to_mantissa_and_scale(value)
+    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Decimal {} is too large", value)))?;
+  write_signed(writer, mantissa)?;
+  write_unsigned(writer, scale as u64)
+}
+
+fn read_decimal<R: Read>(reader: &mut R) -> io::Result<Decimal> {
+  let mantissa = read_signed(reader)?;
+  let scale = read_unsigned(reader)?;
+  Ok(from_mantissa_and_scale(mantissa, scale as u32))
+}
+
+fn write_unsigned<W: Write>(writer: &mut W, mut value: u64) -> io::Result<()> {
+  let mut buf = [0u8; 10];
+  let mut len = 0;
+  loop {
+    let byte = (value & 0x7f) as u8;
+    value >>= 7;
+    if value == 0 {
+      buf[len] = byte;
+      len += 1;
+      break;
+    }
+    buf[len] = byte | 0x80;
+    len += 1;
+  }
+  writer.write_all(&buf[..len])
+}
+
+fn read_unsigned<R: Read>(reader: &mut R) -> io::Result<u64> {
+  let mut value = 0u64;
+  for shift in (0..64).step_by(7) {
+    let mut byte = [0u8];
+    reader.read_exact(&mut byte)?;
+    value |= ((byte[0] & 0x7f) as u64) << shift;
+    if byte[0] & 0x80 == 0 {
+      return Ok(value);
+    }
+  }
+  Err(io::Error::new(io::ErrorKind::InvalidData, "Varint is too long."))
+}
+
+fn write_signed<W: Write>(writer: &mut W, value: i64) -> io::Result<()> {
+  write_unsigned(writer, ((value << 1) ^ (value >> 63)) as u64)
+}
+
+fn read_signed<R: Read>(reader: &mut R) -> io::Result<i64> {
+  let value = read_unsigned(reader)?;
+  Ok(((value >> 1) as i64) ^ -((value & 1) as i64))
+}
+
+#[cfg(test)]
+mod test {
+  use crate::web_socket::response::{L2UpdateResponse, ResponseMessages, SnapshotResponse};
+
+  use super::{DeltaReader, DeltaWriter};
+
+  #[test]
+  fn round_trip() -> Result<(), serde_json::error::Error> {
+    let snapshot: SnapshotResponse = serde_json::from_str(r#"{
+      "product_id": "BTC-USD",
+      "bids": [["10101.10", "0.45054140"], ["10101.00", "1.5"]],
+      "asks": [["10102.55", "0.57753524"]]
+    }"#)?;
+    let update: L2UpdateResponse = serde_json::from_str(r#"{
+      "product_id": "BTC-USD",
+      "time": "2019-08-14T20:42:27.265Z",
+      "changes": [["buy", "10101.10", "0.0"], ["sell", "10102.00", "1.0"]]
+    }"#)?;
+
+    let mut writer = DeltaWriter::new(Vec::new());
+    writer.write_snapshot(&snapshot).unwrap();
+    writer.write_update(&update).unwrap();
+    let encoded = writer.into_inner();
+    let json_len = serde_json::to_string(&snapshot)?.len() + serde_json::to_string(&update)?.len();
+    assert!(encoded.len() * 3 < json_len);
+
+    let messages: Vec<ResponseMessages> = DeltaReader::new(encoded.as_slice()).collect::<Result<_, _>>().unwrap();
+    assert_eq!(messages.len(), 2);
+    match &messages[0] {
+      ResponseMessages::Snapshot { resp } => assert_eq!(resp.bids, snapshot.bids),
+      _ => panic!("Unexpected message type"),
+    }
+    match &messages[1] {
+      ResponseMessages::L2Update { resp } => {
+        assert_eq!(resp.time, update.time);
+        assert_eq!(resp.changes[1].price, update.changes[1].price);
+        assert_eq!(resp.changes[0].size, update.changes[0].size);
+      }
+      _ => panic!("Unexpected message type"),
+    }
+    Ok(())
+  }
+}
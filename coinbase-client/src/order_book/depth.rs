@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use num_traits::ToPrimitive;
+use serde::{Deserialize, Serialize};
+
+use crate::web_socket::response::{HeartBeatResponse, L2UpdateResponse, SnapshotResponse};
+use crate::web_socket::{Clock, CoinBaseWebSocketMessageHandler, SystemClock, Terminate};
+
+use super::{Level, OrderBooks};
+
+/// Top levels of the order book at the given point in time.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DepthSnapshot {
+  pub product_id: String,
+  pub time: DateTime<Utc>,
+  pub bids: Vec<Level>,
+  pub asks: Vec<Level>,
+}
+
+pub trait DepthSnapshotSink {
+  fn on_depth_snapshot(&mut self, snapshot: &DepthSnapshot) -> Result<(), Terminate>;
+}
+
+impl<F: FnMut(&DepthSnapshot) -> Result<(), Terminate>> DepthSnapshotSink for F {
+  fn on_depth_snapshot(&mut self, snapshot: &DepthSnapshot) -> Result<(), Terminate> {
+    self(snapshot)
+  }
+}
+
+/// Samples order books at a fixed cadence and emits top `depth` levels of each side.
+///
+/// Handler doesn't own a timer, snapshots that are due are emitted when the next message
+/// for the product arrives. Subscribe to the `heartbeat` channel alongside `level2` to get
+/// regular samples for quiet products. Emission times stay on the `interval` grid, so a late
+/// snapshot doesn't shift the following ones.
+pub struct DepthSnapshotHandler<S: DepthSnapshotSink> {
+  books: OrderBooks,
+  depth: usize,
+  interval: Duration,
+  next_emit: HashMap<String, Instant>,
+  sink: S,
+  clock: Arc<dyn Clock>,
+}
+
+impl<S: DepthSnapshotSink> DepthSnapshotHandler<S> {
+  pub fn new(depth: usize, interval: Duration, sink: S) -> Self {
+    DepthSnapshotHandler { books: OrderBooks::new(), depth, interval, next_emit: HashMap::new(), sink, clock: Arc::new(SystemClock) }
+  }
+
+  /// Clock the sampling cadence and snapshot times are taken from, e.g. the clock of a replay.
+  pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+    self.clock = clock;
+    self
+  }
+
+  pub fn books(&self) -> &OrderBooks {
+    &self.books
+  }
+
+  fn emit_if_due(&mut self, product_id: &str, now: Instant) -> Result<(), Terminate> {
+    let next_emit = match self.next_emit.get_mut(product_id) {
+      Some(next_emit) => next_emit,
+      None => return Ok(()),
+    };
+    if now < *next_emit {
+      return Ok(());
+    }
+    while *next_emit <= now {
+      *next_emit += self.interval;
+    }
+
+    let book = match self.books.get(product_id) {
+      Some(book) => book,
+      None => return Ok(()),
+    };
+    let snapshot = DepthSnapshot {
+      product_id: product_id.into(),
+      time: self.clock.utc_now(),
+      bids: book.top_bids(self.depth),
+      asks: book.top_asks(self.depth),
+    };
+    self.sink.on_depth_snapshot(&snapshot)
+  }
+}
+
+impl<S: DepthSnapshotSink> CoinBaseWebSocketMessageHandler for DepthSnapshotHandler<S> {
+  fn on_heartbeat(&mut self, resp: &HeartBeatResponse) -> Result<(), Terminate> {
+    self.emit_if_due(resp.product_id.as_str(), self.clock.now())
+  }
+
+  fn on_snapshot(&mut self, resp: &SnapshotResponse) -> Result<(), Terminate> {
+    self.books.on_snapshot(resp)?;
+    let now = self.clock.now();
+    self.next_emit.entry(resp.product_id.clone()).or_insert(now);
+    self.emit_if_due(resp.product_id.as_str(), now)
+  }
+
+  fn on_l2_update(&mut self, resp: &L2UpdateResponse) -> Result<(), Terminate> {
+    self.books.on_l2_update(resp)?;
+    self.emit_if_due(resp.product_id.as_str(), self.clock.now())
+  }
+}
+
+/// Emits the top `depth` levels of each side whenever they change beyond the tolerance, a
+/// compact alternative to recording every level2 update.
+///
+/// A snapshot is emitted when a price enters or leaves the top levels, or the size of a level
+/// moves by more than `tolerance` (a fraction, 0.05 is 5%) from the last emitted snapshot. With
+/// the default tolerance of zero every change of the top levels is emitted.
+pub struct DepthChangeHandler<S: DepthSnapshotSink> {
+  books: OrderBooks,
+  depth: usize,
+  tolerance: f64,
+  emitted: HashMap<String, DepthSnapshot>,
+  sink: S,
+  clock: Arc<dyn Clock>,
+}
+
+impl<S: DepthSnapshotSink> DepthChangeHandler<S> {
+  pub fn new(depth: usize, sink: S) -> Self {
+    DepthChangeHandler { books: OrderBooks::new(), depth, tolerance: 0.0, emitted: HashMap::new(), sink, clock: Arc::new(SystemClock) }
+  }
+
+  /// Clock stamping snapshots emitted after a `snapshot` message, which carries no time.
+  pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+    self.clock = clock;
+    self
+  }
+
+  pub fn tolerance(mut self, tolerance: f64) -> Self {
+    self.tolerance = tolerance;
+    self
+  }
+
+  pub fn books(&self) -> &OrderBooks {
+    &self.books
+  }
+
+  fn emit_if_changed(&mut self, product_id: &str, time: DateTime<Utc>) -> Result<(), Terminate> {
+    let book = match self.books.get(product_id) {
+      Some(book) => book,
+      None => return Ok(()),
+    };
+    let snapshot = DepthSnapshot { product_id: product_id.into(), time, bids: book.top_bids(self.depth), asks: book.top_asks(self.depth) };
+    let changed = match self.emitted.get(product_id) {
+      Some(emitted) => changed(&emitted.bids, &snapshot.bids, self.tolerance) || changed(&emitted.asks, &snapshot.asks, self.tolerance),
+      None => true,
+    };
+    if !changed {
+      return Ok(());
+    }
+    self.sink.on_depth_snapshot(&snapshot)?;
+    self.emitted.insert(product_id.into(), snapshot);
+    Ok(())
+  }
+}
+
+fn changed(emitted: &[Level], current: &[Level], tolerance: f64) -> bool {
+  emitted.len() != current.len() || emitted.iter().zip(current).any(|(emitted, current)| {
+    if emitted.price != current.price {
+      return true;
+    }
+    let before = emitted.size.to_f64().unwrap_or_default();
+    let after = current.size.to_f64().unwrap_or_default();
+    (after - before).abs() > before * tolerance
+  })
+}
+
+impl<S: DepthSnapshotSink> CoinBaseWebSocketMessageHandler for DepthChangeHandler<S> {
+  fn on_snapshot(&mut self, resp: &SnapshotResponse) -> Result<(), Terminate> {
+    self.books.on_snapshot(resp)?;
+    // Levels after a resubscription are emitted even if they didn't change.
+    self.emitted.remove(&resp.product_id);
+    self.emit_if_changed(resp.product_id.as_str(), self.clock.utc_now())
+  }
+
+  fn on_l2_update(&mut self, resp: &L2UpdateResponse) -> Result<(), Terminate> {
+    self.books.on_l2_update(resp)?;
+    self.emit_if_changed(resp.product_id.as_str(), resp.time)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{DepthChangeHandler, DepthSnapshot};
+  use crate::web_socket::response::{L2UpdateResponse, SnapshotResponse};
+  use crate::web_socket::CoinBaseWebSocketMessageHandler;
+
+  fn update(side: &str, price: &str, size: &str) -> L2UpdateResponse {
+    serde_json::from_str(&format!(
+      r#"{{"product_id": "BTC-USD", "time": "2020-08-31T15:00:00Z", "changes": [["{}", "{}", "{}"]]}}"#,
+      side, price, size
+    )).unwrap()
+  }
+
+  #[test]
+  fn emit_top_levels_changing_beyond_tolerance() {
+    let mut snapshots: Vec<DepthSnapshot> = Vec::new();
+    let mut handler = DepthChangeHandler::new(2, |snapshot: &DepthSnapshot| {
+      snapshots.push(snapshot.clone());
+      Ok(())
+    }).tolerance(0.1);
+    let snapshot: SnapshotResponse = serde_json::from_str(r#"{
+      "product_id": "BTC-USD",
+      "bids": [["100", "10"], ["99", "10"], ["98", "10"]],
+      "asks": [["101", "10"], ["102", "10"]]
+    }"#).unwrap();
+    handler.on_snapshot(&snapshot).unwrap();
+    // Below the top levels and within the tolerance.
+    handler.on_l2_update(&update("buy", "98", "50")).unwrap();
+    handler.on_l2_update(&update("sell", "101", "10.5")).unwrap();
+    handler.on_l2_update(&update("sell", "101", "11.5")).unwrap();
+    handler.on_l2_update(&update("buy", "100", "0")).unwrap();
+    drop(handler);
+
+    assert_eq!(snapshots.len(), 3);
+    assert_eq!(snapshots[1].asks[0].size, "11.5".parse().unwrap());
+    let bids: Vec<String> = snapshots[2].bids.iter().map(|level| level.price.to_string()).collect();
+    assert_eq!(bids, vec!["99", "98"]);
+  }
+}
@@ -0,0 +1,285 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::decimal::Decimal;
+use crate::web_socket::response::{
+  ChangeResponse, DoneResponse, FinishReason, MatchResponse, OpenResponse, OrderId, ReceivedResponse, Side,
+};
+use crate::web_socket::{CoinBaseWebSocketMessageHandler, Terminate};
+
+use super::memory::MemoryUsage;
+
+/// Change of a level3 order book, derived from the `full` channel messages.
+///
+/// Orders enter the book with `Add` once they rest, `received` messages and the `done` of
+/// orders that never rested (takers and orders canceled right away) produce no event. Prices
+/// and sizes of orders that rested before the subscription are unknown, their `Modify` and
+/// `Delete` events come without the missing values.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum OrderBookEvent {
+  /// Order rests on the book, from `open`.
+  Add {
+    product_id: String,
+    sequence: i64,
+    time: DateTime<Utc>,
+    order_id: OrderId,
+    side: Side,
+    price: Decimal,
+    size: Decimal,
+  },
+  /// Size of a resting order changed (self-trade prevention), from `change`.
+  Modify {
+    product_id: String,
+    sequence: i64,
+    time: DateTime<Utc>,
+    order_id: OrderId,
+    side: Side,
+    price: Option<Decimal>,
+    old_size: Decimal,
+    new_size: Decimal,
+  },
+  /// Trade against a resting order, from `match`. `side` is the side of the maker order.
+  Execute {
+    product_id: String,
+    sequence: i64,
+    time: DateTime<Utc>,
+    trade_id: i64,
+    maker_order_id: OrderId,
+    taker_order_id: OrderId,
+    side: Side,
+    price: Decimal,
+    size: Decimal,
+    /// Size of the maker order left on the book, when the order is known.
+    remaining_size: Option<Decimal>,
+  },
+  /// Order left the book, from `done`.
+  Delete {
+    product_id: String,
+    sequence: i64,
+    time: DateTime<Utc>,
+    order_id: OrderId,
+    side: Side,
+    price: Option<Decimal>,
+    remaining_size: Option<Decimal>,
+    reason: FinishReason,
+  },
+}
+
+impl OrderBookEvent {
+  pub fn product_id(&self) -> &str {
+    match self {
+      OrderBookEvent::Add { product_id, .. }
+      | OrderBookEvent::Modify { product_id, .. }
+      | OrderBookEvent::Execute { product_id, .. }
+      | OrderBookEvent::Delete { product_id, .. } => product_id,
+    }
+  }
+
+  pub fn sequence(&self) -> i64 {
+    match self {
+      OrderBookEvent::Add { sequence, .. }
+      | OrderBookEvent::Modify { sequence, .. }
+      | OrderBookEvent::Execute { sequence, .. }
+      | OrderBookEvent::Delete { sequence, .. } => *sequence,
+    }
+  }
+
+  pub fn time(&self) -> DateTime<Utc> {
+    match self {
+      OrderBookEvent::Add { time, .. }
+      | OrderBookEvent::Modify { time, .. }
+      | OrderBookEvent::Execute { time, .. }
+      | OrderBookEvent::Delete { time, .. } => *time,
+    }
+  }
+}
+
+pub trait OrderBookEventSink {
+  fn on_order_book_event(&mut self, event: &OrderBookEvent) -> Result<(), Terminate>;
+}
+
+impl<F: FnMut(&OrderBookEvent) -> Result<(), Terminate>> OrderBookEventSink for F {
+  fn on_order_book_event(&mut self, event: &OrderBookEvent) -> Result<(), Terminate> {
+    self(event)
+  }
+}
+
+struct RestingOrder {
+  price: Decimal,
+  size: Decimal,
+}
+
+/// Translates the five message types of the `full` channel into `OrderBookEvent`s.
+///
+/// Keeps the price and size of every order that rested since the subscription, and the ids of
+/// received orders until they rest or are done. With `max_orders` set, orders opening over the
+/// cap are not kept and their later events come without price and size like the ones of orders
+/// from before the subscription.
+pub struct FullChannelTranslator<S: OrderBookEventSink> {
+  sink: S,
+  resting: HashMap<OrderId, RestingOrder>,
+  received: HashSet<OrderId>,
+  max_orders: Option<usize>,
+  untracked: u64,
+}
+
+impl<S: OrderBookEventSink> FullChannelTranslator<S> {
+  pub fn new(sink: S) -> Self {
+    FullChannelTranslator { sink, resting: HashMap::new(), received: HashSet::new(), max_orders: None, untracked: 0 }
+  }
+
+  pub fn max_orders(mut self, orders: usize) -> Self {
+    self.max_orders = Some(orders);
+    self
+  }
+
+  /// Number of orders resting on the books since the subscription.
+  pub fn resting_orders(&self) -> usize {
+    self.resting.len()
+  }
+
+  /// Number of orders not kept because of `max_orders`.
+  pub fn untracked_orders(&self) -> u64 {
+    self.untracked
+  }
+
+  pub fn memory_usage(&self) -> MemoryUsage {
+    MemoryUsage::of_orders(self.resting.len(), self.received.len())
+  }
+}
+
+impl<S: OrderBookEventSink> CoinBaseWebSocketMessageHandler for FullChannelTranslator<S> {
+  fn on_received(&mut self, resp: &ReceivedResponse) -> Result<(), Terminate> {
+    self.received.insert(resp.order_id);
+    Ok(())
+  }
+
+  fn on_open(&mut self, resp: &OpenResponse) -> Result<(), Terminate> {
+    self.received.remove(&resp.order_id);
+    if self.max_orders.is_some_and(|max| self.resting.len() >= max) {
+      self.untracked += 1;
+    } else {
+      let order = RestingOrder { price: resp.price.clone(), size: resp.remaining_size.clone() };
+      self.resting.insert(resp.order_id, order);
+    }
+    self.sink.on_order_book_event(&OrderBookEvent::Add {
+      product_id: resp.product_id.clone(),
+      sequence: resp.sequence,
+      time: resp.time,
+      order_id: resp.order_id,
+      side: resp.side,
+      price: resp.price.clone(),
+      size: resp.remaining_size.clone(),
+    })
+  }
+
+  fn on_change(&mut self, resp: &ChangeResponse) -> Result<(), Terminate> {
+    if self.received.contains(&resp.order_id) {
+      return Ok(());
+    }
+    let price = match self.resting.get_mut(&resp.order_id) {
+      Some(order) => {
+        order.size = resp.new_size.clone();
+        Some(order.price.clone())
+      }
+      None => resp.price.clone(),
+    };
+    self.sink.on_order_book_event(&OrderBookEvent::Modify {
+      product_id: resp.product_id.clone(),
+      sequence: resp.sequence,
+      time: resp.time,
+      order_id: resp.order_id,
+      side: resp.side,
+      price,
+      old_size: resp.old_size.clone(),
+      new_size: resp.new_size.clone(),
+    })
+  }
+
+  fn on_match(&mut self, resp: &MatchResponse) -> Result<(), Terminate> {
+    let remaining_size = self.resting.get_mut(&resp.maker_order_id).map(|order| {
+      order.size = &order.size - &resp.size;
+      order.size.clone()
+    });
+    self.sink.on_order_book_event(&OrderBookEvent::Execute {
+      product_id: resp.product_id.clone(),
+      sequence: resp.sequence,
+      time: resp.time,
+      trade_id: resp.trade_id,
+      maker_order_id: resp.maker_order_id,
+      taker_order_id: resp.taker_order_id,
+      side: resp.side,
+      price: resp.price.clone(),
+      size: resp.size.clone(),
+      remaining_size,
+    })
+  }
+
+  fn on_done(&mut self, resp: &DoneResponse) -> Result<(), Terminate> {
+    if self.received.remove(&resp.order_id) {
+      return Ok(());
+    }
+    let order = self.resting.remove(&resp.order_id);
+    self.sink.on_order_book_event(&OrderBookEvent::Delete {
+      product_id: resp.product_id.clone(),
+      sequence: resp.sequence,
+      time: resp.time,
+      order_id: resp.order_id,
+      side: resp.side,
+      price: order.as_ref().map(|order| order.price.clone()),
+      remaining_size: order.map(|order| order.size),
+      reason: resp.reason,
+    })
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{FullChannelTranslator, OrderBookEvent};
+  use crate::web_socket::{dispatch, ResponseMessages, Terminate};
+
+  #[test]
+  fn translate_full_channel() -> Result<(), serde_json::error::Error> {
+    let maker = "68e6a28f-ae28-4788-8d4f-5ab4e5e5ae08";
+    let taker = "1aa8fb1d-4d2d-4b8e-9a09-3e5e55bb1a4a";
+    let messages = [
+      format!(r#"{{"type": "received", "time": "2019-08-14T20:42:27.265Z", "product_id": "BTC-USD", "sequence": 1,
+        "order_id": "{}", "side": "sell", "order_type": "limit", "size": "2", "price": "100.00"}}"#, maker),
+      format!(r#"{{"type": "open", "time": "2019-08-14T20:42:27.265Z", "product_id": "BTC-USD", "sequence": 2,
+        "order_id": "{}", "side": "sell", "price": "100.00", "remaining_size": "2"}}"#, maker),
+      format!(r#"{{"type": "received", "time": "2019-08-14T20:42:28.265Z", "product_id": "BTC-USD", "sequence": 3,
+        "order_id": "{}", "side": "buy", "order_type": "market", "funds": "1000"}}"#, taker),
+      format!(r#"{{"type": "match", "time": "2019-08-14T20:42:28.265Z", "product_id": "BTC-USD", "sequence": 4,
+        "trade_id": 10, "maker_order_id": "{}", "taker_order_id": "{}", "side": "sell", "size": "1.5",
+        "price": "100.00"}}"#, maker, taker),
+      format!(r#"{{"type": "done", "time": "2019-08-14T20:42:28.265Z", "product_id": "BTC-USD", "sequence": 5,
+        "order_id": "{}", "side": "buy", "reason": "filled"}}"#, taker),
+      format!(r#"{{"type": "done", "time": "2019-08-14T20:42:29.265Z", "product_id": "BTC-USD", "sequence": 6,
+        "order_id": "{}", "side": "sell", "reason": "canceled"}}"#, maker),
+    ];
+
+    let mut events = Vec::new();
+    let mut translator = FullChannelTranslator::new(|event: &OrderBookEvent| -> Result<(), Terminate> {
+      events.push(event.clone());
+      Ok(())
+    });
+    for message in messages.iter() {
+      dispatch(&mut translator, &serde_json::from_str::<ResponseMessages>(message)?).unwrap();
+    }
+    assert_eq!(translator.resting_orders(), 0);
+    drop(translator);
+
+    assert_eq!(events.iter().map(|event| event.sequence()).collect::<Vec<_>>(), vec![2, 4, 6]);
+    match &events[2] {
+      OrderBookEvent::Delete { remaining_size, price, .. } => {
+        assert_eq!(remaining_size, &Some("0.5".parse().unwrap()));
+        assert_eq!(price, &Some("100.00".parse().unwrap()));
+      }
+      event => panic!("Expected delete, got {:?}", event),
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,200 @@
+//! Order book state at any point of recorded level2 data, e.g. to simulate fills in backtests.
+use std::collections::HashMap;
+use std::io::{self, BufRead, Read};
+
+use chrono::{DateTime, Utc};
+
+use crate::replay::JsonLines;
+use crate::web_socket::response::{L2UpdateResponse, ResponseMessages, SnapshotResponse};
+use crate::web_socket::{CoinBaseWebSocketMessageHandler, Terminate};
+
+use super::{DeltaReader, OrderBook};
+
+/// Book state from which updates are replayed, at a snapshot or after every
+/// `checkpoint_every` updates.
+#[derive(Debug)]
+struct Checkpoint {
+  time: DateTime<Utc>,
+  // Index of the first update that is not applied to the book.
+  next_update: usize,
+  book: OrderBook,
+}
+
+#[derive(Debug, Default)]
+struct ProductHistory {
+  checkpoints: Vec<Checkpoint>,
+  updates: Vec<L2UpdateResponse>,
+  // Book after the last recorded update, `None` until the first snapshot.
+  current: Option<OrderBook>,
+  // Snapshot received before any update told the time.
+  untimed: bool,
+  since_checkpoint: usize,
+}
+
+/// Keeps recorded snapshots and updates of all products in memory and reconstructs the book
+/// of a product at any time within the recording.
+///
+/// Snapshot messages carry no time, a snapshot is taken to be valid from the time of the last
+/// update before it, or of the first update after it when it starts the recording. Updates
+/// received before the first snapshot of a product are ignored.
+#[derive(Debug)]
+pub struct BookHistory {
+  products: HashMap<String, ProductHistory>,
+  last_time: Option<DateTime<Utc>>,
+  checkpoint_every: usize,
+}
+
+impl Default for BookHistory {
+  fn default() -> Self {
+    BookHistory { products: HashMap::new(), last_time: None, checkpoint_every: 1_000 }
+  }
+}
+
+impl BookHistory {
+  pub fn new() -> Self {
+    BookHistory::default()
+  }
+
+  /// Copies the book every this many updates, so a query replays at most that many. Trades
+  /// memory for query speed, 1000 by default.
+  pub fn checkpoint_every(mut self, updates: usize) -> Self {
+    self.checkpoint_every = updates.max(1);
+    self
+  }
+
+  /// Records level2 messages, other messages are ignored. Messages must come in feed order.
+  pub fn load<I: Iterator<Item=io::Result<ResponseMessages>>>(mut self, messages: I) -> io::Result<Self> {
+    for message in messages {
+      match message? {
+        ResponseMessages::Snapshot { resp } => self.record_snapshot(&resp),
+        ResponseMessages::L2Update { resp } => self.record_update(&resp),
+        _ => {}
+      }
+    }
+    Ok(self)
+  }
+
+  /// Loads level2 data encoded with `DeltaWriter`.
+  pub fn from_deltas<R: Read>(reader: R) -> io::Result<Self> {
+    BookHistory::new().load(DeltaReader::new(reader))
+  }
+
+  /// Loads messages stored one JSON document per line.
+  pub fn from_json_lines<R: BufRead>(reader: R) -> io::Result<Self> {
+    BookHistory::new().load(JsonLines::new(reader))
+  }
+
+  pub fn record_snapshot(&mut self, snapshot: &SnapshotResponse) {
+    let last_time = self.last_time;
+    let history = self.products.entry(snapshot.product_id.clone()).or_default();
+    let book = OrderBook::from_snapshot(snapshot);
+    match last_time {
+      Some(time) => history.checkpoints.push(Checkpoint { time, next_update: history.updates.len(), book: book.clone() }),
+      None => history.untimed = true,
+    }
+    history.current = Some(book);
+    history.since_checkpoint = 0;
+  }
+
+  pub fn record_update(&mut self, update: &L2UpdateResponse) {
+    self.last_time = Some(self.last_time.map_or(update.time, |time| time.max(update.time)));
+    let checkpoint_every = self.checkpoint_every;
+    let history = match self.products.get_mut(&update.product_id) {
+      Some(history) => history,
+      None => return,
+    };
+    let book = match &mut history.current {
+      Some(book) => book,
+      None => return,
+    };
+    if history.untimed {
+      history.checkpoints.push(Checkpoint { time: update.time, next_update: history.updates.len(), book: book.clone() });
+      history.untimed = false;
+    }
+    book.apply(update);
+    history.updates.push(update.clone());
+    history.since_checkpoint += 1;
+    if history.since_checkpoint >= checkpoint_every {
+      history.checkpoints.push(Checkpoint { time: update.time, next_update: history.updates.len(), book: book.clone() });
+      history.since_checkpoint = 0;
+    }
+  }
+
+  /// Book of the product after all updates with time up to and including `time`. `None` for
+  /// an unknown product or a time before its first snapshot.
+  pub fn book_at(&self, product_id: &str, time: DateTime<Utc>) -> Option<OrderBook> {
+    let history = self.products.get(product_id)?;
+    let index = history.checkpoints.partition_point(|checkpoint| checkpoint.time <= time).checked_sub(1)?;
+    let checkpoint = &history.checkpoints[index];
+    // Updates after the next checkpoint belong to it, e.g. to a snapshot that reset the book.
+    let end = history.checkpoints.get(index + 1).map_or(history.updates.len(), |next| next.next_update);
+    let mut book = checkpoint.book.clone();
+    history.updates[checkpoint.next_update..end].iter()
+      .take_while(|update| update.time <= time)
+      .for_each(|update| book.apply(update));
+    Some(book)
+  }
+
+  /// Time of the first snapshot and of the last update of the product.
+  pub fn time_range(&self, product_id: &str) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let history = self.products.get(product_id)?;
+    let start = history.checkpoints.first()?.time;
+    let end = history.updates.last().map_or(start, |update| update.time);
+    Some((start, end))
+  }
+
+  pub fn products(&self) -> impl Iterator<Item=&str> {
+    self.products.keys().map(|product_id| product_id.as_str())
+  }
+}
+
+/// Records the live feed, so books can be looked up in the past while it is running.
+impl CoinBaseWebSocketMessageHandler for BookHistory {
+  fn on_snapshot(&mut self, resp: &SnapshotResponse) -> Result<(), Terminate> {
+    self.record_snapshot(resp);
+    Ok(())
+  }
+
+  fn on_l2_update(&mut self, resp: &L2UpdateResponse) -> Result<(), Terminate> {
+    self.record_update(resp);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use chrono::{DateTime, Utc};
+
+  use super::BookHistory;
+
+  fn time(time: &str) -> DateTime<Utc> {
+    time.parse().unwrap()
+  }
+
+  #[test]
+  fn reconstruct_book_at_time() {
+    let lines = r#"
+      {"type": "snapshot", "product_id": "BTC-USD", "bids": [["100.00", "1.0"]], "asks": [["101.00", "1.0"]]}
+      {"type": "l2update", "product_id": "BTC-USD", "time": "2020-08-31T15:00:01Z", "changes": [["buy", "100.50", "2.0"]]}
+      {"type": "l2update", "product_id": "BTC-USD", "time": "2020-08-31T15:00:02Z", "changes": [["sell", "101.00", "0"]]}
+      {"type": "l2update", "product_id": "BTC-USD", "time": "2020-08-31T15:00:03Z", "changes": [["sell", "100.75", "3.0"]]}
+      {"type": "snapshot", "product_id": "BTC-USD", "bids": [["99.00", "1.0"]], "asks": [["99.50", "1.0"]]}
+      {"type": "l2update", "product_id": "BTC-USD", "time": "2020-08-31T15:00:05Z", "changes": [["buy", "99.25", "1.0"]]}
+    "#;
+    let history = BookHistory::new().checkpoint_every(2).load(super::JsonLines::new(lines.as_bytes())).unwrap();
+
+    assert!(history.book_at("BTC-USD", time("2020-08-31T14:59:59Z")).is_none());
+    assert!(history.book_at("ETH-USD", time("2020-08-31T15:00:01Z")).is_none());
+    let book = history.book_at("BTC-USD", time("2020-08-31T15:00:01.5Z")).unwrap();
+    assert_eq!(book.best_bid().unwrap().price, "100.50".parse().unwrap());
+    assert_eq!(book.best_ask().unwrap().price, "101.00".parse().unwrap());
+    let book = history.book_at("BTC-USD", time("2020-08-31T15:00:02Z")).unwrap();
+    assert!(book.best_ask().is_none());
+    // Second snapshot is valid from the last update before it.
+    let book = history.book_at("BTC-USD", time("2020-08-31T15:00:04Z")).unwrap();
+    assert_eq!(book.best_bid().unwrap().price, "99.00".parse().unwrap());
+    let book = history.book_at("BTC-USD", time("2020-08-31T16:00:00Z")).unwrap();
+    assert_eq!(book.best_bid().unwrap().price, "99.25".parse().unwrap());
+    assert_eq!(history.time_range("BTC-USD"), Some((time("2020-08-31T15:00:01Z"), time("2020-08-31T15:00:05Z"))));
+  }
+}
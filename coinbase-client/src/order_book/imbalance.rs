@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use num_traits::ToPrimitive;
+use serde::{Deserialize, Serialize};
+
+use crate::web_socket::response::{L2UpdateResponse, SnapshotResponse};
+use crate::web_socket::{Clock, CoinBaseWebSocketMessageHandler, SystemClock, Terminate};
+
+use super::{Level, OrderBook, OrderBooks};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImbalanceState {
+  Balanced,
+  /// More volume on the bid side.
+  BidHeavy,
+  AskHeavy,
+}
+
+/// Change of the imbalance state of a product's book.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BookImbalance {
+  pub product_id: String,
+  pub time: DateTime<Utc>,
+  pub state: ImbalanceState,
+  pub previous: ImbalanceState,
+  /// `(bid_volume - ask_volume) / (bid_volume + ask_volume)`, from -1 to 1.
+  pub imbalance: f64,
+  pub bid_volume: f64,
+  pub ask_volume: f64,
+}
+
+pub trait BookImbalanceSink {
+  fn on_book_imbalance(&mut self, imbalance: &BookImbalance) -> Result<(), Terminate>;
+}
+
+impl<F: FnMut(&BookImbalance) -> Result<(), Terminate>> BookImbalanceSink for F {
+  fn on_book_imbalance(&mut self, imbalance: &BookImbalance) -> Result<(), Terminate> {
+    self(imbalance)
+  }
+}
+
+fn volume(levels: Vec<Level>) -> f64 {
+  levels.iter().map(|level| level.size.to_f64().unwrap_or_default()).sum()
+}
+
+/// Maintains level2 books and compares bid and ask volume over the top `levels` levels of
+/// every product. The sink is notified when a book becomes bid or ask heavy, once the imbalance
+/// reaches `threshold` (or `-threshold`), and when it becomes balanced again.
+///
+/// With `hysteresis(exit)` a heavy book stays heavy until the imbalance falls back below `exit`,
+/// so an imbalance hovering around the threshold doesn't flip the state on every update.
+pub struct BookImbalanceHandler<S: BookImbalanceSink> {
+  books: OrderBooks,
+  levels: usize,
+  threshold: f64,
+  exit: f64,
+  states: HashMap<String, ImbalanceState>,
+  sink: S,
+  clock: Arc<dyn Clock>,
+}
+
+impl<S: BookImbalanceSink> BookImbalanceHandler<S> {
+  pub fn new(levels: usize, threshold: f64, sink: S) -> Self {
+    BookImbalanceHandler {
+      books: OrderBooks::new(),
+      levels,
+      threshold,
+      exit: threshold,
+      states: HashMap::new(),
+      sink,
+      clock: Arc::new(SystemClock),
+    }
+  }
+
+  /// Clock stamping changes caused by a `snapshot` message, which carries no time.
+  pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+    self.clock = clock;
+    self
+  }
+
+  /// Imbalance below which a heavy book is balanced again, at most `threshold`.
+  pub fn hysteresis(mut self, exit: f64) -> Self {
+    self.exit = exit.min(self.threshold);
+    self
+  }
+
+  pub fn books(&self) -> &OrderBooks {
+    &self.books
+  }
+
+  pub fn state(&self, product_id: &str) -> Option<ImbalanceState> {
+    self.states.get(product_id).copied()
+  }
+
+  /// Current imbalance of the product's book, `None` without a book or volume.
+  pub fn imbalance(&self, product_id: &str) -> Option<f64> {
+    self.books.get(product_id).and_then(|book| self.measure(book)).map(|(imbalance, _, _)| imbalance)
+  }
+
+  fn measure(&self, book: &OrderBook) -> Option<(f64, f64, f64)> {
+    let bid_volume = volume(book.top_bids(self.levels));
+    let ask_volume = volume(book.top_asks(self.levels));
+    let total = bid_volume + ask_volume;
+    if total <= 0.0 {
+      return None;
+    }
+    Some(((bid_volume - ask_volume) / total, bid_volume, ask_volume))
+  }
+
+  fn next_state(&self, previous: ImbalanceState, imbalance: f64) -> ImbalanceState {
+    match previous {
+      ImbalanceState::BidHeavy if imbalance >= self.exit => ImbalanceState::BidHeavy,
+      ImbalanceState::AskHeavy if imbalance <= -self.exit => ImbalanceState::AskHeavy,
+      _ if imbalance >= self.threshold => ImbalanceState::BidHeavy,
+      _ if imbalance <= -self.threshold => ImbalanceState::AskHeavy,
+      _ => ImbalanceState::Balanced,
+    }
+  }
+
+  fn update(&mut self, product_id: &str, time: DateTime<Utc>) -> Result<(), Terminate> {
+    let (imbalance, bid_volume, ask_volume) = match self.books.get(product_id).and_then(|book| self.measure(book)) {
+      Some(measured) => measured,
+      None => return Ok(()),
+    };
+    let previous = self.states.get(product_id).copied().unwrap_or(ImbalanceState::Balanced);
+    let state = self.next_state(previous, imbalance);
+    self.states.insert(product_id.into(), state);
+    if state == previous {
+      return Ok(());
+    }
+    self.sink.on_book_imbalance(&BookImbalance {
+      product_id: product_id.into(),
+      time,
+      state,
+      previous,
+      imbalance,
+      bid_volume,
+      ask_volume,
+    })
+  }
+}
+
+impl<S: BookImbalanceSink> CoinBaseWebSocketMessageHandler for BookImbalanceHandler<S> {
+  fn on_snapshot(&mut self, resp: &SnapshotResponse) -> Result<(), Terminate> {
+    self.books.on_snapshot(resp)?;
+    self.update(&resp.product_id, self.clock.utc_now())
+  }
+
+  fn on_l2_update(&mut self, resp: &L2UpdateResponse) -> Result<(), Terminate> {
+    self.books.on_l2_update(resp)?;
+    self.update(&resp.product_id, resp.time)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{BookImbalance, BookImbalanceHandler, ImbalanceState};
+  use crate::web_socket::response::{L2UpdateResponse, SnapshotResponse};
+  use crate::web_socket::CoinBaseWebSocketMessageHandler;
+
+  fn bid(size: &str) -> Result<L2UpdateResponse, serde_json::error::Error> {
+    serde_json::from_str(&format!(r#"{{
+      "product_id": "BTC-USD", "time": "2019-08-14T20:42:27.265Z", "changes": [["buy", "100.00", "{}"]]
+    }}"#, size))
+  }
+
+  #[test]
+  fn switch_states_with_hysteresis() -> Result<(), serde_json::error::Error> {
+    let snapshot: SnapshotResponse = serde_json::from_str(r#"{
+      "product_id": "BTC-USD", "bids": [["100.00", "1"]], "asks": [["101.00", "2"], ["150.00", "100"]]
+    }"#)?;
+    let mut events: Vec<BookImbalance> = Vec::new();
+    let mut handler = BookImbalanceHandler::new(1, 0.5, |event: &BookImbalance| {
+      events.push(event.clone());
+      Ok(())
+    }).hysteresis(0.2);
+
+    // Only the best levels count, (1 - 2) / 3.
+    handler.on_snapshot(&snapshot).unwrap();
+    assert_eq!(handler.state("BTC-USD"), Some(ImbalanceState::Balanced));
+    handler.on_l2_update(&bid("6")?).unwrap();
+    // Stays bid heavy down to the exit threshold.
+    handler.on_l2_update(&bid("3")?).unwrap();
+    assert_eq!(handler.state("BTC-USD"), Some(ImbalanceState::BidHeavy));
+    handler.on_l2_update(&bid("2.5")?).unwrap();
+    handler.on_l2_update(&bid("0.5")?).unwrap();
+    assert!((handler.imbalance("BTC-USD").unwrap() + 0.6).abs() < 1e-9);
+    drop(handler);
+
+    let states: Vec<_> = events.iter().map(|event| (event.previous, event.state)).collect();
+    assert_eq!(states, vec![
+      (ImbalanceState::Balanced, ImbalanceState::BidHeavy),
+      (ImbalanceState::BidHeavy, ImbalanceState::Balanced),
+      (ImbalanceState::Balanced, ImbalanceState::AskHeavy),
+    ]);
+    assert_eq!((events[0].bid_volume, events[0].ask_volume), (6.0, 2.0));
+    Ok(())
+  }
+}
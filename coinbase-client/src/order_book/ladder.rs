@@ -0,0 +1,250 @@
+use std::collections::{BTreeSet, HashMap};
+
+use num_traits::ToPrimitive;
+use serde::{Deserialize, Serialize};
+
+use crate::decimal::{Decimal, Zero};
+use crate::web_socket::response::{L2UpdateResponse, Side, SnapshotResponse};
+use crate::web_socket::{CoinBaseWebSocketMessageHandler, Terminate};
+
+use super::{OrderBook, OrderBooks};
+
+/// Sizes of the levels priced within `[price, price + bin_size)`.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct LadderBin {
+  pub price: Decimal,
+  pub bid_size: Decimal,
+  pub ask_size: Decimal,
+}
+
+/// Fixed number of equally wide price bins around the mid price of a book, for heatmaps and
+/// depth ladders. Bins are ordered from the lowest price up and the mid price falls into the
+/// bin in the middle, levels outside the ladder are left out.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct Ladder {
+  pub product_id: String,
+  pub bin_size: Decimal,
+  pub bins: Vec<LadderBin>,
+}
+
+impl Ladder {
+  pub(super) fn around(book: &OrderBook, bin_size: &Decimal, bins: usize) -> Option<Ladder> {
+    if bins == 0 || *bin_size <= Decimal::zero() {
+      return None;
+    }
+    let mid = match (book.best_bid(), book.best_ask()) {
+      (Some(bid), Some(ask)) => (bid.price + ask.price) / Decimal::from(2),
+      (Some(level), None) | (None, Some(level)) => level.price,
+      (None, None) => return None,
+    };
+    let center = (mid / bin_size).to_f64()?.floor() as i64;
+    let lowest = center - (bins / 2) as i64;
+    let bins = (0..bins as i64)
+      .map(|index| bin(book, bin_size.clone() * Decimal::from(lowest + index), bin_size))
+      .collect();
+    Some(Ladder { product_id: book.product_id().into(), bin_size: bin_size.clone(), bins })
+  }
+
+  /// Index of the bin the price falls into.
+  pub fn bin_index(&self, price: &Decimal) -> Option<usize> {
+    let lowest = &self.bins.first()?.price;
+    if price < lowest {
+      return None;
+    }
+    let index = ((price - lowest) / &self.bin_size).to_f64()?.floor() as usize;
+    (index < self.bins.len()).then_some(index)
+  }
+
+  /// Brings a copy of the ladder up to date with an update of its product.
+  pub fn apply(&mut self, update: &LadderUpdate) {
+    match update {
+      LadderUpdate::Full(ladder) => *self = ladder.clone(),
+      LadderUpdate::Bins { bins, .. } => {
+        for (index, bin) in bins {
+          if let Some(current) = self.bins.get_mut(*index) {
+            *current = bin.clone();
+          }
+        }
+      }
+    }
+  }
+}
+
+fn bin(book: &OrderBook, price: Decimal, bin_size: &Decimal) -> LadderBin {
+  let high = &price + bin_size;
+  LadderBin {
+    bid_size: book.size_between(Side::BUY, &price, &high),
+    ask_size: book.size_between(Side::SELL, &price, &high),
+    price,
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum LadderUpdate {
+  /// Whole ladder, sent first and whenever the ladder is recentered.
+  Full(Ladder),
+  /// Bins that changed, by their index in the ladder.
+  Bins { product_id: String, bins: Vec<(usize, LadderBin)> },
+}
+
+impl LadderUpdate {
+  pub fn product_id(&self) -> &str {
+    match self {
+      LadderUpdate::Full(ladder) => ladder.product_id.as_str(),
+      LadderUpdate::Bins { product_id, .. } => product_id.as_str(),
+    }
+  }
+}
+
+pub trait LadderSink {
+  fn on_ladder(&mut self, update: &LadderUpdate) -> Result<(), Terminate>;
+}
+
+impl<F: FnMut(&LadderUpdate) -> Result<(), Terminate>> LadderSink for F {
+  fn on_ladder(&mut self, update: &LadderUpdate) -> Result<(), Terminate> {
+    self(update)
+  }
+}
+
+/// Keeps a ladder of every level2 book and emits only the bins an update changed, so
+/// consumers apply them with `Ladder::apply` instead of rebuilding the ladder on every tick.
+///
+/// The ladder stays in place while the mid price moves, and is recentered with a full update
+/// once the mid price moves more than `recenter_after` bins from the middle, a quarter of the
+/// bins by default.
+pub struct LadderHandler<S: LadderSink> {
+  books: OrderBooks,
+  bin_size: Decimal,
+  bins: usize,
+  recenter_after: usize,
+  ladders: HashMap<String, Ladder>,
+  sink: S,
+}
+
+impl<S: LadderSink> LadderHandler<S> {
+  pub fn new(bin_size: Decimal, bins: usize, sink: S) -> Self {
+    LadderHandler { books: OrderBooks::new(), bin_size, bins, recenter_after: (bins / 4).max(1), ladders: HashMap::new(), sink }
+  }
+
+  pub fn recenter_after(mut self, bins: usize) -> Self {
+    self.recenter_after = bins;
+    self
+  }
+
+  pub fn books(&self) -> &OrderBooks {
+    &self.books
+  }
+
+  pub fn ladder(&self, product_id: &str) -> Option<&Ladder> {
+    self.ladders.get(product_id)
+  }
+
+  fn rebuild(&mut self, product_id: &str) -> Result<(), Terminate> {
+    let ladder = match self.books.get(product_id).and_then(|book| book.ladder(&self.bin_size, self.bins)) {
+      Some(ladder) => ladder,
+      None => {
+        self.ladders.remove(product_id);
+        return Ok(());
+      }
+    };
+    let update = LadderUpdate::Full(ladder.clone());
+    self.ladders.insert(product_id.into(), ladder);
+    self.sink.on_ladder(&update)
+  }
+
+  fn update(&mut self, resp: &L2UpdateResponse) -> Result<(), Terminate> {
+    let (book, ladder) = match (self.books.get(&resp.product_id), self.ladders.get_mut(&resp.product_id)) {
+      (Some(book), Some(ladder)) => (book, ladder),
+      _ => return self.rebuild(&resp.product_id),
+    };
+    let (bin_size, recenter_after) = (&self.bin_size, self.recenter_after);
+    let middle = ladder.bins.len() / 2;
+    let centered = match (book.best_bid(), book.best_ask()) {
+      (Some(bid), Some(ask)) => ladder.bin_index(&((bid.price + ask.price) / Decimal::from(2)))
+        .is_some_and(|index| index.abs_diff(middle) <= recenter_after),
+      _ => false,
+    };
+    if !centered {
+      return self.rebuild(&resp.product_id);
+    }
+
+    let touched: BTreeSet<usize> = resp.changes.iter().filter_map(|change| ladder.bin_index(&change.price)).collect();
+    let bins: Vec<(usize, LadderBin)> = touched.into_iter()
+      .map(|index| (index, bin(book, ladder.bins[index].price.clone(), bin_size)))
+      .filter(|(index, bin)| ladder.bins[*index] != *bin)
+      .collect();
+    if bins.is_empty() {
+      return Ok(());
+    }
+    let update = LadderUpdate::Bins { product_id: resp.product_id.clone(), bins };
+    ladder.apply(&update);
+    self.sink.on_ladder(&update)
+  }
+}
+
+impl<S: LadderSink> CoinBaseWebSocketMessageHandler for LadderHandler<S> {
+  fn on_snapshot(&mut self, resp: &SnapshotResponse) -> Result<(), Terminate> {
+    self.books.on_snapshot(resp)?;
+    self.rebuild(&resp.product_id)
+  }
+
+  fn on_l2_update(&mut self, resp: &L2UpdateResponse) -> Result<(), Terminate> {
+    self.books.on_l2_update(resp)?;
+    self.update(resp)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{Ladder, LadderHandler, LadderUpdate};
+  use crate::web_socket::response::{L2UpdateResponse, SnapshotResponse};
+  use crate::web_socket::CoinBaseWebSocketMessageHandler;
+
+  fn update(changes: &str) -> L2UpdateResponse {
+    serde_json::from_str(&format!(r#"{{"product_id": "BTC-USD", "time": "2020-08-31T15:00:00Z", "changes": {}}}"#, changes)).unwrap()
+  }
+
+  #[test]
+  fn keep_ladder_up_to_date() {
+    let mut updates: Vec<LadderUpdate> = Vec::new();
+    let mut handler = LadderHandler::new("1".parse().unwrap(), 6, |update: &LadderUpdate| {
+      updates.push(update.clone());
+      Ok(())
+    });
+    let snapshot: SnapshotResponse = serde_json::from_str(r#"{
+      "product_id": "BTC-USD",
+      "bids": [["99.5", "1"], ["99", "2"], ["98.2", "1"], ["90", "5"]],
+      "asks": [["100.5", "1"], ["101", "3"]]
+    }"#).unwrap();
+    handler.on_snapshot(&snapshot).unwrap();
+    let ladder = handler.ladder("BTC-USD").unwrap().clone();
+    let sizes: Vec<(String, String, String)> = ladder.bins.iter()
+      .map(|bin| (bin.price.to_string(), bin.bid_size.to_string(), bin.ask_size.to_string()))
+      .collect();
+    let expected = [("97", "0", "0"), ("98", "1", "0"), ("99", "3", "0"), ("100", "0", "1"), ("101", "0", "3"), ("102", "0", "0")];
+    assert_eq!(sizes, expected.iter().map(|(a, b, c)| (a.to_string(), b.to_string(), c.to_string())).collect::<Vec<_>>());
+
+    // Only the changed bin, levels outside the ladder are ignored.
+    handler.on_l2_update(&update(r#"[["sell", "100.7", "2"], ["buy", "90", "6"]]"#)).unwrap();
+    // Mid price moves two bins up, the ladder is recentered.
+    handler.on_l2_update(&update(r#"[["sell", "100.5", "0"], ["sell", "100.7", "0"], ["sell", "101", "0"], ["sell", "106", "1"]]"#)).unwrap();
+    let current = handler.ladder("BTC-USD").unwrap().clone();
+    drop(handler);
+
+    assert_eq!(updates.len(), 3);
+    match &updates[1] {
+      LadderUpdate::Bins { bins, .. } => assert_eq!((bins.len(), bins[0].0, bins[0].1.ask_size.to_string()), (1, 3, "3".to_string())),
+      update => panic!("Expected changed bins, got {:?}", update),
+    }
+    let mut copy: Option<Ladder> = None;
+    for update in updates.iter() {
+      match (&mut copy, update) {
+        (Some(copy), update) => copy.apply(update),
+        (None, LadderUpdate::Full(ladder)) => copy = Some(ladder.clone()),
+        (None, update) => panic!("Expected full ladder first, got {:?}", update),
+      }
+    }
+    assert_eq!(copy.unwrap(), current);
+    assert_eq!(current.bins[0].price, "99".parse().unwrap());
+  }
+}
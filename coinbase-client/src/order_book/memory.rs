@@ -0,0 +1,172 @@
+use std::mem::size_of;
+use std::ops::{Add, AddAssign};
+
+use serde::{Deserialize, Serialize};
+
+use crate::decimal::Decimal;
+use crate::web_socket::response::OrderId;
+
+// Approximate bookkeeping cost of a map entry on top of its key and value.
+const ENTRY_OVERHEAD: usize = 16;
+// Book and its product id.
+const BOOK_BYTES: usize = 128;
+
+/// Approximate memory held by books. Only the inline size of decimals is counted, big decimal
+/// backends allocate a little more on the heap.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct MemoryUsage {
+  pub books: usize,
+  /// Price levels of level2 books.
+  pub levels: usize,
+  /// Orders of level3 books.
+  pub orders: usize,
+  pub bytes: usize,
+}
+
+impl MemoryUsage {
+  pub(crate) fn of_book(levels: usize) -> Self {
+    MemoryUsage::of_books(1, levels)
+  }
+
+  pub(crate) fn of_books(books: usize, levels: usize) -> Self {
+    MemoryUsage { books, levels, orders: 0, bytes: books * BOOK_BYTES + levels * level_bytes() }
+  }
+
+  pub(crate) fn of_orders(orders: usize, ids: usize) -> Self {
+    let order_bytes = size_of::<OrderId>() + 2 * size_of::<Decimal>() + ENTRY_OVERHEAD;
+    let id_bytes = size_of::<OrderId>() + ENTRY_OVERHEAD;
+    MemoryUsage { books: 0, levels: 0, orders, bytes: orders * order_bytes + ids * id_bytes }
+  }
+}
+
+pub(crate) fn level_bytes() -> usize {
+  2 * size_of::<Decimal>() + ENTRY_OVERHEAD
+}
+
+impl Add for MemoryUsage {
+  type Output = MemoryUsage;
+
+  fn add(mut self, other: MemoryUsage) -> MemoryUsage {
+    self += other;
+    self
+  }
+}
+
+impl AddAssign for MemoryUsage {
+  fn add_assign(&mut self, other: MemoryUsage) {
+    self.books += other.books;
+    self.levels += other.levels;
+    self.orders += other.orders;
+    self.bytes += other.bytes;
+  }
+}
+
+/// What happens to a book that goes over a limit.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EvictionPolicy {
+  /// Remove the levels furthest from the best price, the book stays usable near the top.
+  TrimDeepLevels,
+  /// Drop the whole book and ignore its updates until the next snapshot, e.g. after the
+  /// product is subscribed again.
+  Resync,
+}
+
+/// Caps of `OrderBooks`, nothing is capped by default.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct MemoryLimits {
+  pub max_levels_per_side: Option<usize>,
+  /// Cap of `MemoryUsage::bytes` of all books together, checked for the book being updated.
+  pub max_bytes: Option<usize>,
+  pub policy: EvictionPolicy,
+}
+
+impl Default for MemoryLimits {
+  fn default() -> Self {
+    MemoryLimits { max_levels_per_side: None, max_bytes: None, policy: EvictionPolicy::TrimDeepLevels }
+  }
+}
+
+impl MemoryLimits {
+  pub fn new(policy: EvictionPolicy) -> Self {
+    MemoryLimits { policy, ..MemoryLimits::default() }
+  }
+
+  pub fn max_levels_per_side(mut self, levels: usize) -> Self {
+    self.max_levels_per_side = Some(levels);
+    self
+  }
+
+  pub fn max_bytes(mut self, bytes: usize) -> Self {
+    self.max_bytes = Some(bytes);
+    self
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{level_bytes, EvictionPolicy, MemoryLimits, MemoryUsage};
+  use crate::order_book::{OrderBook, OrderBooks};
+  use crate::web_socket::response::{L2UpdateResponse, SnapshotResponse};
+  use crate::web_socket::CoinBaseWebSocketMessageHandler;
+
+  fn snapshot(product_id: &str) -> Result<SnapshotResponse, serde_json::error::Error> {
+    serde_json::from_str(&format!(r#"{{
+      "product_id": "{}",
+      "bids": [["100", "1"], ["99", "1"], ["98", "1"]],
+      "asks": [["101", "1"], ["102", "1"]]
+    }}"#, product_id))
+  }
+
+  fn update(product_id: &str) -> Result<L2UpdateResponse, serde_json::error::Error> {
+    serde_json::from_str(&format!(r#"{{
+      "product_id": "{}", "time": "2019-08-14T20:42:27.265Z", "changes": [["sell", "103", "1"], ["sell", "104", "1"]]
+    }}"#, product_id))
+  }
+
+  #[test]
+  fn enforce_limits() -> Result<(), serde_json::error::Error> {
+    let limits = MemoryLimits::new(EvictionPolicy::TrimDeepLevels).max_levels_per_side(2);
+    let mut books = OrderBooks::new().with_limits(limits);
+    books.on_snapshot(&snapshot("BTC-USD")?).unwrap();
+    books.on_l2_update(&update("BTC-USD")?).unwrap();
+    let book = books.get("BTC-USD").unwrap();
+    assert_eq!(book.top_bids(5).len(), 2);
+    assert_eq!(book.top_asks(5).last().unwrap().price, "102".parse().unwrap());
+    assert_eq!(books.memory_usage(), MemoryUsage::of_book(4));
+
+    // Room for one book of 5 levels.
+    let limits = MemoryLimits::new(EvictionPolicy::Resync).max_bytes(MemoryUsage::of_book(5).bytes + level_bytes());
+    let mut books = OrderBooks::new().with_limits(limits);
+    books.on_snapshot(&snapshot("BTC-USD")?).unwrap();
+    books.on_snapshot(&snapshot("ETH-USD")?).unwrap();
+    assert!(books.get("ETH-USD").is_none());
+    assert_eq!(books.awaiting_snapshot().collect::<Vec<_>>(), vec!["ETH-USD"]);
+    books.on_l2_update(&update("ETH-USD")?).unwrap();
+    books.on_l2_update(&update("BTC-USD")?).unwrap();
+    assert!(books.get("BTC-USD").is_none());
+    assert_eq!((books.evictions(), books.memory_usage()), (2, MemoryUsage::default()));
+    Ok(())
+  }
+
+  #[test]
+  fn keep_running_total_of_books() -> Result<(), serde_json::error::Error> {
+    let total = |books: &OrderBooks| books.iter().fold(MemoryUsage::default(), |usage, book| usage + book.memory_usage());
+    // Room for two books of 5 levels and one more level.
+    let limits = MemoryLimits::new(EvictionPolicy::TrimDeepLevels).max_bytes(2 * MemoryUsage::of_book(5).bytes + level_bytes());
+    let mut books = OrderBooks::new().with_limits(limits);
+    books.on_snapshot(&snapshot("BTC-USD")?).unwrap();
+    books.on_snapshot(&snapshot("ETH-USD")?).unwrap();
+    assert_eq!(books.memory_usage(), MemoryUsage::of_books(2, 10));
+    books.on_l2_update(&update("BTC-USD")?).unwrap();
+    assert_eq!((books.evictions(), books.memory_usage()), (1, MemoryUsage::of_books(2, 11)));
+    assert_eq!(books.memory_usage(), total(&books));
+
+    // Replaced books are no longer counted.
+    books.insert(OrderBook::from_snapshot(&snapshot("BTC-USD")?));
+    books.insert(OrderBook::new("SOL-USD"));
+    assert_eq!(books.memory_usage(), MemoryUsage::of_books(3, 10));
+    assert_eq!(books.memory_usage(), total(&books));
+    Ok(())
+  }
+}
@@ -0,0 +1,35 @@
+pub mod book;
+pub use book::{Level, OrderBook, OrderBooks};
+
+pub mod ladder;
+pub use ladder::{Ladder, LadderBin, LadderHandler, LadderSink, LadderUpdate};
+
+pub mod memory;
+pub use memory::{EvictionPolicy, MemoryLimits, MemoryUsage};
+
+pub mod depth;
+pub use depth::{DepthChangeHandler, DepthSnapshot, DepthSnapshotHandler, DepthSnapshotSink};
+
+pub mod bbo;
+pub use bbo::{Bbo, BboSink, BboTracker, SpreadStats};
+
+pub mod top;
+pub use top::{TopOfBook, TopOfBookHandler, TopOfBookSink};
+
+pub mod imbalance;
+pub use imbalance::{BookImbalance, BookImbalanceHandler, BookImbalanceSink, ImbalanceState};
+
+pub mod delta;
+pub use delta::{DeltaReader, DeltaWriter};
+
+pub mod persist;
+pub use persist::{BookStore, PersistedBook, PersistentOrderBooks};
+
+pub mod historical;
+pub use historical::BookHistory;
+
+pub mod full;
+pub use full::{FullChannelTranslator, OrderBookEvent, OrderBookEventSink};
+
+pub mod validate;
+pub use validate::{BookDiscrepancy, BookDiscrepancySink, DiscrepancyKind, MatchBookValidator};
@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::rest::{CoinbaseRestClient, RestError};
+use crate::web_socket::response::{Change, L2UpdateResponse, SnapshotResponse};
+use crate::web_socket::{Clock, CoinBaseWebSocketMessageHandler, SystemClock, Terminate};
+
+use super::{OrderBook, OrderBooks};
+
+const BOOK_STORE_ID: &str = "BookStore";
+
+/// Book state written to disk, the book with the time it was saved.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PersistedBook {
+  pub time: DateTime<Utc>,
+  pub snapshot: SnapshotResponse,
+}
+
+/// Directory with the latest saved book of every product, a `book_<product>.json` file each.
+#[derive(Debug, Clone)]
+pub struct BookStore {
+  directory: PathBuf,
+}
+
+impl BookStore {
+  pub fn open(directory: &Path) -> io::Result<Self> {
+    fs::create_dir_all(directory)?;
+    Ok(BookStore { directory: directory.to_path_buf() })
+  }
+
+  fn path(&self, product_id: &str) -> PathBuf {
+    self.directory.join(format!("book_{}.json", product_id))
+  }
+
+  /// Writes into a temporary file that is renamed over the previous one, so a crash while
+  /// saving leaves the previous book.
+  pub fn save(&self, book: &OrderBook, time: DateTime<Utc>) -> io::Result<()> {
+    let path = self.path(book.product_id());
+    let tmp_path = path.with_extension("json.tmp");
+    let mut file = File::create(&tmp_path)?;
+    serde_json::to_writer(&mut file, &PersistedBook { time, snapshot: book.to_snapshot() })?;
+    file.flush()?;
+    fs::rename(tmp_path, path)
+  }
+
+  pub fn load(&self, product_id: &str) -> io::Result<Option<PersistedBook>> {
+    match fs::read(self.path(product_id)) {
+      Ok(content) => Ok(Some(serde_json::from_slice(&content)?)),
+      Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+      Err(err) => Err(err),
+    }
+  }
+
+  /// Every saved book in the directory.
+  pub fn load_all(&self) -> io::Result<Vec<PersistedBook>> {
+    let mut books = Vec::new();
+    for entry in fs::read_dir(&self.directory)? {
+      let file_name = entry?.file_name().to_string_lossy().into_owned();
+      if let Some(product_id) = file_name.strip_prefix("book_").and_then(|name| name.strip_suffix(".json")) {
+        books.extend(self.load(product_id)?);
+      }
+    }
+    Ok(books)
+  }
+}
+
+/// Maintains level2 books like `OrderBooks` and saves every product's book to the store at most
+/// once per `interval`, and all of them on `close`. Books are saved on the handler thread.
+///
+/// After a restart `warm_start` restores the saved books, so they are available before the
+/// `snapshot` message of the new subscription arrives, and `catch_up` brings them closer to the
+/// current state with the REST book. Restored books can miss changes beyond the REST book's
+/// depth and are replaced by the `snapshot` message.
+pub struct PersistentOrderBooks {
+  books: OrderBooks,
+  store: BookStore,
+  interval: Duration,
+  last_saved: HashMap<String, Instant>,
+  clock: Arc<dyn Clock>,
+}
+
+impl PersistentOrderBooks {
+  pub fn new(store: BookStore, interval: Duration) -> Self {
+    PersistentOrderBooks { books: OrderBooks::new(), store, interval, last_saved: HashMap::new(), clock: Arc::new(SystemClock) }
+  }
+
+  /// Clock the save interval, save times and the age of restored books are taken from.
+  pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+    self.clock = clock;
+    self
+  }
+
+  pub fn books(&self) -> &OrderBooks {
+    &self.books
+  }
+
+  /// Restores books saved within `max_age` and returns their products.
+  pub fn warm_start(&mut self, max_age: Duration) -> io::Result<Vec<String>> {
+    let max_age = chrono::Duration::from_std(max_age).unwrap_or(chrono::Duration::MAX);
+    let now = self.clock.utc_now();
+    let mut product_ids = Vec::new();
+    for persisted in self.store.load_all()? {
+      if now - persisted.time > max_age {
+        tracing::debug!(target: BOOK_STORE_ID, "Skipping book of {} saved at {}.", persisted.snapshot.product_id, persisted.time);
+        continue;
+      }
+      product_ids.push(persisted.snapshot.product_id.clone());
+      self.books.insert(OrderBook::from_snapshot(&persisted.snapshot));
+    }
+    tracing::info!(target: BOOK_STORE_ID, "Restored books of {:?}.", product_ids);
+    Ok(product_ids)
+  }
+
+  /// Overlays the REST book of the product onto its restored book, see `OrderBook::overlay`,
+  /// and returns the changes since the book was saved. Products without a book get a new one.
+  pub fn catch_up(&mut self, rest_client: &CoinbaseRestClient, product_id: &str) -> Result<Vec<Change>, RestError> {
+    let snapshot = rest_client.get_product_book(product_id)?.to_snapshot(product_id);
+    let mut book = self.books.get(product_id).cloned().unwrap_or_else(|| OrderBook::new(product_id));
+    let changes = book.overlay(&snapshot);
+    self.books.insert(book);
+    Ok(changes)
+  }
+
+  /// Saves books of all products.
+  pub fn save_all(&mut self) -> io::Result<()> {
+    let now = self.clock.utc_now();
+    for book in self.books.iter() {
+      self.store.save(book, now)?;
+    }
+    let saved_at = self.clock.now();
+    for saved in self.last_saved.values_mut() {
+      *saved = saved_at;
+    }
+    Ok(())
+  }
+
+  fn save_due(&mut self, product_id: &str) {
+    let now = self.clock.now();
+    let interval = self.interval;
+    if self.last_saved.get(product_id).is_some_and(|saved| now.saturating_duration_since(*saved) < interval) {
+      return;
+    }
+    let book = match self.books.get(product_id) {
+      Some(book) => book,
+      None => return,
+    };
+    if let Err(err) = self.store.save(book, self.clock.utc_now()) {
+      tracing::warn!(target: BOOK_STORE_ID, "Could not save book of {}: {}", product_id, err);
+    }
+    self.last_saved.insert(product_id.into(), now);
+  }
+}
+
+impl CoinBaseWebSocketMessageHandler for PersistentOrderBooks {
+  fn on_snapshot(&mut self, resp: &SnapshotResponse) -> Result<(), Terminate> {
+    self.books.on_snapshot(resp)?;
+    self.save_due(&resp.product_id);
+    Ok(())
+  }
+
+  fn on_l2_update(&mut self, resp: &L2UpdateResponse) -> Result<(), Terminate> {
+    self.books.on_l2_update(resp)?;
+    self.save_due(&resp.product_id);
+    Ok(())
+  }
+
+  fn close(&mut self) -> Result<(), Terminate> {
+    if let Err(err) = self.save_all() {
+      tracing::warn!(target: BOOK_STORE_ID, "Could not save books: {}", err);
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::time::Duration;
+
+  use super::{BookStore, PersistentOrderBooks};
+  use crate::web_socket::response::{L2UpdateResponse, SnapshotResponse};
+  use crate::web_socket::CoinBaseWebSocketMessageHandler;
+
+  #[test]
+  fn warm_start_from_saved_books() -> Result<(), serde_json::error::Error> {
+    let directory = std::env::temp_dir().join(format!("coinbase-books-{}", std::process::id()));
+    let store = BookStore::open(&directory).unwrap();
+    let snapshot: SnapshotResponse = serde_json::from_str(r#"{
+      "product_id": "BTC-USD", "bids": [["100.00", "1"]], "asks": [["101.00", "2"], ["150.00", "100"]]
+    }"#)?;
+    let update: L2UpdateResponse = serde_json::from_str(r#"{
+      "product_id": "BTC-USD", "time": "2019-08-14T20:42:27.265Z", "changes": [["sell", "101.00", "0"]]
+    }"#)?;
+
+    let mut books = PersistentOrderBooks::new(store.clone(), Duration::from_secs(3600));
+    books.on_snapshot(&snapshot).unwrap();
+    // Saved with the snapshot, the update waits for the interval or `close`.
+    books.on_l2_update(&update).unwrap();
+    assert_eq!(store.load("BTC-USD").unwrap().unwrap().snapshot.asks.len(), 2);
+    books.close().unwrap();
+
+    let mut restored = PersistentOrderBooks::new(store, Duration::from_secs(3600));
+    assert_eq!(restored.warm_start(Duration::from_secs(60)).unwrap(), vec!["BTC-USD".to_string()]);
+    let book = restored.books().get("BTC-USD").unwrap();
+    assert_eq!(book.best_ask().unwrap().price, "150.00".parse().unwrap());
+    assert_eq!(book.top_bids(5).len(), 1);
+
+    // Only the REST book's price range is replaced.
+    let mut book = book.clone();
+    let rest: SnapshotResponse = serde_json::from_str(r#"{
+      "product_id": "BTC-USD", "bids": [["100.00", "3"]], "asks": [["120.00", "5"]]
+    }"#)?;
+    assert_eq!(book.overlay(&rest).len(), 2);
+    assert_eq!(book.top_asks(5).len(), 2);
+
+    std::fs::remove_dir_all(&directory).unwrap();
+    Ok(())
+  }
+}
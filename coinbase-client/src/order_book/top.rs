@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::web_socket::response::{HeartBeatResponse, L2UpdateResponse, SnapshotResponse};
+use crate::web_socket::{Clock, CoinBaseWebSocketMessageHandler, SystemClock, Terminate};
+
+use super::{Level, OrderBooks};
+
+/// Best bid and ask levels of a level2 order book.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct TopOfBook {
+  pub product_id: String,
+  pub time: DateTime<Utc>,
+  pub bid: Option<Level>,
+  pub ask: Option<Level>,
+  /// Number of earlier top of book changes merged into this one, zero without coalescing.
+  pub coalesced: u64,
+}
+
+impl TopOfBook {
+  fn same_levels(&self, other: &TopOfBook) -> bool {
+    self.bid == other.bid && self.ask == other.ask
+  }
+}
+
+pub trait TopOfBookSink {
+  fn on_top_of_book(&mut self, top: &TopOfBook) -> Result<(), Terminate>;
+}
+
+impl<F: FnMut(&TopOfBook) -> Result<(), Terminate>> TopOfBookSink for F {
+  fn on_top_of_book(&mut self, top: &TopOfBook) -> Result<(), Terminate> {
+    self(top)
+  }
+}
+
+#[derive(Default)]
+struct ProductState {
+  emitted: Option<TopOfBook>,
+  pending: Option<TopOfBook>,
+  last_emit: Option<Instant>,
+}
+
+/// Maintains level2 books and notifies the sink whenever best bid or ask (price or size) of a
+/// product changes.
+///
+/// With `coalesce(min_interval)` the sink is notified at most once per `min_interval` for every
+/// product, with the latest state; changes in between are merged and changes that cancel out
+/// are not reported at all. Like `DepthSnapshotHandler`, pending changes are emitted when the
+/// next message for the product arrives, subscribe to `heartbeat` to bound the delay.
+pub struct TopOfBookHandler<S: TopOfBookSink> {
+  books: OrderBooks,
+  min_interval: Option<Duration>,
+  products: HashMap<String, ProductState>,
+  sink: S,
+  clock: Arc<dyn Clock>,
+}
+
+impl<S: TopOfBookSink> TopOfBookHandler<S> {
+  pub fn new(sink: S) -> Self {
+    TopOfBookHandler { books: OrderBooks::new(), min_interval: None, products: HashMap::new(), sink, clock: Arc::new(SystemClock) }
+  }
+
+  pub fn coalesce(mut self, min_interval: Duration) -> Self {
+    self.min_interval = Some(min_interval);
+    self
+  }
+
+  /// Clock the coalescing interval is measured with, also stamps tops taken from snapshots.
+  pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+    self.clock = clock;
+    self
+  }
+
+  pub fn books(&self) -> &OrderBooks {
+    &self.books
+  }
+
+  /// Emits all pending changes regardless of the interval.
+  pub fn flush(&mut self) -> Result<(), Terminate> {
+    let now = self.clock.now();
+    for state in self.products.values_mut() {
+      if let Some(top) = state.pending.take() {
+        self.sink.on_top_of_book(&top)?;
+        state.emitted = Some(top);
+        state.last_emit = Some(now);
+      }
+    }
+    Ok(())
+  }
+
+  fn update(&mut self, product_id: &str, time: DateTime<Utc>, now: Instant) -> Result<(), Terminate> {
+    let book = match self.books.get(product_id) {
+      Some(book) => book,
+      None => return Ok(()),
+    };
+    let mut top = TopOfBook {
+      product_id: product_id.into(),
+      time,
+      bid: book.best_bid(),
+      ask: book.best_ask(),
+      coalesced: 0,
+    };
+
+    let state = self.products.entry(product_id.into()).or_default();
+    let same_as = |other: &Option<TopOfBook>| other.as_ref().map(|other| other.same_levels(&top)).unwrap_or(false);
+    let (same_as_pending, same_as_emitted) = (same_as(&state.pending), same_as(&state.emitted));
+    if same_as_pending {
+      return self.emit_if_due(product_id, now);
+    }
+    if same_as_emitted {
+      // Changes since the last notification cancelled out.
+      state.pending = None;
+      return Ok(());
+    }
+    if let Some(pending) = &state.pending {
+      top.coalesced = pending.coalesced + 1;
+    }
+    state.pending = Some(top);
+    self.emit_if_due(product_id, now)
+  }
+
+  fn emit_if_due(&mut self, product_id: &str, now: Instant) -> Result<(), Terminate> {
+    let state = match self.products.get_mut(product_id) {
+      Some(state) => state,
+      None => return Ok(()),
+    };
+    let due = match (self.min_interval, state.last_emit) {
+      (Some(min_interval), Some(last_emit)) => now >= last_emit + min_interval,
+      _ => true,
+    };
+    if !due {
+      return Ok(());
+    }
+    if let Some(top) = state.pending.take() {
+      self.sink.on_top_of_book(&top)?;
+      state.emitted = Some(top);
+      state.last_emit = Some(now);
+    }
+    Ok(())
+  }
+}
+
+impl<S: TopOfBookSink> CoinBaseWebSocketMessageHandler for TopOfBookHandler<S> {
+  fn on_heartbeat(&mut self, resp: &HeartBeatResponse) -> Result<(), Terminate> {
+    self.emit_if_due(resp.product_id.as_str(), self.clock.now())
+  }
+
+  fn on_snapshot(&mut self, resp: &SnapshotResponse) -> Result<(), Terminate> {
+    self.books.on_snapshot(resp)?;
+    self.update(resp.product_id.as_str(), self.clock.utc_now(), self.clock.now())
+  }
+
+  fn on_l2_update(&mut self, resp: &L2UpdateResponse) -> Result<(), Terminate> {
+    self.books.on_l2_update(resp)?;
+    self.update(resp.product_id.as_str(), resp.time, self.clock.now())
+  }
+
+  fn close(&mut self) -> Result<(), Terminate> {
+    self.flush()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::time::Duration;
+
+  use super::{TopOfBook, TopOfBookHandler};
+  use crate::web_socket::response::{L2UpdateResponse, SnapshotResponse};
+  use crate::web_socket::CoinBaseWebSocketMessageHandler;
+
+  fn update(side: &str, price: &str, size: &str) -> Result<L2UpdateResponse, serde_json::error::Error> {
+    serde_json::from_str(&format!(r#"{{
+      "product_id": "BTC-USD", "time": "2019-08-14T20:42:27.265Z", "changes": [["{}", "{}", "{}"]]
+    }}"#, side, price, size))
+  }
+
+  #[test]
+  fn coalesce_top_of_book_changes() -> Result<(), serde_json::error::Error> {
+    let snapshot: SnapshotResponse = serde_json::from_str(r#"{
+      "product_id": "BTC-USD", "bids": [["10101.10", "0.45"]], "asks": [["10102.55", "0.57"]]
+    }"#)?;
+    let mut tops: Vec<TopOfBook> = Vec::new();
+    let mut handler = TopOfBookHandler::new(|top: &TopOfBook| {
+      tops.push(top.clone());
+      Ok(())
+    }).coalesce(Duration::from_secs(60));
+
+    handler.on_snapshot(&snapshot).unwrap();
+    // Below the best bid, top of book doesn't change.
+    handler.on_l2_update(&update("buy", "10100.00", "1.0")?).unwrap();
+    handler.on_l2_update(&update("buy", "10101.20", "1.0")?).unwrap();
+    handler.on_l2_update(&update("sell", "10102.50", "2.0")?).unwrap();
+    handler.close().unwrap();
+    drop(handler);
+
+    assert_eq!(tops.len(), 2);
+    assert_eq!(tops[0].coalesced, 0);
+    assert_eq!(tops[1].coalesced, 1);
+    assert_eq!(tops[1].bid.as_ref().unwrap().price, "10101.20".parse().unwrap());
+    assert_eq!(tops[1].ask.as_ref().unwrap().price, "10102.50".parse().unwrap());
+    Ok(())
+  }
+}
@@ -0,0 +1,167 @@
+use chrono::{DateTime, Utc};
+use num_traits::ToPrimitive;
+use serde::{Deserialize, Serialize};
+
+use crate::decimal::Decimal;
+use crate::web_socket::response::{L2UpdateResponse, MatchResponse, Side, SnapshotResponse};
+use crate::web_socket::{CoinBaseWebSocketMessageHandler, Terminate};
+
+use super::{OrderBook, OrderBooks};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum DiscrepancyKind {
+  /// Trade price is through the opposite side of the book, e.g. a sell maker filled below the
+  /// best bid.
+  CrossedPrice { opposite_best: Decimal },
+  /// Trade size is larger than the maker side's liquidity priced at the trade price or better.
+  ExceedsLiquidity { displayed: Decimal },
+}
+
+/// Match that doesn't fit the local level2 book, which usually means the book is out of sync.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BookDiscrepancy {
+  pub product_id: String,
+  pub time: DateTime<Utc>,
+  pub trade_id: i64,
+  /// Side of the maker order.
+  pub side: Side,
+  pub price: Decimal,
+  pub size: Decimal,
+  #[serde(flatten)]
+  pub kind: DiscrepancyKind,
+}
+
+pub trait BookDiscrepancySink {
+  fn on_book_discrepancy(&mut self, discrepancy: &BookDiscrepancy) -> Result<(), Terminate>;
+}
+
+impl<F: FnMut(&BookDiscrepancy) -> Result<(), Terminate>> BookDiscrepancySink for F {
+  fn on_book_discrepancy(&mut self, discrepancy: &BookDiscrepancy) -> Result<(), Terminate> {
+    self(discrepancy)
+  }
+}
+
+/// Maintains level2 books and checks every match of the `matches` channel against them: the
+/// price must not be through the opposite side of the book and the size must not exceed the
+/// maker side's displayed size at the price or better by more than `tolerance` (a fraction,
+/// 0.1 allows 10% more).
+///
+/// Level2 updates and matches come on different channels, an update that already removed the
+/// filled liquidity can arrive first, so allow some tolerance and look at the rate of
+/// discrepancies rather than single ones. Products without a book are not checked.
+pub struct MatchBookValidator<S: BookDiscrepancySink> {
+  books: OrderBooks,
+  tolerance: f64,
+  checked: u64,
+  discrepancies: u64,
+  sink: S,
+}
+
+impl<S: BookDiscrepancySink> MatchBookValidator<S> {
+  pub fn new(tolerance: f64, sink: S) -> Self {
+    MatchBookValidator { books: OrderBooks::new(), tolerance, checked: 0, discrepancies: 0, sink }
+  }
+
+  pub fn books(&self) -> &OrderBooks {
+    &self.books
+  }
+
+  /// Number of matches checked against a book.
+  pub fn checked(&self) -> u64 {
+    self.checked
+  }
+
+  pub fn discrepancies(&self) -> u64 {
+    self.discrepancies
+  }
+
+  fn check(&self, book: &OrderBook, resp: &MatchResponse) -> Option<DiscrepancyKind> {
+    let opposite_best = match resp.side {
+      Side::SELL => book.best_bid().filter(|best| resp.price < best.price),
+      Side::BUY => book.best_ask().filter(|best| resp.price > best.price),
+    };
+    if let Some(best) = opposite_best {
+      return Some(DiscrepancyKind::CrossedPrice { opposite_best: best.price });
+    }
+    let (displayed, _) = book.depth_within(resp.side, &resp.price);
+    let limit = displayed.to_f64().unwrap_or_default() * (1.0 + self.tolerance);
+    if resp.size.to_f64().unwrap_or_default() > limit {
+      return Some(DiscrepancyKind::ExceedsLiquidity { displayed });
+    }
+    None
+  }
+}
+
+impl<S: BookDiscrepancySink> CoinBaseWebSocketMessageHandler for MatchBookValidator<S> {
+  fn on_snapshot(&mut self, resp: &SnapshotResponse) -> Result<(), Terminate> {
+    self.books.on_snapshot(resp)
+  }
+
+  fn on_l2_update(&mut self, resp: &L2UpdateResponse) -> Result<(), Terminate> {
+    self.books.on_l2_update(resp)
+  }
+
+  fn on_match(&mut self, resp: &MatchResponse) -> Result<(), Terminate> {
+    let kind = match self.books.get(&resp.product_id) {
+      Some(book) => self.check(book, resp),
+      None => return Ok(()),
+    };
+    self.checked += 1;
+    let kind = match kind {
+      Some(kind) => kind,
+      None => return Ok(()),
+    };
+    self.discrepancies += 1;
+    self.sink.on_book_discrepancy(&BookDiscrepancy {
+      product_id: resp.product_id.clone(),
+      time: resp.time,
+      trade_id: resp.trade_id,
+      side: resp.side,
+      price: resp.price.clone(),
+      size: resp.size.clone(),
+      kind,
+    })
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{BookDiscrepancy, DiscrepancyKind, MatchBookValidator};
+  use crate::web_socket::response::{MatchResponse, SnapshotResponse};
+  use crate::web_socket::CoinBaseWebSocketMessageHandler;
+
+  fn sell_match(trade_id: i64, price: &str, size: &str) -> Result<MatchResponse, serde_json::error::Error> {
+    serde_json::from_str(&format!(r#"{{
+      "time": "2019-08-14T20:42:27.265Z", "product_id": "BTC-USD", "sequence": 1, "trade_id": {},
+      "maker_order_id": "68e6a28f-ae28-4788-8d4f-5ab4e5e5ae08", "taker_order_id": "1aa8fb1d-4d2d-4b8e-9a09-3e5e55bb1a4a",
+      "side": "sell", "price": "{}", "size": "{}"
+    }}"#, trade_id, price, size))
+  }
+
+  #[test]
+  fn report_matches_that_do_not_fit_the_book() -> Result<(), serde_json::error::Error> {
+    let snapshot: SnapshotResponse = serde_json::from_str(r#"{
+      "product_id": "BTC-USD", "bids": [["100.00", "1"]], "asks": [["101.00", "2"], ["102.00", "3"]]
+    }"#)?;
+    let mut discrepancies: Vec<BookDiscrepancy> = Vec::new();
+    let mut validator = MatchBookValidator::new(0.1, |discrepancy: &BookDiscrepancy| {
+      discrepancies.push(discrepancy.clone());
+      Ok(())
+    });
+
+    validator.on_match(&sell_match(1, "101.00", "1")?).unwrap();
+    validator.on_snapshot(&snapshot).unwrap();
+    // Within the tolerance of the 5 offered up to 102.
+    validator.on_match(&sell_match(2, "102.00", "5.4")?).unwrap();
+    validator.on_match(&sell_match(3, "101.00", "2.5")?).unwrap();
+    validator.on_match(&sell_match(4, "99.00", "0.1")?).unwrap();
+    assert_eq!((validator.checked(), validator.discrepancies()), (3, 2));
+    drop(validator);
+
+    assert_eq!(discrepancies[0].trade_id, 3);
+    assert_eq!(discrepancies[0].kind, DiscrepancyKind::ExceedsLiquidity { displayed: "2".parse().unwrap() });
+    assert_eq!(discrepancies[1].kind, DiscrepancyKind::CrossedPrice { opposite_best: "100.00".parse().unwrap() });
+    Ok(())
+  }
+}
@@ -0,0 +1,221 @@
+//! Value of the balances of a profile in a single currency, updated with the live prices of the
+//! `ticker` channel:
+//!
+//! ```no_run
+//! use coinbase_client::portfolio::{Portfolio, Valuation};
+//! use coinbase_client::rest::CoinbaseRestClient;
+//! use coinbase_client::web_socket::Terminate;
+//!
+//! let client = CoinbaseRestClient::production().with_profile("main").unwrap();
+//! let mut portfolio = Portfolio::new("USD", |valuation: &Valuation| {
+//!   println!("{} {}", valuation.total, valuation.quote_currency);
+//!   Ok::<(), Terminate>(())
+//! });
+//! portfolio.fetch(&client).unwrap();
+//! // Register `portfolio` as a handler of a client subscribed to the ticker channel of the
+//! // products of the held currencies.
+//! ```
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::conversion::CurrencyConverter;
+use crate::decimal::{Decimal, Zero};
+use crate::rest::{Account, CoinbaseRestClient, RestError};
+use crate::web_socket::response::{Product, StatusResponse, TickerResponse};
+use crate::web_socket::{CoinBaseWebSocketMessageHandler, Terminate};
+
+/// Balance of one currency and its value in the quote currency, `None` while there is no
+/// price to convert it with.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Holding {
+  pub currency: String,
+  pub balance: Decimal,
+  pub value: Option<Decimal>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Valuation {
+  /// Time of the ticker that changed the valuation.
+  pub time: DateTime<Utc>,
+  pub quote_currency: String,
+  /// Sum of the values of the priced holdings.
+  pub total: Decimal,
+  pub holdings: Vec<Holding>,
+}
+
+impl Valuation {
+  /// Currencies that could not be valued and are missing from the total.
+  pub fn unpriced(&self) -> impl Iterator<Item=&str> {
+    self.holdings.iter().filter(|holding| holding.value.is_none()).map(|holding| holding.currency.as_str())
+  }
+}
+
+pub trait ValuationSink {
+  fn on_valuation(&mut self, valuation: &Valuation) -> Result<(), Terminate>;
+}
+
+impl<F: FnMut(&Valuation) -> Result<(), Terminate>> ValuationSink for F {
+  fn on_valuation(&mut self, valuation: &Valuation) -> Result<(), Terminate> {
+    self(valuation)
+  }
+}
+
+/// Values the balances of the accounts in `quote_currency` with mid prices of the `ticker`
+/// channel, converting through cross pairs where needed (see `CurrencyConverter`). A
+/// valuation is emitted when the total moves by at least `min_change`, or a holding gets or
+/// loses its price, and on the first ticker after the balances were set.
+///
+/// Balances are taken as they were fetched, call `fetch` or `set_accounts` again to pick up
+/// fills and transfers.
+pub struct Portfolio<S: ValuationSink> {
+  quote_currency: String,
+  balances: Vec<(String, Decimal)>,
+  converter: CurrencyConverter,
+  min_change: Decimal,
+  last: Option<Valuation>,
+  sink: S,
+}
+
+impl<S: ValuationSink> Portfolio<S> {
+  pub fn new(quote_currency: &str, sink: S) -> Self {
+    Portfolio {
+      quote_currency: quote_currency.into(),
+      balances: Vec::new(),
+      converter: CurrencyConverter::new(),
+      min_change: Decimal::zero(),
+      last: None,
+      sink,
+    }
+  }
+
+  /// Smallest move of the total, in the quote currency, that is emitted. Every move is emitted
+  /// by default.
+  pub fn min_change(mut self, min_change: Decimal) -> Self {
+    self.min_change = min_change;
+    self
+  }
+
+  /// Fetches the products and the balances of the client's profile.
+  pub fn fetch(&mut self, client: &CoinbaseRestClient) -> Result<(), RestError> {
+    self.set_products(&client.get_products()?);
+    self.set_accounts(&client.get_accounts()?);
+    Ok(())
+  }
+
+  /// Replaces the balances, accounts without a balance are left out. The new valuation is
+  /// emitted with the next ticker.
+  pub fn set_accounts(&mut self, accounts: &[Account]) {
+    self.balances = accounts.iter()
+      .filter(|account| !account.balance.is_zero())
+      .map(|account| (account.currency.clone(), account.balance.clone()))
+      .collect();
+    self.last = None;
+  }
+
+  pub fn set_products(&mut self, products: &[Product]) {
+    self.converter.set_products(products);
+  }
+
+  /// Latest valuation, computed with the prices known at the moment.
+  pub fn valuation(&self) -> Valuation {
+    self.value(Utc::now())
+  }
+
+  fn value(&self, time: DateTime<Utc>) -> Valuation {
+    let holdings: Vec<Holding> = self.balances.iter()
+      .map(|(currency, balance)| Holding {
+        currency: currency.clone(),
+        balance: balance.clone(),
+        value: self.converter.convert(balance.clone(), currency, &self.quote_currency),
+      })
+      .collect();
+    let total = holdings.iter().filter_map(|holding| holding.value.clone()).fold(Decimal::zero(), |total, value| total + value);
+    Valuation { time, quote_currency: self.quote_currency.clone(), total, holdings }
+  }
+
+  fn revalue(&mut self, time: DateTime<Utc>) -> Result<(), Terminate> {
+    let valuation = self.value(time);
+    let changed = match &self.last {
+      Some(last) => {
+        let change = valuation.total.clone() - last.total.clone();
+        let unpriced = |valuation: &Valuation| valuation.unpriced().count();
+        change >= self.min_change || -change >= self.min_change || unpriced(last) != unpriced(&valuation)
+      }
+      None => true,
+    };
+    if !changed {
+      return Ok(());
+    }
+    self.sink.on_valuation(&valuation)?;
+    self.last = Some(valuation);
+    Ok(())
+  }
+}
+
+impl<S: ValuationSink> CoinBaseWebSocketMessageHandler for Portfolio<S> {
+  fn on_status(&mut self, resp: &StatusResponse) -> Result<(), Terminate> {
+    self.converter.on_status(resp)
+  }
+
+  fn on_ticker(&mut self, resp: &TickerResponse) -> Result<(), Terminate> {
+    self.converter.on_ticker(resp)?;
+    if self.balances.is_empty() {
+      return Ok(());
+    }
+    self.revalue(resp.time)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{Portfolio, Valuation};
+  use crate::decimal::Decimal;
+  use crate::rest::Account;
+  use crate::web_socket::response::{Product, TickerResponse};
+  use crate::web_socket::CoinBaseWebSocketMessageHandler;
+
+  fn product(base: &str, quote: &str) -> Product {
+    serde_json::from_str(&format!(r#"{{
+      "id": "{0}-{1}", "base_currency": "{0}", "quote_currency": "{1}", "display_name": "{0}/{1}",
+      "status": "online", "post_only": false, "limit_only": false, "cancel_only": false
+    }}"#, base, quote)).unwrap()
+  }
+
+  fn account(currency: &str, balance: &str) -> Account {
+    serde_json::from_str(&format!(r#"{{
+      "id": "{0}-account", "currency": "{0}", "balance": "{1}", "available": "{1}", "hold": "0", "profile_id": "main"
+    }}"#, currency, balance)).unwrap()
+  }
+
+  fn ticker(product_id: &str, bid: &str, ask: &str) -> TickerResponse {
+    serde_json::from_str(&format!(r#"{{
+      "trade_id": 1, "sequence": 1, "time": "2020-08-31T15:00:00Z", "product_id": "{}", "price": "{}",
+      "side": "buy", "last_size": "0.03", "best_bid": "{}", "best_ask": "{}"
+    }}"#, product_id, bid, bid, ask)).unwrap()
+  }
+
+  #[test]
+  fn value_balances_in_quote_currency() {
+    let mut valuations: Vec<Valuation> = Vec::new();
+    let mut portfolio = Portfolio::new("USD", |valuation: &Valuation| {
+      valuations.push(valuation.clone());
+      Ok(())
+    }).min_change(Decimal::from(10));
+    portfolio.set_products(&[product("BTC", "USD"), product("ETH", "BTC")]);
+    portfolio.set_accounts(&[account("USD", "100"), account("BTC", "0.5"), account("ETH", "10"), account("EUR", "0")]);
+
+    portfolio.on_ticker(&ticker("BTC-USD", "9999", "10001")).unwrap();
+    portfolio.on_ticker(&ticker("ETH-BTC", "0.04", "0.04")).unwrap();
+    // Below the minimal change.
+    portfolio.on_ticker(&ticker("BTC-USD", "10004", "10006")).unwrap();
+    portfolio.on_ticker(&ticker("BTC-USD", "10039", "10041")).unwrap();
+    assert_eq!(portfolio.valuation().total, Decimal::from(9136));
+    drop(portfolio);
+
+    let totals: Vec<Decimal> = valuations.iter().map(|valuation| valuation.total.clone()).collect();
+    assert_eq!(totals, vec![Decimal::from(5100), Decimal::from(9100), Decimal::from(9136)]);
+    assert_eq!(valuations[0].unpriced().collect::<Vec<_>>(), vec!["ETH"]);
+    assert_eq!(valuations[2].holdings.len(), 3);
+    assert_eq!(valuations[2].unpriced().count(), 0);
+  }
+}
@@ -0,0 +1,143 @@
+//! Web socket server that fans the feed received over a single coinbase connection out to
+//! local consumers. Combine it with `PublishingHandler` to rebroadcast messages in the same
+//! JSON layout as the coinbase feed:
+//!
+//! ```no_run
+//! use coinbase_client::rebroadcast::RebroadcastServer;
+//! use coinbase_client::sinks::{PublishingHandler, Serialization};
+//!
+//! let server = RebroadcastServer::bind("127.0.0.1:9000").unwrap();
+//! let handler = PublishingHandler::new(server, Serialization::Json);
+//! ```
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crossbeam::{Sender, TrySendError};
+use tungstenite::Message;
+
+use crate::sinks::{Publisher, SinkError};
+
+const REBROADCAST_ID: &str = "Rebroadcast";
+
+/// Number of messages that can wait for a single consumer before it is disconnected.
+const CLIENT_QUEUE_CAPACITY: usize = 10_000;
+
+pub struct RebroadcastServer {
+  local_addr: SocketAddr,
+  clients: Arc<Mutex<Vec<Sender<Message>>>>,
+}
+
+impl RebroadcastServer {
+  /// Binds the server and starts accepting consumers on a background thread.
+  pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    let clients = Arc::new(Mutex::new(Vec::new()));
+    let accept_clients = clients.clone();
+    thread::Builder::new()
+      .name(REBROADCAST_ID.into())
+      .spawn(move || {
+        for stream in listener.incoming() {
+          match stream {
+            Ok(stream) => accept(stream, &accept_clients),
+            Err(err) => tracing::warn!(target: REBROADCAST_ID, "Could not accept consumer: {}", err),
+          }
+        }
+      })?;
+    tracing::info!(target: REBROADCAST_ID, "Rebroadcasting feed on {}", local_addr);
+    Ok(RebroadcastServer { local_addr, clients })
+  }
+
+  pub fn local_addr(&self) -> SocketAddr {
+    self.local_addr
+  }
+
+  /// Number of currently connected consumers.
+  pub fn client_count(&self) -> usize {
+    self.clients.lock().unwrap().len()
+  }
+}
+
+fn accept(stream: TcpStream, clients: &Arc<Mutex<Vec<Sender<Message>>>>) {
+  let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+  let mut socket = match tungstenite::accept(stream) {
+    Ok(socket) => socket,
+    Err(err) => {
+      tracing::warn!(target: REBROADCAST_ID, "Web socket handshake with {} failed: {}", peer, err);
+      return;
+    }
+  };
+  let (sender, receiver) = crossbeam::bounded::<Message>(CLIENT_QUEUE_CAPACITY);
+  let spawned = thread::Builder::new()
+    .name(format!("{}-{}", REBROADCAST_ID, peer))
+    .spawn(move || {
+      // Exits once the consumer goes away or the server drops the sender.
+      for message in receiver.iter() {
+        if let Err(err) = socket.write_message(message) {
+          tracing::info!(target: REBROADCAST_ID, "Consumer {} disconnected: {}", peer, err);
+          return;
+        }
+      }
+      let _ = socket.close(None);
+    });
+  match spawned {
+    Ok(_) => clients.lock().unwrap().push(sender),
+    Err(err) => tracing::warn!(target: REBROADCAST_ID, "Could not spawn consumer thread: {}", err),
+  }
+}
+
+impl Publisher for RebroadcastServer {
+  fn publish(&mut self, _channel: &str, _product_id: &str, payload: &[u8]) -> Result<(), SinkError> {
+    let message = match std::str::from_utf8(payload) {
+      Ok(text) => Message::text(text),
+      Err(_) => Message::binary(payload),
+    };
+    // Consumers that went away or can't keep up are dropped, so they don't hold back the others.
+    self.clients.lock().unwrap().retain(|client| match client.try_send(message.clone()) {
+      Ok(()) => true,
+      Err(TrySendError::Full(_)) => {
+        tracing::warn!(target: REBROADCAST_ID, "Disconnecting consumer that can't keep up with the feed.");
+        false
+      }
+      Err(TrySendError::Disconnected(_)) => false,
+    });
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::thread;
+  use std::time::{Duration, Instant};
+
+  use super::RebroadcastServer;
+  use crate::sinks::{PublishingHandler, Serialization};
+  use crate::web_socket::{CoinBaseWebSocketMessageHandler, ResponseMessages};
+
+  #[test]
+  fn rebroadcast_to_consumer() -> Result<(), serde_json::error::Error> {
+    let server = RebroadcastServer::bind("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}", server.local_addr());
+    let (mut consumer, _) = tungstenite::connect(url::Url::parse(&url).unwrap()).unwrap();
+
+    let started = Instant::now();
+    while server.client_count() == 0 && started.elapsed() < Duration::from_secs(5) {
+      thread::sleep(Duration::from_millis(10));
+    }
+
+    let heartbeat = serde_json::from_str(r#"{
+      "sequence": 90, "last_trade_id": 20, "product_id": "BTC-USD", "time": "2014-11-07T08:19:28.464459Z"
+    }"#)?;
+    let mut handler = PublishingHandler::new(server, Serialization::Json);
+    handler.on_heartbeat(&heartbeat).unwrap();
+
+    let message = consumer.read_message().unwrap();
+    match serde_json::from_str(message.to_text().unwrap())? {
+      ResponseMessages::Heartbeat { resp } => assert_eq!(resp.product_id, "BTC-USD"),
+      _ => panic!("Unexpected message type"),
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,274 @@
+//! Reconciliation of the profile's fills with the ledgers of its accounts. Every fill should
+//! move its base and quote currency by the traded size and notional and charge its fee, and
+//! every ledger entry should take the balance from the previous one to its own:
+//!
+//! ```no_run
+//! use chrono::{Duration, Utc};
+//! use coinbase_client::reconcile::Reconciler;
+//! use coinbase_client::rest::CoinbaseRestClient;
+//!
+//! let client = CoinbaseRestClient::production().with_profile("main").unwrap();
+//! let mut reconciler = Reconciler::new(Utc::now() - Duration::days(1), Utc::now());
+//! reconciler.fetch(&client, &["BTC-USD"]).unwrap();
+//! let report = reconciler.report();
+//! ```
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::decimal::{Decimal, Zero};
+use crate::rest::{CoinbaseRestClient, Fill, LedgerEntry, LedgerEntryType, RestError};
+use crate::web_socket::response::Side;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Discrepancy {
+  /// The fill moved no funds of the currency.
+  MissingEntry { trade_id: i64, currency: String, expected: Decimal },
+  /// Entries of the trade moved a different amount of the currency than the fill.
+  AmountMismatch { trade_id: i64, currency: String, expected: Decimal, actual: Decimal },
+  /// Match, fee or rebate entry of a trade that is not among the fills.
+  UnexplainedEntry { entry_id: String, currency: String, entry_type: LedgerEntryType, amount: Decimal },
+  /// The entry's balance is not the balance of the previous entry plus its amount.
+  BalanceJump { entry_id: String, currency: String, expected: Decimal, actual: Decimal },
+}
+
+/// Ledger of one currency over the reconciled range, amounts are summed by entry type.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CurrencySummary {
+  /// Balance before the first and after the last entry.
+  pub opening: Decimal,
+  pub closing: Decimal,
+  pub traded: Decimal,
+  /// Fees net of rebates, negative when charged.
+  pub fees: Decimal,
+  pub transfers: Decimal,
+  pub conversions: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReconciliationReport {
+  pub from: DateTime<Utc>,
+  pub to: DateTime<Utc>,
+  pub currencies: BTreeMap<String, CurrencySummary>,
+  pub discrepancies: Vec<Discrepancy>,
+}
+
+impl ReconciliationReport {
+  pub fn is_reconciled(&self) -> bool {
+    self.discrepancies.is_empty()
+  }
+}
+
+/// Collects ledger entries and fills within `from..to` and matches them up by trade id. Fills
+/// of products whose base or quote currency ledger wasn't added are only checked against the
+/// ledger that was.
+pub struct Reconciler {
+  from: DateTime<Utc>,
+  to: DateTime<Utc>,
+  ledgers: BTreeMap<String, Vec<LedgerEntry>>,
+  fills: Vec<Fill>,
+}
+
+impl Reconciler {
+  pub fn new(from: DateTime<Utc>, to: DateTime<Utc>) -> Self {
+    Reconciler { from, to, ledgers: BTreeMap::new(), fills: Vec::new() }
+  }
+
+  fn in_range(&self, time: DateTime<Utc>) -> bool {
+    self.from <= time && time < self.to
+  }
+
+  pub fn add_ledger(&mut self, currency: &str, entries: impl IntoIterator<Item=LedgerEntry>) {
+    let (from, to) = (self.from, self.to);
+    let ledger = self.ledgers.entry(currency.into()).or_default();
+    ledger.extend(entries.into_iter().filter(|entry| from <= entry.created_at && entry.created_at < to));
+  }
+
+  /// Adds fills from `/fills` or built from the user channel with `Fill::from_match`.
+  pub fn add_fills(&mut self, fills: impl IntoIterator<Item=Fill>) {
+    let fills: Vec<Fill> = fills.into_iter().filter(|fill| self.in_range(fill.created_at)).collect();
+    self.fills.extend(fills);
+  }
+
+  /// Adds the latest page of the ledger of every account of the profile and of the fills of
+  /// every product.
+  pub fn fetch(&mut self, client: &CoinbaseRestClient, product_ids: &[&str]) -> Result<(), RestError> {
+    for account in client.get_accounts()? {
+      let entries = client.get_ledger(account.id.as_str())?;
+      self.add_ledger(account.currency.as_str(), entries);
+    }
+    for product_id in product_ids {
+      let fills = client.get_fills(product_id)?;
+      self.add_fills(fills);
+    }
+    Ok(())
+  }
+
+  pub fn report(&self) -> ReconciliationReport {
+    let mut discrepancies = Vec::new();
+    let mut currencies = BTreeMap::new();
+    // Amount moved by match and by fee or rebate entries, per trade and currency.
+    let mut moved: HashMap<(i64, &str), (Decimal, Decimal)> = HashMap::new();
+
+    for (currency, ledger) in &self.ledgers {
+      let mut entries: Vec<&LedgerEntry> = ledger.iter().collect();
+      entries.sort_by_key(|entry| entry.created_at);
+      let first = match entries.first() {
+        Some(first) => first,
+        None => continue,
+      };
+      let mut summary = CurrencySummary {
+        opening: &first.balance - &first.amount,
+        closing: &first.balance - &first.amount,
+        traded: Decimal::zero(),
+        fees: Decimal::zero(),
+        transfers: Decimal::zero(),
+        conversions: Decimal::zero(),
+      };
+      for entry in entries {
+        let expected = &summary.closing + &entry.amount;
+        if expected != entry.balance {
+          discrepancies.push(Discrepancy::BalanceJump {
+            entry_id: entry.id.clone(),
+            currency: currency.clone(),
+            expected,
+            actual: entry.balance.clone(),
+          });
+        }
+        summary.closing = entry.balance.clone();
+        match entry.entry_type {
+          LedgerEntryType::Match => summary.traded += entry.amount.clone(),
+          LedgerEntryType::Fee | LedgerEntryType::Rebate => summary.fees += entry.amount.clone(),
+          LedgerEntryType::Transfer => summary.transfers += entry.amount.clone(),
+          LedgerEntryType::Conversion => summary.conversions += entry.amount.clone(),
+        }
+        let trade_id = match entry.trade_id() {
+          Some(trade_id) => trade_id,
+          None => continue,
+        };
+        let (matched, fees) = moved.entry((trade_id, currency.as_str())).or_insert_with(|| (Decimal::zero(), Decimal::zero()));
+        match entry.entry_type {
+          LedgerEntryType::Match => *matched += entry.amount.clone(),
+          LedgerEntryType::Fee | LedgerEntryType::Rebate => *fees += entry.amount.clone(),
+          _ => {}
+        }
+      }
+      currencies.insert(currency.clone(), summary);
+    }
+
+    let mut explained: HashSet<(i64, &str)> = HashSet::new();
+    for fill in &self.fills {
+      let (base, quote) = match fill.product_id.split_once('-') {
+        Some(currencies) => currencies,
+        None => continue,
+      };
+      let notional = &fill.price * &fill.size;
+      let (base_amount, quote_amount) = match fill.side {
+        Side::BUY => (fill.size.clone(), -notional),
+        Side::SELL => (-fill.size.clone(), notional),
+      };
+      let expectations = [(base, base_amount, Decimal::zero()), (quote, quote_amount, -fill.fee.clone())];
+      for (currency, expected_matched, expected_fees) in expectations.iter() {
+        if !self.ledgers.contains_key(*currency) {
+          continue;
+        }
+        explained.insert((fill.trade_id, *currency));
+        let (matched, fees) = match moved.get(&(fill.trade_id, *currency)) {
+          Some(moved) => moved.clone(),
+          None => {
+            discrepancies.push(Discrepancy::MissingEntry {
+              trade_id: fill.trade_id,
+              currency: currency.to_string(),
+              expected: expected_matched.clone(),
+            });
+            continue;
+          }
+        };
+        for (expected, actual) in [(expected_matched, matched), (expected_fees, fees)].iter() {
+          if *expected != actual {
+            discrepancies.push(Discrepancy::AmountMismatch {
+              trade_id: fill.trade_id,
+              currency: currency.to_string(),
+              expected: (*expected).clone(),
+              actual: actual.clone(),
+            });
+          }
+        }
+      }
+    }
+
+    for (currency, ledger) in &self.ledgers {
+      let unexplained = ledger.iter()
+        .filter(|entry| matches!(entry.entry_type, LedgerEntryType::Match | LedgerEntryType::Fee | LedgerEntryType::Rebate))
+        .filter(|entry| !entry.trade_id().is_some_and(|trade_id| explained.contains(&(trade_id, currency.as_str()))));
+      for entry in unexplained {
+        discrepancies.push(Discrepancy::UnexplainedEntry {
+          entry_id: entry.id.clone(),
+          currency: currency.clone(),
+          entry_type: entry.entry_type,
+          amount: entry.amount.clone(),
+        });
+      }
+    }
+
+    ReconciliationReport { from: self.from, to: self.to, currencies, discrepancies }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use chrono::{DateTime, Utc};
+
+  use super::{Discrepancy, Reconciler};
+  use crate::rest::{Fill, LedgerEntry, LedgerEntryType};
+
+  fn time(time: &str) -> DateTime<Utc> {
+    time.parse().unwrap()
+  }
+
+  fn entry(id: &str, entry_type: &str, amount: &str, balance: &str, trade_id: &str) -> LedgerEntry {
+    serde_json::from_str(&format!(r#"{{
+      "id": "{}", "created_at": "2020-08-31T15:00:00Z", "amount": "{}", "balance": "{}", "type": "{}",
+      "details": {{ "trade_id": "{}", "product_id": "BTC-USD" }}
+    }}"#, id, amount, balance, entry_type, trade_id)).unwrap()
+  }
+
+  fn fill(trade_id: i64, side: &str, price: &str, size: &str, fee: &str) -> Fill {
+    serde_json::from_str(&format!(r#"{{
+      "trade_id": {}, "product_id": "BTC-USD", "order_id": "d50ec984-77a8-460a-b958-66f114b0de9b",
+      "created_at": "2020-08-31T15:00:00Z", "liquidity": "T", "price": "{}", "size": "{}", "fee": "{}",
+      "side": "{}", "settled": true
+    }}"#, trade_id, price, size, fee, side)).unwrap()
+  }
+
+  #[test]
+  fn match_fills_with_ledger_entries() {
+    let mut reconciler = Reconciler::new(time("2020-08-31T00:00:00Z"), time("2020-09-01T00:00:00Z"));
+    reconciler.add_ledger("USD", vec![
+      entry("1", "transfer", "1000", "1000", ""),
+      entry("2", "match", "-100", "900", "7"),
+      entry("3", "fee", "-0.25", "899.75", "7"),
+      entry("4", "match", "50", "949.75", "8"),
+      // Balance jumps by 10 without an entry explaining it.
+      entry("5", "match", "-20", "939.75", "9"),
+    ]);
+    reconciler.add_ledger("BTC", vec![
+      entry("6", "match", "1", "1", "7"),
+      entry("7", "match", "-0.5", "0.5", "8"),
+    ]);
+    reconciler.add_fills(vec![fill(7, "buy", "100", "1", "0.25"), fill(8, "sell", "100", "0.5", "0.1")]);
+
+    let report = reconciler.report();
+    let usd = &report.currencies["USD"];
+    assert_eq!((usd.opening.clone(), usd.closing.clone()), ("0".parse().unwrap(), "939.75".parse().unwrap()));
+    assert_eq!(usd.fees, "-0.25".parse().unwrap());
+    assert_eq!(report.discrepancies, vec![
+      Discrepancy::BalanceJump { entry_id: "5".into(), currency: "USD".into(), expected: "929.75".parse().unwrap(), actual: "939.75".parse().unwrap() },
+      Discrepancy::AmountMismatch { trade_id: 8, currency: "USD".into(), expected: "-0.1".parse().unwrap(), actual: "0".parse().unwrap() },
+      Discrepancy::UnexplainedEntry { entry_id: "5".into(), currency: "USD".into(), entry_type: LedgerEntryType::Match, amount: "-20".parse().unwrap() },
+    ]);
+    assert!(!report.is_reconciled());
+  }
+}
@@ -0,0 +1,380 @@
+//! Replays recorded messages through a handler, without a web socket connection.
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io::{self, BufRead, Read};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::order_book::DeltaReader;
+use crate::web_socket::{dispatch, parse_response, Clock, CoinBaseWebSocketMessageHandler, ResponseMessages, SystemClock};
+
+/// Iterator over messages stored one JSON document per line, in the coinbase feed format.
+pub struct JsonLines<R: BufRead> {
+  reader: R,
+  line: String,
+}
+
+impl<R: BufRead> JsonLines<R> {
+  pub fn new(reader: R) -> Self {
+    JsonLines { reader, line: String::new() }
+  }
+}
+
+impl<R: BufRead> Iterator for JsonLines<R> {
+  type Item = io::Result<ResponseMessages>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    loop {
+      self.line.clear();
+      match self.reader.read_line(&mut self.line) {
+        Ok(0) => return None,
+        Ok(_) if self.line.trim().is_empty() => continue,
+        Ok(_) => {
+          return Some(parse_response(self.line.as_str())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)));
+        }
+        Err(err) => return Some(Err(err)),
+      }
+    }
+  }
+}
+
+pub struct ReplayClient<I: Iterator<Item=io::Result<ResponseMessages>>> {
+  messages: I,
+}
+
+impl<I: Iterator<Item=io::Result<ResponseMessages>>> ReplayClient<I> {
+  pub fn new(messages: I) -> Self {
+    ReplayClient { messages }
+  }
+
+  /// Initializes the handler, delivers all messages in order and closes the handler.
+  /// Stops early when the handler terminates. Returns number of delivered messages.
+  pub fn run<H: CoinBaseWebSocketMessageHandler + ?Sized>(self, handler: &mut H) -> io::Result<u64> {
+    let mut delivered = 0;
+    if handler.initialize().is_err() {
+      return Ok(delivered);
+    }
+    for message in self.messages {
+      let message = message?;
+      delivered += 1;
+      if dispatch(handler, &message).is_err() {
+        break;
+      }
+    }
+    let _ = handler.close();
+    Ok(delivered)
+  }
+}
+
+impl<I: Iterator<Item=io::Result<ResponseMessages>>> ReplayClient<I> {
+  /// Delivers the messages as if they went through a network with the given conditions.
+  pub fn with_conditions(self, conditions: NetworkConditions) -> ReplayClient<Impaired<I>> {
+    ReplayClient::new(Impaired::new(self.messages, conditions))
+  }
+}
+
+impl<R: BufRead> ReplayClient<JsonLines<R>> {
+  pub fn from_json_lines(reader: R) -> Self {
+    ReplayClient::new(JsonLines::new(reader))
+  }
+}
+
+impl<R: Read> ReplayClient<DeltaReader<R>> {
+  /// Replays level2 data encoded with `DeltaWriter`.
+  pub fn from_deltas(reader: R) -> Self {
+    ReplayClient::new(DeltaReader::new(reader))
+  }
+}
+
+/// Adverse network conditions simulated by `ReplayClient::with_conditions`. Recorded messages
+/// are sent `spacing` apart and arrive after `latency` plus a uniformly distributed part of
+/// `jitter`, so jitter larger than the spacing reorders messages. On top of that messages are
+/// dropped, or swapped with the following one, with the given probabilities.
+///
+/// All randomness comes from `seed`, the same conditions produce the same delivery order on
+/// every run.
+#[derive(Debug, Clone)]
+pub struct NetworkConditions {
+  latency: Duration,
+  jitter: Duration,
+  spacing: Duration,
+  drop_probability: f64,
+  reorder_probability: f64,
+  seed: u64,
+  realtime: bool,
+  clock: Arc<dyn Clock>,
+}
+
+impl Default for NetworkConditions {
+  fn default() -> Self {
+    NetworkConditions {
+      latency: Duration::from_millis(0),
+      jitter: Duration::from_millis(0),
+      spacing: Duration::from_millis(1),
+      drop_probability: 0.0,
+      reorder_probability: 0.0,
+      seed: 0,
+      realtime: false,
+      clock: Arc::new(SystemClock),
+    }
+  }
+}
+
+impl NetworkConditions {
+  pub fn new() -> Self {
+    NetworkConditions::default()
+  }
+
+  pub fn latency(mut self, latency: Duration) -> Self {
+    self.latency = latency;
+    self
+  }
+
+  pub fn jitter(mut self, jitter: Duration) -> Self {
+    self.jitter = jitter;
+    self
+  }
+
+  /// Time between two recorded messages, 1ms by default.
+  pub fn spacing(mut self, spacing: Duration) -> Self {
+    self.spacing = spacing;
+    self
+  }
+
+  pub fn drop_probability(mut self, probability: f64) -> Self {
+    self.drop_probability = probability.clamp(0.0, 1.0);
+    self
+  }
+
+  pub fn reorder_probability(mut self, probability: f64) -> Self {
+    self.reorder_probability = probability.clamp(0.0, 1.0);
+    self
+  }
+
+  pub fn seed(mut self, seed: u64) -> Self {
+    self.seed = seed;
+    self
+  }
+
+  /// Sleeps until the simulated arrival of every message instead of delivering them right away,
+  /// for handlers that look at the wall clock.
+  pub fn realtime(mut self, realtime: bool) -> Self {
+    self.realtime = realtime;
+    self
+  }
+
+  /// Clock realtime replays sleep on, a `MockClock` replays them without waiting.
+  pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+    self.clock = clock;
+    self
+  }
+}
+
+// SplitMix64, good enough for simulations and stable across versions of any dependency.
+struct Rng(u64);
+
+impl Rng {
+  fn next_u64(&mut self) -> u64 {
+    self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = self.0;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+  }
+
+  /// Uniform in `[0, 1)`.
+  fn next_f64(&mut self) -> f64 {
+    (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+  }
+}
+
+/// Messages ordered by arrival, ties are broken by the order they were sent in.
+struct InFlight {
+  arrival: Duration,
+  sent: u64,
+  message: ResponseMessages,
+}
+
+impl PartialEq for InFlight {
+  fn eq(&self, other: &Self) -> bool {
+    (self.arrival, self.sent) == (other.arrival, other.sent)
+  }
+}
+
+impl Eq for InFlight {}
+
+impl PartialOrd for InFlight {
+  fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl Ord for InFlight {
+  fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+    (self.arrival, self.sent).cmp(&(other.arrival, other.sent))
+  }
+}
+
+/// Iterator adapter created by `ReplayClient::with_conditions`.
+pub struct Impaired<I: Iterator<Item=io::Result<ResponseMessages>>> {
+  messages: I,
+  conditions: NetworkConditions,
+  rng: Rng,
+  sent: u64,
+  exhausted: bool,
+  in_flight: BinaryHeap<Reverse<InFlight>>,
+  held: Option<InFlight>,
+  swapped: Option<ResponseMessages>,
+  started: Option<Instant>,
+}
+
+impl<I: Iterator<Item=io::Result<ResponseMessages>>> Impaired<I> {
+  fn new(messages: I, conditions: NetworkConditions) -> Self {
+    Impaired {
+      messages,
+      rng: Rng(conditions.seed),
+      conditions,
+      sent: 0,
+      exhausted: false,
+      in_flight: BinaryHeap::new(),
+      held: None,
+      swapped: None,
+      started: None,
+    }
+  }
+
+  fn send_time(&self, sent: u64) -> Duration {
+    self.conditions.spacing * sent as u32
+  }
+
+  /// Sends messages until the earliest one in flight can no longer be overtaken.
+  fn fill(&mut self) -> io::Result<()> {
+    while !self.exhausted {
+      if let Some(Reverse(first)) = self.in_flight.peek() {
+        if self.send_time(self.sent) + self.conditions.latency > first.arrival {
+          break;
+        }
+      }
+      match self.messages.next() {
+        None => self.exhausted = true,
+        Some(message) => {
+          let message = message?;
+          let sent = self.sent;
+          self.sent += 1;
+          if self.rng.next_f64() < self.conditions.drop_probability {
+            continue;
+          }
+          let jitter = self.conditions.jitter.mul_f64(self.rng.next_f64());
+          let arrival = self.send_time(sent) + self.conditions.latency + jitter;
+          self.in_flight.push(Reverse(InFlight { arrival, sent, message }));
+        }
+      }
+    }
+    Ok(())
+  }
+
+  fn deliver(&mut self, message: InFlight) -> ResponseMessages {
+    if self.conditions.realtime {
+      let clock = &self.conditions.clock;
+      let started = *self.started.get_or_insert_with(|| clock.now());
+      let elapsed = clock.now().duration_since(started);
+      if message.arrival > elapsed {
+        clock.sleep(message.arrival - elapsed);
+      }
+    }
+    message.message
+  }
+}
+
+impl<I: Iterator<Item=io::Result<ResponseMessages>>> Iterator for Impaired<I> {
+  type Item = io::Result<ResponseMessages>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if let Some(swapped) = self.swapped.take() {
+      return Some(Ok(swapped));
+    }
+    if let Err(err) = self.fill() {
+      return Some(Err(err));
+    }
+    match self.in_flight.pop() {
+      Some(Reverse(next)) => {
+        if self.held.is_none() && self.rng.next_f64() < self.conditions.reorder_probability {
+          self.held = Some(next);
+          return self.next();
+        }
+        // Held message is delivered right after the one it was swapped with.
+        self.swapped = self.held.take().map(|held| held.message);
+        Some(Ok(self.deliver(next)))
+      }
+      None => self.held.take().map(|held| Ok(self.deliver(held))),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::sync::Arc;
+  use std::time::Duration;
+
+  use chrono::Utc;
+
+  use crate::order_book::{DeltaWriter, OrderBooks};
+  use crate::web_socket::response::{L2UpdateResponse, SnapshotResponse};
+  use crate::web_socket::{Clock, MockClock};
+
+  use super::{NetworkConditions, ReplayClient};
+
+  #[test]
+  fn replay_deltas_into_books() -> Result<(), serde_json::error::Error> {
+    let snapshot: SnapshotResponse = serde_json::from_str(r#"{
+      "product_id": "BTC-USD", "bids": [["10101.10", "0.45"]], "asks": [["10102.55", "0.57"]]
+    }"#)?;
+    let update: L2UpdateResponse = serde_json::from_str(r#"{
+      "product_id": "BTC-USD", "time": "2019-08-14T20:42:27.265Z", "changes": [["sell", "10102.00", "1.0"]]
+    }"#)?;
+    let mut writer = DeltaWriter::new(Vec::new());
+    writer.write_snapshot(&snapshot).unwrap();
+    writer.write_update(&update).unwrap();
+    let encoded = writer.into_inner();
+
+    let mut books = OrderBooks::new();
+    let delivered = ReplayClient::from_deltas(encoded.as_slice()).run(&mut books).unwrap();
+    assert_eq!(delivered, 2);
+    assert_eq!(books.get("BTC-USD").unwrap().best_ask().unwrap().price, "10102.00".parse().unwrap());
+    Ok(())
+  }
+
+  #[test]
+  fn impair_delivery_deterministically() {
+    let lines: String = (1..=50)
+      .map(|sequence| format!(r#"{{"type": "heartbeat", "sequence": {}, "last_trade_id": 1, "product_id": "BTC-USD", "time": "2020-08-31T15:05:14.336755Z"}}"#, sequence) + "\n")
+      .collect();
+    let sequences = |conditions: NetworkConditions| -> Vec<i64> {
+      ReplayClient::from_json_lines(lines.as_bytes()).with_conditions(conditions).messages
+        .map(|message| message.unwrap().sequence().unwrap())
+        .collect()
+    };
+
+    assert_eq!(sequences(NetworkConditions::new().latency(Duration::from_millis(20))), (1..=50).collect::<Vec<_>>());
+
+    // Realtime delivery sleeps on the clock until the last message arrives.
+    let clock = MockClock::new(Utc::now());
+    let start = clock.now();
+    let conditions = NetworkConditions::new().latency(Duration::from_millis(20)).realtime(true).clock(Arc::new(clock.clone()));
+    assert_eq!(sequences(conditions).len(), 50);
+    assert_eq!(clock.now().duration_since(start), Duration::from_millis(69));
+
+    let conditions = NetworkConditions::new()
+      .jitter(Duration::from_millis(5))
+      .drop_probability(0.1)
+      .reorder_probability(0.1)
+      .seed(7);
+    let impaired = sequences(conditions.clone());
+    assert_eq!(impaired, sequences(conditions));
+    assert!(impaired.len() < 50);
+    assert!(impaired.windows(2).any(|pair| pair[0] > pair[1]));
+    // Jitter of 5 spacings can't move a message further than that.
+    assert!(impaired.windows(2).all(|pair| pair[0] - pair[1] <= 6));
+  }
+}
@@ -0,0 +1,346 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::auth::{Profile, Profiles};
+use crate::web_socket::response::Product;
+
+use super::orders::OrderRateLimiter;
+use super::response::CandleRow;
+use super::{Account, Fees, Fill, Granularity, HistoricalCandle, LedgerEntry, NewOrder, Order, ProductBook, RestError, RetryPolicy, Trade};
+
+const REST_CLIENT_ID: &str = "RestClient";
+
+// Public endpoints are limited to 3 requests per second.
+const PUBLIC_REQUEST_INTERVAL: Duration = Duration::from_millis(350);
+
+#[derive(Clone)]
+pub struct CoinbaseRestClient {
+  url: String,
+  agent: ureq::Agent,
+  profiles: Profiles,
+  // Profile that signs requests to private endpoints.
+  profile: Option<Profile>,
+  order_rate_limiter: Option<OrderRateLimiter>,
+  retry_policy: RetryPolicy,
+}
+
+impl CoinbaseRestClient {
+  pub(crate) fn new(url: &str) -> Self {
+    CoinbaseRestClient {
+      url: url.into(),
+      agent: ureq::AgentBuilder::new()
+        .user_agent("coinbase-client-rs")
+        .build(),
+      profiles: Profiles::new(),
+      profile: None,
+      order_rate_limiter: None,
+      retry_policy: RetryPolicy::default(),
+    }
+  }
+
+  pub fn production() -> Self {
+    CoinbaseRestClient::new("https://api.pro.coinbase.com")
+  }
+
+  pub fn sandbox() -> Self {
+    CoinbaseRestClient::new("https://api-public.sandbox.pro.coinbase.com")
+  }
+
+  /// Registers API key profiles that can be selected with `with_profile`.
+  pub fn with_profiles(mut self, profiles: Profiles) -> Self {
+    self.profiles = profiles;
+    self
+  }
+
+  /// Client that signs private requests with the named profile. Clients share the connection
+  /// pool, so a client per profile is cheap.
+  pub fn with_profile(&self, name: &str) -> Result<Self, RestError> {
+    let profile = self.profiles.get(name)?.clone();
+    Ok(CoinbaseRestClient { profile: Some(profile), ..self.clone() })
+  }
+
+  pub fn profile(&self) -> Option<&Profile> {
+    self.profile.as_ref()
+  }
+
+  /// Refuses to place more than `max_orders` orders of a product within `window`, with
+  /// `RestError::RateLimited`, before anything is sent. The limit is shared by clients created
+  /// from this one with `with_profile`.
+  pub fn order_rate_limit(mut self, max_orders: usize, window: Duration) -> Self {
+    self.order_rate_limiter = Some(OrderRateLimiter::new(max_orders, window));
+    self
+  }
+
+  /// How GET requests are retried, `RetryPolicy::default()` unless set. Orders are never
+  /// retried, a failed request may still have placed the order.
+  pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+    self.retry_policy = policy;
+    self
+  }
+
+  /// Places the order with the selected profile.
+  pub fn place_order(&self, order: &NewOrder) -> Result<Order, RestError> {
+    if self.profile.is_none() {
+      return Err(RestError::Unauthenticated);
+    }
+    if let Some(limiter) = &self.order_rate_limiter {
+      limiter.acquire(order.product_id.as_str(), Instant::now())
+        .map_err(|retry_after| RestError::RateLimited { product_id: order.product_id.clone(), retry_after })?;
+    }
+    self.post_private("/orders", order)
+  }
+
+  /// Fetches balances of the portfolio of the selected profile.
+  pub fn get_accounts(&self) -> Result<Vec<Account>, RestError> {
+    self.get_private("/accounts")
+  }
+
+  /// Fetches single page of the latest fills of the selected profile's orders of the product,
+  /// ordered from newest to oldest.
+  pub fn get_fills(&self, product_id: &str) -> Result<Vec<Fill>, RestError> {
+    self.get_private(format!("/fills?product_id={}", product_id).as_str())
+  }
+
+  /// Fetches single page of the latest balance changes of the account, ordered from newest to
+  /// oldest.
+  pub fn get_ledger(&self, account_id: &str) -> Result<Vec<LedgerEntry>, RestError> {
+    self.get_private(format!("/accounts/{}/ledger", account_id).as_str())
+  }
+
+  /// Fetches the fee rates and 30 day volume of the selected profile, see `FeeModel::from_fees`.
+  pub fn get_fees(&self) -> Result<Fees, RestError> {
+    self.get_private("/fees")
+  }
+
+  /// Fetches all products listed on the exchange, including the ones that are not online.
+  pub fn get_products(&self) -> Result<Vec<Product>, RestError> {
+    self.get("/products")
+  }
+
+  /// Fetches single page of the latest trades for the product. If `before` trade id is given
+  /// only trades older than it are returned. Trades are ordered from newest to oldest.
+  pub fn get_trades(&self, product_id: &str, before: Option<i64>) -> Result<Vec<Trade>, RestError> {
+    let path = format!("/products/{}/trades", product_id);
+    match before {
+      Some(trade_id) => self.get_with_query(path.as_str(), &[("after", trade_id.to_string())]),
+      None => self.get(path.as_str()),
+    }
+  }
+
+  /// Fetches the best 50 bid and ask levels of the product's order book, aggregated by price.
+  pub fn get_product_book(&self, product_id: &str) -> Result<ProductBook, RestError> {
+    let path = format!("/products/{}/book", product_id);
+    self.get_with_query(path.as_str(), &[("level", "2".to_string())])
+  }
+
+  /// Fetches candles of the product starting within `[start, end)`, ordered from oldest to
+  /// newest. The exchange returns at most 300 candles per request, see `history::CandleHistory`
+  /// for longer ranges.
+  pub fn get_candles(
+    &self,
+    product_id: &str,
+    granularity: Granularity,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+  ) -> Result<Vec<HistoricalCandle>, RestError> {
+    let path = format!("/products/{}/candles", product_id);
+    // The end is inclusive on the exchange.
+    let last = end - chrono::Duration::seconds(1);
+    let query = [
+      ("granularity", granularity.seconds().to_string()),
+      ("start", start.to_rfc3339_opts(SecondsFormat::Secs, true)),
+      ("end", last.to_rfc3339_opts(SecondsFormat::Secs, true)),
+    ];
+    let rows: Vec<CandleRow> = self.get_with_query(path.as_str(), &query)?;
+    let mut candles: Vec<HistoricalCandle> = rows.into_iter()
+      .map(HistoricalCandle::from)
+      .filter(|candle| candle.time >= start && candle.time < end)
+      .collect();
+    candles.sort_by_key(|candle| candle.time);
+    Ok(candles)
+  }
+
+  /// Lazily pages through the trade history of the product, starting from the trade right before
+  /// `before` (or from the latest trade) and going back in time.
+  pub fn trade_history(&self, product_id: &str, before: Option<i64>) -> TradeHistory {
+    TradeHistory {
+      client: self.clone(),
+      product_id: product_id.into(),
+      cursor: before,
+      page: Vec::new(),
+      last_request: None,
+      exhausted: false,
+    }
+  }
+
+  fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T, RestError> {
+    self.get_with_query(path, &[])
+  }
+
+  fn get_with_query<T: DeserializeOwned>(&self, path: &str, query: &[(&str, String)]) -> Result<T, RestError> {
+    let url = format!("{}{}", self.url, path);
+    self.with_retries(|| {
+      tracing::debug!(target: REST_CLIENT_ID, "GET {} {:?}", url, query);
+      let mut request = self.agent.get(url.as_str());
+      for (key, value) in query {
+        request = request.query(key, value.as_str());
+      }
+      let response = request.call()?;
+      Ok(response.into_json()?)
+    })
+  }
+
+  fn get_private<T: DeserializeOwned>(&self, path: &str) -> Result<T, RestError> {
+    // Signed again on every attempt, signatures expire.
+    self.with_retries(|| {
+      let request = self.signed_request("GET", path, "")?;
+      Ok(request.call()?.into_json()?)
+    })
+  }
+
+  fn with_retries<T>(&self, mut request: impl FnMut() -> Result<T, RestError>) -> Result<T, RestError> {
+    let mut attempt = 0;
+    loop {
+      match request() {
+        Err(err) => match self.retry_policy.delay(attempt, &err) {
+          Some(delay) => {
+            tracing::warn!(target: REST_CLIENT_ID, "Request failed, retrying in {:?}: {}", delay, err);
+            thread::sleep(delay);
+            attempt += 1;
+          }
+          None => return Err(err),
+        },
+        result => return result,
+      }
+    }
+  }
+
+  fn post_private<B: Serialize, T: DeserializeOwned>(&self, path: &str, body: &B) -> Result<T, RestError> {
+    let body = serde_json::to_string(body).expect("Request bodies are always serializable.");
+    let request = self.signed_request("POST", path, body.as_str())?
+      .set("Content-Type", "application/json");
+    Ok(request.send_string(body.as_str())?.into_json()?)
+  }
+
+  fn signed_request(&self, method: &str, path: &str, body: &str) -> Result<ureq::Request, RestError> {
+    let profile = self.profile.as_ref().ok_or(RestError::Unauthenticated)?;
+    let url = format!("{}{}", self.url, path);
+    tracing::debug!(target: REST_CLIENT_ID, "{} {} as {}", method, url, profile.credentials.key());
+    let signature = profile.credentials.sign(method, path, body);
+    Ok(self.agent.request(method, url.as_str())
+      .set("CB-ACCESS-KEY", signature.key.as_str())
+      .set("CB-ACCESS-SIGN", signature.signature.as_str())
+      .set("CB-ACCESS-TIMESTAMP", signature.timestamp.as_str())
+      .set("CB-ACCESS-PASSPHRASE", signature.passphrase.as_str()))
+  }
+}
+
+/// Iterator over the trade history of a single product, from newest to oldest trade.
+/// Pages are fetched on demand while obeying the public endpoint rate limit.
+pub struct TradeHistory {
+  client: CoinbaseRestClient,
+  product_id: String,
+  cursor: Option<i64>,
+  page: Vec<Trade>,
+  last_request: Option<Instant>,
+  exhausted: bool,
+}
+
+impl TradeHistory {
+  fn fetch_page(&mut self) -> Result<(), RestError> {
+    if let Some(last_request) = self.last_request {
+      let elapsed = last_request.elapsed();
+      if elapsed < PUBLIC_REQUEST_INTERVAL {
+        thread::sleep(PUBLIC_REQUEST_INTERVAL - elapsed);
+      }
+    }
+    self.last_request = Some(Instant::now());
+
+    let mut page = self.client.get_trades(self.product_id.as_str(), self.cursor)?;
+    match page.last() {
+      Some(oldest) => self.cursor = Some(oldest.trade_id),
+      None => self.exhausted = true,
+    }
+    // Reverse so that popping yields trades from newest to oldest.
+    page.reverse();
+    self.page = page;
+    Ok(())
+  }
+}
+
+impl Iterator for TradeHistory {
+  type Item = Result<Trade, RestError>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.page.is_empty() && !self.exhausted {
+      if let Err(err) = self.fetch_page() {
+        self.exhausted = true;
+        return Some(Err(err));
+      }
+    }
+    self.page.pop().map(Ok)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::web_socket::response::Product;
+
+  #[test]
+  fn deserialize_products() -> Result<(), serde_json::error::Error> {
+    let json = r#"
+    [
+      {
+        "id":"BTC-USD",
+        "base_currency":"BTC",
+        "quote_currency":"USD",
+        "base_min_size":"0.00100000",
+        "base_max_size":"280.00000000",
+        "quote_increment":"0.01000000",
+        "base_increment":"0.00000001",
+        "display_name":"BTC/USD",
+        "min_market_funds":"5",
+        "max_market_funds":"1000000",
+        "margin_enabled":false,
+        "post_only":false,
+        "limit_only":false,
+        "cancel_only":false,
+        "trading_disabled":false,
+        "status":"online",
+        "status_message":""
+      },
+      {
+        "id":"GNT-USDC",
+        "base_currency":"GNT",
+        "quote_currency":"USDC",
+        "base_min_size":"1.00000000",
+        "base_max_size":"490000.00000000",
+        "quote_increment":"0.00000100",
+        "base_increment":"1.00000000",
+        "display_name":"GNT/USDC",
+        "min_market_funds":"1.0",
+        "max_market_funds":"100000",
+        "margin_enabled":false,
+        "post_only":false,
+        "limit_only":false,
+        "cancel_only":true,
+        "trading_disabled":false,
+        "status":"online",
+        "status_message":""
+      }
+    ]
+    "#;
+    let products: Vec<Product> = serde_json::from_str(json)?;
+    let online: Vec<&str> = products.iter()
+      .filter(|product| product.is_online())
+      .map(|product| product.id.as_str())
+      .collect();
+    assert_eq!(online, vec!["BTC-USD"]);
+    Ok(())
+  }
+}
@@ -0,0 +1,156 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use thiserror::Error;
+
+use crate::auth::AuthError;
+
+#[derive(Error, Debug)]
+pub enum RestError {
+  /// Coinbase refused the request as invalid, e.g. an order with insufficient funds.
+  #[error("Coinbase rejected the request: {message}")]
+  BadRequest { message: String },
+
+  /// Wrong API key, signature or passphrase, or the key lacks the permission.
+  #[error("Coinbase refused the credentials with status {status}: {message}")]
+  Unauthorized { status: u16, message: String },
+
+  /// Exchange rate limit reached, `retry_after` is set when coinbase said how long to wait.
+  #[error("Coinbase rate limit reached, retry after {retry_after:?}: {message}")]
+  TooManyRequests { retry_after: Option<Duration>, message: String },
+
+  /// Server side failure that may go away when the request is repeated.
+  #[error("Coinbase failed with status {status}: {message}")]
+  Server { status: u16, message: String },
+
+  #[error("Coinbase responded with status {status}: {message}")]
+  Status { status: u16, message: String },
+
+  #[error("Could not reach coinbase: {0}")]
+  Transport(String),
+
+  #[error("Could not parse coinbase response: {0}")]
+  Parse(#[from] std::io::Error),
+
+  #[error("Order rate limit of {product_id} reached, retry after {retry_after:?}")]
+  RateLimited { product_id: String, retry_after: Duration },
+
+  #[error("Private endpoint requires a profile, select one with `with_profile`")]
+  Unauthenticated,
+
+  #[error(transparent)]
+  Auth(#[from] AuthError),
+}
+
+impl RestError {
+  /// Classifies an error response by its status. The message is taken from the JSON body
+  /// coinbase sends with errors, `retry_after` is the value of the `Retry-After` header.
+  pub(crate) fn from_response(status: u16, retry_after: Option<&str>, body: &str) -> Self {
+    let message = serde_json::from_str::<serde_json::Value>(body).ok()
+      .and_then(|value| value.get("message").and_then(|message| message.as_str()).map(String::from))
+      .unwrap_or_else(|| body.to_string());
+    match status {
+      400 => RestError::BadRequest { message },
+      401 | 403 => RestError::Unauthorized { status, message },
+      429 => RestError::TooManyRequests { retry_after: retry_after.and_then(parse_retry_after), message },
+      500..=599 => RestError::Server { status, message },
+      _ => RestError::Status { status, message },
+    }
+  }
+
+  /// Whether repeating the same request may succeed.
+  pub fn is_retryable(&self) -> bool {
+    matches!(self, RestError::TooManyRequests { .. } | RestError::Server { .. } | RestError::Transport(_) | RestError::RateLimited { .. })
+  }
+
+  /// How long to wait before repeating the request, when it is known.
+  pub fn retry_after(&self) -> Option<Duration> {
+    match self {
+      RestError::TooManyRequests { retry_after, .. } => *retry_after,
+      RestError::RateLimited { retry_after, .. } => Some(*retry_after),
+      _ => None,
+    }
+  }
+}
+
+// Either a number of seconds or an HTTP date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+  let value = value.trim();
+  if let Ok(seconds) = value.parse::<f64>() {
+    return Duration::try_from_secs_f64(seconds).ok();
+  }
+  let at = DateTime::parse_from_rfc2822(value).ok()?.with_timezone(&Utc);
+  Some((at - Utc::now()).to_std().unwrap_or_default())
+}
+
+impl From<ureq::Error> for RestError {
+  fn from(error: ureq::Error) -> Self {
+    match error {
+      ureq::Error::Status(status, response) => {
+        let retry_after = response.header("Retry-After").map(String::from);
+        let body = response.into_string().unwrap_or_default();
+        RestError::from_response(status, retry_after.as_deref(), &body)
+      }
+      ureq::Error::Transport(transport) => RestError::Transport(transport.to_string()),
+    }
+  }
+}
+
+/// How GET requests, which are safe to repeat, are retried after rate limiting, server errors
+/// and transport failures. Rate limited requests wait for the `Retry-After` coinbase sent,
+/// others back off exponentially from `base_delay` up to `max_delay`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+  pub max_retries: u32,
+  pub base_delay: Duration,
+  pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+  fn default() -> Self {
+    RetryPolicy { max_retries: 3, base_delay: Duration::from_millis(500), max_delay: Duration::from_secs(10) }
+  }
+}
+
+impl RetryPolicy {
+  /// Fails on the first error.
+  pub fn none() -> Self {
+    RetryPolicy { max_retries: 0, ..RetryPolicy::default() }
+  }
+
+  /// Delay before retry number `attempt`, counted from 0, or `None` when the error is final.
+  pub(crate) fn delay(&self, attempt: u32, err: &RestError) -> Option<Duration> {
+    if attempt >= self.max_retries || !err.is_retryable() {
+      return None;
+    }
+    let backoff = self.base_delay.saturating_mul(2u32.saturating_pow(attempt)).min(self.max_delay);
+    Some(err.retry_after().unwrap_or(backoff))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::time::Duration;
+
+  use super::{RestError, RetryPolicy};
+
+  #[test]
+  fn classify_error_responses() {
+    let rejected = RestError::from_response(400, None, r#"{"message": "Insufficient funds"}"#);
+    assert!(matches!(&rejected, RestError::BadRequest { message } if message == "Insufficient funds"));
+    assert!(!rejected.is_retryable());
+    assert!(matches!(RestError::from_response(401, None, r#"{"message": "invalid signature"}"#), RestError::Unauthorized { status: 401, .. }));
+    assert!(matches!(RestError::from_response(502, None, "Bad Gateway"), RestError::Server { status: 502, message } if message == "Bad Gateway"));
+    assert!(matches!(RestError::from_response(404, None, r#"{"message": "NotFound"}"#), RestError::Status { status: 404, .. }));
+
+    let limited = RestError::from_response(429, Some("2"), r#"{"message": "Slow down"}"#);
+    assert_eq!(limited.retry_after(), Some(Duration::from_secs(2)));
+
+    let policy = RetryPolicy { max_retries: 3, base_delay: Duration::from_millis(100), max_delay: Duration::from_millis(300) };
+    assert_eq!(policy.delay(0, &limited), Some(Duration::from_secs(2)));
+    let failed = RestError::from_response(503, None, "");
+    let delays: Vec<_> = (0..4).map(|attempt| policy.delay(attempt, &failed)).collect();
+    assert_eq!(delays, vec![Some(Duration::from_millis(100)), Some(Duration::from_millis(200)), Some(Duration::from_millis(300)), None]);
+    assert_eq!(policy.delay(0, &rejected), None);
+  }
+}
@@ -0,0 +1,155 @@
+use serde::{Deserialize, Serialize};
+
+use crate::decimal::Decimal;
+use crate::web_socket::response::OrderType;
+
+use super::NewOrder;
+
+/// Fee rates of the profile's current tier, from `/fees`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Fees {
+  pub maker_fee_rate: Decimal,
+  pub taker_fee_rate: Decimal,
+  /// Trailing 30 day volume in USD the tier is based on.
+  pub usd_volume: Option<Decimal>,
+}
+
+/// Whether an order added liquidity to the book or took it, `M` and `T` in fills.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Liquidity {
+  #[serde(rename = "M")]
+  Maker,
+  #[serde(rename = "T")]
+  Taker,
+}
+
+/// Rates that apply from `min_volume` (30 day USD volume) until the next tier.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct FeeTier {
+  pub min_volume: Decimal,
+  pub maker_rate: Decimal,
+  pub taker_rate: Decimal,
+}
+
+impl FeeTier {
+  fn new(min_volume: &str, maker_rate: &str, taker_rate: &str) -> Self {
+    FeeTier {
+      min_volume: min_volume.parse().unwrap(),
+      maker_rate: maker_rate.parse().unwrap(),
+      taker_rate: taker_rate.parse().unwrap(),
+    }
+  }
+
+  fn rate(&self, liquidity: Liquidity) -> &Decimal {
+    match liquidity {
+      Liquidity::Maker => &self.maker_rate,
+      Liquidity::Taker => &self.taker_rate,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FeeEstimate {
+  pub liquidity: Liquidity,
+  pub rate: Decimal,
+  /// Price times size, in the quote currency.
+  pub notional: Decimal,
+  /// Fee in the quote currency.
+  pub fee: Decimal,
+}
+
+/// Estimates fees of prospective orders from the volume based fee tiers.
+#[derive(Debug, Clone)]
+pub struct FeeModel {
+  // Sorted by minimal volume.
+  tiers: Vec<FeeTier>,
+}
+
+impl FeeModel {
+  pub fn new(mut tiers: Vec<FeeTier>) -> Self {
+    tiers.sort_by_key(|tier| tier.min_volume.clone());
+    FeeModel { tiers }
+  }
+
+  /// Published Coinbase Pro tiers.
+  pub fn coinbase_pro() -> Self {
+    // @formatter:off
+    FeeModel::new(vec![
+      FeeTier::new("0",         "0.005",  "0.005" ),
+      FeeTier::new("10000",     "0.0035", "0.0035"),
+      FeeTier::new("50000",     "0.0015", "0.0025"),
+      FeeTier::new("100000",    "0.001",  "0.002" ),
+      FeeTier::new("1000000",   "0.0008", "0.0018"),
+      FeeTier::new("10000000",  "0.0005", "0.0015"),
+      FeeTier::new("50000000",  "0",      "0.001" ),
+      FeeTier::new("100000000", "0",      "0.0005"),
+    ])
+    // @formatter:on
+  }
+
+  /// Single tier with the rates the exchange reported for the profile, which also covers
+  /// custom rates that the published tiers don't know about.
+  pub fn from_fees(fees: &Fees) -> Self {
+    FeeModel::new(vec![FeeTier {
+      min_volume: Decimal::from(0),
+      maker_rate: fees.maker_fee_rate.clone(),
+      taker_rate: fees.taker_fee_rate.clone(),
+    }])
+  }
+
+  pub fn tier(&self, volume_30d: &Decimal) -> Option<&FeeTier> {
+    self.tiers.iter().rev().find(|tier| tier.min_volume <= *volume_30d)
+  }
+
+  pub fn estimate(&self, liquidity: Liquidity, price: &Decimal, size: &Decimal, volume_30d: &Decimal) -> FeeEstimate {
+    let rate = self.tier(volume_30d).map(|tier| tier.rate(liquidity).clone()).unwrap_or_else(|| Decimal::from(0));
+    let notional = price * size;
+    let fee = &notional * &rate;
+    FeeEstimate { liquidity, rate, notional, fee }
+  }
+
+  /// Estimates the fee of the order if it is filled at `price`. Post only orders pay the maker
+  /// rate, all other orders are assumed to take liquidity. Funds of market orders are used as
+  /// the notional when given.
+  pub fn estimate_order(&self, order: &NewOrder, price: &Decimal, volume_30d: &Decimal) -> FeeEstimate {
+    let liquidity = match (order.order_type, order.post_only) {
+      (OrderType::LIMIT, Some(true)) => Liquidity::Maker,
+      _ => Liquidity::Taker,
+    };
+    let price = order.price.as_ref().unwrap_or(price);
+    match (&order.size, &order.funds) {
+      (Some(size), _) => self.estimate(liquidity, price, size, volume_30d),
+      (None, Some(funds)) => self.estimate(liquidity, &Decimal::from(1), funds, volume_30d),
+      (None, None) => self.estimate(liquidity, price, &Decimal::from(0), volume_30d),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{FeeModel, Fees, Liquidity};
+  use crate::rest::OrderBuilder;
+  use crate::web_socket::response::Side;
+
+  #[test]
+  fn estimate_fees_by_tier() -> Result<(), serde_json::error::Error> {
+    let model = FeeModel::coinbase_pro();
+    let price = "10000".parse().unwrap();
+    let size = "0.5".parse().unwrap();
+
+    let estimate = model.estimate(Liquidity::Taker, &price, &size, &"0".parse().unwrap());
+    assert_eq!(estimate.fee, "25".parse().unwrap());
+    let estimate = model.estimate(Liquidity::Maker, &price, &size, &"75000".parse().unwrap());
+    assert_eq!(estimate.rate, "0.0015".parse().unwrap());
+    assert_eq!(estimate.fee, "7.5".parse().unwrap());
+
+    let order = OrderBuilder::limit("BTC-USD", Side::BUY, price.clone(), size).post_only(true).build().unwrap();
+    assert_eq!(model.estimate_order(&order, &price, &"75000".parse().unwrap()).liquidity, Liquidity::Maker);
+
+    let fees: Fees = serde_json::from_str(r#"{"maker_fee_rate": "0.0015", "taker_fee_rate": "0.0025", "usd_volume": "25000.00"}"#)?;
+    let order = OrderBuilder::market_funds("BTC-USD", Side::BUY, "100".parse().unwrap()).build().unwrap();
+    let estimate = FeeModel::from_fees(&fees).estimate_order(&order, &price, &"0".parse().unwrap());
+    assert_eq!(estimate.fee, "0.25".parse().unwrap());
+    Ok(())
+  }
+}
@@ -0,0 +1,14 @@
+pub mod error;
+pub use error::{RestError, RetryPolicy};
+
+pub mod response;
+pub use response::{Account, Fill, Granularity, HistoricalCandle, LedgerDetails, LedgerEntry, LedgerEntryType, ProductBook, Trade};
+
+pub mod orders;
+pub use orders::{CancelAfter, NewOrder, Order, OrderBuilder, OrderError, SelfTradePrevention, TimeInForce};
+
+pub mod fees;
+pub use fees::{FeeEstimate, FeeModel, FeeTier, Fees, Liquidity};
+
+pub mod client;
+pub use client::{CoinbaseRestClient, TradeHistory};
@@ -0,0 +1,288 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use num_traits::Zero;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::decimal::Decimal;
+use crate::web_socket::response::{OrderId, OrderType, Side};
+
+/// Self-trade prevention, what happens when the order would match an order of the same user.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SelfTradePrevention {
+  /// Decrease the larger order by the smaller one and cancel the smaller, the default.
+  #[serde(rename = "dc")]
+  DecrementAndCancel,
+  #[serde(rename = "co")]
+  CancelOldest,
+  #[serde(rename = "cn")]
+  CancelNewest,
+  #[serde(rename = "cb")]
+  CancelBoth,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TimeInForce {
+  #[serde(rename = "GTC")]
+  GoodTillCanceled,
+  /// Requires `cancel_after`.
+  #[serde(rename = "GTT")]
+  GoodTillTime,
+  #[serde(rename = "IOC")]
+  ImmediateOrCancel,
+  #[serde(rename = "FOK")]
+  FillOrKill,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CancelAfter {
+  Min,
+  Hour,
+  Day,
+}
+
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+pub enum OrderError {
+  #[error("Post only is not allowed for market orders")]
+  PostOnlyMarket,
+
+  #[error("Post only is not allowed with time in force {0:?}")]
+  PostOnlyTimeInForce(TimeInForce),
+
+  #[error("Time in force is allowed only for limit orders")]
+  TimeInForceMarket,
+
+  #[error("Cancel after requires time in force GTT")]
+  CancelAfterWithoutGtt,
+
+  #[error("Time in force GTT requires cancel after")]
+  GttWithoutCancelAfter,
+
+  #[error("{0} must be positive")]
+  NotPositive(&'static str),
+}
+
+/// Order accepted by `POST /orders`, built and validated with `OrderBuilder`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NewOrder {
+  pub product_id: String,
+  pub side: Side,
+  #[serde(rename = "type")]
+  pub order_type: OrderType,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub client_oid: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub stp: Option<SelfTradePrevention>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub price: Option<Decimal>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub size: Option<Decimal>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub funds: Option<Decimal>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub time_in_force: Option<TimeInForce>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub cancel_after: Option<CancelAfter>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub post_only: Option<bool>,
+}
+
+pub struct OrderBuilder {
+  order: NewOrder,
+}
+
+impl OrderBuilder {
+  fn new(product_id: &str, side: Side, order_type: OrderType) -> Self {
+    OrderBuilder {
+      order: NewOrder {
+        product_id: product_id.into(),
+        side,
+        order_type,
+        client_oid: None,
+        stp: None,
+        price: None,
+        size: None,
+        funds: None,
+        time_in_force: None,
+        cancel_after: None,
+        post_only: None,
+      },
+    }
+  }
+
+  pub fn limit(product_id: &str, side: Side, price: Decimal, size: Decimal) -> Self {
+    let mut builder = OrderBuilder::new(product_id, side, OrderType::LIMIT);
+    builder.order.price = Some(price);
+    builder.order.size = Some(size);
+    builder
+  }
+
+  /// Market order for `size` of the base currency.
+  pub fn market(product_id: &str, side: Side, size: Decimal) -> Self {
+    let mut builder = OrderBuilder::new(product_id, side, OrderType::MARKET);
+    builder.order.size = Some(size);
+    builder
+  }
+
+  /// Market order spending (or receiving) `funds` of the quote currency.
+  pub fn market_funds(product_id: &str, side: Side, funds: Decimal) -> Self {
+    let mut builder = OrderBuilder::new(product_id, side, OrderType::MARKET);
+    builder.order.funds = Some(funds);
+    builder
+  }
+
+  pub fn client_oid(mut self, client_oid: &str) -> Self {
+    self.order.client_oid = Some(client_oid.into());
+    self
+  }
+
+  pub fn stp(mut self, stp: SelfTradePrevention) -> Self {
+    self.order.stp = Some(stp);
+    self
+  }
+
+  pub fn time_in_force(mut self, time_in_force: TimeInForce) -> Self {
+    self.order.time_in_force = Some(time_in_force);
+    self
+  }
+
+  pub fn cancel_after(mut self, cancel_after: CancelAfter) -> Self {
+    self.order.cancel_after = Some(cancel_after);
+    self
+  }
+
+  pub fn post_only(mut self, post_only: bool) -> Self {
+    self.order.post_only = Some(post_only);
+    self
+  }
+
+  /// Checks the flag combinations the exchange would reject.
+  pub fn build(self) -> Result<NewOrder, OrderError> {
+    let order = self.order;
+    let is_market = order.order_type == OrderType::MARKET;
+    let post_only = order.post_only.unwrap_or(false);
+    for (name, value) in [("price", &order.price), ("size", &order.size), ("funds", &order.funds)] {
+      if let Some(value) = value {
+        if Zero::is_zero(value) || *value < Decimal::zero() {
+          return Err(OrderError::NotPositive(name));
+        }
+      }
+    }
+    // @formatter:off
+    match (order.time_in_force, order.cancel_after) {
+      (Some(_), _) if is_market                          => return Err(OrderError::TimeInForceMarket),
+      (Some(TimeInForce::GoodTillTime), None)            => return Err(OrderError::GttWithoutCancelAfter),
+      (Some(TimeInForce::GoodTillTime), Some(_))         => {}
+      (_, Some(_))                                       => return Err(OrderError::CancelAfterWithoutGtt),
+      _ => {}
+    }
+    match order.time_in_force {
+      _ if post_only && is_market                        => return Err(OrderError::PostOnlyMarket),
+      Some(tif @ TimeInForce::ImmediateOrCancel) |
+      Some(tif @ TimeInForce::FillOrKill) if post_only   => return Err(OrderError::PostOnlyTimeInForce(tif)),
+      _ => {}
+    }
+    // @formatter:on
+    Ok(order)
+  }
+}
+
+/// Order as reported by the REST API.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Order {
+  pub id: OrderId,
+  pub product_id: String,
+  pub side: Side,
+  #[serde(rename = "type")]
+  pub order_type: OrderType,
+  pub price: Option<Decimal>,
+  pub size: Option<Decimal>,
+  pub funds: Option<Decimal>,
+  pub stp: Option<SelfTradePrevention>,
+  pub time_in_force: Option<TimeInForce>,
+  #[serde(default)]
+  pub post_only: bool,
+  pub created_at: DateTime<Utc>,
+  pub fill_fees: Option<Decimal>,
+  pub filled_size: Option<Decimal>,
+  pub executed_value: Option<Decimal>,
+  pub status: String,
+  #[serde(default)]
+  pub settled: bool,
+}
+
+/// Client-side limit of orders placed per product within a sliding window, so bursts are
+/// refused locally instead of by the exchange. Shared by all clones of the REST client.
+#[derive(Clone)]
+pub(crate) struct OrderRateLimiter {
+  max_orders: usize,
+  window: Duration,
+  placed: Arc<Mutex<HashMap<String, VecDeque<Instant>>>>,
+}
+
+impl OrderRateLimiter {
+  pub(crate) fn new(max_orders: usize, window: Duration) -> Self {
+    OrderRateLimiter { max_orders: max_orders.max(1), window, placed: Arc::new(Mutex::new(HashMap::new())) }
+  }
+
+  /// Records the order if the product is below the limit, otherwise returns how long to wait.
+  pub(crate) fn acquire(&self, product_id: &str, now: Instant) -> Result<(), Duration> {
+    let mut placed = self.placed.lock().unwrap();
+    let placed = placed.entry(product_id.into()).or_default();
+    while placed.front().map(|at| now.duration_since(*at) >= self.window).unwrap_or(false) {
+      placed.pop_front();
+    }
+    if placed.len() >= self.max_orders {
+      return Err(self.window - now.duration_since(placed[0]));
+    }
+    placed.push_back(now);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::time::{Duration, Instant};
+
+  use super::{CancelAfter, OrderBuilder, OrderError, OrderRateLimiter, SelfTradePrevention, TimeInForce};
+  use crate::web_socket::response::Side;
+
+  #[test]
+  fn validate_orders_and_rates() {
+    let order = OrderBuilder::limit("BTC-USD", Side::BUY, "10100.5".parse().unwrap(), "0.01".parse().unwrap())
+      .stp(SelfTradePrevention::CancelOldest)
+      .time_in_force(TimeInForce::GoodTillTime)
+      .cancel_after(CancelAfter::Hour)
+      .post_only(true)
+      .build()
+      .unwrap();
+    let json = serde_json::to_value(&order).unwrap();
+    assert_eq!(json["stp"], "co");
+    assert_eq!(json["time_in_force"], "GTT");
+    assert_eq!(json["type"], "limit");
+    assert!(json.get("funds").is_none());
+
+    let market = || OrderBuilder::market("BTC-USD", Side::SELL, "0.01".parse().unwrap());
+    assert_eq!(market().post_only(true).build().unwrap_err(), OrderError::PostOnlyMarket);
+    assert_eq!(market().time_in_force(TimeInForce::FillOrKill).build().unwrap_err(), OrderError::TimeInForceMarket);
+    assert_eq!(
+      OrderBuilder::limit("BTC-USD", Side::BUY, "1".parse().unwrap(), "1".parse().unwrap())
+        .post_only(true).time_in_force(TimeInForce::ImmediateOrCancel).build().unwrap_err(),
+      OrderError::PostOnlyTimeInForce(TimeInForce::ImmediateOrCancel)
+    );
+    assert_eq!(market().cancel_after(CancelAfter::Day).build().unwrap_err(), OrderError::CancelAfterWithoutGtt);
+    assert_eq!(OrderBuilder::market("BTC-USD", Side::SELL, "0".parse().unwrap()).build().unwrap_err(), OrderError::NotPositive("size"));
+
+    let limiter = OrderRateLimiter::new(2, Duration::from_secs(1));
+    let start = Instant::now();
+    assert!(limiter.acquire("BTC-USD", start).is_ok());
+    assert!(limiter.acquire("BTC-USD", start + Duration::from_millis(100)).is_ok());
+    assert!(limiter.acquire("ETH-USD", start + Duration::from_millis(100)).is_ok());
+    assert_eq!(limiter.acquire("BTC-USD", start + Duration::from_millis(400)), Err(Duration::from_millis(600)));
+    assert!(limiter.acquire("BTC-USD", start + Duration::from_millis(1000)).is_ok());
+  }
+}
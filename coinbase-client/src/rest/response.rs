@@ -0,0 +1,223 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::decimal::Decimal;
+use crate::web_socket::response::{LastMatchResponse, MatchResponse, OrderId, Side, SnapshotResponse};
+
+use super::Liquidity;
+
+/// Balance of one currency in the portfolio of the API key, from `/accounts`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Account {
+  pub id: String,
+  pub currency: String,
+  pub balance: Decimal,
+  pub available: Decimal,
+  pub hold: Decimal,
+  pub profile_id: String,
+}
+
+/// Execution of one of the profile's orders, from `/fills`. Side is the side of the own order.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Fill {
+  pub trade_id: i64,
+  pub product_id: String,
+  pub order_id: OrderId,
+  pub created_at: DateTime<Utc>,
+  pub liquidity: Liquidity,
+  pub price: Decimal,
+  pub size: Decimal,
+  /// Fee in the quote currency.
+  pub fee: Decimal,
+  pub side: Side,
+  #[serde(default)]
+  pub settled: bool,
+}
+
+impl Fill {
+  /// Fill of `order_id` from a `match` message of the user channel, which doesn't carry the fee.
+  /// `None` when the order is neither the maker nor the taker of the match.
+  pub fn from_match(resp: &MatchResponse, order_id: &OrderId, fee: Decimal) -> Option<Self> {
+    let (liquidity, side) = if resp.maker_order_id == *order_id {
+      (Liquidity::Maker, resp.side)
+    } else if resp.taker_order_id == *order_id {
+      let side = match resp.side {
+        Side::BUY => Side::SELL,
+        Side::SELL => Side::BUY,
+      };
+      (Liquidity::Taker, side)
+    } else {
+      return None;
+    };
+    Some(Fill {
+      trade_id: resp.trade_id,
+      product_id: resp.product_id.clone(),
+      order_id: *order_id,
+      created_at: resp.time,
+      liquidity,
+      price: resp.price.clone(),
+      size: resp.size.clone(),
+      fee,
+      side,
+      settled: false,
+    })
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LedgerEntryType {
+  /// Deposit or withdrawal.
+  Transfer,
+  /// Funds moved by a trade.
+  Match,
+  Fee,
+  Rebate,
+  /// Stablecoin conversion, e.g. USD to USDC.
+  Conversion,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LedgerDetails {
+  pub order_id: Option<String>,
+  pub trade_id: Option<String>,
+  pub product_id: Option<String>,
+  pub transfer_id: Option<String>,
+  pub transfer_type: Option<String>,
+}
+
+/// Change of an account's balance, from `/accounts/{id}/ledger`. `balance` is the balance
+/// after the change.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LedgerEntry {
+  pub id: String,
+  pub created_at: DateTime<Utc>,
+  pub amount: Decimal,
+  pub balance: Decimal,
+  #[serde(rename = "type")]
+  pub entry_type: LedgerEntryType,
+  #[serde(default)]
+  pub details: LedgerDetails,
+}
+
+impl LedgerEntry {
+  pub fn trade_id(&self) -> Option<i64> {
+    self.details.trade_id.as_deref()?.parse().ok()
+  }
+}
+
+/// Best levels of the order book as returned by `/products/{id}/book?level=2`, levels are
+/// `(price, size, number of orders)`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProductBook {
+  pub sequence: i64,
+  pub bids: Vec<(Decimal, Decimal, u64)>,
+  pub asks: Vec<(Decimal, Decimal, u64)>,
+}
+
+impl ProductBook {
+  /// The book in the form of the web socket `snapshot` message.
+  pub fn to_snapshot(&self, product_id: &str) -> SnapshotResponse {
+    let levels = |levels: &[(Decimal, Decimal, u64)]| levels.iter()
+      .map(|(price, size, _)| vec![price.clone(), size.clone()])
+      .collect();
+    SnapshotResponse { product_id: product_id.into(), bids: levels(&self.bids), asks: levels(&self.asks) }
+  }
+}
+
+/// Single executed trade as returned by `/products/{id}/trades`.
+/// Side is the side of the maker order, same as in `match` messages.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Trade {
+  pub time: DateTime<Utc>,
+  pub trade_id: i64,
+  pub price: Decimal,
+  pub size: Decimal,
+  pub side: Side,
+}
+
+impl From<&MatchResponse> for Trade {
+  fn from(resp: &MatchResponse) -> Self {
+    Trade {
+      time: resp.time,
+      trade_id: resp.trade_id,
+      price: resp.price.clone(),
+      size: resp.size.clone(),
+      side: resp.side,
+    }
+  }
+}
+
+impl From<&LastMatchResponse> for Trade {
+  fn from(resp: &LastMatchResponse) -> Self {
+    Trade {
+      time: resp.time,
+      trade_id: resp.trade_id,
+      price: resp.price.clone(),
+      size: resp.size.clone(),
+      side: resp.side,
+    }
+  }
+}
+
+/// Candle widths supported by `/products/{id}/candles`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Granularity {
+  OneMinute,
+  FiveMinutes,
+  FifteenMinutes,
+  OneHour,
+  SixHours,
+  OneDay,
+}
+
+impl Granularity {
+  pub fn seconds(self) -> i64 {
+    // @formatter:off
+    match self {
+      Granularity::OneMinute      => 60,
+      Granularity::FiveMinutes    => 300,
+      Granularity::FifteenMinutes => 900,
+      Granularity::OneHour        => 3600,
+      Granularity::SixHours       => 21600,
+      Granularity::OneDay         => 86400,
+    }
+    // @formatter:on
+  }
+
+  /// Start of the candle containing `time`.
+  pub fn floor(self, time: DateTime<Utc>) -> DateTime<Utc> {
+    let seconds = time.timestamp() - time.timestamp().rem_euclid(self.seconds());
+    DateTime::from_timestamp(seconds, 0).unwrap_or(time)
+  }
+}
+
+/// OHLCV candle starting at `time`, from `/products/{id}/candles`. Periods without trades have
+/// no candle.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct HistoricalCandle {
+  pub time: DateTime<Utc>,
+  pub low: Decimal,
+  pub high: Decimal,
+  pub open: Decimal,
+  pub close: Decimal,
+  pub volume: Decimal,
+}
+
+/// Candle in the `[time, low, high, open, close, volume]` form of the endpoint.
+#[derive(Deserialize)]
+pub(crate) struct CandleRow(
+  i64,
+  #[serde(deserialize_with = "crate::decimal::lenient")] Decimal,
+  #[serde(deserialize_with = "crate::decimal::lenient")] Decimal,
+  #[serde(deserialize_with = "crate::decimal::lenient")] Decimal,
+  #[serde(deserialize_with = "crate::decimal::lenient")] Decimal,
+  #[serde(deserialize_with = "crate::decimal::lenient")] Decimal,
+);
+
+impl From<CandleRow> for HistoricalCandle {
+  fn from(CandleRow(time, low, high, open, close, volume): CandleRow) -> Self {
+    HistoricalCandle { time: DateTime::from_timestamp(time, 0).unwrap_or_default(), low, high, open, close, volume }
+  }
+}
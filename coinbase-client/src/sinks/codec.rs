@@ -0,0 +1,107 @@
+use serde::Serialize;
+
+use super::SinkError;
+
+/// Turns messages into the bytes handed to a sink, so sinks don't depend on a format.
+pub trait Codec {
+  fn encode<T: Serialize + ?Sized>(&self, message: &T) -> Result<Vec<u8>, SinkError>;
+}
+
+/// JSON with the field names of the coinbase feed.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+  fn encode<T: Serialize + ?Sized>(&self, message: &T) -> Result<Vec<u8>, SinkError> {
+    serde_json::to_vec(message).map_err(|err| SinkError::Serialize(err.to_string()))
+  }
+}
+
+/// Single CSV row without the line terminator. Fields of the message are written in the order
+/// of their names, nested values like level2 changes as JSON.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct CsvCodec;
+
+impl CsvCodec {
+  /// Column names of the rows of messages like this one.
+  pub fn header<T: Serialize + ?Sized>(&self, message: &T) -> Result<Vec<u8>, SinkError> {
+    let columns: Vec<String> = match to_value(message)? {
+      serde_json::Value::Object(fields) => fields.keys().cloned().collect(),
+      _ => vec!["value".to_string()],
+    };
+    write_row(&columns)
+  }
+}
+
+impl Codec for CsvCodec {
+  fn encode<T: Serialize + ?Sized>(&self, message: &T) -> Result<Vec<u8>, SinkError> {
+    let cells: Vec<String> = match to_value(message)? {
+      serde_json::Value::Object(fields) => fields.values().map(to_cell).collect(),
+      value => vec![to_cell(&value)],
+    };
+    write_row(&cells)
+  }
+}
+
+fn to_value<T: Serialize + ?Sized>(message: &T) -> Result<serde_json::Value, SinkError> {
+  serde_json::to_value(message).map_err(|err| SinkError::Serialize(err.to_string()))
+}
+
+fn to_cell(value: &serde_json::Value) -> String {
+  match value {
+    serde_json::Value::Null => String::new(),
+    serde_json::Value::String(value) => value.clone(),
+    value => value.to_string(),
+  }
+}
+
+fn write_row(cells: &[String]) -> Result<Vec<u8>, SinkError> {
+  let mut writer = csv::WriterBuilder::new().terminator(csv::Terminator::Any(b'\n')).from_writer(Vec::new());
+  writer.write_record(cells).map_err(|err| SinkError::Serialize(err.to_string()))?;
+  let mut row = writer.into_inner().map_err(|err| SinkError::Serialize(err.to_string()))?;
+  row.pop();
+  Ok(row)
+}
+
+/// Compact binary encoding, consumers have to use the same `bincode` version.
+#[cfg(feature = "bincode")]
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct BincodeCodec;
+
+#[cfg(feature = "bincode")]
+impl Codec for BincodeCodec {
+  fn encode<T: Serialize + ?Sized>(&self, message: &T) -> Result<Vec<u8>, SinkError> {
+    bincode::serialize(message).map_err(|err| SinkError::Serialize(err.to_string()))
+  }
+}
+
+/// MessagePack with field names, readable without the Rust types.
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct MsgpackCodec;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MsgpackCodec {
+  fn encode<T: Serialize + ?Sized>(&self, message: &T) -> Result<Vec<u8>, SinkError> {
+    rmp_serde::to_vec_named(message).map_err(|err| SinkError::Serialize(err.to_string()))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{Codec, CsvCodec, JsonCodec};
+  use crate::web_socket::response::L2UpdateResponse;
+
+  #[test]
+  fn encode_rows_and_json() -> Result<(), serde_json::error::Error> {
+    let update: L2UpdateResponse = serde_json::from_str(r#"{
+      "product_id": "BTC-USD", "time": "2019-08-14T20:42:27.265Z", "changes": [["buy", "100.00", "1.5"]]
+    }"#)?;
+    assert_eq!(CsvCodec.header(&update).unwrap(), b"changes,product_id,time");
+    let row = String::from_utf8(CsvCodec.encode(&update).unwrap()).unwrap();
+    assert_eq!(row, r#""[[""buy"",""100.00"",""1.5""]]",BTC-USD,2019-08-14T20:42:27.265Z"#);
+    let json: L2UpdateResponse = serde_json::from_slice(&JsonCodec.encode(&update).unwrap())?;
+    assert_eq!(json.product_id, "BTC-USD");
+    Ok(())
+  }
+}
@@ -0,0 +1,13 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum SinkError {
+  #[error("Could not serialize message: {0}")]
+  Serialize(String),
+
+  #[error("Could not publish message: {0}")]
+  Publish(String),
+
+  #[error("Write-ahead log failed: {0}")]
+  Wal(#[from] std::io::Error),
+}
@@ -0,0 +1,209 @@
+use std::collections::HashSet;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{Publisher, SinkError};
+
+const HTTP_PUBLISHER_ID: &str = "HttpPublisher";
+
+/// Posts messages to an HTTP endpoint in batches, as a JSON array of messages, so it expects
+/// `Serialization::Json` payloads. Use it with `PublishingHandler` to forward the feed to a
+/// webhook or a serverless function:
+///
+/// ```no_run
+/// use coinbase_client::sinks::{HttpPublisher, PublishingHandler, Serialization};
+///
+/// let publisher = HttpPublisher::new("https://example.com/hook")
+///   .channels(&["matches"])
+///   .products(&["BTC-USD"]);
+/// let handler = PublishingHandler::new(publisher, Serialization::Json);
+/// ```
+///
+/// A batch is posted once it has `batch_size` messages or its first message is older than
+/// `linger`, checked as messages arrive, and on `flush`. Failed posts are retried with
+/// exponential backoff on the handler thread, so a slow endpoint slows down the feed. After the
+/// last retry the batch is dropped and the error returned.
+pub struct HttpPublisher {
+  url: String,
+  agent: ureq::Agent,
+  channels: Option<HashSet<String>>,
+  products: Option<HashSet<String>>,
+  batch_size: usize,
+  linger: Duration,
+  retries: u32,
+  backoff: Duration,
+  max_backoff: Duration,
+  batch: Vec<u8>,
+  batched: usize,
+  batch_start: Option<Instant>,
+}
+
+impl HttpPublisher {
+  pub fn new(url: &str) -> Self {
+    HttpPublisher {
+      url: url.into(),
+      agent: ureq::AgentBuilder::new().timeout(Duration::from_secs(10)).build(),
+      channels: None,
+      products: None,
+      batch_size: 100,
+      linger: Duration::from_secs(1),
+      retries: 3,
+      backoff: Duration::from_millis(200),
+      max_backoff: Duration::from_secs(5),
+      batch: Vec::new(),
+      batched: 0,
+      batch_start: None,
+    }
+  }
+
+  /// Posts only messages of these channels, all channels by default.
+  pub fn channels(mut self, channels: &[&str]) -> Self {
+    self.channels = Some(channels.iter().map(|channel| channel.to_string()).collect());
+    self
+  }
+
+  /// Posts only messages of these products, all products by default.
+  pub fn products(mut self, products: &[&str]) -> Self {
+    self.products = Some(products.iter().map(|product| product.to_string()).collect());
+    self
+  }
+
+  /// Messages per post and how long the first message of a batch may wait for the others,
+  /// 100 messages and 1 second by default.
+  pub fn batch(mut self, batch_size: usize, linger: Duration) -> Self {
+    self.batch_size = batch_size.max(1);
+    self.linger = linger;
+    self
+  }
+
+  /// Retries of a failed post, with the backoff doubled after every retry up to `max_backoff`.
+  /// 3 retries from 200 ms up to 5 seconds by default.
+  pub fn retry(mut self, retries: u32, backoff: Duration, max_backoff: Duration) -> Self {
+    self.retries = retries;
+    self.backoff = backoff;
+    self.max_backoff = max_backoff;
+    self
+  }
+
+  fn accepts(&self, channel: &str, product_id: &str) -> bool {
+    self.channels.as_ref().is_none_or(|channels| channels.contains(channel))
+      && self.products.as_ref().is_none_or(|products| products.contains(product_id))
+  }
+
+  fn post_batch(&mut self) -> Result<(), SinkError> {
+    if self.batched == 0 {
+      return Ok(());
+    }
+    self.batch.push(b']');
+    let body = std::mem::take(&mut self.batch);
+    let messages = self.batched;
+    self.batched = 0;
+    self.batch_start = None;
+
+    let mut backoff = self.backoff;
+    let mut attempt = 0;
+    loop {
+      let error = match self.agent.post(&self.url).set("Content-Type", "application/json").send_bytes(&body) {
+        Ok(_) => return Ok(()),
+        // Client errors other than throttling won't go away by retrying.
+        Err(ureq::Error::Status(status, _)) if status < 500 && status != 429 => {
+          return Err(SinkError::Publish(format!("{} messages rejected with status {}", messages, status)));
+        }
+        Err(err) => err,
+      };
+      if attempt == self.retries {
+        return Err(SinkError::Publish(format!("{} messages not posted after {} retries: {}", messages, attempt, error)));
+      }
+      attempt += 1;
+      tracing::debug!(target: HTTP_PUBLISHER_ID, "Post failed, retry {} in {:?}: {}", attempt, backoff, error);
+      thread::sleep(backoff);
+      backoff = (backoff * 2).min(self.max_backoff);
+    }
+  }
+}
+
+impl Publisher for HttpPublisher {
+  fn publish(&mut self, channel: &str, product_id: &str, payload: &[u8]) -> Result<(), SinkError> {
+    if !self.accepts(channel, product_id) {
+      return Ok(());
+    }
+    self.batch.push(if self.batched == 0 { b'[' } else { b',' });
+    self.batch.extend_from_slice(payload);
+    self.batched += 1;
+    let batch_start = *self.batch_start.get_or_insert_with(Instant::now);
+    if self.batched >= self.batch_size || batch_start.elapsed() >= self.linger {
+      self.post_batch()?;
+    }
+    Ok(())
+  }
+
+  fn flush(&mut self) -> Result<(), SinkError> {
+    self.post_batch()
+  }
+}
+
+impl Drop for HttpPublisher {
+  fn drop(&mut self) {
+    if let Err(err) = self.post_batch() {
+      tracing::warn!(target: HTTP_PUBLISHER_ID, "Could not post the last batch: {}", err);
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::io::{BufRead, BufReader, Read, Write};
+  use std::net::TcpListener;
+  use std::thread;
+  use std::time::Duration;
+
+  use super::HttpPublisher;
+  use crate::sinks::Publisher;
+
+  #[test]
+  fn post_filtered_batches_with_retry() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    // Fails the first post with a server error, then accepts.
+    let server = thread::spawn(move || {
+      let mut bodies = Vec::new();
+      for status in &["503 Service Unavailable", "200 OK", "200 OK"] {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut length = 0;
+        loop {
+          let mut line = String::new();
+          reader.read_line(&mut line).unwrap();
+          if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+            length = value.trim().parse().unwrap();
+          }
+          if line == "\r\n" {
+            break;
+          }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        bodies.push(String::from_utf8(body).unwrap());
+        write!(reader.get_mut(), "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status).unwrap();
+      }
+      bodies
+    });
+
+    let mut publisher = HttpPublisher::new(&url)
+      .channels(&["matches"])
+      .batch(2, Duration::from_secs(60))
+      .retry(1, Duration::from_millis(1), Duration::from_millis(1));
+    publisher.publish("matches", "BTC-USD", br#"{"trade_id":1}"#).unwrap();
+    publisher.publish("ticker", "BTC-USD", br#"{"sequence":1}"#).unwrap();
+    publisher.publish("matches", "ETH-USD", br#"{"trade_id":2}"#).unwrap();
+    publisher.publish("matches", "BTC-USD", br#"{"trade_id":3}"#).unwrap();
+    publisher.flush().unwrap();
+
+    let bodies = server.join().unwrap();
+    assert_eq!(bodies, vec![
+      r#"[{"trade_id":1},{"trade_id":2}]"#,
+      r#"[{"trade_id":1},{"trade_id":2}]"#,
+      r#"[{"trade_id":3}]"#,
+    ]);
+  }
+}
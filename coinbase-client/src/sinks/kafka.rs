@@ -0,0 +1,31 @@
+use std::time::Duration;
+
+use ::kafka::producer::{Producer, Record, RequiredAcks};
+
+use super::{Publisher, SinkError};
+
+/// Publishes messages to the `<prefix><channel>` topic with product id as the record key,
+/// so all messages of a product end up in the same partition and stay ordered.
+pub struct KafkaPublisher {
+  producer: Producer,
+  topic_prefix: String,
+}
+
+impl KafkaPublisher {
+  pub fn connect(hosts: Vec<String>, topic_prefix: &str) -> Result<Self, SinkError> {
+    let producer = Producer::from_hosts(hosts)
+      .with_ack_timeout(Duration::from_secs(1))
+      .with_required_acks(RequiredAcks::One)
+      .create()
+      .map_err(|err| SinkError::Publish(err.to_string()))?;
+    Ok(KafkaPublisher { producer, topic_prefix: topic_prefix.into() })
+  }
+}
+
+impl Publisher for KafkaPublisher {
+  fn publish(&mut self, channel: &str, product_id: &str, payload: &[u8]) -> Result<(), SinkError> {
+    let topic = format!("{}{}", self.topic_prefix, channel);
+    self.producer.send(&Record::from_key_value(topic.as_str(), product_id, payload))
+      .map_err(|err| SinkError::Publish(err.to_string()))
+  }
+}
@@ -0,0 +1,28 @@
+pub mod error;
+pub use error::SinkError;
+
+pub mod codec;
+pub use codec::{Codec, CsvCodec, JsonCodec};
+#[cfg(feature = "bincode")]
+pub use codec::BincodeCodec;
+#[cfg(feature = "msgpack")]
+pub use codec::MsgpackCodec;
+
+pub mod publisher;
+pub use publisher::{Publisher, PublishingHandler, Serialization};
+
+pub mod http;
+pub use http::HttpPublisher;
+
+pub mod wal;
+pub use wal::{DurablePublisher, WalRecord, WriteAheadLog};
+
+#[cfg(feature = "kafka")]
+pub mod kafka;
+#[cfg(feature = "kafka")]
+pub use self::kafka::KafkaPublisher;
+
+#[cfg(feature = "redis")]
+pub mod redis;
+#[cfg(feature = "redis")]
+pub use self::redis::RedisSink;
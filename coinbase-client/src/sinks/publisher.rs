@@ -0,0 +1,170 @@
+use serde::Serialize;
+
+use crate::web_socket::{response, CoinBaseWebSocketMessageHandler, Terminate};
+
+use super::{Codec, CsvCodec, JsonCodec, SinkError};
+
+const PUBLISHER_ID: &str = "Publisher";
+
+/// Destination of the messages published by `PublishingHandler`, e.g. a message broker.
+pub trait Publisher {
+  fn publish(&mut self, channel: &str, product_id: &str, payload: &[u8]) -> Result<(), SinkError>;
+
+  /// Makes the published messages durable, for publishers that buffer them.
+  fn flush(&mut self) -> Result<(), SinkError> {
+    Ok(())
+  }
+}
+
+/// Built-in codecs, for choosing one at runtime.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Serialization {
+  /// Same layout as the coinbase feed, so consumers can parse it into `ResponseMessages`.
+  Json,
+  /// See `CsvCodec`.
+  Csv,
+  /// Compact binary encoding, consumers have to use the same `bincode` version.
+  #[cfg(feature = "bincode")]
+  Bincode,
+  #[cfg(feature = "msgpack")]
+  Msgpack,
+}
+
+impl Codec for Serialization {
+  fn encode<T: Serialize + ?Sized>(&self, message: &T) -> Result<Vec<u8>, SinkError> {
+    match self {
+      Serialization::Json => JsonCodec.encode(message),
+      Serialization::Csv => CsvCodec.encode(message),
+      #[cfg(feature = "bincode")]
+      Serialization::Bincode => super::BincodeCodec.encode(message),
+      #[cfg(feature = "msgpack")]
+      Serialization::Msgpack => super::MsgpackCodec.encode(message),
+    }
+  }
+}
+
+// Messages keep the `type` tag so different message types on the same channel can be told apart.
+// @formatter:off
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum PublishedMessage<'a> {
+  Heartbeat (&'a response::HeartBeatResponse),
+  Ticker    (&'a response::TickerResponse   ),
+  Snapshot  (&'a response::SnapshotResponse ),
+  L2Update  (&'a response::L2UpdateResponse ),
+  Match     (&'a response::MatchResponse    ),
+  Received  (&'a response::ReceivedResponse ),
+  Open      (&'a response::OpenResponse     ),
+  Change    (&'a response::ChangeResponse   ),
+  Done      (&'a response::DoneResponse     ),
+  Active    (&'a response::ActiveResponse   ),
+  #[allow(non_camel_case_types)]
+  Last_Match(&'a response::LastMatchResponse),
+}
+// @formatter:on
+
+/// Handler that publishes every market data message through the publisher, keyed by
+/// the channel the message belongs to and its product id, encoded with the codec. Publishing
+/// errors are logged and the message is skipped, a broker hiccup doesn't stop the feed.
+pub struct PublishingHandler<P: Publisher, C: Codec = Serialization> {
+  publisher: P,
+  codec: C,
+}
+
+impl<P: Publisher, C: Codec> PublishingHandler<P, C> {
+  pub fn new(publisher: P, codec: C) -> Self {
+    PublishingHandler { publisher, codec }
+  }
+
+  fn publish(&mut self, channel: &str, product_id: &str, message: PublishedMessage) -> Result<(), Terminate> {
+    let payload = self.codec.encode(&message);
+    let result = payload.and_then(|payload| self.publisher.publish(channel, product_id, &payload));
+    if let Err(err) = result {
+      tracing::warn!(target: PUBLISHER_ID, "Skipping {} message for {}: {}", channel, product_id, err);
+    }
+    Ok(())
+  }
+}
+
+impl<P: Publisher, C: Codec> CoinBaseWebSocketMessageHandler for PublishingHandler<P, C> {
+  fn on_heartbeat(&mut self, resp: &response::HeartBeatResponse) -> Result<(), Terminate> {
+    self.publish("heartbeat", &resp.product_id, PublishedMessage::Heartbeat(resp))
+  }
+
+  fn on_ticker(&mut self, resp: &response::TickerResponse) -> Result<(), Terminate> {
+    self.publish("ticker", &resp.product_id, PublishedMessage::Ticker(resp))
+  }
+
+  fn on_snapshot(&mut self, resp: &response::SnapshotResponse) -> Result<(), Terminate> {
+    self.publish("level2", &resp.product_id, PublishedMessage::Snapshot(resp))
+  }
+
+  fn on_l2_update(&mut self, resp: &response::L2UpdateResponse) -> Result<(), Terminate> {
+    self.publish("level2", &resp.product_id, PublishedMessage::L2Update(resp))
+  }
+
+  fn on_match(&mut self, resp: &response::MatchResponse) -> Result<(), Terminate> {
+    self.publish("matches", &resp.product_id, PublishedMessage::Match(resp))
+  }
+
+  fn on_received(&mut self, resp: &response::ReceivedResponse) -> Result<(), Terminate> {
+    self.publish("full", &resp.product_id, PublishedMessage::Received(resp))
+  }
+
+  fn on_open(&mut self, resp: &response::OpenResponse) -> Result<(), Terminate> {
+    self.publish("full", &resp.product_id, PublishedMessage::Open(resp))
+  }
+
+  fn on_change(&mut self, resp: &response::ChangeResponse) -> Result<(), Terminate> {
+    self.publish("full", &resp.product_id, PublishedMessage::Change(resp))
+  }
+
+  fn on_done(&mut self, resp: &response::DoneResponse) -> Result<(), Terminate> {
+    self.publish("full", &resp.product_id, PublishedMessage::Done(resp))
+  }
+
+  fn on_active(&mut self, resp: &response::ActiveResponse) -> Result<(), Terminate> {
+    self.publish("full", &resp.product_id, PublishedMessage::Active(resp))
+  }
+
+  fn on_last_match(&mut self, resp: &response::LastMatchResponse) -> Result<(), Terminate> {
+    self.publish("matches", &resp.product_id, PublishedMessage::Last_Match(resp))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{Publisher, PublishingHandler, Serialization};
+  use crate::sinks::SinkError;
+  use crate::web_socket::{CoinBaseWebSocketMessageHandler, ResponseMessages};
+
+  #[derive(Default)]
+  struct Recorded(Vec<(String, String, Vec<u8>)>);
+
+  impl Publisher for Recorded {
+    fn publish(&mut self, channel: &str, product_id: &str, payload: &[u8]) -> Result<(), SinkError> {
+      self.0.push((channel.into(), product_id.into(), payload.to_vec()));
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn publish_json_in_feed_format() -> Result<(), serde_json::error::Error> {
+    let match_msg = serde_json::from_str(r#"{
+      "trade_id": 10, "sequence": 50, "maker_order_id": "ac928c66-ca53-498f-9c13-a110027a60e8",
+      "taker_order_id": "132fb6ae-456b-4654-b4e0-d681ac05cea1", "time": "2014-11-07T08:19:27.028459Z",
+      "product_id": "BTC-USD", "size": "5.23512", "price": "400.23", "side": "sell"
+    }"#)?;
+    let mut handler = PublishingHandler::new(Recorded::default(), Serialization::Json);
+    handler.on_match(&match_msg).unwrap();
+
+    let (channel, product_id, payload) = &handler.publisher.0[0];
+    assert_eq!(channel, "matches");
+    assert_eq!(product_id, "BTC-USD");
+    match serde_json::from_slice(payload)? {
+      ResponseMessages::Match { resp } => assert_eq!(resp.trade_id, 10),
+      _ => panic!("Unexpected message type"),
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,75 @@
+use ::redis::{Connection, RedisResult};
+
+use crate::web_socket::{response, CoinBaseWebSocketMessageHandler, Terminate};
+
+use super::{Codec, JsonCodec, SinkError};
+
+const REDIS_SINK_ID: &str = "RedisSink";
+
+/// Publishes ticker and match messages to Redis and keeps the latest values per product:
+///
+/// - `<prefix>:ticker:<product_id>` channel and key with the latest ticker,
+/// - `<prefix>:matches:<product_id>` channel with every match,
+/// - `<prefix>:bbo:<product_id>` hash with `bid`, `ask` and `time` fields.
+///
+/// Tickers and matches are JSON unless another codec is set with `codec`. Commands for a
+/// message are sent as a single pipeline. Errors are logged and the message is skipped.
+pub struct RedisSink<C: Codec = JsonCodec> {
+  connection: Connection,
+  prefix: String,
+  codec: C,
+}
+
+impl RedisSink {
+  pub fn connect(url: &str, prefix: &str) -> Result<Self, SinkError> {
+    let connection = ::redis::Client::open(url)
+      .and_then(|client| client.get_connection())
+      .map_err(|err| SinkError::Publish(err.to_string()))?;
+    Ok(RedisSink { connection, prefix: prefix.into(), codec: JsonCodec })
+  }
+}
+
+impl<C: Codec> RedisSink<C> {
+  pub fn codec<D: Codec>(self, codec: D) -> RedisSink<D> {
+    RedisSink { connection: self.connection, prefix: self.prefix, codec }
+  }
+
+  fn publish_ticker(&mut self, resp: &response::TickerResponse) -> Result<(), SinkError> {
+    let payload = self.codec.encode(resp)?;
+    let ticker_key = format!("{}:ticker:{}", self.prefix, resp.product_id);
+    let bbo_key = format!("{}:bbo:{}", self.prefix, resp.product_id);
+    let result: RedisResult<()> = ::redis::pipe()
+      .cmd("PUBLISH").arg(&ticker_key).arg(&payload).ignore()
+      .cmd("SET").arg(&ticker_key).arg(&payload).ignore()
+      .cmd("HSET").arg(&bbo_key)
+        .arg("bid").arg(resp.best_bid.to_string())
+        .arg("ask").arg(resp.best_ask.to_string())
+        .arg("time").arg(resp.time.to_rfc3339())
+        .ignore()
+      .query(&mut self.connection);
+    result.map_err(|err| SinkError::Publish(err.to_string()))
+  }
+
+  fn publish_match(&mut self, resp: &response::MatchResponse) -> Result<(), SinkError> {
+    let payload = self.codec.encode(resp)?;
+    let channel = format!("{}:matches:{}", self.prefix, resp.product_id);
+    let result: RedisResult<()> = ::redis::cmd("PUBLISH").arg(&channel).arg(&payload).query(&mut self.connection);
+    result.map_err(|err| SinkError::Publish(err.to_string()))
+  }
+}
+
+impl<C: Codec> CoinBaseWebSocketMessageHandler for RedisSink<C> {
+  fn on_ticker(&mut self, resp: &response::TickerResponse) -> Result<(), Terminate> {
+    if let Err(err) = self.publish_ticker(resp) {
+      tracing::warn!(target: REDIS_SINK_ID, "Skipping ticker for {}: {}", resp.product_id, err);
+    }
+    Ok(())
+  }
+
+  fn on_match(&mut self, resp: &response::MatchResponse) -> Result<(), Terminate> {
+    if let Err(err) = self.publish_match(resp) {
+      tracing::warn!(target: REDIS_SINK_ID, "Skipping match for {}: {}", resp.product_id, err);
+    }
+    Ok(())
+  }
+}
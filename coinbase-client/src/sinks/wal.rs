@@ -0,0 +1,349 @@
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use super::{Publisher, SinkError};
+
+const WAL_ID: &str = "WriteAheadLog";
+
+const MAGIC: &[u8; 8] = b"CBWAL\0\0\x01";
+// Magic, capacity, sequence and offset of the oldest unacknowledged record.
+const HEADER_LEN: u64 = 32;
+// Sequence, payload length and checksum.
+const RECORD_HEADER_LEN: u64 = 16;
+
+/// Record that was appended but not acknowledged yet.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct WalRecord {
+  pub seq: u64,
+  pub channel: String,
+  pub product_id: String,
+  pub payload: Vec<u8>,
+}
+
+/// Ring buffer in a file of fixed size that holds data a sink received but didn't flush yet.
+/// Sinks append every message before handing it on and acknowledge it once it is durable
+/// downstream. After a crash the unacknowledged records are read back and delivered again,
+/// so delivery is at least once: the records flushed right before the crash may repeat.
+///
+/// When the buffer is full the oldest records are overwritten, the sink has fallen behind by
+/// more than `capacity` bytes and those records are lost. Records are written to the file
+/// without syncing, they survive a crash of the process but not of the machine unless
+/// `sync(true)` is set.
+pub struct WriteAheadLog {
+  file: File,
+  capacity: u64,
+  // Logical offsets grow forever, the position in the file is the offset modulo capacity.
+  read_seq: u64,
+  read_offset: u64,
+  next_seq: u64,
+  write_offset: u64,
+  // Sequence, offset and length of unacknowledged records.
+  pending: VecDeque<(u64, u64, u64)>,
+  sync: bool,
+  overwritten: u64,
+}
+
+impl WriteAheadLog {
+  /// Opens the log, or creates it with `capacity` bytes for records. An existing log keeps the
+  /// capacity it was created with.
+  pub fn open<P: AsRef<Path>>(path: P, capacity: u64) -> io::Result<Self> {
+    let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+    if file.metadata()?.len() == 0 {
+      file.set_len(HEADER_LEN + capacity)?;
+      let mut wal = WriteAheadLog::empty(file, capacity, 1, 0);
+      wal.write_header()?;
+      return Ok(wal);
+    }
+
+    let mut header = [0u8; HEADER_LEN as usize];
+    file.read_exact(&mut header)?;
+    if &header[..8] != MAGIC {
+      return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a write-ahead log"));
+    }
+    let capacity = u64::from_le_bytes(header[8..16].try_into().unwrap());
+    let read_seq = u64::from_le_bytes(header[16..24].try_into().unwrap());
+    let read_offset = u64::from_le_bytes(header[24..32].try_into().unwrap());
+    let mut wal = WriteAheadLog::empty(file, capacity, read_seq, read_offset);
+    wal.recover()?;
+    Ok(wal)
+  }
+
+  fn empty(file: File, capacity: u64, read_seq: u64, read_offset: u64) -> Self {
+    WriteAheadLog {
+      file,
+      capacity,
+      read_seq,
+      read_offset,
+      next_seq: read_seq,
+      write_offset: read_offset,
+      pending: VecDeque::new(),
+      sync: false,
+      overwritten: 0,
+    }
+  }
+
+  /// Syncs the file after every append.
+  pub fn sync(mut self, sync: bool) -> Self {
+    self.sync = sync;
+    self
+  }
+
+  /// Number of records that were overwritten before they were acknowledged.
+  pub fn overwritten(&self) -> u64 {
+    self.overwritten
+  }
+
+  pub fn pending(&self) -> usize {
+    self.pending.len()
+  }
+
+  /// Scans records following the last acknowledged one. The scan stops at the first record
+  /// with an unexpected sequence or a wrong checksum, i.e. at a stale or partially written one.
+  fn recover(&mut self) -> io::Result<()> {
+    loop {
+      if self.write_offset - self.read_offset + RECORD_HEADER_LEN > self.capacity {
+        break;
+      }
+      let header = self.read_at(self.write_offset, RECORD_HEADER_LEN)?;
+      let seq = u64::from_le_bytes(header[..8].try_into().unwrap());
+      let len = u32::from_le_bytes(header[8..12].try_into().unwrap()) as u64;
+      let checksum = u32::from_le_bytes(header[12..16].try_into().unwrap());
+      let total = RECORD_HEADER_LEN + len;
+      if seq != self.next_seq || self.write_offset - self.read_offset + total > self.capacity {
+        break;
+      }
+      let payload = self.read_at(self.write_offset + RECORD_HEADER_LEN, len)?;
+      if self::checksum(seq, &payload) != checksum {
+        break;
+      }
+      self.pending.push_back((seq, self.write_offset, total));
+      self.next_seq += 1;
+      self.write_offset += total;
+    }
+    if !self.pending.is_empty() {
+      tracing::info!(target: WAL_ID, "Recovered {} unacknowledged records.", self.pending.len());
+    }
+    Ok(())
+  }
+
+  /// Appends the record and returns its sequence.
+  pub fn append(&mut self, channel: &str, product_id: &str, payload: &[u8]) -> io::Result<u64> {
+    let mut record = Vec::with_capacity(4 + channel.len() + product_id.len() + payload.len());
+    for field in &[channel, product_id] {
+      record.extend_from_slice(&(field.len() as u16).to_le_bytes());
+      record.extend_from_slice(field.as_bytes());
+    }
+    record.extend_from_slice(payload);
+    let total = RECORD_HEADER_LEN + record.len() as u64;
+    if total > self.capacity {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "Record is larger than the write-ahead log"));
+    }
+
+    let mut overwritten = false;
+    while self.write_offset + total - self.read_offset > self.capacity {
+      let (seq, offset, len) = self.pending.pop_front().expect("Pending records fill the log.");
+      self.read_seq = seq + 1;
+      self.read_offset = offset + len;
+      self.overwritten += 1;
+      overwritten = true;
+    }
+    if overwritten {
+      tracing::warn!(target: WAL_ID, "Log is full, overwrote unacknowledged records ({} so far).", self.overwritten);
+      self.write_header()?;
+    }
+
+    let seq = self.next_seq;
+    let mut bytes = Vec::with_capacity(total as usize);
+    bytes.extend_from_slice(&seq.to_le_bytes());
+    bytes.extend_from_slice(&(record.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&checksum(seq, &record).to_le_bytes());
+    bytes.extend_from_slice(&record);
+    self.write_at(self.write_offset, &bytes)?;
+    if self.sync {
+      self.file.sync_data()?;
+    }
+    self.pending.push_back((seq, self.write_offset, total));
+    self.next_seq += 1;
+    self.write_offset += total;
+    Ok(seq)
+  }
+
+  /// Marks all records up to and including `seq` as durable downstream.
+  pub fn acknowledge(&mut self, seq: u64) -> io::Result<()> {
+    let before = self.pending.len();
+    while self.pending.front().map(|(pending_seq, _, _)| *pending_seq <= seq).unwrap_or(false) {
+      let (pending_seq, offset, len) = self.pending.pop_front().unwrap();
+      self.read_seq = pending_seq + 1;
+      self.read_offset = offset + len;
+    }
+    if self.pending.len() != before {
+      self.write_header()?;
+    }
+    Ok(())
+  }
+
+  /// Reads back all unacknowledged records, oldest first.
+  pub fn unacknowledged(&mut self) -> io::Result<Vec<WalRecord>> {
+    let pending: Vec<_> = self.pending.iter().cloned().collect();
+    let mut records = Vec::with_capacity(pending.len());
+    for (seq, offset, len) in pending {
+      let record = self.read_at(offset + RECORD_HEADER_LEN, len - RECORD_HEADER_LEN)?;
+      records.push(parse_record(seq, &record)?);
+    }
+    Ok(records)
+  }
+
+  fn write_header(&mut self) -> io::Result<()> {
+    let mut header = Vec::with_capacity(HEADER_LEN as usize);
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&self.capacity.to_le_bytes());
+    header.extend_from_slice(&self.read_seq.to_le_bytes());
+    header.extend_from_slice(&self.read_offset.to_le_bytes());
+    self.file.seek(SeekFrom::Start(0))?;
+    self.file.write_all(&header)
+  }
+
+  fn read_at(&mut self, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0u8; len as usize];
+    let position = offset % self.capacity;
+    let first = len.min(self.capacity - position) as usize;
+    self.file.seek(SeekFrom::Start(HEADER_LEN + position))?;
+    self.file.read_exact(&mut bytes[..first])?;
+    if first < bytes.len() {
+      self.file.seek(SeekFrom::Start(HEADER_LEN))?;
+      self.file.read_exact(&mut bytes[first..])?;
+    }
+    Ok(bytes)
+  }
+
+  fn write_at(&mut self, offset: u64, bytes: &[u8]) -> io::Result<()> {
+    let position = offset % self.capacity;
+    let first = (bytes.len() as u64).min(self.capacity - position) as usize;
+    self.file.seek(SeekFrom::Start(HEADER_LEN + position))?;
+    self.file.write_all(&bytes[..first])?;
+    if first < bytes.len() {
+      self.file.seek(SeekFrom::Start(HEADER_LEN))?;
+      self.file.write_all(&bytes[first..])?;
+    }
+    Ok(())
+  }
+}
+
+// FNV-1a, the sequence is included so stale records from an earlier lap don't verify.
+fn checksum(seq: u64, record: &[u8]) -> u32 {
+  seq.to_le_bytes().iter().chain(record.iter())
+    .fold(0x811c_9dc5u32, |hash, byte| (hash ^ *byte as u32).wrapping_mul(0x0100_0193))
+}
+
+fn parse_record(seq: u64, record: &[u8]) -> io::Result<WalRecord> {
+  let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Malformed write-ahead log record");
+  let mut rest = record;
+  let mut fields = Vec::with_capacity(2);
+  for _ in 0..2 {
+    if rest.len() < 2 {
+      return Err(invalid());
+    }
+    let len = u16::from_le_bytes([rest[0], rest[1]]) as usize;
+    let field = rest.get(2..2 + len).ok_or_else(invalid)?;
+    fields.push(String::from_utf8(field.to_vec()).map_err(|_| invalid())?);
+    rest = &rest[2 + len..];
+  }
+  let product_id = fields.pop().unwrap();
+  let channel = fields.pop().unwrap();
+  Ok(WalRecord { seq, channel, product_id, payload: rest.to_vec() })
+}
+
+/// Publisher that appends every message to a write-ahead log before publishing it, and
+/// acknowledges the log once the inner publisher flushed. Records left over from a previous
+/// run are published again when it is created.
+///
+/// A message the inner publisher rejects is not retried, the log only protects against the
+/// process going away.
+pub struct DurablePublisher<P: Publisher> {
+  publisher: P,
+  wal: WriteAheadLog,
+  flush_every: usize,
+  unflushed: usize,
+  last_seq: Option<u64>,
+}
+
+impl<P: Publisher> DurablePublisher<P> {
+  pub fn new(mut publisher: P, mut wal: WriteAheadLog) -> Result<Self, SinkError> {
+    let recovered = wal.unacknowledged()?;
+    if let Some(last) = recovered.last() {
+      for record in &recovered {
+        publisher.publish(&record.channel, &record.product_id, &record.payload)?;
+      }
+      publisher.flush()?;
+      wal.acknowledge(last.seq)?;
+      tracing::info!(target: WAL_ID, "Published {} records recovered from the log.", recovered.len());
+    }
+    Ok(DurablePublisher { publisher, wal, flush_every: 1, unflushed: 0, last_seq: None })
+  }
+
+  /// Flushes the inner publisher after this many messages, 1 by default.
+  pub fn flush_every(mut self, messages: usize) -> Self {
+    self.flush_every = messages.max(1);
+    self
+  }
+}
+
+impl<P: Publisher> Publisher for DurablePublisher<P> {
+  fn publish(&mut self, channel: &str, product_id: &str, payload: &[u8]) -> Result<(), SinkError> {
+    self.last_seq = Some(self.wal.append(channel, product_id, payload)?);
+    self.publisher.publish(channel, product_id, payload)?;
+    self.unflushed += 1;
+    if self.unflushed >= self.flush_every {
+      self.flush()?;
+    }
+    Ok(())
+  }
+
+  fn flush(&mut self) -> Result<(), SinkError> {
+    self.publisher.flush()?;
+    self.unflushed = 0;
+    if let Some(seq) = self.last_seq.take() {
+      self.wal.acknowledge(seq)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::fs;
+
+  use super::WriteAheadLog;
+
+  #[test]
+  fn recover_unacknowledged_records_after_wrap() {
+    let path = std::env::temp_dir().join(format!("coinbase-wal-test-{}", std::process::id()));
+    let _ = fs::remove_file(&path);
+
+    let mut wal = WriteAheadLog::open(&path, 100).unwrap();
+    for i in 0..5u8 {
+      wal.append("matches", "BTC-USD", &[i; 10]).unwrap();
+    }
+    // Only two of the 44 byte records fit, the oldest ones were overwritten.
+    assert_eq!(wal.overwritten(), 3);
+    wal.acknowledge(4).unwrap();
+    let seq = wal.append("ticker", "ETH-USD", b"{}").unwrap();
+    drop(wal);
+
+    let mut wal = WriteAheadLog::open(&path, 1000).unwrap();
+    let records = wal.unacknowledged().unwrap();
+    let seqs: Vec<_> = records.iter().map(|record| record.seq).collect();
+    assert_eq!(seqs, vec![5, seq]);
+    assert_eq!(records[0].payload, vec![4; 10]);
+    assert_eq!(records[1].channel, "ticker");
+    assert_eq!(records[1].product_id, "ETH-USD");
+
+    wal.acknowledge(seq).unwrap();
+    drop(wal);
+    assert_eq!(WriteAheadLog::open(&path, 1000).unwrap().pending(), 0);
+    fs::remove_file(&path).unwrap();
+  }
+}
@@ -0,0 +1,65 @@
+//! Recorded feed messages and a harness that drives handlers through them, so handlers can be
+//! tested without a connection. The fixtures are the files in `tests/fixtures`, one per channel,
+//! starting with the `subscriptions` message, and are embedded in the crate.
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+use crate::replay::{JsonLines, ReplayClient};
+use crate::web_socket::CoinBaseWebSocketMessageHandler;
+
+// @formatter:off
+const FIXTURES: &[(&str, &str)] = &[
+  ("heartbeat", include_str!("../tests/fixtures/heartbeat.jsonl")),
+  ("status",    include_str!("../tests/fixtures/status.jsonl")),
+  ("ticker",    include_str!("../tests/fixtures/ticker.jsonl")),
+  ("level2",    include_str!("../tests/fixtures/level2.jsonl")),
+  ("matches",   include_str!("../tests/fixtures/matches.jsonl")),
+  ("full",      include_str!("../tests/fixtures/full.jsonl")),
+];
+// @formatter:on
+
+/// Names of the available fixtures, same as the channel they were recorded from.
+pub fn fixtures() -> impl Iterator<Item=&'static str> {
+  FIXTURES.iter().map(|(name, _)| *name)
+}
+
+/// Messages of the fixture, one JSON document per line.
+pub fn fixture(name: &str) -> Option<&'static str> {
+  FIXTURES.iter().find(|(fixture, _)| *fixture == name).map(|(_, messages)| *messages)
+}
+
+/// Outcome of driving a handler through a fixture.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct FixtureRun {
+  /// Messages delivered before the fixture ended or the handler terminated.
+  pub delivered: u64,
+  /// Delivered messages per message type.
+  pub messages: BTreeMap<String, u64>,
+}
+
+/// Initializes the handler, delivers all messages of the named fixture in order and closes the
+/// handler, exactly like `ReplayClient`. Fails with `NotFound` for an unknown fixture.
+pub fn run_fixture<H: CoinBaseWebSocketMessageHandler + ?Sized>(name: &str, handler: &mut H) -> io::Result<FixtureRun> {
+  let messages = fixture(name)
+    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Unknown fixture {}", name)))?;
+  run(messages.as_bytes(), handler)
+}
+
+/// Like `run_fixture`, for recordings in the same format outside this crate.
+pub fn run_fixture_file<H: CoinBaseWebSocketMessageHandler + ?Sized, P: AsRef<Path>>(path: P, handler: &mut H) -> io::Result<FixtureRun> {
+  run(BufReader::new(File::open(path)?), handler)
+}
+
+fn run<R: BufRead, H: CoinBaseWebSocketMessageHandler + ?Sized>(reader: R, handler: &mut H) -> io::Result<FixtureRun> {
+  let mut messages = BTreeMap::new();
+  // Messages are parsed right before they are delivered, so only delivered ones are counted.
+  let counted = JsonLines::new(reader).inspect(|message| {
+    if let Ok(message) = message {
+      *messages.entry(message.kind().to_string()).or_insert(0) += 1;
+    }
+  });
+  let delivered = ReplayClient::new(counted).run(handler)?;
+  Ok(FixtureRun { delivered, messages })
+}
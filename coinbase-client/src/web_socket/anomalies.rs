@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+
+use num_traits::ToPrimitive;
+
+use crate::decimal::{Decimal, Zero};
+
+use super::borrowed::BorrowedMessages;
+use super::response::{Change, ResponseMessages};
+
+/// What the worker does with a message that breaks an invariant, on top of notifying handlers
+/// through `on_data_anomaly`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum AnomalyPolicy {
+  /// Deliver the message anyway.
+  Report,
+  /// Don't deliver the message to the handlers.
+  Drop,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AnomalyKind {
+  /// Negative price or size, or a trade of zero size or price.
+  InvalidValue { field: &'static str, value: Decimal },
+  /// Best bid above the best ask, or at it for a snapshot.
+  CrossedBook { best_bid: Decimal, best_ask: Decimal },
+  /// Sequence lower than one already seen for the product.
+  SequenceRegression { previous: i64, sequence: i64 },
+  /// Trade price too far from the last valid trade, `deviation` is relative to its price.
+  PriceOutOfBand { price: Decimal, last_trade_price: Decimal, deviation: f64 },
+  /// Numeric fields sent empty or null, replaced by the default set with
+  /// `CoinbaseWebSocketClient::malformed_decimals` or by `None`.
+  MalformedNumbers { replaced: usize },
+}
+
+/// Message that breaks a semantic invariant of the feed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DataAnomaly {
+  pub product_id: String,
+  pub message_type: &'static str,
+  pub kind: AnomalyKind,
+}
+
+#[derive(Default)]
+struct ProductState {
+  sequence: Option<i64>,
+  last_trade_price: Option<Decimal>,
+}
+
+/// Checks incoming messages against invariants the exchange guarantees: non-negative prices and
+/// sizes, uncrossed quotes, sequences that never go back and trade prices within
+/// `max_price_deviation` of the last trade. Only trades that passed all checks move the band.
+pub(crate) struct AnomalyDetector {
+  max_price_deviation: f64,
+  products: HashMap<String, ProductState>,
+}
+
+impl AnomalyDetector {
+  pub(crate) fn new(max_price_deviation: f64) -> Self {
+    AnomalyDetector { max_price_deviation, products: HashMap::new() }
+  }
+
+  pub(crate) fn check(&mut self, message: &ResponseMessages) -> Vec<DataAnomaly> {
+    let mut check = Check::new(message.kind(), message.product_id().unwrap_or_default());
+    match message {
+      ResponseMessages::Ticker { resp } => {
+        check.ticker(&resp.price, &resp.last_size, &resp.best_bid, &resp.best_ask);
+        self.sequence(&mut check, resp.sequence);
+      }
+      ResponseMessages::Match { resp } => self.trade(&mut check, resp.sequence, &resp.price, &resp.size),
+      ResponseMessages::Last_Match { resp } => self.trade(&mut check, resp.sequence, &resp.price, &resp.size),
+      ResponseMessages::L2Update { resp } => check.changes(&resp.changes),
+      ResponseMessages::Snapshot { resp } => check.snapshot(&resp.bids, &resp.asks),
+      _ => {
+        if let Some(sequence) = message.sequence() {
+          self.sequence(&mut check, sequence);
+        }
+      }
+    }
+    check.anomalies
+  }
+
+  pub(crate) fn check_borrowed(&mut self, message: &BorrowedMessages) -> Vec<DataAnomaly> {
+    // @formatter:off
+    match message {
+      BorrowedMessages::Heartbeat(resp) => {
+        let mut check = Check::new("heartbeat", &resp.product_id);
+        self.sequence(&mut check, resp.sequence);
+        check.anomalies
+      }
+      BorrowedMessages::Ticker(resp) => {
+        let mut check = Check::new("ticker", &resp.product_id);
+        check.ticker(&resp.price, &resp.last_size, &resp.best_bid, &resp.best_ask);
+        self.sequence(&mut check, resp.sequence);
+        check.anomalies
+      }
+      BorrowedMessages::L2Update(resp) => {
+        let mut check = Check::new("l2update", &resp.product_id);
+        check.changes(&resp.changes);
+        check.anomalies
+      }
+      BorrowedMessages::Match(resp) | BorrowedMessages::Last_Match(resp) => {
+        let message_type = if let BorrowedMessages::Match(_) = message { "match" } else { "last_match" };
+        let mut check = Check::new(message_type, &resp.product_id);
+        self.trade(&mut check, resp.sequence, &resp.price, &resp.size);
+        check.anomalies
+      }
+      BorrowedMessages::Other => Vec::new(),
+    }
+    // @formatter:on
+  }
+
+  fn state(&mut self, product_id: &str) -> &mut ProductState {
+    if !self.products.contains_key(product_id) {
+      self.products.insert(product_id.into(), ProductState::default());
+    }
+    self.products.get_mut(product_id).unwrap()
+  }
+
+  fn sequence(&mut self, check: &mut Check, sequence: i64) {
+    let state = self.state(&check.product_id);
+    match state.sequence {
+      Some(previous) if sequence < previous => check.report(AnomalyKind::SequenceRegression { previous, sequence }),
+      _ => state.sequence = Some(sequence),
+    }
+  }
+
+  fn trade(&mut self, check: &mut Check, sequence: i64, price: &Decimal, size: &Decimal) {
+    check.positive("price", price);
+    check.positive("size", size);
+    self.sequence(check, sequence);
+    let max_price_deviation = self.max_price_deviation;
+    let state = self.state(&check.product_id);
+    if let Some(last_trade_price) = &state.last_trade_price {
+      let deviation = (price - last_trade_price).to_f64().unwrap_or(f64::MAX).abs()
+        / last_trade_price.to_f64().unwrap_or(f64::MAX);
+      if deviation > max_price_deviation {
+        let last_trade_price = last_trade_price.clone();
+        check.report(AnomalyKind::PriceOutOfBand { price: price.clone(), last_trade_price, deviation });
+      }
+    }
+    if check.anomalies.is_empty() {
+      state.last_trade_price = Some(price.clone());
+    }
+  }
+}
+
+/// Anomalies found in a single message.
+struct Check {
+  message_type: &'static str,
+  product_id: String,
+  anomalies: Vec<DataAnomaly>,
+}
+
+impl Check {
+  fn new(message_type: &'static str, product_id: &str) -> Self {
+    Check { message_type, product_id: product_id.into(), anomalies: Vec::new() }
+  }
+
+  fn report(&mut self, kind: AnomalyKind) {
+    self.anomalies.push(DataAnomaly { product_id: self.product_id.clone(), message_type: self.message_type, kind });
+  }
+
+  fn non_negative(&mut self, field: &'static str, value: &Decimal) {
+    if *value < Decimal::zero() {
+      self.report(AnomalyKind::InvalidValue { field, value: value.clone() });
+    }
+  }
+
+  fn positive(&mut self, field: &'static str, value: &Decimal) {
+    if Zero::is_zero(value) || *value < Decimal::zero() {
+      self.report(AnomalyKind::InvalidValue { field, value: value.clone() });
+    }
+  }
+
+  fn ticker(&mut self, price: &Decimal, last_size: &Decimal, best_bid: &Decimal, best_ask: &Decimal) {
+    self.non_negative("price", price);
+    self.non_negative("last_size", last_size);
+    self.non_negative("best_bid", best_bid);
+    self.non_negative("best_ask", best_ask);
+    // One sided books are sent with zero on the empty side.
+    if !Zero::is_zero(best_bid) && !Zero::is_zero(best_ask) && best_bid > best_ask {
+      self.report(AnomalyKind::CrossedBook { best_bid: best_bid.clone(), best_ask: best_ask.clone() });
+    }
+  }
+
+  fn changes(&mut self, changes: &[Change]) {
+    for change in changes {
+      self.positive("price", &change.price);
+      self.non_negative("size", &change.size);
+    }
+  }
+
+  fn snapshot(&mut self, bids: &[Vec<Decimal>], asks: &[Vec<Decimal>]) {
+    for level in bids.iter().chain(asks.iter()).filter(|level| level.len() >= 2) {
+      self.positive("price", &level[0]);
+      self.non_negative("size", &level[1]);
+    }
+    let best_bid = bids.iter().filter_map(|level| level.first()).max();
+    let best_ask = asks.iter().filter_map(|level| level.first()).min();
+    if let (Some(best_bid), Some(best_ask)) = (best_bid, best_ask) {
+      if best_bid >= best_ask {
+        self.report(AnomalyKind::CrossedBook { best_bid: best_bid.clone(), best_ask: best_ask.clone() });
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{AnomalyDetector, AnomalyKind};
+  use crate::web_socket::response::{parse_response, ResponseMessages};
+
+  fn trade(sequence: i64, price: &str, size: &str) -> Result<ResponseMessages, serde_json::error::Error> {
+    parse_response(&format!(r#"{{
+      "type": "match", "trade_id": 1, "maker_order_id": "125f1d3d-3100-41ce-9341-fc330bdcebcb",
+      "taker_order_id": "5b0a9f2d-3388-4fd4-a106-b96b1e6d302f", "side": "buy", "size": "{}",
+      "price": "{}", "product_id": "ETH-USD", "sequence": {}, "time": "2020-08-31T15:05:14.336755Z"
+    }}"#, size, price, sequence))
+  }
+
+  fn kinds(detector: &mut AnomalyDetector, message: &ResponseMessages) -> Vec<AnomalyKind> {
+    detector.check(message).into_iter().map(|anomaly| anomaly.kind).collect()
+  }
+
+  #[test]
+  fn report_broken_invariants() -> Result<(), serde_json::error::Error> {
+    let mut detector = AnomalyDetector::new(0.1);
+    assert!(kinds(&mut detector, &trade(10, "400.00", "1.0")?).is_empty());
+    assert!(kinds(&mut detector, &trade(11, "420.00", "1.0")?).is_empty());
+    assert_eq!(kinds(&mut detector, &trade(9, "420.00", "1.0")?), vec![AnomalyKind::SequenceRegression { previous: 11, sequence: 9 }]);
+    match kinds(&mut detector, &trade(12, "4200.00", "1.0")?).as_slice() {
+      [AnomalyKind::PriceOutOfBand { deviation, .. }] => assert!((deviation - 9.0).abs() < 1e-9),
+      kinds => panic!("Unexpected anomalies {:?}", kinds),
+    }
+    // Band stays around the last valid trade.
+    assert!(kinds(&mut detector, &trade(13, "425.00", "1.0")?).is_empty());
+    assert_eq!(kinds(&mut detector, &trade(14, "425.00", "-1.0")?).len(), 1);
+
+    let ticker = parse_response(r#"{
+      "type": "ticker", "trade_id": 20153558, "sequence": 15, "time": "2020-08-31T15:05:14.336755Z",
+      "product_id": "ETH-USD", "price": "425.00", "side": "buy", "last_size": "1.0", "best_bid": "426.00", "best_ask": "425.50"
+    }"#)?;
+    assert!(matches!(kinds(&mut detector, &ticker).as_slice(), [AnomalyKind::CrossedBook { .. }]));
+    let snapshot = parse_response(r#"{
+      "type": "snapshot", "product_id": "ETH-USD", "bids": [["425.00", "1.0"]], "asks": [["425.50", "2.0"]]
+    }"#)?;
+    assert!(kinds(&mut detector, &snapshot).is_empty());
+    Ok(())
+  }
+}
@@ -0,0 +1,213 @@
+//! Borrowed variants of the high rate messages (heartbeat, ticker, level2 and matches).
+//!
+//! String fields borrow from the received frame instead of allocating, which matters when
+//! thousands of messages arrive every second. Strings that contain escape sequences can't be
+//! borrowed and are transparently allocated, hence the `Cow`.
+use std::borrow::Cow;
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::decimal::Decimal;
+
+use super::response::{
+  Change, HeartBeatResponse, L2UpdateResponse, LastMatchResponse, MatchResponse, OrderId, Side, TickerResponse,
+};
+
+// @formatter:off
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum BorrowedMessages<'a> {
+  Heartbeat (#[serde(borrow)] HeartBeatRef<'a>),
+  Ticker    (#[serde(borrow)] TickerRef<'a>   ),
+  L2Update  (#[serde(borrow)] L2UpdateRef<'a> ),
+  Match     (#[serde(borrow)] MatchRef<'a>    ),
+  #[allow(non_camel_case_types)]
+  Last_Match(#[serde(borrow)] MatchRef<'a>    ),
+  /// Any other message type, which has to be parsed into `ResponseMessages`.
+  #[serde(other)]
+  Other,
+}
+// @formatter:on
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct HeartBeatRef<'a> {
+  pub sequence: i64,
+  pub last_trade_id: i64,
+  #[serde(borrow)]
+  pub product_id: Cow<'a, str>,
+  pub time: DateTime<Utc>,
+}
+
+impl HeartBeatRef<'_> {
+  pub fn to_response(&self) -> HeartBeatResponse {
+    HeartBeatResponse {
+      sequence: self.sequence,
+      last_trade_id: self.last_trade_id,
+      product_id: self.product_id.to_string(),
+      time: self.time,
+    }
+  }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TickerRef<'a> {
+  pub trade_id: i64,
+  pub sequence: i64,
+  pub time: DateTime<Utc>,
+  #[serde(borrow)]
+  pub product_id: Cow<'a, str>,
+  #[serde(deserialize_with = "crate::decimal::lenient")]
+  pub price: Decimal,
+  pub side: Side,
+  #[serde(deserialize_with = "crate::decimal::lenient")]
+  pub last_size: Decimal,
+  #[serde(deserialize_with = "crate::decimal::lenient")]
+  pub best_bid: Decimal,
+  #[serde(deserialize_with = "crate::decimal::lenient")]
+  pub best_ask: Decimal,
+}
+
+impl TickerRef<'_> {
+  pub fn to_response(&self) -> TickerResponse {
+    TickerResponse {
+      trade_id: self.trade_id,
+      sequence: self.sequence,
+      time: self.time,
+      product_id: self.product_id.to_string(),
+      price: self.price.clone(),
+      side: self.side,
+      last_size: self.last_size.clone(),
+      best_bid: self.best_bid.clone(),
+      best_ask: self.best_ask.clone(),
+    }
+  }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct L2UpdateRef<'a> {
+  #[serde(borrow)]
+  pub product_id: Cow<'a, str>,
+  pub time: DateTime<Utc>,
+  pub changes: Vec<Change>,
+}
+
+impl L2UpdateRef<'_> {
+  pub fn to_response(&self) -> L2UpdateResponse {
+    L2UpdateResponse {
+      product_id: self.product_id.to_string(),
+      time: self.time,
+      changes: self.changes.clone(),
+    }
+  }
+}
+
+/// Used for both `match` and `last_match` messages since they share the layout.
+#[derive(Deserialize, Debug, Clone)]
+pub struct MatchRef<'a> {
+  pub time: DateTime<Utc>,
+  #[serde(borrow)]
+  pub product_id: Cow<'a, str>,
+  pub sequence: i64,
+  pub trade_id: i64,
+  pub maker_order_id: OrderId,
+  pub taker_order_id: OrderId,
+  #[serde(deserialize_with = "crate::decimal::lenient")]
+  pub size: Decimal,
+  #[serde(deserialize_with = "crate::decimal::lenient")]
+  pub price: Decimal,
+  pub side: Side,
+}
+
+impl MatchRef<'_> {
+  pub fn to_response(&self) -> MatchResponse {
+    MatchResponse {
+      time: self.time,
+      product_id: self.product_id.to_string(),
+      sequence: self.sequence,
+      trade_id: self.trade_id,
+      maker_order_id: self.maker_order_id,
+      taker_order_id: self.taker_order_id,
+      size: self.size.clone(),
+      price: self.price.clone(),
+      side: self.side,
+    }
+  }
+
+  pub fn to_last_match_response(&self) -> LastMatchResponse {
+    LastMatchResponse {
+      time: self.time,
+      product_id: self.product_id.to_string(),
+      sequence: self.sequence,
+      trade_id: self.trade_id,
+      maker_order_id: self.maker_order_id,
+      taker_order_id: self.taker_order_id,
+      size: self.size.clone(),
+      price: self.price.clone(),
+      side: self.side,
+    }
+  }
+}
+
+/// Type and product id of a message, parsed without touching the rest of its fields. Used to
+/// decide whether a message is worth parsing in full.
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct MessageHeader<'a> {
+  #[serde(rename = "type")]
+  pub kind: &'a str,
+  #[serde(default)]
+  pub product_id: Option<&'a str>,
+}
+
+#[cfg(test)]
+mod test {
+  use std::borrow::Cow;
+
+  use super::{BorrowedMessages, MessageHeader};
+
+  #[test]
+  fn borrow_product_id_from_frame() -> Result<(), serde_json::error::Error> {
+    let msg = r#"{
+    "type":"match",
+    "trade_id":62995921,
+    "maker_order_id":"125f1d3d-3100-41ce-9341-fc330bdcebcb",
+    "taker_order_id":"5b0a9f2d-3388-4fd4-a106-b96b1e6d302f",
+    "side":"buy",
+    "size":"1.9",
+    "price":"434.19",
+    "product_id":"ETH-USD",
+    "sequence":10182385681,
+    "time":"2020-08-31T15:05:14.336755Z"
+    }"#;
+    match serde_json::from_str(msg)? {
+      BorrowedMessages::Match(resp) => {
+        assert!(matches!(resp.product_id, Cow::Borrowed("ETH-USD")));
+        assert_eq!(resp.maker_order_id.to_string(), "125f1d3d-3100-41ce-9341-fc330bdcebcb");
+        assert_eq!(resp.to_response().trade_id, 62995921);
+      }
+      _ => panic!("Unexpected message type"),
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn other_messages_are_not_parsed() -> Result<(), serde_json::error::Error> {
+    let msg = r#"{"type":"done","side":"buy","product_id":"ETH-EUR","reason":"filled"}"#;
+    match serde_json::from_str(msg)? {
+      BorrowedMessages::Other => {}
+      _ => panic!("Unexpected message type"),
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn parse_header_only() -> Result<(), serde_json::error::Error> {
+    let msg = r#"{"type":"done","side":"buy","product_id":"ETH-EUR","reason":"filled","price":"433.1"}"#;
+    let header: MessageHeader = serde_json::from_str(msg)?;
+    assert_eq!(header.kind, "done");
+    assert_eq!(header.product_id, Some("ETH-EUR"));
+    let header: MessageHeader = serde_json::from_str(r#"{"type":"subscriptions","channels":[]}"#)?;
+    assert_eq!(header.product_id, None);
+    Ok(())
+  }
+}
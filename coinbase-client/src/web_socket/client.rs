@@ -1,27 +1,93 @@
-use std::collections::HashSet;
-use std::iter::FromIterator;
-use std::sync::Mutex; // TODO maybe replace this with parking_log::Mutex if necessary.
+use std::any::Any;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex}; // TODO maybe replace this with parking_log::Mutex if necessary.
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::thread;
 use std::thread::JoinHandle;
 use std::time::{Duration, Instant};
 
-use crossbeam::{Sender, TryRecvError, Receiver};
-use log;
+use crossbeam::{Sender, SendTimeoutError, RecvTimeoutError, TryRecvError, TrySendError, Receiver};
+use thiserror::Error;
+use tracing;
 use tungstenite::{Message, WebSocket};
 use tungstenite::client::AutoStream;
+use tungstenite::stream::Stream;
 use url::Url;
 
-use super::common::Channel;
-use super::CoinBaseWebSocketMessageHandler;
+use crate::auth::{Profile, VERIFY_METHOD, VERIFY_PATH};
+use crate::decimal::{self, Decimal};
+use crate::rest::{CoinbaseRestClient, RestError};
+
+use super::borrowed::{BorrowedMessages, MessageHeader};
+use super::clock::{Clock, SystemClock};
+use super::failover::{EndpointHealth, Endpoints, FailoverPolicy};
+use super::common::{Channel, Channels};
+use super::context::MessageContext;
+use super::anomalies::{AnomalyDetector, AnomalyKind, AnomalyPolicy, DataAnomaly};
+use super::dedup::Deduplicator;
+use super::filter::{string_field, MessageFilter};
+use super::{CoinBaseWebSocketMessageHandler, CompositeCoinBaseWebSocketMessageHandler};
+use super::handler::{dispatch, HandlerId};
 use super::request::{SubscribeRequest, UnsubscribeRequest};
+use super::product_status::{ProductStatus, ProductStatusChange, ProductStatusTracker};
+use super::reconnect::{ReconnectGuard, ReconnectStormPolicy};
+use super::rollover::DayRollover;
+use super::snapshot_cache::SnapshotCache;
+use super::staleness::{StalePolicy, StaleProductMonitor};
+use super::trade_gaps::TradeGapDetector;
 use super::RequestMessages;
 use super::response;
+use super::subscriptions::Subscriptions;
+use super::validation::{validate_subscription, SubscriptionError};
+use super::wildcard::{self, ProductPattern, WildcardSubscription};
+use super::worker_thread::WorkerThread;
 
 
 enum WebSocketWorkerMessages {
   Subscribe { product_ids: Vec<String>, channels: Vec<Channel> },
   Unsubscribe { product_ids: Vec<String>, channels: Vec<Channel> },
-  Stop,
+  Watch { subscription: WildcardSubscription },
+  Ping,
+  Reconnect,
+  ReplaySnapshots,
+  AddHandler { id: HandlerId, handler: Box<dyn CoinBaseWebSocketMessageHandler + Send>, replay: bool },
+  RemoveHandler { id: HandlerId },
+  Stop { deadline: Option<Instant> },
+}
+
+/// What the worker does when a handler panics while processing a message.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum PanicPolicy {
+  /// Panic is propagated and the worker stops, unless it is supervised.
+  Terminate,
+  /// Panic is logged and the message is skipped.
+  Continue,
+}
+
+/// Why the worker stopped, returned by `wait` and `stop`.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub enum ClientExitReason {
+  /// Client was stopped, or dropped without stopping it.
+  Stopped,
+  /// A handler returned `Terminate`.
+  HandlerTerminated,
+  /// Connection could not be established or re-established, or the subscription could not be sent.
+  ConnectionFailed,
+  /// The `reconnect_storm_guard` policy gave up.
+  ReconnectStorm,
+  /// Worker panicked and wasn't supervised, with the panic message.
+  Panicked(String),
+}
+
+/// Why `start` didn't start the worker, the handler is dropped then.
+#[derive(Error, Eq, PartialEq, Clone, Debug)]
+pub enum StartError {
+  #[error("Client is already running")]
+  Running,
+  #[error("Worker of the previous run missed its stop deadline and is still stopping")]
+  StillStopping,
 }
 
 #[derive(Eq, PartialEq, Clone, Copy, Debug)]
@@ -34,122 +100,652 @@ enum ClientState {
 
 pub struct CoinbaseWebSocketClient {
   url: String,
+  rest_client: CoinbaseRestClient,
+  backfill_trades: bool,
+  max_backfilled_trades: usize,
+  resume_trade_ids: HashMap<String, i64>,
+  cache_snapshots: bool,
+  borrowed_messages: bool,
+  two_phase_parsing: bool,
+  unsubscribe_on_stop: bool,
+  panic_policy: PanicPolicy,
+  supervise: bool,
+  message_filter: Option<MessageFilter>,
+  stale_products: Option<(Duration, StalePolicy)>,
+  max_subscribe_payload: Option<usize>,
+  deduplication_window: Option<usize>,
+  trade_gaps: Option<bool>,
+  anomalies: Option<(f64, AnomalyPolicy)>,
+  malformed_default: Option<Decimal>,
+  profile: Option<Profile>,
+  reconnect_storm: Option<ReconnectStormPolicy>,
+  day_rollover: Option<Duration>,
+  worker_thread: WorkerThread,
+  clock: Arc<dyn Clock>,
+  warm_standby: bool,
+  // Shared with the worker, which picks the endpoint of every connection.
+  endpoints: Arc<Mutex<Endpoints>>,
 
   state: ClientState,
   lock: Mutex<()>,
   sender: Sender<WebSocketWorkerMessages>,
   receiver: Receiver<WebSocketWorkerMessages>,
-  join_handle: Option<JoinHandle<()>>,
+  next_handler_id: Arc<AtomicU64>,
+  known_products: Arc<Mutex<Option<HashSet<String>>>>,
+  exit_reason: Arc<Mutex<Option<ClientExitReason>>>,
+  // Subscriptions of the last worker, the next `start` subscribes to them again.
+  subscriptions: Arc<Mutex<Subscriptions>>,
+  wildcards: Arc<Mutex<Vec<WildcardSubscription>>>,
+  pending: Arc<Mutex<PendingSubscriptions>>,
+  join_handle: Option<JoinHandle<ClientExitReason>>,
+}
+
+/// Subscription requests made through a controller while no worker runs, applied by `start`.
+struct PendingSubscriptions {
+  running: bool,
+  capacity: usize,
+  requests: Vec<WebSocketWorkerMessages>,
 }
 
 impl CoinbaseWebSocketClient {
-  fn new(url: &str) -> Self {
+  fn new(url: &str, rest_client: CoinbaseRestClient) -> Self {
     let (sender, receiver) = crossbeam::bounded(10);
+    let endpoints = Endpoints::new(vec![Url::parse(url).unwrap()], FailoverPolicy::default());
     CoinbaseWebSocketClient {
       url: url.into(),
+      rest_client,
+      backfill_trades: false,
+      max_backfilled_trades: MAX_BACKFILLED_TRADES,
+      resume_trade_ids: HashMap::new(),
+      cache_snapshots: false,
+      borrowed_messages: false,
+      two_phase_parsing: false,
+      unsubscribe_on_stop: false,
+      panic_policy: PanicPolicy::Terminate,
+      supervise: false,
+      message_filter: None,
+      stale_products: None,
+      max_subscribe_payload: None,
+      deduplication_window: None,
+      trade_gaps: None,
+      anomalies: None,
+      malformed_default: None,
+      profile: None,
+      reconnect_storm: None,
+      day_rollover: None,
+      worker_thread: WorkerThread::named(WEBSOCKET_WORKER_ID),
+      clock: Arc::new(SystemClock),
+      warm_standby: false,
+      endpoints: Arc::new(Mutex::new(endpoints)),
       state: ClientState::NotInitialized,
       lock: Mutex::new(()),
       sender, receiver,
+      // Id 0 belongs to the handler given to `start`.
+      next_handler_id: Arc::new(AtomicU64::new(1)),
+      known_products: Arc::new(Mutex::new(None)),
+      exit_reason: Arc::new(Mutex::new(None)),
+      subscriptions: Arc::new(Mutex::new(Subscriptions::new())),
+      wildcards: Arc::new(Mutex::new(Vec::new())),
+      pending: Arc::new(Mutex::new(PendingSubscriptions { running: false, capacity: 64, requests: Vec::new() })),
       join_handle: None,
     }
   }
 
   pub fn production() -> Self {
-    CoinbaseWebSocketClient::new("wss://ws-feed.pro.coinbase.com")
+    CoinbaseWebSocketClient::new("wss://ws-feed.pro.coinbase.com", CoinbaseRestClient::production())
   }
 
   pub fn sandbox() -> Self {
-    CoinbaseWebSocketClient::new("wss://ws-feed-public.sandbox.pro.coinbase.com")
+    CoinbaseWebSocketClient::new(
+      "wss://ws-feed-public.sandbox.pro.coinbase.com",
+      CoinbaseRestClient::sandbox(),
+    )
+  }
+
+  /// When enabled, the client remembers last seen trade id for every product and after
+  /// a reconnect downloads trades that were missed while disconnected. Missed trades are
+  /// delivered through `on_backfilled_trade` before any live data from the new connection.
+  /// Requires subscription to the `matches` or `full` channel.
+  pub fn backfill_trades_on_reconnect(mut self, enabled: bool) -> Self {
+    self.backfill_trades = enabled;
+    self
+  }
+
+  /// Most trades downloaded per product by the backfill after a reconnect, 10 000 by default.
+  /// Older missed trades are not downloaded, their ids are reported through `on_missed_trades`
+  /// instead.
+  pub fn max_backfilled_trades(mut self, max_trades: usize) -> Self {
+    self.max_backfilled_trades = max_trades;
+    self
+  }
+
+  /// Continues from trades delivered before, e.g. by an earlier run of the application: once
+  /// connected, trades newer than the given id of each product are downloaded and delivered
+  /// through `on_backfilled_trade` before live data, and live trades that were already
+  /// backfilled are skipped. Enables `backfill_trades_on_reconnect`.
+  pub fn resume_trades(mut self, last_trade_ids: HashMap<String, i64>) -> Self {
+    self.backfill_trades = true;
+    self.resume_trade_ids = last_trade_ids;
+    self
+  }
+
+  /// When enabled, the client keeps the latest status message and the current state of
+  /// every level2 order book, which can be replayed with `controller.replay_snapshots()`.
+  pub fn cache_snapshots(mut self, enabled: bool) -> Self {
+    self.cache_snapshots = enabled;
+    self
+  }
+
+  /// When enabled, heartbeat, ticker, level2 and match messages are parsed into
+  /// `BorrowedMessages` and delivered through `on_borrowed` without allocating their strings.
+  pub fn borrowed_messages(mut self, enabled: bool) -> Self {
+    self.borrowed_messages = enabled;
+    self
+  }
+
+  /// When enabled, only the type of every message is parsed first and the rest of the message is
+  /// parsed only if a handler wants that type (see `wants_message_type`). Pays off when handlers
+  /// use a few of the received types, e.g. only matches from the `full` channel.
+  pub fn two_phase_parsing(mut self, enabled: bool) -> Self {
+    self.two_phase_parsing = enabled;
+    self
+  }
+
+  /// When enabled, the worker unsubscribes from all channels on stop and waits briefly for the
+  /// server to acknowledge it, so handlers see the final (empty) subscriptions message.
+  pub fn unsubscribe_on_stop(mut self, enabled: bool) -> Self {
+    self.unsubscribe_on_stop = enabled;
+    self
+  }
+
+  /// Sets what happens when a handler panics, by default the worker stops.
+  pub fn on_handler_panic(mut self, policy: PanicPolicy) -> Self {
+    self.panic_policy = policy;
+    self
+  }
+
+  /// When enabled, a panicked worker is restarted: it reconnects, restores all subscriptions
+  /// and initializes the handlers again.
+  pub fn supervise(mut self, enabled: bool) -> Self {
+    self.supervise = enabled;
+    self
+  }
+
+  /// Drops messages rejected by the filter before they are parsed.
+  pub fn message_filter(mut self, filter: MessageFilter) -> Self {
+    self.message_filter = Some(filter);
+    self
+  }
+
+  /// Reports products that produced no messages (heartbeats excluded) for `timeout` through
+  /// `on_product_stale`, e.g. halted or delisted products, and applies the policy to them.
+  pub fn stale_product_timeout(mut self, timeout: Duration, policy: StalePolicy) -> Self {
+    self.stale_products = Some((timeout, policy));
+    self
+  }
+
+  /// Splits subscribe requests into messages of at most `max_payload` bytes, not counting the
+  /// signature of authenticated connections. Every chunk is sent only after the server
+  /// acknowledged the previous one with a subscriptions message.
+  pub fn max_subscribe_payload(mut self, max_payload: usize) -> Self {
+    self.max_subscribe_payload = Some(max_payload);
+    self
+  }
+
+  /// Drops messages that were already delivered, like trades and snapshots repeated after a
+  /// reconnect and resubscribe. The last `window` message ids of every product are remembered.
+  pub fn deduplicate(mut self, window: usize) -> Self {
+    self.deduplication_window = Some(window);
+    self
+  }
+
+  /// Compares `last_trade_id` of heartbeats with the trades received on the `matches` or `full`
+  /// channel and reports missed trades through `on_missed_trades`. With `backfill` the missed
+  /// trades are then downloaded and delivered through `on_backfilled_trade`. Requires the
+  /// `heartbeat` channel for the products.
+  pub fn detect_trade_gaps(mut self, backfill: bool) -> Self {
+    self.trade_gaps = Some(backfill);
+    self
+  }
+
+  /// Checks messages for broken invariants, like negative sizes, crossed quotes, sequences going
+  /// back or trade prices more than `max_price_deviation` (relative) away from the last trade,
+  /// and reports them through `on_data_anomaly`. The policy decides whether such messages are
+  /// still delivered.
+  pub fn detect_anomalies(mut self, max_price_deviation: f64, policy: AnomalyPolicy) -> Self {
+    self.anomalies = Some((max_price_deviation, policy));
+    self
+  }
+
+  /// Uses `default` for required numeric fields that arrive as empty strings or nulls, instead
+  /// of failing the whole message. Replaced values are reported through `on_data_anomaly`, and
+  /// such a message is dropped only with `detect_anomalies(_, AnomalyPolicy::Drop)`.
+  pub fn malformed_decimals(mut self, default: Decimal) -> Self {
+    self.malformed_default = Some(default);
+    self
+  }
+
+  /// Signs every subscribe request of this connection with the profile's API key, which is
+  /// needed for the `user` channel and adds own order ids to the `full` channel. Use one client
+  /// per profile to follow several portfolios.
+  pub fn authenticate(mut self, profile: Profile) -> Self {
+    self.profile = Some(profile);
+    self
+  }
+
+  /// Slows down connection attempts that come too often, e.g. when Coinbase accepts the
+  /// connection and drops it right away during an outage. Every attempt during a storm is
+  /// reported through `on_reconnect_storm` and waits for the policy's backoff, commands sent
+  /// to the worker wait with it. Once the policy gives up the worker stops.
+  pub fn reconnect_storm_guard(mut self, policy: ReconnectStormPolicy) -> Self {
+    self.reconnect_storm = Some(policy);
+    self
+  }
+
+  /// Calls `on_day_rollover` of the handlers once a day ends, days ending `boundary` after UTC
+  /// midnight (`Duration::ZERO` for midnight) and named by the date they start on. The worker
+  /// checks the wall clock between messages, subscribe to `heartbeat` to get the call on time.
+  pub fn day_rollover(mut self, boundary: Duration) -> Self {
+    self.day_rollover = Some(boundary);
+    self
+  }
+
+  /// Name, core and priority of the worker thread, e.g. to tell the connections of a sharded
+  /// feed apart in a profiler or to keep them on dedicated cores.
+  pub fn worker_thread(mut self, worker_thread: WorkerThread) -> Self {
+    self.worker_thread = worker_thread;
+    self
+  }
+
+  /// Clock of the worker's reconnect timing, stop deadlines, subscribe acknowledgements, pings,
+  /// stale product checks, day rollover and message contexts, e.g. a `MockClock` to test them
+  /// without waiting.
+  pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+    self.clock = clock;
+    self
+  }
+
+  /// Endpoints to fail over to, in order, when the primary endpoint keeps failing (see
+  /// `FailoverPolicy`). Subscriptions and handlers are kept across the switch, like on any
+  /// reconnect. Panics if a url is invalid.
+  pub fn failover(mut self, fallback_urls: &[&str], policy: FailoverPolicy) -> Self {
+    let urls = std::iter::once(self.url.as_str()).chain(fallback_urls.iter().copied())
+      .map(|url| Url::parse(url).unwrap_or_else(|err| panic!("Invalid web socket url {}: {}", url, err)))
+      .collect();
+    self.endpoints = Arc::new(Mutex::new(Endpoints::new(urls, policy)));
+    self
+  }
+
+  /// Keeps a second connection open without subscriptions, which takes over right away when
+  /// the connection drops instead of connecting anew. The standby connection is opened on a
+  /// helper thread after the first one, on the endpoint the next reconnect would use, and pinged
+  /// every few seconds between messages, a dead one is replaced.
+  pub fn warm_standby(mut self, enabled: bool) -> Self {
+    self.warm_standby = enabled;
+    self
+  }
+
+  /// How many commands controllers can queue for the running worker, 10 by default. Once the
+  /// queue is full `subscribe` and the other commands block until the worker catches up,
+  /// `try_subscribe` and `subscribe_timeout` fail with `SubscriptionError::QueueFull` instead.
+  /// Must be set before controllers are created.
+  pub fn command_capacity(mut self, capacity: usize) -> Self {
+    let (sender, receiver) = crossbeam::bounded(capacity.max(1));
+    self.sender = sender;
+    self.receiver = receiver;
+    self
+  }
+
+  /// How many subscribe and unsubscribe requests controllers can make before `start`, or
+  /// between `stop` and the next `start`, 64 by default. They are applied in order when the
+  /// worker starts, which then connects right away. Further requests fail with
+  /// `SubscriptionError::PendingFull`.
+  pub fn pending_subscriptions(self, capacity: usize) -> Self {
+    self.pending.lock().unwrap().capacity = capacity;
+    self
   }
 
-  pub fn start<T: CoinBaseWebSocketMessageHandler + Send + 'static>(&mut self, handler: T) {
+  /// Starts the worker with the given handler. A stopped client can be started again with the
+  /// same configuration, controllers and subscriptions, the worker then connects right away.
+  /// Handlers added through the controller to the previous worker are not carried over.
+  /// Fails while the client runs, or while the worker that missed the deadline of
+  /// `stop_with_deadline` is still stopping.
+  pub fn start<T: CoinBaseWebSocketMessageHandler + Send + 'static>(&mut self, handler: T) -> Result<(), StartError> {
     let _guard = self.lock.lock().unwrap();
-    if self.state != ClientState::NotInitialized {
-      panic!("Client is in state {:?}", self.state); // TODO add appropriate error.
+    if self.state == ClientState::Running {
+      return Err(StartError::Running);
     }
+    if self.state == ClientState::Stopped {
+      // Worker that missed the stop deadline still reads from the same channel.
+      match self.join_handle.take() {
+        Some(join_handle) if !join_handle.is_finished() => {
+          self.join_handle = Some(join_handle);
+          return Err(StartError::StillStopping);
+        }
+        Some(join_handle) => {
+          let _ = join_handle.join();
+        }
+        None => {}
+      }
+      // Stop sent to a worker that had already finished would stop the new one.
+      let pending: Vec<_> = self.receiver.try_iter()
+        .filter(|msg| !matches!(msg, WebSocketWorkerMessages::Stop { .. }))
+        .collect();
+      for msg in pending {
+        let _ = self.sender.send(msg);
+      }
+    }
+    *self.exit_reason.lock().unwrap() = None;
 
     let receiver = self.receiver.clone();
-    let url = self.url.clone();
-    let join_handle = thread::spawn(move || {
+    let endpoints = self.endpoints.clone();
+    let url = self.endpoints.lock().unwrap().select(self.clock.now()).clone();
+    let rest_client = self.rest_client.clone();
+    let backfill_trades = self.backfill_trades;
+    let max_backfilled_trades = self.max_backfilled_trades;
+    let last_trade_ids = self.resume_trade_ids.clone();
+    let borrowed_messages = self.borrowed_messages;
+    let two_phase_parsing = self.two_phase_parsing;
+    let unsubscribe_on_stop = self.unsubscribe_on_stop;
+    let panic_policy = self.panic_policy;
+    let supervise = self.supervise;
+    let message_filter = self.message_filter.clone();
+    let stale_products = self.stale_products;
+    let max_subscribe_payload = self.max_subscribe_payload;
+    let deduplication_window = self.deduplication_window;
+    let trade_gaps = self.trade_gaps;
+    let anomalies = self.anomalies;
+    let malformed_default = self.malformed_default.clone();
+    let profile = self.profile.clone();
+    let reconnect_storm = self.reconnect_storm;
+    let day_rollover = self.day_rollover.map(|boundary| {
+      DayRollover::new(chrono::Duration::from_std(boundary).unwrap_or_else(|_| chrono::Duration::zero()))
+    });
+    let snapshot_cache = if self.cache_snapshots { Some(SnapshotCache::new()) } else { None };
+    let clock = self.clock.clone();
+    let warm_standby = self.warm_standby;
+    let exit_reason = self.exit_reason.clone();
+    let last_subscriptions = self.subscriptions.clone();
+    let mut pending = self.pending.lock().unwrap();
+    let mut subscriptions = last_subscriptions.lock().unwrap().clone();
+    let last_wildcards = self.wildcards.clone();
+    let mut wildcards = last_wildcards.lock().unwrap().clone();
+    for request in pending.requests.drain(..) {
+      match request {
+        WebSocketWorkerMessages::Subscribe { product_ids, channels } => {
+          subscriptions.add(&product_ids, &channels);
+        }
+        WebSocketWorkerMessages::Unsubscribe { product_ids, channels } => {
+          subscriptions.remove(&product_ids, &channels);
+        }
+        WebSocketWorkerMessages::Watch { subscription } => wildcards.push(subscription),
+        _ => { /* Only subscription requests are buffered. */ }
+      }
+    }
+    pending.running = true;
+    drop(pending);
+    let join_handle = self.worker_thread.spawn(move || {
+      decimal::set_malformed_default(malformed_default);
       let mut worker = CoinBaseWebSocketClientWorker {
-        url: Url::parse(url.as_str()).unwrap(),
+        url,
+        endpoints,
+        rest_client,
+        backfill_trades,
+        max_backfilled_trades,
+        borrowed_messages,
+        two_phase_parsing,
+        unsubscribe_on_stop,
+        panic_policy,
+        message_filter,
+        stale_monitor: stale_products.map(|(timeout, policy)| (StaleProductMonitor::new(timeout), policy)),
+        last_stale_check: clock.now(),
+        warm_standby,
+        standby: None,
+        opening_standby: None,
+        standby_due: clock.now(),
+        product_status: ProductStatusTracker::new(),
+        max_subscribe_payload,
+        deduplicator: deduplication_window.map(Deduplicator::new),
+        trade_gaps: trade_gaps.map(|backfill| (TradeGapDetector::new(), backfill)),
+        anomaly_detector: anomalies.map(|(max_price_deviation, policy)| (AnomalyDetector::new(max_price_deviation), policy)),
+        profile,
+        pending_chunks: VecDeque::new(),
+        chunk_sent_at: None,
+        last_trade_ids,
+        snapshot_cache,
+        pings: HashMap::new(),
+        next_ping_id: 0,
+        connection_id: 0,
+        connection_span: tracing::Span::none(),
         last_connect_time: None,
+        exit_reason: None,
+        reconnect_guard: reconnect_storm.map(ReconnectGuard::new),
+        day_rollover,
         receiver,
         opt_socket: None,
-        product_ids: HashSet::new(),
-        channels: HashSet::new(),
-        handler,
+        subscriptions,
+        wildcards,
+        stop_deadline: None,
+        handler: CompositeCoinBaseWebSocketMessageHandler::new(vec![Box::new(handler)]),
+        clock,
       };
-      worker.run();
-    });
+      let mut result = panic::catch_unwind(AssertUnwindSafe(|| worker.run()));
+      let reason = loop {
+        let payload = match result {
+          Ok(reason) => break reason,
+          Err(payload) => payload,
+        };
+        tracing::error!(target: WEBSOCKET_WORKER_ID, "Worker panicked: {}", panic_message(&payload));
+        if !supervise {
+          break ClientExitReason::Panicked(panic_message(&payload).into());
+        }
+        tracing::warn!(target: WEBSOCKET_WORKER_ID, "Restarting worker.");
+        result = panic::catch_unwind(AssertUnwindSafe(|| worker.restart()));
+      };
+      *last_subscriptions.lock().unwrap() = worker.subscriptions.clone();
+      *last_wildcards.lock().unwrap() = worker.wildcards.clone();
+      *exit_reason.lock().unwrap() = Some(reason.clone());
+      reason
+    }).expect("Could not spawn the worker thread.");
     self.join_handle = Some(join_handle);
-    self.state = ClientState::Running
+    self.state = ClientState::Running;
+    Ok(())
   }
 
   pub fn controller(&self) -> CoinbaseWebSocketClientController {
     CoinbaseWebSocketClientController {
-      sender: self.sender.clone()
+      sender: self.sender.clone(),
+      rest_client: self.rest_client.clone(),
+      next_handler_id: self.next_handler_id.clone(),
+      known_products: self.known_products.clone(),
+      authenticated: self.profile.is_some(),
+      pending: self.pending.clone(),
     }
   }
 
-  pub fn stop(mut self) {
+  /// Why the worker stopped, `None` while it is running.
+  pub fn exit_reason(&self) -> Option<ClientExitReason> {
+    self.exit_reason.lock().unwrap().clone()
+  }
+
+  /// Connection history of the primary and fallback endpoints, in the order of preference.
+  pub fn endpoint_health(&self) -> Vec<EndpointHealth> {
+    self.endpoints.lock().unwrap().health(self.clock.now())
+  }
+
+  /// Stops the worker and returns why it stopped, which is `ClientExitReason::Stopped` unless
+  /// it had stopped on its own before.
+  pub fn stop(&mut self) -> ClientExitReason {
+    self.stop_with(None).expect("Worker is joined without a deadline.")
+  }
+
+  /// Stops the worker gracefully: no new messages are read from the socket, pending commands
+  /// are processed, handlers are closed and only then the socket is closed. Returns `None` if
+  /// the worker didn't finish within the deadline, it is left to finish on its own then.
+  pub fn stop_with_deadline(&mut self, deadline: Duration) -> Option<ClientExitReason> {
+    self.stop_with(Some(self.clock.now() + deadline))
+  }
+
+  fn stop_with(&mut self, deadline: Option<Instant>) -> Option<ClientExitReason> {
     let _guard = self.lock.lock().unwrap();
     match self.state {
       ClientState::NotInitialized => {
-        log::info!("Client stop was called but client was not started.");
-        return;
+        tracing::info!("Client stop was called but client was not started.");
+        return Some(ClientExitReason::Stopped);
       },
       ClientState::Stopped => {
-        log::info!("Client stopped multiple times");
-        return;
+        tracing::info!("Client stopped multiple times");
+        return Some(self.exit_reason().unwrap_or(ClientExitReason::Stopped));
       },
       _ => { /* ignore */ }
     }
     // Note: Sender must be set otherwise it is an error and it should panic.
-    match self.sender.send(WebSocketWorkerMessages::Stop) {
+    match self.sender.send(WebSocketWorkerMessages::Stop { deadline }) {
       Err(_) => {
-        log::error!("Couldn't send stop message to the worker since channel was closed.");
+        tracing::error!("Couldn't send stop message to the worker since channel was closed.");
       },
       _ => { /* ignore */ }
     };
-    self.join_handle.take().unwrap()
-      .join()
-      .expect("Got error while joining worker thread.");
     self.state = ClientState::Stopped;
+    self.pending.lock().unwrap().running = false;
+    let join_handle = self.join_handle.take().unwrap();
+    if let Some(deadline) = deadline {
+      while !join_handle.is_finished() {
+        if self.clock.now() >= deadline {
+          tracing::warn!("Worker didn't stop before the deadline.");
+          self.join_handle = Some(join_handle);
+          return None;
+        }
+        self.clock.sleep(Duration::from_millis(10));
+      }
+    }
+    Some(join_handle.join().expect("Got error while joining worker thread."))
   }
 
-  pub fn wait(mut self) {
+  /// Waits until the worker stops on its own and returns why it stopped.
+  pub fn wait(&mut self) -> ClientExitReason {
     let _guard = self.lock.lock().unwrap();
     match self.state {
       ClientState::NotInitialized => {
-        log::info!("Client stop was called but client was not started.");
-        return;
+        tracing::info!("Client stop was called but client was not started.");
+        return ClientExitReason::Stopped;
       },
       ClientState::Stopped => {
-        log::info!("Client stopped multiple times");
-        return;
+        tracing::info!("Client stopped multiple times");
+        return self.exit_reason().unwrap_or(ClientExitReason::Stopped);
       },
       _ => { /* ignore */ }
     }
     let join_handle = self.join_handle.take().unwrap();
     self.state = ClientState::Stopped;
+    self.pending.lock().unwrap().running = false;
     drop(_guard);
-    let _ = join_handle.join();
+    join_handle.join().unwrap_or_else(|payload| ClientExitReason::Panicked(panic_message(&payload).into()))
   }
 }
 
 pub struct CoinbaseWebSocketClientController {
   sender: Sender<WebSocketWorkerMessages>,
+  rest_client: CoinbaseRestClient,
+  next_handler_id: Arc<AtomicU64>,
+  // Products fetched from the REST API, shared by all controllers of the client.
+  known_products: Arc<Mutex<Option<HashSet<String>>>>,
+  authenticated: bool,
+  pending: Arc<Mutex<PendingSubscriptions>>,
 }
 
 impl CoinbaseWebSocketClientController {
 
+  /// Subscribes through the running worker. Before `start` the request waits for the worker,
+  /// see `CoinbaseWebSocketClient::pending_subscriptions`, requests over the limit are dropped
+  /// with an error log, `subscribe_checked` returns the error instead.
   pub fn subscribe(
     &self,
     product_ids: Vec<String>,
     channels: Vec<Channel>,
   ) {
-    self.send_message(WebSocketWorkerMessages::Subscribe { product_ids, channels });
+    if let Err(err) = self.send_subscription(WebSocketWorkerMessages::Subscribe { product_ids, channels }) {
+      tracing::error!("{}", err);
+    }
+  }
+
+  /// Like `subscribe`, but fails right away when the worker's command queue is full.
+  pub fn try_subscribe(&self, product_ids: Vec<String>, channels: Vec<Channel>) -> Result<(), SubscriptionError> {
+    let message = WebSocketWorkerMessages::Subscribe { product_ids, channels };
+    self.send_subscription_with(message, try_send)
+  }
+
+  /// Like `subscribe`, but waits at most `timeout` for room in the worker's command queue, so
+  /// the caller, e.g. an async task moved to a blocking thread, is never stuck for long.
+  pub fn subscribe_timeout(&self, product_ids: Vec<String>, channels: Vec<Channel>, timeout: Duration) -> Result<(), SubscriptionError> {
+    let message = WebSocketWorkerMessages::Subscribe { product_ids, channels };
+    self.send_subscription_with(message, |sender, message| send_timeout(sender, message, timeout))
+  }
+
+  /// Subscribes to the given channels for every product that is currently online
+  /// and accepts new orders. Product list is fetched from the REST API.
+  pub fn subscribe_all(&self, channels: Vec<Channel>) -> Result<(), RestError> {
+    let products = self.rest_client.get_products()?;
+    *self.known_products.lock().unwrap() = Some(products.iter().map(|product| product.id.clone()).collect());
+    let product_ids: Vec<String> = products.into_iter()
+      .filter(|product| product.is_online())
+      .map(|product| product.id)
+      .collect();
+    tracing::info!("Subscribing to {} online products", product_ids.len());
+    self.subscribe(product_ids, channels);
+    Ok(())
+  }
+
+  /// Subscribes to the products matching the specs, which are product ids, globs like `*-USD`,
+  /// or `base:BTC` and `quote:EUR` (see `ProductPattern`). Specs are expanded against the
+  /// product list cached for `subscribe_checked`. The `status` channel is subscribed too, and
+  /// products it reports online later are subscribed as well when they match a spec with a
+  /// wildcard, e.g. new listings. Returns the products subscribed right away.
+  pub fn subscribe_matching(&self, specs: &[&str], channels: Vec<Channel>) -> Result<Vec<String>, SubscriptionError> {
+    let patterns = specs.iter().map(|spec| spec.parse()).collect::<Result<Vec<ProductPattern>, _>>()?;
+    let mut known_products = self.known_products.lock().unwrap();
+    if known_products.is_none() {
+      *known_products = Some(self.fetch_product_ids()?);
+    }
+    let product_ids = wildcard::expand(&patterns, known_products.as_ref().unwrap().iter().map(String::as_str));
+    drop(known_products);
+    tracing::info!("Subscribing to {} products matching {:?}", product_ids.len(), specs);
+
+    if patterns.iter().any(ProductPattern::is_wildcard) {
+      let subscription = WildcardSubscription { patterns, channels: channels.clone() };
+      self.send_subscription(WebSocketWorkerMessages::Watch { subscription })?;
+    }
+    let mut channels = channels;
+    channels.push(Channel::new(Channels::Status));
+    self.send_subscription(WebSocketWorkerMessages::Subscribe { product_ids: product_ids.clone(), channels })?;
+    Ok(product_ids)
+  }
+
+  /// Validates product ids and channel/product combinations before subscribing. Products are
+  /// checked against the product list fetched from the REST API on first use, see `refresh_products`.
+  pub fn subscribe_checked(
+    &self,
+    product_ids: Vec<String>,
+    channels: Vec<Channel>,
+  ) -> Result<(), SubscriptionError> {
+    let mut known_products = self.known_products.lock().unwrap();
+    if known_products.is_none() {
+      *known_products = Some(self.fetch_product_ids()?);
+    }
+    validate_subscription(known_products.as_ref().unwrap(), &product_ids, &channels, self.authenticated)?;
+    drop(known_products);
+    self.send_subscription(WebSocketWorkerMessages::Subscribe { product_ids, channels })
+  }
+
+  /// Fetches the product list used by `subscribe_checked` again, e.g. after new products were listed.
+  pub fn refresh_products(&self) -> Result<(), RestError> {
+    let product_ids = self.fetch_product_ids()?;
+    *self.known_products.lock().unwrap() = Some(product_ids);
+    Ok(())
+  }
+
+  fn fetch_product_ids(&self) -> Result<HashSet<String>, RestError> {
+    Ok(self.rest_client.get_products()?.into_iter().map(|product| product.id).collect())
   }
 
   pub fn unsubscribe(
@@ -157,95 +753,365 @@ impl CoinbaseWebSocketClientController {
     product_ids: Vec<String>,
     channels: Vec<Channel>,
   ) {
-    self.send_message(WebSocketWorkerMessages::Unsubscribe { product_ids, channels });
+    if let Err(err) = self.send_subscription(WebSocketWorkerMessages::Unsubscribe { product_ids, channels }) {
+      tracing::error!("{}", err);
+    }
+  }
+
+  /// Like `unsubscribe`, but fails right away when the worker's command queue is full.
+  pub fn try_unsubscribe(&self, product_ids: Vec<String>, channels: Vec<Channel>) -> Result<(), SubscriptionError> {
+    let message = WebSocketWorkerMessages::Unsubscribe { product_ids, channels };
+    self.send_subscription_with(message, try_send)
+  }
+
+  /// Like `unsubscribe`, but waits at most `timeout` for room in the worker's command queue.
+  pub fn unsubscribe_timeout(&self, product_ids: Vec<String>, channels: Vec<Channel>, timeout: Duration) -> Result<(), SubscriptionError> {
+    let message = WebSocketWorkerMessages::Unsubscribe { product_ids, channels };
+    self.send_subscription_with(message, |sender, message| send_timeout(sender, message, timeout))
+  }
+
+  /// Sends web socket ping to the server. Round trip time is reported
+  /// through `on_pong` once the matching pong arrives.
+  pub fn ping(&self) {
+    self.send_message(WebSocketWorkerMessages::Ping);
+  }
+
+  /// Drops the current connection, reconnects and restores all subscriptions.
+  pub fn reconnect(&self) {
+    self.send_message(WebSocketWorkerMessages::Reconnect);
+  }
+
+  /// Replays the cached status and order book snapshots to the handler.
+  /// Does nothing unless the client was created with `cache_snapshots(true)`.
+  pub fn replay_snapshots(&self) {
+    self.send_message(WebSocketWorkerMessages::ReplaySnapshots);
+  }
+
+  /// Registers additional handler on the running client. Handler is initialized by the worker
+  /// and receives messages from then on. With `replay` set, the cached snapshots are replayed
+  /// to it first (requires `cache_snapshots(true)`).
+  pub fn add_handler(&self, handler: Box<dyn CoinBaseWebSocketMessageHandler + Send>, replay: bool) -> HandlerId {
+    let id = HandlerId(self.next_handler_id.fetch_add(1, Ordering::Relaxed));
+    self.send_message(WebSocketWorkerMessages::AddHandler { id, handler, replay });
+    id
+  }
+
+  /// Removes handler added with `add_handler`, handler is closed before it is dropped.
+  pub fn remove_handler(&self, id: HandlerId) {
+    self.send_message(WebSocketWorkerMessages::RemoveHandler { id });
+  }
+
+  /// Sends the request to the worker, or keeps it for `start` while no worker runs.
+  fn send_subscription(&self, message: WebSocketWorkerMessages) -> Result<(), SubscriptionError> {
+    self.send_subscription_with(message, |_, message| {
+      self.send_message(message);
+      Ok(())
+    })
+  }
+
+  fn send_subscription_with(
+    &self,
+    message: WebSocketWorkerMessages,
+    send: impl FnOnce(&Sender<WebSocketWorkerMessages>, WebSocketWorkerMessages) -> Result<(), SubscriptionError>,
+  ) -> Result<(), SubscriptionError> {
+    let mut pending = self.pending.lock().unwrap();
+    if pending.running {
+      drop(pending);
+      return send(&self.sender, message);
+    }
+    if pending.requests.len() >= pending.capacity {
+      return Err(SubscriptionError::PendingFull { capacity: pending.capacity });
+    }
+    pending.requests.push(message);
+    Ok(())
   }
 
   fn send_message(&self, message: WebSocketWorkerMessages) {
     match self.sender.send(message) {
       Err(_) => {
-        log::warn!("Got error while sending message to the websocket worker because channel is closed.");
+        tracing::warn!("Got error while sending message to the websocket worker because channel is closed.");
       },
       _ => { /* ignore */ }
     };
   }
 }
 
+fn try_send(sender: &Sender<WebSocketWorkerMessages>, message: WebSocketWorkerMessages) -> Result<(), SubscriptionError> {
+  sender.try_send(message).map_err(|err| match err {
+    TrySendError::Full(_) => SubscriptionError::QueueFull { capacity: sender.capacity().unwrap_or_default() },
+    TrySendError::Disconnected(_) => SubscriptionError::Disconnected,
+  })
+}
+
+fn send_timeout(sender: &Sender<WebSocketWorkerMessages>, message: WebSocketWorkerMessages, timeout: Duration) -> Result<(), SubscriptionError> {
+  sender.send_timeout(message, timeout).map_err(|err| match err {
+    SendTimeoutError::Timeout(_) => SubscriptionError::QueueFull { capacity: sender.capacity().unwrap_or_default() },
+    SendTimeoutError::Disconnected(_) => SubscriptionError::Disconnected,
+  })
+}
+
 const WEBSOCKET_WORKER_ID: &str = "WebSocketWorker";
 
+/// How long the worker waits for the server to acknowledge unsubscribe on stop.
+const UNSUBSCRIBE_ACK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long the worker waits for acknowledgement of a subscribe chunk before sending the next one.
+const SUBSCRIBE_ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the worker looks for stale products.
+const STALE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// How often the standby connection is pinged.
+const STANDBY_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+// Trades downloaded per product after a reconnect, a hundred requests.
+const MAX_BACKFILLED_TRADES: usize = 10_000;
+
+const STANDBY_THREAD_NAME: &str = "WebSocketStandby";
+
+type ConnectResult = Result<WebSocket<AutoStream>, tungstenite::Error>;
+
 enum TerminateOrReconnect {
   Reconnect,
   Terminal,
 }
 
-struct CoinBaseWebSocketClientWorker<T: CoinBaseWebSocketMessageHandler> {
+struct CoinBaseWebSocketClientWorker {
+  // Endpoint of the current connection.
   url: Url,
+  endpoints: Arc<Mutex<Endpoints>>,
+  rest_client: CoinbaseRestClient,
+  backfill_trades: bool,
+  max_backfilled_trades: usize,
+  borrowed_messages: bool,
+  two_phase_parsing: bool,
+  unsubscribe_on_stop: bool,
+  panic_policy: PanicPolicy,
+  message_filter: Option<MessageFilter>,
+  stale_monitor: Option<(StaleProductMonitor, StalePolicy)>,
+  product_status: ProductStatusTracker,
+  max_subscribe_payload: Option<usize>,
+  deduplicator: Option<Deduplicator>,
+  // Detector and whether to backfill the detected gaps.
+  trade_gaps: Option<(TradeGapDetector, bool)>,
+  anomaly_detector: Option<(AnomalyDetector, AnomalyPolicy)>,
+  profile: Option<Profile>,
+  // Subscribe chunks waiting for the acknowledgement of the chunk sent at `chunk_sent_at`.
+  pending_chunks: VecDeque<SubscribeRequest>,
+  chunk_sent_at: Option<Instant>,
+  last_stale_check: Instant,
+  warm_standby: bool,
+  // Connected socket without subscriptions and its endpoint, checked again at `standby_due`.
+  standby: Option<(WebSocket<AutoStream>, Url)>,
+  // Standby connection being opened on a helper thread, and its endpoint.
+  opening_standby: Option<(Receiver<ConnectResult>, Url)>,
+  standby_due: Instant,
+  last_trade_ids: HashMap<String, i64>,
+  snapshot_cache: Option<SnapshotCache>,
+  pings: HashMap<u64, Instant>,
+  next_ping_id: u64,
+  connection_id: u64,
+  // Span of the current connection, entered while the worker processes a step.
+  connection_span: tracing::Span,
   last_connect_time: Option<Instant>,
+  // Reason recorded where the worker decides to stop, handlers returning `Terminate` record none.
+  exit_reason: Option<ClientExitReason>,
+  reconnect_guard: Option<ReconnectGuard>,
+  day_rollover: Option<DayRollover>,
   receiver: crossbeam::Receiver<WebSocketWorkerMessages>,
   opt_socket: Option<WebSocket<AutoStream>>,
-  product_ids: HashSet<String>,
-  channels: HashSet<Channel>,
-  handler: T,
+  subscriptions: Subscriptions,
+  // Patterns whose matching products are subscribed once the status channel reports them online.
+  wildcards: Vec<WildcardSubscription>,
+  stop_deadline: Option<Instant>,
+  // Handler given to `start` followed by the handlers added at runtime.
+  handler: CompositeCoinBaseWebSocketMessageHandler,
+  clock: Arc<dyn Clock>,
 }
 
-impl<T: CoinBaseWebSocketMessageHandler> CoinBaseWebSocketClientWorker<T> {
-  fn run(&mut self) {
-    if self.wait_until_initial_connection().is_err() {
+impl CoinBaseWebSocketClientWorker {
+  fn run(&mut self) -> ClientExitReason {
+    // Restarted client connects with the subscriptions of the previous worker.
+    let connected = if self.subscriptions.is_empty() {
+      self.wait_until_initial_connection()
+    } else {
+      self.connect().and_then(|_| self.subscribe())
+    };
+    if let Err(err) = connected {
       // Note technically this can be both terminal and reconnect errors,
       // since subscribe can return reconnect error, but if we were not
       // able to establish initial connection and subscription then we
       // opt out from trying to establish any further connections.
-      log::warn!(target: WEBSOCKET_WORKER_ID, "Initial connection could not be established.");
-      return;
+      tracing::warn!(target: WEBSOCKET_WORKER_ID, "Initial connection could not be established.");
+      return self.exit_reason(err);
+    }
+    tracing::trace!("Initial connection acquired");
+    self.run_connected()
+  }
+
+  /// Connects again with the current subscriptions after the worker panicked.
+  fn restart(&mut self) -> ClientExitReason {
+    self.opt_socket = None;
+    self.endpoints.lock().unwrap().on_disconnected(self.clock.now());
+    if let Err(err) = self.connect().and_then(|_| self.subscribe()) {
+      tracing::warn!(target: WEBSOCKET_WORKER_ID, "Could not reconnect the restarted worker.");
+      return self.exit_reason(err);
     }
+    self.run_connected()
+  }
 
-    log::trace!("Initial connection acquired");
+  fn run_connected(&mut self) -> ClientExitReason {
     match self.handler.initialize() {
       Err(_) => {
-        log::warn!("Got terminate signal from the handler.");
-        return;
+        tracing::warn!("Got terminate signal from the handler.");
+        return ClientExitReason::HandlerTerminated;
       },
       _ => { /* ignore */ }
     }
-    log::trace!("Initializing handler");
+    tracing::trace!("Initializing handler");
+    // Trades known from a previous run, or from before the worker was restarted.
+    if self.backfill_trades && !self.last_trade_ids.is_empty() {
+      if let Err(err) = self.backfill_missed_trades() {
+        self.shutdown();
+        return self.exit_reason(err);
+      }
+    }
 
     // Main event loop.
-    loop {
-      let res = self.step();
-      if res.is_err() {
-        match res.unwrap_err() {
+    let reason = loop {
+      if let Err(err) = self.step() {
+        match err {
           TerminateOrReconnect::Reconnect => {
-            if self.connect().and_then(|_| self.subscribe()).is_err() {
-              log::warn!(target: WEBSOCKET_WORKER_ID, "Could not reconnect to the web socket stream.");
-              return;
+            if self.endpoints.lock().unwrap().on_disconnected(self.clock.now()) {
+              tracing::warn!(target: WEBSOCKET_WORKER_ID, "Connections to {} keep dropping, failing over.", self.url);
+            }
+            if let Err(err) = self.connect().and_then(|_| self.subscribe()) {
+              tracing::warn!(target: WEBSOCKET_WORKER_ID, "Could not reconnect to the web socket stream.");
+              break self.exit_reason(err);
+            }
+            if self.backfill_trades {
+              if let Err(err) = self.backfill_missed_trades() {
+                break self.exit_reason(err);
+              }
             }
           }
-          TerminateOrReconnect::Terminal => return
+          TerminateOrReconnect::Terminal => break self.exit_reason(err),
         };
       }
+    };
+    self.shutdown();
+    reason
+  }
+
+  /// Stops the worker for the given reason.
+  fn terminate(&mut self, reason: ClientExitReason) -> TerminateOrReconnect {
+    self.exit_reason = Some(reason);
+    TerminateOrReconnect::Terminal
+  }
+
+  /// Why the worker stops because of `err`, terminal errors without a recorded reason come
+  /// from handlers.
+  fn exit_reason(&mut self, err: TerminateOrReconnect) -> ClientExitReason {
+    match err {
+      TerminateOrReconnect::Reconnect => ClientExitReason::ConnectionFailed,
+      TerminateOrReconnect::Terminal => self.exit_reason.take().unwrap_or(ClientExitReason::HandlerTerminated),
+    }
+  }
+
+  /// Processes commands still waiting in the queue, closes handlers and then the socket.
+  /// Messages that arrive on the socket in the meantime are not read anymore.
+  fn shutdown(&mut self) {
+    let clock = self.clock.clone();
+    let deadline_passed = |deadline: Option<Instant>| deadline.map(|d| clock.now() >= d).unwrap_or(false);
+    while !deadline_passed(self.stop_deadline) {
+      match self.receiver.try_recv() {
+        Ok(WebSocketWorkerMessages::AddHandler { id, handler, replay }) => {
+          let _ = self.add_handler(id, handler, replay);
+        }
+        Ok(WebSocketWorkerMessages::RemoveHandler { id }) => self.remove_handler(id),
+        Ok(_) => tracing::debug!(target: WEBSOCKET_WORKER_ID, "Ignoring command received while stopping."),
+        Err(_) => break,
+      }
+    }
+
+    if self.unsubscribe_on_stop {
+      self.unsubscribe_all();
+    }
+
+    if self.handler.close().is_err() {
+      tracing::debug!(target: WEBSOCKET_WORKER_ID, "Handler returned terminate while closing.");
+    }
+
+    let standby = self.standby.take().map(|(socket, _)| socket);
+    for mut socket in self.opt_socket.take().into_iter().chain(standby) {
+      if let Err(err) = socket.close(None).and_then(|_| socket.write_pending()) {
+        tracing::debug!(target: WEBSOCKET_WORKER_ID, "Could not close the socket cleanly: {:?}", err);
+      }
     }
+    tracing::info!(target: WEBSOCKET_WORKER_ID, "Worker stopped.");
   }
 
 
   fn step(&mut self) -> Result<(), TerminateOrReconnect> {
+    let connection_span = self.connection_span.clone();
+    let _entered = connection_span.enter();
+    let now = self.clock.now();
+    if self.stale_monitor.is_some() && now.duration_since(self.last_stale_check) >= STALE_CHECK_INTERVAL {
+      self.last_stale_check = now;
+      self.check_stale_products()?;
+    }
+    if self.warm_standby {
+      self.receive_standby(false);
+      if now >= self.standby_due {
+        self.standby_due = now + STANDBY_KEEPALIVE_INTERVAL;
+        self.keep_standby_alive();
+      }
+    }
+    let wall_clock = self.clock.utc_now();
+    if let Some(day) = self.day_rollover.as_mut().and_then(|rollover| rollover.check(wall_clock)) {
+      tracing::info!(target: WEBSOCKET_WORKER_ID, "Day {} ended.", day);
+      self.handler.on_day_rollover(day).map_err(|_| TerminateOrReconnect::Terminal)?;
+    }
+    if self.chunk_sent_at.map(|sent_at| now.duration_since(sent_at) >= SUBSCRIBE_ACK_TIMEOUT).unwrap_or(false) {
+      tracing::warn!(target: WEBSOCKET_WORKER_ID, "Subscribe chunk was not acknowledged in time, sending the next one.");
+      self.send_next_chunk()?;
+    }
     match self.receiver.try_recv() {
       Ok(msg) => {
         match msg {
           WebSocketWorkerMessages::Subscribe { product_ids, channels } => {
             // Subscribe to new channels.
-            log::debug!(target: WEBSOCKET_WORKER_ID, "Got subscribe message for products: {:?}, and channels: {:?}", product_ids, channels);
-            self.append_subscriptions(&product_ids, &channels);
-            self.subscribe()
+            tracing::debug!(target: WEBSOCKET_WORKER_ID, "Got subscribe message for products: {:?}, and channels: {:?}", product_ids, channels);
+            let added = self.subscriptions.add(&product_ids, &channels);
+            self.subscribe_to(added)
           }
           WebSocketWorkerMessages::Unsubscribe { product_ids, channels } => {
             // Unsubscribe from some channels.
-            log::debug!(target: WEBSOCKET_WORKER_ID, "Got unsubscribe message for products: {:?}, and channels: {:?}", product_ids, channels);
-            self.remove_subscriptions(&product_ids, &channels);
-            self.unsubscribe(product_ids, channels)
+            tracing::debug!(target: WEBSOCKET_WORKER_ID, "Got unsubscribe message for products: {:?}, and channels: {:?}", product_ids, channels);
+            let removed = self.subscriptions.remove(&product_ids, &channels);
+            self.unsubscribe_from(removed)
           }
-          WebSocketWorkerMessages::Stop => {
+          WebSocketWorkerMessages::Watch { subscription } => {
+            self.wildcards.push(subscription);
+            Ok(())
+          }
+          WebSocketWorkerMessages::Ping => self.ping(),
+          WebSocketWorkerMessages::ReplaySnapshots => self.replay_snapshots(),
+          WebSocketWorkerMessages::AddHandler { id, handler, replay } => self.add_handler(id, handler, replay),
+          WebSocketWorkerMessages::RemoveHandler { id } => {
+            self.remove_handler(id);
+            Ok(())
+          }
+          WebSocketWorkerMessages::Reconnect => {
+            tracing::info!(target: WEBSOCKET_WORKER_ID, "Got reconnect signal for web socket stream");
+            self.endpoints.lock().unwrap().on_closed();
+            Err(TerminateOrReconnect::Reconnect)
+          }
+          WebSocketWorkerMessages::Stop { deadline } => {
             // Exit gracefully.
-            log::info!(target: WEBSOCKET_WORKER_ID, "Got stop signal for web socket stream");
-            return Err(TerminateOrReconnect::Terminal);
+            tracing::info!(target: WEBSOCKET_WORKER_ID, "Got stop signal for web socket stream");
+            self.stop_deadline = deadline;
+            Err(self.terminate(ClientExitReason::Stopped))
           }
         }
       }
@@ -254,8 +1120,8 @@ impl<T: CoinBaseWebSocketMessageHandler> CoinBaseWebSocketClientWorker<T> {
           TryRecvError::Empty => self.consume_socket(),
           TryRecvError::Disconnected => {
             // Exit with error.
-            log::error!(target: WEBSOCKET_WORKER_ID, "Message Channel closed from outside. This is illegal state.");
-            Err(TerminateOrReconnect::Terminal)
+            tracing::error!(target: WEBSOCKET_WORKER_ID, "Message Channel closed from outside. This is illegal state.");
+            Err(self.terminate(ClientExitReason::Stopped))
           }
         }
       }
@@ -265,19 +1131,31 @@ impl<T: CoinBaseWebSocketMessageHandler> CoinBaseWebSocketClientWorker<T> {
   fn connect(&mut self) -> Result<(), TerminateOrReconnect> {
     loop {
       let can_try_to_connect = self.last_connect_time
-        .map(|instant| instant + Duration::from_millis(500) < Instant::now())
+        .map(|instant| instant + Duration::from_millis(500) < self.clock.now())
         .unwrap_or(true);
 
       if can_try_to_connect {
+        self.check_reconnect_storm()?;
+        // Standby that is still being opened is waited for instead of opening another connection.
+        self.receive_standby(true);
+        if let Some((socket, url)) = self.standby.take() {
+          tracing::info!(target: WEBSOCKET_WORKER_ID, "Switching to the standby connection to {}", url);
+          self.url = url;
+          self.use_socket(socket);
+          // Replaced on the next step.
+          self.standby_due = self.clock.now();
+          return Ok(());
+        }
+        self.url = self.endpoints.lock().unwrap().select(self.clock.now()).clone();
         match tungstenite::connect(&self.url) {
           Ok((socket, http_response)) => {
-            log::info!(target: WEBSOCKET_WORKER_ID, "Connected to the server");
-            log::info!(target: WEBSOCKET_WORKER_ID, "Response HTTP code: {}", http_response.status());
-            log::info!(target: WEBSOCKET_WORKER_ID, "Response contains the following headers:");
+            tracing::info!(target: WEBSOCKET_WORKER_ID, "Connected to {}", self.url);
+            tracing::info!(target: WEBSOCKET_WORKER_ID, "Response HTTP code: {}", http_response.status());
+            tracing::info!(target: WEBSOCKET_WORKER_ID, "Response contains the following headers:");
             for (header, value) in http_response.headers() {
-              log::info!(target: WEBSOCKET_WORKER_ID, "{}: {:?}", header, value);
+              tracing::info!(target: WEBSOCKET_WORKER_ID, "{}: {:?}", header, value);
             }
-            self.opt_socket = Some(socket); // Last socket will be dropped here.
+            self.use_socket(socket);
             return Ok(());
           }
           Err(error) => {
@@ -285,187 +1163,1542 @@ impl<T: CoinBaseWebSocketMessageHandler> CoinBaseWebSocketClientWorker<T> {
               tungstenite::Error::ConnectionClosed => {
                 // Connection was closed normally, meaning that
                 // this is an illegal state and it is ok to terminate here.
-                log::warn!(target: WEBSOCKET_WORKER_ID, "Trying to work with web-socket after connection was manually closed.");
-                return Err(TerminateOrReconnect::Terminal);
+                tracing::warn!(target: WEBSOCKET_WORKER_ID, "Trying to work with web-socket after connection was manually closed.");
+                return Err(self.terminate(ClientExitReason::ConnectionFailed));
               }
               error => {
                 // Just log errors and ignore.
-                log::warn!(target: WEBSOCKET_WORKER_ID, "Got error while connecting: {:?}", error);
+                tracing::warn!(target: WEBSOCKET_WORKER_ID, "Got error while connecting to {}: {:?}", self.url, error);
               }
             }
-            self.last_connect_time = Some(Instant::now());
+            if self.endpoints.lock().unwrap().on_failure(self.clock.now()) {
+              tracing::warn!(target: WEBSOCKET_WORKER_ID, "Could not connect to {} repeatedly, failing over.", self.url);
+            }
+            self.last_connect_time = Some(self.clock.now());
           }
         };
       } else {
-        log::debug!(target: WEBSOCKET_WORKER_ID, "Going to sleep before reconnect for 250 millis");
-        thread::sleep(Duration::from_millis(250))
+        tracing::debug!(target: WEBSOCKET_WORKER_ID, "Going to sleep before reconnect for 250 millis");
+        self.clock.sleep(Duration::from_millis(250))
       }
     }
   }
 
-  fn append_subscriptions(&mut self, product_ids: &Vec<String>, channels: &Vec<Channel>) {
-    self.product_ids.extend(product_ids.clone());
-    self.channels.extend(channels.clone());
+  /// Makes the socket, connected to `self.url`, the current connection.
+  fn use_socket(&mut self, socket: WebSocket<AutoStream>) {
+    self.endpoints.lock().unwrap().on_connected(self.clock.now());
+    if let Some(guard) = self.reconnect_guard.as_mut() {
+      guard.on_connected(self.clock.now());
+    }
+    self.opt_socket = Some(socket); // Last socket will be dropped here.
+    self.pings.clear(); // Pings sent on the old socket will never be answered.
+    self.connection_id += 1;
+    // Chunks of the old connection are sent again as part of the full subscription.
+    self.pending_chunks.clear();
+    self.chunk_sent_at = None;
+    self.connection_span = tracing::info_span!(
+      target: WEBSOCKET_WORKER_ID, "connection", id = self.connection_id, url = %self.url
+    );
   }
 
-  fn subscribe(&mut self) -> Result<(), TerminateOrReconnect> {
-    let product_ids = Vec::from_iter(self.product_ids.clone());
-    let channels = Vec::from_iter(self.channels.clone());
-    self.send_request(
-      RequestMessages::Subscribe { req: SubscribeRequest::new(product_ids, channels) }
-    )
+  /// Starts opening the standby connection, or pings it and reads whatever arrived on it. A
+  /// standby that fails is dropped and opened again on the next check.
+  fn keep_standby_alive(&mut self) {
+    let (mut socket, url) = match self.standby.take() {
+      Some(standby) => standby,
+      None => {
+        if self.opening_standby.is_none() {
+          self.open_standby();
+        }
+        return;
+      }
+    };
+    set_read_timeout(&socket, Some(Duration::from_millis(1)));
+    let mut alive = socket.write_message(Message::Ping(Vec::new())).is_ok();
+    while alive {
+      match socket.read_message() {
+        // Pongs, and pings which are answered while reading.
+        Ok(_) => continue,
+        Err(tungstenite::Error::Io(err)) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => break,
+        Err(err) => {
+          tracing::warn!(target: WEBSOCKET_WORKER_ID, "Standby connection to {} failed: {:?}", url, err);
+          alive = false;
+        }
+      }
+    }
+    set_read_timeout(&socket, None);
+    if alive {
+      self.standby = Some((socket, url));
+    }
   }
 
-  fn remove_subscriptions(&mut self, product_ids: &Vec<String>, channels: &Vec<Channel>) {
-    product_ids.iter().for_each(|product| { self.product_ids.remove(product); });
-    channels.iter().for_each(|product| { self.channels.remove(product); });
+  /// Connects to the endpoint the next reconnect would use on a helper thread, so the worker
+  /// keeps processing messages meanwhile. The socket is picked up by `receive_standby`.
+  fn open_standby(&mut self) {
+    let url = self.endpoints.lock().unwrap().select(self.clock.now()).clone();
+    let (sender, receiver) = crossbeam::bounded(1);
+    let thread_url = url.clone();
+    let spawned = thread::Builder::new().name(STANDBY_THREAD_NAME.into()).spawn(move || {
+      // Socket is dropped if the worker stopped in the meantime.
+      let _ = sender.send(tungstenite::connect(&thread_url).map(|(socket, _)| socket));
+    });
+    match spawned {
+      Ok(_) => self.opening_standby = Some((receiver, url)),
+      Err(err) => tracing::warn!(target: WEBSOCKET_WORKER_ID, "Could not spawn thread opening the standby connection: {}", err),
+    }
   }
 
-  fn unsubscribe(&mut self, product_ids: Vec<String>, channels: Vec<Channel>) -> Result<(), TerminateOrReconnect> {
-    self.send_request(
-      RequestMessages::Unsubscribe { req: UnsubscribeRequest::new(product_ids, channels) }
-    )
+  /// Takes the standby connection from the helper thread once it is open, or waits for it.
+  fn receive_standby(&mut self, wait: bool) {
+    let (receiver, url) = match self.opening_standby.take() {
+      Some(opening) => opening,
+      None => return,
+    };
+    let result = if wait {
+      receiver.recv().map_err(|_| TryRecvError::Disconnected)
+    } else {
+      receiver.try_recv()
+    };
+    match result {
+      Ok(Ok(socket)) => {
+        tracing::debug!(target: WEBSOCKET_WORKER_ID, "Opened standby connection to {}", url);
+        self.standby = Some((socket, url));
+      }
+      Ok(Err(err)) => tracing::warn!(target: WEBSOCKET_WORKER_ID, "Could not open standby connection to {}: {:?}", url, err),
+      Err(TryRecvError::Empty) => self.opening_standby = Some((receiver, url)),
+      Err(TryRecvError::Disconnected) => tracing::warn!(target: WEBSOCKET_WORKER_ID, "Thread opening the standby connection to {} failed.", url),
+    }
   }
 
-  fn send_request(&mut self, request: RequestMessages) -> Result<(), TerminateOrReconnect> {
-    let socket = self.opt_socket.as_mut().unwrap();
-    let json_msg = serde_json::to_string(&request).unwrap();
-    socket.write_message(Message::text(json_msg)).or_else(|err| {
-      log::debug!(target: WEBSOCKET_WORKER_ID, "Got error while sending subscribe message ");
-      handle_ws_error(err)
-    })
+  /// Reports a reconnect storm to the handler and waits for its backoff, or gives up.
+  fn check_reconnect_storm(&mut self) -> Result<(), TerminateOrReconnect> {
+    let now = self.clock.now();
+    let storm = match self.reconnect_guard.as_mut().and_then(|guard| guard.on_attempt(now)) {
+      Some(storm) => storm,
+      None => return Ok(()),
+    };
+    self.handler.on_reconnect_storm(&storm).map_err(|_| TerminateOrReconnect::Terminal)?;
+    if storm.terminal {
+      tracing::error!(
+        target: WEBSOCKET_WORKER_ID,
+        "Giving up after {} connection attempts during a reconnect storm, {} within the window.",
+        storm.attempts, storm.recent_attempts
+      );
+      return Err(self.terminate(ClientExitReason::ReconnectStorm));
+    }
+    tracing::warn!(
+      target: WEBSOCKET_WORKER_ID,
+      "Reconnect storm, {} connection attempts within the window, waiting {:?} before the next one.",
+      storm.recent_attempts, storm.backoff
+    );
+    self.clock.sleep(storm.backoff);
+    Ok(())
   }
 
-  fn wait_until_initial_connection(&mut self) -> Result<(), TerminateOrReconnect> {
-    let started = Instant::now();
-    loop {
-      match self.receiver.try_recv() {
-        Ok(msg) => {
-          match msg {
-            WebSocketWorkerMessages::Subscribe { product_ids, channels } => {
-              log::info!("Got subscribe message: product_ids: {:?} | channels: {:?}", &product_ids, &channels);
-              self.append_subscriptions(&product_ids, &channels);
-              return self.connect()
-                .and_then(|_| self.subscribe());
-            }
-            WebSocketWorkerMessages::Unsubscribe { product_ids: _, channels: _ } => {
-              log::warn!(target: WEBSOCKET_WORKER_ID, "Got unsubscribe message, but no initial connection was establish.");
-              continue;
-            }
-            WebSocketWorkerMessages::Stop => {
-              log::warn!("Got stop message before initial connection was established");
-              // Exit but gracefully
-              return Ok(());
-            }
-          }
-        }
-        Err(err) => {
-          match err {
-            TryRecvError::Empty => {
-              // Just wait
-              if started.elapsed() > Duration::from_secs(15) {
-                log::warn!(target: WEBSOCKET_WORKER_ID, "Haven't subscribed for {} seconds.", started.elapsed().as_secs());
-              }
-              log::debug!(target: WEBSOCKET_WORKER_ID, "Sleeping 1 second");
-              thread::sleep(Duration::from_secs(1));
-            }
-            TryRecvError::Disconnected => {
-              log::error!(target: WEBSOCKET_WORKER_ID, "Communication channel closed. This is illegal ");
-              return Err(TerminateOrReconnect::Terminal);
-            }
-          }
+  /// Subscribes to the complete subscription set, used on (re)connect.
+  fn subscribe(&mut self) -> Result<(), TerminateOrReconnect> {
+    self.subscribe_to(self.subscriptions.channels())
+  }
+
+  fn subscribe_to(&mut self, channels: Vec<Channel>) -> Result<(), TerminateOrReconnect> {
+    if channels.is_empty() {
+      tracing::debug!(target: WEBSOCKET_WORKER_ID, "Nothing to subscribe to.");
+      return Ok(());
+    }
+    match self.max_subscribe_payload {
+      Some(max_payload) => {
+        self.pending_chunks.extend(SubscribeRequest::chunked(channels, max_payload));
+        if self.chunk_sent_at.is_none() {
+          self.send_next_chunk()
+        } else {
+          Ok(())
         }
       }
+      None => self.send_request(
+        RequestMessages::Subscribe { req: SubscribeRequest::new(Vec::new(), channels) }
+      ),
     }
   }
 
-  fn consume_socket(&mut self) -> Result<(), TerminateOrReconnect> {
-    // UNWRAP if we ever get here and socket is empty then this should panic
-    // because that is an illegal state.
-    let socket = self.opt_socket.as_mut().unwrap();
-    match socket.read_message() {
-      Ok(msg) => self.handle_ws_message(msg),
-      Err(err) => {
-        log::warn!(target: WEBSOCKET_WORKER_ID, "Got web socket error while consuming web socket message");
-        handle_ws_error(err)
+  /// Subscribes products that came online and match a wildcard subscription.
+  fn subscribe_matching(&mut self, changes: &[ProductStatusChange]) -> Result<(), TerminateOrReconnect> {
+    let online: Vec<&str> = changes.iter()
+      .filter(|change| change.current.as_ref().is_some_and(|state| state.status == ProductStatus::Online))
+      .map(|change| change.product_id.as_str())
+      .collect();
+    let mut added = Vec::new();
+    for subscription in self.wildcards.iter() {
+      let product_ids: Vec<String> = online.iter().filter(|product_id| subscription.matches(product_id)).map(|id| id.to_string()).collect();
+      if !product_ids.is_empty() {
+        added.extend(self.subscriptions.add(&product_ids, &subscription.channels));
+      }
+    }
+    if added.is_empty() {
+      return Ok(());
+    }
+    tracing::info!(target: WEBSOCKET_WORKER_ID, "Subscribing products matching wildcards: {:?}", added);
+    self.subscribe_to(added)
+  }
+
+  fn send_next_chunk(&mut self) -> Result<(), TerminateOrReconnect> {
+    match self.pending_chunks.pop_front() {
+      Some(req) => {
+        self.chunk_sent_at = Some(self.clock.now());
+        self.send_request(RequestMessages::Subscribe { req })
+      }
+      None => {
+        self.chunk_sent_at = None;
+        Ok(())
+      }
+    }
+  }
+
+  fn unsubscribe_from(&mut self, channels: Vec<Channel>) -> Result<(), TerminateOrReconnect> {
+    if channels.is_empty() {
+      tracing::debug!(target: WEBSOCKET_WORKER_ID, "Nothing to unsubscribe from.");
+      return Ok(());
+    }
+    self.send_request(
+      RequestMessages::Unsubscribe { req: UnsubscribeRequest::unsubscribe_from_channels(channels) }
+    )
+  }
+
+  /// Unsubscribes from everything and dispatches messages until the server confirms it with
+  /// an empty subscriptions message, or the acknowledgement timeout (or stop deadline) passes.
+  /// The subscription set is kept, the next `start` subscribes to it again.
+  fn unsubscribe_all(&mut self) {
+    if self.opt_socket.is_none() || self.subscriptions.is_empty() {
+      return;
+    }
+    if self.unsubscribe_from(self.subscriptions.channels()).is_err() {
+      return;
+    }
+
+    let mut ack_deadline = self.clock.now() + UNSUBSCRIBE_ACK_TIMEOUT;
+    if let Some(stop_deadline) = self.stop_deadline {
+      ack_deadline = ack_deadline.min(stop_deadline);
+    }
+    loop {
+      let remaining = match ack_deadline.checked_duration_since(self.clock.now()) {
+        Some(remaining) if remaining > Duration::from_millis(0) => remaining,
+        _ => {
+          tracing::warn!(target: WEBSOCKET_WORKER_ID, "Unsubscribe was not acknowledged in time.");
+          return;
+        }
+      };
+      let socket = self.opt_socket.as_mut().unwrap();
+      set_read_timeout(socket, Some(remaining));
+      let message = match socket.read_message() {
+        Ok(message) => message,
+        Err(err) => {
+          tracing::debug!(target: WEBSOCKET_WORKER_ID, "Stopped waiting for unsubscribe acknowledgement: {:?}", err);
+          return;
+        }
+      };
+      let is_ack = match &message {
+        Message::Text(json) => matches!(
+          serde_json::from_str(json.as_str()),
+          Ok(response::ResponseMessages::Subscriptions { resp }) if resp.channels.is_empty()
+        ),
+        _ => false,
+      };
+      let received_at = self.clock.now();
+      if self.handle_ws_message(message, received_at).is_err() || is_ack {
+        return;
+      }
+    }
+  }
+
+  fn check_stale_products(&mut self) -> Result<(), TerminateOrReconnect> {
+    let (now, wall_clock) = (self.clock.now(), self.clock.utc_now());
+    let (stale, policy) = match self.stale_monitor.as_mut() {
+      Some((monitor, policy)) => (monitor.stale(&self.subscriptions, now, wall_clock), *policy),
+      None => return Ok(()),
+    };
+    for (product_id, last_seen) in stale {
+      tracing::warn!(target: WEBSOCKET_WORKER_ID, product_id = product_id.as_str(), "Product is stale, last message at {}.", last_seen);
+      self.handler.on_product_stale(&product_id, last_seen).map_err(|_| TerminateOrReconnect::Terminal)?;
+      if policy == StalePolicy::Notify {
+        continue;
+      }
+      let channels = self.subscriptions.channels_of(&product_id);
+      let removed = self.subscriptions.remove(&[], &channels);
+      self.unsubscribe_from(removed)?;
+      if policy == StalePolicy::Resubscribe {
+        let added = self.subscriptions.add(&[], &channels);
+        self.subscribe_to(added)?;
+        if let Some((monitor, _)) = self.stale_monitor.as_mut() {
+          monitor.reset(&product_id, now, wall_clock);
+        }
+      }
+    }
+    Ok(())
+  }
+
+  fn ping(&mut self) -> Result<(), TerminateOrReconnect> {
+    let ping_id = self.next_ping_id;
+    self.next_ping_id += 1;
+    self.pings.insert(ping_id, self.clock.now());
+    let socket = self.opt_socket.as_mut().unwrap();
+    socket.write_message(Message::Ping(ping_id.to_be_bytes().to_vec())).or_else(|err| {
+      tracing::debug!(target: WEBSOCKET_WORKER_ID, "Got error while sending ping message ");
+      self.handle_ws_error(err)
+    })
+  }
+
+  fn handle_pong(&mut self, payload: Vec<u8>) -> Result<(), TerminateOrReconnect> {
+    let mut ping_id = [0u8; 8];
+    if payload.len() != ping_id.len() {
+      tracing::debug!(target: WEBSOCKET_WORKER_ID, "Got unsolicited pong.");
+      return Ok(());
+    }
+    ping_id.copy_from_slice(&payload);
+    match self.pings.remove(&u64::from_be_bytes(ping_id)) {
+      Some(sent_at) => self.handler.on_pong(self.clock.now().duration_since(sent_at))
+        .map_err(|_| TerminateOrReconnect::Terminal),
+      None => Ok(())
+    }
+  }
+
+  fn send_request(&mut self, mut request: RequestMessages) -> Result<(), TerminateOrReconnect> {
+    // Signed right before sending, the server rejects signatures older than 30 seconds.
+    if let (RequestMessages::Subscribe { req }, Some(profile)) = (&mut request, &self.profile) {
+      req.auth = Some(profile.credentials.sign(VERIFY_METHOD, VERIFY_PATH, ""));
+    }
+    let socket = self.opt_socket.as_mut().unwrap();
+    let json_msg = request.to_json();
+    socket.write_message(Message::text(json_msg)).or_else(|err| {
+      tracing::debug!(target: WEBSOCKET_WORKER_ID, "Got error while sending subscribe message ");
+      self.handle_ws_error(err)
+    })
+  }
+
+  fn wait_until_initial_connection(&mut self) -> Result<(), TerminateOrReconnect> {
+    let started = self.clock.now();
+    loop {
+      match self.receiver.recv_timeout(Duration::from_secs(1)) {
+        Ok(msg) => {
+          match msg {
+            WebSocketWorkerMessages::Subscribe { product_ids, channels } => {
+              tracing::info!("Got subscribe message: product_ids: {:?} | channels: {:?}", &product_ids, &channels);
+              self.subscriptions.add(&product_ids, &channels);
+              return self.connect()
+                .and_then(|_| self.subscribe());
+            }
+            WebSocketWorkerMessages::Unsubscribe { product_ids: _, channels: _ } => {
+              tracing::warn!(target: WEBSOCKET_WORKER_ID, "Got unsubscribe message, but no initial connection was establish.");
+              continue;
+            }
+            WebSocketWorkerMessages::Watch { subscription } => {
+              self.wildcards.push(subscription);
+              continue;
+            }
+            WebSocketWorkerMessages::AddHandler { id, handler, .. } => {
+              // Handler is initialized together with the main handler once connected.
+              self.handler.insert_handler(id, handler);
+              continue;
+            }
+            WebSocketWorkerMessages::RemoveHandler { id } => {
+              self.handler.remove_handler(id);
+              continue;
+            }
+            WebSocketWorkerMessages::Ping | WebSocketWorkerMessages::Reconnect | WebSocketWorkerMessages::ReplaySnapshots => {
+              tracing::warn!(target: WEBSOCKET_WORKER_ID, "Got connection command, but no initial connection was establish.");
+              continue;
+            }
+            WebSocketWorkerMessages::Stop { .. } => {
+              tracing::warn!("Got stop message before initial connection was established");
+              // Nothing was initialized yet, so there is nothing to drain or close.
+              return Err(self.terminate(ClientExitReason::Stopped));
+            }
+          }
+        }
+        Err(err) => {
+          match err {
+            RecvTimeoutError::Timeout => {
+              // Just wait
+              let waiting = self.clock.now().duration_since(started);
+              if waiting > Duration::from_secs(15) {
+                tracing::warn!(target: WEBSOCKET_WORKER_ID, "Haven't subscribed for {} seconds.", waiting.as_secs());
+              }
+            }
+            RecvTimeoutError::Disconnected => {
+              tracing::error!(target: WEBSOCKET_WORKER_ID, "Communication channel closed. This is illegal ");
+              return Err(self.terminate(ClientExitReason::Stopped));
+            }
+          }
+        }
+      }
+    }
+  }
+
+  /// Downloads trades newer than the last seen trade for every tracked product and delivers
+  /// them to the handler in order. REST errors are logged and the product is skipped since
+  /// live data should not be held back because of the backfill.
+  fn backfill_missed_trades(&mut self) -> Result<(), TerminateOrReconnect> {
+    let last_trade_ids: Vec<(String, i64)> = self.last_trade_ids.iter()
+      .map(|(product_id, trade_id)| (product_id.clone(), *trade_id))
+      .collect();
+
+    for (product_id, last_trade_id) in last_trade_ids {
+      let mut missed = Vec::new();
+      let mut complete = true;
+      for trade in self.rest_client.trade_history(product_id.as_str(), None) {
+        match trade {
+          Ok(trade) if trade.trade_id <= last_trade_id => break,
+          Ok(_) if missed.len() >= self.max_backfilled_trades => {
+            complete = false;
+            break;
+          }
+          Ok(trade) => missed.push(trade),
+          Err(err) => {
+            tracing::warn!(target: WEBSOCKET_WORKER_ID, "Could not backfill trades for {}: {}", product_id, err);
+            complete = false;
+            break;
+          }
+        }
+      }
+      tracing::info!(target: WEBSOCKET_WORKER_ID, "Backfilling {} missed trades for {}", missed.len(), product_id);
+
+      // Trades between the last delivered one and the oldest downloaded one are left out.
+      match missed.last() {
+        Some(oldest) if !complete && oldest.trade_id > last_trade_id + 1 => {
+          let (from_trade_id, to_trade_id) = (last_trade_id + 1, oldest.trade_id - 1);
+          tracing::warn!(target: WEBSOCKET_WORKER_ID, "Missed trades {}..={} of {} were not backfilled", from_trade_id, to_trade_id, product_id);
+          self.handler.on_missed_trades(product_id.as_str(), from_trade_id, to_trade_id)
+            .map_err(|_| TerminateOrReconnect::Terminal)?;
+        }
+        _ => {}
+      }
+
+      for trade in missed.iter().rev() {
+        self.handler.on_backfilled_trade(product_id.as_str(), trade)
+          .map_err(|_| TerminateOrReconnect::Terminal)?;
+        self.last_trade_ids.insert(product_id.clone(), trade.trade_id);
+      }
+    }
+    Ok(())
+  }
+
+  fn trade_seen(&mut self, product_id: &str, trade_id: i64) {
+    if let Some((detector, _)) = self.trade_gaps.as_mut() {
+      detector.on_trade(product_id, trade_id);
+    }
+  }
+
+  /// Notifies the handler about the anomalies of a message, returns whether the message should
+  /// still be delivered.
+  fn report_anomalies(&mut self, anomalies: Vec<DataAnomaly>) -> Result<bool, TerminateOrReconnect> {
+    for anomaly in anomalies.iter() {
+      tracing::warn!(target: WEBSOCKET_WORKER_ID, "Anomaly in {} message of {}: {:?}", anomaly.message_type, anomaly.product_id, anomaly.kind);
+      self.handler.on_data_anomaly(anomaly).map_err(|_| TerminateOrReconnect::Terminal)?;
+    }
+    Ok(anomalies.is_empty() || !matches!(self.anomaly_detector, Some((_, AnomalyPolicy::Drop))))
+  }
+
+  /// Reports trades that the heartbeat says were made but never arrived, and downloads them
+  /// when backfill is enabled. Only products subscribed to trades are checked.
+  fn check_trade_gap(&mut self, product_id: &str, last_trade_id: i64) -> Result<(), TerminateOrReconnect> {
+    let subscribed_to_trades = self.subscriptions.channels_of(product_id).iter()
+      .any(|channel| matches!(channel.name(), Channels::Matches | Channels::Full));
+    let (gap, backfill) = match self.trade_gaps.as_mut() {
+      Some((detector, backfill)) if subscribed_to_trades => (detector.on_heartbeat(product_id, last_trade_id), *backfill),
+      _ => return Ok(()),
+    };
+    let (from_trade_id, to_trade_id) = match gap {
+      Some(gap) => gap,
+      None => return Ok(()),
+    };
+    tracing::warn!(target: WEBSOCKET_WORKER_ID, "Missed trades {}..={} of {}", from_trade_id, to_trade_id, product_id);
+    self.handler.on_missed_trades(product_id, from_trade_id, to_trade_id)
+      .map_err(|_| TerminateOrReconnect::Terminal)?;
+    if !backfill {
+      return Ok(());
+    }
+
+    let mut missed = Vec::new();
+    for trade in self.rest_client.trade_history(product_id, Some(to_trade_id + 1)) {
+      match trade {
+        Ok(trade) if trade.trade_id >= from_trade_id => missed.push(trade),
+        Ok(_) => break,
+        Err(err) => {
+          tracing::warn!(target: WEBSOCKET_WORKER_ID, "Could not backfill trades for {}: {}", product_id, err);
+          break;
+        }
+      }
+    }
+    for trade in missed.iter().rev() {
+      self.handler.on_backfilled_trade(product_id, trade)
+        .map_err(|_| TerminateOrReconnect::Terminal)?;
+    }
+    // Keeps the reconnect backfill from downloading the same trades again.
+    if let Some(latest) = missed.first() {
+      self.record_trade(product_id, latest.trade_id);
+    }
+    Ok(())
+  }
+
+  fn add_handler(
+    &mut self,
+    id: HandlerId,
+    mut handler: Box<dyn CoinBaseWebSocketMessageHandler + Send>,
+    replay: bool,
+  ) -> Result<(), TerminateOrReconnect> {
+    if handler.initialize().is_err() {
+      tracing::warn!(target: WEBSOCKET_WORKER_ID, "Handler {:?} refused to initialize, it won't be added.", id);
+      return Ok(());
+    }
+    if replay {
+      match &self.snapshot_cache {
+        Some(cache) => {
+          if cache.replay(&mut handler).is_err() {
+            tracing::warn!(target: WEBSOCKET_WORKER_ID, "Handler {:?} terminated during snapshot replay.", id);
+            return Ok(());
+          }
+        }
+        None => tracing::warn!(target: WEBSOCKET_WORKER_ID, "Snapshot replay requested, but snapshot cache is disabled."),
+      }
+    }
+    tracing::info!(target: WEBSOCKET_WORKER_ID, "Added handler {:?}", id);
+    self.handler.insert_handler(id, handler);
+    Ok(())
+  }
+
+  fn remove_handler(&mut self, id: HandlerId) {
+    match self.handler.remove_handler(id) {
+      Some(mut handler) => {
+        let _ = handler.close();
+        tracing::info!(target: WEBSOCKET_WORKER_ID, "Removed handler {:?}", id);
+      }
+      None => tracing::warn!(target: WEBSOCKET_WORKER_ID, "Handler {:?} is not registered.", id),
+    }
+  }
+
+  fn replay_snapshots(&mut self) -> Result<(), TerminateOrReconnect> {
+    match &self.snapshot_cache {
+      Some(cache) => cache.replay(&mut self.handler).map_err(|_| TerminateOrReconnect::Terminal),
+      None => {
+        tracing::warn!(target: WEBSOCKET_WORKER_ID, "Snapshot replay requested, but snapshot cache is disabled.");
+        Ok(())
+      }
+    }
+  }
+
+  /// Records the trade and returns false if it was already seen (e.g. delivered by the backfill).
+  fn record_trade(&mut self, product_id: &str, trade_id: i64) -> bool {
+    if !self.backfill_trades {
+      return true;
+    }
+    match self.last_trade_ids.get_mut(product_id) {
+      Some(last_trade_id) if *last_trade_id >= trade_id => false,
+      Some(last_trade_id) => {
+        *last_trade_id = trade_id;
+        true
+      }
+      None => {
+        self.last_trade_ids.insert(product_id.into(), trade_id);
+        true
+      }
+    }
+  }
+
+  fn consume_socket(&mut self) -> Result<(), TerminateOrReconnect> {
+    // UNWRAP if we ever get here and socket is empty then this should panic
+    // because that is an illegal state.
+    let socket = self.opt_socket.as_mut().unwrap();
+    match socket.read_message() {
+      Ok(msg) => {
+        let received_at = self.clock.now();
+        match panic::catch_unwind(AssertUnwindSafe(|| self.handle_ws_message(msg, received_at))) {
+          Ok(result) => result,
+          Err(payload) => self.handle_panic(payload),
+        }
+      }
+      Err(err) => {
+        tracing::warn!(target: WEBSOCKET_WORKER_ID, "Got web socket error while consuming web socket message");
+        self.handle_ws_error(err)
       }
     }
   }
 
-  fn handle_ws_message(&mut self, message: Message) -> Result<(), TerminateOrReconnect> {
+  fn handle_panic(&mut self, payload: Box<dyn Any + Send>) -> Result<(), TerminateOrReconnect> {
+    match self.panic_policy {
+      PanicPolicy::Continue => {
+        tracing::error!(target: WEBSOCKET_WORKER_ID, "Handler panicked, skipping message: {}", panic_message(&payload));
+        Ok(())
+      }
+      PanicPolicy::Terminate => panic::resume_unwind(payload),
+    }
+  }
+
+  fn handle_ws_error(&mut self, error: tungstenite::Error) -> Result<(), TerminateOrReconnect> {
+    match error {
+      tungstenite::Error::ConnectionClosed => {
+        // Connection was closed normally, meaning that
+        // this is an illegal state and it is ok to panic here
+        tracing::warn!(target: WEBSOCKET_WORKER_ID, "Trying to work with web-socket after connection was manually closed.");
+        Err(self.terminate(ClientExitReason::ConnectionFailed))
+      }
+      tungstenite::Error::AlreadyClosed => {
+        // Connection was closed for some reason and we need to try to reconnect.
+        tracing::warn!(target: WEBSOCKET_WORKER_ID, "WebSocket connection is closed for unknown reason.");
+        Err(TerminateOrReconnect::Reconnect)
+      }
+      tungstenite::Error::Io(error) => {
+        // Once io error happen we can choose to either reconnect or try to reconnect.
+        // Here we choose to just drop current connection and try to reconnect since
+        // IO errors are produced for a lot of different reasons some of which might pass
+        // after some time (e.g. timeout due to large load or missing network connection).
+        tracing::warn!(target: WEBSOCKET_WORKER_ID, "Got an IO error, {:?}. ", error);
+        Err(TerminateOrReconnect::Reconnect)
+      }
+      error => {
+        // Just log errors and ignore.
+        tracing::warn!(target: WEBSOCKET_WORKER_ID, "Got error: {:?}", error);
+        Ok(())
+      }
+    }
+  }
+
+  fn handle_ws_message(&mut self, message: Message, received_at: Instant) -> Result<(), TerminateOrReconnect> {
     match message {
       Message::Text(json) => {
-        log::debug!(target: WEBSOCKET_WORKER_ID, "{}", json);
+        tracing::debug!(target: WEBSOCKET_WORKER_ID, "{}", json);
+        let ctx = MessageContext {
+          received_at,
+          wall_clock: self.clock.utc_now(),
+          connection_id: self.connection_id,
+          raw_len: json.len(),
+        };
+        self.handler.on_message_context(&ctx).map_err(|_| TerminateOrReconnect::Terminal)?;
+        if let Some((monitor, _)) = self.stale_monitor.as_mut() {
+          if string_field(&json, "type") != Some("heartbeat") {
+            if let Some(product_id) = string_field(&json, "product_id") {
+              monitor.seen(product_id, received_at, ctx.wall_clock);
+            }
+          }
+        }
         return self.handle_message(json);
       }
       Message::Close(opt_close_frame) => {
-        log::info!(target: WEBSOCKET_WORKER_ID, "Got WebSocket::Close message from stream.");
-        if opt_close_frame.is_some() {
-          let close_frame = opt_close_frame.unwrap();
-          log::info!(target: WEBSOCKET_WORKER_ID, "Close code: {} | Close reason: {}", close_frame.code, close_frame.reason);
+        tracing::info!(target: WEBSOCKET_WORKER_ID, "Got WebSocket::Close message from stream.");
+        if let Some(close_frame) = opt_close_frame {
+          tracing::info!(target: WEBSOCKET_WORKER_ID, "Close code: {} | Close reason: {}", close_frame.code, close_frame.reason);
         }
         return Err(TerminateOrReconnect::Reconnect);
       }
-      Message::Ping(_) => log::debug!(target: WEBSOCKET_WORKER_ID, "WebSocket::Ping"),
-      Message::Pong(_) => log::debug!(target: WEBSOCKET_WORKER_ID, "WebSocket::Pong"),
-      Message::Binary(_) => log::warn!(target: WEBSOCKET_WORKER_ID, "WebSocket binary message received?")
+      Message::Ping(_) => tracing::debug!(target: WEBSOCKET_WORKER_ID, "WebSocket::Ping"),
+      Message::Pong(payload) => {
+        tracing::debug!(target: WEBSOCKET_WORKER_ID, "WebSocket::Pong");
+        return self.handle_pong(payload);
+      }
+      Message::Binary(_) => tracing::warn!(target: WEBSOCKET_WORKER_ID, "WebSocket binary message received?")
     };
     Ok(())
   }
 
   fn handle_message(&mut self, json_msg: String) -> Result<(), TerminateOrReconnect> {
-    let response_result = serde_json::from_str(json_msg.as_str());
-    if response_result.is_err() {
-      log::warn!(target: WEBSOCKET_WORKER_ID, "Could not parse following message from the coinbase: \n {}", json_msg);
-      return Ok(()); // Just ignore the message.
-    }
-
-    // @formatter:off
-    match response_result.unwrap() {
-      response::ResponseMessages::Subscriptions { resp } => { self.handler.on_subscriptions(&resp) }
-      response::ResponseMessages::Heartbeat     { resp } => { self.handler.on_heartbeat(&resp)     }
-      response::ResponseMessages::Status        { resp } => { self.handler.on_status(&resp)        }
-      response::ResponseMessages::Ticker        { resp } => { self.handler.on_ticker(&resp)        }
-      response::ResponseMessages::Snapshot      { resp } => { self.handler.on_snapshot(&resp)      }
-      response::ResponseMessages::L2Update      { resp } => { self.handler.on_l2_update(&resp)     }
-      response::ResponseMessages::Match         { resp } => { self.handler.on_match(&resp)         }
-      response::ResponseMessages::Received      { resp } => { self.handler.on_received(&resp)      }
-      response::ResponseMessages::Open          { resp } => { self.handler.on_open(&resp)          }
-      response::ResponseMessages::Change        { resp } => { self.handler.on_change(&resp)        }
-      response::ResponseMessages::Done          { resp } => { self.handler.on_done(&resp)          }
-      response::ResponseMessages::Active        { resp } => { self.handler.on_active(&resp)        }
-      response::ResponseMessages::Last_Match    { resp } => { self.handler.on_last_match(&resp)    }
-      response::ResponseMessages::Error         { resp } => { self.handler.on_error(&resp)         }
-    }.map_err(|_| TerminateOrReconnect::Terminal)
-    // @formatter:on
+    if let Some(filter) = &self.message_filter {
+      if !filter.accepts(json_msg.as_str()) {
+        return Ok(());
+      }
+    }
+
+    if self.two_phase_parsing {
+      // Frames without a readable header are parsed in full below, so errors are reported as usual.
+      if let Ok(header) = serde_json::from_str::<MessageHeader>(json_msg.as_str()) {
+        if !self.needs_message_type(header.kind) {
+          return Ok(());
+        }
+      }
+    }
+
+    decimal::take_replaced();
+    if self.borrowed_messages {
+      // Frames that can't be parsed here are parsed again below so the error is reported as usual.
+      match serde_json::from_str(json_msg.as_str()) {
+        Ok(BorrowedMessages::Other) | Err(_) => {}
+        Ok(msg) => return self.handle_borrowed_message(&msg),
+      }
+      decimal::take_replaced();
+    }
+
+    let response = match response::parse_response(json_msg.as_str()) {
+      Ok(response) => response,
+      Err(err) => {
+        tracing::warn!(target: WEBSOCKET_WORKER_ID, "Could not parse following message from the coinbase: \n {}", json_msg);
+        // Let the handler decide what to do with the malformed frame, otherwise just ignore the message.
+        return self.handler.on_parse_error(json_msg.as_str(), &err)
+          .map_err(|_| TerminateOrReconnect::Terminal);
+      }
+    };
+
+    if let response::ResponseMessages::Subscriptions { .. } = &response {
+      if self.chunk_sent_at.is_some() {
+        self.send_next_chunk()?;
+      }
+    }
+
+    if let Some(deduplicator) = self.deduplicator.as_mut() {
+      if deduplicator.is_duplicate(&response) {
+        tracing::debug!(target: WEBSOCKET_WORKER_ID, "Skipping duplicate {} message.", response.kind());
+        return Ok(());
+      }
+    }
+
+    let mut anomalies = match self.anomaly_detector.as_mut() {
+      Some((detector, _)) => detector.check(&response),
+      None => Vec::new(),
+    };
+    let replaced = decimal::take_replaced();
+    if replaced > 0 {
+      let product_id = response.product_id().unwrap_or_default().into();
+      anomalies.push(DataAnomaly { product_id, message_type: response.kind(), kind: AnomalyKind::MalformedNumbers { replaced } });
+    }
+    if !self.report_anomalies(anomalies)? {
+      return Ok(());
+    }
+
+    let is_new_trade = match &response {
+      response::ResponseMessages::Match      { resp } => self.record_trade(&resp.product_id, resp.trade_id),
+      response::ResponseMessages::Last_Match { resp } => self.record_trade(&resp.product_id, resp.trade_id),
+      _ => true,
+    };
+    if !is_new_trade {
+      tracing::debug!(target: WEBSOCKET_WORKER_ID, "Skipping already delivered trade.");
+      return Ok(());
+    }
+
+    match &response {
+      response::ResponseMessages::Heartbeat  { resp } => self.check_trade_gap(&resp.product_id, resp.last_trade_id)?,
+      response::ResponseMessages::Match      { resp } => self.trade_seen(&resp.product_id, resp.trade_id),
+      response::ResponseMessages::Last_Match { resp } => self.trade_seen(&resp.product_id, resp.trade_id),
+      _ => {}
+    }
+
+    if let Some(cache) = self.snapshot_cache.as_mut() {
+      let _ = match &response {
+        response::ResponseMessages::Status   { resp } => cache.on_status(resp),
+        response::ResponseMessages::Snapshot { resp } => cache.on_snapshot(resp),
+        response::ResponseMessages::L2Update { resp } => cache.on_l2_update(resp),
+        _ => Ok(()),
+      };
+    }
+
+    let span = tracing::debug_span!(
+      target: WEBSOCKET_WORKER_ID, "message",
+      kind = response.kind(), product_id = response.product_id(), sequence = response.sequence()
+    );
+    let _entered = span.enter();
+    let started = self.clock.now();
+    let mut result = dispatch(&mut self.handler, &response).map_err(|_| TerminateOrReconnect::Terminal);
+    if let (Ok(()), response::ResponseMessages::Status { resp }) = (&result, &response) {
+      let changes = self.product_status.update(resp);
+      for change in changes.iter() {
+        result = self.handler.on_product_status_change(change).map_err(|_| TerminateOrReconnect::Terminal);
+        if result.is_err() {
+          break;
+        }
+      }
+      if result.is_ok() {
+        result = self.subscribe_matching(&changes);
+      }
+    }
+    tracing::trace!(target: WEBSOCKET_WORKER_ID, elapsed_us = self.clock.now().duration_since(started).as_micros() as u64, "Message handled.");
+    result
+  }
+
+  /// Message types used by the handlers or by the worker itself (trade backfill, snapshot cache).
+  fn needs_message_type(&self, message_type: &str) -> bool {
+    self.handler.wants_message_type(message_type)
+      || (self.backfill_trades && matches!(message_type, "match" | "last_match"))
+      || (self.snapshot_cache.is_some() && matches!(message_type, "status" | "snapshot" | "l2update"))
+      || (self.chunk_sent_at.is_some() && message_type == "subscriptions")
+      || (self.trade_gaps.is_some() && matches!(message_type, "heartbeat" | "match" | "last_match"))
+      || (!self.wildcards.is_empty() && message_type == "status")
+  }
+
+  fn handle_borrowed_message(&mut self, msg: &BorrowedMessages) -> Result<(), TerminateOrReconnect> {
+    if let Some(deduplicator) = self.deduplicator.as_mut() {
+      if deduplicator.is_borrowed_duplicate(msg) {
+        tracing::debug!(target: WEBSOCKET_WORKER_ID, "Skipping duplicate message.");
+        return Ok(());
+      }
+    }
+
+    let mut anomalies = match self.anomaly_detector.as_mut() {
+      Some((detector, _)) => detector.check_borrowed(msg),
+      None => Vec::new(),
+    };
+    let replaced = decimal::take_replaced();
+    if replaced > 0 {
+      let (message_type, product_id) = match msg {
+        BorrowedMessages::Ticker(resp) => ("ticker", resp.product_id.to_string()),
+        BorrowedMessages::L2Update(resp) => ("l2update", resp.product_id.to_string()),
+        BorrowedMessages::Match(resp) => ("match", resp.product_id.to_string()),
+        BorrowedMessages::Last_Match(resp) => ("last_match", resp.product_id.to_string()),
+        _ => ("other", String::new()),
+      };
+      anomalies.push(DataAnomaly { product_id, message_type, kind: AnomalyKind::MalformedNumbers { replaced } });
+    }
+    if !self.report_anomalies(anomalies)? {
+      return Ok(());
+    }
+
+    let is_new_trade = match msg {
+      BorrowedMessages::Match(resp) | BorrowedMessages::Last_Match(resp) => self.record_trade(&resp.product_id, resp.trade_id),
+      _ => true,
+    };
+    if !is_new_trade {
+      tracing::debug!(target: WEBSOCKET_WORKER_ID, "Skipping already delivered trade.");
+      return Ok(());
+    }
+
+    match msg {
+      BorrowedMessages::Heartbeat(resp) => self.check_trade_gap(&resp.product_id, resp.last_trade_id)?,
+      BorrowedMessages::Match(resp) | BorrowedMessages::Last_Match(resp) => self.trade_seen(&resp.product_id, resp.trade_id),
+      _ => {}
+    }
+
+    if let (Some(cache), BorrowedMessages::L2Update(resp)) = (self.snapshot_cache.as_mut(), msg) {
+      let _ = cache.on_l2_update(&resp.to_response());
+    }
+
+    let (kind, product_id, sequence) = match msg {
+      BorrowedMessages::Heartbeat(resp) => ("heartbeat", Some(&resp.product_id), Some(resp.sequence)),
+      BorrowedMessages::Ticker(resp) => ("ticker", Some(&resp.product_id), Some(resp.sequence)),
+      BorrowedMessages::L2Update(resp) => ("l2update", Some(&resp.product_id), None),
+      BorrowedMessages::Match(resp) => ("match", Some(&resp.product_id), Some(resp.sequence)),
+      BorrowedMessages::Last_Match(resp) => ("last_match", Some(&resp.product_id), Some(resp.sequence)),
+      BorrowedMessages::Other => ("other", None, None),
+    };
+    let span = tracing::debug_span!(
+      target: WEBSOCKET_WORKER_ID, "message",
+      kind, product_id = product_id.map(|id| id.as_ref()), sequence
+    );
+    let _entered = span.enter();
+    let started = self.clock.now();
+    let result = self.handler.on_borrowed(msg).map_err(|_| TerminateOrReconnect::Terminal);
+    tracing::trace!(target: WEBSOCKET_WORKER_ID, elapsed_us = self.clock.now().duration_since(started).as_micros() as u64, "Message handled.");
+    result
+  }
+}
+
+pub(crate) fn panic_message(payload: &Box<dyn Any + Send>) -> &str {
+  if let Some(message) = payload.downcast_ref::<&str>() {
+    message
+  } else if let Some(message) = payload.downcast_ref::<String>() {
+    message.as_str()
+  } else {
+    "unknown panic"
+  }
+}
+
+fn set_read_timeout(socket: &WebSocket<AutoStream>, timeout: Option<Duration>) {
+  let result = match socket.get_ref() {
+    Stream::Plain(stream) => stream.set_read_timeout(timeout),
+    Stream::Tls(stream) => stream.get_ref().set_read_timeout(timeout),
+  };
+  if let Err(err) = result {
+    tracing::debug!(target: WEBSOCKET_WORKER_ID, "Could not set socket read timeout: {}", err);
   }
 }
 
-fn handle_ws_error(error: tungstenite::Error) -> Result<(), TerminateOrReconnect> {
-  match error {
-    tungstenite::Error::ConnectionClosed => {
-      // Connection was closed normally, meaning that
-      // this is an illegal state and it is ok to panic here
-      log::warn!(target: WEBSOCKET_WORKER_ID, "Trying to work with web-socket after connection was manually closed.");
-      Err(TerminateOrReconnect::Terminal)
-    }
-    tungstenite::Error::AlreadyClosed => {
-      // Connection was closed for some reason and we need to try to reconnect.
-      log::warn!(target: WEBSOCKET_WORKER_ID, "WebSocket connection is closed for unknown reason.");
-      Err(TerminateOrReconnect::Reconnect)
-    }
-    tungstenite::Error::Io(error) => {
-      // Once io error happen we can choose to either reconnect or try to reconnect.
-      // Here we choose to just drop current connection and try to reconnect since
-      // IO errors are produced for a lot of different reasons some of which might pass
-      // after some time (e.g. timeout due to large load or missing network connection).
-      log::warn!(target: WEBSOCKET_WORKER_ID, "Got an IO error, {:?}. ", error);
-      Err(TerminateOrReconnect::Reconnect)
-    }
-    error => {
-      // Just log errors and ignore.
-      log::warn!(target: WEBSOCKET_WORKER_ID, "Got error: {:?}", error);
+#[cfg(test)]
+mod test {
+  use std::collections::HashMap;
+  use std::io::{self, BufRead, BufReader, Write};
+  use std::net::{TcpListener, TcpStream};
+  use std::sync::Arc;
+  use std::thread;
+  use std::time::{Duration, Instant};
+
+  use chrono::Utc;
+
+  use crossbeam::{Receiver, Sender, TryRecvError};
+  use tungstenite::{Message, WebSocket};
+
+  use super::{ClientExitReason, CoinbaseWebSocketClient, PanicPolicy, StartError, WebSocketWorkerMessages, STANDBY_KEEPALIVE_INTERVAL, SUBSCRIBE_ACK_TIMEOUT};
+  use crate::rest::{self, CoinbaseRestClient};
+  use crate::web_socket::clock::MockClock;
+  use crate::web_socket::common::{Channel, Channels};
+  use crate::web_socket::context::MessageContext;
+  use crate::web_socket::response::{self, HeartBeatResponse, L2UpdateResponse, SnapshotResponse};
+  use crate::web_socket::{
+    CoinBaseWebSocketMessageHandler, CompositeCoinBaseWebSocketMessageHandler, SubscriptionError, Terminate, ThreadedHandler,
+  };
+
+  // How long tests wait for the worker.
+  const TIMEOUT: Duration = Duration::from_secs(5);
+
+  #[test]
+  fn restart_after_stop() {
+    let mut client = CoinbaseWebSocketClient::sandbox();
+    for _ in 0..2 {
+      client.start(CompositeCoinBaseWebSocketMessageHandler::new(vec![])).unwrap();
+      assert_eq!(client.exit_reason(), None);
+      assert_eq!(client.stop(), ClientExitReason::Stopped);
+      assert_eq!(client.exit_reason(), Some(ClientExitReason::Stopped));
+    }
+  }
+  #[test]
+  fn buffer_subscriptions_before_start() {
+    let client = CoinbaseWebSocketClient::sandbox().pending_subscriptions(1);
+    let controller = client.controller();
+    let subscribe = || WebSocketWorkerMessages::Subscribe {
+      product_ids: vec!["BTC-USD".into()],
+      channels: Channel::from_names(&[Channels::Ticker]),
+    };
+    assert!(controller.send_subscription(subscribe()).is_ok());
+    assert!(matches!(controller.send_subscription(subscribe()), Err(SubscriptionError::PendingFull { capacity: 1 })));
+    assert_eq!(client.pending.lock().unwrap().requests.len(), 1);
+  }
+
+  #[test]
+  fn fail_when_command_queue_is_full() {
+    let client = CoinbaseWebSocketClient::sandbox().command_capacity(1);
+    client.pending.lock().unwrap().running = true;
+    let controller = client.controller();
+    let product_ids = || vec!["BTC-USD".to_string()];
+    let channels = || Channel::from_names(&[Channels::Ticker]);
+    assert!(controller.try_subscribe(product_ids(), channels()).is_ok());
+    assert!(matches!(controller.try_unsubscribe(product_ids(), channels()), Err(SubscriptionError::QueueFull { capacity: 1 })));
+    let timeout = Duration::from_millis(10);
+    assert!(matches!(controller.subscribe_timeout(product_ids(), channels(), timeout), Err(SubscriptionError::QueueFull { capacity: 1 })));
+    assert_eq!(client.receiver.len(), 1);
+  }
+
+  #[test]
+  fn expand_wildcard_subscriptions() {
+    let client = CoinbaseWebSocketClient::sandbox();
+    let products = ["BTC-USD", "ETH-USD", "ETH-EUR", "ETH-BTC"].iter().map(|id| id.to_string()).collect();
+    *client.known_products.lock().unwrap() = Some(products);
+    let controller = client.controller();
+    let product_ids = controller.subscribe_matching(&["*-USD", "quote:EUR"], Channel::from_names(&[Channels::Ticker])).unwrap();
+    assert_eq!(product_ids, vec!["BTC-USD", "ETH-EUR", "ETH-USD"]);
+    assert!(matches!(controller.subscribe_matching(&["quote:"], Vec::new()), Err(SubscriptionError::InvalidPattern { .. })));
+
+    let pending = client.pending.lock().unwrap();
+    match &pending.requests[..] {
+      [WebSocketWorkerMessages::Watch { subscription }, WebSocketWorkerMessages::Subscribe { channels, .. }] => {
+        assert!(subscription.matches("SOL-USD") && !subscription.matches("SOL-GBP"));
+        assert!(channels.contains(&Channel::new(Channels::Status)));
+      }
+      _ => panic!("Expected watch and subscribe requests"),
+    }
+  }
+
+  #[test]
+  fn resubscribe_after_unsubscribe_on_stop() {
+    let feed = MockFeed::bind();
+    let mut client = feed.client().unsubscribe_on_stop(true);
+    client.controller().subscribe(vec!["BTC-USD".into()], Channel::from_names(&[Channels::Heartbeat]));
+    let subscribe = serde_json::json!({"type": "subscribe", "product_ids": [], "channels": [{"name": "heartbeat", "product_ids": ["BTC-USD"]}]});
+    for _ in 0..2 {
+      client.start(CompositeCoinBaseWebSocketMessageHandler::new(vec![])).unwrap();
+      let connection = feed.accept();
+      assert_eq!(connection.request(), subscribe);
+      let acknowledged = thread::spawn(move || {
+        let request = connection.request();
+        connection.send(r#"{"type":"subscriptions","channels":[]}"#);
+        request
+      });
+      assert_eq!(client.stop(), ClientExitReason::Stopped);
+      assert_eq!(acknowledged.join().unwrap()["type"], "unsubscribe");
+    }
+  }
+
+  #[test]
+  fn time_out_subscribe_chunks_with_the_clock() {
+    let feed = MockFeed::bind();
+    let clock = MockClock::new(Utc::now());
+    let mut client = feed.client().max_subscribe_payload(1).clock(Arc::new(clock.clone()));
+    client.controller().subscribe(vec!["BTC-USD".into(), "ETH-USD".into()], Channel::from_names(&[Channels::Heartbeat]));
+    client.start(CompositeCoinBaseWebSocketMessageHandler::new(vec![])).unwrap();
+    let connection = feed.accept();
+    assert_eq!(connection.request()["channels"], serde_json::json!([{"name": "heartbeat", "product_ids": ["BTC-USD"]}]));
+
+    // Never acknowledged, the next chunk is sent once the clock passes the timeout.
+    assert!(connection.requests.recv_timeout(Duration::from_millis(50)).is_err());
+    clock.advance(SUBSCRIBE_ACK_TIMEOUT);
+    assert_eq!(connection.request()["channels"], serde_json::json!([{"name": "heartbeat", "product_ids": ["ETH-USD"]}]));
+    assert_eq!(client.stop(), ClientExitReason::Stopped);
+  }
+
+  #[test]
+  fn switch_to_standby_connection() {
+    let feed = MockFeed::bind();
+    let clock = MockClock::new(Utc::now());
+    let mut client = feed.client().warm_standby(true).clock(Arc::new(clock.clone()));
+    client.controller().subscribe(vec!["BTC-USD".into()], Channel::from_names(&[Channels::Heartbeat]));
+    client.start(CompositeCoinBaseWebSocketMessageHandler::new(vec![])).unwrap();
+    let connection = feed.accept();
+    let subscribe = connection.request();
+    let standby = feed.accept();
+    // Live standby is pinged, not replaced.
+    clock.advance(STANDBY_KEEPALIVE_INTERVAL);
+    assert!(feed.connections.recv_timeout(Duration::from_millis(50)).is_err());
+
+    drop(connection);
+    assert_eq!(standby.request(), subscribe);
+    // Next standby is opened right away and gets no subscriptions.
+    let next_standby = feed.accept();
+    assert!(next_standby.requests.recv_timeout(Duration::from_millis(50)).is_err());
+    assert_eq!(client.stop(), ClientExitReason::Stopped);
+  }
+
+  #[test]
+  fn report_unparsable_frames_with_raw_payload() {
+    let feed = MockFeed::bind();
+    let mut client = feed.client();
+    client.controller().subscribe(vec!["BTC-USD".into()], Channel::from_names(&[Channels::Heartbeat]));
+    let (handler, events) = Events::new();
+    client.start(handler).unwrap();
+    let connection = feed.accept();
+    connection.request();
+
+    let frame = r#"{"type":"heartbeat","product_id":"BTC-USD","sequence":"one"}"#;
+    connection.send(frame);
+    connection.send(&heartbeat("BTC-USD", 2));
+    let err = response::parse_response(frame).unwrap_err();
+    assert_eq!(next_event(&events), "initialize");
+    assert_eq!(next_event(&events), format!("parse_error {} | {}", frame, err));
+    // The malformed frame is skipped and the feed goes on.
+    assert_eq!(next_event(&events), "heartbeat BTC-USD 2");
+    assert_eq!(client.stop(), ClientExitReason::Stopped);
+    assert_eq!(next_event(&events), "close");
+  }
+
+  #[test]
+  fn report_trades_left_out_of_backfill() {
+    let feed = MockFeed::bind();
+    let rest_client = CoinbaseRestClient::new(&trade_history(400));
+    let last_trade_ids = vec![("BTC-USD".to_string(), 100)].into_iter().collect::<HashMap<_, _>>();
+    let mut client = CoinbaseWebSocketClient::new(&feed.url, rest_client)
+      .resume_trades(last_trade_ids)
+      .max_backfilled_trades(150);
+    client.controller().subscribe(vec!["BTC-USD".into()], Channel::from_names(&[Channels::Matches]));
+    let (handler, events) = Events::new();
+    client.start(handler).unwrap();
+    let _connection = feed.accept();
+
+    assert_eq!(next_event(&events), "initialize");
+    // Only the newest trades are downloaded, the rest of the gap is reported.
+    assert_eq!(next_event(&events), "missed_trades BTC-USD 101 250");
+    for trade_id in 251..=400 {
+      assert_eq!(next_event(&events), format!("backfilled BTC-USD {}", trade_id));
+    }
+    assert_eq!(client.stop(), ClientExitReason::Stopped);
+    assert_eq!(next_event(&events), "close");
+  }
+
+  #[test]
+  fn ping_and_force_reconnect() {
+    let feed = MockFeed::bind();
+    let mut client = feed.client();
+    let controller = client.controller();
+    controller.subscribe(vec!["BTC-USD".into()], Channel::from_names(&[Channels::Heartbeat]));
+    let (handler, events) = Events::new();
+    client.start(handler).unwrap();
+    let connection = feed.accept();
+    let subscribe = connection.request();
+    assert_eq!(next_event(&events), "initialize");
+
+    controller.ping();
+    assert_eq!(next_event(&events), "pong");
+    // Subscriptions are restored on the new connection.
+    controller.reconnect();
+    let reconnected = feed.accept();
+    assert_eq!(reconnected.request(), subscribe);
+    reconnected.send(&heartbeat("BTC-USD", 1));
+    assert_eq!(next_event(&events), "heartbeat BTC-USD 1");
+    assert_eq!(client.stop(), ClientExitReason::Stopped);
+  }
+
+  #[test]
+  fn keep_products_of_each_channel() {
+    let feed = MockFeed::bind();
+    let mut client = feed.client();
+    let controller = client.controller();
+    let channels = vec![
+      Channel::with_product_ids(Channels::Ticker, vec!["BTC-USD".into()]),
+      Channel::with_product_ids(Channels::Level2, vec!["BTC-USD".into(), "ETH-USD".into()]),
+    ];
+    controller.subscribe(Vec::new(), channels);
+    client.start(CompositeCoinBaseWebSocketMessageHandler::new(vec![])).unwrap();
+    let connection = feed.accept();
+    assert_eq!(connection.request()["channels"], serde_json::json!([
+      {"name": "ticker", "product_ids": ["BTC-USD"]},
+      {"name": "level2", "product_ids": ["BTC-USD", "ETH-USD"]},
+    ]));
+
+    controller.unsubscribe(Vec::new(), vec![Channel::with_product_ids(Channels::Level2, vec!["BTC-USD".into()])]);
+    assert_eq!(connection.request()["channels"], serde_json::json!([{"name": "level2", "product_ids": ["BTC-USD"]}]));
+    // Other channels keep their products after a reconnect.
+    controller.reconnect();
+    let reconnected = feed.accept();
+    assert_eq!(reconnected.request()["channels"], serde_json::json!([
+      {"name": "ticker", "product_ids": ["BTC-USD"]},
+      {"name": "level2", "product_ids": ["ETH-USD"]},
+    ]));
+    assert_eq!(client.stop(), ClientExitReason::Stopped);
+  }
+
+  #[test]
+  fn pass_context_of_every_frame() {
+    let feed = MockFeed::bind();
+    let start = Utc::now();
+    let clock = MockClock::new(start);
+    let mut client = feed.client().clock(Arc::new(clock));
+    let controller = client.controller();
+    controller.subscribe(vec!["BTC-USD".into()], Channel::from_names(&[Channels::Heartbeat]));
+    let (sender, contexts) = crossbeam::unbounded();
+    client.start(Contexts(sender)).unwrap();
+    let connection = feed.accept();
+    connection.request();
+
+    let frame = heartbeat("BTC-USD", 1);
+    connection.send(&frame);
+    let ctx = contexts.recv_timeout(TIMEOUT).unwrap();
+    assert_eq!((ctx.wall_clock, ctx.connection_id, ctx.raw_len), (start, 1, frame.len()));
+    // Frames of the next connection are told apart by its id.
+    controller.reconnect();
+    let reconnected = feed.accept();
+    reconnected.request();
+    reconnected.send(&frame);
+    assert_eq!(contexts.recv_timeout(TIMEOUT).unwrap().connection_id, 2);
+    assert_eq!(client.stop(), ClientExitReason::Stopped);
+  }
+
+  #[test]
+  fn replay_cached_books_to_late_handlers() {
+    let feed = MockFeed::bind();
+    let mut client = feed.client().cache_snapshots(true);
+    let controller = client.controller();
+    controller.subscribe(vec!["BTC-USD".into()], Channel::from_names(&[Channels::Level2]));
+    let (handler, events) = Events::new();
+    client.start(handler).unwrap();
+    let connection = feed.accept();
+    connection.request();
+    connection.send(r#"{"type":"snapshot","product_id":"BTC-USD","bids":[["100","1"]],"asks":[["101","2"]]}"#);
+    connection.send(r#"{"type":"l2update","product_id":"BTC-USD","time":"2020-08-31T15:00:00Z","changes":[["buy","100","3"]]}"#);
+    assert_eq!(next_event(&events), "initialize");
+    assert_eq!(next_event(&events), "snapshot BTC-USD 1");
+    assert_eq!(next_event(&events), "l2update BTC-USD 100:3");
+
+    // Snapshot of the book with the update applied, not the original snapshot message.
+    let (late, late_events) = Events::new();
+    controller.add_handler(Box::new(late), true);
+    assert_eq!(next_event(&late_events), "initialize");
+    assert_eq!(next_event(&late_events), "snapshot BTC-USD 3");
+    controller.replay_snapshots();
+    assert_eq!(next_event(&events), "snapshot BTC-USD 3");
+    assert_eq!(next_event(&late_events), "snapshot BTC-USD 3");
+    assert_eq!(client.stop(), ClientExitReason::Stopped);
+  }
+
+  #[test]
+  fn add_and_remove_handlers_while_running() {
+    let feed = MockFeed::bind();
+    let mut client = feed.client();
+    let controller = client.controller();
+    controller.subscribe(vec!["BTC-USD".into()], Channel::from_names(&[Channels::Heartbeat]));
+    let (handler, events) = Events::new();
+    client.start(handler).unwrap();
+    let connection = feed.accept();
+    connection.request();
+    assert_eq!(next_event(&events), "initialize");
+
+    let (added, added_events) = Events::new();
+    let id = controller.add_handler(Box::new(added), false);
+    assert_eq!(next_event(&added_events), "initialize");
+    connection.send(&heartbeat("BTC-USD", 1));
+    assert_eq!(next_event(&events), "heartbeat BTC-USD 1");
+    assert_eq!(next_event(&added_events), "heartbeat BTC-USD 1");
+
+    // Removed handler is closed and gets nothing more.
+    controller.remove_handler(id);
+    assert_eq!(next_event(&added_events), "close");
+    connection.send(&heartbeat("BTC-USD", 2));
+    assert_eq!(next_event(&events), "heartbeat BTC-USD 2");
+    assert_eq!(client.stop(), ClientExitReason::Stopped);
+    assert_eq!(next_event(&events), "close");
+    assert!(added_events.try_recv().is_err());
+  }
+
+  #[test]
+  fn parse_decimals_without_rounding() {
+    let feed = MockFeed::bind();
+    let mut client = feed.client();
+    client.controller().subscribe(vec!["BTC-USD".into()], Channel::from_names(&[Channels::Level2]));
+    let (handler, events) = Events::new();
+    client.start(handler).unwrap();
+    let connection = feed.accept();
+    connection.request();
+    connection.send(r#"{"type":"l2update","product_id":"BTC-USD","time":"2020-08-31T15:00:00Z","changes":[
+      ["sell","29000.123456789","0.1"],["buy","0.00010001","12345678.87654321"]
+    ]}"#);
+    assert_eq!(next_event(&events), "initialize");
+    assert_eq!(next_event(&events), "l2update BTC-USD 29000.123456789:0.1 0.00010001:12345678.87654321");
+    assert_eq!(client.stop(), ClientExitReason::Stopped);
+  }
+
+  #[test]
+  fn deliver_queued_calls_before_stopping() {
+    let feed = MockFeed::bind();
+    let mut client = feed.client();
+    client.controller().subscribe(vec!["BTC-USD".into()], Channel::from_names(&[Channels::Heartbeat]));
+    let (read, read_events) = Events::new();
+    let (queued, queued_events) = Events::new();
+    let (release, gate) = crossbeam::bounded::<()>(0);
+    let queued = ThreadedHandler::new(Gated { events: queued, gate }, 16).unwrap();
+    client.start(CompositeCoinBaseWebSocketMessageHandler::new(vec![Box::new(read), Box::new(queued)])).unwrap();
+    let connection = feed.accept();
+    connection.request();
+    assert_eq!(next_event(&read_events), "initialize");
+    for sequence in 1..=3 {
+      connection.send(&heartbeat("BTC-USD", sequence));
+      assert_eq!(next_event(&read_events), format!("heartbeat BTC-USD {}", sequence));
+    }
+
+    // Heartbeats still wait in the queue of the threaded handler when the stop is requested.
+    let stopped = thread::spawn(move || client.stop_with_deadline(TIMEOUT));
+    assert_eq!(next_event(&read_events), "close");
+    drop(release);
+    assert_eq!(stopped.join().unwrap(), Some(ClientExitReason::Stopped));
+    let delivered: Vec<String> = queued_events.try_iter().collect();
+    assert_eq!(delivered, vec!["initialize", "heartbeat BTC-USD 1", "heartbeat BTC-USD 2", "heartbeat BTC-USD 3", "close"]);
+  }
+
+  #[test]
+  fn refuse_start_until_late_worker_stopped() {
+    let feed = MockFeed::bind();
+    let mut client = feed.client();
+    client.controller().subscribe(vec!["BTC-USD".into()], Channel::from_names(&[Channels::Heartbeat]));
+    let (events, _) = Events::new();
+    let (release, gate) = crossbeam::bounded::<()>(0);
+    client.start(Gated { events, gate }).unwrap();
+    assert_eq!(client.start(CompositeCoinBaseWebSocketMessageHandler::new(vec![])), Err(StartError::Running));
+    let _connection = feed.accept();
+
+    // Worker is stuck closing the handler.
+    assert_eq!(client.stop_with_deadline(Duration::from_millis(50)), None);
+    assert_eq!(client.start(CompositeCoinBaseWebSocketMessageHandler::new(vec![])), Err(StartError::StillStopping));
+    drop(release);
+    let deadline = Instant::now() + TIMEOUT;
+    while client.start(CompositeCoinBaseWebSocketMessageHandler::new(vec![])) == Err(StartError::StillStopping) {
+      assert!(Instant::now() < deadline, "Worker did not stop.");
+      thread::sleep(Duration::from_millis(1));
+    }
+    feed.accept();
+    assert_eq!(client.stop(), ClientExitReason::Stopped);
+  }
+
+  #[test]
+  fn skip_messages_a_handler_panicked_on() {
+    let feed = MockFeed::bind();
+    let mut client = feed.client().on_handler_panic(PanicPolicy::Continue);
+    client.controller().subscribe(vec!["BTC-USD".into()], Channel::from_names(&[Channels::Heartbeat]));
+    let (events, receiver) = Events::new();
+    client.start(PanicOn(1, events)).unwrap();
+    let connection = feed.accept();
+    connection.request();
+    connection.send(&heartbeat("BTC-USD", 1));
+    connection.send(&heartbeat("BTC-USD", 2));
+    assert_eq!(next_event(&receiver), "initialize");
+    assert_eq!(next_event(&receiver), "heartbeat BTC-USD 2");
+    assert!(feed.connections.try_recv().is_err());
+    assert_eq!(client.stop(), ClientExitReason::Stopped);
+  }
+
+  #[test]
+  fn restart_supervised_worker_after_panic() {
+    let feed = MockFeed::bind();
+    let mut client = feed.client().supervise(true);
+    client.controller().subscribe(vec!["BTC-USD".into()], Channel::from_names(&[Channels::Heartbeat]));
+    let (events, receiver) = Events::new();
+    client.start(PanicOn(1, events)).unwrap();
+    let connection = feed.accept();
+    let subscribe = connection.request();
+    assert_eq!(next_event(&receiver), "initialize");
+    connection.send(&heartbeat("BTC-USD", 1));
+
+    // Restarted worker connects again, restores the subscriptions and initializes the handler.
+    let restarted = feed.accept();
+    assert_eq!(restarted.request(), subscribe);
+    assert_eq!(next_event(&receiver), "initialize");
+    restarted.send(&heartbeat("BTC-USD", 2));
+    assert_eq!(next_event(&receiver), "heartbeat BTC-USD 2");
+    assert_eq!(client.stop(), ClientExitReason::Stopped);
+  }
+
+  #[test]
+  fn report_why_the_worker_exited() {
+    let feed = MockFeed::bind();
+    let mut client = feed.client();
+    client.controller().subscribe(vec!["BTC-USD".into()], Channel::from_names(&[Channels::Heartbeat]));
+    let (events, _) = Events::new();
+    client.start(TerminateOn(1, events)).unwrap();
+    let connection = feed.accept();
+    connection.request();
+    connection.send(&heartbeat("BTC-USD", 1));
+    assert_eq!(client.wait(), ClientExitReason::HandlerTerminated);
+    assert_eq!(client.exit_reason(), Some(ClientExitReason::HandlerTerminated));
+
+    // Unsupervised worker exits with the panic message.
+    let (events, _) = Events::new();
+    client.start(PanicOn(1, events)).unwrap();
+    assert_eq!(client.exit_reason(), None);
+    let connection = feed.accept();
+    connection.request();
+    connection.send(&heartbeat("BTC-USD", 1));
+    let panicked = ClientExitReason::Panicked("Heartbeat 1".into());
+    assert_eq!(client.wait(), panicked);
+    // Stopping a worker that already exited reports why it exited.
+    assert_eq!(client.stop(), panicked);
+  }
+
+  /// Web socket server standing in for the feed. Accepted connections are pinged every few
+  /// milliseconds, so a worker waiting on the socket gets to its commands.
+  struct MockFeed {
+    url: String,
+    connections: Receiver<MockConnection>,
+  }
+
+  /// Server side of a connection, dropping it closes the connection.
+  struct MockConnection {
+    requests: Receiver<String>,
+    messages: Sender<Message>,
+  }
+
+  impl MockFeed {
+    fn bind() -> Self {
+      let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+      let url = format!("ws://{}", listener.local_addr().unwrap());
+      let (sender, connections) = crossbeam::unbounded();
+      thread::spawn(move || {
+        for stream in listener.incoming() {
+          let socket = match stream.map(tungstenite::accept) {
+            Ok(Ok(socket)) => socket,
+            _ => continue,
+          };
+          let (request_sender, requests) = crossbeam::unbounded();
+          let (messages, message_receiver) = crossbeam::unbounded();
+          if sender.send(MockConnection { requests, messages }).is_err() {
+            return;
+          }
+          thread::spawn(move || serve(socket, request_sender, message_receiver));
+        }
+      });
+      MockFeed { url, connections }
+    }
+
+    fn client(&self) -> CoinbaseWebSocketClient {
+      CoinbaseWebSocketClient::new(&self.url, CoinbaseRestClient::sandbox())
+    }
+
+    fn accept(&self) -> MockConnection {
+      self.connections.recv_timeout(TIMEOUT).expect("Worker did not connect.")
+    }
+  }
+
+  impl MockConnection {
+    /// Next request sent by the worker.
+    fn request(&self) -> serde_json::Value {
+      let request = self.requests.recv_timeout(TIMEOUT).expect("Worker did not send a request.");
+      serde_json::from_str(&request).unwrap()
+    }
+
+    fn send(&self, json: &str) {
+      self.messages.send(Message::text(json)).unwrap();
+    }
+  }
+
+  fn serve(mut socket: WebSocket<TcpStream>, requests: Sender<String>, messages: Receiver<Message>) {
+    socket.get_ref().set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+    loop {
+      match socket.read_message() {
+        Ok(Message::Text(request)) => {
+          let _ = requests.send(request);
+        }
+        Ok(_) => {}
+        Err(tungstenite::Error::Io(err)) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+        Err(_) => return,
+      }
+      loop {
+        match messages.try_recv() {
+          Ok(message) => {
+            if socket.write_message(message).is_err() {
+              return;
+            }
+          }
+          Err(TryRecvError::Empty) => break,
+          Err(TryRecvError::Disconnected) => {
+            let _ = socket.close(None).and_then(|_| socket.write_pending());
+            return;
+          }
+        }
+      }
+      if socket.write_message(Message::Ping(Vec::new())).is_err() {
+        return;
+      }
+    }
+  }
+
+  /// REST server with trades `1..=newest` of every product, returns its url.
+  fn trade_history(newest: i64) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    thread::spawn(move || {
+      for stream in listener.incoming() {
+        let mut reader = BufReader::new(stream.unwrap());
+        let mut request = String::new();
+        reader.read_line(&mut request).unwrap();
+        loop {
+          let mut line = String::new();
+          if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+            break;
+          }
+        }
+        let before = request.split(['=', ' ']).nth(2).and_then(|after| after.parse::<i64>().ok());
+        let trades: Vec<String> = (1..before.unwrap_or(newest + 1)).rev().take(100).map(|trade_id| format!(
+          r#"{{"time":"2020-08-31T15:00:00Z","trade_id":{},"price":"100","size":"1","side":"buy"}}"#, trade_id
+        )).collect();
+        let body = format!("[{}]", trades.join(","));
+        let _ = write!(
+          reader.get_mut(),
+          "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+          body.len(), body
+        );
+      }
+    });
+    url
+  }
+
+  fn heartbeat(product_id: &str, sequence: i64) -> String {
+    format!(
+      r#"{{"type":"heartbeat","sequence":{},"last_trade_id":1,"product_id":"{}","time":"2020-08-31T15:00:00Z"}}"#,
+      sequence, product_id
+    )
+  }
+
+  /// Handler reporting the callbacks it gets.
+  struct Events(Sender<String>);
+
+  impl Events {
+    fn new() -> (Self, Receiver<String>) {
+      let (sender, receiver) = crossbeam::unbounded();
+      (Events(sender), receiver)
+    }
+
+    fn report(&self, event: String) -> Result<(), Terminate> {
+      let _ = self.0.send(event);
       Ok(())
     }
   }
-}
\ No newline at end of file
+
+  impl CoinBaseWebSocketMessageHandler for Events {
+    fn initialize(&mut self) -> Result<(), Terminate> {
+      self.report("initialize".into())
+    }
+
+    fn on_heartbeat(&mut self, resp: &HeartBeatResponse) -> Result<(), Terminate> {
+      self.report(format!("heartbeat {} {}", resp.product_id, resp.sequence))
+    }
+
+    fn on_snapshot(&mut self, resp: &SnapshotResponse) -> Result<(), Terminate> {
+      // Size of the best bid.
+      let size = resp.bids.first().map(|level| level[1].to_string()).unwrap_or_default();
+      self.report(format!("snapshot {} {}", resp.product_id, size))
+    }
+
+    fn on_l2_update(&mut self, resp: &L2UpdateResponse) -> Result<(), Terminate> {
+      let changes: Vec<String> = resp.changes.iter().map(|change| format!("{}:{}", change.price, change.size)).collect();
+      self.report(format!("l2update {} {}", resp.product_id, changes.join(" ")))
+    }
+
+    fn on_pong(&mut self, _round_trip_time: Duration) -> Result<(), Terminate> {
+      self.report("pong".into())
+    }
+
+    fn on_parse_error(&mut self, raw: &str, err: &serde_json::Error) -> Result<(), Terminate> {
+      self.report(format!("parse_error {} | {}", raw, err))
+    }
+
+    fn on_backfilled_trade(&mut self, product_id: &str, trade: &rest::Trade) -> Result<(), Terminate> {
+      self.report(format!("backfilled {} {}", product_id, trade.trade_id))
+    }
+
+    fn on_missed_trades(&mut self, product_id: &str, from_trade_id: i64, to_trade_id: i64) -> Result<(), Terminate> {
+      self.report(format!("missed_trades {} {} {}", product_id, from_trade_id, to_trade_id))
+    }
+
+    fn close(&mut self) -> Result<(), Terminate> {
+      self.report("close".into())
+    }
+  }
+
+  /// Events that wait for the gate before heartbeats and close, until the gate is dropped.
+  struct Gated {
+    events: Events,
+    gate: Receiver<()>,
+  }
+
+  impl CoinBaseWebSocketMessageHandler for Gated {
+    fn initialize(&mut self) -> Result<(), Terminate> {
+      self.events.initialize()
+    }
+
+    fn on_heartbeat(&mut self, resp: &HeartBeatResponse) -> Result<(), Terminate> {
+      let _ = self.gate.recv();
+      self.events.on_heartbeat(resp)
+    }
+
+    fn close(&mut self) -> Result<(), Terminate> {
+      let _ = self.gate.recv();
+      self.events.close()
+    }
+  }
+
+  /// Events that panic on the heartbeat with the given sequence.
+  struct PanicOn(i64, Events);
+
+  impl CoinBaseWebSocketMessageHandler for PanicOn {
+    fn initialize(&mut self) -> Result<(), Terminate> {
+      self.1.initialize()
+    }
+
+    fn on_heartbeat(&mut self, resp: &HeartBeatResponse) -> Result<(), Terminate> {
+      if resp.sequence == self.0 {
+        panic!("Heartbeat {}", resp.sequence);
+      }
+      self.1.on_heartbeat(resp)
+    }
+  }
+
+  /// Handler that terminates on the heartbeat with the given sequence.
+  struct TerminateOn(i64, Events);
+
+  impl CoinBaseWebSocketMessageHandler for TerminateOn {
+    fn on_heartbeat(&mut self, resp: &HeartBeatResponse) -> Result<(), Terminate> {
+      if resp.sequence == self.0 {
+        return Err(Terminate);
+      }
+      self.1.on_heartbeat(resp)
+    }
+  }
+
+  /// Handler forwarding the context of every frame.
+  struct Contexts(Sender<MessageContext>);
+
+  impl CoinBaseWebSocketMessageHandler for Contexts {
+    fn on_message_context(&mut self, ctx: &MessageContext) -> Result<(), Terminate> {
+      let _ = self.0.send(*ctx);
+      Ok(())
+    }
+  }
+
+  fn next_event(events: &Receiver<String>) -> String {
+    events.recv_timeout(TIMEOUT).expect("Handler was not called.")
+  }
+}
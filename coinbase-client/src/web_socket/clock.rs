@@ -0,0 +1,88 @@
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+
+/// Source of time for the worker (reconnect backoff, stale product checks, day rollover),
+/// schedules, throttling and realtime replays. `SystemClock` is used unless one is given.
+pub trait Clock: Debug + Send + Sync {
+  /// Monotonic time, for measuring intervals.
+  fn now(&self) -> Instant;
+
+  /// Wall clock time, for calendar boundaries and timestamps.
+  fn utc_now(&self) -> DateTime<Utc>;
+
+  fn sleep(&self, duration: Duration);
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+  fn now(&self) -> Instant {
+    Instant::now()
+  }
+
+  fn utc_now(&self) -> DateTime<Utc> {
+    Utc::now()
+  }
+
+  fn sleep(&self, duration: Duration) {
+    thread::sleep(duration)
+  }
+}
+
+/// Clock that only moves when it is advanced. Sleeping advances it by the slept duration and
+/// returns right away, so backoffs and realtime replays take no time. Clones share the time.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+  time: Arc<Mutex<(Instant, DateTime<Utc>)>>,
+}
+
+impl MockClock {
+  /// Clock reading `start` as the wall clock time.
+  pub fn new(start: DateTime<Utc>) -> Self {
+    MockClock { time: Arc::new(Mutex::new((Instant::now(), start))) }
+  }
+
+  pub fn advance(&self, duration: Duration) {
+    let mut time = self.time.lock().unwrap();
+    time.0 += duration;
+    time.1 += chrono::Duration::from_std(duration).unwrap_or(chrono::Duration::MAX);
+  }
+}
+
+impl Clock for MockClock {
+  fn now(&self) -> Instant {
+    self.time.lock().unwrap().0
+  }
+
+  fn utc_now(&self) -> DateTime<Utc> {
+    self.time.lock().unwrap().1
+  }
+
+  fn sleep(&self, duration: Duration) {
+    self.advance(duration)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::time::Duration;
+
+  use chrono::{TimeZone, Utc};
+
+  use super::{Clock, MockClock};
+
+  #[test]
+  fn mock_clock_moves_only_when_advanced() {
+    let clock = MockClock::new(Utc.with_ymd_and_hms(2020, 8, 31, 23, 59, 59).unwrap());
+    let start = clock.now();
+    let shared = clock.clone();
+    shared.sleep(Duration::from_millis(1500));
+    assert_eq!(clock.now().duration_since(start), Duration::from_millis(1500));
+    assert_eq!(clock.utc_now(), Utc.with_ymd_and_hms(2020, 9, 1, 0, 0, 0).unwrap() + chrono::Duration::milliseconds(500));
+  }
+}
@@ -2,10 +2,10 @@ use std::str::FromStr;
 
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde::de::{Error, MapAccess, Visitor};
-use serde::export::Formatter;
+use std::fmt::{Display, Formatter};
 use serde::ser::SerializeStruct;
 
-#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash)]
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash, Ord, PartialOrd)]
 #[serde(rename_all = "lowercase")]
 pub enum Channels {
   Heartbeat,
@@ -34,18 +34,19 @@ impl FromStr for Channels {
   }
 }
 
-impl ToString for Channels {
-
-  fn to_string(&self) -> String {
-    match self {
-      Channels::Heartbeat => "heartbeat".to_string(),
-      Channels::Status => "status".to_string(),
-      Channels::Ticker => "ticker".to_string(),
-      Channels::Level2 => "level2".to_string(),
-      Channels::Matches => "matches".to_string(),
-      Channels::User => "user".to_string(),
-      Channels::Full => "full".to_string(),
-    }
+impl Display for Channels {
+
+  fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+    let name = match self {
+      Channels::Heartbeat => "heartbeat",
+      Channels::Status => "status",
+      Channels::Ticker => "ticker",
+      Channels::Level2 => "level2",
+      Channels::Matches => "matches",
+      Channels::User => "user",
+      Channels::Full => "full",
+    };
+    f.write_str(name)
   }
 }
 
@@ -62,6 +63,14 @@ impl Channel {
     Channel { name: channel, product_ids: Some(product_ids) }
   }
 
+  pub fn name(&self) -> &Channels {
+    &self.name
+  }
+
+  pub fn product_ids(&self) -> Option<&[String]> {
+    self.product_ids.as_deref()
+  }
+
   pub fn from_names(channels: &[Channels]) -> Vec<Self> {
     channels.iter()
       .map(|c| Channel::new(c.clone()))
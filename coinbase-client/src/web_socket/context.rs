@@ -0,0 +1,17 @@
+use std::time::Instant;
+
+use chrono::{DateTime, Utc};
+
+/// Metadata of a single web socket frame, delivered to handlers through
+/// `on_message_context` right before the callback for the message itself.
+#[derive(Debug, Clone, Copy)]
+pub struct MessageContext {
+  /// Monotonic time when the frame was read from the socket, use it for latency accounting.
+  pub received_at: Instant,
+  /// Wall clock time when the frame was read, comparable with exchange timestamps.
+  pub wall_clock: DateTime<Utc>,
+  /// Incremented on every (re)connect, so data from different connections can be told apart.
+  pub connection_id: u64,
+  /// Length of the raw frame in bytes.
+  pub raw_len: usize,
+}
@@ -0,0 +1,153 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+
+use super::borrowed::BorrowedMessages;
+use super::response::ResponseMessages;
+
+/// Ids are unique only within a stream: tickers repeat the sequence of the trade that
+/// triggered them and trades are identified by trade id.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+enum Stream {
+  Heartbeat,
+  Ticker,
+  Trade,
+  Orders,
+}
+
+#[derive(Default)]
+struct RecentIds {
+  order: VecDeque<(Stream, i64)>,
+  seen: HashSet<(Stream, i64)>,
+  /// Hash of the last snapshot, cleared by the first update that follows it.
+  snapshot: Option<u64>,
+}
+
+/// Suppresses messages that were already delivered, e.g. overlapping trades and repeated
+/// snapshots after a reconnect. Remembers the last `window` ids of every product, so memory
+/// stays bounded; a duplicate older than that is delivered again.
+///
+/// A snapshot is a duplicate only when it is identical to the previous snapshot of the product
+/// and no level2 update arrived in between, otherwise handlers need it to reset their books.
+pub(crate) struct Deduplicator {
+  window: usize,
+  products: HashMap<String, RecentIds>,
+}
+
+impl Deduplicator {
+  pub(crate) fn new(window: usize) -> Self {
+    Deduplicator { window: window.max(1), products: HashMap::new() }
+  }
+
+  fn recent(&mut self, product_id: &str) -> &mut RecentIds {
+    if !self.products.contains_key(product_id) {
+      self.products.insert(product_id.into(), RecentIds::default());
+    }
+    self.products.get_mut(product_id).unwrap()
+  }
+
+  fn seen(&mut self, product_id: &str, stream: Stream, id: i64) -> bool {
+    let window = self.window;
+    let recent = self.recent(product_id);
+    if !recent.seen.insert((stream, id)) {
+      return true;
+    }
+    recent.order.push_back((stream, id));
+    if recent.order.len() > window {
+      let oldest = recent.order.pop_front().unwrap();
+      recent.seen.remove(&oldest);
+    }
+    false
+  }
+
+  fn seen_snapshot(&mut self, product_id: &str, hash: u64) -> bool {
+    let recent = self.recent(product_id);
+    recent.snapshot.replace(hash) == Some(hash)
+  }
+
+  fn updated(&mut self, product_id: &str) {
+    if let Some(recent) = self.products.get_mut(product_id) {
+      recent.snapshot = None;
+    }
+  }
+
+  pub(crate) fn is_duplicate(&mut self, message: &ResponseMessages) -> bool {
+    // @formatter:off
+    match message {
+      ResponseMessages::Heartbeat  { resp } => self.seen(&resp.product_id, Stream::Heartbeat, resp.sequence),
+      ResponseMessages::Ticker     { resp } => self.seen(&resp.product_id, Stream::Ticker,    resp.sequence),
+      ResponseMessages::Match      { resp } => self.seen(&resp.product_id, Stream::Trade,     resp.trade_id),
+      ResponseMessages::Last_Match { resp } => self.seen(&resp.product_id, Stream::Trade,     resp.trade_id),
+      ResponseMessages::Received   { resp } => self.seen(&resp.product_id, Stream::Orders,    resp.sequence),
+      ResponseMessages::Open       { resp } => self.seen(&resp.product_id, Stream::Orders,    resp.sequence),
+      ResponseMessages::Change     { resp } => self.seen(&resp.product_id, Stream::Orders,    resp.sequence),
+      ResponseMessages::Done       { resp } => self.seen(&resp.product_id, Stream::Orders,    resp.sequence),
+      ResponseMessages::Snapshot   { resp } => {
+        let mut hasher = DefaultHasher::new();
+        (&resp.bids, &resp.asks).hash(&mut hasher);
+        self.seen_snapshot(&resp.product_id, hasher.finish())
+      }
+      ResponseMessages::L2Update   { resp } => {
+        self.updated(&resp.product_id);
+        false
+      }
+      _ => false,
+    }
+    // @formatter:on
+  }
+
+  pub(crate) fn is_borrowed_duplicate(&mut self, message: &BorrowedMessages) -> bool {
+    // @formatter:off
+    match message {
+      BorrowedMessages::Heartbeat (resp) => self.seen(&resp.product_id, Stream::Heartbeat, resp.sequence),
+      BorrowedMessages::Ticker    (resp) => self.seen(&resp.product_id, Stream::Ticker,    resp.sequence),
+      BorrowedMessages::Match     (resp) => self.seen(&resp.product_id, Stream::Trade,     resp.trade_id),
+      BorrowedMessages::Last_Match(resp) => self.seen(&resp.product_id, Stream::Trade,     resp.trade_id),
+      BorrowedMessages::L2Update  (resp) => {
+        self.updated(&resp.product_id);
+        false
+      }
+      BorrowedMessages::Other => false,
+    }
+    // @formatter:on
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::Deduplicator;
+  use crate::web_socket::response::{parse_response, ResponseMessages};
+
+  fn message(json: &str) -> Result<ResponseMessages, serde_json::error::Error> {
+    parse_response(json)
+  }
+
+  #[test]
+  fn suppress_repeated_messages_within_window() -> Result<(), serde_json::error::Error> {
+    let trade = |trade_id: i64| message(&format!(r#"{{
+      "type": "match", "trade_id": {}, "maker_order_id": "125f1d3d-3100-41ce-9341-fc330bdcebcb",
+      "taker_order_id": "5b0a9f2d-3388-4fd4-a106-b96b1e6d302f", "side": "buy", "size": "1.9",
+      "price": "434.19", "product_id": "ETH-USD", "sequence": 10182385681, "time": "2020-08-31T15:05:14.336755Z"
+    }}"#, trade_id));
+    let snapshot = message(r#"{
+      "type": "snapshot", "product_id": "ETH-USD", "bids": [["432.39", "0.0218"]], "asks": [["432.47", "4.49"]]
+    }"#)?;
+    let update = message(r#"{
+      "type": "l2update", "product_id": "ETH-USD", "time": "2020-08-31T14:37:46.291473Z", "changes": [["buy", "432.38", "2.7"]]
+    }"#)?;
+
+    let mut dedup = Deduplicator::new(2);
+    assert!(!dedup.is_duplicate(&trade(1)?));
+    assert!(!dedup.is_duplicate(&trade(2)?));
+    assert!(dedup.is_duplicate(&trade(1)?));
+    assert!(!dedup.is_duplicate(&trade(3)?));
+    // Fell out of the window.
+    assert!(!dedup.is_duplicate(&trade(1)?));
+
+    assert!(!dedup.is_duplicate(&snapshot));
+    assert!(dedup.is_duplicate(&snapshot));
+    assert!(!dedup.is_duplicate(&update));
+    assert!(!dedup.is_duplicate(&snapshot));
+    Ok(())
+  }
+}
@@ -0,0 +1,280 @@
+use chrono::{DateTime, Utc};
+
+use super::response::{self, ResponseMessages};
+use super::{CoinBaseWebSocketMessageHandler, Terminate};
+
+/// Message of the feed, for consumers that handle all messages in one place rather than
+/// through the handler callbacks. `product_id`, `sequence` and `time` read the routing key and
+/// order of any message, whatever its type calls the fields.
+// @formatter:off
+#[derive(Debug, Clone)]
+pub enum MarketEvent {
+  Subscriptions(response::SubscriptionResponse),
+  Heartbeat(response::HeartBeatResponse),
+  Status(response::StatusResponse),
+  Ticker(response::TickerResponse),
+  Snapshot(response::SnapshotResponse),
+  L2Update(response::L2UpdateResponse),
+  Match(response::MatchResponse),
+  Received(response::ReceivedResponse),
+  Open(response::OpenResponse),
+  Change(response::ChangeResponse),
+  Done(response::DoneResponse),
+  Active(response::ActiveResponse),
+  MarginProfileUpdate(Box<response::MarginProfileUpdateResponse>),
+  LastMatch(response::LastMatchResponse),
+  Error(response::ErrorResponse),
+}
+// @formatter:on
+
+impl MarketEvent {
+  /// Value of the `type` field of the message.
+  pub fn kind(&self) -> &'static str {
+    // @formatter:off
+    match self {
+      MarketEvent::Subscriptions(_)       => "subscriptions",
+      MarketEvent::Heartbeat(_)           => "heartbeat",
+      MarketEvent::Status(_)              => "status",
+      MarketEvent::Ticker(_)              => "ticker",
+      MarketEvent::Snapshot(_)            => "snapshot",
+      MarketEvent::L2Update(_)            => "l2update",
+      MarketEvent::Match(_)               => "match",
+      MarketEvent::Received(_)            => "received",
+      MarketEvent::Open(_)                => "open",
+      MarketEvent::Change(_)              => "change",
+      MarketEvent::Done(_)                => "done",
+      MarketEvent::Active(_)              => "active",
+      MarketEvent::MarginProfileUpdate(_) => "margin_profile_update",
+      MarketEvent::LastMatch(_)           => "last_match",
+      MarketEvent::Error(_)               => "error",
+    }
+    // @formatter:on
+  }
+
+  /// Product the message is about, `None` for subscriptions, status and error messages.
+  pub fn product_id(&self) -> Option<&str> {
+    // @formatter:off
+    match self {
+      MarketEvent::Heartbeat(resp)           => Some(&resp.product_id),
+      MarketEvent::Ticker(resp)              => Some(&resp.product_id),
+      MarketEvent::Snapshot(resp)            => Some(&resp.product_id),
+      MarketEvent::L2Update(resp)            => Some(&resp.product_id),
+      MarketEvent::Match(resp)               => Some(&resp.product_id),
+      MarketEvent::Received(resp)            => Some(&resp.product_id),
+      MarketEvent::Open(resp)                => Some(&resp.product_id),
+      MarketEvent::Change(resp)              => Some(&resp.product_id),
+      MarketEvent::Done(resp)                => Some(&resp.product_id),
+      MarketEvent::Active(resp)              => Some(&resp.product_id),
+      MarketEvent::MarginProfileUpdate(resp) => Some(&resp.product_id),
+      MarketEvent::LastMatch(resp)           => Some(&resp.product_id),
+      _ => None,
+    }
+    // @formatter:on
+  }
+
+  /// Sequence number of the product's feed, for messages that carry one.
+  pub fn sequence(&self) -> Option<i64> {
+    // @formatter:off
+    match self {
+      MarketEvent::Heartbeat(resp) => Some(resp.sequence),
+      MarketEvent::Ticker(resp)    => Some(resp.sequence),
+      MarketEvent::Match(resp)     => Some(resp.sequence),
+      MarketEvent::Received(resp)  => Some(resp.sequence),
+      MarketEvent::Open(resp)      => Some(resp.sequence),
+      MarketEvent::Change(resp)    => Some(resp.sequence),
+      MarketEvent::Done(resp)      => Some(resp.sequence),
+      MarketEvent::LastMatch(resp) => Some(resp.sequence),
+      _ => None,
+    }
+    // @formatter:on
+  }
+
+  /// Exchange time of the message, including the `timestamp` of stop activations and margin
+  /// profile updates.
+  pub fn time(&self) -> Option<DateTime<Utc>> {
+    // @formatter:off
+    match self {
+      MarketEvent::Heartbeat(resp)           => Some(resp.time),
+      MarketEvent::Ticker(resp)              => Some(resp.time),
+      MarketEvent::L2Update(resp)            => Some(resp.time),
+      MarketEvent::Match(resp)               => Some(resp.time),
+      MarketEvent::Received(resp)            => Some(resp.time),
+      MarketEvent::Open(resp)                => Some(resp.time),
+      MarketEvent::Change(resp)              => Some(resp.time),
+      MarketEvent::Done(resp)                => Some(resp.time),
+      MarketEvent::Active(resp)              => resp.activated_at(),
+      MarketEvent::MarginProfileUpdate(resp) => Some(resp.timestamp),
+      MarketEvent::LastMatch(resp)           => Some(resp.time),
+      _ => None,
+    }
+    // @formatter:on
+  }
+}
+
+impl From<ResponseMessages> for MarketEvent {
+  fn from(message: ResponseMessages) -> Self {
+    // @formatter:off
+    match message {
+      ResponseMessages::Subscriptions { resp }         => MarketEvent::Subscriptions(resp),
+      ResponseMessages::Heartbeat { resp }             => MarketEvent::Heartbeat(resp),
+      ResponseMessages::Status { resp }                => MarketEvent::Status(resp),
+      ResponseMessages::Ticker { resp }                => MarketEvent::Ticker(resp),
+      ResponseMessages::Snapshot { resp }              => MarketEvent::Snapshot(resp),
+      ResponseMessages::L2Update { resp }              => MarketEvent::L2Update(resp),
+      ResponseMessages::Match { resp }                 => MarketEvent::Match(resp),
+      ResponseMessages::Received { resp }              => MarketEvent::Received(resp),
+      ResponseMessages::Open { resp }                  => MarketEvent::Open(resp),
+      ResponseMessages::Change { resp }                => MarketEvent::Change(resp),
+      ResponseMessages::Done { resp }                  => MarketEvent::Done(resp),
+      ResponseMessages::Active { resp }                => MarketEvent::Active(resp),
+      ResponseMessages::Margin_Profile_Update { resp } => MarketEvent::MarginProfileUpdate(resp),
+      ResponseMessages::Last_Match { resp }            => MarketEvent::LastMatch(resp),
+      ResponseMessages::Error { resp }                 => MarketEvent::Error(resp),
+    }
+    // @formatter:on
+  }
+}
+
+impl From<MarketEvent> for ResponseMessages {
+  fn from(event: MarketEvent) -> Self {
+    // @formatter:off
+    match event {
+      MarketEvent::Subscriptions(resp)       => ResponseMessages::Subscriptions { resp },
+      MarketEvent::Heartbeat(resp)           => ResponseMessages::Heartbeat { resp },
+      MarketEvent::Status(resp)              => ResponseMessages::Status { resp },
+      MarketEvent::Ticker(resp)              => ResponseMessages::Ticker { resp },
+      MarketEvent::Snapshot(resp)            => ResponseMessages::Snapshot { resp },
+      MarketEvent::L2Update(resp)            => ResponseMessages::L2Update { resp },
+      MarketEvent::Match(resp)               => ResponseMessages::Match { resp },
+      MarketEvent::Received(resp)            => ResponseMessages::Received { resp },
+      MarketEvent::Open(resp)                => ResponseMessages::Open { resp },
+      MarketEvent::Change(resp)              => ResponseMessages::Change { resp },
+      MarketEvent::Done(resp)                => ResponseMessages::Done { resp },
+      MarketEvent::Active(resp)              => ResponseMessages::Active { resp },
+      MarketEvent::MarginProfileUpdate(resp) => ResponseMessages::Margin_Profile_Update { resp },
+      MarketEvent::LastMatch(resp)           => ResponseMessages::Last_Match { resp },
+      MarketEvent::Error(resp)               => ResponseMessages::Error { resp },
+    }
+    // @formatter:on
+  }
+}
+
+pub trait MarketEventSink {
+  fn on_event(&mut self, event: MarketEvent) -> Result<(), Terminate>;
+}
+
+impl<F: FnMut(MarketEvent) -> Result<(), Terminate>> MarketEventSink for F {
+  fn on_event(&mut self, event: MarketEvent) -> Result<(), Terminate> {
+    self(event)
+  }
+}
+
+/// Handler that turns every feed message into a `MarketEvent` for the sink. Callbacks that are
+/// not feed messages, like pongs or stale products, are not forwarded.
+pub struct MarketEventHandler<S: MarketEventSink> {
+  sink: S,
+}
+
+impl<S: MarketEventSink> MarketEventHandler<S> {
+  pub fn new(sink: S) -> Self {
+    MarketEventHandler { sink }
+  }
+
+  pub fn into_inner(self) -> S {
+    self.sink
+  }
+}
+
+impl<S: MarketEventSink> CoinBaseWebSocketMessageHandler for MarketEventHandler<S> {
+  fn on_subscriptions(&mut self, resp: &response::SubscriptionResponse) -> Result<(), Terminate> {
+    self.sink.on_event(MarketEvent::Subscriptions(resp.clone()))
+  }
+
+  fn on_heartbeat(&mut self, resp: &response::HeartBeatResponse) -> Result<(), Terminate> {
+    self.sink.on_event(MarketEvent::Heartbeat(resp.clone()))
+  }
+
+  fn on_status(&mut self, resp: &response::StatusResponse) -> Result<(), Terminate> {
+    self.sink.on_event(MarketEvent::Status(resp.clone()))
+  }
+
+  fn on_ticker(&mut self, resp: &response::TickerResponse) -> Result<(), Terminate> {
+    self.sink.on_event(MarketEvent::Ticker(resp.clone()))
+  }
+
+  fn on_snapshot(&mut self, resp: &response::SnapshotResponse) -> Result<(), Terminate> {
+    self.sink.on_event(MarketEvent::Snapshot(resp.clone()))
+  }
+
+  fn on_l2_update(&mut self, resp: &response::L2UpdateResponse) -> Result<(), Terminate> {
+    self.sink.on_event(MarketEvent::L2Update(resp.clone()))
+  }
+
+  fn on_match(&mut self, resp: &response::MatchResponse) -> Result<(), Terminate> {
+    self.sink.on_event(MarketEvent::Match(resp.clone()))
+  }
+
+  fn on_received(&mut self, resp: &response::ReceivedResponse) -> Result<(), Terminate> {
+    self.sink.on_event(MarketEvent::Received(resp.clone()))
+  }
+
+  fn on_open(&mut self, resp: &response::OpenResponse) -> Result<(), Terminate> {
+    self.sink.on_event(MarketEvent::Open(resp.clone()))
+  }
+
+  fn on_change(&mut self, resp: &response::ChangeResponse) -> Result<(), Terminate> {
+    self.sink.on_event(MarketEvent::Change(resp.clone()))
+  }
+
+  fn on_done(&mut self, resp: &response::DoneResponse) -> Result<(), Terminate> {
+    self.sink.on_event(MarketEvent::Done(resp.clone()))
+  }
+
+  fn on_active(&mut self, resp: &response::ActiveResponse) -> Result<(), Terminate> {
+    self.sink.on_event(MarketEvent::Active(resp.clone()))
+  }
+
+  fn on_margin_profile_update(&mut self, resp: &response::MarginProfileUpdateResponse) -> Result<(), Terminate> {
+    self.sink.on_event(MarketEvent::MarginProfileUpdate(Box::new(resp.clone())))
+  }
+
+  fn on_last_match(&mut self, resp: &response::LastMatchResponse) -> Result<(), Terminate> {
+    self.sink.on_event(MarketEvent::LastMatch(resp.clone()))
+  }
+
+  fn on_error(&mut self, resp: &response::ErrorResponse) -> Result<(), Terminate> {
+    self.sink.on_event(MarketEvent::Error(resp.clone()))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{MarketEvent, MarketEventHandler};
+  use crate::web_socket::{dispatch, ResponseMessages};
+
+  #[test]
+  fn normalize_routing_keys() {
+    let messages = [
+      r#"{"type": "ticker", "trade_id": 20153558, "sequence": 3, "time": "2020-08-31T15:00:00Z", "product_id": "BTC-USD",
+          "price": "4388.01", "side": "buy", "last_size": "0.03", "best_bid": "4388", "best_ask": "4388.01"}"#,
+      r#"{"type": "activate", "product_id": "BTC-USD", "order_id": "d0c5340b-6d6c-49d9-b567-48c4bfca13d2", "user_id": "u",
+          "profile_id": "p", "timestamp": "1598886000.5", "stop_type": "entry", "side": "buy", "stop_price": "80", "size": "2"}"#,
+      r#"{"type": "subscriptions", "channels": []}"#,
+    ];
+    let mut events = Vec::new();
+    let mut handler = MarketEventHandler::new(|event: MarketEvent| {
+      events.push(event);
+      Ok(())
+    });
+    for json in messages.iter() {
+      dispatch(&mut handler, &ResponseMessages::from_json(json).unwrap()).unwrap();
+    }
+
+    let keys: Vec<_> = events.iter().map(|event| (event.kind(), event.product_id(), event.sequence())).collect();
+    assert_eq!(keys, vec![("ticker", Some("BTC-USD"), Some(3)), ("active", Some("BTC-USD"), None), ("subscriptions", None, None)]);
+    assert_eq!(events[1].time(), Some("2020-08-31T15:00:00.500Z".parse().unwrap()));
+
+    let message: ResponseMessages = events.remove(0).into();
+    assert_eq!(MarketEvent::from(message).time(), Some("2020-08-31T15:00:00Z".parse().unwrap()));
+  }
+}
@@ -0,0 +1,176 @@
+use std::time::{Duration, Instant};
+
+use url::Url;
+
+/// When the worker switches to another endpoint, see `CoinbaseWebSocketClient::failover`.
+///
+/// An endpoint fails when connecting to it fails or its connection drops within `min_uptime`.
+/// After `max_failures` failures in a row it is skipped for `cooldown` and the next endpoint in
+/// order is used. Every connection goes to the first endpoint that is not skipped, so the
+/// primary endpoint is used again once its cooldown is over.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct FailoverPolicy {
+  pub max_failures: usize,
+  pub min_uptime: Duration,
+  pub cooldown: Duration,
+}
+
+impl FailoverPolicy {
+  /// Connections dropping within 30 seconds fail, failed endpoints are skipped for 5 minutes.
+  pub fn new(max_failures: usize) -> Self {
+    FailoverPolicy { max_failures: max_failures.max(1), min_uptime: Duration::from_secs(30), cooldown: Duration::from_secs(300) }
+  }
+
+  pub fn min_uptime(mut self, min_uptime: Duration) -> Self {
+    self.min_uptime = min_uptime;
+    self
+  }
+
+  pub fn cooldown(mut self, cooldown: Duration) -> Self {
+    self.cooldown = cooldown;
+    self
+  }
+}
+
+impl Default for FailoverPolicy {
+  fn default() -> Self {
+    FailoverPolicy::new(3)
+  }
+}
+
+/// Connection history of an endpoint, see `CoinbaseWebSocketClient::endpoint_health`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct EndpointHealth {
+  pub url: String,
+  pub connections: u64,
+  pub failures: u64,
+  pub consecutive_failures: usize,
+  /// Whether the worker is connected to it, or connects to it next.
+  pub active: bool,
+  /// Time left until a skipped endpoint is used again.
+  pub skipped_for: Option<Duration>,
+}
+
+struct Endpoint {
+  url: Url,
+  connections: u64,
+  failures: u64,
+  consecutive_failures: usize,
+  skipped_until: Option<Instant>,
+}
+
+/// Endpoints of the client in the order of preference, the first one is the primary.
+pub(crate) struct Endpoints {
+  policy: FailoverPolicy,
+  endpoints: Vec<Endpoint>,
+  current: usize,
+  connected_at: Option<Instant>,
+}
+
+impl Endpoints {
+  pub(crate) fn new(urls: Vec<Url>, policy: FailoverPolicy) -> Self {
+    let endpoints = urls.into_iter()
+      .map(|url| Endpoint { url, connections: 0, failures: 0, consecutive_failures: 0, skipped_until: None })
+      .collect();
+    Endpoints { policy, endpoints, current: 0, connected_at: None }
+  }
+
+  /// Endpoint to connect to at `now`: the first one that is not skipped, or the one whose
+  /// cooldown ends first when all are.
+  pub(crate) fn select(&mut self, now: Instant) -> &Url {
+    let available = |endpoint: &Endpoint| endpoint.skipped_until.is_none_or(|until| until <= now);
+    self.current = self.endpoints.iter().position(available)
+      .or_else(|| (0..self.endpoints.len()).min_by_key(|&index| self.endpoints[index].skipped_until))
+      .unwrap_or(0);
+    let endpoint = &mut self.endpoints[self.current];
+    if endpoint.skipped_until.is_some_and(|until| until <= now) {
+      endpoint.skipped_until = None;
+    }
+    &endpoint.url
+  }
+
+  pub(crate) fn on_connected(&mut self, now: Instant) {
+    self.endpoints[self.current].connections += 1;
+    self.connected_at = Some(now);
+  }
+
+  /// Connection to the current endpoint dropped, which counts as a failure if it was short.
+  pub(crate) fn on_disconnected(&mut self, now: Instant) -> bool {
+    match self.connected_at.take() {
+      Some(connected_at) if now.duration_since(connected_at) < self.policy.min_uptime => self.on_failure(now),
+      Some(_) => {
+        self.endpoints[self.current].consecutive_failures = 0;
+        false
+      }
+      None => false,
+    }
+  }
+
+  /// Connection was closed on purpose, e.g. by `controller.reconnect()`, which is no failure.
+  pub(crate) fn on_closed(&mut self) {
+    self.connected_at = None;
+  }
+
+  /// Records a failure of the current endpoint, returns whether it is skipped from now on.
+  pub(crate) fn on_failure(&mut self, now: Instant) -> bool {
+    self.connected_at = None;
+    let endpoint = &mut self.endpoints[self.current];
+    endpoint.failures += 1;
+    endpoint.consecutive_failures += 1;
+    if endpoint.consecutive_failures < self.policy.max_failures {
+      return false;
+    }
+    endpoint.consecutive_failures = 0;
+    endpoint.skipped_until = Some(now + self.policy.cooldown);
+    self.endpoints.len() > 1
+  }
+
+  pub(crate) fn health(&self, now: Instant) -> Vec<EndpointHealth> {
+    self.endpoints.iter().enumerate()
+      .map(|(index, endpoint)| EndpointHealth {
+        url: endpoint.url.to_string(),
+        connections: endpoint.connections,
+        failures: endpoint.failures,
+        consecutive_failures: endpoint.consecutive_failures,
+        active: index == self.current,
+        skipped_for: endpoint.skipped_until.and_then(|until| until.checked_duration_since(now)).filter(|left| !left.is_zero()),
+      })
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::time::{Duration, Instant};
+
+  use url::Url;
+
+  use super::{Endpoints, FailoverPolicy};
+
+  #[test]
+  fn fail_over_and_back_to_primary() {
+    let urls = vec![Url::parse("wss://primary.example").unwrap(), Url::parse("wss://fallback.example").unwrap()];
+    let policy = FailoverPolicy::new(2).min_uptime(Duration::from_secs(10)).cooldown(Duration::from_secs(60));
+    let mut endpoints = Endpoints::new(urls, policy);
+    let start = Instant::now();
+    let at = |secs: u64| start + Duration::from_secs(secs);
+
+    assert_eq!(endpoints.select(at(0)).host_str(), Some("primary.example"));
+    assert!(!endpoints.on_failure(at(0)));
+    // Dropping right after connecting fails the endpoint as well.
+    assert_eq!(endpoints.select(at(1)).host_str(), Some("primary.example"));
+    endpoints.on_connected(at(1));
+    assert!(endpoints.on_disconnected(at(2)));
+    assert_eq!(endpoints.select(at(2)).host_str(), Some("fallback.example"));
+    endpoints.on_connected(at(2));
+
+    let health = endpoints.health(at(30));
+    assert_eq!((health[0].failures, health[0].skipped_for), (2, Some(Duration::from_secs(32))));
+    assert!(health[1].active && health[1].connections == 1);
+
+    // Long connection doesn't count as failure, the primary is used again after the cooldown.
+    assert!(!endpoints.on_disconnected(at(70)));
+    assert_eq!(endpoints.health(at(70))[1].failures, 0);
+    assert_eq!(endpoints.select(at(70)).host_str(), Some("primary.example"));
+  }
+}
@@ -0,0 +1,100 @@
+use std::collections::HashSet;
+
+use super::common::Channels;
+
+/// Drops unwanted messages in the worker before they are deserialized. Message type and
+/// product id are looked up in the raw JSON with plain string matching, so filtered out
+/// messages cost only a scan over their first few fields.
+///
+/// Messages without a product id (subscriptions, status, errors) are never dropped because
+/// of the product filter.
+#[derive(Debug, Clone, Default)]
+pub struct MessageFilter {
+  ignored_types: HashSet<String>,
+  product_ids: Option<HashSet<String>>,
+}
+
+impl MessageFilter {
+  pub fn new() -> Self {
+    MessageFilter::default()
+  }
+
+  /// Drops messages with the given `type`, e.g. `"heartbeat"`.
+  pub fn ignore_type(mut self, message_type: &str) -> Self {
+    self.ignored_types.insert(message_type.into());
+    self
+  }
+
+  /// Drops all message types sent on the channel. Types are shared between channels, e.g.
+  /// ignoring `full` also drops `match` messages of the `matches` channel.
+  pub fn ignore_channel(self, channel: Channels) -> Self {
+    let types: &[&str] = match channel {
+      Channels::Heartbeat => &["heartbeat"],
+      Channels::Status => &["status"],
+      Channels::Ticker => &["ticker"],
+      Channels::Level2 => &["snapshot", "l2update"],
+      Channels::Matches => &["match", "last_match"],
+      Channels::Full => &["received", "open", "change", "done", "match", "active", "activate"],
+      Channels::User => &["received", "open", "change", "done", "match", "active", "activate", "margin_profile_update"],
+    };
+    types.iter().fold(self, |filter, message_type| filter.ignore_type(message_type))
+  }
+
+  /// Delivers only messages of the given products.
+  pub fn only_products<S: Into<String>, I: IntoIterator<Item=S>>(mut self, product_ids: I) -> Self {
+    self.product_ids.get_or_insert_with(HashSet::new).extend(product_ids.into_iter().map(Into::into));
+    self
+  }
+
+  pub fn accepts(&self, raw: &str) -> bool {
+    if !self.ignored_types.is_empty() {
+      if let Some(message_type) = string_field(raw, "type") {
+        if self.ignored_types.contains(message_type) {
+          return false;
+        }
+      }
+    }
+    match (&self.product_ids, string_field(raw, "product_id")) {
+      (Some(product_ids), Some(product_id)) => product_ids.contains(product_id),
+      _ => true,
+    }
+  }
+}
+
+/// Value of a top level string field, assuming it contains no escape sequences (which holds
+/// for message types and product ids).
+pub(crate) fn string_field<'a>(raw: &'a str, name: &str) -> Option<&'a str> {
+  let key = format!("\"{}\"", name);
+  let mut rest = raw;
+  while let Some(position) = rest.find(key.as_str()) {
+    rest = &rest[position + key.len()..];
+    let value = rest.trim_start();
+    // A match that isn't followed by a colon is a value, not a key.
+    if let Some(value) = value.strip_prefix(':') {
+      let value = value.trim_start().strip_prefix('"')?;
+      return value.find('"').map(|end| &value[..end]);
+    }
+  }
+  None
+}
+
+#[cfg(test)]
+mod test {
+  use super::MessageFilter;
+  use crate::web_socket::common::Channels;
+
+  #[test]
+  fn filter_raw_messages() {
+    let heartbeat = r#"{"type":"heartbeat","last_trade_id":1,"product_id":"BTC-USD","sequence":2,"time":"2020-08-31T15:15:01.044966Z"}"#;
+    let ticker = r#"{"type": "ticker", "sequence": 3, "product_id": "ETH-EUR", "price": "333.0"}"#;
+    let done = r#"{"type":"done","order_type":"limit","product_id":"BTC-USD","sequence":4}"#;
+    let subscriptions = r#"{"type":"subscriptions","channels":[{"name":"ticker","product_ids":["ETH-EUR"]}]}"#;
+
+    let filter = MessageFilter::new().ignore_channel(Channels::Heartbeat).only_products(vec!["BTC-USD"]);
+    assert!(!filter.accepts(heartbeat));
+    assert!(!filter.accepts(ticker));
+    assert!(filter.accepts(done));
+    assert!(filter.accepts(subscriptions));
+    assert!(MessageFilter::new().accepts(heartbeat));
+  }
+}
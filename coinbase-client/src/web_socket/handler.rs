@@ -1,4 +1,17 @@
-use super::response;
+use std::io;
+use std::time::Duration;
+
+use chrono::{DateTime, NaiveDate, Utc};
+
+use crate::rest;
+
+use super::anomalies::DataAnomaly;
+use super::borrowed::BorrowedMessages;
+use super::context::MessageContext;
+use super::product_status::ProductStatusChange;
+use super::reconnect::ReconnectStorm;
+use super::response::{self, ResponseMessages};
+use super::threaded::ThreadedHandler;
 
 #[derive(Debug)]
 pub struct Terminate;
@@ -6,6 +19,7 @@ pub struct Terminate;
 // @formatter:off
 pub trait CoinBaseWebSocketMessageHandler {
   fn initialize      (&mut self                                        ) -> Result<(), Terminate> { Ok(()) }
+  fn on_message_context(&mut self, _ctx: &MessageContext              ) -> Result<(), Terminate> { Ok(()) }
   fn on_subscriptions(&mut self, _resp: &response::SubscriptionResponse) -> Result<(), Terminate> { Ok(()) }
   fn on_heartbeat    (&mut self, _resp: &response::HeartBeatResponse   ) -> Result<(), Terminate> { Ok(()) }
   fn on_status       (&mut self, _resp: &response::StatusResponse      ) -> Result<(), Terminate> { Ok(()) }
@@ -18,29 +32,130 @@ pub trait CoinBaseWebSocketMessageHandler {
   fn on_change       (&mut self, _resp: &response::ChangeResponse      ) -> Result<(), Terminate> { Ok(()) }
   fn on_done         (&mut self, _resp: &response::DoneResponse        ) -> Result<(), Terminate> { Ok(()) }
   fn on_active       (&mut self, _resp: &response::ActiveResponse      ) -> Result<(), Terminate> { Ok(()) }
+  fn on_margin_profile_update(&mut self, _resp: &response::MarginProfileUpdateResponse) -> Result<(), Terminate> { Ok(()) }
   fn on_last_match   (&mut self, _resp: &response::LastMatchResponse   ) -> Result<(), Terminate> { Ok(()) }
   fn on_error        (&mut self, _resp: &response::ErrorResponse       ) -> Result<(), Terminate> { Ok(()) }
+  fn on_parse_error  (&mut self, _raw: &str, _err: &serde_json::Error  ) -> Result<(), Terminate> { Ok(()) }
+  fn on_backfilled_trade(&mut self, _product_id: &str, _trade: &rest::Trade) -> Result<(), Terminate> { Ok(()) }
+  fn on_pong         (&mut self, _round_trip_time: Duration            ) -> Result<(), Terminate> { Ok(()) }
+  fn on_product_stale(&mut self, _product_id: &str, _last_seen: DateTime<Utc>) -> Result<(), Terminate> { Ok(()) }
+  /// Called after `on_status` for every product whose status or trading mode changed.
+  fn on_product_status_change(&mut self, _change: &ProductStatusChange) -> Result<(), Terminate> { Ok(()) }
+  /// Called when a heartbeat reports trades (inclusive id range) that never arrived on the matches channel,
+  /// or that the backfill after a reconnect left out.
+  fn on_missed_trades(&mut self, _product_id: &str, _from_trade_id: i64, _to_trade_id: i64) -> Result<(), Terminate> { Ok(()) }
+  /// Called before the message that broke the invariant is delivered, or instead of it with `AnomalyPolicy::Drop`.
+  fn on_data_anomaly (&mut self, _anomaly: &DataAnomaly                ) -> Result<(), Terminate> { Ok(()) }
+  /// Called before every connection attempt during a reconnect storm, before the worker waits for the backoff.
+  fn on_reconnect_storm(&mut self, _storm: &ReconnectStorm            ) -> Result<(), Terminate> { Ok(()) }
+  /// Called once the day `date` ended, at UTC midnight or the boundary set with `CoinbaseWebSocketClient::day_rollover`.
+  fn on_day_rollover (&mut self, _date: NaiveDate                       ) -> Result<(), Terminate> { Ok(()) }
   fn close           (&mut self                                        ) -> Result<(), Terminate> { Ok(()) }
+
+  /// Tells whether the handler uses messages with the given `type`. When the client runs with
+  /// `two_phase_parsing(true)`, messages that no handler wants are dropped after reading only
+  /// their type. By default every message type is wanted.
+  fn wants_message_type(&self, _message_type: &str) -> bool { true }
+
+  /// Receives high rate messages parsed without allocating their strings, only called when the
+  /// client runs with `borrowed_messages(true)`. By default the message is converted and handed
+  /// to the regular callback, override it to avoid the allocations.
+  fn on_borrowed(&mut self, msg: &BorrowedMessages) -> Result<(), Terminate> {
+    match msg {
+      BorrowedMessages::Heartbeat(resp)  => self.on_heartbeat(&resp.to_response()),
+      BorrowedMessages::Ticker(resp)     => self.on_ticker(&resp.to_response()),
+      BorrowedMessages::L2Update(resp)   => self.on_l2_update(&resp.to_response()),
+      BorrowedMessages::Match(resp)      => self.on_match(&resp.to_response()),
+      BorrowedMessages::Last_Match(resp) => self.on_last_match(&resp.to_last_match_response()),
+      BorrowedMessages::Other            => Ok(()),
+    }
+  }
 }
 // @formatter:on
 
+/// Calls the handler callback that matches the message type.
+pub fn dispatch<H: CoinBaseWebSocketMessageHandler + ?Sized>(
+  handler: &mut H,
+  message: &ResponseMessages,
+) -> Result<(), Terminate> {
+  // @formatter:off
+  match message {
+    ResponseMessages::Subscriptions { resp } => handler.on_subscriptions(resp),
+    ResponseMessages::Heartbeat     { resp } => handler.on_heartbeat(resp),
+    ResponseMessages::Status        { resp } => handler.on_status(resp),
+    ResponseMessages::Ticker        { resp } => handler.on_ticker(resp),
+    ResponseMessages::Snapshot      { resp } => handler.on_snapshot(resp),
+    ResponseMessages::L2Update      { resp } => handler.on_l2_update(resp),
+    ResponseMessages::Match         { resp } => handler.on_match(resp),
+    ResponseMessages::Received      { resp } => handler.on_received(resp),
+    ResponseMessages::Open          { resp } => handler.on_open(resp),
+    ResponseMessages::Change        { resp } => handler.on_change(resp),
+    ResponseMessages::Done          { resp } => handler.on_done(resp),
+    ResponseMessages::Active        { resp } => handler.on_active(resp),
+    ResponseMessages::Margin_Profile_Update { resp } => handler.on_margin_profile_update(resp),
+    ResponseMessages::Last_Match    { resp } => handler.on_last_match(resp),
+    ResponseMessages::Error         { resp } => handler.on_error(resp),
+  }
+  // @formatter:on
+}
+
+/// Identifies a handler registered in the composite handler.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct HandlerId(pub(crate) u64);
 
 pub struct CompositeCoinBaseWebSocketMessageHandler {
-  handlers: Vec<Box<dyn CoinBaseWebSocketMessageHandler>>
+  handlers: Vec<(HandlerId, Box<dyn CoinBaseWebSocketMessageHandler + Send>)>,
+  next_id: u64,
 }
 
 impl CompositeCoinBaseWebSocketMessageHandler {
-  pub fn new(handlers: Vec<Box<dyn CoinBaseWebSocketMessageHandler>>) -> Self {
-    CompositeCoinBaseWebSocketMessageHandler { handlers }
+  pub fn new(handlers: Vec<Box<dyn CoinBaseWebSocketMessageHandler + Send>>) -> Self {
+    let handlers: Vec<_> = handlers.into_iter()
+      .enumerate()
+      .map(|(id, handler)| (HandlerId(id as u64), handler))
+      .collect();
+    let next_id = handlers.len() as u64;
+    CompositeCoinBaseWebSocketMessageHandler { handlers, next_id }
+  }
+
+  pub fn add_handler(&mut self, handler: Box<dyn CoinBaseWebSocketMessageHandler + Send>) -> HandlerId {
+    let id = HandlerId(self.next_id);
+    self.insert_handler(id, handler);
+    id
+  }
+
+  /// Adds handler that runs on its own thread behind a queue of `capacity` calls, see `ThreadedHandler`.
+  pub fn add_threaded_handler<H>(&mut self, handler: H, capacity: usize) -> io::Result<HandlerId>
+    where H: CoinBaseWebSocketMessageHandler + Send + 'static {
+    Ok(self.add_handler(Box::new(ThreadedHandler::new(handler, capacity)?)))
+  }
+
+  /// Adds handler with an id allocated outside of this composite (e.g. by the controller).
+  pub(crate) fn insert_handler(&mut self, id: HandlerId, handler: Box<dyn CoinBaseWebSocketMessageHandler + Send>) {
+    self.next_id = self.next_id.max(id.0 + 1);
+    self.handlers.push((id, handler));
+  }
+
+  pub fn remove_handler(&mut self, id: HandlerId) -> Option<Box<dyn CoinBaseWebSocketMessageHandler + Send>> {
+    let position = self.handlers.iter().position(|(handler_id, _)| *handler_id == id)?;
+    Some(self.handlers.remove(position).1)
+  }
+
+  pub fn len(&self) -> usize {
+    self.handlers.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.handlers.is_empty()
   }
 }
 
 macro_rules! compose_visitors {
-  ($self:expr, $fn:ident $(,$opt_argument:expr)?) => {{
+  ($self:expr, $fn:ident $(,$opt_argument:expr)*) => {{
     use std::vec::Vec;
     let mut errors = Vec::with_capacity(0);
-    for handler in $self.handlers.iter_mut() {
-      match handler.$fn($($opt_argument)?) {
+    for (_, handler) in $self.handlers.iter_mut() {
+      match handler.$fn($($opt_argument),*) {
         Err(error) => errors.push(error),
         _ => {}
       }
@@ -60,6 +175,10 @@ impl CoinBaseWebSocketMessageHandler for CompositeCoinBaseWebSocketMessageHandle
     compose_visitors!(self, initialize)
   }
 
+  fn on_message_context(&mut self, ctx: &MessageContext) -> Result<(), Terminate> {
+    compose_visitors!(self, on_message_context, ctx)
+  }
+
   fn on_subscriptions(&mut self, resp: &response::SubscriptionResponse) -> Result<(), Terminate> {
     compose_visitors!(self, on_subscriptions, resp)
   }
@@ -108,6 +227,10 @@ impl CoinBaseWebSocketMessageHandler for CompositeCoinBaseWebSocketMessageHandle
     compose_visitors!(self, on_active, resp)
   }
 
+  fn on_margin_profile_update(&mut self, resp: &response::MarginProfileUpdateResponse) -> Result<(), Terminate> {
+    compose_visitors!(self, on_margin_profile_update, resp)
+  }
+
   fn on_last_match(&mut self, resp: &response::LastMatchResponse) -> Result<(), Terminate> {
     compose_visitors!(self, on_last_match, resp)
   }
@@ -116,7 +239,169 @@ impl CoinBaseWebSocketMessageHandler for CompositeCoinBaseWebSocketMessageHandle
     compose_visitors!(self, on_error, resp)
   }
 
+  fn on_parse_error(&mut self, raw: &str, err: &serde_json::Error) -> Result<(), Terminate> {
+    compose_visitors!(self, on_parse_error, raw, err)
+  }
+
+  fn on_backfilled_trade(&mut self, product_id: &str, trade: &rest::Trade) -> Result<(), Terminate> {
+    compose_visitors!(self, on_backfilled_trade, product_id, trade)
+  }
+
+  fn on_pong(&mut self, round_trip_time: Duration) -> Result<(), Terminate> {
+    compose_visitors!(self, on_pong, round_trip_time)
+  }
+
+  fn on_product_stale(&mut self, product_id: &str, last_seen: DateTime<Utc>) -> Result<(), Terminate> {
+    compose_visitors!(self, on_product_stale, product_id, last_seen)
+  }
+
+  fn on_product_status_change(&mut self, change: &ProductStatusChange) -> Result<(), Terminate> {
+    compose_visitors!(self, on_product_status_change, change)
+  }
+
+  fn on_missed_trades(&mut self, product_id: &str, from_trade_id: i64, to_trade_id: i64) -> Result<(), Terminate> {
+    compose_visitors!(self, on_missed_trades, product_id, from_trade_id, to_trade_id)
+  }
+
+  fn on_data_anomaly(&mut self, anomaly: &DataAnomaly) -> Result<(), Terminate> {
+    compose_visitors!(self, on_data_anomaly, anomaly)
+  }
+
+  fn on_reconnect_storm(&mut self, storm: &ReconnectStorm) -> Result<(), Terminate> {
+    compose_visitors!(self, on_reconnect_storm, storm)
+  }
+
+  fn on_day_rollover(&mut self, date: NaiveDate) -> Result<(), Terminate> {
+    compose_visitors!(self, on_day_rollover, date)
+  }
+
   fn close(&mut self) -> Result<(), Terminate> {
     compose_visitors!(self, close)
   } // Return None by default.
+
+  fn on_borrowed(&mut self, msg: &BorrowedMessages) -> Result<(), Terminate> {
+    compose_visitors!(self, on_borrowed, msg)
+  }
+
+  fn wants_message_type(&self, message_type: &str) -> bool {
+    self.handlers.iter().any(|(_, handler)| handler.wants_message_type(message_type))
+  }
+}
+
+impl<T: CoinBaseWebSocketMessageHandler + ?Sized> CoinBaseWebSocketMessageHandler for Box<T> {
+  fn initialize(&mut self) -> Result<(), Terminate> {
+    (**self).initialize()
+  }
+
+  fn on_message_context(&mut self, ctx: &MessageContext) -> Result<(), Terminate> {
+    (**self).on_message_context(ctx)
+  }
+
+  fn on_subscriptions(&mut self, resp: &response::SubscriptionResponse) -> Result<(), Terminate> {
+    (**self).on_subscriptions(resp)
+  }
+
+  fn on_heartbeat(&mut self, resp: &response::HeartBeatResponse) -> Result<(), Terminate> {
+    (**self).on_heartbeat(resp)
+  }
+
+  fn on_status(&mut self, resp: &response::StatusResponse) -> Result<(), Terminate> {
+    (**self).on_status(resp)
+  }
+
+  fn on_ticker(&mut self, resp: &response::TickerResponse) -> Result<(), Terminate> {
+    (**self).on_ticker(resp)
+  }
+
+  fn on_snapshot(&mut self, resp: &response::SnapshotResponse) -> Result<(), Terminate> {
+    (**self).on_snapshot(resp)
+  }
+
+  fn on_l2_update(&mut self, resp: &response::L2UpdateResponse) -> Result<(), Terminate> {
+    (**self).on_l2_update(resp)
+  }
+
+  fn on_match(&mut self, resp: &response::MatchResponse) -> Result<(), Terminate> {
+    (**self).on_match(resp)
+  }
+
+  fn on_received(&mut self, resp: &response::ReceivedResponse) -> Result<(), Terminate> {
+    (**self).on_received(resp)
+  }
+
+  fn on_open(&mut self, resp: &response::OpenResponse) -> Result<(), Terminate> {
+    (**self).on_open(resp)
+  }
+
+  fn on_change(&mut self, resp: &response::ChangeResponse) -> Result<(), Terminate> {
+    (**self).on_change(resp)
+  }
+
+  fn on_done(&mut self, resp: &response::DoneResponse) -> Result<(), Terminate> {
+    (**self).on_done(resp)
+  }
+
+  fn on_active(&mut self, resp: &response::ActiveResponse) -> Result<(), Terminate> {
+    (**self).on_active(resp)
+  }
+
+  fn on_margin_profile_update(&mut self, resp: &response::MarginProfileUpdateResponse) -> Result<(), Terminate> {
+    (**self).on_margin_profile_update(resp)
+  }
+
+  fn on_last_match(&mut self, resp: &response::LastMatchResponse) -> Result<(), Terminate> {
+    (**self).on_last_match(resp)
+  }
+
+  fn on_error(&mut self, resp: &response::ErrorResponse) -> Result<(), Terminate> {
+    (**self).on_error(resp)
+  }
+
+  fn on_parse_error(&mut self, raw: &str, err: &serde_json::Error) -> Result<(), Terminate> {
+    (**self).on_parse_error(raw, err)
+  }
+
+  fn on_backfilled_trade(&mut self, product_id: &str, trade: &rest::Trade) -> Result<(), Terminate> {
+    (**self).on_backfilled_trade(product_id, trade)
+  }
+
+  fn on_pong(&mut self, round_trip_time: Duration) -> Result<(), Terminate> {
+    (**self).on_pong(round_trip_time)
+  }
+
+  fn on_product_stale(&mut self, product_id: &str, last_seen: DateTime<Utc>) -> Result<(), Terminate> {
+    (**self).on_product_stale(product_id, last_seen)
+  }
+
+  fn on_product_status_change(&mut self, change: &ProductStatusChange) -> Result<(), Terminate> {
+    (**self).on_product_status_change(change)
+  }
+
+  fn on_missed_trades(&mut self, product_id: &str, from_trade_id: i64, to_trade_id: i64) -> Result<(), Terminate> {
+    (**self).on_missed_trades(product_id, from_trade_id, to_trade_id)
+  }
+
+  fn on_data_anomaly(&mut self, anomaly: &DataAnomaly) -> Result<(), Terminate> {
+    (**self).on_data_anomaly(anomaly)
+  }
+
+  fn on_reconnect_storm(&mut self, storm: &ReconnectStorm) -> Result<(), Terminate> {
+    (**self).on_reconnect_storm(storm)
+  }
+
+  fn on_day_rollover(&mut self, date: NaiveDate) -> Result<(), Terminate> {
+    (**self).on_day_rollover(date)
+  }
+
+  fn close(&mut self) -> Result<(), Terminate> {
+    (**self).close()
+  }
+
+  fn on_borrowed(&mut self, msg: &BorrowedMessages) -> Result<(), Terminate> {
+    (**self).on_borrowed(msg)
+  }
+
+  fn wants_message_type(&self, message_type: &str) -> bool {
+    (**self).wants_message_type(message_type)
+  }
 }
\ No newline at end of file
@@ -0,0 +1,441 @@
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, NaiveDate, Utc};
+
+use crate::rest;
+
+use super::anomalies::DataAnomaly;
+use super::borrowed::BorrowedMessages;
+use super::client::{panic_message, PanicPolicy};
+use super::context::MessageContext;
+use super::product_status::ProductStatusChange;
+use super::reconnect::ReconnectStorm;
+use super::response;
+use super::stats::Histogram;
+use super::{CoinBaseWebSocketMessageHandler, Terminate};
+
+const HANDLER_LAYER_ID: &str = "HandlerLayer";
+
+/// Handler callback together with its arguments, as seen by a `HandlerLayer`.
+#[derive(Debug, Clone, Copy)]
+pub enum HandlerCall<'a> {
+  Initialize,
+  MessageContext(&'a MessageContext),
+  Subscriptions(&'a response::SubscriptionResponse),
+  Heartbeat(&'a response::HeartBeatResponse),
+  Status(&'a response::StatusResponse),
+  Ticker(&'a response::TickerResponse),
+  Snapshot(&'a response::SnapshotResponse),
+  L2Update(&'a response::L2UpdateResponse),
+  Match(&'a response::MatchResponse),
+  Received(&'a response::ReceivedResponse),
+  Open(&'a response::OpenResponse),
+  Change(&'a response::ChangeResponse),
+  Done(&'a response::DoneResponse),
+  Active(&'a response::ActiveResponse),
+  MarginProfileUpdate(&'a response::MarginProfileUpdateResponse),
+  LastMatch(&'a response::LastMatchResponse),
+  Error(&'a response::ErrorResponse),
+  ParseError { raw: &'a str, err: &'a serde_json::Error },
+  BackfilledTrade { product_id: &'a str, trade: &'a rest::Trade },
+  Pong(Duration),
+  ProductStale { product_id: &'a str, last_seen: DateTime<Utc> },
+  ProductStatusChange(&'a ProductStatusChange),
+  MissedTrades { product_id: &'a str, from_trade_id: i64, to_trade_id: i64 },
+  DataAnomaly(&'a DataAnomaly),
+  ReconnectStorm(&'a ReconnectStorm),
+  DayRollover(NaiveDate),
+  Borrowed(&'a BorrowedMessages<'a>),
+  Close,
+}
+
+impl HandlerCall<'_> {
+  /// Name of the handler method, e.g. `"on_ticker"`.
+  pub fn name(&self) -> &'static str {
+    // @formatter:off
+    match self {
+      HandlerCall::Initialize                 => "initialize",
+      HandlerCall::MessageContext(_)          => "on_message_context",
+      HandlerCall::Subscriptions(_)           => "on_subscriptions",
+      HandlerCall::Heartbeat(_)               => "on_heartbeat",
+      HandlerCall::Status(_)                  => "on_status",
+      HandlerCall::Ticker(_)                  => "on_ticker",
+      HandlerCall::Snapshot(_)                => "on_snapshot",
+      HandlerCall::L2Update(_)                => "on_l2_update",
+      HandlerCall::Match(_)                   => "on_match",
+      HandlerCall::Received(_)                => "on_received",
+      HandlerCall::Open(_)                    => "on_open",
+      HandlerCall::Change(_)                  => "on_change",
+      HandlerCall::Done(_)                    => "on_done",
+      HandlerCall::Active(_)                  => "on_active",
+      HandlerCall::MarginProfileUpdate(_)     => "on_margin_profile_update",
+      HandlerCall::LastMatch(_)               => "on_last_match",
+      HandlerCall::Error(_)                   => "on_error",
+      HandlerCall::ParseError { .. }          => "on_parse_error",
+      HandlerCall::BackfilledTrade { .. }     => "on_backfilled_trade",
+      HandlerCall::Pong(_)                    => "on_pong",
+      HandlerCall::ProductStale { .. }        => "on_product_stale",
+      HandlerCall::ProductStatusChange(_)     => "on_product_status_change",
+      HandlerCall::MissedTrades { .. }        => "on_missed_trades",
+      HandlerCall::DataAnomaly(_)             => "on_data_anomaly",
+      HandlerCall::ReconnectStorm(_)          => "on_reconnect_storm",
+      HandlerCall::DayRollover(_)             => "on_day_rollover",
+      HandlerCall::Borrowed(_)                => "on_borrowed",
+      HandlerCall::Close                      => "close",
+    }
+    // @formatter:on
+  }
+
+  /// Product the call is about, `None` for connection level calls.
+  pub fn product_id(&self) -> Option<&str> {
+    // @formatter:off
+    match self {
+      HandlerCall::Heartbeat(resp)            => Some(&resp.product_id),
+      HandlerCall::Ticker(resp)               => Some(&resp.product_id),
+      HandlerCall::Snapshot(resp)             => Some(&resp.product_id),
+      HandlerCall::L2Update(resp)             => Some(&resp.product_id),
+      HandlerCall::Match(resp)                => Some(&resp.product_id),
+      HandlerCall::Received(resp)             => Some(&resp.product_id),
+      HandlerCall::Open(resp)                 => Some(&resp.product_id),
+      HandlerCall::Change(resp)               => Some(&resp.product_id),
+      HandlerCall::Done(resp)                 => Some(&resp.product_id),
+      HandlerCall::Active(resp)               => Some(&resp.product_id),
+      HandlerCall::MarginProfileUpdate(resp)  => Some(&resp.product_id),
+      HandlerCall::LastMatch(resp)            => Some(&resp.product_id),
+      HandlerCall::BackfilledTrade { product_id, .. }
+      | HandlerCall::ProductStale { product_id, .. }
+      | HandlerCall::MissedTrades { product_id, .. } => Some(product_id),
+      HandlerCall::ProductStatusChange(change) => Some(&change.product_id),
+      HandlerCall::DataAnomaly(anomaly)       => Some(&anomaly.product_id),
+      HandlerCall::Borrowed(BorrowedMessages::Heartbeat(resp)) => Some(&resp.product_id),
+      HandlerCall::Borrowed(BorrowedMessages::Ticker(resp))    => Some(&resp.product_id),
+      HandlerCall::Borrowed(BorrowedMessages::L2Update(resp))  => Some(&resp.product_id),
+      HandlerCall::Borrowed(BorrowedMessages::Match(resp))
+      | HandlerCall::Borrowed(BorrowedMessages::Last_Match(resp)) => Some(&resp.product_id),
+      _ => None,
+    }
+    // @formatter:on
+  }
+}
+
+/// Middleware wrapped around every callback of a handler, see `LayerExt::with_layer`.
+///
+/// The layer decides whether and how the wrapped handler is called by invoking `next`, so one
+/// layer covers all callbacks, e.g. to time them, skip some of them or catch their panics.
+pub trait HandlerLayer {
+  fn call(&mut self, call: &HandlerCall, next: &mut dyn FnMut() -> Result<(), Terminate>) -> Result<(), Terminate>;
+}
+
+/// Handler that runs every callback of `inner` through `layer`.
+pub struct Layered<L: HandlerLayer, H: CoinBaseWebSocketMessageHandler> {
+  layer: L,
+  inner: H,
+}
+
+impl<L: HandlerLayer, H: CoinBaseWebSocketMessageHandler> Layered<L, H> {
+  pub fn new(inner: H, layer: L) -> Self {
+    Layered { layer, inner }
+  }
+
+  pub fn layer(&self) -> &L {
+    &self.layer
+  }
+
+  pub fn layer_mut(&mut self) -> &mut L {
+    &mut self.layer
+  }
+
+  pub fn inner(&self) -> &H {
+    &self.inner
+  }
+
+  pub fn into_inner(self) -> H {
+    self.inner
+  }
+}
+
+/// Wraps handlers into layers, the last layer added is the outermost one:
+/// `handler.with_layer(timing).with_layer(filter)` times only the calls that pass the filter.
+pub trait LayerExt: CoinBaseWebSocketMessageHandler + Sized {
+  fn with_layer<L: HandlerLayer>(self, layer: L) -> Layered<L, Self> {
+    Layered::new(self, layer)
+  }
+}
+
+impl<H: CoinBaseWebSocketMessageHandler> LayerExt for H {}
+
+macro_rules! layered {
+  ($self:ident, $call:expr, $fn:ident $(,$argument:expr)*) => {{
+    let inner = &mut $self.inner;
+    $self.layer.call(&$call, &mut || inner.$fn($($argument),*))
+  }}
+}
+
+impl<L: HandlerLayer, H: CoinBaseWebSocketMessageHandler> CoinBaseWebSocketMessageHandler for Layered<L, H> {
+  fn initialize(&mut self) -> Result<(), Terminate> {
+    layered!(self, HandlerCall::Initialize, initialize)
+  }
+
+  fn on_message_context(&mut self, ctx: &MessageContext) -> Result<(), Terminate> {
+    layered!(self, HandlerCall::MessageContext(ctx), on_message_context, ctx)
+  }
+
+  fn on_subscriptions(&mut self, resp: &response::SubscriptionResponse) -> Result<(), Terminate> {
+    layered!(self, HandlerCall::Subscriptions(resp), on_subscriptions, resp)
+  }
+
+  fn on_heartbeat(&mut self, resp: &response::HeartBeatResponse) -> Result<(), Terminate> {
+    layered!(self, HandlerCall::Heartbeat(resp), on_heartbeat, resp)
+  }
+
+  fn on_status(&mut self, resp: &response::StatusResponse) -> Result<(), Terminate> {
+    layered!(self, HandlerCall::Status(resp), on_status, resp)
+  }
+
+  fn on_ticker(&mut self, resp: &response::TickerResponse) -> Result<(), Terminate> {
+    layered!(self, HandlerCall::Ticker(resp), on_ticker, resp)
+  }
+
+  fn on_snapshot(&mut self, resp: &response::SnapshotResponse) -> Result<(), Terminate> {
+    layered!(self, HandlerCall::Snapshot(resp), on_snapshot, resp)
+  }
+
+  fn on_l2_update(&mut self, resp: &response::L2UpdateResponse) -> Result<(), Terminate> {
+    layered!(self, HandlerCall::L2Update(resp), on_l2_update, resp)
+  }
+
+  fn on_match(&mut self, resp: &response::MatchResponse) -> Result<(), Terminate> {
+    layered!(self, HandlerCall::Match(resp), on_match, resp)
+  }
+
+  fn on_received(&mut self, resp: &response::ReceivedResponse) -> Result<(), Terminate> {
+    layered!(self, HandlerCall::Received(resp), on_received, resp)
+  }
+
+  fn on_open(&mut self, resp: &response::OpenResponse) -> Result<(), Terminate> {
+    layered!(self, HandlerCall::Open(resp), on_open, resp)
+  }
+
+  fn on_change(&mut self, resp: &response::ChangeResponse) -> Result<(), Terminate> {
+    layered!(self, HandlerCall::Change(resp), on_change, resp)
+  }
+
+  fn on_done(&mut self, resp: &response::DoneResponse) -> Result<(), Terminate> {
+    layered!(self, HandlerCall::Done(resp), on_done, resp)
+  }
+
+  fn on_active(&mut self, resp: &response::ActiveResponse) -> Result<(), Terminate> {
+    layered!(self, HandlerCall::Active(resp), on_active, resp)
+  }
+
+  fn on_margin_profile_update(&mut self, resp: &response::MarginProfileUpdateResponse) -> Result<(), Terminate> {
+    layered!(self, HandlerCall::MarginProfileUpdate(resp), on_margin_profile_update, resp)
+  }
+
+  fn on_last_match(&mut self, resp: &response::LastMatchResponse) -> Result<(), Terminate> {
+    layered!(self, HandlerCall::LastMatch(resp), on_last_match, resp)
+  }
+
+  fn on_error(&mut self, resp: &response::ErrorResponse) -> Result<(), Terminate> {
+    layered!(self, HandlerCall::Error(resp), on_error, resp)
+  }
+
+  fn on_parse_error(&mut self, raw: &str, err: &serde_json::Error) -> Result<(), Terminate> {
+    layered!(self, HandlerCall::ParseError { raw, err }, on_parse_error, raw, err)
+  }
+
+  fn on_backfilled_trade(&mut self, product_id: &str, trade: &rest::Trade) -> Result<(), Terminate> {
+    layered!(self, HandlerCall::BackfilledTrade { product_id, trade }, on_backfilled_trade, product_id, trade)
+  }
+
+  fn on_pong(&mut self, round_trip_time: Duration) -> Result<(), Terminate> {
+    layered!(self, HandlerCall::Pong(round_trip_time), on_pong, round_trip_time)
+  }
+
+  fn on_product_stale(&mut self, product_id: &str, last_seen: DateTime<Utc>) -> Result<(), Terminate> {
+    layered!(self, HandlerCall::ProductStale { product_id, last_seen }, on_product_stale, product_id, last_seen)
+  }
+
+  fn on_product_status_change(&mut self, change: &ProductStatusChange) -> Result<(), Terminate> {
+    layered!(self, HandlerCall::ProductStatusChange(change), on_product_status_change, change)
+  }
+
+  fn on_missed_trades(&mut self, product_id: &str, from_trade_id: i64, to_trade_id: i64) -> Result<(), Terminate> {
+    let call = HandlerCall::MissedTrades { product_id, from_trade_id, to_trade_id };
+    layered!(self, call, on_missed_trades, product_id, from_trade_id, to_trade_id)
+  }
+
+  fn on_data_anomaly(&mut self, anomaly: &DataAnomaly) -> Result<(), Terminate> {
+    layered!(self, HandlerCall::DataAnomaly(anomaly), on_data_anomaly, anomaly)
+  }
+
+  fn on_reconnect_storm(&mut self, storm: &ReconnectStorm) -> Result<(), Terminate> {
+    layered!(self, HandlerCall::ReconnectStorm(storm), on_reconnect_storm, storm)
+  }
+
+  fn on_day_rollover(&mut self, date: NaiveDate) -> Result<(), Terminate> {
+    layered!(self, HandlerCall::DayRollover(date), on_day_rollover, date)
+  }
+
+  fn close(&mut self) -> Result<(), Terminate> {
+    layered!(self, HandlerCall::Close, close)
+  }
+
+  fn on_borrowed(&mut self, msg: &BorrowedMessages) -> Result<(), Terminate> {
+    layered!(self, HandlerCall::Borrowed(msg), on_borrowed, msg)
+  }
+
+  fn wants_message_type(&self, message_type: &str) -> bool {
+    self.inner.wants_message_type(message_type)
+  }
+}
+
+/// Records how long the wrapped handler spends in each callback.
+#[derive(Debug, Clone, Default)]
+pub struct TimingLayer {
+  histograms: HashMap<&'static str, Histogram>,
+}
+
+impl TimingLayer {
+  pub fn new() -> Self {
+    TimingLayer::default()
+  }
+
+  /// Durations of the callback with the given name, e.g. `"on_l2_update"`.
+  pub fn histogram(&self, name: &str) -> Option<&Histogram> {
+    self.histograms.get(name)
+  }
+
+  pub fn histograms(&self) -> impl Iterator<Item=(&'static str, &Histogram)> + '_ {
+    self.histograms.iter().map(|(name, histogram)| (*name, histogram))
+  }
+}
+
+impl HandlerLayer for TimingLayer {
+  fn call(&mut self, call: &HandlerCall, next: &mut dyn FnMut() -> Result<(), Terminate>) -> Result<(), Terminate> {
+    let start = Instant::now();
+    let result = next();
+    self.histograms.entry(call.name()).or_default().record(start.elapsed());
+    result
+  }
+}
+
+/// Calls the wrapped handler only for calls the predicate accepts, the others are skipped.
+pub struct FilterLayer<F: FnMut(&HandlerCall) -> bool> {
+  predicate: F,
+}
+
+impl<F: FnMut(&HandlerCall) -> bool> FilterLayer<F> {
+  pub fn new(predicate: F) -> Self {
+    FilterLayer { predicate }
+  }
+}
+
+impl<F: FnMut(&HandlerCall) -> bool> HandlerLayer for FilterLayer<F> {
+  fn call(&mut self, call: &HandlerCall, next: &mut dyn FnMut() -> Result<(), Terminate>) -> Result<(), Terminate> {
+    if (self.predicate)(call) { next() } else { Ok(()) }
+  }
+}
+
+/// Catches panics of the wrapped handler, so one faulty handler can't take down the others.
+/// With `PanicPolicy::Continue` the call is skipped, with `PanicPolicy::Terminate` it returns
+/// `Terminate`. Either way the panic is logged.
+pub struct CatchPanicLayer {
+  policy: PanicPolicy,
+  panics: u64,
+}
+
+impl CatchPanicLayer {
+  pub fn new(policy: PanicPolicy) -> Self {
+    CatchPanicLayer { policy, panics: 0 }
+  }
+
+  pub fn panics(&self) -> u64 {
+    self.panics
+  }
+}
+
+impl HandlerLayer for CatchPanicLayer {
+  fn call(&mut self, call: &HandlerCall, next: &mut dyn FnMut() -> Result<(), Terminate>) -> Result<(), Terminate> {
+    match panic::catch_unwind(AssertUnwindSafe(next)) {
+      Ok(result) => result,
+      Err(payload) => {
+        self.panics += 1;
+        tracing::error!(target: HANDLER_LAYER_ID, "Handler panicked in {}: {}", call.name(), panic_message(&payload));
+        match self.policy {
+          PanicPolicy::Continue => Ok(()),
+          PanicPolicy::Terminate => Err(Terminate),
+        }
+      }
+    }
+  }
+}
+
+/// Logs every call of the wrapped handler at trace level, and the calls that return `Terminate`.
+pub struct LoggingLayer {
+  name: String,
+}
+
+impl LoggingLayer {
+  /// `name` identifies the wrapped handler in the log.
+  pub fn new(name: &str) -> Self {
+    LoggingLayer { name: name.into() }
+  }
+}
+
+impl HandlerLayer for LoggingLayer {
+  fn call(&mut self, call: &HandlerCall, next: &mut dyn FnMut() -> Result<(), Terminate>) -> Result<(), Terminate> {
+    tracing::trace!(target: HANDLER_LAYER_ID, "{}.{} {}", self.name, call.name(), call.product_id().unwrap_or(""));
+    let result = next();
+    if result.is_err() {
+      tracing::warn!(target: HANDLER_LAYER_ID, "{} terminated in {}.", self.name, call.name());
+    }
+    result
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{CatchPanicLayer, FilterLayer, HandlerCall, LayerExt, TimingLayer};
+  use crate::web_socket::response::TickerResponse;
+  use crate::web_socket::{CoinBaseWebSocketMessageHandler, PanicPolicy, Terminate};
+
+  #[derive(Default)]
+  struct Tickers(Vec<String>);
+
+  impl CoinBaseWebSocketMessageHandler for Tickers {
+    fn on_ticker(&mut self, resp: &TickerResponse) -> Result<(), Terminate> {
+      if resp.product_id == "PANIC-USD" {
+        panic!("bad ticker");
+      }
+      self.0.push(resp.product_id.clone());
+      Ok(())
+    }
+  }
+
+  fn ticker(product_id: &str) -> TickerResponse {
+    serde_json::from_str(&format!(
+      r#"{{"trade_id": 1, "sequence": 2, "time": "2020-08-31T15:00:00Z", "product_id": "{}", "price": "1",
+        "side": "buy", "last_size": "1", "best_bid": "1", "best_ask": "2"}}"#,
+      product_id
+    )).unwrap()
+  }
+
+  #[test]
+  fn layers_wrap_every_callback() {
+    let mut handler = Tickers::default()
+      .with_layer(CatchPanicLayer::new(PanicPolicy::Continue))
+      .with_layer(TimingLayer::new())
+      .with_layer(FilterLayer::new(|call: &HandlerCall| call.product_id() != Some("ETH-USD")));
+
+    for product_id in &["BTC-USD", "ETH-USD", "PANIC-USD", "BTC-EUR"] {
+      handler.on_ticker(&ticker(product_id)).unwrap();
+    }
+    let timing = handler.inner().layer();
+    assert_eq!(timing.histogram("on_ticker").unwrap().count(), 3);
+    assert_eq!(handler.inner().inner().layer().panics(), 1);
+    assert_eq!(handler.into_inner().into_inner().into_inner().0, vec!["BTC-USD", "BTC-EUR"]);
+  }
+}
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::product_status::{ProductState, ProductStatus, ProductStatusTracker, TradingMode};
+use super::response::{StatusResponse, TickerResponse};
+use super::{CoinBaseWebSocketMessageHandler, Terminate};
+
+/// Trading state of a market, from the least to the most restrictive.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MarketState {
+  Open,
+  LimitOnly,
+  PostOnly,
+  /// Orders are collected without matching, e.g. while a product is relisted.
+  Auction,
+  CancelOnly,
+  /// Offline or delisted, or gone from the status channel.
+  Halted,
+}
+
+impl MarketState {
+  /// Whether resting limit orders can be placed, i.e. quotes can stay on the book.
+  pub fn allows_quoting(&self) -> bool {
+    matches!(self, MarketState::Open | MarketState::LimitOnly | MarketState::PostOnly)
+  }
+}
+
+impl From<&ProductState> for MarketState {
+  fn from(state: &ProductState) -> Self {
+    let auction = state.status_message.as_deref().is_some_and(|message| message.to_lowercase().contains("auction"));
+    match (&state.status, state.mode) {
+      (ProductStatus::Offline, _) | (ProductStatus::Delisted, _) => MarketState::Halted,
+      (_, TradingMode::CancelOnly) => MarketState::CancelOnly,
+      _ if auction => MarketState::Auction,
+      (_, TradingMode::PostOnly) => MarketState::PostOnly,
+      (_, TradingMode::LimitOnly) => MarketState::LimitOnly,
+      (_, TradingMode::Full) => MarketState::Open,
+    }
+  }
+}
+
+/// What revealed the change.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MarketStateSource {
+  Status,
+  /// Ticker with crossed or locked best bid and ask.
+  Ticker,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MarketStateChange {
+  pub product_id: String,
+  pub time: DateTime<Utc>,
+  /// `None` the first time the product is seen.
+  pub previous: Option<MarketState>,
+  pub current: MarketState,
+  pub source: MarketStateSource,
+}
+
+pub trait MarketStateSink {
+  fn on_market_state_change(&mut self, change: &MarketStateChange) -> Result<(), Terminate>;
+}
+
+impl<F: FnMut(&MarketStateChange) -> Result<(), Terminate>> MarketStateSink for F {
+  fn on_market_state_change(&mut self, change: &MarketStateChange) -> Result<(), Terminate> {
+    self(change)
+  }
+}
+
+#[derive(Default)]
+struct Market {
+  // State reported by the status channel.
+  status: Option<MarketState>,
+  // Whether the last ticker had a crossed or locked book.
+  crossed: bool,
+  state: Option<MarketState>,
+}
+
+impl Market {
+  fn effective(&self) -> Option<MarketState> {
+    match self.status {
+      Some(MarketState::Open) | None if self.crossed => Some(MarketState::Auction),
+      status => status,
+    }
+  }
+}
+
+/// Follows the `status` channel and the quotes of the `ticker` channel and reports every change
+/// of a product's `MarketState`. An open product whose ticker shows a crossed or locked book is
+/// treated as being in an auction until an uncrossed ticker arrives.
+pub struct MarketStateHandler<S: MarketStateSink> {
+  tracker: ProductStatusTracker,
+  markets: HashMap<String, Market>,
+  sink: S,
+}
+
+impl<S: MarketStateSink> MarketStateHandler<S> {
+  pub fn new(sink: S) -> Self {
+    MarketStateHandler { tracker: ProductStatusTracker::new(), markets: HashMap::new(), sink }
+  }
+
+  pub fn state(&self, product_id: &str) -> Option<MarketState> {
+    self.markets.get(product_id).and_then(|market| market.state)
+  }
+
+  fn update(&mut self, product_id: &str, time: DateTime<Utc>, source: MarketStateSource) -> Result<(), Terminate> {
+    let market = match self.markets.get_mut(product_id) {
+      Some(market) => market,
+      None => return Ok(()),
+    };
+    let current = match market.effective() {
+      Some(current) if market.state != Some(current) => current,
+      _ => return Ok(()),
+    };
+    let previous = market.state.replace(current);
+    self.sink.on_market_state_change(&MarketStateChange { product_id: product_id.into(), time, previous, current, source })
+  }
+}
+
+impl<S: MarketStateSink> CoinBaseWebSocketMessageHandler for MarketStateHandler<S> {
+  fn on_status(&mut self, resp: &StatusResponse) -> Result<(), Terminate> {
+    for change in self.tracker.update(resp) {
+      let status = change.current.as_ref().map_or(MarketState::Halted, MarketState::from);
+      self.markets.entry(change.product_id.clone()).or_default().status = Some(status);
+      self.update(&change.product_id, change.time, MarketStateSource::Status)?;
+    }
+    Ok(())
+  }
+
+  fn on_ticker(&mut self, resp: &TickerResponse) -> Result<(), Terminate> {
+    let crossed = resp.best_bid >= resp.best_ask;
+    let market = self.markets.entry(resp.product_id.clone()).or_default();
+    if market.crossed == crossed {
+      return Ok(());
+    }
+    market.crossed = crossed;
+    self.update(&resp.product_id, resp.time, MarketStateSource::Ticker)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{MarketState, MarketStateChange, MarketStateHandler, MarketStateSource};
+  use crate::web_socket::response::{StatusResponse, TickerResponse};
+  use crate::web_socket::CoinBaseWebSocketMessageHandler;
+
+  fn status(post_only: bool, message: &str) -> Result<StatusResponse, serde_json::error::Error> {
+    serde_json::from_str(&format!(r#"{{
+      "currencies": [],
+      "products": [
+        {{ "id": "BTC-USD", "base_currency": "BTC", "quote_currency": "USD", "display_name": "BTC/USD",
+           "status": "online", "status_message": "{}", "post_only": {}, "limit_only": false, "cancel_only": false }}
+      ]
+    }}"#, message, post_only))
+  }
+
+  fn ticker(best_bid: &str, best_ask: &str) -> Result<TickerResponse, serde_json::error::Error> {
+    serde_json::from_str(&format!(r#"{{
+      "trade_id": 1, "sequence": 2, "time": "2020-08-31T15:00:00Z", "product_id": "BTC-USD", "price": "100",
+      "side": "buy", "last_size": "1", "best_bid": "{}", "best_ask": "{}"
+    }}"#, best_bid, best_ask))
+  }
+
+  #[test]
+  fn report_state_transitions() -> Result<(), serde_json::error::Error> {
+    let mut changes: Vec<MarketStateChange> = Vec::new();
+    let mut handler = MarketStateHandler::new(|change: &MarketStateChange| {
+      changes.push(change.clone());
+      Ok(())
+    });
+    handler.on_status(&status(false, "")?).unwrap();
+    handler.on_ticker(&ticker("100", "101")?).unwrap();
+    handler.on_ticker(&ticker("101", "100")?).unwrap();
+    assert!(!handler.state("BTC-USD").unwrap().allows_quoting());
+    handler.on_ticker(&ticker("100", "101")?).unwrap();
+    handler.on_status(&status(true, "")?).unwrap();
+    handler.on_status(&status(false, "Auction mode")?).unwrap();
+    drop(handler);
+
+    let states: Vec<_> = changes.iter().map(|change| (change.previous, change.current, change.source)).collect();
+    assert_eq!(states, vec![
+      (None, MarketState::Open, MarketStateSource::Status),
+      (Some(MarketState::Open), MarketState::Auction, MarketStateSource::Ticker),
+      (Some(MarketState::Auction), MarketState::Open, MarketStateSource::Ticker),
+      (Some(MarketState::Open), MarketState::PostOnly, MarketStateSource::Status),
+      (Some(MarketState::PostOnly), MarketState::Auction, MarketStateSource::Status),
+    ]);
+    Ok(())
+  }
+}
@@ -1,13 +1,83 @@
 pub mod common;
 
 pub mod response;
-pub use response::ResponseMessages;
+pub use response::{parse_response, ResponseMessages};
+
+pub mod borrowed;
+pub use borrowed::{BorrowedMessages, MessageHeader};
 
 pub mod request;
 pub use request::RequestMessages;
 
+pub mod subscriptions;
+pub use subscriptions::Subscriptions;
+
+pub mod validation;
+pub use validation::{InvalidChannel, SubscriptionError};
+
+pub mod wildcard;
+pub use wildcard::ProductPattern;
+
+pub mod event;
+pub use event::{MarketEvent, MarketEventHandler, MarketEventSink};
+
+pub mod filter;
+pub use filter::MessageFilter;
+
+pub mod product_status;
+pub use product_status::{ProductState, ProductStatus, ProductStatusChange, ProductStatusTracker, TradingMode};
+
+pub mod market_state;
+pub use market_state::{MarketState, MarketStateChange, MarketStateHandler, MarketStateSink, MarketStateSource};
+
+pub mod staleness;
+pub use staleness::StalePolicy;
+
+pub mod anomalies;
+pub use anomalies::{AnomalyKind, AnomalyPolicy, DataAnomaly};
+
+mod dedup;
+mod rollover;
+mod trade_gaps;
+
+pub mod reorder;
+pub use reorder::ReorderingHandler;
+
+pub mod throttle;
+pub use throttle::ThrottlingHandler;
+
+pub mod layer;
+pub use layer::{CatchPanicLayer, FilterLayer, HandlerCall, HandlerLayer, LayerExt, Layered, LoggingLayer, TimingLayer};
+
+pub mod failover;
+pub use failover::{EndpointHealth, FailoverPolicy};
+
+pub mod reconnect;
+pub use reconnect::{ReconnectStorm, ReconnectStormPolicy};
+
+pub mod schedule;
+pub use schedule::{Schedule, ScheduleHandle, ScheduledChange, TimeWindow};
+
+pub mod stats;
+pub use stats::{Histogram, MessageStatsHandler, MessageTypeStats, StatsSink, StatsSummary};
+
+pub mod snapshot_cache;
+pub use snapshot_cache::SnapshotCache;
+
+pub mod clock;
+pub use clock::{Clock, MockClock, SystemClock};
+
+pub mod worker_thread;
+pub use worker_thread::WorkerThread;
+
+pub mod threaded;
+pub use threaded::ThreadedHandler;
+
 pub mod client;
-pub use client::{CoinbaseWebSocketClient, CoinbaseWebSocketClientController};
+pub use client::{ClientExitReason, CoinbaseWebSocketClient, CoinbaseWebSocketClientController, PanicPolicy, StartError};
+
+pub mod context;
+pub use context::MessageContext;
 
 pub mod handler;
-pub use handler::{CoinBaseWebSocketMessageHandler, CompositeCoinBaseWebSocketMessageHandler, Terminate};
\ No newline at end of file
+pub use handler::{dispatch, CoinBaseWebSocketMessageHandler, CompositeCoinBaseWebSocketMessageHandler, HandlerId, Terminate};
\ No newline at end of file
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::response::{Product, StatusResponse};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProductStatus {
+  Online,
+  Offline,
+  Delisted,
+  /// Status this crate doesn't know about, or missing status.
+  Other(String),
+}
+
+impl From<Option<&str>> for ProductStatus {
+  fn from(status: Option<&str>) -> Self {
+    match status {
+      Some("online") => ProductStatus::Online,
+      Some("offline") => ProductStatus::Offline,
+      Some("delisted") => ProductStatus::Delisted,
+      other => ProductStatus::Other(other.unwrap_or_default().into()),
+    }
+  }
+}
+
+/// Which orders the exchange accepts for a product, from the most restrictive flag.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TradingMode {
+  Full,
+  LimitOnly,
+  PostOnly,
+  CancelOnly,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct ProductState {
+  pub status: ProductStatus,
+  pub mode: TradingMode,
+  pub status_message: Option<String>,
+}
+
+impl From<&Product> for ProductState {
+  fn from(product: &Product) -> Self {
+    let mode = if product.cancel_only.unwrap_or(false) {
+      TradingMode::CancelOnly
+    } else if product.post_only {
+      TradingMode::PostOnly
+    } else if product.limit_only {
+      TradingMode::LimitOnly
+    } else {
+      TradingMode::Full
+    };
+    ProductState {
+      status: ProductStatus::from(product.status.as_deref()),
+      mode,
+      status_message: product.status_message.clone().filter(|message| !message.is_empty()),
+    }
+  }
+}
+
+/// Transition of a product between two status messages. `previous` is `None` the first time a
+/// product is seen, `current` is `None` when the product disappeared from the status channel.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct ProductStatusChange {
+  pub product_id: String,
+  /// Wall clock time when the status message with the change was processed.
+  pub time: DateTime<Utc>,
+  pub previous: Option<ProductState>,
+  pub current: Option<ProductState>,
+}
+
+impl ProductStatusChange {
+  /// Whether the product is online and accepts all order types after the change.
+  pub fn is_tradable(&self) -> bool {
+    self.current.as_ref()
+      .map(|state| state.status == ProductStatus::Online && state.mode == TradingMode::Full)
+      .unwrap_or(false)
+  }
+}
+
+/// Compares consecutive status messages and reports products whose state changed.
+#[derive(Debug, Default)]
+pub struct ProductStatusTracker {
+  states: HashMap<String, ProductState>,
+}
+
+impl ProductStatusTracker {
+  pub fn new() -> Self {
+    ProductStatusTracker::default()
+  }
+
+  pub fn state(&self, product_id: &str) -> Option<&ProductState> {
+    self.states.get(product_id)
+  }
+
+  pub fn update(&mut self, status: &StatusResponse) -> Vec<ProductStatusChange> {
+    let time = Utc::now();
+    let mut states: HashMap<String, ProductState> = status.products.iter()
+      .map(|product| (product.id.clone(), ProductState::from(product)))
+      .collect();
+
+    let mut changes = Vec::new();
+    for (product_id, current) in states.iter() {
+      let previous = self.states.remove(product_id);
+      if previous.as_ref() != Some(current) {
+        changes.push(ProductStatusChange { product_id: product_id.clone(), time, previous, current: Some(current.clone()) });
+      }
+    }
+    // Whatever is left wasn't in the new status message.
+    for (product_id, previous) in self.states.drain() {
+      changes.push(ProductStatusChange { product_id, time, previous: Some(previous), current: None });
+    }
+    std::mem::swap(&mut self.states, &mut states);
+    changes.sort_by(|a, b| a.product_id.cmp(&b.product_id));
+    changes
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{ProductStatus, ProductStatusTracker, TradingMode};
+  use crate::web_socket::response::StatusResponse;
+
+  fn status(btc: &str, btc_post_only: bool) -> Result<StatusResponse, serde_json::error::Error> {
+    serde_json::from_str(&format!(r#"{{
+      "currencies": [],
+      "products": [
+        {{ "id": "BTC-USD", "base_currency": "BTC", "quote_currency": "USD", "display_name": "BTC/USD",
+           "status": "{}", "status_message": "", "post_only": {}, "limit_only": false, "cancel_only": false }},
+        {{ "id": "ETH-USD", "base_currency": "ETH", "quote_currency": "USD", "display_name": "ETH/USD",
+           "status": "online", "post_only": false, "limit_only": false, "cancel_only": false }}
+      ]
+    }}"#, btc, btc_post_only))
+  }
+
+  #[test]
+  fn report_transitions() -> Result<(), serde_json::error::Error> {
+    let mut tracker = ProductStatusTracker::new();
+    let changes = tracker.update(&status("online", false)?);
+    assert_eq!(changes.len(), 2);
+    assert!(changes.iter().all(|change| change.previous.is_none() && change.is_tradable()));
+
+    assert!(tracker.update(&status("online", false)?).is_empty());
+
+    let changes = tracker.update(&status("online", true)?);
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].product_id, "BTC-USD");
+    assert_eq!(changes[0].current.as_ref().unwrap().mode, TradingMode::PostOnly);
+    assert!(!changes[0].is_tradable());
+
+    let changes = tracker.update(&status("delisted", false)?);
+    assert_eq!(changes[0].current.as_ref().unwrap().status, ProductStatus::Delisted);
+    Ok(())
+  }
+}
@@ -0,0 +1,128 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Limits on connection attempts, see `CoinbaseWebSocketClient::reconnect_storm_guard`.
+///
+/// More than `max_attempts` connection attempts within `window` start a storm. From then on
+/// every attempt waits for a backoff that starts at `backoff` and doubles up to `max_backoff`,
+/// until a connection stays up for `window`. With `give_up_after(n)` the worker stops after
+/// `n` attempts within a storm.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ReconnectStormPolicy {
+  pub max_attempts: usize,
+  pub window: Duration,
+  pub backoff: Duration,
+  pub max_backoff: Duration,
+  pub give_up_after: Option<usize>,
+}
+
+impl ReconnectStormPolicy {
+  /// Backoff from 1 second up to a minute, never gives up.
+  pub fn new(max_attempts: usize, window: Duration) -> Self {
+    ReconnectStormPolicy {
+      max_attempts,
+      window,
+      backoff: Duration::from_secs(1),
+      max_backoff: Duration::from_secs(60),
+      give_up_after: None,
+    }
+  }
+
+  pub fn backoff(mut self, backoff: Duration, max_backoff: Duration) -> Self {
+    self.backoff = backoff;
+    self.max_backoff = max_backoff.max(backoff);
+    self
+  }
+
+  pub fn give_up_after(mut self, attempts: usize) -> Self {
+    self.give_up_after = Some(attempts);
+    self
+  }
+}
+
+/// Connection attempt made during a reconnect storm, handed to `on_reconnect_storm` before
+/// the worker waits for the backoff.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ReconnectStorm {
+  /// Attempts since the storm started, this one included.
+  pub attempts: usize,
+  /// Attempts within the policy's window.
+  pub recent_attempts: usize,
+  pub backoff: Duration,
+  /// Whether the worker gives up instead of connecting.
+  pub terminal: bool,
+}
+
+pub(crate) struct ReconnectGuard {
+  policy: ReconnectStormPolicy,
+  attempts: VecDeque<Instant>,
+  storm_attempts: usize,
+  connected_at: Option<Instant>,
+}
+
+impl ReconnectGuard {
+  pub(crate) fn new(policy: ReconnectStormPolicy) -> Self {
+    ReconnectGuard { policy, attempts: VecDeque::new(), storm_attempts: 0, connected_at: None }
+  }
+
+  /// Records a connection attempt, returns the storm it belongs to, if any.
+  pub(crate) fn on_attempt(&mut self, now: Instant) -> Option<ReconnectStorm> {
+    let window = self.policy.window;
+    // Storm is over once a connection stayed up for the whole window.
+    if self.connected_at.take().is_some_and(|connected_at| now.duration_since(connected_at) >= window) {
+      self.storm_attempts = 0;
+      self.attempts.clear();
+    }
+    self.attempts.push_back(now);
+    while self.attempts.front().is_some_and(|attempt| now.duration_since(*attempt) > window) {
+      self.attempts.pop_front();
+    }
+    if self.storm_attempts == 0 && self.attempts.len() <= self.policy.max_attempts {
+      return None;
+    }
+    self.storm_attempts += 1;
+    let doublings = (self.storm_attempts - 1).min(31) as u32;
+    let backoff = self.policy.backoff.saturating_mul(1 << doublings).min(self.policy.max_backoff);
+    Some(ReconnectStorm {
+      attempts: self.storm_attempts,
+      recent_attempts: self.attempts.len(),
+      backoff,
+      terminal: self.policy.give_up_after.is_some_and(|attempts| self.storm_attempts > attempts),
+    })
+  }
+
+  pub(crate) fn on_connected(&mut self, now: Instant) {
+    self.connected_at = Some(now);
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::time::{Duration, Instant};
+
+  use super::{ReconnectGuard, ReconnectStormPolicy};
+
+  #[test]
+  fn escalate_storm_until_stable() {
+    let policy = ReconnectStormPolicy::new(2, Duration::from_secs(10))
+      .backoff(Duration::from_secs(1), Duration::from_secs(3))
+      .give_up_after(3);
+    let mut guard = ReconnectGuard::new(policy);
+    let start = Instant::now();
+    let at = |secs: u64| start + Duration::from_secs(secs);
+
+    assert!(guard.on_attempt(at(0)).is_none());
+    assert!(guard.on_attempt(at(1)).is_none());
+    let backoffs: Vec<_> = (2..5).map(|secs| guard.on_attempt(at(secs)).unwrap().backoff.as_secs()).collect();
+    assert_eq!(backoffs, vec![1, 2, 3]);
+    // Attempts spread out by the backoff still belong to the storm.
+    assert!(guard.on_attempt(at(60)).unwrap().terminal);
+
+    let mut guard = ReconnectGuard::new(policy);
+    for secs in 0..4 {
+      guard.on_attempt(at(secs));
+      guard.on_connected(at(secs));
+    }
+    assert!(guard.on_attempt(at(30)).is_none());
+  }
+}
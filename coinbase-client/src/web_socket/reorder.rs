@@ -0,0 +1,435 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, NaiveDate, Utc};
+
+use crate::rest;
+
+use super::anomalies::DataAnomaly;
+use super::clock::{Clock, SystemClock};
+use super::context::MessageContext;
+use super::product_status::ProductStatusChange;
+use super::reconnect::ReconnectStorm;
+use super::response;
+use super::{CoinBaseWebSocketMessageHandler, Terminate};
+
+const REORDERING_HANDLER_ID: &str = "ReorderingHandler";
+
+/// Sequenced message held back by the reordering buffer.
+enum Sequenced {
+  Match(response::MatchResponse),
+  LastMatch(response::LastMatchResponse),
+  Received(response::ReceivedResponse),
+  Open(response::OpenResponse),
+  Change(response::ChangeResponse),
+  Done(response::DoneResponse),
+}
+
+impl Sequenced {
+  fn deliver<H: CoinBaseWebSocketMessageHandler>(&self, context: Option<MessageContext>, handler: &mut H) -> Result<(), Terminate> {
+    if let Some(ctx) = context {
+      handler.on_message_context(&ctx)?;
+    }
+    match self {
+      Sequenced::Match(resp) => handler.on_match(resp),
+      Sequenced::LastMatch(resp) => handler.on_last_match(resp),
+      Sequenced::Received(resp) => handler.on_received(resp),
+      Sequenced::Open(resp) => handler.on_open(resp),
+      Sequenced::Change(resp) => handler.on_change(resp),
+      Sequenced::Done(resp) => handler.on_done(resp),
+    }
+  }
+}
+
+#[derive(Default)]
+struct ProductBuffer {
+  next_sequence: Option<i64>,
+  // Held back messages with the context of their frame and when they were buffered.
+  pending: BTreeMap<i64, (Instant, Option<MessageContext>, Sequenced)>,
+}
+
+/// Handler decorator that delivers sequenced messages of every product in strictly increasing
+/// sequence order. Messages that arrive ahead of a missing sequence are held back until the
+/// gap is filled or until the oldest of them waited `max_delay`, in which case the gap is
+/// skipped. Duplicates and messages older than the last delivered one are dropped.
+///
+/// Meant for the `full` channel, where sequence numbers of a product are contiguous. Tickers
+/// carry the sequence of the match that triggered them and skip the rest, they are passed
+/// through right away unless older than the last delivered ticker of the product. Other
+/// messages (level2, status, backfilled trades, ...) are passed through immediately. Expired
+/// messages are released when the next message arrives, subscribe to `heartbeat` to bound the
+/// delay for quiet products.
+///
+/// The context of a frame is delivered right before its message, also when the message was
+/// held back.
+pub struct ReorderingHandler<H: CoinBaseWebSocketMessageHandler> {
+  inner: H,
+  max_delay: Duration,
+  buffers: HashMap<String, ProductBuffer>,
+  last_tickers: HashMap<String, i64>,
+  // Context of the frame whose message comes next.
+  context: Option<MessageContext>,
+  clock: Arc<dyn Clock>,
+}
+
+impl<H: CoinBaseWebSocketMessageHandler> ReorderingHandler<H> {
+  pub fn new(inner: H, max_delay: Duration) -> Self {
+    ReorderingHandler { inner, max_delay, buffers: HashMap::new(), last_tickers: HashMap::new(), context: None, clock: Arc::new(SystemClock) }
+  }
+
+  /// Clock measuring how long messages are held back, e.g. the clock of a replay.
+  pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+    self.clock = clock;
+    self
+  }
+
+  pub fn inner(&self) -> &H {
+    &self.inner
+  }
+
+  pub fn into_inner(self) -> H {
+    self.inner
+  }
+
+  /// Number of messages currently held back.
+  pub fn pending(&self) -> usize {
+    self.buffers.values().map(|buffer| buffer.pending.len()).sum()
+  }
+
+  fn accept(&mut self, product_id: &str, sequence: i64, message: Sequenced) -> Result<(), Terminate> {
+    let now = self.clock.now();
+    let context = self.context.take();
+    let key = product_id.to_string();
+    let buffer = self.buffers.entry(key.clone()).or_default();
+    match buffer.next_sequence {
+      Some(next) if sequence < next => {
+        tracing::debug!(target: REORDERING_HANDLER_ID, "Dropping out of date message {} for {}", sequence, product_id);
+      }
+      Some(next) if sequence > next => {
+        buffer.pending.entry(sequence).or_insert((now, context, message));
+      }
+      _ => {
+        buffer.next_sequence = Some(sequence + 1);
+        message.deliver(context, &mut self.inner)?;
+      }
+    }
+    self.release(&key, now)?;
+    self.release_expired(now)
+  }
+
+  /// Delivers buffered messages that are next in sequence.
+  fn release(&mut self, key: &str, now: Instant) -> Result<(), Terminate> {
+    let max_delay = self.max_delay;
+    let buffer = match self.buffers.get_mut(key) {
+      Some(buffer) => buffer,
+      None => return Ok(()),
+    };
+    while let Some(next) = buffer.next_sequence {
+      let (_, context, message) = match buffer.pending.remove(&next) {
+        Some(pending) => pending,
+        None => break,
+      };
+      buffer.next_sequence = Some(next + 1);
+      message.deliver(context, &mut self.inner)?;
+    }
+
+    let expired = buffer.pending.values().next()
+      .map(|(buffered_at, _, _)| now.duration_since(*buffered_at) >= max_delay)
+      .unwrap_or(false);
+    if expired {
+      // Give up on the gap and continue from the oldest held back message.
+      let (&first, _) = buffer.pending.iter().next().unwrap();
+      tracing::debug!(target: REORDERING_HANDLER_ID, "Skipping sequence gap {:?}..{} for {}", buffer.next_sequence, first, key);
+      buffer.next_sequence = Some(first);
+      return self.release(key, now);
+    }
+    Ok(())
+  }
+
+  fn release_expired(&mut self, now: Instant) -> Result<(), Terminate> {
+    let keys: Vec<String> = self.buffers.iter()
+      .filter(|(_, buffer)| !buffer.pending.is_empty())
+      .map(|(key, _)| key.clone())
+      .collect();
+    for key in keys {
+      self.release(&key, now)?;
+    }
+    Ok(())
+  }
+
+  /// Delivers everything that is held back, in order and regardless of gaps.
+  pub fn flush(&mut self) -> Result<(), Terminate> {
+    for buffer in self.buffers.values_mut() {
+      for (sequence, (_, context, message)) in std::mem::take(&mut buffer.pending) {
+        buffer.next_sequence = Some(sequence + 1);
+        message.deliver(context, &mut self.inner)?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Delivers the context of the message that is passed through.
+  fn pass_context(&mut self) -> Result<(), Terminate> {
+    match self.context.take() {
+      Some(ctx) => self.inner.on_message_context(&ctx),
+      None => Ok(()),
+    }
+  }
+}
+
+impl<H: CoinBaseWebSocketMessageHandler> CoinBaseWebSocketMessageHandler for ReorderingHandler<H> {
+  fn initialize(&mut self) -> Result<(), Terminate> {
+    self.inner.initialize()
+  }
+
+  fn on_message_context(&mut self, ctx: &MessageContext) -> Result<(), Terminate> {
+    // Frame without a message for this handler.
+    self.pass_context()?;
+    self.context = Some(*ctx);
+    Ok(())
+  }
+
+  fn on_subscriptions(&mut self, resp: &response::SubscriptionResponse) -> Result<(), Terminate> {
+    self.pass_context()?;
+    self.inner.on_subscriptions(resp)
+  }
+
+  fn on_heartbeat(&mut self, resp: &response::HeartBeatResponse) -> Result<(), Terminate> {
+    let context = self.context.take();
+    self.release_expired(self.clock.now())?;
+    if let Some(ctx) = context {
+      self.inner.on_message_context(&ctx)?;
+    }
+    self.inner.on_heartbeat(resp)
+  }
+
+  fn on_status(&mut self, resp: &response::StatusResponse) -> Result<(), Terminate> {
+    self.pass_context()?;
+    self.inner.on_status(resp)
+  }
+
+  fn on_ticker(&mut self, resp: &response::TickerResponse) -> Result<(), Terminate> {
+    match self.last_tickers.get_mut(&resp.product_id) {
+      Some(last) if resp.sequence <= *last => {
+        tracing::debug!(target: REORDERING_HANDLER_ID, "Dropping out of date ticker {} for {}", resp.sequence, resp.product_id);
+        self.context = None;
+        return Ok(());
+      }
+      Some(last) => *last = resp.sequence,
+      None => {
+        self.last_tickers.insert(resp.product_id.clone(), resp.sequence);
+      }
+    }
+    self.pass_context()?;
+    self.inner.on_ticker(resp)
+  }
+
+  fn on_snapshot(&mut self, resp: &response::SnapshotResponse) -> Result<(), Terminate> {
+    self.pass_context()?;
+    self.inner.on_snapshot(resp)
+  }
+
+  fn on_l2_update(&mut self, resp: &response::L2UpdateResponse) -> Result<(), Terminate> {
+    self.pass_context()?;
+    self.inner.on_l2_update(resp)
+  }
+
+  fn on_match(&mut self, resp: &response::MatchResponse) -> Result<(), Terminate> {
+    self.accept(&resp.product_id, resp.sequence, Sequenced::Match(resp.clone()))
+  }
+
+  fn on_received(&mut self, resp: &response::ReceivedResponse) -> Result<(), Terminate> {
+    self.accept(&resp.product_id, resp.sequence, Sequenced::Received(resp.clone()))
+  }
+
+  fn on_open(&mut self, resp: &response::OpenResponse) -> Result<(), Terminate> {
+    self.accept(&resp.product_id, resp.sequence, Sequenced::Open(resp.clone()))
+  }
+
+  fn on_change(&mut self, resp: &response::ChangeResponse) -> Result<(), Terminate> {
+    self.accept(&resp.product_id, resp.sequence, Sequenced::Change(resp.clone()))
+  }
+
+  fn on_done(&mut self, resp: &response::DoneResponse) -> Result<(), Terminate> {
+    self.accept(&resp.product_id, resp.sequence, Sequenced::Done(resp.clone()))
+  }
+
+  fn on_active(&mut self, resp: &response::ActiveResponse) -> Result<(), Terminate> {
+    self.pass_context()?;
+    self.inner.on_active(resp)
+  }
+
+  fn on_margin_profile_update(&mut self, resp: &response::MarginProfileUpdateResponse) -> Result<(), Terminate> {
+    self.pass_context()?;
+    self.inner.on_margin_profile_update(resp)
+  }
+
+  fn on_last_match(&mut self, resp: &response::LastMatchResponse) -> Result<(), Terminate> {
+    self.accept(&resp.product_id, resp.sequence, Sequenced::LastMatch(resp.clone()))
+  }
+
+  fn on_error(&mut self, resp: &response::ErrorResponse) -> Result<(), Terminate> {
+    self.pass_context()?;
+    self.inner.on_error(resp)
+  }
+
+  fn on_parse_error(&mut self, raw: &str, err: &serde_json::Error) -> Result<(), Terminate> {
+    self.pass_context()?;
+    self.inner.on_parse_error(raw, err)
+  }
+
+  fn on_backfilled_trade(&mut self, product_id: &str, trade: &rest::Trade) -> Result<(), Terminate> {
+    self.inner.on_backfilled_trade(product_id, trade)
+  }
+
+  fn on_pong(&mut self, round_trip_time: Duration) -> Result<(), Terminate> {
+    self.inner.on_pong(round_trip_time)
+  }
+
+  fn on_product_stale(&mut self, product_id: &str, last_seen: DateTime<Utc>) -> Result<(), Terminate> {
+    self.inner.on_product_stale(product_id, last_seen)
+  }
+
+  fn on_product_status_change(&mut self, change: &ProductStatusChange) -> Result<(), Terminate> {
+    self.inner.on_product_status_change(change)
+  }
+
+  fn on_missed_trades(&mut self, product_id: &str, from_trade_id: i64, to_trade_id: i64) -> Result<(), Terminate> {
+    self.inner.on_missed_trades(product_id, from_trade_id, to_trade_id)
+  }
+
+  fn on_data_anomaly(&mut self, anomaly: &DataAnomaly) -> Result<(), Terminate> {
+    self.inner.on_data_anomaly(anomaly)
+  }
+
+  fn on_reconnect_storm(&mut self, storm: &ReconnectStorm) -> Result<(), Terminate> {
+    self.inner.on_reconnect_storm(storm)
+  }
+
+  fn on_day_rollover(&mut self, date: NaiveDate) -> Result<(), Terminate> {
+    self.inner.on_day_rollover(date)
+  }
+
+  fn close(&mut self) -> Result<(), Terminate> {
+    self.flush()?;
+    self.inner.close()
+  }
+
+  fn wants_message_type(&self, message_type: &str) -> bool {
+    self.inner.wants_message_type(message_type)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::sync::Arc;
+  use std::time::{Duration, Instant};
+
+  use chrono::Utc;
+
+  use super::ReorderingHandler;
+  use crate::web_socket::clock::MockClock;
+  use crate::web_socket::context::MessageContext;
+  use crate::web_socket::response::{DoneResponse, HeartBeatResponse, TickerResponse};
+  use crate::web_socket::{CoinBaseWebSocketMessageHandler, Terminate};
+
+  /// Sequences of delivered messages, contexts are recorded as their negated `raw_len`.
+  #[derive(Default)]
+  struct Sequences(Vec<i64>);
+
+  impl CoinBaseWebSocketMessageHandler for Sequences {
+    fn on_message_context(&mut self, ctx: &MessageContext) -> Result<(), Terminate> {
+      self.0.push(-(ctx.raw_len as i64));
+      Ok(())
+    }
+
+    fn on_ticker(&mut self, resp: &TickerResponse) -> Result<(), Terminate> {
+      self.0.push(resp.sequence);
+      Ok(())
+    }
+
+    fn on_done(&mut self, resp: &DoneResponse) -> Result<(), Terminate> {
+      self.0.push(resp.sequence);
+      Ok(())
+    }
+  }
+
+  fn done(sequence: i64) -> Result<DoneResponse, serde_json::error::Error> {
+    serde_json::from_str(&format!(r#"{{
+      "side": "buy", "product_id": "ETH-EUR", "time": "2020-08-31T15:15:01.044966Z",
+      "sequence": {}, "order_id": "64093d3f-1d99-4858-a5af-1c85e0c83e48", "reason": "filled"
+    }}"#, sequence))
+  }
+
+  fn ticker(sequence: i64) -> Result<TickerResponse, serde_json::error::Error> {
+    serde_json::from_str(&format!(r#"{{
+      "trade_id": 1, "sequence": {}, "time": "2020-08-31T15:15:01.044966Z", "product_id": "ETH-EUR",
+      "price": "380", "side": "buy", "last_size": "1", "best_bid": "379", "best_ask": "380"
+    }}"#, sequence))
+  }
+
+  #[test]
+  fn pass_tickers_through_without_waiting_for_gaps() -> Result<(), serde_json::error::Error> {
+    let mut handler = ReorderingHandler::new(Sequences::default(), Duration::from_secs(60));
+    for sequence in &[10, 15, 12] {
+      handler.on_ticker(&ticker(*sequence)?).unwrap();
+    }
+    assert_eq!((handler.inner().0.clone(), handler.pending()), (vec![10, 15], 0));
+    Ok(())
+  }
+
+  #[test]
+  fn deliver_in_sequence_order() -> Result<(), serde_json::error::Error> {
+    let mut handler = ReorderingHandler::new(Sequences::default(), Duration::from_secs(60));
+    for sequence in &[1, 3, 4, 2, 2, 5, 7] {
+      handler.on_done(&done(*sequence)?).unwrap();
+    }
+    assert_eq!(handler.inner().0, vec![1, 2, 3, 4, 5]);
+    assert_eq!(handler.pending(), 1);
+    handler.close().unwrap();
+    assert_eq!(handler.into_inner().0, vec![1, 2, 3, 4, 5, 7]);
+    Ok(())
+  }
+
+  #[test]
+  fn deliver_context_with_held_back_message() -> Result<(), serde_json::error::Error> {
+    let mut handler = ReorderingHandler::new(Sequences::default(), Duration::from_secs(60));
+    for sequence in &[1, 3, 2] {
+      let ctx = MessageContext { received_at: Instant::now(), wall_clock: Utc::now(), connection_id: 1, raw_len: *sequence as usize };
+      handler.on_message_context(&ctx).unwrap();
+      handler.on_done(&done(*sequence)?).unwrap();
+    }
+    assert_eq!(handler.into_inner().0, vec![-1, 1, -2, 2, -3, 3]);
+    Ok(())
+  }
+
+  #[test]
+  fn skip_gap_after_max_delay() -> Result<(), serde_json::error::Error> {
+    let mut handler = ReorderingHandler::new(Sequences::default(), Duration::from_millis(0));
+    for sequence in &[1, 3, 4] {
+      handler.on_done(&done(*sequence)?).unwrap();
+    }
+    assert_eq!(handler.inner().0, vec![1, 3, 4]);
+    Ok(())
+  }
+
+  #[test]
+  fn release_held_back_messages_by_the_clock() -> Result<(), serde_json::error::Error> {
+    let clock = MockClock::new(Utc::now());
+    let mut handler = ReorderingHandler::new(Sequences::default(), Duration::from_secs(1)).with_clock(Arc::new(clock.clone()));
+    for sequence in &[1, 3] {
+      handler.on_done(&done(*sequence)?).unwrap();
+    }
+    let heartbeat: HeartBeatResponse = serde_json::from_str(
+      r#"{"sequence": 1, "last_trade_id": 1, "product_id": "ETH-EUR", "time": "2020-08-31T15:15:01Z"}"#
+    )?;
+    clock.advance(Duration::from_millis(999));
+    handler.on_heartbeat(&heartbeat).unwrap();
+    assert_eq!(handler.pending(), 1);
+    clock.advance(Duration::from_millis(1));
+    handler.on_heartbeat(&heartbeat).unwrap();
+    assert_eq!((handler.inner().0.clone(), handler.pending()), (vec![1, 3], 0));
+    Ok(())
+  }
+}
@@ -1,26 +1,86 @@
 use serde::{Deserialize, Serialize};
 
-use super::common::Channel;
+use crate::auth::Signature;
+
+use super::common::{Channel, Channels};
 
 // @formatter:off
-#[serde(tag = "type", rename_all = "lowercase")]
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
 pub enum RequestMessages {
   Subscribe   { #[serde(flatten)] req: SubscribeRequest },
   Unsubscribe { #[serde(flatten)] req: UnsubscribeRequest },
 }
 // @formatter:on
 
+impl RequestMessages {
+  /// Serializes the request into the JSON frame sent to the server.
+  pub fn to_json(&self) -> String {
+    serde_json::to_string(self).expect("Request messages are always serializable.")
+  }
+
+  pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+    serde_json::from_str(json)
+  }
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SubscribeRequest {
   pub product_ids: Vec<String>,
   pub channels: Vec<Channel>,
+  /// Signature of `GET /users/self/verify`, required for the `user` channel.
+  #[serde(flatten, default, skip_serializing_if = "Option::is_none")]
+  pub auth: Option<Signature>,
 }
 
 impl SubscribeRequest {
   pub fn new(product_ids: Vec<String>, channels: Vec<Channel>) -> Self {
-    SubscribeRequest { product_ids, channels }
+    SubscribeRequest { product_ids, channels, auth: None }
+  }
+
+  /// Splits subscription of the channels into requests that serialize into at most
+  /// `max_payload` bytes. Products of a channel may be spread over several requests, a request
+  /// that doesn't fit even with a single product is sent anyway.
+  pub fn chunked(channels: Vec<Channel>, max_payload: usize) -> Vec<SubscribeRequest> {
+    let mut requests = Vec::new();
+    let mut current: Vec<(Channels, Option<Vec<String>>)> = Vec::new();
+    for channel in channels {
+      let entries: Vec<Option<String>> = match channel.product_ids() {
+        Some(product_ids) => product_ids.iter().cloned().map(Some).collect(),
+        None => vec![None],
+      };
+      for product_id in entries {
+        let mut candidate = current.clone();
+        match (candidate.last_mut(), product_id.clone()) {
+          (Some((name, Some(product_ids))), Some(product_id)) if name == channel.name() => product_ids.push(product_id),
+          (_, product_id) => candidate.push((channel.name().clone(), product_id.map(|id| vec![id]))),
+        }
+        if !current.is_empty() && SubscribeRequest::from_entries(&candidate).payload_len() > max_payload {
+          requests.push(SubscribeRequest::from_entries(&current));
+          current = vec![(channel.name().clone(), product_id.map(|id| vec![id]))];
+        } else {
+          current = candidate;
+        }
+      }
+    }
+    if !current.is_empty() {
+      requests.push(SubscribeRequest::from_entries(&current));
+    }
+    requests
+  }
+
+  fn from_entries(entries: &[(Channels, Option<Vec<String>>)]) -> Self {
+    let channels = entries.iter()
+      .map(|(name, product_ids)| match product_ids {
+        Some(product_ids) => Channel::with_product_ids(name.clone(), product_ids.clone()),
+        None => Channel::new(name.clone()),
+      })
+      .collect();
+    SubscribeRequest::new(Vec::new(), channels)
+  }
+
+  fn payload_len(self) -> usize {
+    RequestMessages::Subscribe { req: self }.to_json().len()
   }
 }
 
@@ -39,4 +99,41 @@ impl UnsubscribeRequest {
   pub fn new(product_ids: Vec<String>, channels: Vec<Channel>) -> Self {
     UnsubscribeRequest { product_ids: Some(product_ids), channels }
   }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+  use super::{RequestMessages, SubscribeRequest};
+  use crate::web_socket::common::{Channel, Channels};
+
+  #[test]
+  fn chunk_large_subscriptions() {
+    let product_ids: Vec<String> = (0..90).map(|i| format!("P{:02}-USD", i)).collect();
+    let channels = vec![
+      Channel::new(Channels::Status),
+      Channel::with_product_ids(Channels::Ticker, product_ids.clone()),
+      Channel::with_product_ids(Channels::Level2, product_ids),
+    ];
+    let requests = SubscribeRequest::chunked(channels, 500);
+    assert!(requests.len() > 1);
+    let mut products = 0;
+    for req in requests {
+      products += req.channels.iter().map(|channel| channel.product_ids().map(|ids| ids.len()).unwrap_or(0)).sum::<usize>();
+      assert!(RequestMessages::Subscribe { req }.to_json().len() <= 500);
+    }
+    assert_eq!(products, 180);
+  }
+
+  #[test]
+  fn subscribe_to_json() -> Result<(), serde_json::error::Error> {
+    let request = RequestMessages::Subscribe {
+      req: SubscribeRequest::new(vec!["BTC-USD".into()], vec![Channel::new(Channels::Ticker)]),
+    };
+    let json = request.to_json();
+    assert_eq!(json, r#"{"type":"subscribe","product_ids":["BTC-USD"],"channels":["ticker"]}"#);
+    match RequestMessages::from_json(&json)? {
+      RequestMessages::Subscribe { req } => assert_eq!(req.product_ids, vec!["BTC-USD".to_string()]),
+      _ => panic!("Unexpected message type"),
+    }
+    Ok(())
+  }
+}
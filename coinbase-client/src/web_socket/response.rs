@@ -1,30 +1,70 @@
 use std::collections::HashMap;
 
-use bigdecimal::BigDecimal;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde::de::{SeqAccess, Visitor, Error};
-use serde::export::Formatter;
+use std::fmt::{self, Formatter};
+use std::str::FromStr;
 use serde::ser::SerializeSeq;
 use serde_json::Value;
+use uuid::Uuid;
+
+use crate::decimal::{Decimal, LenientDecimal};
 
 use super::common::Channel;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum Side { BUY, SELL }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum OrderType { LIMIT, MARKET, STOP }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum FinishReason { FILLED, CANCELED }
 
+/// Kind of a stop order, `loss` stops trigger when the last trade price falls to or below the
+/// stop price, `entry` stops when it rises to or above it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StopType { LOSS, ENTRY }
+
+/// Side of an open margin position.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MarginPosition { LONG, SHORT }
+
+/// Id of an order, sent as a UUID string. Stored in 16 bytes, so it is `Copy` and cheap to use
+/// as a map key, and malformed ids fail the message when it is parsed.
+#[derive(Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[serde(transparent)]
+pub struct OrderId(pub Uuid);
+
+impl fmt::Display for OrderId {
+  fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+    self.0.hyphenated().fmt(f)
+  }
+}
+
+impl fmt::Debug for OrderId {
+  fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+    write!(f, "OrderId({})", self)
+  }
+}
+
+impl FromStr for OrderId {
+  type Err = uuid::Error;
+
+  fn from_str(id: &str) -> Result<Self, Self::Err> {
+    Uuid::parse_str(id).map(OrderId)
+  }
+}
+
 // @formatter:off
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type", rename_all = "lowercase")]
-#[derive(Serialize, Deserialize, Debug)]
 pub enum ResponseMessages {
   Subscriptions { #[serde(flatten)] resp: SubscriptionResponse },
   Heartbeat     { #[serde(flatten)] resp: HeartBeatResponse    },
@@ -37,7 +77,10 @@ pub enum ResponseMessages {
   Open          { #[serde(flatten)] resp: OpenResponse         },
   Change        { #[serde(flatten)] resp: ChangeResponse       },
   Done          { #[serde(flatten)] resp: DoneResponse         },
+  #[serde(alias = "activate")]
   Active        { #[serde(flatten)] resp: ActiveResponse       },
+  #[allow(non_camel_case_types)]
+  Margin_Profile_Update { #[serde(flatten)] resp: Box<MarginProfileUpdateResponse> },
   Error         { #[serde(flatten)] resp: ErrorResponse        },
   // Note that since we are converting tag to lowercase,
   // then the we force the snake_case here instead of camelCase.
@@ -46,12 +89,88 @@ pub enum ResponseMessages {
 }
 // @formatter:on
 
-#[derive(Serialize, Deserialize, Debug)]
+impl ResponseMessages {
+  /// Parses a raw frame of the web socket feed.
+  pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+    serde_json::from_str(json)
+  }
+
+  /// Serializes the message back into the feed format, including the `type` tag.
+  pub fn to_json(&self) -> String {
+    serde_json::to_string(self).expect("Response messages are always serializable.")
+  }
+
+  /// Value of the `type` field of the message.
+  pub fn kind(&self) -> &'static str {
+    // @formatter:off
+    match self {
+      ResponseMessages::Subscriptions { .. } => "subscriptions",
+      ResponseMessages::Heartbeat     { .. } => "heartbeat",
+      ResponseMessages::Status        { .. } => "status",
+      ResponseMessages::Ticker        { .. } => "ticker",
+      ResponseMessages::Snapshot      { .. } => "snapshot",
+      ResponseMessages::L2Update      { .. } => "l2update",
+      ResponseMessages::Match         { .. } => "match",
+      ResponseMessages::Received      { .. } => "received",
+      ResponseMessages::Open          { .. } => "open",
+      ResponseMessages::Change        { .. } => "change",
+      ResponseMessages::Done          { .. } => "done",
+      ResponseMessages::Active        { .. } => "active",
+      ResponseMessages::Margin_Profile_Update { .. } => "margin_profile_update",
+      ResponseMessages::Error         { .. } => "error",
+      ResponseMessages::Last_Match    { .. } => "last_match",
+    }
+    // @formatter:on
+  }
+
+  pub fn product_id(&self) -> Option<&str> {
+    // @formatter:off
+    match self {
+      ResponseMessages::Heartbeat  { resp } => Some(&resp.product_id),
+      ResponseMessages::Ticker     { resp } => Some(&resp.product_id),
+      ResponseMessages::Snapshot   { resp } => Some(&resp.product_id),
+      ResponseMessages::L2Update   { resp } => Some(&resp.product_id),
+      ResponseMessages::Match      { resp } => Some(&resp.product_id),
+      ResponseMessages::Received   { resp } => Some(&resp.product_id),
+      ResponseMessages::Open       { resp } => Some(&resp.product_id),
+      ResponseMessages::Change     { resp } => Some(&resp.product_id),
+      ResponseMessages::Done       { resp } => Some(&resp.product_id),
+      ResponseMessages::Active     { resp } => Some(&resp.product_id),
+      ResponseMessages::Margin_Profile_Update { resp } => Some(&resp.product_id),
+      ResponseMessages::Last_Match { resp } => Some(&resp.product_id),
+      _ => None,
+    }
+    // @formatter:on
+  }
+
+  pub fn sequence(&self) -> Option<i64> {
+    // @formatter:off
+    match self {
+      ResponseMessages::Heartbeat  { resp } => Some(resp.sequence),
+      ResponseMessages::Ticker     { resp } => Some(resp.sequence),
+      ResponseMessages::Match      { resp } => Some(resp.sequence),
+      ResponseMessages::Received   { resp } => Some(resp.sequence),
+      ResponseMessages::Open       { resp } => Some(resp.sequence),
+      ResponseMessages::Change     { resp } => Some(resp.sequence),
+      ResponseMessages::Done       { resp } => Some(resp.sequence),
+      ResponseMessages::Last_Match { resp } => Some(resp.sequence),
+      _ => None,
+    }
+    // @formatter:on
+  }
+}
+
+/// Parses a message of the web socket feed, the same way the client does.
+pub fn parse_response(json: &str) -> Result<ResponseMessages, serde_json::Error> {
+  ResponseMessages::from_json(json)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SubscriptionResponse {
   pub channels: Vec<Channel>
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct HeartBeatResponse {
   pub sequence: i64,
   pub last_trade_id: i64,
@@ -59,34 +178,38 @@ pub struct HeartBeatResponse {
   pub time: DateTime<Utc>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct StatusResponse {
   pub products: Vec<Product>,
   pub currencies: Vec<Currency>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TickerResponse {
   pub trade_id: i64,
   pub sequence: i64,
   pub time: DateTime<Utc>,
   pub product_id: String,
-  pub price: BigDecimal,
+  #[serde(deserialize_with = "crate::decimal::lenient")]
+  pub price: Decimal,
   pub side: Side,
-  pub last_size: BigDecimal,
-  pub best_bid: BigDecimal,
-  pub best_ask: BigDecimal,
+  #[serde(deserialize_with = "crate::decimal::lenient")]
+  pub last_size: Decimal,
+  #[serde(deserialize_with = "crate::decimal::lenient")]
+  pub best_bid: Decimal,
+  #[serde(deserialize_with = "crate::decimal::lenient")]
+  pub best_ask: Decimal,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SnapshotResponse {
   // TODO sequence number or not?
   pub product_id: String,
-  pub bids: Vec<Vec<BigDecimal>>,
-  pub asks: Vec<Vec<BigDecimal>>,
+  pub bids: Vec<Vec<Decimal>>,
+  pub asks: Vec<Vec<Decimal>>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct L2UpdateResponse {
   // TODO sequence number or maybe there is no need for sequence number since l2update maybe in order
   //  always.
@@ -95,101 +218,176 @@ pub struct L2UpdateResponse {
   pub changes: Vec<Change>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MatchResponse {
   pub time: DateTime<Utc>,
   pub product_id: String,
   pub sequence: i64,
   pub trade_id: i64,
-  pub maker_order_id: String,
-  pub taker_order_id: String,
-  pub size: BigDecimal,
-  pub price: BigDecimal,
+  pub maker_order_id: OrderId,
+  pub taker_order_id: OrderId,
+  #[serde(deserialize_with = "crate::decimal::lenient")]
+  pub size: Decimal,
+  #[serde(deserialize_with = "crate::decimal::lenient")]
+  pub price: Decimal,
   pub side: Side,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ReceivedResponse {
   pub time: DateTime<Utc>,
   pub product_id: String,
   pub sequence: i64,
-  pub order_id: String,
+  pub order_id: OrderId,
+  /// Set on the user channel for orders placed with one.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub client_oid: Option<String>,
   pub side: Side,
   pub order_type: OrderType,
 
   // For limit orders
-  pub size: Option<BigDecimal>,
-  pub price: Option<BigDecimal>,
+  #[serde(default, deserialize_with = "crate::decimal::lenient_option")]
+  pub size: Option<Decimal>,
+  #[serde(default, deserialize_with = "crate::decimal::lenient_option")]
+  pub price: Option<Decimal>,
 
   // For Market orders
-  pub funds: Option<BigDecimal>,
+  #[serde(default, deserialize_with = "crate::decimal::lenient_option")]
+  pub funds: Option<Decimal>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct OpenResponse {
   pub time: DateTime<Utc>,
   pub product_id: String,
   pub sequence: i64,
-  pub order_id: String,
-  pub price: BigDecimal,
+  pub order_id: OrderId,
+  #[serde(deserialize_with = "crate::decimal::lenient")]
+  pub price: Decimal,
   pub side: Side,
-  pub remaining_size: BigDecimal,
+  #[serde(deserialize_with = "crate::decimal::lenient")]
+  pub remaining_size: Decimal,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ChangeResponse {
   pub time: DateTime<Utc>,
   pub product_id: String,
   pub sequence: i64,
-  pub order_id: String,
-  pub new_size: BigDecimal,
-  pub old_size: BigDecimal,
-  pub price: Option<BigDecimal>,
+  pub order_id: OrderId,
+  #[serde(deserialize_with = "crate::decimal::lenient")]
+  pub new_size: Decimal,
+  #[serde(deserialize_with = "crate::decimal::lenient")]
+  pub old_size: Decimal,
+  #[serde(default, deserialize_with = "crate::decimal::lenient_option")]
+  pub price: Option<Decimal>,
   pub side: Side,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DoneResponse {
   pub time: DateTime<Utc>,
   pub product_id: String,
   pub sequence: i64,
-  pub order_id: String,
+  pub order_id: OrderId,
   pub reason: FinishReason,
   pub side: Side,
 }
 
-// TODO consider if this is necessary.
-#[derive(Serialize, Deserialize, Debug)]
+/// Activation of a stop order of the user, sent as `activate` and converted to `active` when
+/// serialized back. Limit stops carry `size`, market stops `funds`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ActiveResponse {
-  pub time: DateTime<Utc>,
+  /// Not sent by the exchange, which only sends `timestamp`, see `activated_at`.
+  #[serde(default)]
+  pub time: Option<DateTime<Utc>>,
   pub product_id: String,
-  pub order_id: String,
+  pub order_id: OrderId,
   pub user_id: String,
   pub profile_id: String,
+  /// Seconds since the epoch with a fractional part, e.g. `"1483736448.299000"`.
   pub timestamp: String,
-  // Not really sure what this is
-  pub stop_type: String,
+  pub stop_type: StopType,
   pub side: Side,
-  pub stop_price: BigDecimal,
-  pub size: BigDecimal,
-  pub funds: BigDecimal,
+  #[serde(deserialize_with = "crate::decimal::lenient")]
+  pub stop_price: Decimal,
+  #[serde(default, deserialize_with = "crate::decimal::lenient_option")]
+  pub size: Option<Decimal>,
+  #[serde(default, deserialize_with = "crate::decimal::lenient_option")]
+  pub funds: Option<Decimal>,
+  #[serde(default, deserialize_with = "crate::decimal::lenient_option")]
+  pub taker_fee_rate: Option<Decimal>,
+  #[serde(default)]
+  pub private: bool,
+}
+
+impl ActiveResponse {
+  /// Time the stop was triggered, `time` if present, otherwise parsed from `timestamp`.
+  pub fn activated_at(&self) -> Option<DateTime<Utc>> {
+    if self.time.is_some() {
+      return self.time;
+    }
+    let seconds: f64 = self.timestamp.parse().ok()?;
+    let nanos = (seconds.fract() * 1e9).round() as u32;
+    DateTime::from_timestamp(seconds.trunc() as i64, nanos)
+  }
+}
+
+/// Update of the user's margin profile of a product, sent whenever its position, funding or
+/// margin call changes. `nonce` increases with every update of the profile.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MarginProfileUpdateResponse {
+  pub product_id: String,
+  pub timestamp: DateTime<Utc>,
+  pub user_id: String,
+  pub profile_id: String,
+  pub nonce: i64,
+  pub position: MarginPosition,
+  #[serde(deserialize_with = "crate::decimal::lenient")]
+  pub position_size: Decimal,
+  #[serde(deserialize_with = "crate::decimal::lenient")]
+  pub position_compliment: Decimal,
+  #[serde(deserialize_with = "crate::decimal::lenient")]
+  pub position_max_size: Decimal,
+  /// Side, price, size and funds of the order that is placed on a margin call.
+  pub call_side: Side,
+  #[serde(deserialize_with = "crate::decimal::lenient")]
+  pub call_price: Decimal,
+  #[serde(deserialize_with = "crate::decimal::lenient")]
+  pub call_size: Decimal,
+  #[serde(deserialize_with = "crate::decimal::lenient")]
+  pub call_funds: Decimal,
+  /// Whether the position is covered by the profile's funds.
+  pub covered: bool,
+  pub next_expire_time: Option<DateTime<Utc>>,
+  #[serde(deserialize_with = "crate::decimal::lenient")]
+  pub base_balance: Decimal,
+  #[serde(deserialize_with = "crate::decimal::lenient")]
+  pub base_funding: Decimal,
+  #[serde(deserialize_with = "crate::decimal::lenient")]
+  pub quote_balance: Decimal,
+  #[serde(deserialize_with = "crate::decimal::lenient")]
+  pub quote_funding: Decimal,
+  #[serde(default)]
   pub private: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LastMatchResponse {
   pub trade_id: i64,
-  pub maker_order_id: String,
-  pub taker_order_id: String,
+  pub maker_order_id: OrderId,
+  pub taker_order_id: OrderId,
   pub side: Side,
-  pub size: BigDecimal,
-  pub price: BigDecimal,
+  #[serde(deserialize_with = "crate::decimal::lenient")]
+  pub size: Decimal,
+  #[serde(deserialize_with = "crate::decimal::lenient")]
+  pub price: Decimal,
   pub product_id: String,
   pub sequence: i64,
   pub time: DateTime<Utc>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ErrorResponse {
   pub msg: String,
   pub extra: HashMap<String, Value>,
@@ -199,37 +397,44 @@ pub struct ErrorResponse {
 // Product                 //
 /////////////////////////////
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Product {
-  id: String,
-  base_currency: String,
-  quote_currency: String,
-  base_min_size: Option<BigDecimal>,
-  base_max_size: Option<BigDecimal>,
-  base_increment: Option<BigDecimal>,
-  quote_increment: Option<BigDecimal>,
-  display_name: String,
-  status: Option<String>,
-  status_message: Option<String>,
-  min_market_funds: Option<BigDecimal>,
-  max_market_funds: Option<BigDecimal>,
-  post_only: bool,
-  limit_only: bool,
-  cancel_only: Option<bool>,
+  pub id: String,
+  pub base_currency: String,
+  pub quote_currency: String,
+  pub base_min_size: Option<Decimal>,
+  pub base_max_size: Option<Decimal>,
+  pub base_increment: Option<Decimal>,
+  pub quote_increment: Option<Decimal>,
+  pub display_name: String,
+  pub status: Option<String>,
+  pub status_message: Option<String>,
+  pub min_market_funds: Option<Decimal>,
+  pub max_market_funds: Option<Decimal>,
+  pub post_only: bool,
+  pub limit_only: bool,
+  pub cancel_only: Option<bool>,
+}
+
+impl Product {
+  /// Product is tradable when it is online and not restricted to order cancellation only.
+  pub fn is_online(&self) -> bool {
+    self.status.as_deref() == Some("online") && !self.cancel_only.unwrap_or(false)
+  }
 }
 
 /////////////////////////////
 // Currency                //
 /////////////////////////////
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Currency {
   id: String,
   name: String,
-  min_size: BigDecimal,
+  min_size: Decimal,
   status: String,
   status_message: Option<String>,
-  max_precision: BigDecimal,
+  max_precision: Decimal,
   convertible_to: Vec<String>,
 }
 
@@ -237,11 +442,11 @@ pub struct Currency {
 // Change                  //
 /////////////////////////////
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Change {
-  side: Side,
-  price: BigDecimal,
-  size: BigDecimal,
+  pub side: Side,
+  pub price: Decimal,
+  pub size: Decimal,
 }
 
 impl Serialize for Change {
@@ -270,10 +475,12 @@ impl<'de> Deserialize<'de> for Change {
         A: SeqAccess<'de>, {
         let side = seq.next_element()?
           .ok_or_else(|| Error::invalid_length(0, &self))?;
-        let price = seq.next_element()?
-          .ok_or_else(|| Error::invalid_length(1, &self))?;
-        let size = seq.next_element()?
-          .ok_or_else(|| Error::invalid_length(2, &self))?;
+        let price = seq.next_element::<LenientDecimal>()?
+          .ok_or_else(|| Error::invalid_length(1, &self))?
+          .required()?;
+        let size = seq.next_element::<LenientDecimal>()?
+          .ok_or_else(|| Error::invalid_length(2, &self))?
+          .required()?;
         Ok(Change { side, price, size })
       }
     }
@@ -286,7 +493,7 @@ impl<'de> Deserialize<'de> for Change {
 mod test {
   use serde_json;
 
-  use super::ResponseMessages;
+  use super::{OrderId, ResponseMessages};
 
   #[test]
   fn deserialize_heartbeat_msg() -> Result<(), serde_json::error::Error> {
@@ -304,10 +511,10 @@ mod test {
         println!("Ok got: {:?}", resp);
       }
       _ => {
-        assert!(false)
+        panic!("Unexpected message type")
       }
     };
-    return Ok(());
+    Ok(())
   }
 
   #[test]
@@ -329,7 +536,7 @@ mod test {
     let ticker: ResponseMessages = serde_json::from_str(msg)?;
     match ticker {
       ResponseMessages::Ticker { resp: _ } => {},
-      _ => assert!(false)
+      _ => panic!("Unexpected message type")
     };
     Ok(())
   }
@@ -351,10 +558,10 @@ mod test {
     match done {
       ResponseMessages::Done { resp: _ } => {},
       _ => {
-        assert!(false);
+        panic!("Unexpected message type");
       }
     };
-    return Ok(());
+    Ok(())
   }
 
   #[test]
@@ -419,10 +626,10 @@ mod test {
     match serde_json::from_str(msg)? {
       ResponseMessages::Status { resp: _ } => {},
       _ => {
-        assert!(false)
+        panic!("Unexpected message type")
       }
     }
-    return Ok(());
+    Ok(())
   }
 
   #[test]
@@ -444,10 +651,10 @@ mod test {
     match serde_json::from_str(msg)? {
       ResponseMessages::Match { resp: _ } => {},
       _ => {
-        assert!(false);
+        panic!("Unexpected message type");
       }
     }
-    return Ok(());
+    Ok(())
   }
 
   #[test]
@@ -465,8 +672,150 @@ mod test {
     "#;
     match serde_json::from_str(msg)? {
       ResponseMessages::Done { resp: _ } => {},
-      _ => { assert!(false) }
+      _ => { panic!("Unexpected message type") }
     };
-    return Ok(());
+    Ok(())
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn test_activate() -> Result<(), serde_json::error::Error> {
+    use super::StopType;
+
+    let msg = r#"
+    {
+    "type":"activate",
+    "product_id":"BTC-USD",
+    "timestamp":"1483736448.299000",
+    "user_id":"12",
+    "profile_id":"30000727-d308-cf50-7b1c-c06deb1934fc",
+    "order_id":"7b52009b-64fd-0a2a-49e6-d8a939753077",
+    "stop_type":"entry",
+    "side":"buy",
+    "stop_price":"80",
+    "size":"2",
+    "funds":"50",
+    "taker_fee_rate":"0.0025",
+    "private":true
+    }
+    "#;
+    match serde_json::from_str(msg)? {
+      ResponseMessages::Active { resp } => {
+        assert_eq!(resp.stop_type, StopType::ENTRY);
+        assert_eq!(resp.activated_at().unwrap().timestamp_millis(), 1483736448299);
+      }
+      _ => { panic!("Unexpected message type") }
+    };
+    Ok(())
+  }
+
+  #[test]
+  fn test_margin_profile_update() -> Result<(), serde_json::error::Error> {
+    use super::MarginPosition;
+
+    let msg = r#"
+    {
+    "type":"margin_profile_update",
+    "product_id":"BTC-USD",
+    "timestamp":"2019-07-09T17:33:25.3Z",
+    "user_id":"534",
+    "profile_id":"7b52009b-64fd-0a2a-49e6-d8a939753077",
+    "nonce":1,
+    "position":"long",
+    "position_size":"12.8",
+    "position_compliment":"0",
+    "position_max_size":"30",
+    "call_side":"sell",
+    "call_price":"1231.11",
+    "call_size":"0.01",
+    "call_funds":"22.1",
+    "covered":true,
+    "next_expire_time":"2019-07-10T17:33:25.3Z",
+    "base_balance":"22.1",
+    "base_funding":"0",
+    "quote_balance":"0",
+    "quote_funding":"0",
+    "private":true
+    }
+    "#;
+    let message: ResponseMessages = serde_json::from_str(msg)?;
+    assert_eq!(message.kind(), "margin_profile_update");
+    match message {
+      ResponseMessages::Margin_Profile_Update { resp } => assert_eq!(resp.position, MarginPosition::LONG),
+      _ => { panic!("Unexpected message type") }
+    };
+    Ok(())
+  }
+
+  #[test]
+  fn json_round_trip() -> Result<(), serde_json::error::Error> {
+    let msg = r#"{"type":"l2update","product_id":"ETH-USD","time":"2020-08-31T14:37:46.291473Z","changes":[["buy","432.38","2.76195236"]]}"#;
+    let json = ResponseMessages::from_json(msg)?.to_json();
+    match ResponseMessages::from_json(&json)? {
+      ResponseMessages::L2Update { resp } => {
+        assert_eq!(resp.product_id, "ETH-USD");
+        assert_eq!(resp.changes[0].size, "2.76195236".parse().unwrap());
+      }
+      _ => panic!("Unexpected message type"),
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn lenient_decimals() -> Result<(), serde_json::error::Error> {
+    use crate::decimal::{self, Decimal};
+
+    let ticker = r#"{
+      "type": "ticker", "trade_id": 20153558, "sequence": 3262786978, "time": "2020-08-31T15:05:14.336755Z",
+      "product_id": "BTC-USD", "price": "4388.01", "side": "buy", "last_size": "0.03", "best_bid": "", "best_ask": null
+    }"#;
+    assert!(ResponseMessages::from_json(ticker).is_err());
+    decimal::take_replaced();
+
+    decimal::set_malformed_default(Some(Decimal::from(0)));
+    match ResponseMessages::from_json(ticker)? {
+      ResponseMessages::Ticker { resp } => assert_eq!(resp.best_ask, Decimal::from(0)),
+      _ => panic!("Unexpected message type"),
+    }
+    assert_eq!(decimal::take_replaced(), 2);
+    decimal::set_malformed_default(None);
+
+    let received = r#"{
+      "type": "received", "time": "2014-11-07T08:19:27.028459Z", "product_id": "BTC-USD", "sequence": 12,
+      "order_id": "dddec984-77a8-460a-b958-66f114b0de9b", "side": "sell", "order_type": "market", "size": "", "funds": "3000.234"
+    }"#;
+    match ResponseMessages::from_json(received)? {
+      ResponseMessages::Received { resp } => assert!(resp.size.is_none() && resp.price.is_none()),
+      _ => panic!("Unexpected message type"),
+    }
+    assert_eq!(decimal::take_replaced(), 1);
+    Ok(())
+  }
+
+  #[test]
+  fn reject_malformed_order_ids() {
+    let received = r#"{
+      "type": "received", "time": "2014-11-07T08:19:27.028459Z", "product_id": "BTC-USD", "sequence": 12,
+      "order_id": "not-a-uuid", "side": "sell", "order_type": "limit", "size": "1.0", "price": "300.00"
+    }"#;
+    assert!(ResponseMessages::from_json(received).is_err());
+
+    let trade = r#"{
+      "type": "match", "trade_id": 62995921, "maker_order_id": "125f1d3d-3100-41ce-9341",
+      "taker_order_id": "5b0a9f2d-3388-4fd4-a106-b96b1e6d302f", "side": "buy", "size": "1.9",
+      "price": "434.19", "product_id": "ETH-USD", "sequence": 10182385681, "time": "2020-08-31T15:05:14.336755Z"
+    }"#;
+    assert!(ResponseMessages::from_json(trade).is_err());
+  }
+
+  #[test]
+  fn order_id_round_trip() -> Result<(), serde_json::error::Error> {
+    let id: OrderId = "DDDEC984-77A8-460A-B958-66F114B0DE9B".parse().unwrap();
+    assert_eq!(id.to_string(), "dddec984-77a8-460a-b958-66f114b0de9b");
+    assert_eq!(id.to_string().parse::<OrderId>().unwrap(), id);
+
+    let json = serde_json::to_string(&id)?;
+    assert_eq!(json, r#""dddec984-77a8-460a-b958-66f114b0de9b""#);
+    assert_eq!(serde_json::from_str::<OrderId>(&json)?, id);
+    Ok(())
+  }
+}
@@ -0,0 +1,47 @@
+use chrono::{DateTime, NaiveDate, Utc};
+
+/// Tells when the day ends, for days that end `boundary` after UTC midnight.
+pub(crate) struct DayRollover {
+  boundary: chrono::Duration,
+  day: Option<NaiveDate>,
+}
+
+impl DayRollover {
+  pub(crate) fn new(boundary: chrono::Duration) -> Self {
+    DayRollover { boundary, day: None }
+  }
+
+  fn day_of(&self, time: DateTime<Utc>) -> NaiveDate {
+    (time - self.boundary).naive_utc().date()
+  }
+
+  /// Day that ended since the last call, the first call only notes the current day.
+  pub(crate) fn check(&mut self, now: DateTime<Utc>) -> Option<NaiveDate> {
+    let day = self.day_of(now);
+    match self.day.replace(day) {
+      Some(previous) if previous < day => Some(previous),
+      _ => None,
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use chrono::{DateTime, NaiveDate, Utc};
+
+  use super::DayRollover;
+
+  fn time(time: &str) -> DateTime<Utc> {
+    time.parse().unwrap()
+  }
+
+  #[test]
+  fn roll_over_at_boundary() {
+    // Days end at 22:00 UTC.
+    let mut rollover = DayRollover::new(chrono::Duration::hours(22));
+    assert_eq!(rollover.check(time("2020-08-31T21:59:59Z")), None);
+    assert_eq!(rollover.check(time("2020-08-31T21:59:59.9Z")), None);
+    assert_eq!(rollover.check(time("2020-08-31T22:00:00Z")), Some(NaiveDate::from_ymd_opt(2020, 8, 30).unwrap()));
+    assert_eq!(rollover.check(time("2020-09-01T00:00:00Z")), None);
+  }
+}
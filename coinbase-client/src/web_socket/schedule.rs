@@ -0,0 +1,212 @@
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use chrono::{DateTime, Datelike, NaiveTime, Utc, Weekday};
+use crossbeam::{RecvTimeoutError, Sender};
+
+use super::client::CoinbaseWebSocketClientController;
+use super::clock::{Clock, SystemClock};
+use super::common::Channel;
+
+const SCHEDULE_ID: &str = "Schedule";
+
+/// Time of day window in UTC, on the given days of the week. A window whose end is before its
+/// start runs over midnight and belongs to the day it starts on, so `Fri 22:00-02:00` ends on
+/// Saturday morning.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TimeWindow {
+  days: Vec<Weekday>,
+  start: NaiveTime,
+  end: NaiveTime,
+}
+
+impl TimeWindow {
+  pub fn new(days: &[Weekday], start: NaiveTime, end: NaiveTime) -> Self {
+    TimeWindow { days: days.to_vec(), start, end }
+  }
+
+  pub fn daily(start: NaiveTime, end: NaiveTime) -> Self {
+    TimeWindow::new(&[Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri, Weekday::Sat, Weekday::Sun], start, end)
+  }
+
+  /// Monday to Friday.
+  pub fn weekdays(start: NaiveTime, end: NaiveTime) -> Self {
+    TimeWindow::new(&[Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri], start, end)
+  }
+
+  pub fn contains(&self, time: DateTime<Utc>) -> bool {
+    let day = time.weekday();
+    let time = time.time();
+    if self.start <= self.end {
+      self.days.contains(&day) && self.start <= time && time < self.end
+    } else {
+      (self.days.contains(&day) && time >= self.start) || (self.days.contains(&day.pred()) && time < self.end)
+    }
+  }
+}
+
+struct Entry {
+  product_ids: Vec<String>,
+  channels: Vec<Channel>,
+  windows: Vec<TimeWindow>,
+  active: bool,
+}
+
+impl Entry {
+  fn is_due(&self, now: DateTime<Utc>) -> bool {
+    self.windows.iter().any(|window| window.contains(now))
+  }
+}
+
+/// Subscription change of a schedule entry.
+#[derive(Debug, Clone)]
+pub enum ScheduledChange {
+  Subscribe { product_ids: Vec<String>, channels: Vec<Channel> },
+  Unsubscribe { product_ids: Vec<String>, channels: Vec<Channel> },
+}
+
+/// Subscriptions that are only held during their time windows, e.g. level2 of a product on
+/// weekdays from 13:00 to 21:00 UTC to keep the recorded data small:
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use chrono::NaiveTime;
+/// use coinbase_client::web_socket::{CoinbaseWebSocketClient, CompositeCoinBaseWebSocketMessageHandler, Schedule, TimeWindow};
+/// use coinbase_client::web_socket::common::{Channel, Channels};
+///
+/// let mut client = CoinbaseWebSocketClient::production();
+/// client.start(CompositeCoinBaseWebSocketMessageHandler::new(vec![])).unwrap();
+/// let hours = TimeWindow::weekdays(NaiveTime::from_hms_opt(13, 0, 0).unwrap(), NaiveTime::from_hms_opt(21, 0, 0).unwrap());
+/// let handle = Schedule::new()
+///   .add(vec!["BTC-USD".into()], Channel::from_names(&[Channels::Level2]), vec![hours])
+///   .start(client.controller(), Duration::from_secs(10));
+/// ```
+///
+/// Entries are unsubscribed with the same products and channels they were subscribed with, so
+/// entries sharing a product and channel unsubscribe each other.
+pub struct Schedule {
+  entries: Vec<Entry>,
+  clock: Arc<dyn Clock>,
+}
+
+impl Default for Schedule {
+  fn default() -> Self {
+    Schedule { entries: Vec::new(), clock: Arc::new(SystemClock) }
+  }
+}
+
+impl Schedule {
+  pub fn new() -> Self {
+    Schedule::default()
+  }
+
+  /// Clock the started schedule checks its windows against.
+  pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+    self.clock = clock;
+    self
+  }
+
+  /// Adds subscription held while any of the windows contains the current time.
+  pub fn add(mut self, product_ids: Vec<String>, channels: Vec<Channel>, windows: Vec<TimeWindow>) -> Self {
+    self.entries.push(Entry { product_ids, channels, windows, active: false });
+    self
+  }
+
+  /// Changes needed at `now`, entries are considered subscribed after their changes are returned.
+  pub fn changes(&mut self, now: DateTime<Utc>) -> Vec<ScheduledChange> {
+    let mut changes = Vec::new();
+    for entry in self.entries.iter_mut() {
+      let due = entry.is_due(now);
+      if due == entry.active {
+        continue;
+      }
+      entry.active = due;
+      let product_ids = entry.product_ids.clone();
+      let channels = entry.channels.clone();
+      changes.push(if due {
+        ScheduledChange::Subscribe { product_ids, channels }
+      } else {
+        ScheduledChange::Unsubscribe { product_ids, channels }
+      });
+    }
+    changes
+  }
+
+  /// Checks the schedule every `check_interval` on a background thread and subscribes or
+  /// unsubscribes through the controller. Entries that are due are subscribed right away.
+  pub fn start(mut self, controller: CoinbaseWebSocketClientController, check_interval: Duration) -> ScheduleHandle {
+    let (stop, stopped) = crossbeam::bounded::<()>(1);
+    let apply = move |schedule: &mut Schedule| {
+      let now = schedule.clock.utc_now();
+      for change in schedule.changes(now) {
+        match change {
+          ScheduledChange::Subscribe { product_ids, channels } => {
+            tracing::info!(target: SCHEDULE_ID, "Subscribing to {:?} of {:?}", channels, product_ids);
+            controller.subscribe(product_ids, channels);
+          }
+          ScheduledChange::Unsubscribe { product_ids, channels } => {
+            tracing::info!(target: SCHEDULE_ID, "Unsubscribing from {:?} of {:?}", channels, product_ids);
+            controller.unsubscribe(product_ids, channels);
+          }
+        }
+      }
+    };
+    thread::Builder::new()
+      .name(SCHEDULE_ID.into())
+      .spawn(move || {
+        apply(&mut self);
+        // Wakes up on every check, stops once the handle is dropped.
+        while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(check_interval) {
+          apply(&mut self);
+        }
+      })
+      .expect("Could not spawn schedule thread");
+    ScheduleHandle { _stop: stop }
+  }
+}
+
+/// Running schedule, the schedule stops when the handle is dropped. Subscriptions are left as
+/// they are.
+pub struct ScheduleHandle {
+  _stop: Sender<()>,
+}
+
+#[cfg(test)]
+mod test {
+  use chrono::{DateTime, NaiveTime, Utc, Weekday};
+
+  use super::{Schedule, ScheduledChange, TimeWindow};
+  use crate::web_socket::common::{Channel, Channels};
+
+  fn at(time: &str) -> DateTime<Utc> {
+    time.parse().unwrap()
+  }
+
+  fn hm(hour: u32, minute: u32) -> NaiveTime {
+    NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+  }
+
+  #[test]
+  fn subscribe_within_windows() {
+    let overnight = TimeWindow::new(&[Weekday::Fri], hm(22, 0), hm(2, 0));
+    // 2021-01-08 is a Friday.
+    assert!(overnight.contains(at("2021-01-09T01:59:00Z")));
+    assert!(!overnight.contains(at("2021-01-08T01:00:00Z")));
+
+    let mut schedule = Schedule::new()
+      .add(vec!["BTC-USD".into()], Channel::from_names(&[Channels::Level2]), vec![TimeWindow::weekdays(hm(13, 0), hm(21, 0))]);
+    let kinds = |changes: Vec<ScheduledChange>| -> Vec<&'static str> {
+      changes.iter().map(|change| match change {
+        ScheduledChange::Subscribe { .. } => "subscribe",
+        ScheduledChange::Unsubscribe { .. } => "unsubscribe",
+      }).collect()
+    };
+    assert!(kinds(schedule.changes(at("2021-01-08T12:59:00Z"))).is_empty());
+    assert_eq!(kinds(schedule.changes(at("2021-01-08T13:00:00Z"))), vec!["subscribe"]);
+    assert!(kinds(schedule.changes(at("2021-01-08T20:00:00Z"))).is_empty());
+    assert_eq!(kinds(schedule.changes(at("2021-01-08T21:00:00Z"))), vec!["unsubscribe"]);
+    // Saturday.
+    assert!(kinds(schedule.changes(at("2021-01-09T14:00:00Z"))).is_empty());
+  }
+}
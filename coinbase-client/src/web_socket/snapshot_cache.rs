@@ -0,0 +1,45 @@
+use crate::order_book::OrderBooks;
+
+use super::response::{L2UpdateResponse, SnapshotResponse, StatusResponse};
+use super::{CoinBaseWebSocketMessageHandler, Terminate};
+
+/// Keeps the latest status message and the current order book of every level2 product,
+/// so they can be replayed to handlers that didn't see the original messages.
+#[derive(Debug, Default)]
+pub struct SnapshotCache {
+  books: OrderBooks,
+  status: Option<StatusResponse>,
+}
+
+impl SnapshotCache {
+  pub fn new() -> Self {
+    SnapshotCache { books: OrderBooks::new(), status: None }
+  }
+
+  /// Delivers the cached status and a snapshot of every cached book to the handler.
+  /// Snapshots reflect all updates received so far, not just the original snapshot message.
+  pub fn replay<H: CoinBaseWebSocketMessageHandler + ?Sized>(&self, handler: &mut H) -> Result<(), Terminate> {
+    if let Some(status) = &self.status {
+      handler.on_status(status)?;
+    }
+    for book in self.books.iter() {
+      handler.on_snapshot(&book.to_snapshot())?;
+    }
+    Ok(())
+  }
+}
+
+impl CoinBaseWebSocketMessageHandler for SnapshotCache {
+  fn on_status(&mut self, resp: &StatusResponse) -> Result<(), Terminate> {
+    self.status = Some(resp.clone());
+    Ok(())
+  }
+
+  fn on_snapshot(&mut self, resp: &SnapshotResponse) -> Result<(), Terminate> {
+    self.books.on_snapshot(resp)
+  }
+
+  fn on_l2_update(&mut self, resp: &L2UpdateResponse) -> Result<(), Terminate> {
+    self.books.on_l2_update(resp)
+  }
+}
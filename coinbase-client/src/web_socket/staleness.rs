@@ -0,0 +1,105 @@
+use std::collections::{BTreeSet, HashMap};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+
+use super::common::Channels;
+use super::subscriptions::Subscriptions;
+
+/// What the worker does with a product that stopped producing messages, on top of
+/// notifying handlers through `on_product_stale`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum StalePolicy {
+  /// Only notify the handlers.
+  Notify,
+  /// Unsubscribe and subscribe the product again, which also requests fresh snapshots. The
+  /// product is reported again if it stays quiet for another timeout.
+  Resubscribe,
+  /// Unsubscribe the product from all channels.
+  Unsubscribe,
+}
+
+struct LastSeen {
+  at: Instant,
+  wall_clock: DateTime<Utc>,
+  reported: bool,
+}
+
+/// Tracks when every subscribed product last produced a message. Heartbeats are sent for halted
+/// products as well, so they don't count, and products subscribed only to `heartbeat` are not
+/// monitored.
+pub(crate) struct StaleProductMonitor {
+  timeout: Duration,
+  last_seen: HashMap<String, LastSeen>,
+}
+
+impl StaleProductMonitor {
+  pub(crate) fn new(timeout: Duration) -> Self {
+    StaleProductMonitor { timeout, last_seen: HashMap::new() }
+  }
+
+  pub(crate) fn seen(&mut self, product_id: &str, at: Instant, wall_clock: DateTime<Utc>) {
+    if let Some(last_seen) = self.last_seen.get_mut(product_id) {
+      *last_seen = LastSeen { at, wall_clock, reported: false };
+    }
+  }
+
+  /// Products that became stale since the last call, with the time they were last seen.
+  /// Products that were just subscribed get the full timeout before they are reported.
+  pub(crate) fn stale(&mut self, subscriptions: &Subscriptions, now: Instant, wall_clock: DateTime<Utc>) -> Vec<(String, DateTime<Utc>)> {
+    let monitored: BTreeSet<String> = subscriptions.channels().into_iter()
+      .filter(|channel| *channel.name() != Channels::Heartbeat)
+      .flat_map(|channel| channel.product_ids().map(|ids| ids.to_vec()).unwrap_or_default())
+      .collect();
+    self.last_seen.retain(|product_id, _| monitored.contains(product_id));
+    for product_id in monitored {
+      self.last_seen.entry(product_id)
+        .or_insert_with(|| LastSeen { at: now, wall_clock, reported: false });
+    }
+
+    let timeout = self.timeout;
+    self.last_seen.iter_mut()
+      .filter(|(_, last_seen)| !last_seen.reported && now.duration_since(last_seen.at) >= timeout)
+      .map(|(product_id, last_seen)| {
+        last_seen.reported = true;
+        (product_id.clone(), last_seen.wall_clock)
+      })
+      .collect()
+  }
+
+  /// Gives the product a new timeout window, e.g. after it was subscribed again.
+  pub(crate) fn reset(&mut self, product_id: &str, now: Instant, wall_clock: DateTime<Utc>) {
+    self.last_seen.insert(product_id.into(), LastSeen { at: now, wall_clock, reported: false });
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::time::{Duration, Instant};
+
+  use chrono::Utc;
+
+  use super::StaleProductMonitor;
+  use crate::web_socket::common::{Channel, Channels};
+  use crate::web_socket::subscriptions::Subscriptions;
+
+  #[test]
+  fn report_quiet_products_once() {
+    let mut subscriptions = Subscriptions::new();
+    let product_ids = vec!["BTC-USD".to_string(), "ETH-USD".to_string()];
+    subscriptions.add(&product_ids, &Channel::from_names(&[Channels::Ticker]));
+    subscriptions.add(&["XRP-USD".to_string()], &Channel::from_names(&[Channels::Heartbeat]));
+
+    let start = Instant::now();
+    let wall_clock = Utc::now();
+    let mut monitor = StaleProductMonitor::new(Duration::from_secs(10));
+    assert!(monitor.stale(&subscriptions, start, wall_clock).is_empty());
+    monitor.seen("BTC-USD", start + Duration::from_secs(5), wall_clock);
+
+    let stale = monitor.stale(&subscriptions, start + Duration::from_secs(11), wall_clock);
+    assert_eq!(stale.iter().map(|(product_id, _)| product_id.as_str()).collect::<Vec<_>>(), vec!["ETH-USD"]);
+    assert_eq!(stale[0].1, wall_clock);
+    assert!(monitor.stale(&subscriptions, start + Duration::from_secs(12), wall_clock).is_empty());
+    assert_eq!(monitor.stale(&subscriptions, start + Duration::from_secs(16), wall_clock).len(), 1);
+  }
+}
@@ -0,0 +1,355 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+
+use super::clock::{Clock, SystemClock};
+use super::context::MessageContext;
+use super::response;
+use super::{CoinBaseWebSocketMessageHandler, Terminate};
+
+// Upper bounds of the interarrival buckets double from 64 µs up to about 67 s.
+const FIRST_BUCKET_MICROS: u64 = 64;
+const BUCKETS: usize = 21;
+
+/// Histogram of durations with exponential buckets, from 64 µs doubling up to about 67 s,
+/// and a last bucket for everything longer.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Histogram {
+  counts: [u64; BUCKETS + 1],
+  count: u64,
+  sum: Duration,
+  max: Duration,
+}
+
+impl Histogram {
+  fn bound(bucket: usize) -> Option<Duration> {
+    if bucket < BUCKETS {
+      Some(Duration::from_micros(FIRST_BUCKET_MICROS << bucket))
+    } else {
+      None
+    }
+  }
+
+  pub fn record(&mut self, duration: Duration) {
+    let bucket = (0..BUCKETS).find(|bucket| duration <= Histogram::bound(*bucket).unwrap()).unwrap_or(BUCKETS);
+    self.counts[bucket] += 1;
+    self.count += 1;
+    self.sum += duration;
+    self.max = self.max.max(duration);
+  }
+
+  pub fn count(&self) -> u64 {
+    self.count
+  }
+
+  pub fn mean(&self) -> Option<Duration> {
+    if self.count == 0 {
+      return None;
+    }
+    Some(self.sum.div_f64(self.count as f64))
+  }
+
+  pub fn max(&self) -> Duration {
+    self.max
+  }
+
+  /// Upper bound of the bucket holding the `quantile` (0 to 1) of the recorded durations,
+  /// the maximum for the last bucket.
+  pub fn quantile(&self, quantile: f64) -> Option<Duration> {
+    if self.count == 0 {
+      return None;
+    }
+    let rank = ((quantile.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+    let mut seen = 0;
+    for (bucket, count) in self.counts.iter().enumerate() {
+      seen += count;
+      if seen >= rank {
+        return Some(Histogram::bound(bucket).map_or(self.max, |bound| bound.min(self.max)));
+      }
+    }
+    Some(self.max)
+  }
+
+  /// Non empty buckets with their upper bound, `None` for the last one.
+  pub fn buckets(&self) -> impl Iterator<Item=(Option<Duration>, u64)> + '_ {
+    self.counts.iter().enumerate()
+      .filter(|(_, count)| **count > 0)
+      .map(|(bucket, count)| (Histogram::bound(bucket), *count))
+  }
+}
+
+/// Messages of one type and product received within a summary's interval.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MessageTypeStats {
+  pub count: u64,
+  /// Raw frame bytes.
+  pub bytes: u64,
+  pub max_bytes: usize,
+  /// Time between consecutive messages of the type and product.
+  pub interarrival: Histogram,
+}
+
+/// Statistics of the messages received since the previous summary, keyed by message type and
+/// product, `None` for messages without one like `subscriptions`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatsSummary {
+  pub start: DateTime<Utc>,
+  pub elapsed: Duration,
+  pub messages: BTreeMap<(String, Option<String>), MessageTypeStats>,
+}
+
+impl StatsSummary {
+  pub fn total_count(&self) -> u64 {
+    self.messages.values().map(|stats| stats.count).sum()
+  }
+
+  pub fn total_bytes(&self) -> u64 {
+    self.messages.values().map(|stats| stats.bytes).sum()
+  }
+
+  /// Messages per second of the type and product.
+  pub fn rate(&self, message_type: &str, product_id: Option<&str>) -> f64 {
+    let key = (message_type.to_string(), product_id.map(String::from));
+    let count = self.messages.get(&key).map_or(0, |stats| stats.count);
+    count as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+  }
+}
+
+/// Table with a line per message type and product.
+impl Display for StatsSummary {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    let secs = self.elapsed.as_secs_f64().max(f64::EPSILON);
+    writeln!(f, "{} messages, {} bytes in {:.1}s since {}", self.total_count(), self.total_bytes(), secs, self.start)?;
+    for ((message_type, product_id), stats) in &self.messages {
+      writeln!(
+        f, "{:<14} {:<12} {:>9.1} msg/s {:>11.1} B/s  max {:>7} B  gap p50 {:?} p99 {:?} max {:?}",
+        message_type, product_id.as_deref().unwrap_or("-"),
+        stats.count as f64 / secs, stats.bytes as f64 / secs, stats.max_bytes,
+        stats.interarrival.quantile(0.5).unwrap_or_default(),
+        stats.interarrival.quantile(0.99).unwrap_or_default(),
+        stats.interarrival.max(),
+      )?;
+    }
+    Ok(())
+  }
+}
+
+pub trait StatsSink {
+  fn on_stats(&mut self, summary: &StatsSummary) -> Result<(), Terminate>;
+}
+
+impl<F: FnMut(&StatsSummary) -> Result<(), Terminate>> StatsSink for F {
+  fn on_stats(&mut self, summary: &StatsSummary) -> Result<(), Terminate> {
+    self(summary)
+  }
+}
+
+/// Counts messages, their raw size and the time between them per message type and product,
+/// and hands a summary to the sink every `interval`. Run it on the channels you plan to record
+/// for a while to see the rates and sizes they bring.
+///
+/// The summary is emitted when the first message after the interval arrives, and on `flush`
+/// and `close`. Statistics start over with every summary.
+pub struct MessageStatsHandler<S: StatsSink> {
+  interval: Duration,
+  sink: S,
+  start: Instant,
+  start_time: DateTime<Utc>,
+  messages: BTreeMap<(String, Option<String>), MessageTypeStats>,
+  // Last message of every type and product, kept across summaries.
+  last_received: BTreeMap<(String, Option<String>), Instant>,
+  // Context of the message being delivered.
+  context: Option<MessageContext>,
+  clock: Arc<dyn Clock>,
+}
+
+impl<S: StatsSink> MessageStatsHandler<S> {
+  pub fn new(interval: Duration, sink: S) -> Self {
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+    MessageStatsHandler {
+      interval,
+      sink,
+      start: clock.now(),
+      start_time: clock.utc_now(),
+      messages: BTreeMap::new(),
+      last_received: BTreeMap::new(),
+      context: None,
+      clock,
+    }
+  }
+
+  /// Clock timing messages without a context and the summaries, e.g. the clock of a replay.
+  /// Statistics start over from the clock's current time.
+  pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+    self.start = clock.now();
+    self.start_time = clock.utc_now();
+    self.clock = clock;
+    self
+  }
+
+  /// Statistics since the last summary.
+  pub fn current(&self) -> StatsSummary {
+    StatsSummary { start: self.start_time, elapsed: self.clock.now().saturating_duration_since(self.start), messages: self.messages.clone() }
+  }
+
+  /// Emits the summary right away and starts over.
+  pub fn flush(&mut self) -> Result<(), Terminate> {
+    self.emit(self.clock.now())
+  }
+
+  fn emit(&mut self, now: Instant) -> Result<(), Terminate> {
+    let summary = StatsSummary {
+      start: self.start_time,
+      elapsed: now.saturating_duration_since(self.start),
+      messages: std::mem::take(&mut self.messages),
+    };
+    self.start = now;
+    self.start_time = self.clock.utc_now();
+    self.sink.on_stats(&summary)
+  }
+
+  fn record(&mut self, message_type: &str, product_id: Option<&str>) -> Result<(), Terminate> {
+    let (received_at, bytes) = match self.context.take() {
+      Some(ctx) => (ctx.received_at, ctx.raw_len),
+      None => (self.clock.now(), 0),
+    };
+    let key = (message_type.to_string(), product_id.map(String::from));
+    let stats = self.messages.entry(key.clone()).or_default();
+    stats.count += 1;
+    stats.bytes += bytes as u64;
+    stats.max_bytes = stats.max_bytes.max(bytes);
+    if let Some(last) = self.last_received.insert(key, received_at) {
+      stats.interarrival.record(received_at.saturating_duration_since(last));
+    }
+    if received_at.saturating_duration_since(self.start) >= self.interval {
+      self.emit(received_at)?;
+    }
+    Ok(())
+  }
+}
+
+impl<S: StatsSink> CoinBaseWebSocketMessageHandler for MessageStatsHandler<S> {
+  fn on_message_context(&mut self, ctx: &MessageContext) -> Result<(), Terminate> {
+    self.context = Some(*ctx);
+    Ok(())
+  }
+
+  fn on_subscriptions(&mut self, _resp: &response::SubscriptionResponse) -> Result<(), Terminate> {
+    self.record("subscriptions", None)
+  }
+
+  fn on_heartbeat(&mut self, resp: &response::HeartBeatResponse) -> Result<(), Terminate> {
+    self.record("heartbeat", Some(&resp.product_id))
+  }
+
+  fn on_status(&mut self, _resp: &response::StatusResponse) -> Result<(), Terminate> {
+    self.record("status", None)
+  }
+
+  fn on_ticker(&mut self, resp: &response::TickerResponse) -> Result<(), Terminate> {
+    self.record("ticker", Some(&resp.product_id))
+  }
+
+  fn on_snapshot(&mut self, resp: &response::SnapshotResponse) -> Result<(), Terminate> {
+    self.record("snapshot", Some(&resp.product_id))
+  }
+
+  fn on_l2_update(&mut self, resp: &response::L2UpdateResponse) -> Result<(), Terminate> {
+    self.record("l2update", Some(&resp.product_id))
+  }
+
+  fn on_match(&mut self, resp: &response::MatchResponse) -> Result<(), Terminate> {
+    self.record("match", Some(&resp.product_id))
+  }
+
+  fn on_received(&mut self, resp: &response::ReceivedResponse) -> Result<(), Terminate> {
+    self.record("received", Some(&resp.product_id))
+  }
+
+  fn on_open(&mut self, resp: &response::OpenResponse) -> Result<(), Terminate> {
+    self.record("open", Some(&resp.product_id))
+  }
+
+  fn on_change(&mut self, resp: &response::ChangeResponse) -> Result<(), Terminate> {
+    self.record("change", Some(&resp.product_id))
+  }
+
+  fn on_done(&mut self, resp: &response::DoneResponse) -> Result<(), Terminate> {
+    self.record("done", Some(&resp.product_id))
+  }
+
+  fn on_active(&mut self, resp: &response::ActiveResponse) -> Result<(), Terminate> {
+    self.record("active", Some(&resp.product_id))
+  }
+
+  fn on_margin_profile_update(&mut self, resp: &response::MarginProfileUpdateResponse) -> Result<(), Terminate> {
+    self.record("margin_profile_update", Some(&resp.product_id))
+  }
+
+  fn on_last_match(&mut self, resp: &response::LastMatchResponse) -> Result<(), Terminate> {
+    self.record("last_match", Some(&resp.product_id))
+  }
+
+  fn on_error(&mut self, _resp: &response::ErrorResponse) -> Result<(), Terminate> {
+    self.record("error", None)
+  }
+
+  fn close(&mut self) -> Result<(), Terminate> {
+    self.flush()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::time::{Duration, Instant};
+
+  use chrono::Utc;
+
+  use super::{Histogram, MessageStatsHandler, StatsSummary};
+  use crate::web_socket::context::MessageContext;
+  use crate::web_socket::response::HeartBeatResponse;
+  use crate::web_socket::CoinBaseWebSocketMessageHandler;
+
+  #[test]
+  fn summarize_per_type_and_product() -> Result<(), serde_json::error::Error> {
+    let mut histogram = Histogram::default();
+    for millis in &[1, 1, 2, 500] {
+      histogram.record(Duration::from_millis(*millis));
+    }
+    assert_eq!(histogram.quantile(0.5), Some(Duration::from_micros(1024)));
+    assert_eq!(histogram.quantile(1.0), Some(Duration::from_millis(500)));
+    assert_eq!(histogram.buckets().count(), 3);
+
+    let heartbeat: HeartBeatResponse = serde_json::from_str(r#"{
+      "sequence": 90, "last_trade_id": 20, "product_id": "BTC-USD", "time": "2014-11-07T08:19:28.464459Z"
+    }"#)?;
+    let mut summaries: Vec<StatsSummary> = Vec::new();
+    let mut handler = MessageStatsHandler::new(Duration::from_secs(60), |summary: &StatsSummary| {
+      summaries.push(summary.clone());
+      Ok(())
+    });
+    let start = Instant::now();
+    for (offset, raw_len) in &[(0, 100), (1, 120), (3, 90)] {
+      let ctx = MessageContext { received_at: start + Duration::from_secs(*offset), wall_clock: Utc::now(), connection_id: 1, raw_len: *raw_len };
+      handler.on_message_context(&ctx).unwrap();
+      handler.on_heartbeat(&heartbeat).unwrap();
+    }
+    let current = handler.current();
+    let stats = &current.messages[&("heartbeat".to_string(), Some("BTC-USD".to_string()))];
+    assert_eq!((stats.count, stats.bytes, stats.max_bytes), (3, 310, 120));
+    assert_eq!(stats.interarrival.max(), Duration::from_secs(2));
+
+    // The first message after the interval emits the summary.
+    let ctx = MessageContext { received_at: start + Duration::from_secs(61), wall_clock: Utc::now(), connection_id: 1, raw_len: 100 };
+    handler.on_message_context(&ctx).unwrap();
+    handler.on_heartbeat(&heartbeat).unwrap();
+    handler.close().unwrap();
+    drop(handler);
+    assert_eq!(summaries.len(), 2);
+    assert_eq!((summaries[0].total_count(), summaries[1].total_count()), (4, 0));
+    Ok(())
+  }
+}
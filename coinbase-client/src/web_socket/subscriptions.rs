@@ -0,0 +1,153 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::common::{Channel, Channels};
+
+/// Bookkeeping of active subscriptions as channel -> products.
+///
+/// Every change returns only the difference against the current state, so the worker
+/// can send minimal subscribe and unsubscribe messages. Channels that don't take
+/// products (e.g. status) are kept with an empty product set.
+#[derive(Debug, Default, Clone)]
+pub struct Subscriptions {
+  channels: BTreeMap<Channels, BTreeSet<String>>,
+}
+
+impl Subscriptions {
+  pub fn new() -> Self {
+    Subscriptions { channels: BTreeMap::new() }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.channels.is_empty()
+  }
+
+  pub fn product_ids(&self, channel: &Channels) -> Option<&BTreeSet<String>> {
+    self.channels.get(channel)
+  }
+
+  /// Adds subscriptions and returns channels with products that were not subscribed before.
+  /// Channel specific product ids take precedence over the request wide product ids.
+  pub fn add(&mut self, product_ids: &[String], channels: &[Channel]) -> Vec<Channel> {
+    let mut added = Vec::new();
+    for channel in channels {
+      let channel_product_ids = channel.product_ids().unwrap_or(product_ids);
+      let is_new_channel = !self.channels.contains_key(channel.name());
+      let subscribed = self.channels.entry(channel.name().clone()).or_default();
+      let new_product_ids: Vec<String> = channel_product_ids.iter()
+        .filter(|product_id| subscribed.insert((*product_id).clone()))
+        .cloned()
+        .collect();
+
+      if !new_product_ids.is_empty() {
+        added.push(Channel::with_product_ids(channel.name().clone(), new_product_ids));
+      } else if is_new_channel {
+        added.push(Channel::new(channel.name().clone()));
+      }
+    }
+    added
+  }
+
+  /// Removes subscriptions and returns channels with products that were actually subscribed.
+  /// Channel without any product ids is removed completely.
+  pub fn remove(&mut self, product_ids: &[String], channels: &[Channel]) -> Vec<Channel> {
+    let mut removed = Vec::new();
+    for channel in channels {
+      let channel_product_ids = channel.product_ids().unwrap_or(product_ids);
+      if channel_product_ids.is_empty() {
+        if self.channels.remove(channel.name()).is_some() {
+          removed.push(Channel::new(channel.name().clone()));
+        }
+        continue;
+      }
+
+      let subscribed = match self.channels.get_mut(channel.name()) {
+        Some(subscribed) => subscribed,
+        None => continue,
+      };
+      let removed_product_ids: Vec<String> = channel_product_ids.iter()
+        .filter(|product_id| subscribed.remove(*product_id))
+        .cloned()
+        .collect();
+      if subscribed.is_empty() {
+        self.channels.remove(channel.name());
+      }
+      if !removed_product_ids.is_empty() {
+        removed.push(Channel::with_product_ids(channel.name().clone(), removed_product_ids));
+      }
+    }
+    removed
+  }
+
+  /// Channels the product is subscribed to, each with only that product.
+  pub fn channels_of(&self, product_id: &str) -> Vec<Channel> {
+    self.channels.iter()
+      .filter(|(_, product_ids)| product_ids.contains(product_id))
+      .map(|(name, _)| Channel::with_product_ids(name.clone(), vec![product_id.to_string()]))
+      .collect()
+  }
+
+  /// Complete subscription set, each channel with its own products.
+  pub fn channels(&self) -> Vec<Channel> {
+    self.channels.iter()
+      .map(|(name, product_ids)| {
+        if product_ids.is_empty() {
+          Channel::new(name.clone())
+        } else {
+          Channel::with_product_ids(name.clone(), product_ids.iter().cloned().collect())
+        }
+      })
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::web_socket::common::{Channel, Channels};
+
+  use super::Subscriptions;
+
+  fn products(ids: &[&str]) -> Vec<String> {
+    ids.iter().map(|id| id.to_string()).collect()
+  }
+
+  #[test]
+  fn add_returns_only_new_subscriptions() {
+    let mut subscriptions = Subscriptions::new();
+    let added = subscriptions.add(&products(&["BTC-USD"]), &Channel::from_names(&[Channels::Ticker, Channels::Status]));
+    assert_eq!(added.len(), 2);
+
+    let added = subscriptions.add(&products(&["BTC-USD", "ETH-USD"]), &Channel::from_names(&[Channels::Ticker]));
+    assert_eq!(added, vec![Channel::with_product_ids(Channels::Ticker, products(&["ETH-USD"]))]);
+
+    let added = subscriptions.add(&products(&["BTC-USD"]), &Channel::from_names(&[Channels::Ticker]));
+    assert!(added.is_empty());
+  }
+
+  #[test]
+  fn remove_keeps_channel_for_remaining_products() {
+    let mut subscriptions = Subscriptions::new();
+    subscriptions.add(&products(&["BTC-USD", "ETH-USD"]), &Channel::from_names(&[Channels::Ticker, Channels::Matches]));
+
+    let removed = subscriptions.remove(&products(&["BTC-USD", "LTC-USD"]), &Channel::from_names(&[Channels::Ticker]));
+    assert_eq!(removed, vec![Channel::with_product_ids(Channels::Ticker, products(&["BTC-USD"]))]);
+    assert_eq!(subscriptions.product_ids(&Channels::Ticker).unwrap().len(), 1);
+    assert_eq!(subscriptions.product_ids(&Channels::Matches).unwrap().len(), 2);
+
+    let removed = subscriptions.remove(&[], &Channel::from_names(&[Channels::Matches]));
+    assert_eq!(removed, vec![Channel::new(Channels::Matches)]);
+    assert!(subscriptions.product_ids(&Channels::Matches).is_none());
+  }
+
+  #[test]
+  fn channel_product_ids_take_precedence() {
+    let mut subscriptions = Subscriptions::new();
+    subscriptions.add(&products(&["BTC-USD"]), &[
+      Channel::new(Channels::Ticker),
+      Channel::with_product_ids(Channels::Level2, products(&["ETH-USD"])),
+    ]);
+    assert_eq!(subscriptions.channels(), vec![
+      Channel::with_product_ids(Channels::Ticker, products(&["BTC-USD"])),
+      Channel::with_product_ids(Channels::Level2, products(&["ETH-USD"])),
+    ]);
+  }
+}
@@ -0,0 +1,401 @@
+use std::collections::HashSet;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use chrono::{DateTime, NaiveDate, Utc};
+use crossbeam::{Sender, TrySendError};
+
+use crate::rest;
+
+use super::anomalies::DataAnomaly;
+use super::context::MessageContext;
+use super::handler::dispatch;
+use super::product_status::ProductStatusChange;
+use super::reconnect::ReconnectStorm;
+use super::response::{self, ResponseMessages};
+use super::worker_thread::WorkerThread;
+use super::{CoinBaseWebSocketMessageHandler, Terminate};
+
+const THREADED_HANDLER_ID: &str = "ThreadedHandler";
+
+// Dropped calls between two warnings.
+const DROP_LOG_INTERVAL: u64 = 1000;
+
+const MESSAGE_TYPES: &[&str] = &[
+  "subscriptions", "heartbeat", "status", "ticker", "snapshot", "l2update", "match", "received", "open", "change",
+  "done", "active", "activate", "margin_profile_update", "last_match", "error",
+];
+
+/// Owned copy of a handler callback, sent to the handler thread.
+enum Call {
+  Initialize,
+  MessageContext(MessageContext),
+  Message(ResponseMessages),
+  // Frame and the text of the error, the error itself isn't `Clone`.
+  ParseError(String, String),
+  BackfilledTrade(String, rest::Trade),
+  Pong(Duration),
+  ProductStale(String, DateTime<Utc>),
+  ProductStatusChange(ProductStatusChange),
+  MissedTrades(String, i64, i64),
+  DataAnomaly(DataAnomaly),
+  ReconnectStorm(ReconnectStorm),
+  DayRollover(NaiveDate),
+  Close,
+}
+
+impl Call {
+  fn apply<H: CoinBaseWebSocketMessageHandler>(self, handler: &mut H) -> Result<(), Terminate> {
+    match self {
+      Call::Initialize => handler.initialize(),
+      Call::MessageContext(ctx) => handler.on_message_context(&ctx),
+      Call::Message(message) => dispatch(handler, &message),
+      // Displays the same text as the original error, without its category and position.
+      Call::ParseError(raw, err) => handler.on_parse_error(&raw, &serde::de::Error::custom(err)),
+      Call::BackfilledTrade(product_id, trade) => handler.on_backfilled_trade(&product_id, &trade),
+      Call::Pong(round_trip_time) => handler.on_pong(round_trip_time),
+      Call::ProductStale(product_id, last_seen) => handler.on_product_stale(&product_id, last_seen),
+      Call::ProductStatusChange(change) => handler.on_product_status_change(&change),
+      Call::MissedTrades(product_id, from, to) => handler.on_missed_trades(&product_id, from, to),
+      Call::DataAnomaly(anomaly) => handler.on_data_anomaly(&anomaly),
+      Call::ReconnectStorm(storm) => handler.on_reconnect_storm(&storm),
+      Call::DayRollover(date) => handler.on_day_rollover(date),
+      Call::Close => handler.close(),
+    }
+  }
+}
+
+/// Runs a handler on its own thread behind a bounded queue, so a slow handler (e.g. writing into
+/// a database) doesn't hold back the other handlers of the feed. Callbacks are copied into the
+/// queue and the wrapped handler sees them in the same order.
+///
+/// Calls that find the queue full wait for space, or are dropped and counted with
+/// `block_when_full(false)`. Once the wrapped handler returns `Terminate` its thread exits and
+/// the next call returns `Terminate` too. `close` waits until the queue is drained.
+pub struct ThreadedHandler {
+  sender: Option<Sender<Call>>,
+  join_handle: Option<JoinHandle<()>>,
+  terminated: Arc<AtomicBool>,
+  dropped: Arc<AtomicU64>,
+  wanted: HashSet<&'static str>,
+  block_when_full: bool,
+}
+
+impl ThreadedHandler {
+  pub fn new<H>(handler: H, capacity: usize) -> io::Result<Self>
+    where H: CoinBaseWebSocketMessageHandler + Send + 'static {
+    ThreadedHandler::with_thread(handler, capacity, WorkerThread::named(THREADED_HANDLER_ID))
+  }
+
+  /// Runs the handler on a thread with the given name, core and priority.
+  pub fn with_thread<H>(mut handler: H, capacity: usize, thread: WorkerThread) -> io::Result<Self>
+    where H: CoinBaseWebSocketMessageHandler + Send + 'static {
+    let wanted = MESSAGE_TYPES.iter().copied().filter(|message_type| handler.wants_message_type(message_type)).collect();
+    let (sender, receiver) = crossbeam::bounded::<Call>(capacity);
+    let terminated = Arc::new(AtomicBool::new(false));
+    let thread_terminated = terminated.clone();
+    let name = thread.name().to_string();
+    let join_handle = thread.spawn(move || {
+      // Exits once the handler terminates or the sender is dropped.
+      for call in receiver.iter() {
+        if call.apply(&mut handler).is_err() {
+          tracing::info!(target: THREADED_HANDLER_ID, "Handler on {} terminated.", name);
+          thread_terminated.store(true, Ordering::Relaxed);
+          return;
+        }
+      }
+    })?;
+    Ok(ThreadedHandler {
+      sender: Some(sender),
+      join_handle: Some(join_handle),
+      terminated,
+      dropped: Arc::new(AtomicU64::new(0)),
+      wanted,
+      block_when_full: true,
+    })
+  }
+
+  /// Whether calls wait for space in the queue, which slows down the other handlers when this
+  /// one can't keep up, enabled by default. Otherwise they are dropped and logged every thousand
+  /// drops, see `dropped`.
+  pub fn block_when_full(mut self, block: bool) -> Self {
+    self.block_when_full = block;
+    self
+  }
+
+  /// Number of calls dropped because the queue was full.
+  pub fn dropped(&self) -> u64 {
+    self.dropped.load(Ordering::Relaxed)
+  }
+
+  fn send(&mut self, call: Call) -> Result<(), Terminate> {
+    let sender = match &self.sender {
+      Some(sender) if !self.terminated.load(Ordering::Relaxed) => sender,
+      _ => return Err(Terminate),
+    };
+    if self.block_when_full {
+      return sender.send(call).map_err(|_| Terminate);
+    }
+    match sender.try_send(call) {
+      Ok(()) => Ok(()),
+      Err(TrySendError::Full(_)) => {
+        let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        if dropped % DROP_LOG_INTERVAL == 1 {
+          tracing::warn!(target: THREADED_HANDLER_ID, "Handler can't keep up, dropped {} calls so far.", dropped);
+        }
+        Ok(())
+      }
+      Err(TrySendError::Disconnected(_)) => Err(Terminate),
+    }
+  }
+
+  fn message(&mut self, message: ResponseMessages) -> Result<(), Terminate> {
+    self.send(Call::Message(message))
+  }
+
+  /// Closes the queue and waits until the thread handled everything in it.
+  fn join(&mut self) -> Result<(), Terminate> {
+    self.sender = None;
+    if let Some(join_handle) = self.join_handle.take() {
+      if join_handle.join().is_err() {
+        tracing::error!(target: THREADED_HANDLER_ID, "Handler thread panicked.");
+        self.terminated.store(true, Ordering::Relaxed);
+      }
+    }
+    if self.terminated.load(Ordering::Relaxed) { Err(Terminate) } else { Ok(()) }
+  }
+}
+
+impl Drop for ThreadedHandler {
+  fn drop(&mut self) {
+    let _ = self.join();
+  }
+}
+
+impl CoinBaseWebSocketMessageHandler for ThreadedHandler {
+  fn initialize(&mut self) -> Result<(), Terminate> {
+    self.send(Call::Initialize)
+  }
+
+  fn on_message_context(&mut self, ctx: &MessageContext) -> Result<(), Terminate> {
+    self.send(Call::MessageContext(*ctx))
+  }
+
+  fn on_subscriptions(&mut self, resp: &response::SubscriptionResponse) -> Result<(), Terminate> {
+    self.message(ResponseMessages::Subscriptions { resp: resp.clone() })
+  }
+
+  fn on_heartbeat(&mut self, resp: &response::HeartBeatResponse) -> Result<(), Terminate> {
+    self.message(ResponseMessages::Heartbeat { resp: resp.clone() })
+  }
+
+  fn on_status(&mut self, resp: &response::StatusResponse) -> Result<(), Terminate> {
+    self.message(ResponseMessages::Status { resp: resp.clone() })
+  }
+
+  fn on_ticker(&mut self, resp: &response::TickerResponse) -> Result<(), Terminate> {
+    self.message(ResponseMessages::Ticker { resp: resp.clone() })
+  }
+
+  fn on_snapshot(&mut self, resp: &response::SnapshotResponse) -> Result<(), Terminate> {
+    self.message(ResponseMessages::Snapshot { resp: resp.clone() })
+  }
+
+  fn on_l2_update(&mut self, resp: &response::L2UpdateResponse) -> Result<(), Terminate> {
+    self.message(ResponseMessages::L2Update { resp: resp.clone() })
+  }
+
+  fn on_match(&mut self, resp: &response::MatchResponse) -> Result<(), Terminate> {
+    self.message(ResponseMessages::Match { resp: resp.clone() })
+  }
+
+  fn on_received(&mut self, resp: &response::ReceivedResponse) -> Result<(), Terminate> {
+    self.message(ResponseMessages::Received { resp: resp.clone() })
+  }
+
+  fn on_open(&mut self, resp: &response::OpenResponse) -> Result<(), Terminate> {
+    self.message(ResponseMessages::Open { resp: resp.clone() })
+  }
+
+  fn on_change(&mut self, resp: &response::ChangeResponse) -> Result<(), Terminate> {
+    self.message(ResponseMessages::Change { resp: resp.clone() })
+  }
+
+  fn on_done(&mut self, resp: &response::DoneResponse) -> Result<(), Terminate> {
+    self.message(ResponseMessages::Done { resp: resp.clone() })
+  }
+
+  fn on_active(&mut self, resp: &response::ActiveResponse) -> Result<(), Terminate> {
+    self.message(ResponseMessages::Active { resp: resp.clone() })
+  }
+
+  fn on_margin_profile_update(&mut self, resp: &response::MarginProfileUpdateResponse) -> Result<(), Terminate> {
+    self.message(ResponseMessages::Margin_Profile_Update { resp: Box::new(resp.clone()) })
+  }
+
+  fn on_last_match(&mut self, resp: &response::LastMatchResponse) -> Result<(), Terminate> {
+    self.message(ResponseMessages::Last_Match { resp: resp.clone() })
+  }
+
+  fn on_error(&mut self, resp: &response::ErrorResponse) -> Result<(), Terminate> {
+    self.message(ResponseMessages::Error { resp: resp.clone() })
+  }
+
+  fn on_parse_error(&mut self, raw: &str, err: &serde_json::Error) -> Result<(), Terminate> {
+    self.send(Call::ParseError(raw.into(), err.to_string()))
+  }
+
+  fn on_backfilled_trade(&mut self, product_id: &str, trade: &rest::Trade) -> Result<(), Terminate> {
+    self.send(Call::BackfilledTrade(product_id.into(), trade.clone()))
+  }
+
+  fn on_pong(&mut self, round_trip_time: Duration) -> Result<(), Terminate> {
+    self.send(Call::Pong(round_trip_time))
+  }
+
+  fn on_product_stale(&mut self, product_id: &str, last_seen: DateTime<Utc>) -> Result<(), Terminate> {
+    self.send(Call::ProductStale(product_id.into(), last_seen))
+  }
+
+  fn on_product_status_change(&mut self, change: &ProductStatusChange) -> Result<(), Terminate> {
+    self.send(Call::ProductStatusChange(change.clone()))
+  }
+
+  fn on_missed_trades(&mut self, product_id: &str, from_trade_id: i64, to_trade_id: i64) -> Result<(), Terminate> {
+    self.send(Call::MissedTrades(product_id.into(), from_trade_id, to_trade_id))
+  }
+
+  fn on_data_anomaly(&mut self, anomaly: &DataAnomaly) -> Result<(), Terminate> {
+    self.send(Call::DataAnomaly(anomaly.clone()))
+  }
+
+  fn on_reconnect_storm(&mut self, storm: &ReconnectStorm) -> Result<(), Terminate> {
+    self.send(Call::ReconnectStorm(storm.clone()))
+  }
+
+  fn on_day_rollover(&mut self, date: NaiveDate) -> Result<(), Terminate> {
+    self.send(Call::DayRollover(date))
+  }
+
+  fn close(&mut self) -> Result<(), Terminate> {
+    let sender = match &self.sender {
+      Some(sender) => sender,
+      None => return Err(Terminate),
+    };
+    // Close always waits for space, so the handler gets to flush whatever it holds.
+    let _ = sender.send(Call::Close);
+    self.join()
+  }
+
+  fn wants_message_type(&self, message_type: &str) -> bool {
+    self.wanted.contains(message_type) || !MESSAGE_TYPES.contains(&message_type)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::sync::{Arc, Mutex};
+
+  use crossbeam::{Receiver, Sender};
+
+  use super::ThreadedHandler;
+  use crate::web_socket::response::HeartBeatResponse;
+  use crate::web_socket::{
+    dispatch, CoinBaseWebSocketMessageHandler, CompositeCoinBaseWebSocketMessageHandler, ResponseMessages, Terminate,
+  };
+
+  /// Records heartbeat sequences, each heartbeat waits until the test releases it.
+  struct Heartbeats {
+    sequences: Arc<Mutex<Vec<i64>>>,
+    started: Sender<i64>,
+    release: Receiver<()>,
+  }
+
+  impl CoinBaseWebSocketMessageHandler for Heartbeats {
+    fn on_heartbeat(&mut self, resp: &HeartBeatResponse) -> Result<(), Terminate> {
+      let _ = self.started.send(resp.sequence);
+      let _ = self.release.recv();
+      self.sequences.lock().unwrap().push(resp.sequence);
+      Ok(())
+    }
+  }
+
+  struct ParseErrors(Arc<Mutex<Vec<(String, String)>>>);
+
+  impl CoinBaseWebSocketMessageHandler for ParseErrors {
+    fn on_parse_error(&mut self, raw: &str, err: &serde_json::Error) -> Result<(), Terminate> {
+      self.0.lock().unwrap().push((raw.into(), err.to_string()));
+      Ok(())
+    }
+  }
+
+  /// Test side of `Heartbeats`, dropping `release` lets all heartbeats through.
+  struct Gate {
+    sequences: Arc<Mutex<Vec<i64>>>,
+    started: Receiver<i64>,
+    release: Sender<()>,
+  }
+
+  fn heartbeats() -> (Heartbeats, Gate) {
+    let sequences = Arc::new(Mutex::new(Vec::new()));
+    let (started, started_receiver) = crossbeam::unbounded();
+    let (release_sender, release) = crossbeam::unbounded();
+    let gate = Gate { sequences: sequences.clone(), started: started_receiver, release: release_sender };
+    (Heartbeats { sequences, started, release }, gate)
+  }
+
+  fn heartbeat(sequence: i64) -> ResponseMessages {
+    let heartbeat = format!(
+      r#"{{"type":"heartbeat","last_trade_id":1,"product_id":"BTC-USD","sequence":{},"time":"2020-08-31T15:15:01.044966Z"}}"#,
+      sequence
+    );
+    serde_json::from_str(&heartbeat).unwrap()
+  }
+
+  #[test]
+  fn threaded_handler_keeps_order() {
+    let (handler, gate) = heartbeats();
+    let mut composite = CompositeCoinBaseWebSocketMessageHandler::new(vec![]);
+    composite.add_threaded_handler(handler, 100).unwrap();
+
+    for sequence in 0..20 {
+      dispatch(&mut composite, &heartbeat(sequence)).unwrap();
+    }
+    // Dispatching doesn't wait for the handler, which is still held at the first heartbeat.
+    assert_eq!(gate.started.recv().unwrap(), 0);
+    assert!(gate.sequences.lock().unwrap().is_empty());
+    drop(gate.release);
+    composite.close().unwrap();
+    assert_eq!(*gate.sequences.lock().unwrap(), (0..20).collect::<Vec<_>>());
+  }
+
+  #[test]
+  fn drop_calls_when_full_unless_blocking() {
+    let (handler, gate) = heartbeats();
+    let mut threaded = ThreadedHandler::new(handler, 1).unwrap().block_when_full(false);
+    dispatch(&mut threaded, &heartbeat(0)).unwrap();
+    assert_eq!(gate.started.recv().unwrap(), 0);
+    // First one waits in the queue, the rest find it full.
+    for sequence in 1..4 {
+      dispatch(&mut threaded, &heartbeat(sequence)).unwrap();
+    }
+    assert_eq!(threaded.dropped(), 2);
+    drop(gate.release);
+    threaded.close().unwrap();
+    assert_eq!(*gate.sequences.lock().unwrap(), vec![0, 1]);
+  }
+
+  #[test]
+  fn forward_parse_errors() {
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    let mut threaded = ThreadedHandler::new(ParseErrors(errors.clone()), 10).unwrap();
+    let raw = r#"{"type":"heartbeat","sequence":"one"}"#;
+    let err = serde_json::from_str::<ResponseMessages>(raw).unwrap_err();
+    threaded.on_parse_error(raw, &err).unwrap();
+    threaded.close().unwrap();
+    assert_eq!(*errors.lock().unwrap(), vec![(raw.to_string(), err.to_string())]);
+  }
+}
@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, NaiveDate, Utc};
+
+use crate::rest;
+
+use super::anomalies::DataAnomaly;
+use super::clock::{Clock, SystemClock};
+use super::context::MessageContext;
+use super::product_status::ProductStatusChange;
+use super::reconnect::ReconnectStorm;
+use super::response;
+use super::{CoinBaseWebSocketMessageHandler, Terminate};
+
+#[derive(Default)]
+struct ProductTicker {
+  last_delivery: Option<Instant>,
+  // Latest ticker received since the last delivery.
+  pending: Option<response::TickerResponse>,
+}
+
+/// Handler decorator that delivers tickers of every product at most once per `interval`, for
+/// consumers like dashboards that only need the latest state. Tickers received within the
+/// interval replace each other and the latest one is delivered once the interval is over.
+///
+/// Held back tickers are delivered when the next message arrives, of any product, subscribe to
+/// `heartbeat` to bound the delay. All other messages are passed through immediately.
+pub struct ThrottlingHandler<H: CoinBaseWebSocketMessageHandler> {
+  inner: H,
+  interval: Duration,
+  products: HashMap<String, ProductTicker>,
+  // Earliest time a held back ticker may be delivered.
+  next_due: Option<Instant>,
+  clock: Arc<dyn Clock>,
+}
+
+impl<H: CoinBaseWebSocketMessageHandler> ThrottlingHandler<H> {
+  pub fn new(inner: H, interval: Duration) -> Self {
+    ThrottlingHandler { inner, interval, products: HashMap::new(), next_due: None, clock: Arc::new(SystemClock) }
+  }
+
+  /// Clock for tickers and heartbeats, which are not preceded by a message context outside
+  /// of the client, e.g. in replays.
+  pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+    self.clock = clock;
+    self
+  }
+
+  pub fn inner(&self) -> &H {
+    &self.inner
+  }
+
+  pub fn into_inner(self) -> H {
+    self.inner
+  }
+
+  /// Number of products with a held back ticker.
+  pub fn pending(&self) -> usize {
+    self.products.values().filter(|product| product.pending.is_some()).count()
+  }
+
+  fn accept(&mut self, resp: &response::TickerResponse, now: Instant) -> Result<(), Terminate> {
+    let interval = self.interval;
+    let product = self.products.entry(resp.product_id.clone()).or_default();
+    match product.last_delivery {
+      Some(last_delivery) if now < last_delivery + interval => {
+        product.pending = Some(resp.clone());
+        let due = last_delivery + interval;
+        self.next_due = Some(self.next_due.map_or(due, |next_due| next_due.min(due)));
+        Ok(())
+      }
+      _ => {
+        product.pending = None;
+        product.last_delivery = Some(now);
+        self.inner.on_ticker(resp)
+      }
+    }
+  }
+
+  /// Delivers held back tickers whose interval is over.
+  fn release_due(&mut self, now: Instant) -> Result<(), Terminate> {
+    if self.next_due.is_none_or(|next_due| now < next_due) {
+      return Ok(());
+    }
+    self.next_due = None;
+    for product in self.products.values_mut() {
+      let last_delivery = match (product.last_delivery, &product.pending) {
+        (Some(last_delivery), Some(_)) => last_delivery,
+        _ => continue,
+      };
+      let due = last_delivery + self.interval;
+      if now < due {
+        self.next_due = Some(self.next_due.map_or(due, |next_due| next_due.min(due)));
+        continue;
+      }
+      let ticker = product.pending.take().unwrap();
+      product.last_delivery = Some(now);
+      self.inner.on_ticker(&ticker)?;
+    }
+    Ok(())
+  }
+
+  /// Delivers all held back tickers regardless of the interval.
+  pub fn flush(&mut self) -> Result<(), Terminate> {
+    let now = self.clock.now();
+    self.next_due = None;
+    for product in self.products.values_mut() {
+      if let Some(ticker) = product.pending.take() {
+        product.last_delivery = Some(now);
+        self.inner.on_ticker(&ticker)?;
+      }
+    }
+    Ok(())
+  }
+}
+
+impl<H: CoinBaseWebSocketMessageHandler> CoinBaseWebSocketMessageHandler for ThrottlingHandler<H> {
+  fn initialize(&mut self) -> Result<(), Terminate> {
+    self.inner.initialize()
+  }
+
+  fn on_message_context(&mut self, ctx: &MessageContext) -> Result<(), Terminate> {
+    self.release_due(ctx.received_at)?;
+    self.inner.on_message_context(ctx)
+  }
+
+  fn on_subscriptions(&mut self, resp: &response::SubscriptionResponse) -> Result<(), Terminate> {
+    self.inner.on_subscriptions(resp)
+  }
+
+  fn on_heartbeat(&mut self, resp: &response::HeartBeatResponse) -> Result<(), Terminate> {
+    self.release_due(self.clock.now())?;
+    self.inner.on_heartbeat(resp)
+  }
+
+  fn on_status(&mut self, resp: &response::StatusResponse) -> Result<(), Terminate> {
+    self.inner.on_status(resp)
+  }
+
+  fn on_ticker(&mut self, resp: &response::TickerResponse) -> Result<(), Terminate> {
+    let now = self.clock.now();
+    self.release_due(now)?;
+    self.accept(resp, now)
+  }
+
+  fn on_snapshot(&mut self, resp: &response::SnapshotResponse) -> Result<(), Terminate> {
+    self.inner.on_snapshot(resp)
+  }
+
+  fn on_l2_update(&mut self, resp: &response::L2UpdateResponse) -> Result<(), Terminate> {
+    self.inner.on_l2_update(resp)
+  }
+
+  fn on_match(&mut self, resp: &response::MatchResponse) -> Result<(), Terminate> {
+    self.inner.on_match(resp)
+  }
+
+  fn on_received(&mut self, resp: &response::ReceivedResponse) -> Result<(), Terminate> {
+    self.inner.on_received(resp)
+  }
+
+  fn on_open(&mut self, resp: &response::OpenResponse) -> Result<(), Terminate> {
+    self.inner.on_open(resp)
+  }
+
+  fn on_change(&mut self, resp: &response::ChangeResponse) -> Result<(), Terminate> {
+    self.inner.on_change(resp)
+  }
+
+  fn on_done(&mut self, resp: &response::DoneResponse) -> Result<(), Terminate> {
+    self.inner.on_done(resp)
+  }
+
+  fn on_active(&mut self, resp: &response::ActiveResponse) -> Result<(), Terminate> {
+    self.inner.on_active(resp)
+  }
+
+  fn on_margin_profile_update(&mut self, resp: &response::MarginProfileUpdateResponse) -> Result<(), Terminate> {
+    self.inner.on_margin_profile_update(resp)
+  }
+
+  fn on_last_match(&mut self, resp: &response::LastMatchResponse) -> Result<(), Terminate> {
+    self.inner.on_last_match(resp)
+  }
+
+  fn on_error(&mut self, resp: &response::ErrorResponse) -> Result<(), Terminate> {
+    self.inner.on_error(resp)
+  }
+
+  fn on_parse_error(&mut self, raw: &str, err: &serde_json::Error) -> Result<(), Terminate> {
+    self.inner.on_parse_error(raw, err)
+  }
+
+  fn on_backfilled_trade(&mut self, product_id: &str, trade: &rest::Trade) -> Result<(), Terminate> {
+    self.inner.on_backfilled_trade(product_id, trade)
+  }
+
+  fn on_pong(&mut self, round_trip_time: Duration) -> Result<(), Terminate> {
+    self.inner.on_pong(round_trip_time)
+  }
+
+  fn on_product_stale(&mut self, product_id: &str, last_seen: DateTime<Utc>) -> Result<(), Terminate> {
+    self.inner.on_product_stale(product_id, last_seen)
+  }
+
+  fn on_product_status_change(&mut self, change: &ProductStatusChange) -> Result<(), Terminate> {
+    self.inner.on_product_status_change(change)
+  }
+
+  fn on_missed_trades(&mut self, product_id: &str, from_trade_id: i64, to_trade_id: i64) -> Result<(), Terminate> {
+    self.inner.on_missed_trades(product_id, from_trade_id, to_trade_id)
+  }
+
+  fn on_data_anomaly(&mut self, anomaly: &DataAnomaly) -> Result<(), Terminate> {
+    self.inner.on_data_anomaly(anomaly)
+  }
+
+  fn on_reconnect_storm(&mut self, storm: &ReconnectStorm) -> Result<(), Terminate> {
+    self.inner.on_reconnect_storm(storm)
+  }
+
+  fn on_day_rollover(&mut self, date: NaiveDate) -> Result<(), Terminate> {
+    self.inner.on_day_rollover(date)
+  }
+
+  fn close(&mut self) -> Result<(), Terminate> {
+    self.flush()?;
+    self.inner.close()
+  }
+
+  fn wants_message_type(&self, message_type: &str) -> bool {
+    self.inner.wants_message_type(message_type)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::sync::Arc;
+  use std::time::Duration;
+
+  use chrono::Utc;
+
+  use super::ThrottlingHandler;
+  use crate::web_socket::clock::MockClock;
+  use crate::web_socket::response::{HeartBeatResponse, TickerResponse};
+  use crate::web_socket::{CoinBaseWebSocketMessageHandler, Terminate};
+
+  #[derive(Default)]
+  struct Tickers(Vec<(String, i64)>);
+
+  impl CoinBaseWebSocketMessageHandler for Tickers {
+    fn on_ticker(&mut self, resp: &TickerResponse) -> Result<(), Terminate> {
+      self.0.push((resp.product_id.clone(), resp.sequence));
+      Ok(())
+    }
+  }
+
+  fn ticker(product_id: &str, sequence: i64) -> Result<TickerResponse, serde_json::error::Error> {
+    serde_json::from_str(&format!(r#"{{
+      "trade_id": 20153558, "sequence": {}, "time": "2017-09-02T17:05:49.250000Z",
+      "product_id": "{}", "price": "4388.01", "side": "buy", "last_size": "0.03",
+      "best_bid": "4388", "best_ask": "4388.01"
+    }}"#, sequence, product_id))
+  }
+
+  #[test]
+  fn deliver_latest_ticker_per_interval() -> Result<(), serde_json::error::Error> {
+    let clock = MockClock::new(Utc::now());
+    let mut handler = ThrottlingHandler::new(Tickers::default(), Duration::from_millis(50)).with_clock(Arc::new(clock.clone()));
+    for sequence in 1..=3 {
+      handler.on_ticker(&ticker("BTC-USD", sequence)?).unwrap();
+    }
+    handler.on_ticker(&ticker("ETH-USD", 10)?).unwrap();
+    assert_eq!(handler.pending(), 1);
+
+    clock.advance(Duration::from_millis(60));
+    let heartbeat: HeartBeatResponse = serde_json::from_str(r#"{
+      "sequence": 90, "last_trade_id": 20, "product_id": "BTC-USD", "time": "2014-11-07T08:19:28.464459Z"
+    }"#)?;
+    handler.on_heartbeat(&heartbeat).unwrap();
+    handler.on_ticker(&ticker("BTC-USD", 4)?).unwrap();
+    handler.close().unwrap();
+
+    let delivered: Vec<_> = handler.into_inner().0.into_iter().map(|(product, sequence)| format!("{}:{}", product, sequence)).collect();
+    assert_eq!(delivered, vec!["BTC-USD:1", "ETH-USD:10", "BTC-USD:3", "BTC-USD:4"]);
+    Ok(())
+  }
+}
@@ -0,0 +1,55 @@
+use std::collections::HashMap;
+
+/// Compares the `last_trade_id` of heartbeats with the last trade delivered from the `matches`
+/// (or `full`) channel. Trade ids of a product are consecutive, so a heartbeat that is ahead of
+/// the last delivered trade means the trades in between were lost.
+#[derive(Debug, Default)]
+pub(crate) struct TradeGapDetector {
+  last_trade_ids: HashMap<String, i64>,
+}
+
+impl TradeGapDetector {
+  pub(crate) fn new() -> Self {
+    TradeGapDetector::default()
+  }
+
+  pub(crate) fn on_trade(&mut self, product_id: &str, trade_id: i64) {
+    match self.last_trade_ids.get_mut(product_id) {
+      Some(last_trade_id) => *last_trade_id = (*last_trade_id).max(trade_id),
+      None => {
+        self.last_trade_ids.insert(product_id.into(), trade_id);
+      }
+    }
+  }
+
+  /// Returns the inclusive range of missed trade ids, if any. The first heartbeat of a product
+  /// only sets the baseline. The missed trades count as seen afterwards, so a gap is reported once.
+  pub(crate) fn on_heartbeat(&mut self, product_id: &str, last_trade_id: i64) -> Option<(i64, i64)> {
+    let seen = self.last_trade_ids.entry(product_id.into()).or_insert(last_trade_id);
+    if last_trade_id <= *seen {
+      return None;
+    }
+    let gap = (*seen + 1, last_trade_id);
+    *seen = last_trade_id;
+    Some(gap)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::TradeGapDetector;
+
+  #[test]
+  fn report_trades_missing_before_heartbeat() {
+    let mut detector = TradeGapDetector::new();
+    assert_eq!(detector.on_heartbeat("ETH-USD", 100), None);
+    detector.on_trade("ETH-USD", 101);
+    detector.on_trade("ETH-USD", 102);
+    assert_eq!(detector.on_heartbeat("ETH-USD", 102), None);
+    assert_eq!(detector.on_heartbeat("ETH-USD", 105), Some((103, 105)));
+    assert_eq!(detector.on_heartbeat("ETH-USD", 105), None);
+    // Late trade doesn't move the last seen id back.
+    detector.on_trade("ETH-USD", 104);
+    assert_eq!(detector.on_heartbeat("ETH-USD", 106), Some((106, 106)));
+  }
+}
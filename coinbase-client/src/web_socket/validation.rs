@@ -0,0 +1,107 @@
+use std::collections::HashSet;
+
+use thiserror::Error;
+
+use crate::rest::RestError;
+
+use super::common::{Channel, Channels};
+
+/// Channel that can't be subscribed in the requested form.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct InvalidChannel {
+  pub channel: Channels,
+  pub reason: &'static str,
+}
+
+#[derive(Error, Debug)]
+pub enum SubscriptionError {
+  #[error("Invalid subscription, unknown products: {unknown_products:?}, invalid channels: {invalid_channels:?}")]
+  Invalid { unknown_products: Vec<String>, invalid_channels: Vec<InvalidChannel> },
+
+  #[error("Invalid product spec {spec:?}, expected a product id, a glob like *-USD, base:<currency> or quote:<currency>")]
+  InvalidPattern { spec: String },
+
+  #[error("Could not fetch products to validate the subscription: {0}")]
+  Rest(#[from] RestError),
+
+  #[error("Client is not running and already holds {capacity} subscription requests for start")]
+  PendingFull { capacity: usize },
+
+  #[error("Command queue of the worker is full, it holds {capacity} commands")]
+  QueueFull { capacity: usize },
+
+  #[error("Worker stopped while the request was sent")]
+  Disconnected,
+}
+
+/// Checks the subscription against the list of known products before anything is sent,
+/// so typos are reported with the offending entries instead of a generic server error.
+/// The `user` channel is valid only on `authenticated` connections.
+pub fn validate_subscription(
+  known_products: &HashSet<String>,
+  product_ids: &[String],
+  channels: &[Channel],
+  authenticated: bool,
+) -> Result<(), SubscriptionError> {
+  let mut unknown_products: Vec<String> = Vec::new();
+  let mut invalid_channels = Vec::new();
+
+  for channel in channels {
+    let channel_product_ids = channel.product_ids().unwrap_or(product_ids);
+    for product_id in channel_product_ids {
+      if !known_products.contains(product_id) && !unknown_products.contains(product_id) {
+        unknown_products.push(product_id.clone());
+      }
+    }
+
+    let reason = match channel.name() {
+      // Status is sent for all products, product ids are ignored.
+      Channels::Status => None,
+      Channels::User if !authenticated => Some("requires an authenticated connection"),
+      _ if channel_product_ids.is_empty() => Some("requires at least one product id"),
+      _ => None,
+    };
+    if let Some(reason) = reason {
+      invalid_channels.push(InvalidChannel { channel: channel.name().clone(), reason });
+    }
+  }
+
+  if unknown_products.is_empty() && invalid_channels.is_empty() {
+    Ok(())
+  } else {
+    Err(SubscriptionError::Invalid { unknown_products, invalid_channels })
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::collections::HashSet;
+
+  use super::{validate_subscription, InvalidChannel, SubscriptionError};
+  use crate::web_socket::common::{Channel, Channels};
+
+  #[test]
+  fn report_invalid_entries() {
+    let known: HashSet<String> = vec!["BTC-USD".to_string(), "ETH-USD".to_string()].into_iter().collect();
+    let product_ids = vec!["BTC-USD".to_string(), "BTC-USDD".to_string()];
+    let channels = vec![
+      Channel::new(Channels::Ticker),
+      Channel::new(Channels::Status),
+      Channel::with_product_ids(Channels::Level2, vec![]),
+    ];
+
+    assert!(validate_subscription(&known, &product_ids[..1], &channels[..2], false).is_ok());
+    let user = vec![Channel::new(Channels::User)];
+    assert!(validate_subscription(&known, &product_ids[..1], &user, false).is_err());
+    assert!(validate_subscription(&known, &product_ids[..1], &user, true).is_ok());
+    match validate_subscription(&known, &product_ids, &channels, false) {
+      Err(SubscriptionError::Invalid { unknown_products, invalid_channels }) => {
+        assert_eq!(unknown_products, vec!["BTC-USDD".to_string()]);
+        assert_eq!(invalid_channels, vec![
+          InvalidChannel { channel: Channels::Level2, reason: "requires at least one product id" }
+        ]);
+      }
+      _ => panic!("Subscription should be invalid"),
+    }
+  }
+}
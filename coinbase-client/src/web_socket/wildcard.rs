@@ -0,0 +1,131 @@
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use super::common::Channel;
+use super::validation::SubscriptionError;
+
+/// Products a subscription spec refers to, see `CoinbaseWebSocketClientController::subscribe_matching`.
+///
+/// Parsed from a product id (`BTC-USD`), a glob on product ids where `*` matches any text
+/// (`*-USD`, `BTC-*`, `*`), or a currency the products are quoted in (`quote:EUR`) or trade
+/// (`base:BTC`). Specs are case insensitive.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum ProductPattern {
+  Product(String),
+  Glob(String),
+  Base(String),
+  Quote(String),
+}
+
+impl ProductPattern {
+  pub fn matches(&self, product_id: &str) -> bool {
+    let (base, quote) = product_id.split_once('-').unwrap_or((product_id, ""));
+    match self {
+      ProductPattern::Product(id) => id == product_id,
+      ProductPattern::Glob(glob) => glob_matches(glob, product_id),
+      ProductPattern::Base(currency) => currency == base,
+      ProductPattern::Quote(currency) => currency == quote,
+    }
+  }
+
+  /// Whether products listed later can match too.
+  pub fn is_wildcard(&self) -> bool {
+    !matches!(self, ProductPattern::Product(_))
+  }
+}
+
+fn glob_matches(glob: &str, text: &str) -> bool {
+  let mut parts = glob.split('*');
+  let first = parts.next().unwrap_or_default();
+  let mut rest = match text.strip_prefix(first) {
+    Some(rest) => rest,
+    None => return false,
+  };
+  let parts: Vec<&str> = parts.collect();
+  let (last, middle) = match parts.split_last() {
+    Some(split) => split,
+    // No `*` at all.
+    None => return rest.is_empty(),
+  };
+  for part in middle {
+    match rest.find(part) {
+      Some(index) => rest = &rest[index + part.len()..],
+      None => return false,
+    }
+  }
+  rest.ends_with(last)
+}
+
+impl FromStr for ProductPattern {
+  type Err = SubscriptionError;
+
+  fn from_str(spec: &str) -> Result<Self, Self::Err> {
+    let spec = spec.trim().to_uppercase();
+    let pattern = if let Some(currency) = spec.strip_prefix("QUOTE:") {
+      ProductPattern::Quote(currency.into())
+    } else if let Some(currency) = spec.strip_prefix("BASE:") {
+      ProductPattern::Base(currency.into())
+    } else if spec.contains('*') {
+      ProductPattern::Glob(spec.clone())
+    } else {
+      ProductPattern::Product(spec.clone())
+    };
+    match &pattern {
+      ProductPattern::Product(value) | ProductPattern::Glob(value) | ProductPattern::Base(value) | ProductPattern::Quote(value)
+        if value.is_empty() || value.contains(':') => Err(SubscriptionError::InvalidPattern { spec }),
+      _ => Ok(pattern),
+    }
+  }
+}
+
+impl Display for ProductPattern {
+  fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+    match self {
+      ProductPattern::Product(id) => f.write_str(id),
+      ProductPattern::Glob(glob) => f.write_str(glob),
+      ProductPattern::Base(currency) => write!(f, "base:{}", currency),
+      ProductPattern::Quote(currency) => write!(f, "quote:{}", currency),
+    }
+  }
+}
+
+/// Product ids matching any of the patterns, sorted.
+pub fn expand<'a, I: IntoIterator<Item=&'a str>>(patterns: &[ProductPattern], product_ids: I) -> Vec<String> {
+  let mut matched: Vec<String> = product_ids.into_iter()
+    .filter(|product_id| patterns.iter().any(|pattern| pattern.matches(product_id)))
+    .map(String::from)
+    .collect();
+  matched.sort();
+  matched.dedup();
+  matched
+}
+
+/// Channels the worker subscribes for every product coming online that matches the patterns.
+#[derive(Debug, Clone)]
+pub(crate) struct WildcardSubscription {
+  pub(crate) patterns: Vec<ProductPattern>,
+  pub(crate) channels: Vec<Channel>,
+}
+
+impl WildcardSubscription {
+  pub(crate) fn matches(&self, product_id: &str) -> bool {
+    self.patterns.iter().any(|pattern| pattern.is_wildcard() && pattern.matches(product_id))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{expand, ProductPattern};
+
+  #[test]
+  fn match_product_specs() {
+    let patterns: Vec<ProductPattern> = ["*-usd", "quote:EUR", "BTC-*C", "ETH-BTC"].iter().map(|spec| spec.parse().unwrap()).collect();
+    assert_eq!(patterns[1], ProductPattern::Quote("EUR".into()));
+    assert!(!patterns[3].is_wildcard());
+    let products = ["BTC-USD", "ETH-USD", "ETH-EUR", "ETH-BTC", "BTC-USDC", "LTC-GBP", "USD-GBP"];
+    assert_eq!(expand(&patterns, products.iter().copied()), vec!["BTC-USD", "BTC-USDC", "ETH-BTC", "ETH-EUR", "ETH-USD"]);
+    assert!("base:BTC".parse::<ProductPattern>().unwrap().matches("BTC-GBP"));
+    assert!("quote:".parse::<ProductPattern>().is_err());
+    assert!("*".parse::<ProductPattern>().unwrap().matches("LTC-GBP"));
+  }
+}
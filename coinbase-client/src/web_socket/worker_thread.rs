@@ -0,0 +1,102 @@
+use std::io;
+use std::thread::{self, JoinHandle};
+
+#[cfg(feature = "affinity")]
+const WORKER_THREAD_ID: &str = "WorkerThread";
+
+/// Settings of the thread the client's worker runs on, see `CoinbaseWebSocketClient::worker_thread`.
+///
+/// Names are shown by `top -H` and profilers, Linux cuts them to 15 bytes. Pinning and priority
+/// need the `affinity` feature, they are applied by the worker itself and only logged when the
+/// system refuses them.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct WorkerThread {
+  name: String,
+  #[cfg(feature = "affinity")]
+  core: Option<usize>,
+  #[cfg(feature = "affinity")]
+  nice: Option<i32>,
+}
+
+impl WorkerThread {
+  pub fn named(name: &str) -> Self {
+    WorkerThread {
+      name: name.into(),
+      #[cfg(feature = "affinity")]
+      core: None,
+      #[cfg(feature = "affinity")]
+      nice: None,
+    }
+  }
+
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  /// Runs the worker only on the given core, ids as listed by `core_affinity::get_core_ids`.
+  #[cfg(feature = "affinity")]
+  pub fn pin_to_core(mut self, core: usize) -> Self {
+    self.core = Some(core);
+    self
+  }
+
+  /// Niceness of the worker thread, from -20 (highest priority, needs `CAP_SYS_NICE`) to 19.
+  /// Only applied on Linux.
+  #[cfg(feature = "affinity")]
+  pub fn nice(mut self, nice: i32) -> Self {
+    self.nice = Some(nice);
+    self
+  }
+
+  pub(crate) fn spawn<F, T>(&self, f: F) -> io::Result<JoinHandle<T>>
+    where F: FnOnce() -> T + Send + 'static,
+          T: Send + 'static {
+    let settings = self.clone();
+    thread::Builder::new().name(self.name.clone()).spawn(move || {
+      settings.apply();
+      f()
+    })
+  }
+
+  #[cfg(not(feature = "affinity"))]
+  fn apply(&self) {}
+
+  #[cfg(feature = "affinity")]
+  fn apply(&self) {
+    if let Some(core) = self.core {
+      if !core_affinity::set_for_current(core_affinity::CoreId { id: core }) {
+        tracing::warn!(target: WORKER_THREAD_ID, "Could not pin {} to core {}.", self.name, core);
+      }
+    }
+    if let Some(nice) = self.nice {
+      if let Err(err) = set_nice(nice) {
+        tracing::warn!(target: WORKER_THREAD_ID, "Could not set niceness of {} to {}: {}", self.name, nice, err);
+      }
+    }
+  }
+}
+
+#[cfg(all(feature = "affinity", target_os = "linux"))]
+fn set_nice(nice: i32) -> io::Result<()> {
+  // Linux applies the niceness of a thread id to that thread only.
+  let result = unsafe { libc::setpriority(libc::PRIO_PROCESS, libc::gettid() as libc::id_t, nice) };
+  if result == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+#[cfg(all(feature = "affinity", not(target_os = "linux")))]
+fn set_nice(_nice: i32) -> io::Result<()> {
+  Err(io::Error::new(io::ErrorKind::Unsupported, "thread niceness is only supported on Linux"))
+}
+
+#[cfg(test)]
+mod test {
+  use super::WorkerThread;
+
+  #[test]
+  fn spawn_named_thread() {
+    let handle = WorkerThread::named("coinbase-ws-0")
+      .spawn(|| std::thread::current().name().map(String::from))
+      .unwrap();
+    assert_eq!(handle.join().unwrap().as_deref(), Some("coinbase-ws-0"));
+  }
+}
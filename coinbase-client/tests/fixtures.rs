@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+use coinbase_client::analytics::{Candle, CandleAggregator};
+use coinbase_client::conversion::CurrencyConverter;
+use coinbase_client::order_book::OrderBooks;
+use coinbase_client::testing::{fixtures, run_fixture};
+use coinbase_client::web_socket::response::HeartBeatResponse;
+use coinbase_client::web_socket::{CoinBaseWebSocketMessageHandler, Terminate};
+
+#[test]
+fn every_fixture_parses() {
+  for name in fixtures() {
+    let run = run_fixture(name, &mut OrderBooks::new()).unwrap();
+    assert_eq!(run.messages.get("subscriptions"), Some(&1), "fixture {}", name);
+    assert!(run.delivered > 1, "fixture {}", name);
+  }
+  assert!(run_fixture("unknown", &mut OrderBooks::new()).is_err());
+}
+
+#[test]
+fn level2_into_order_books() {
+  let mut books = OrderBooks::new();
+  let run = run_fixture("level2", &mut books).unwrap();
+  assert_eq!(run.messages.get("l2update"), Some(&4));
+
+  let book = books.get("ETH-USD").unwrap();
+  assert_eq!(book.best_bid().unwrap().price, "432.42".parse().unwrap());
+  assert_eq!(book.best_ask().unwrap().price, "432.48".parse().unwrap());
+  assert_eq!(book.best_ask().unwrap().size, "0.25".parse().unwrap());
+}
+
+#[test]
+fn matches_into_candles() {
+  let mut candles: Vec<Candle> = Vec::new();
+  let mut aggregator = CandleAggregator::new(Duration::from_secs(60), |candle: &Candle| {
+    candles.push(candle.clone());
+    Ok(())
+  });
+  run_fixture("matches", &mut aggregator).unwrap();
+  drop(aggregator);
+
+  assert_eq!(candles.len(), 2);
+  assert_eq!(candles[0].trades, 2);
+  assert_eq!(candles[0].volume, "2.15".parse().unwrap());
+  assert_eq!(candles[1].close, "434.13".parse().unwrap());
+}
+
+#[test]
+fn status_into_converter() {
+  let mut converter = CurrencyConverter::new();
+  run_fixture("status", &mut converter).unwrap();
+  run_fixture("ticker", &mut converter).unwrap();
+  assert_eq!(converter.rate("ETH", "USD"), Some("432.355".parse().unwrap()));
+}
+
+#[test]
+fn stop_when_handler_terminates() {
+  struct FirstHeartbeat;
+
+  impl CoinBaseWebSocketMessageHandler for FirstHeartbeat {
+    fn on_heartbeat(&mut self, _: &HeartBeatResponse) -> Result<(), Terminate> {
+      Err(Terminate)
+    }
+  }
+
+  let run = run_fixture("heartbeat", &mut FirstHeartbeat).unwrap();
+  assert_eq!(run.delivered, 2);
+  assert_eq!(run.messages.get("heartbeat"), Some(&1));
+}
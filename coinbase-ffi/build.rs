@@ -0,0 +1,32 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+fn main() {
+  let crate_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+  let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+  println!("cargo:rerun-if-changed=src/lib.rs");
+  println!("cargo:rerun-if-changed=cbindgen.toml");
+
+  let config = cbindgen::Config::from_file(crate_dir.join("cbindgen.toml")).expect("Invalid cbindgen.toml");
+  let bindings = match cbindgen::generate_with_config(&crate_dir, config) {
+    Ok(bindings) => bindings,
+    // Keep the committed header when the sources can't be parsed, rustc reports the actual error.
+    Err(err) => {
+      println!("cargo:warning=Could not generate C header: {}", err);
+      return;
+    }
+  };
+
+  // The header is generated into `OUT_DIR` and the committed one is only touched when the
+  // generated content differs, so builds don't dirty the working tree.
+  let generated = out_dir.join("coinbase.h");
+  bindings.write_to_file(&generated);
+  let header = crate_dir.join("include").join("coinbase.h");
+  let content = fs::read(&generated).expect("Generated header can't be read");
+  if fs::read(&header).ok().as_ref() != Some(&content) {
+    fs::create_dir_all(header.parent().unwrap()).expect("Can't create the include directory");
+    fs::write(&header, content).expect("Can't write the C header");
+    println!("cargo:warning=Updated include/coinbase.h, commit it with the API change.");
+  }
+}
@@ -0,0 +1,120 @@
+#ifndef COINBASE_FFI_H
+#define COINBASE_FFI_H
+
+/* Generated by cbindgen from coinbase-ffi, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Opaque handle of a running client.
+ */
+typedef struct CoinbaseWsClient CoinbaseWsClient;
+
+/**
+ * Receives every message as JSON (NUL terminated, `len` bytes without the terminator) together
+ * with its `type`. Returning non-zero stops the client.
+ */
+typedef int (*CoinbaseJsonCallback)(void *user_data,
+                                    const char *message_type,
+                                    const char *json,
+                                    uintptr_t len);
+
+/**
+ * Top of the book and last trade, from the `ticker` channel.
+ */
+typedef struct CoinbaseTicker {
+  const char *product_id;
+  int64_t sequence;
+  int64_t trade_id;
+  /**
+   * Microseconds since the Unix epoch.
+   */
+  int64_t time_us;
+  double price;
+  double last_size;
+  double best_bid;
+  double best_ask;
+  /**
+   * 0 for buy, 1 for sell.
+   */
+  uint8_t side;
+} CoinbaseTicker;
+
+typedef int (*CoinbaseTickerCallback)(void *user_data, const struct CoinbaseTicker *ticker);
+
+/**
+ * Trade from the `matches` or `full` channel.
+ */
+typedef struct CoinbaseMatch {
+  const char *product_id;
+  int64_t sequence;
+  int64_t trade_id;
+  /**
+   * Microseconds since the Unix epoch.
+   */
+  int64_t time_us;
+  double price;
+  double size;
+  /**
+   * 0 for buy, 1 for sell.
+   */
+  uint8_t side;
+} CoinbaseMatch;
+
+typedef int (*CoinbaseMatchCallback)(void *user_data, const struct CoinbaseMatch *trade);
+
+/**
+ * Callbacks are optional. `on_json` receives every message, including those also delivered
+ * to `on_ticker` or `on_match`, unless the packed callback stopped the client. Pointers passed
+ * to callbacks are valid only for the duration of the call.
+ */
+typedef struct CoinbaseCallbacks {
+  void *user_data;
+  CoinbaseJsonCallback on_json;
+  CoinbaseTickerCallback on_ticker;
+  CoinbaseMatchCallback on_match;
+} CoinbaseCallbacks;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Starts a production client subscribed to `channels` (e.g. "ticker", "matches") for the given
+ * products. Returns NULL when a product id or channel name is invalid, the client could not
+ * be started or the subscription could not be sent. The returned client must be released with
+ * `coinbase_ws_stop`.
+ *
+ * # Safety
+ *
+ * `product_ids` must point to `product_ids_len` and `channels` to `channels_len` valid NUL
+ * terminated strings, either may be NULL when its length is zero. The strings are copied before
+ * the call returns. Callbacks are called on the client's worker thread until `coinbase_ws_stop`
+ * returns, so they and `user_data` must be safe to use from that thread and `user_data` must
+ * stay valid until then.
+ */
+struct CoinbaseWsClient *coinbase_ws_start(const char *const *product_ids,
+                                           uintptr_t product_ids_len,
+                                           const char *const *channels,
+                                           uintptr_t channels_len,
+                                           struct CoinbaseCallbacks callbacks);
+
+/**
+ * Stops the client, waits for the worker thread and frees the client. After this returns no
+ * callback is called anymore.
+ *
+ * # Safety
+ *
+ * `client` must be NULL or a pointer returned by `coinbase_ws_start` that wasn't stopped yet,
+ * it is invalid after this returns and must not be stopped again.
+ */
+void coinbase_ws_stop(struct CoinbaseWsClient *client);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* COINBASE_FFI_H */
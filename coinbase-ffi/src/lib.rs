@@ -0,0 +1,328 @@
+//! C ABI for consuming the coinbase feed from other languages.
+//!
+//! `coinbase_ws_start` connects, subscribes and delivers messages to the given callbacks on the
+//! client's worker thread, so callbacks and their `user_data` must be safe to call from another
+//! thread. The header in `include/coinbase.h` is regenerated by the build script, the file is
+//! rewritten only when the exported API changes.
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use num_traits::ToPrimitive;
+
+use coinbase::decimal::Decimal;
+use coinbase::web_socket::common::{Channel, Channels};
+use coinbase::web_socket::response::{self, ResponseMessages, Side};
+use coinbase::web_socket::{CoinBaseWebSocketMessageHandler, CoinbaseWebSocketClient, Terminate};
+
+#[cfg(feature = "python")]
+mod python;
+
+/// Top of the book and last trade, from the `ticker` channel.
+#[repr(C)]
+pub struct CoinbaseTicker {
+  pub product_id: *const c_char,
+  pub sequence: i64,
+  pub trade_id: i64,
+  /// Microseconds since the Unix epoch.
+  pub time_us: i64,
+  pub price: f64,
+  pub last_size: f64,
+  pub best_bid: f64,
+  pub best_ask: f64,
+  /// 0 for buy, 1 for sell.
+  pub side: u8,
+}
+
+/// Trade from the `matches` or `full` channel.
+#[repr(C)]
+pub struct CoinbaseMatch {
+  pub product_id: *const c_char,
+  pub sequence: i64,
+  pub trade_id: i64,
+  /// Microseconds since the Unix epoch.
+  pub time_us: i64,
+  pub price: f64,
+  pub size: f64,
+  /// 0 for buy, 1 for sell.
+  pub side: u8,
+}
+
+/// Receives every message as JSON (NUL terminated, `len` bytes without the terminator) together
+/// with its `type`. Returning non-zero stops the client.
+pub type CoinbaseJsonCallback = Option<extern "C" fn(user_data: *mut c_void, message_type: *const c_char, json: *const c_char, len: usize) -> c_int>;
+pub type CoinbaseTickerCallback = Option<extern "C" fn(user_data: *mut c_void, ticker: *const CoinbaseTicker) -> c_int>;
+pub type CoinbaseMatchCallback = Option<extern "C" fn(user_data: *mut c_void, trade: *const CoinbaseMatch) -> c_int>;
+
+/// Callbacks are optional. `on_json` receives every message, including those also delivered
+/// to `on_ticker` or `on_match`, unless the packed callback stopped the client. Pointers passed
+/// to callbacks are valid only for the duration of the call.
+#[repr(C)]
+pub struct CoinbaseCallbacks {
+  pub user_data: *mut c_void,
+  pub on_json: CoinbaseJsonCallback,
+  pub on_ticker: CoinbaseTickerCallback,
+  pub on_match: CoinbaseMatchCallback,
+}
+
+/// Opaque handle of a running client.
+pub struct CoinbaseWsClient {
+  client: CoinbaseWebSocketClient,
+}
+
+struct FfiHandler {
+  callbacks: CoinbaseCallbacks,
+}
+
+// The caller of `coinbase_ws_start` guarantees that callbacks and user data can be used from
+// the worker thread.
+unsafe impl Send for FfiHandler {}
+
+impl FfiHandler {
+  fn json(&self, message: ResponseMessages) -> Result<(), Terminate> {
+    let on_json = match self.callbacks.on_json {
+      Some(on_json) => on_json,
+      None => return Ok(()),
+    };
+    let message_type = CString::new(message.kind()).unwrap();
+    let json = message.to_json();
+    let len = json.len();
+    // JSON produced by serde_json never contains NUL bytes.
+    let json = CString::new(json).unwrap();
+    result(on_json(self.callbacks.user_data, message_type.as_ptr(), json.as_ptr(), len))
+  }
+}
+
+impl CoinBaseWebSocketMessageHandler for FfiHandler {
+  fn on_subscriptions(&mut self, resp: &response::SubscriptionResponse) -> Result<(), Terminate> {
+    self.json(ResponseMessages::Subscriptions { resp: resp.clone() })
+  }
+
+  fn on_heartbeat(&mut self, resp: &response::HeartBeatResponse) -> Result<(), Terminate> {
+    self.json(ResponseMessages::Heartbeat { resp: resp.clone() })
+  }
+
+  fn on_status(&mut self, resp: &response::StatusResponse) -> Result<(), Terminate> {
+    self.json(ResponseMessages::Status { resp: resp.clone() })
+  }
+
+  fn on_ticker(&mut self, resp: &response::TickerResponse) -> Result<(), Terminate> {
+    if let Some(on_ticker) = self.callbacks.on_ticker {
+      let product_id = CString::new(resp.product_id.as_str()).unwrap_or_default();
+      let ticker = CoinbaseTicker {
+        product_id: product_id.as_ptr(),
+        sequence: resp.sequence,
+        trade_id: resp.trade_id,
+        time_us: time_us(&resp.time),
+        price: to_f64(&resp.price),
+        last_size: to_f64(&resp.last_size),
+        best_bid: to_f64(&resp.best_bid),
+        best_ask: to_f64(&resp.best_ask),
+        side: side(resp.side),
+      };
+      result(on_ticker(self.callbacks.user_data, &ticker))?;
+    }
+    self.json(ResponseMessages::Ticker { resp: resp.clone() })
+  }
+
+  fn on_snapshot(&mut self, resp: &response::SnapshotResponse) -> Result<(), Terminate> {
+    self.json(ResponseMessages::Snapshot { resp: resp.clone() })
+  }
+
+  fn on_l2_update(&mut self, resp: &response::L2UpdateResponse) -> Result<(), Terminate> {
+    self.json(ResponseMessages::L2Update { resp: resp.clone() })
+  }
+
+  fn on_match(&mut self, resp: &response::MatchResponse) -> Result<(), Terminate> {
+    if let Some(on_match) = self.callbacks.on_match {
+      let product_id = CString::new(resp.product_id.as_str()).unwrap_or_default();
+      let trade = CoinbaseMatch {
+        product_id: product_id.as_ptr(),
+        sequence: resp.sequence,
+        trade_id: resp.trade_id,
+        time_us: time_us(&resp.time),
+        price: to_f64(&resp.price),
+        size: to_f64(&resp.size),
+        side: side(resp.side),
+      };
+      result(on_match(self.callbacks.user_data, &trade))?;
+    }
+    self.json(ResponseMessages::Match { resp: resp.clone() })
+  }
+
+  fn on_received(&mut self, resp: &response::ReceivedResponse) -> Result<(), Terminate> {
+    self.json(ResponseMessages::Received { resp: resp.clone() })
+  }
+
+  fn on_open(&mut self, resp: &response::OpenResponse) -> Result<(), Terminate> {
+    self.json(ResponseMessages::Open { resp: resp.clone() })
+  }
+
+  fn on_change(&mut self, resp: &response::ChangeResponse) -> Result<(), Terminate> {
+    self.json(ResponseMessages::Change { resp: resp.clone() })
+  }
+
+  fn on_done(&mut self, resp: &response::DoneResponse) -> Result<(), Terminate> {
+    self.json(ResponseMessages::Done { resp: resp.clone() })
+  }
+
+  fn on_active(&mut self, resp: &response::ActiveResponse) -> Result<(), Terminate> {
+    self.json(ResponseMessages::Active { resp: resp.clone() })
+  }
+
+  fn on_margin_profile_update(&mut self, resp: &response::MarginProfileUpdateResponse) -> Result<(), Terminate> {
+    self.json(ResponseMessages::Margin_Profile_Update { resp: Box::new(resp.clone()) })
+  }
+
+  fn on_last_match(&mut self, resp: &response::LastMatchResponse) -> Result<(), Terminate> {
+    self.json(ResponseMessages::Last_Match { resp: resp.clone() })
+  }
+
+  fn on_error(&mut self, resp: &response::ErrorResponse) -> Result<(), Terminate> {
+    self.json(ResponseMessages::Error { resp: resp.clone() })
+  }
+}
+
+fn result(code: c_int) -> Result<(), Terminate> {
+  if code == 0 { Ok(()) } else { Err(Terminate) }
+}
+
+fn time_us(time: &DateTime<Utc>) -> i64 {
+  time.timestamp_nanos_opt().unwrap_or_default() / 1_000
+}
+
+fn to_f64(value: &Decimal) -> f64 {
+  value.to_f64().unwrap_or(f64::NAN)
+}
+
+fn side(side: Side) -> u8 {
+  match side {
+    Side::BUY => 0,
+    Side::SELL => 1,
+  }
+}
+
+unsafe fn strings(values: *const *const c_char, len: usize) -> Option<Vec<String>> {
+  if len == 0 {
+    return Some(Vec::new());
+  }
+  if values.is_null() {
+    return None;
+  }
+  std::slice::from_raw_parts(values, len).iter()
+    .map(|value| {
+      if value.is_null() {
+        None
+      } else {
+        CStr::from_ptr(*value).to_str().ok().map(String::from)
+      }
+    })
+    .collect()
+}
+
+/// Starts a production client subscribed to `channels` (e.g. "ticker", "matches") for the given
+/// products. Returns NULL when a product id or channel name is invalid, the client could not
+/// be started or the subscription could not be sent. The returned client must be released with
+/// `coinbase_ws_stop`.
+///
+/// # Safety
+///
+/// `product_ids` must point to `product_ids_len` and `channels` to `channels_len` valid NUL
+/// terminated strings, either may be NULL when its length is zero. The strings are copied before
+/// the call returns. Callbacks are called on the client's worker thread until `coinbase_ws_stop`
+/// returns, so they and `user_data` must be safe to use from that thread and `user_data` must
+/// stay valid until then.
+#[no_mangle]
+pub unsafe extern "C" fn coinbase_ws_start(
+  product_ids: *const *const c_char,
+  product_ids_len: usize,
+  channels: *const *const c_char,
+  channels_len: usize,
+  callbacks: CoinbaseCallbacks,
+) -> *mut CoinbaseWsClient {
+  let product_ids = match strings(product_ids, product_ids_len) {
+    Some(product_ids) => product_ids,
+    None => return ptr::null_mut(),
+  };
+  let channels: Option<Vec<Channels>> = strings(channels, channels_len)
+    .and_then(|names| names.iter().map(|name| Channels::from_str(name).ok()).collect());
+  let channels = match channels {
+    Some(channels) => channels,
+    None => return ptr::null_mut(),
+  };
+
+  let mut client = CoinbaseWebSocketClient::production();
+  if client.start(FfiHandler { callbacks }).is_err() {
+    return ptr::null_mut();
+  }
+  if client.controller().try_subscribe(product_ids, Channel::from_names(&channels)).is_err() {
+    client.stop();
+    return ptr::null_mut();
+  }
+  Box::into_raw(Box::new(CoinbaseWsClient { client }))
+}
+
+/// Stops the client, waits for the worker thread and frees the client. After this returns no
+/// callback is called anymore.
+///
+/// # Safety
+///
+/// `client` must be NULL or a pointer returned by `coinbase_ws_start` that wasn't stopped yet,
+/// it is invalid after this returns and must not be stopped again.
+#[no_mangle]
+pub unsafe extern "C" fn coinbase_ws_stop(client: *mut CoinbaseWsClient) {
+  if client.is_null() {
+    return;
+  }
+  Box::from_raw(client).client.stop();
+}
+
+#[cfg(test)]
+mod test {
+  use std::ffi::CStr;
+  use std::os::raw::{c_char, c_int, c_void};
+
+  use coinbase::web_socket::response::MatchResponse;
+  use coinbase::web_socket::CoinBaseWebSocketMessageHandler;
+
+  use super::{CoinbaseCallbacks, CoinbaseMatch, FfiHandler};
+
+  extern "C" fn on_json(user_data: *mut c_void, message_type: *const c_char, _json: *const c_char, _len: usize) -> c_int {
+    let types = unsafe { &mut *(user_data as *mut Vec<String>) };
+    types.push(unsafe { CStr::from_ptr(message_type) }.to_str().unwrap().into());
+    0
+  }
+
+  extern "C" fn on_match(_user_data: *mut c_void, trade: *const CoinbaseMatch) -> c_int {
+    let trade = unsafe { &*trade };
+    assert_eq!(unsafe { CStr::from_ptr(trade.product_id) }.to_str().unwrap(), "ETH-USD");
+    assert_eq!(trade.price, 434.19);
+    // Stop after the first trade.
+    1
+  }
+
+  #[test]
+  fn deliver_packed_and_json_messages() -> Result<(), serde_json::error::Error> {
+    let trade: MatchResponse = serde_json::from_str(r#"{
+      "trade_id": 62995921, "maker_order_id": "125f1d3d-3100-41ce-9341-fc330bdcebcb",
+      "taker_order_id": "5b0a9f2d-3388-4fd4-a106-b96b1e6d302f", "side": "buy", "size": "1.9",
+      "price": "434.19", "product_id": "ETH-USD", "sequence": 10182385681, "time": "2020-08-31T15:05:14.336755Z"
+    }"#)?;
+    let mut types: Vec<String> = Vec::new();
+    let mut handler = FfiHandler {
+      callbacks: CoinbaseCallbacks {
+        user_data: &mut types as *mut Vec<String> as *mut c_void,
+        on_json: Some(on_json),
+        on_ticker: None,
+        on_match: None,
+      },
+    };
+    handler.on_match(&trade).unwrap();
+    handler.callbacks.on_match = Some(on_match);
+    assert!(handler.on_match(&trade).is_err());
+    assert_eq!(types, vec!["match".to_string()]);
+    Ok(())
+  }
+}
@@ -0,0 +1,395 @@
+//! Python module built with the `python` feature (`extension-module` for wheels built by maturin).
+//!
+//! Handlers are plain Python objects; the client calls `on_heartbeat`, `on_ticker`, `on_match`,
+//! `on_last_match`, `on_l2update` and `on_snapshot` with typed messages when the handler defines
+//! them, and `on_message(type, json)` for all other message types. Raising an exception in a
+//! callback stops delivery, `Terminate` does so without printing the exception.
+use std::fs::File;
+use std::io::BufReader;
+use std::str::FromStr;
+
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyIOError, PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+
+use coinbase::replay::ReplayClient;
+use coinbase::web_socket::common::{Channel, Channels};
+use coinbase::web_socket::response::{self, ResponseMessages};
+use coinbase::web_socket::{CoinBaseWebSocketMessageHandler, CoinbaseWebSocketClient, Terminate as RustTerminate};
+
+use super::{side, time_us, to_f64};
+
+create_exception!(coinbase_ffi, Terminate, PyException, "Raise from a handler callback to stop delivering messages.");
+
+fn side_name(value: response::Side) -> &'static str {
+  if side(value) == 0 { "buy" } else { "sell" }
+}
+
+#[pyclass(get_all)]
+#[derive(Clone)]
+pub struct Heartbeat {
+  product_id: String,
+  sequence: i64,
+  last_trade_id: i64,
+  time_us: i64,
+}
+
+#[pyclass(get_all)]
+#[derive(Clone)]
+pub struct Ticker {
+  product_id: String,
+  sequence: i64,
+  trade_id: i64,
+  time_us: i64,
+  price: f64,
+  last_size: f64,
+  best_bid: f64,
+  best_ask: f64,
+  side: &'static str,
+}
+
+/// Trade from the `matches` channel, also used for `last_match`.
+#[pyclass(get_all)]
+#[derive(Clone)]
+pub struct Match {
+  product_id: String,
+  sequence: i64,
+  trade_id: i64,
+  time_us: i64,
+  maker_order_id: String,
+  taker_order_id: String,
+  price: f64,
+  size: f64,
+  side: &'static str,
+}
+
+/// Level2 changes as `(side, price, size)` tuples.
+#[pyclass(get_all)]
+#[derive(Clone)]
+pub struct L2Update {
+  product_id: String,
+  time_us: i64,
+  changes: Vec<(&'static str, f64, f64)>,
+}
+
+/// Level2 snapshot with `(price, size)` levels, best first.
+#[pyclass(get_all)]
+#[derive(Clone)]
+pub struct Snapshot {
+  product_id: String,
+  bids: Vec<(f64, f64)>,
+  asks: Vec<(f64, f64)>,
+}
+
+#[pymethods]
+impl Heartbeat {
+  fn __repr__(&self) -> String {
+    format!("Heartbeat(product_id={:?}, sequence={})", self.product_id, self.sequence)
+  }
+}
+
+#[pymethods]
+impl Ticker {
+  fn __repr__(&self) -> String {
+    format!("Ticker(product_id={:?}, price={}, bid={}, ask={})", self.product_id, self.price, self.best_bid, self.best_ask)
+  }
+}
+
+#[pymethods]
+impl Match {
+  fn __repr__(&self) -> String {
+    format!("Match(product_id={:?}, price={}, size={}, side={:?})", self.product_id, self.price, self.size, self.side)
+  }
+}
+
+#[pymethods]
+impl L2Update {
+  fn __repr__(&self) -> String {
+    format!("L2Update(product_id={:?}, changes={:?})", self.product_id, self.changes)
+  }
+}
+
+#[pymethods]
+impl Snapshot {
+  fn __repr__(&self) -> String {
+    format!("Snapshot(product_id={:?}, bids={}, asks={})", self.product_id, self.bids.len(), self.asks.len())
+  }
+}
+
+fn levels(levels: &[Vec<coinbase::decimal::Decimal>]) -> Vec<(f64, f64)> {
+  levels.iter()
+    .filter(|level| level.len() >= 2)
+    .map(|level| (to_f64(&level[0]), to_f64(&level[1])))
+    .collect()
+}
+
+fn trade(resp: &response::MatchResponse) -> Match {
+  Match {
+    product_id: resp.product_id.clone(),
+    sequence: resp.sequence,
+    trade_id: resp.trade_id,
+    time_us: time_us(&resp.time),
+    maker_order_id: resp.maker_order_id.to_string(),
+    taker_order_id: resp.taker_order_id.to_string(),
+    price: to_f64(&resp.price),
+    size: to_f64(&resp.size),
+    side: side_name(resp.side),
+  }
+}
+
+/// Calls into a Python handler object, acquiring the GIL for every message.
+struct PyHandler {
+  handler: PyObject,
+}
+
+impl PyHandler {
+  fn call<T: for<'py> IntoPyObject<'py>>(&self, method: &str, message: impl FnOnce() -> T) -> Result<(), RustTerminate> {
+    Python::with_gil(|py| {
+      let handler = self.handler.bind(py);
+      if !handler.hasattr(method).unwrap_or(false) {
+        return Ok(());
+      }
+      handler.call_method1(method, (message(),)).map(|_| ()).map_err(|err| terminate(py, err))
+    })
+  }
+
+  fn untyped(&self, message: ResponseMessages) -> Result<(), RustTerminate> {
+    Python::with_gil(|py| {
+      let handler = self.handler.bind(py);
+      if !handler.hasattr("on_message").unwrap_or(false) {
+        return Ok(());
+      }
+      handler.call_method1("on_message", (message.kind(), message.to_json()))
+        .map(|_| ())
+        .map_err(|err| terminate(py, err))
+    })
+  }
+}
+
+fn terminate(py: Python, err: PyErr) -> RustTerminate {
+  if !err.is_instance_of::<Terminate>(py) {
+    err.print(py);
+  }
+  RustTerminate
+}
+
+impl CoinBaseWebSocketMessageHandler for PyHandler {
+  fn on_subscriptions(&mut self, resp: &response::SubscriptionResponse) -> Result<(), RustTerminate> {
+    self.untyped(ResponseMessages::Subscriptions { resp: resp.clone() })
+  }
+
+  fn on_heartbeat(&mut self, resp: &response::HeartBeatResponse) -> Result<(), RustTerminate> {
+    self.call("on_heartbeat", || Heartbeat {
+      product_id: resp.product_id.clone(),
+      sequence: resp.sequence,
+      last_trade_id: resp.last_trade_id,
+      time_us: time_us(&resp.time),
+    })
+  }
+
+  fn on_status(&mut self, resp: &response::StatusResponse) -> Result<(), RustTerminate> {
+    self.untyped(ResponseMessages::Status { resp: resp.clone() })
+  }
+
+  fn on_ticker(&mut self, resp: &response::TickerResponse) -> Result<(), RustTerminate> {
+    self.call("on_ticker", || Ticker {
+      product_id: resp.product_id.clone(),
+      sequence: resp.sequence,
+      trade_id: resp.trade_id,
+      time_us: time_us(&resp.time),
+      price: to_f64(&resp.price),
+      last_size: to_f64(&resp.last_size),
+      best_bid: to_f64(&resp.best_bid),
+      best_ask: to_f64(&resp.best_ask),
+      side: side_name(resp.side),
+    })
+  }
+
+  fn on_snapshot(&mut self, resp: &response::SnapshotResponse) -> Result<(), RustTerminate> {
+    self.call("on_snapshot", || Snapshot {
+      product_id: resp.product_id.clone(),
+      bids: levels(&resp.bids),
+      asks: levels(&resp.asks),
+    })
+  }
+
+  fn on_l2_update(&mut self, resp: &response::L2UpdateResponse) -> Result<(), RustTerminate> {
+    self.call("on_l2update", || L2Update {
+      product_id: resp.product_id.clone(),
+      time_us: time_us(&resp.time),
+      changes: resp.changes.iter()
+        .map(|change| (side_name(change.side), to_f64(&change.price), to_f64(&change.size)))
+        .collect(),
+    })
+  }
+
+  fn on_match(&mut self, resp: &response::MatchResponse) -> Result<(), RustTerminate> {
+    self.call("on_match", || trade(resp))
+  }
+
+  fn on_received(&mut self, resp: &response::ReceivedResponse) -> Result<(), RustTerminate> {
+    self.untyped(ResponseMessages::Received { resp: resp.clone() })
+  }
+
+  fn on_open(&mut self, resp: &response::OpenResponse) -> Result<(), RustTerminate> {
+    self.untyped(ResponseMessages::Open { resp: resp.clone() })
+  }
+
+  fn on_change(&mut self, resp: &response::ChangeResponse) -> Result<(), RustTerminate> {
+    self.untyped(ResponseMessages::Change { resp: resp.clone() })
+  }
+
+  fn on_done(&mut self, resp: &response::DoneResponse) -> Result<(), RustTerminate> {
+    self.untyped(ResponseMessages::Done { resp: resp.clone() })
+  }
+
+  fn on_active(&mut self, resp: &response::ActiveResponse) -> Result<(), RustTerminate> {
+    self.untyped(ResponseMessages::Active { resp: resp.clone() })
+  }
+
+  fn on_margin_profile_update(&mut self, resp: &response::MarginProfileUpdateResponse) -> Result<(), RustTerminate> {
+    self.untyped(ResponseMessages::Margin_Profile_Update { resp: Box::new(resp.clone()) })
+  }
+
+  fn on_last_match(&mut self, resp: &response::LastMatchResponse) -> Result<(), RustTerminate> {
+    self.call("on_last_match", || Match {
+      product_id: resp.product_id.clone(),
+      sequence: resp.sequence,
+      trade_id: resp.trade_id,
+      time_us: time_us(&resp.time),
+      maker_order_id: resp.maker_order_id.to_string(),
+      taker_order_id: resp.taker_order_id.to_string(),
+      price: to_f64(&resp.price),
+      size: to_f64(&resp.size),
+      side: side_name(resp.side),
+    })
+  }
+
+  fn on_error(&mut self, resp: &response::ErrorResponse) -> Result<(), RustTerminate> {
+    self.untyped(ResponseMessages::Error { resp: resp.clone() })
+  }
+}
+
+fn channels(names: Vec<String>) -> PyResult<Vec<Channel>> {
+  let channels = names.iter()
+    .map(|name| Channels::from_str(name).map_err(|_| PyValueError::new_err(format!("Unknown channel {}", name))))
+    .collect::<PyResult<Vec<Channels>>>()?;
+  Ok(Channel::from_names(&channels))
+}
+
+/// Web socket client delivering messages to a Python handler on the client's worker thread.
+#[pyclass(name = "CoinbaseWebSocketClient")]
+pub struct PyClient {
+  client: Option<CoinbaseWebSocketClient>,
+}
+
+#[pymethods]
+impl PyClient {
+  #[new]
+  #[pyo3(signature = (sandbox = false))]
+  fn new(sandbox: bool) -> Self {
+    let client = if sandbox { CoinbaseWebSocketClient::sandbox() } else { CoinbaseWebSocketClient::production() };
+    PyClient { client: Some(client) }
+  }
+
+  fn start(&mut self, handler: PyObject) -> PyResult<()> {
+    self.client()?.start(PyHandler { handler }).map_err(|err| PyRuntimeError::new_err(err.to_string()))
+  }
+
+  fn subscribe(&self, product_ids: Vec<String>, channel_names: Vec<String>) -> PyResult<()> {
+    let channels = channels(channel_names)?;
+    self.client.as_ref().ok_or_else(stopped)?.controller().subscribe(product_ids, channels);
+    Ok(())
+  }
+
+  fn unsubscribe(&self, product_ids: Vec<String>, channel_names: Vec<String>) -> PyResult<()> {
+    let channels = channels(channel_names)?;
+    self.client.as_ref().ok_or_else(stopped)?.controller().unsubscribe(product_ids, channels);
+    Ok(())
+  }
+
+  /// Stops the client and waits for the worker. The GIL is released meanwhile so the worker
+  /// can finish delivering the current message.
+  fn stop(&mut self, py: Python) -> PyResult<()> {
+    let mut client = self.client.take().ok_or_else(stopped)?;
+    py.allow_threads(|| client.stop());
+    Ok(())
+  }
+}
+
+impl PyClient {
+  fn client(&mut self) -> PyResult<&mut CoinbaseWebSocketClient> {
+    self.client.as_mut().ok_or_else(stopped)
+  }
+}
+
+fn stopped() -> PyErr {
+  PyRuntimeError::new_err("Client was stopped")
+}
+
+/// Replays a file with one feed message per line through the handler on the calling thread.
+/// Returns number of delivered messages.
+#[pyfunction]
+fn replay(py: Python, path: &str, handler: PyObject) -> PyResult<u64> {
+  let file = File::open(path).map_err(|err| PyIOError::new_err(err.to_string()))?;
+  let mut handler = PyHandler { handler };
+  py.allow_threads(|| ReplayClient::from_json_lines(BufReader::new(file)).run(&mut handler))
+    .map_err(|err| PyIOError::new_err(err.to_string()))
+}
+
+#[pymodule]
+fn coinbase_ffi(module: &Bound<'_, PyModule>) -> PyResult<()> {
+  module.add_class::<PyClient>()?;
+  module.add_class::<Heartbeat>()?;
+  module.add_class::<Ticker>()?;
+  module.add_class::<Match>()?;
+  module.add_class::<L2Update>()?;
+  module.add_class::<Snapshot>()?;
+  module.add("Terminate", module.py().get_type::<Terminate>())?;
+  module.add_function(wrap_pyfunction!(replay, module)?)?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use std::io::Write;
+
+  use pyo3::ffi::c_str;
+  use pyo3::prelude::*;
+  use pyo3::types::PyDict;
+
+  #[test]
+  fn replay_into_python_handler() -> PyResult<()> {
+    let path = std::env::temp_dir().join("coinbase_ffi_replay.jsonl");
+    let mut file = std::fs::File::create(&path)?;
+    writeln!(file, r#"{{"type":"match","trade_id":1,"maker_order_id":"125f1d3d-3100-41ce-9341-fc330bdcebcb","taker_order_id":"5b0a9f2d-3388-4fd4-a106-b96b1e6d302f","side":"buy","size":"1.9","price":"434.19","product_id":"ETH-USD","sequence":1,"time":"2020-08-31T15:05:14.336755Z"}}"#)?;
+    writeln!(file, r#"{{"type":"heartbeat","sequence":2,"last_trade_id":1,"product_id":"ETH-USD","time":"2020-08-31T15:05:15Z"}}"#)?;
+    writeln!(file, r#"{{"type":"error","msg":"boom","extra":{{}}}}"#)?;
+    drop(file);
+
+    pyo3::prepare_freethreaded_python();
+    Python::with_gil(|py| {
+      let module = PyModule::new(py, "coinbase_ffi")?;
+      super::coinbase_ffi(&module)?;
+      let globals = PyDict::new(py);
+      py.run(c_str!(r#"
+class Handler:
+    def __init__(self):
+        self.seen = []
+    def on_match(self, trade):
+        self.seen.append((trade.product_id, trade.price, trade.side))
+    def on_message(self, kind, json):
+        self.seen.append(kind)
+        raise Terminate()
+"#), Some(&globals), None)?;
+      globals.set_item("Terminate", module.getattr("Terminate")?)?;
+      let handler = py.eval(c_str!("Handler()"), Some(&globals), None)?;
+      let delivered: u64 = module.getattr("replay")?.call1((path.to_str().unwrap(), &handler))?.extract()?;
+      assert_eq!(delivered, 3);
+      let seen: Vec<PyObject> = handler.getattr("seen")?.extract()?;
+      assert_eq!(seen.len(), 2);
+      assert_eq!(seen[0].bind(py).repr()?.to_str()?, "('ETH-USD', 434.19, 'buy')");
+      Ok(())
+    })
+  }
+}
@@ -0,0 +1,195 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+
+use coinbase::rest::{CoinbaseRestClient, RestError, Trade};
+
+use crate::writer::WriteToFileVisitor;
+
+// Trade ids downloaded before they are written, the history is paged from newest to oldest so
+// every window is reversed before it goes to the writer.
+const WINDOW: i64 = 1_000;
+
+// Time between two requests, within the public rate limit.
+const REQUEST_INTERVAL: Duration = Duration::from_millis(350);
+
+/// Inclusive range of trades that should be downloaded. Bounds can be given
+/// either as trade ids or as timestamps, missing bounds are open ended.
+pub struct BackfillRange {
+  pub from_trade_id: Option<i64>,
+  pub to_trade_id: Option<i64>,
+  pub from_time: Option<DateTime<Utc>>,
+  pub to_time: Option<DateTime<Utc>>,
+}
+
+impl BackfillRange {
+  fn is_before_start(&self, trade: &Trade) -> bool {
+    self.from_trade_id.map(|id| trade.trade_id < id).unwrap_or(false)
+      || self.from_time.map(|time| trade.time < time).unwrap_or(false)
+  }
+
+  fn is_after_end(&self, trade: &Trade) -> bool {
+    self.to_trade_id.map(|id| trade.trade_id > id).unwrap_or(false)
+      || self.to_time.map(|time| trade.time > time).unwrap_or(false)
+  }
+}
+
+/// Pages of the trade history, `CoinbaseRestClient::get_trades` unless replaced.
+pub trait TradeSource {
+  /// Trades older than `before`, or the latest trades, ordered from newest to oldest.
+  fn trades(&mut self, product_id: &str, before: Option<i64>) -> Result<Vec<Trade>, RestError>;
+}
+
+impl<F: FnMut(&str, Option<i64>) -> Result<Vec<Trade>, RestError>> TradeSource for F {
+  fn trades(&mut self, product_id: &str, before: Option<i64>) -> Result<Vec<Trade>, RestError> {
+    self(product_id, before)
+  }
+}
+
+/// REST client that waits between requests to stay within the public rate limit.
+pub struct RestTrades {
+  client: CoinbaseRestClient,
+  last_request: Option<Instant>,
+}
+
+impl RestTrades {
+  pub fn new(client: CoinbaseRestClient) -> Self {
+    RestTrades { client, last_request: None }
+  }
+}
+
+impl TradeSource for RestTrades {
+  fn trades(&mut self, product_id: &str, before: Option<i64>) -> Result<Vec<Trade>, RestError> {
+    if let Some(elapsed) = self.last_request.map(|last_request| last_request.elapsed()) {
+      if elapsed < REQUEST_INTERVAL {
+        thread::sleep(REQUEST_INTERVAL - elapsed);
+      }
+    }
+    self.last_request = Some(Instant::now());
+    self.client.get_trades(product_id, before)
+  }
+}
+
+/// Downloads trades within the range for the product and appends them to the
+/// product trades file in chronological order. Trades are downloaded and written in windows
+/// of consecutive trade ids, so only one window is held in memory. Returns number of written
+/// trades.
+pub fn backfill_product<S: TradeSource>(
+  source: &mut S,
+  writer: &mut WriteToFileVisitor,
+  product_id: &str,
+  range: &BackfillRange,
+) -> anyhow::Result<usize> {
+  let newest = match range.to_trade_id {
+    Some(trade_id) => trade_id,
+    None => match source.trades(product_id, None)?.first() {
+      Some(trade) => trade.trade_id,
+      None => return Ok(0),
+    },
+  };
+  let oldest = match (range.from_trade_id, range.from_time) {
+    (Some(trade_id), _) => trade_id.max(1),
+    (None, Some(time)) => first_trade_at(source, product_id, time, newest)?,
+    (None, None) => 1,
+  };
+
+  let mut written = 0;
+  let mut window_start = oldest;
+  while window_start <= newest {
+    let window_end = (window_start + WINDOW - 1).min(newest);
+    let mut window = Vec::new();
+    let mut before = window_end + 1;
+    loop {
+      let page = source.trades(product_id, Some(before))?;
+      let page_oldest = match page.last() {
+        Some(trade) => trade.trade_id,
+        None => break,
+      };
+      window.extend(page.into_iter().filter(|trade| trade.trade_id >= window_start));
+      if page_oldest <= window_start {
+        break;
+      }
+      before = page_oldest;
+    }
+
+    for trade in window.iter().rev() {
+      if range.is_after_end(trade) {
+        return Ok(written);
+      }
+      if !range.is_before_start(trade) {
+        writer.write_trade(trade, product_id);
+        written += 1;
+      }
+    }
+    log::info!("Backfilled trades of {} up to {}, {} so far", product_id, window_end, written);
+    window_start = window_end + 1;
+  }
+  Ok(written)
+}
+
+/// Id of the first trade made at or after `time`, searched among the trades up to `newest`.
+fn first_trade_at<S: TradeSource>(source: &mut S, product_id: &str, time: DateTime<Utc>, newest: i64) -> Result<i64, RestError> {
+  let (mut low, mut high) = (1, newest + 1);
+  while low < high {
+    let middle = low + (high - low) / 2;
+    // Latest trade up to `middle`, trade ids can have holes.
+    match source.trades(product_id, Some(middle + 1))?.first() {
+      Some(trade) if trade.time >= time => high = middle,
+      _ => low = middle + 1,
+    }
+  }
+  Ok(low)
+}
+
+#[cfg(test)]
+mod test {
+  use std::fs;
+
+  use chrono::{DateTime, Duration, Utc};
+
+  use coinbase::rest::{RestError, Trade};
+
+  use super::{backfill_product, BackfillRange};
+  use crate::writer::{FileWriter, WriterConfig};
+
+  fn start() -> DateTime<Utc> {
+    "2020-08-31T00:00:00Z".parse().unwrap()
+  }
+
+  /// Trades 1..=2500 made a second apart, without trade 1300, in pages of 100.
+  fn history(_: &str, before: Option<i64>) -> Result<Vec<Trade>, RestError> {
+    let newest = before.map(|before| before - 1).unwrap_or(2500).min(2500);
+    Ok((1..=newest).rev().filter(|trade_id| *trade_id != 1300).take(100).map(|trade_id| {
+      let time = start() + Duration::seconds(trade_id);
+      serde_json::from_str(&format!(
+        r#"{{"time":"{}","trade_id":{},"price":"100","size":"1","side":"buy"}}"#, time.to_rfc3339(), trade_id
+      )).unwrap()
+    }).collect())
+  }
+
+  #[test]
+  fn write_range_in_chronological_order() {
+    let directory = std::env::temp_dir().join(format!("coinbase-backfill-{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let writer = FileWriter::start(directory.clone(), WriterConfig::default()).unwrap();
+    let mut visitor = writer.visitor().blocking(true);
+    let range = BackfillRange {
+      from_trade_id: None,
+      to_trade_id: None,
+      from_time: Some(start() + Duration::seconds(1200)),
+      to_time: Some(start() + Duration::seconds(2400)),
+    };
+    let mut source = history;
+    assert_eq!(backfill_product(&mut source, &mut visitor, "BTC-USD", &range).unwrap(), 1200);
+    drop(visitor);
+    writer.join();
+
+    let content = fs::read_to_string(directory.join("trades_BTC-USD")).unwrap();
+    let trade_ids: Vec<i64> = content.lines()
+      .map(|line| serde_json::from_str::<Trade>(line).unwrap().trade_id)
+      .collect();
+    assert_eq!(trade_ids, (1200..=2400).filter(|trade_id| *trade_id != 1300).collect::<Vec<_>>());
+    fs::remove_dir_all(&directory).unwrap();
+  }
+}
@@ -0,0 +1,285 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+
+use coinbase::web_socket::common::Channels;
+
+use crate::retention::{DiskLimits, RetentionPolicy};
+use crate::writer::WriterConfig;
+
+/// Prefix of environment variables that override values from the config file.
+const ENV_PREFIX: &str = "COINBASE_SCRAPER_";
+
+/// Scraper settings, read from a TOML file given with `--config`, then overridden by
+/// `COINBASE_SCRAPER_*` environment variables and finally by command line flags.
+///
+/// ```toml
+/// directory = "/data/coinbase"
+/// products = ["BTC-USD", "ETH-USD"]   # all online products when empty
+/// channels = ["ticker", "matches"]
+///
+/// [output]
+/// bars = "1m"                         # or depth_interval_ms = 1000, depth_change_tolerance = 0.05 or taq = true
+/// daily_summaries = true
+///
+/// [writer]
+/// flush_interval_ms = 500
+/// wal = "/data/coinbase/wal"
+/// min_free_mb = 1024
+/// retention = "stop"                 # or "delete_oldest", with manifest = true
+///
+/// [reconnect]
+/// stale_timeout_secs = 30
+/// ```
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScraperConfig {
+  pub directory: Option<PathBuf>,
+  pub products: Vec<String>,
+  pub channels: Vec<Channels>,
+  pub output: OutputConfig,
+  pub writer: WriterSection,
+  pub reconnect: ReconnectConfig,
+}
+
+/// What gets recorded, raw events by default.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+  /// Order book depth snapshots at this interval instead of raw level2 updates.
+  pub depth_interval_ms: Option<u64>,
+  /// Order book depth whenever a price of the top levels or a size by more than this fraction
+  /// changes, instead of raw level2 updates.
+  pub depth_change_tolerance: Option<f64>,
+  pub depth_levels: usize,
+  /// OHLCV bars of `1s` or `1m` instead of raw events.
+  pub bars: Option<String>,
+  /// Quotes and trades in the TAQ layout instead of raw events, takes precedence over bars and depth.
+  pub taq: bool,
+  /// Per product summaries of every day, on top of the other output.
+  pub daily_summaries: bool,
+  /// Hours after UTC midnight at which days end.
+  pub day_boundary_hours: u32,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct WriterSection {
+  pub queue_capacity: usize,
+  pub flush_interval_ms: u64,
+  pub fsync: bool,
+  pub wal: Option<PathBuf>,
+  pub wal_capacity_mb: u64,
+  /// Files per session recorded in a manifest, with trades backfilled from the last session.
+  pub manifest: bool,
+  /// Cap on the size of the output directory.
+  pub max_total_mb: Option<u64>,
+  /// Free space to keep on the disk of the output directory.
+  pub min_free_mb: Option<u64>,
+  /// What to do once a disk limit is reached.
+  pub retention: RetentionPolicy,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReconnectConfig {
+  /// Download trades missed while disconnected.
+  pub backfill_trades: bool,
+  /// Restart the web socket worker when it panics.
+  pub supervise: bool,
+  /// Resubscribe products that sent nothing for this long.
+  pub stale_timeout_secs: Option<u64>,
+  /// Drop messages repeated after a reconnect, remembering this many per product.
+  pub deduplicate_window: Option<usize>,
+}
+
+impl Default for ScraperConfig {
+  fn default() -> Self {
+    ScraperConfig {
+      directory: None,
+      products: Vec::new(),
+      channels: vec![Channels::Ticker, Channels::Matches],
+      output: OutputConfig::default(),
+      writer: WriterSection::default(),
+      reconnect: ReconnectConfig::default(),
+    }
+  }
+}
+
+impl Default for OutputConfig {
+  fn default() -> Self {
+    OutputConfig {
+      depth_interval_ms: None,
+      depth_change_tolerance: None,
+      depth_levels: 10,
+      bars: None,
+      taq: false,
+      daily_summaries: false,
+      day_boundary_hours: 0,
+    }
+  }
+}
+
+impl Default for WriterSection {
+  fn default() -> Self {
+    let writer = WriterConfig::default();
+    WriterSection {
+      queue_capacity: writer.queue_capacity,
+      flush_interval_ms: writer.flush_interval.as_millis() as u64,
+      fsync: writer.fsync,
+      wal: None,
+      wal_capacity_mb: 64,
+      manifest: writer.manifest,
+      max_total_mb: None,
+      min_free_mb: None,
+      retention: writer.disk.policy,
+    }
+  }
+}
+
+impl Default for ReconnectConfig {
+  fn default() -> Self {
+    ReconnectConfig { backfill_trades: true, supervise: false, stale_timeout_secs: None, deduplicate_window: None }
+  }
+}
+
+impl ScraperConfig {
+  /// Reads the file when given and applies the environment overrides.
+  pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+    let mut config = match path {
+      Some(path) => {
+        let content = std::fs::read_to_string(path).with_context(|| format!("Could not read {}", path.display()))?;
+        ScraperConfig::parse(&content).with_context(|| format!("Invalid config {}", path.display()))?
+      }
+      None => ScraperConfig::default(),
+    };
+    config.apply_env(std::env::vars())?;
+    Ok(config)
+  }
+
+  pub fn parse(content: &str) -> anyhow::Result<Self> {
+    Ok(toml::from_str(content)?)
+  }
+
+  /// Overrides values with `COINBASE_SCRAPER_<KEY>` variables, where the key is the field name
+  /// in upper case prefixed by its section, e.g. `COINBASE_SCRAPER_WRITER_FSYNC=true`. Lists
+  /// are comma separated. Unknown variables with the prefix are rejected.
+  pub fn apply_env<I: IntoIterator<Item=(String, String)>>(&mut self, vars: I) -> anyhow::Result<()> {
+    for (name, value) in vars {
+      let key = match name.strip_prefix(ENV_PREFIX) {
+        Some(key) => key.to_ascii_lowercase(),
+        None => continue,
+      };
+      let invalid = |err: &dyn std::fmt::Display| anyhow!("Invalid value of {}: {}", name, err);
+      // @formatter:off
+      match key.as_str() {
+        "directory"                   => self.directory = Some(PathBuf::from(value)),
+        "products"                    => self.products = split_list(&value),
+        "channels"                    => self.channels = split_list(&value).iter()
+          .map(|channel| channel.parse().map_err(|_| invalid(&format!("unknown channel {}", channel))))
+          .collect::<anyhow::Result<_>>()?,
+        "output_depth_interval_ms"    => self.output.depth_interval_ms = Some(value.parse().map_err(|err| invalid(&err))?),
+        "output_depth_change_tolerance" => self.output.depth_change_tolerance = Some(value.parse().map_err(|err| invalid(&err))?),
+        "output_depth_levels"         => self.output.depth_levels = value.parse().map_err(|err| invalid(&err))?,
+        "output_bars"                 => self.output.bars = Some(value),
+        "output_taq"                  => self.output.taq = value.parse().map_err(|err| invalid(&err))?,
+        "output_daily_summaries"      => self.output.daily_summaries = value.parse().map_err(|err| invalid(&err))?,
+        "output_day_boundary_hours"   => self.output.day_boundary_hours = value.parse().map_err(|err| invalid(&err))?,
+        "writer_queue_capacity"       => self.writer.queue_capacity = value.parse().map_err(|err| invalid(&err))?,
+        "writer_flush_interval_ms"    => self.writer.flush_interval_ms = value.parse().map_err(|err| invalid(&err))?,
+        "writer_fsync"                => self.writer.fsync = value.parse().map_err(|err| invalid(&err))?,
+        "writer_wal"                  => self.writer.wal = Some(PathBuf::from(value)),
+        "writer_wal_capacity_mb"      => self.writer.wal_capacity_mb = value.parse().map_err(|err| invalid(&err))?,
+        "writer_manifest"             => self.writer.manifest = value.parse().map_err(|err| invalid(&err))?,
+        "writer_max_total_mb"         => self.writer.max_total_mb = Some(value.parse().map_err(|err| invalid(&err))?),
+        "writer_min_free_mb"          => self.writer.min_free_mb = Some(value.parse().map_err(|err| invalid(&err))?),
+        "writer_retention"            => self.writer.retention = value.parse().map_err(|err| invalid(&err))?,
+        "reconnect_backfill_trades"   => self.reconnect.backfill_trades = value.parse().map_err(|err| invalid(&err))?,
+        "reconnect_supervise"         => self.reconnect.supervise = value.parse().map_err(|err| invalid(&err))?,
+        "reconnect_stale_timeout_secs" => self.reconnect.stale_timeout_secs = Some(value.parse().map_err(|err| invalid(&err))?),
+        "reconnect_deduplicate_window" => self.reconnect.deduplicate_window = Some(value.parse().map_err(|err| invalid(&err))?),
+        _ => return Err(anyhow!("Unknown configuration variable {}", name)),
+      }
+      // @formatter:on
+    }
+    Ok(())
+  }
+
+  /// Interval of the configured bars, if any.
+  pub fn bar_interval(&self) -> anyhow::Result<Option<Duration>> {
+    match self.output.bars.as_deref() {
+      None => Ok(None),
+      Some("1s") => Ok(Some(Duration::from_secs(1))),
+      Some("1m") => Ok(Some(Duration::from_secs(60))),
+      Some(bars) => Err(anyhow!("Unsupported bars {}, expected 1s or 1m", bars)),
+    }
+  }
+
+  pub fn writer_config(&self) -> WriterConfig {
+    WriterConfig {
+      queue_capacity: self.writer.queue_capacity,
+      flush_interval: Duration::from_millis(self.writer.flush_interval_ms),
+      fsync: self.writer.fsync,
+      wal: self.writer.wal.clone().map(|path| (path, self.writer.wal_capacity_mb << 20)),
+      manifest: self.writer.manifest,
+      disk: DiskLimits {
+        max_total_bytes: self.writer.max_total_mb.map(|mb| mb << 20),
+        min_free_bytes: self.writer.min_free_mb.map(|mb| mb << 20),
+        policy: self.writer.retention,
+      },
+    }
+  }
+}
+
+fn split_list(value: &str) -> Vec<String> {
+  value.split(',').map(str::trim).filter(|item| !item.is_empty()).map(String::from).collect()
+}
+
+#[cfg(test)]
+mod test {
+  use std::path::PathBuf;
+  use std::time::Duration;
+
+  use coinbase::web_socket::common::Channels;
+
+  use super::ScraperConfig;
+  use crate::retention::RetentionPolicy;
+
+  #[test]
+  fn env_overrides_file() {
+    let mut config = ScraperConfig::parse(r#"
+      directory = "/data"
+      products = ["BTC-USD"]
+      channels = ["ticker", "level2"]
+
+      [output]
+      bars = "1s"
+
+      [writer]
+      flush_interval_ms = 250
+      wal = "/data/wal"
+    "#).unwrap();
+    assert_eq!(config.channels, vec![Channels::Ticker, Channels::Level2]);
+    assert_eq!(config.bar_interval().unwrap(), Some(Duration::from_secs(1)));
+    assert!(config.reconnect.backfill_trades);
+
+    config.apply_env(vec![
+      ("COINBASE_SCRAPER_PRODUCTS".to_string(), "BTC-USD, ETH-USD".to_string()),
+      ("COINBASE_SCRAPER_WRITER_FSYNC".to_string(), "true".to_string()),
+      ("COINBASE_SCRAPER_WRITER_MIN_FREE_MB".to_string(), "512".to_string()),
+      ("COINBASE_SCRAPER_WRITER_RETENTION".to_string(), "delete_oldest".to_string()),
+      ("HOME".to_string(), "/root".to_string()),
+    ]).unwrap();
+    assert_eq!(config.products, vec!["BTC-USD".to_string(), "ETH-USD".to_string()]);
+    let writer = config.writer_config();
+    assert!(writer.fsync);
+    assert_eq!(writer.flush_interval, Duration::from_millis(250));
+    assert_eq!(writer.wal, Some((PathBuf::from("/data/wal"), 64 << 20)));
+    assert_eq!((writer.disk.min_free_bytes, writer.disk.policy), (Some(512 << 20), RetentionPolicy::DeleteOldest));
+
+    assert!(config.apply_env(vec![("COINBASE_SCRAPER_WRITER_FSINK".to_string(), "1".to_string())]).is_err());
+    assert!(ScraperConfig::parse("unknown = 1").is_err());
+  }
+}
@@ -0,0 +1,369 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, Utc};
+
+use coinbase::analytics::{Candle, DailySummary};
+use coinbase::decimal::Decimal;
+use coinbase::order_book::{DepthSnapshot, Level};
+use coinbase::rest::Trade;
+use coinbase::web_socket::response::{L2UpdateResponse, Side, TickerResponse};
+
+use crate::taq::{TaqQuote, TaqTrade};
+use crate::verify::stream_of;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Format {
+  Csv,
+  #[cfg(feature = "parquet")]
+  Parquet,
+}
+
+impl Format {
+  pub fn extension(&self) -> &'static str {
+    match self {
+      Format::Csv => "csv",
+      #[cfg(feature = "parquet")]
+      Format::Parquet => "parquet",
+    }
+  }
+}
+
+impl std::str::FromStr for Format {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "csv" => Ok(Format::Csv),
+      #[cfg(feature = "parquet")]
+      "parquet" => Ok(Format::Parquet),
+      _ => Err(anyhow!("Unsupported format {}", s)),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum ColumnType {
+  Int,
+  Decimal,
+  Text,
+  Time,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Value {
+  Int(i64),
+  Decimal(Decimal),
+  Text(String),
+  Time(DateTime<Utc>),
+  Null,
+}
+
+impl Value {
+  fn side(side: Side) -> Self {
+    Value::Text(match side {
+      Side::BUY => "buy".into(),
+      Side::SELL => "sell".into(),
+    })
+  }
+}
+
+/// Kind of records in a file written by the scraper, from the file name prefix.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum Kind {
+  Ticker,
+  L2Update,
+  Trades,
+  Depth,
+  Bars,
+  TaqQuotes,
+  TaqTrades,
+  Summaries,
+}
+
+// @formatter:off
+const TICKER_COLUMNS: &[(&str, ColumnType)] = &[
+  ("time", ColumnType::Time), ("product_id", ColumnType::Text), ("sequence", ColumnType::Int),
+  ("trade_id", ColumnType::Int), ("price", ColumnType::Decimal), ("side", ColumnType::Text),
+  ("last_size", ColumnType::Decimal), ("best_bid", ColumnType::Decimal), ("best_ask", ColumnType::Decimal),
+];
+// One row per change.
+const L2UPDATE_COLUMNS: &[(&str, ColumnType)] = &[
+  ("time", ColumnType::Time), ("product_id", ColumnType::Text), ("side", ColumnType::Text),
+  ("price", ColumnType::Decimal), ("size", ColumnType::Decimal),
+];
+const TRADES_COLUMNS: &[(&str, ColumnType)] = &[
+  ("time", ColumnType::Time), ("product_id", ColumnType::Text), ("trade_id", ColumnType::Int),
+  ("price", ColumnType::Decimal), ("size", ColumnType::Decimal), ("side", ColumnType::Text),
+];
+// One row per level, `level` counts from 0 at the best price.
+const DEPTH_COLUMNS: &[(&str, ColumnType)] = &[
+  ("time", ColumnType::Time), ("product_id", ColumnType::Text), ("side", ColumnType::Text),
+  ("level", ColumnType::Int), ("price", ColumnType::Decimal), ("size", ColumnType::Decimal),
+];
+const BARS_COLUMNS: &[(&str, ColumnType)] = &[
+  ("start", ColumnType::Time), ("product_id", ColumnType::Text), ("open", ColumnType::Decimal),
+  ("high", ColumnType::Decimal), ("low", ColumnType::Decimal), ("close", ColumnType::Decimal),
+  ("volume", ColumnType::Decimal), ("trades", ColumnType::Int), ("spread", ColumnType::Decimal),
+];
+const TAQ_QUOTES_COLUMNS: &[(&str, ColumnType)] = &[
+  ("timestamp", ColumnType::Time), ("product", ColumnType::Text), ("bid", ColumnType::Decimal),
+  ("ask", ColumnType::Decimal), ("bidsize", ColumnType::Decimal), ("asksize", ColumnType::Decimal),
+];
+const TAQ_TRADES_COLUMNS: &[(&str, ColumnType)] = &[
+  ("timestamp", ColumnType::Time), ("product", ColumnType::Text), ("price", ColumnType::Decimal),
+  ("size", ColumnType::Decimal), ("side", ColumnType::Text),
+];
+const SUMMARY_COLUMNS: &[(&str, ColumnType)] = &[
+  ("date", ColumnType::Text), ("product_id", ColumnType::Text), ("open", ColumnType::Decimal),
+  ("high", ColumnType::Decimal), ("low", ColumnType::Decimal), ("close", ColumnType::Decimal),
+  ("volume", ColumnType::Decimal), ("vwap", ColumnType::Decimal), ("trades", ColumnType::Int),
+  ("messages", ColumnType::Int),
+];
+// @formatter:on
+
+impl Kind {
+  pub(crate) fn of(file_name: &str) -> Option<(Kind, &str)> {
+    let (id, _) = stream_of(file_name);
+    // @formatter:off
+    let kinds = [
+      ("ticker_", Kind::Ticker), ("l2update_", Kind::L2Update), ("trades_", Kind::Trades),
+      ("depth_", Kind::Depth), ("bars_", Kind::Bars), ("taq_quotes_", Kind::TaqQuotes),
+      ("taq_trades_", Kind::TaqTrades), ("summary_", Kind::Summaries),
+    ];
+    // @formatter:on
+    kinds.iter().find_map(|(prefix, kind)| Some((*kind, id.strip_prefix(prefix)?)))
+  }
+
+  pub(crate) fn columns(&self) -> &'static [(&'static str, ColumnType)] {
+    match self {
+      Kind::Ticker => TICKER_COLUMNS,
+      Kind::L2Update => L2UPDATE_COLUMNS,
+      Kind::Trades => TRADES_COLUMNS,
+      Kind::Depth => DEPTH_COLUMNS,
+      Kind::Bars => BARS_COLUMNS,
+      Kind::TaqQuotes => TAQ_QUOTES_COLUMNS,
+      Kind::TaqTrades => TAQ_TRADES_COLUMNS,
+      Kind::Summaries => SUMMARY_COLUMNS,
+    }
+  }
+
+  /// Parses a recorded line with the response struct it was written from.
+  pub(crate) fn rows(&self, product_id: &str, line: &str) -> serde_json::Result<Vec<Vec<Value>>> {
+    let product = || Value::Text(product_id.into());
+    Ok(match self {
+      Kind::Ticker => {
+        let resp: TickerResponse = serde_json::from_str(line)?;
+        vec![vec![
+          Value::Time(resp.time), Value::Text(resp.product_id), Value::Int(resp.sequence), Value::Int(resp.trade_id),
+          Value::Decimal(resp.price), Value::side(resp.side), Value::Decimal(resp.last_size),
+          Value::Decimal(resp.best_bid), Value::Decimal(resp.best_ask),
+        ]]
+      }
+      Kind::L2Update => {
+        let L2UpdateResponse { product_id, time, changes } = serde_json::from_str(line)?;
+        changes.into_iter()
+          .map(|change| vec![
+            Value::Time(time), Value::Text(product_id.clone()), Value::side(change.side),
+            Value::Decimal(change.price), Value::Decimal(change.size),
+          ])
+          .collect()
+      }
+      Kind::Trades => {
+        let trade: Trade = serde_json::from_str(line)?;
+        vec![vec![
+          Value::Time(trade.time), product(), Value::Int(trade.trade_id), Value::Decimal(trade.price),
+          Value::Decimal(trade.size), Value::side(trade.side),
+        ]]
+      }
+      Kind::Depth => {
+        let snapshot: DepthSnapshot = serde_json::from_str(line)?;
+        let levels = |side: Side, levels: Vec<Level>| -> Vec<Vec<Value>> {
+          levels.into_iter().enumerate()
+            .map(|(level, Level { price, size })| vec![
+              Value::Time(snapshot.time), Value::Text(snapshot.product_id.clone()), Value::side(side),
+              Value::Int(level as i64), Value::Decimal(price), Value::Decimal(size),
+            ])
+            .collect()
+        };
+        let mut rows = levels(Side::BUY, snapshot.bids.clone());
+        rows.extend(levels(Side::SELL, snapshot.asks.clone()));
+        rows
+      }
+      Kind::Bars => {
+        let candle: Candle = serde_json::from_str(line)?;
+        vec![vec![
+          Value::Time(candle.start), Value::Text(candle.product_id), Value::Decimal(candle.open),
+          Value::Decimal(candle.high), Value::Decimal(candle.low), Value::Decimal(candle.close),
+          Value::Decimal(candle.volume), Value::Int(candle.trades as i64),
+          candle.spread.map_or(Value::Null, Value::Decimal),
+        ]]
+      }
+      Kind::TaqQuotes => {
+        let quote: TaqQuote = serde_json::from_str(line)?;
+        let decimal = |value: Option<Decimal>| value.map_or(Value::Null, Value::Decimal);
+        vec![vec![
+          Value::Time(quote.timestamp), Value::Text(quote.product), decimal(quote.bid), decimal(quote.ask),
+          decimal(quote.bidsize), decimal(quote.asksize),
+        ]]
+      }
+      Kind::TaqTrades => {
+        let trade: TaqTrade = serde_json::from_str(line)?;
+        vec![vec![
+          Value::Time(trade.timestamp), Value::Text(trade.product), Value::Decimal(trade.price),
+          Value::Decimal(trade.size), Value::side(trade.side),
+        ]]
+      }
+      Kind::Summaries => {
+        let summary: DailySummary = serde_json::from_str(line)?;
+        let decimal = |value: Option<Decimal>| value.map_or(Value::Null, Value::Decimal);
+        vec![vec![
+          Value::Text(summary.date.to_string()), Value::Text(summary.product_id), decimal(summary.open),
+          decimal(summary.high), decimal(summary.low), decimal(summary.close), Value::Decimal(summary.volume),
+          decimal(summary.vwap), Value::Int(summary.trades as i64), Value::Int(summary.messages as i64),
+        ]]
+      }
+    })
+  }
+}
+
+/// Converts a JSON lines file written by the scraper into `output`, with a column per field of
+/// the recorded response. Level2 updates and depth snapshots get a row per change and level.
+/// Returns number of written rows.
+pub fn convert_file(input: &Path, output: &Path, format: Format) -> anyhow::Result<u64> {
+  let file_name = input.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+  let (kind, product_id) = Kind::of(&file_name).ok_or_else(|| anyhow!("{} is not a file written by the scraper", file_name))?;
+  let reader = BufReader::new(File::open(input).with_context(|| format!("Could not open {}", input.display()))?);
+  let mut rows = Vec::new();
+  for (index, line) in reader.lines().enumerate() {
+    let line = line?;
+    if line.trim().is_empty() {
+      continue;
+    }
+    let parsed = kind.rows(product_id, &line).with_context(|| format!("{}:{}", input.display(), index + 1))?;
+    rows.extend(parsed);
+  }
+  match format {
+    Format::Csv => write_csv(output, kind.columns(), &rows)?,
+    #[cfg(feature = "parquet")]
+    Format::Parquet => parquet_output::write_parquet(output, kind.columns(), &rows)?,
+  }
+  Ok(rows.len() as u64)
+}
+
+fn write_csv(output: &Path, columns: &[(&str, ColumnType)], rows: &[Vec<Value>]) -> anyhow::Result<()> {
+  let mut writer = csv::Writer::from_path(output)?;
+  writer.write_record(columns.iter().map(|(name, _)| name))?;
+  for row in rows {
+    writer.write_record(row.iter().map(|value| match value {
+      Value::Int(value) => value.to_string(),
+      Value::Decimal(value) => value.to_string(),
+      Value::Text(value) => value.clone(),
+      Value::Time(value) => value.to_rfc3339(),
+      Value::Null => String::new(),
+    }))?;
+  }
+  writer.flush()?;
+  Ok(())
+}
+
+/// Decimals are written as doubles, times as UTC timestamps in microseconds.
+#[cfg(feature = "parquet")]
+mod parquet_output {
+  use std::fs::File;
+  use std::path::Path;
+  use std::sync::Arc;
+
+  use parquet::data_type::{ByteArray, ByteArrayType, DoubleType, Int64Type};
+  use parquet::file::properties::WriterProperties;
+  use parquet::file::writer::SerializedFileWriter;
+  use parquet::schema::parser::parse_message_type;
+
+  use super::{ColumnType, Value};
+
+  const ROW_GROUP_SIZE: usize = 100_000;
+
+  pub(super) fn write_parquet(output: &Path, columns: &[(&str, ColumnType)], rows: &[Vec<Value>]) -> anyhow::Result<()> {
+    let fields: Vec<String> = columns.iter()
+      .map(|(name, column_type)| match column_type {
+        ColumnType::Int => format!("OPTIONAL INT64 {};", name),
+        ColumnType::Decimal => format!("OPTIONAL DOUBLE {};", name),
+        ColumnType::Text => format!("OPTIONAL BYTE_ARRAY {} (UTF8);", name),
+        ColumnType::Time => format!("OPTIONAL INT64 {} (TIMESTAMP(MICROS,true));", name),
+      })
+      .collect();
+    let schema = Arc::new(parse_message_type(&format!("message record {{ {} }}", fields.join(" ")))?);
+    let mut writer = SerializedFileWriter::new(File::create(output)?, schema, Arc::new(WriterProperties::builder().build()))?;
+
+    for chunk in rows.chunks(ROW_GROUP_SIZE) {
+      let mut row_group = writer.next_row_group()?;
+      let mut index = 0;
+      while let Some(mut column) = row_group.next_column()? {
+        let values = chunk.iter().map(|row| &row[index]);
+        let definition_levels: Vec<i16> = values.clone().map(|value| (*value != Value::Null) as i16).collect();
+        match columns[index].1 {
+          ColumnType::Int | ColumnType::Time => {
+            let values: Vec<i64> = values.filter_map(|value| match value {
+              Value::Int(value) => Some(*value),
+              Value::Time(value) => Some(value.timestamp_micros()),
+              _ => None,
+            }).collect();
+            column.typed::<Int64Type>().write_batch(&values, Some(&definition_levels), None)?;
+          }
+          ColumnType::Decimal => {
+            let values: Vec<f64> = values.filter_map(|value| match value {
+              Value::Decimal(value) => value.to_string().parse().ok(),
+              _ => None,
+            }).collect();
+            column.typed::<DoubleType>().write_batch(&values, Some(&definition_levels), None)?;
+          }
+          ColumnType::Text => {
+            let values: Vec<ByteArray> = values.filter_map(|value| match value {
+              Value::Text(value) => Some(ByteArray::from(value.as_str())),
+              _ => None,
+            }).collect();
+            column.typed::<ByteArrayType>().write_batch(&values, Some(&definition_levels), None)?;
+          }
+        }
+        column.close()?;
+        index += 1;
+      }
+      row_group.close()?;
+    }
+    writer.close()?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::fs;
+
+  use super::{convert_file, Format};
+
+  #[test]
+  fn convert_depth_to_csv() {
+    let directory = std::env::temp_dir().join(format!("coinbase-convert-{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let input = directory.join("depth_BTC-USD.2");
+    fs::write(&input, concat!(
+      r#"{"product_id":"BTC-USD","time":"2020-08-31T15:00:00Z","bids":[{"price":"100.5","size":"1"},{"price":"100","size":"2"}],"#,
+      r#""asks":[{"price":"101","size":"3"}]}"#, "\n",
+    )).unwrap();
+
+    let output = directory.join("depth_BTC-USD.2.csv");
+    assert_eq!(convert_file(&input, &output, Format::Csv).unwrap(), 3);
+    let csv = fs::read_to_string(&output).unwrap();
+    assert_eq!(csv, concat!(
+      "time,product_id,side,level,price,size\n",
+      "2020-08-31T15:00:00+00:00,BTC-USD,buy,0,100.5,1\n",
+      "2020-08-31T15:00:00+00:00,BTC-USD,buy,1,100,2\n",
+      "2020-08-31T15:00:00+00:00,BTC-USD,sell,0,101,3\n",
+    ));
+    assert!(convert_file(&directory.join("notes.txt"), &output, Format::Csv).is_err());
+
+    fs::remove_dir_all(&directory).unwrap();
+  }
+}
@@ -0,0 +1,191 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::verify::{recorded_files, Line};
+
+/// Records of one stream in one dataset. Trades are keyed by their trade id and tickers by
+/// their sequence, other records only count.
+#[derive(Debug, Default)]
+struct Side {
+  records: u64,
+  first_time: Option<DateTime<Utc>>,
+  last_time: Option<DateTime<Utc>>,
+  keys: BTreeMap<i64, Option<DateTime<Utc>>>,
+}
+
+impl Side {
+  fn load(directory: &Path, files: &[String]) -> io::Result<Self> {
+    let mut side = Side::default();
+    for file_name in files {
+      let content = fs::read(directory.join(file_name))?;
+      let records = content.split(|byte| *byte == b'\n').filter_map(|line| serde_json::from_slice::<Line>(line).ok());
+      for record in records {
+        side.records += 1;
+        let time = record.time();
+        if let Some(time) = time {
+          side.first_time = Some(side.first_time.map_or(time, |first| first.min(time)));
+          side.last_time = Some(side.last_time.map_or(time, |last| last.max(time)));
+        }
+        if let Some(key) = record.sequence.or(record.trade_id) {
+          side.keys.insert(key, time);
+        }
+      }
+    }
+    Ok(side)
+  }
+}
+
+/// Comparison of one stream, e.g. `trades_BTC-USD`, between datasets `a` and `b`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct StreamDiff {
+  pub records: (u64, u64),
+  /// Trade ids or sequences recorded by both datasets.
+  pub common: u64,
+  /// Ranges of trade ids or sequences, `from..=to`, recorded only by `a`.
+  pub only_in_a: Vec<(i64, i64)>,
+  pub only_in_b: Vec<(i64, i64)>,
+  /// `b` minus `a` of the first and last record time, positive when `b` started or stopped later.
+  pub start_skew: Option<Duration>,
+  pub end_skew: Option<Duration>,
+  /// Largest difference in the time of a record found in both, nonzero when e.g. one side
+  /// backfilled it through the REST API.
+  pub max_time_difference: Option<Duration>,
+}
+
+impl StreamDiff {
+  fn of(a: &Side, b: &Side) -> Self {
+    let skew = |a: Option<DateTime<Utc>>, b: Option<DateTime<Utc>>| Some(b? - a?);
+    let mut common = 0;
+    let mut max_time_difference: Option<Duration> = None;
+    for (key, time_a) in &a.keys {
+      let time_b = match b.keys.get(key) {
+        Some(time_b) => time_b,
+        None => continue,
+      };
+      common += 1;
+      if let Some(difference) = skew(*time_a, *time_b).map(|difference| difference.abs()) {
+        max_time_difference = Some(max_time_difference.map_or(difference, |max| max.max(difference)));
+      }
+    }
+    StreamDiff {
+      records: (a.records, b.records),
+      common,
+      only_in_a: ranges(a.keys.keys().filter(|key| !b.keys.contains_key(key))),
+      only_in_b: ranges(b.keys.keys().filter(|key| !a.keys.contains_key(key))),
+      start_skew: skew(a.first_time, b.first_time),
+      end_skew: skew(a.last_time, b.last_time),
+      max_time_difference,
+    }
+  }
+
+  pub fn is_identical(&self) -> bool {
+    self.records.0 == self.records.1 && self.only_in_a.is_empty() && self.only_in_b.is_empty()
+  }
+}
+
+/// Collapses ascending keys into ranges of consecutive keys.
+fn ranges<'a, I: Iterator<Item=&'a i64>>(keys: I) -> Vec<(i64, i64)> {
+  let mut ranges: Vec<(i64, i64)> = Vec::new();
+  for key in keys {
+    match ranges.last_mut() {
+      Some((_, to)) if *to + 1 == *key => *to = *key,
+      _ => ranges.push((*key, *key)),
+    }
+  }
+  ranges
+}
+
+#[derive(Debug, Default)]
+pub struct DiffReport {
+  pub streams: BTreeMap<String, StreamDiff>,
+}
+
+impl DiffReport {
+  pub fn is_identical(&self) -> bool {
+    self.streams.values().all(StreamDiff::is_identical)
+  }
+}
+
+impl Display for DiffReport {
+  fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+    let millis = |duration: Option<Duration>| duration.map_or("-".to_string(), |duration| format!("{}ms", duration.num_milliseconds()));
+    let count = |ranges: &[(i64, i64)]| ranges.iter().map(|(from, to)| to - from + 1).sum::<i64>();
+    for (id, stream) in &self.streams {
+      writeln!(
+        f, "{}: {} / {} records, {} common, {} only in a, {} only in b, start skew {}, end skew {}, max time difference {}",
+        id, stream.records.0, stream.records.1, stream.common, count(&stream.only_in_a), count(&stream.only_in_b),
+        millis(stream.start_skew), millis(stream.end_skew), millis(stream.max_time_difference),
+      )?;
+      for (from, to) in &stream.only_in_a {
+        writeln!(f, "  only in a: {}..={}", from, to)?;
+      }
+      for (from, to) in &stream.only_in_b {
+        writeln!(f, "  only in b: {}..={}", from, to)?;
+      }
+    }
+    let differing = self.streams.values().filter(|stream| !stream.is_identical()).count();
+    writeln!(f, "{} of {} streams differ", differing, self.streams.len())
+  }
+}
+
+/// Compares two directories written by the scraper, e.g. by collectors in two regions, stream
+/// by stream: which trade ids and ticker sequences only one of them has and how far apart their
+/// first and last records are. Streams recorded by only one of them are compared to nothing.
+pub fn diff_directories(a: &Path, b: &Path) -> io::Result<DiffReport> {
+  let mut streams: BTreeMap<String, (Vec<String>, Vec<String>)> = BTreeMap::new();
+  for (id, _, file_name) in recorded_files(a)? {
+    streams.entry(id).or_default().0.push(file_name);
+  }
+  for (id, _, file_name) in recorded_files(b)? {
+    streams.entry(id).or_default().1.push(file_name);
+  }
+  let mut report = DiffReport::default();
+  for (id, (files_a, files_b)) in streams {
+    let diff = StreamDiff::of(&Side::load(a, &files_a)?, &Side::load(b, &files_b)?);
+    report.streams.insert(id, diff);
+  }
+  Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+  use std::fs;
+
+  use chrono::Duration;
+
+  use super::diff_directories;
+
+  #[test]
+  fn compare_trade_coverage() {
+    let directory = std::env::temp_dir().join(format!("coinbase-diff-{}", std::process::id()));
+    let (a, b) = (directory.join("a"), directory.join("b"));
+    fs::create_dir_all(&a).unwrap();
+    fs::create_dir_all(&b).unwrap();
+    let trade = |trade_id: i64, time: &str| format!(
+      r#"{{"time":"{}","trade_id":{},"price":"100","size":"1","side":"buy"}}"#, time, trade_id,
+    ) + "\n";
+    fs::write(a.join("trades_BTC-USD.1"), trade(1, "2020-08-31T15:00:00Z") + &trade(2, "2020-08-31T15:00:01Z")).unwrap();
+    fs::write(a.join("trades_BTC-USD.2"), trade(5, "2020-08-31T15:00:05Z")).unwrap();
+    let trades_b = trade(2, "2020-08-31T15:00:01.250Z") + &trade(3, "2020-08-31T15:00:02Z")
+      + &trade(4, "2020-08-31T15:00:03Z") + &trade(5, "2020-08-31T15:00:05Z") + &trade(6, "2020-08-31T15:00:06Z");
+    fs::write(b.join("trades_BTC-USD.1"), trades_b).unwrap();
+    fs::write(b.join("ticker_BTC-USD.1"), "").unwrap();
+
+    let report = diff_directories(&a, &b).unwrap();
+    let stream = &report.streams["trades_BTC-USD"];
+    assert_eq!((stream.records, stream.common), ((3, 5), 2));
+    assert_eq!(stream.only_in_a, vec![(1, 1)]);
+    assert_eq!(stream.only_in_b, vec![(3, 4), (6, 6)]);
+    assert_eq!((stream.start_skew, stream.end_skew), (Some(Duration::milliseconds(1250)), Some(Duration::seconds(1))));
+    assert_eq!(stream.max_time_difference, Some(Duration::milliseconds(250)));
+    assert!(report.streams["ticker_BTC-USD"].is_identical());
+    assert!(!report.is_identical());
+
+    fs::remove_dir_all(&directory).unwrap();
+  }
+}
@@ -0,0 +1,437 @@
+//! Arrow Flight server for the recorded data, so it can be read straight into dataframes,
+//! e.g. with `pyarrow.flight.connect("grpc://host:port").do_get(pyarrow.flight.Ticket("trades_BTC-USD"))`.
+//!
+//! Every stream of records, e.g. `trades_BTC-USD` over all its session files, is a flight with
+//! the stream id as its path and ticket, and the columns `convert` writes. `ListFlights`,
+//! `GetFlightInfo`, `GetSchema` and `DoGet` are supported. The messages below are the used subset
+//! of the Flight protocol, written by hand so the build doesn't need `protoc`.
+// Calls fail with tonic's `Status`, as in generated services.
+#![allow(clippy::result_large_err)]
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::thread;
+
+use arrow_array::builder::{StringBuilder, TimestampMicrosecondBuilder};
+use arrow_array::{ArrayRef, Float64Array, Int64Array, RecordBatch};
+use arrow_ipc::writer::{DictionaryTracker, IpcDataGenerator, IpcWriteOptions};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use tonic::codegen::{http, BoxFuture, Service, StdError};
+use tonic::server::{NamedService, ServerStreamingService, UnaryService};
+use tonic::{Request, Response, Status};
+
+use crate::convert::{ColumnType, Kind, Value};
+use crate::verify::stream_of;
+
+const FLIGHT_ID: &str = "Flight";
+
+const SERVICE: &str = "arrow.flight.protocol.FlightService";
+
+const BATCH_ROWS: usize = 65_536;
+
+// @formatter:off
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Criteria {
+  /// Prefix of the stream ids to list, e.g. `trades_`, empty lists all.
+  #[prost(bytes = "vec", tag = "1")] pub expression: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum DescriptorType { Unknown = 0, Path = 1, Cmd = 2 }
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct FlightDescriptor {
+  #[prost(enumeration = "DescriptorType", tag = "1")] pub r#type: i32,
+  #[prost(bytes = "vec"                 , tag = "2")] pub cmd   : Vec<u8>,
+  #[prost(string, repeated              , tag = "3")] pub path  : Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Ticket {
+  #[prost(bytes = "vec", tag = "1")] pub ticket: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Location {
+  #[prost(string, tag = "1")] pub uri: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct FlightEndpoint {
+  #[prost(message, optional, tag = "1")] pub ticket  : Option<Ticket>,
+  #[prost(message, repeated, tag = "2")] pub location: Vec<Location>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct FlightInfo {
+  /// IPC encapsulated schema message.
+  #[prost(bytes = "vec"      , tag = "1")] pub schema           : Vec<u8>,
+  #[prost(message, optional  , tag = "2")] pub flight_descriptor: Option<FlightDescriptor>,
+  #[prost(message, repeated  , tag = "3")] pub endpoint         : Vec<FlightEndpoint>,
+  #[prost(int64              , tag = "4")] pub total_records    : i64,
+  #[prost(int64              , tag = "5")] pub total_bytes      : i64,
+  #[prost(bool               , tag = "6")] pub ordered          : bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SchemaResult {
+  #[prost(bytes = "vec", tag = "1")] pub schema: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct FlightData {
+  #[prost(message, optional, tag = "1"   )] pub flight_descriptor: Option<FlightDescriptor>,
+  #[prost(bytes = "vec"    , tag = "2"   )] pub data_header      : Vec<u8>,
+  #[prost(bytes = "vec"    , tag = "3"   )] pub app_metadata     : Vec<u8>,
+  #[prost(bytes = "vec"    , tag = "1000")] pub data_body        : Vec<u8>,
+}
+// @formatter:on
+
+/// Recorded session files of every stream in the directory, in session order.
+fn datasets(directory: &Path) -> io::Result<BTreeMap<String, Vec<(u64, PathBuf)>>> {
+  let mut datasets: BTreeMap<String, Vec<(u64, PathBuf)>> = BTreeMap::new();
+  for entry in fs::read_dir(directory)? {
+    let path = entry?.path();
+    let file_name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    if Kind::of(&file_name).is_some() {
+      let (id, session) = stream_of(&file_name);
+      datasets.entry(id.to_string()).or_default().push((session, path.clone()));
+    }
+  }
+  for files in datasets.values_mut() {
+    files.sort();
+  }
+  Ok(datasets)
+}
+
+fn schema(kind: Kind) -> Schema {
+  let fields: Vec<Field> = kind.columns().iter()
+    .map(|(name, column_type)| {
+      let data_type = match column_type {
+        ColumnType::Int => DataType::Int64,
+        ColumnType::Decimal => DataType::Float64,
+        ColumnType::Text => DataType::Utf8,
+        ColumnType::Time => DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())),
+      };
+      Field::new(*name, data_type, true)
+    })
+    .collect();
+  Schema::new(fields)
+}
+
+/// Reads the records of a stream. Files may be written to at the same time, so a last line
+/// without its terminator is left for the next read.
+fn read_rows(id: &str, files: &[(u64, PathBuf)]) -> anyhow::Result<(Kind, Vec<Vec<Value>>)> {
+  let (kind, product_id) = Kind::of(id).ok_or_else(|| anyhow::anyhow!("Unknown stream {}", id))?;
+  let mut rows = Vec::new();
+  for (_, path) in files {
+    let content = fs::read_to_string(path)?;
+    let complete = content.rfind('\n').map_or("", |end| &content[..end]);
+    for (index, line) in complete.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+      let parsed = kind.rows(product_id, line).map_err(|err| anyhow::anyhow!("{}:{}: {}", path.display(), index + 1, err))?;
+      rows.extend(parsed);
+    }
+  }
+  Ok((kind, rows))
+}
+
+/// Decimals become doubles and times UTC timestamps in microseconds, as in Parquet output.
+fn record_batch(schema: &Arc<Schema>, columns: &[(&str, ColumnType)], rows: &[Vec<Value>]) -> Result<RecordBatch, arrow_schema::ArrowError> {
+  let arrays: Vec<ArrayRef> = columns.iter().enumerate()
+    .map(|(index, (_, column_type))| -> ArrayRef {
+      let values = rows.iter().map(|row| &row[index]);
+      match column_type {
+        ColumnType::Int => Arc::new(values.map(|value| match value {
+          Value::Int(value) => Some(*value),
+          _ => None,
+        }).collect::<Int64Array>()),
+        ColumnType::Decimal => Arc::new(values.map(|value| match value {
+          Value::Decimal(value) => value.to_string().parse().ok(),
+          _ => None,
+        }).collect::<Float64Array>()),
+        ColumnType::Text => {
+          let mut builder = StringBuilder::new();
+          for value in values {
+            match value {
+              Value::Text(value) => builder.append_value(value),
+              _ => builder.append_null(),
+            }
+          }
+          Arc::new(builder.finish())
+        }
+        ColumnType::Time => {
+          let mut builder = TimestampMicrosecondBuilder::new().with_timezone("UTC");
+          for value in values {
+            match value {
+              Value::Time(value) => builder.append_value(value.timestamp_micros()),
+              _ => builder.append_null(),
+            }
+          }
+          Arc::new(builder.finish())
+        }
+      }
+    })
+    .collect();
+  RecordBatch::try_new(schema.clone(), arrays)
+}
+
+fn encoded_schema(schema: &Schema) -> Vec<u8> {
+  let options = IpcWriteOptions::default();
+  let encoded = IpcDataGenerator::default().schema_to_bytes_with_dictionary_tracker(schema, &mut DictionaryTracker::new(false), &options);
+  let mut bytes = Vec::new();
+  arrow_ipc::writer::write_message(&mut bytes, encoded, &options).expect("Writing into memory never fails.");
+  bytes
+}
+
+fn flight_info(id: &str, files: &[(u64, PathBuf)]) -> Result<FlightInfo, Status> {
+  let (kind, _) = Kind::of(id).ok_or_else(|| Status::not_found(format!("Unknown stream {}", id)))?;
+  let total_bytes = files.iter().filter_map(|(_, path)| fs::metadata(path).ok()).map(|metadata| metadata.len() as i64).sum();
+  Ok(FlightInfo {
+    schema: encoded_schema(&schema(kind)),
+    flight_descriptor: Some(FlightDescriptor { r#type: DescriptorType::Path as i32, cmd: Vec::new(), path: vec![id.into()] }),
+    // No location means the data is fetched from this server.
+    endpoint: vec![FlightEndpoint { ticket: Some(Ticket { ticket: id.as_bytes().to_vec() }), location: Vec::new() }],
+    // Counting records would mean reading the whole stream.
+    total_records: -1,
+    total_bytes,
+    ordered: true,
+  })
+}
+
+fn descriptor_id(descriptor: &FlightDescriptor) -> Result<&str, Status> {
+  match descriptor.path.as_slice() {
+    [id] => Ok(id),
+    _ => Err(Status::invalid_argument("Descriptor path must be a single stream id, e.g. trades_BTC-USD")),
+  }
+}
+
+#[derive(Clone)]
+struct FlightService {
+  directory: Arc<PathBuf>,
+}
+
+impl FlightService {
+  fn files(&self, id: &str) -> Result<Vec<(u64, PathBuf)>, Status> {
+    let mut datasets = datasets(&self.directory).map_err(|err| Status::internal(err.to_string()))?;
+    datasets.remove(id).ok_or_else(|| Status::not_found(format!("No recorded stream {}", id)))
+  }
+
+  fn list_flights(&self, criteria: Criteria) -> Result<Vec<FlightInfo>, Status> {
+    let prefix = String::from_utf8_lossy(&criteria.expression).into_owned();
+    let datasets = datasets(&self.directory).map_err(|err| Status::internal(err.to_string()))?;
+    datasets.iter()
+      .filter(|(id, _)| id.starts_with(&prefix))
+      .map(|(id, files)| flight_info(id, files))
+      .collect()
+  }
+
+  fn do_get(&self, ticket: Ticket) -> Result<Vec<FlightData>, Status> {
+    let id = String::from_utf8(ticket.ticket).map_err(|_| Status::invalid_argument("Ticket must be a stream id"))?;
+    let files = self.files(&id)?;
+    let (kind, rows) = read_rows(&id, &files).map_err(|err| Status::internal(err.to_string()))?;
+    let schema = Arc::new(schema(kind));
+    let options = IpcWriteOptions::default();
+    let generator = IpcDataGenerator::default();
+    let mut tracker = DictionaryTracker::new(false);
+    let encoded = generator.schema_to_bytes_with_dictionary_tracker(&schema, &mut tracker, &options);
+    let mut data = vec![FlightData { data_header: encoded.ipc_message, ..FlightData::default() }];
+    for chunk in rows.chunks(BATCH_ROWS) {
+      let batch = record_batch(&schema, kind.columns(), chunk).map_err(|err| Status::internal(err.to_string()))?;
+      // The columns have no dictionaries.
+      let (_, encoded) = generator.encoded_batch(&batch, &mut tracker, &options).map_err(|err| Status::internal(err.to_string()))?;
+      data.push(FlightData { data_header: encoded.ipc_message, data_body: encoded.arrow_data, ..FlightData::default() });
+    }
+    log::info!(target: FLIGHT_ID, "Serving {} rows of {}", rows.len(), id);
+    Ok(data)
+  }
+}
+
+impl NamedService for FlightService {
+  const NAME: &'static str = SERVICE;
+}
+
+type DataStream<T> = tokio_stream::Iter<std::vec::IntoIter<Result<T, Status>>>;
+
+/// Runs a call that reads files on the blocking pool.
+fn blocking<T, F>(call: F) -> BoxFuture<T, Status>
+  where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, Status> + Send + 'static,
+{
+  Box::pin(async move {
+    tokio::task::spawn_blocking(call).await.map_err(|err| Status::internal(err.to_string()))?
+  })
+}
+
+fn stream<T: Send + 'static>(items: Result<Vec<T>, Status>) -> Result<Response<DataStream<T>>, Status> {
+  Ok(Response::new(tokio_stream::iter(items?.into_iter().map(Ok).collect::<Vec<_>>())))
+}
+
+struct ListFlights(FlightService);
+
+impl ServerStreamingService<Criteria> for ListFlights {
+  type Response = FlightInfo;
+  type ResponseStream = DataStream<FlightInfo>;
+  type Future = BoxFuture<Response<Self::ResponseStream>, Status>;
+
+  fn call(&mut self, request: Request<Criteria>) -> Self::Future {
+    let service = self.0.clone();
+    blocking(move || stream(service.list_flights(request.into_inner())))
+  }
+}
+
+struct GetFlightInfo(FlightService);
+
+impl UnaryService<FlightDescriptor> for GetFlightInfo {
+  type Response = FlightInfo;
+  type Future = BoxFuture<Response<FlightInfo>, Status>;
+
+  fn call(&mut self, request: Request<FlightDescriptor>) -> Self::Future {
+    let service = self.0.clone();
+    blocking(move || {
+      let descriptor = request.into_inner();
+      let id = descriptor_id(&descriptor)?;
+      flight_info(id, &service.files(id)?).map(Response::new)
+    })
+  }
+}
+
+struct GetSchema(FlightService);
+
+impl UnaryService<FlightDescriptor> for GetSchema {
+  type Response = SchemaResult;
+  type Future = BoxFuture<Response<SchemaResult>, Status>;
+
+  fn call(&mut self, request: Request<FlightDescriptor>) -> Self::Future {
+    let service = self.0.clone();
+    blocking(move || {
+      let descriptor = request.into_inner();
+      let id = descriptor_id(&descriptor)?;
+      let info = flight_info(id, &service.files(id)?)?;
+      Ok(Response::new(SchemaResult { schema: info.schema }))
+    })
+  }
+}
+
+struct DoGet(FlightService);
+
+impl ServerStreamingService<Ticket> for DoGet {
+  type Response = FlightData;
+  type ResponseStream = DataStream<FlightData>;
+  type Future = BoxFuture<Response<Self::ResponseStream>, Status>;
+
+  fn call(&mut self, request: Request<Ticket>) -> Self::Future {
+    let service = self.0.clone();
+    blocking(move || stream(service.do_get(request.into_inner())))
+  }
+}
+
+// Every method has its own codec types.
+fn grpc<T, U>() -> tonic::server::Grpc<tonic::codec::ProstCodec<T, U>>
+  where
+    T: prost::Message + Send + 'static,
+    U: prost::Message + Default + Send + 'static,
+{
+  tonic::server::Grpc::new(tonic::codec::ProstCodec::default())
+}
+
+impl<B> Service<http::Request<B>> for FlightService
+  where
+    B: tonic::codegen::Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+  type Response = http::Response<tonic::body::BoxBody>;
+  type Error = Infallible;
+  type Future = BoxFuture<Self::Response, Self::Error>;
+
+  fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    Poll::Ready(Ok(()))
+  }
+
+  fn call(&mut self, request: http::Request<B>) -> Self::Future {
+    let service = self.clone();
+    let method = request.uri().path().strip_prefix(&format!("/{}/", SERVICE)).unwrap_or_default().to_string();
+    Box::pin(async move {
+      // @formatter:off
+      Ok(match method.as_str() {
+        "ListFlights"   => grpc().server_streaming(ListFlights(service)  , request).await,
+        "GetFlightInfo" => grpc().unary           (GetFlightInfo(service), request).await,
+        "GetSchema"     => grpc().unary           (GetSchema(service)    , request).await,
+        "DoGet"         => grpc().server_streaming(DoGet(service)        , request).await,
+        _ => Status::unimplemented(format!("{} is not supported", method)).into_http(),
+      })
+      // @formatter:on
+    })
+  }
+}
+
+/// Serves the recorded files of the directory over Arrow Flight on the port, from a background
+/// thread with its own runtime.
+pub fn serve(port: u16, directory: PathBuf) -> io::Result<()> {
+  let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(2).enable_all().build()?;
+  let service = FlightService { directory: Arc::new(directory) };
+  log::info!(target: FLIGHT_ID, "Serving recorded data over Arrow Flight on port {}", port);
+  thread::Builder::new()
+    .name(FLIGHT_ID.into())
+    .spawn(move || {
+      let server = tonic::transport::Server::builder().add_service(service).serve(([0, 0, 0, 0], port).into());
+      if let Err(err) = runtime.block_on(server) {
+        log::error!(target: FLIGHT_ID, "Arrow Flight server failed: {}", err);
+      }
+    })?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use std::fs;
+  use std::path::PathBuf;
+  use std::sync::Arc;
+
+  use arrow_array::cast::AsArray;
+  use arrow_array::types::{Float64Type, Int64Type};
+  use arrow_ipc::reader::StreamReader;
+  use arrow_ipc::writer::{write_message, EncodedData, IpcWriteOptions};
+
+  use super::{Criteria, FlightService, Ticket};
+
+  #[test]
+  fn serve_stream_as_record_batches() {
+    let directory = std::env::temp_dir().join(format!("coinbase-flight-{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let trade = |trade_id: i64, price: &str| format!(
+      r#"{{"time":"2020-08-31T15:00:00Z","trade_id":{},"price":"{}","size":"1","side":"buy"}}"#, trade_id, price,
+    ) + "\n";
+    fs::write(directory.join("trades_BTC-USD.1"), trade(1, "100.5")).unwrap();
+    // The last line is still being written.
+    fs::write(directory.join("trades_BTC-USD.2"), trade(2, "101") + r#"{"time":"#).unwrap();
+    fs::write(directory.join("ticker_ETH-USD"), "").unwrap();
+    let service = FlightService { directory: Arc::new(PathBuf::from(&directory)) };
+
+    let flights = service.list_flights(Criteria { expression: b"trades_".to_vec() }).unwrap();
+    assert_eq!(flights.len(), 1);
+    assert_eq!(flights[0].flight_descriptor.as_ref().unwrap().path, vec!["trades_BTC-USD"]);
+
+    // Reassemble the IPC stream the way Flight clients do.
+    let options = IpcWriteOptions::default();
+    let mut stream = Vec::new();
+    for data in service.do_get(Ticket { ticket: b"trades_BTC-USD".to_vec() }).unwrap() {
+      write_message(&mut stream, EncodedData { ipc_message: data.data_header, arrow_data: data.data_body }, &options).unwrap();
+    }
+    let batches: Vec<_> = StreamReader::try_new(stream.as_slice(), None).unwrap().collect::<Result<_, _>>().unwrap();
+    assert_eq!(batches.len(), 1);
+    let batch = &batches[0];
+    assert_eq!(batch.schema().field(2).name(), "trade_id");
+    assert_eq!(batch.column(2).as_primitive::<Int64Type>().values(), &[1, 2]);
+    assert_eq!(batch.column(3).as_primitive::<Float64Type>().values(), &[100.5, 101.0]);
+    assert_eq!(batch.column(1).as_string::<i32>().value(0), "BTC-USD");
+
+    assert!(service.do_get(Ticket { ticket: b"trades_SOL-USD".to_vec() }).is_err());
+    fs::remove_dir_all(&directory).unwrap();
+  }
+}
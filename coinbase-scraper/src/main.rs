@@ -1,180 +1,375 @@
-use std::borrow::BorrowMut;
-use std::collections::HashMap;
-use std::convert::TryFrom;
-use std::fs::{File, OpenOptions};
-use std::io::{BufWriter, LineWriter, Write};
 use std::path::{Path, PathBuf};
-use std::thread;
-use std::time::{Duration, Instant};
+use std::sync::Arc;
+use std::time::Duration;
 
-use serde::Serialize;
+use chrono::{DateTime, Utc};
+use clap::{Arg, ArgMatches, Command};
 
+use coinbase::analytics::{CandleAggregator, DailySummaryHandler};
+use coinbase::order_book::{DepthChangeHandler, DepthSnapshotHandler, TopOfBookHandler};
+use coinbase::rest::CoinbaseRestClient;
 use coinbase::web_socket::common::{Channel, Channels};
-use coinbase::web_socket::response;
-use coinbase::web_socket::{CoinbaseWebSocketClient};
-use coinbase::web_socket::{CoinBaseWebSocketMessageHandler, Terminate};
+use coinbase::web_socket::{CoinBaseWebSocketMessageHandler, CoinbaseWebSocketClient, CompositeCoinBaseWebSocketMessageHandler, StalePolicy};
 
+mod backfill;
+mod config;
+mod convert;
+mod diff;
+#[cfg(feature = "flight")]
+mod flight;
+mod manifest;
+mod metrics;
+mod retention;
+mod taq;
+mod verify;
+mod watch;
+mod writer;
 
-struct WriteToFileVisitor {
-  writers: HashMap<String, LineWriter<File>>,
-  directory: PathBuf,
+use backfill::{BackfillRange, RestTrades};
+use config::ScraperConfig;
+use metrics::{Metrics, MetricsHandler};
+use retention::{DiskGuardHandler, RetentionPolicy};
+use watch::{Dashboard, WatchHandler};
+use writer::{FileWriter, WriterConfig};
+
+
+fn main() -> anyhow::Result<()> {
+  env_logger::init();
+
+  let matches = Command::new("coinbase-scraper")
+    .about("Records coinbase market data into per-product files.")
+    .args_conflicts_with_subcommands(true)
+    .subcommand_negates_reqs(true)
+    .arg(
+      Arg::new("config").long("config").takes_value(true)
+        .help("TOML file with the scraper settings, overridden by COINBASE_SCRAPER_* variables and flags")
+    )
+    .arg(Arg::new("directory").help("Output directory, required unless set in the config"))
+    .arg(
+      Arg::new("depth-interval-ms").long("depth-interval-ms").takes_value(true)
+        .help("Record order book depth snapshots at this interval instead of raw level2 updates")
+    )
+    .arg(
+      Arg::new("depth-change-tolerance").long("depth-change-tolerance").takes_value(true)
+        .conflicts_with("depth-interval-ms")
+        .help("Record order book depth whenever a price of the top levels, or a size by more than this fraction, changes")
+    )
+    .arg(Arg::new("depth-levels").long("depth-levels").takes_value(true).help("Levels per depth snapshot, 10 by default"))
+    .arg(
+      Arg::new("bars").long("bars").takes_value(true).possible_values(["1s", "1m"])
+        .conflicts_with_all(&["depth-interval-ms", "depth-change-tolerance"])
+        .help("Record OHLCV bars (with spread) aligned to the clock instead of raw events")
+    )
+    .arg(
+      Arg::new("taq").long("taq").conflicts_with_all(&["depth-interval-ms", "depth-change-tolerance", "bars"])
+        .help("Record quotes (best bid and ask with sizes) and trades in the TAQ layout instead of raw events")
+    )
+    .arg(
+      Arg::new("daily-summaries").long("daily-summaries")
+        .help("Also record volume, VWAP, high, low and message counts of every product and day")
+    )
+    .arg(
+      Arg::new("day-boundary-hours").long("day-boundary-hours").takes_value(true)
+        .help("Hours after UTC midnight at which days end, 0 by default")
+    )
+    .arg(Arg::new("queue-capacity").long("queue-capacity").takes_value(true).help("100000 by default"))
+    .arg(Arg::new("flush-interval-ms").long("flush-interval-ms").takes_value(true).help("1000 by default"))
+    .arg(Arg::new("fsync").long("fsync").help("Sync files to disk on every flush"))
+    .arg(
+      Arg::new("wal").long("wal").takes_value(true)
+        .help("Keep unflushed records in this write-ahead log and write them again after a crash")
+    )
+    .arg(
+      Arg::new("manifest").long("manifest")
+        .help("Write new files on every start, record them in manifest.json and backfill trades missed since the last run")
+    )
+    .arg(Arg::new("wal-capacity-mb").long("wal-capacity-mb").takes_value(true).help("64 by default"))
+    .arg(Arg::new("max-total-mb").long("max-total-mb").takes_value(true).help("Cap on the size of the output directory"))
+    .arg(Arg::new("min-free-mb").long("min-free-mb").takes_value(true).help("Free space to keep on the output disk"))
+    .arg(
+      Arg::new("retention").long("retention").takes_value(true).possible_values(["delete_oldest", "stop"])
+        .help("What to do once a disk limit is reached, stop by default. delete_oldest deletes files of earlier sessions and needs --manifest")
+    )
+    .arg(
+      Arg::new("metrics-port").long("metrics-port").takes_value(true)
+        .help("Serve Prometheus metrics over HTTP on this port")
+    )
+    .arg(
+      Arg::new("flight-port").long("flight-port").takes_value(true)
+        .help("Serve the recorded data over Arrow Flight on this port, needs the flight feature")
+    )
+    .subcommand(
+      Command::new("backfill")
+        .about("Downloads historical trades through the REST API.")
+        .arg(Arg::new("directory").required(true).help("Output directory"))
+        .arg(Arg::new("product").long("product").takes_value(true).multiple_occurrences(true).required(true))
+        .arg(Arg::new("from-trade-id").long("from-trade-id").takes_value(true))
+        .arg(Arg::new("to-trade-id").long("to-trade-id").takes_value(true))
+        .arg(Arg::new("from").long("from").takes_value(true).help("RFC 3339 timestamp"))
+        .arg(Arg::new("to").long("to").takes_value(true).help("RFC 3339 timestamp"))
+    )
+    .subcommand(
+      Command::new("verify")
+        .about("Checks trade id, sequence and time continuity of recorded files and prints a gap report.")
+        .arg(Arg::new("directory").required(true).help("Directory written by the scraper"))
+    )
+    .subcommand(
+      Command::new("diff")
+        .about("Compares the trade ids, ticker sequences and timing of two recorded datasets.")
+        .arg(Arg::new("a").required(true).help("Directory written by the scraper"))
+        .arg(Arg::new("b").required(true).help("Directory written by another scraper"))
+    )
+    .subcommand(
+      Command::new("convert")
+        .about("Converts recorded JSON lines files into CSV or Parquet files with a column per field.")
+        .arg(Arg::new("files").required(true).multiple_values(true).help("Files written by the scraper"))
+        .arg(Arg::new("output").long("output").takes_value(true).required(true).help("Output directory"))
+        .arg(
+          Arg::new("format").long("format").takes_value(true).default_value("csv")
+            .help("csv, or parquet when built with the parquet feature")
+        )
+    )
+    .subcommand(
+      Command::new("watch")
+        .about("Shows last price, spread, message rate and reconnects of every product in the terminal.")
+        .arg(
+          Arg::new("product").long("product").takes_value(true).multiple_occurrences(true)
+            .help("Products to watch, all online products by default")
+        )
+        .arg(Arg::new("refresh-ms").long("refresh-ms").takes_value(true).default_value("1000"))
+    )
+    .get_matches();
+
+  match matches.subcommand() {
+    Some(("backfill", matches)) => run_backfill(matches),
+    Some(("verify", matches)) => run_verify(matches),
+    Some(("diff", matches)) => run_diff(matches),
+    Some(("convert", matches)) => run_convert(matches),
+    Some(("watch", matches)) => run_watch(matches),
+    _ => run_scraper(&matches),
+  }
 }
 
-impl WriteToFileVisitor {
+fn run_scraper(matches: &ArgMatches) -> anyhow::Result<()> {
+  let mut config = ScraperConfig::load(matches.get_one::<String>("config").map(Path::new))?;
+  if let Some(directory) = matches.get_one::<String>("directory") {
+    config.directory = Some(PathBuf::from(directory));
+  }
+  if let Some(depth_interval) = parse_arg(matches, "depth-interval-ms")? {
+    config.output.depth_interval_ms = Some(depth_interval);
+    config.output.depth_change_tolerance = None;
+    config.output.bars = None;
+  }
+  if let Some(tolerance) = parse_arg(matches, "depth-change-tolerance")? {
+    config.output.depth_change_tolerance = Some(tolerance);
+    config.output.depth_interval_ms = None;
+    config.output.bars = None;
+  }
+  if let Some(bars) = matches.get_one::<String>("bars") {
+    config.output.bars = Some(bars.clone());
+    config.output.depth_interval_ms = None;
+    config.output.depth_change_tolerance = None;
+  }
+  if matches.contains_id("taq") {
+    config.output.taq = true;
+  }
+  config.output.daily_summaries |= matches.contains_id("daily-summaries");
+  config.output.day_boundary_hours = parse_arg(matches, "day-boundary-hours")?.unwrap_or(config.output.day_boundary_hours);
+  config.output.depth_levels = parse_arg(matches, "depth-levels")?.unwrap_or(config.output.depth_levels);
+  config.writer.queue_capacity = parse_arg(matches, "queue-capacity")?.unwrap_or(config.writer.queue_capacity);
+  config.writer.flush_interval_ms = parse_arg(matches, "flush-interval-ms")?.unwrap_or(config.writer.flush_interval_ms);
+  config.writer.fsync |= matches.contains_id("fsync");
+  config.writer.manifest |= matches.contains_id("manifest");
+  if let Some(wal) = matches.get_one::<String>("wal") {
+    config.writer.wal = Some(PathBuf::from(wal));
+  }
+  config.writer.wal_capacity_mb = parse_arg(matches, "wal-capacity-mb")?.unwrap_or(config.writer.wal_capacity_mb);
+  if let Some(max_total_mb) = parse_arg(matches, "max-total-mb")? {
+    config.writer.max_total_mb = Some(max_total_mb);
+  }
+  if let Some(min_free_mb) = parse_arg(matches, "min-free-mb")? {
+    config.writer.min_free_mb = Some(min_free_mb);
+  }
+  if let Some(retention) = matches.get_one::<String>("retention") {
+    config.writer.retention = retention.parse()?;
+  }
+  if config.writer.retention == RetentionPolicy::DeleteOldest && !config.writer.manifest {
+    anyhow::bail!("Retention delete_oldest needs the manifest, only files of earlier sessions are deleted");
+  }
+  let metrics_port: Option<u16> = parse_arg(matches, "metrics-port")?;
+  let flight_port: Option<u16> = parse_arg(matches, "flight-port")?;
+
+  let directory = config.directory.clone()
+    .ok_or_else(|| anyhow::anyhow!("Output directory must be given as an argument or in the config"))?;
+  let bar_interval = config.bar_interval()?;
+  let depth_interval = config.output.depth_interval_ms;
+  let depth_tolerance = config.output.depth_change_tolerance;
 
-  fn new(directory: PathBuf) -> Self {
-    return WriteToFileVisitor { directory, writers: HashMap::new() };
+  let reconnect = &config.reconnect;
+  let mut client = CoinbaseWebSocketClient::production()
+    .backfill_trades_on_reconnect(reconnect.backfill_trades)
+    .supervise(reconnect.supervise);
+  if let Some(secs) = reconnect.stale_timeout_secs {
+    client = client.stale_product_timeout(Duration::from_secs(secs), StalePolicy::Resubscribe);
+  }
+  if let Some(window) = reconnect.deduplicate_window {
+    client = client.deduplicate(window);
+  }
+  let mut channels = config.channels.clone();
+  match flight_port {
+    #[cfg(feature = "flight")]
+    Some(port) => flight::serve(port, directory.clone())?,
+    #[cfg(not(feature = "flight"))]
+    Some(_) => anyhow::bail!("--flight-port needs the scraper built with the flight feature"),
+    None => {}
+  }
+  let writer_config = config.writer_config();
+  let disk_limits = writer_config.disk.is_enabled();
+  let writer = FileWriter::start(directory, writer_config)?;
+  if !writer.last_trade_ids().is_empty() {
+    client = client.resume_trades(writer.last_trade_ids().clone());
+  }
+  let visitor = writer.visitor();
+  let mut handlers: Vec<Box<dyn CoinBaseWebSocketMessageHandler + Send>> = Vec::new();
+  match (bar_interval, depth_interval, depth_tolerance) {
+    _ if config.output.taq => {
+      channels.extend(vec![Channels::Level2, Channels::Matches]);
+      let visitor = visitor.taq(true);
+      handlers.push(Box::new(visitor.clone()));
+      handlers.push(Box::new(TopOfBookHandler::new(visitor)));
+    }
+    (Some(interval), _, _) => {
+      channels.push(Channels::Heartbeat);
+      handlers.push(Box::new(CandleAggregator::new(interval, visitor)));
+    }
+    (None, Some(millis), _) => {
+      channels.extend(vec![Channels::Level2, Channels::Heartbeat]);
+      let depth = DepthSnapshotHandler::new(
+        config.output.depth_levels,
+        Duration::from_millis(millis),
+        writer.visitor(),
+      );
+      handlers.push(Box::new(visitor.write_l2_updates(false)));
+      handlers.push(Box::new(depth));
+    }
+    (None, None, Some(tolerance)) => {
+      channels.push(Channels::Level2);
+      let depth = DepthChangeHandler::new(config.output.depth_levels, writer.visitor()).tolerance(tolerance);
+      handlers.push(Box::new(visitor.write_l2_updates(false)));
+      handlers.push(Box::new(depth));
+    }
+    (None, None, None) => handlers.push(Box::new(visitor)),
+  };
+  if config.output.daily_summaries {
+    client = client.day_rollover(Duration::from_secs(u64::from(config.output.day_boundary_hours) * 3600));
+    channels.extend(vec![Channels::Matches, Channels::Heartbeat]);
+    handlers.push(Box::new(DailySummaryHandler::new(writer.visitor())));
+  }
+  channels.sort();
+  channels.dedup();
+  if disk_limits {
+    handlers.push(Box::new(DiskGuardHandler::new(writer.stats())));
   }
 
-  fn write<T: Serialize>(&mut self, value: T, id: String) {
-    let mut file_path = self.directory.clone();
-    file_path.push(&id);
-    let writer = self.writers.entry(id).or_insert_with(move || {
-      let file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .append(true)
-        .open(&file_path)
-        .expect(format!("Could not open file {}", &file_path.to_string_lossy()).as_str());
-      LineWriter::new(file)
-    });
+  if let Some(port) = metrics_port {
+    let metrics = Arc::new(Metrics::default());
+    metrics::serve(port, metrics.clone(), writer.stats())?;
+    handlers.push(Box::new(MetricsHandler::new(metrics)));
+  }
 
-    let line = serde_json::to_string(&value).unwrap();
-    writer.write_all(line.as_bytes()).expect("");
-    writer.write_all(b"\n").expect("");
+  client.start(CompositeCoinBaseWebSocketMessageHandler::new(handlers))?;
+  let controller = client.controller();
+  if config.products.is_empty() {
+    controller.subscribe_all(Channel::from_names(&channels))?;
+  } else {
+    controller.subscribe(config.products.clone(), Channel::from_names(&channels));
   }
+  let reason = client.wait();
+  log::info!("Client stopped: {:?}", reason);
+
+  let stats = writer.stats();
+  writer.join();
+  log::info!("Written {} messages, dropped {} messages.", stats.written(), stats.dropped());
+
+  Ok(())
 }
 
-impl Drop for WriteToFileVisitor {
-  fn drop(&mut self) {
-    for writer in self.writers.values_mut() {
-      writer.flush();
-    }
+fn run_backfill(matches: &ArgMatches) -> anyhow::Result<()> {
+  let directory = matches.get_one::<String>("directory").unwrap();
+  let range = BackfillRange {
+    from_trade_id: parse_arg(matches, "from-trade-id")?,
+    to_trade_id: parse_arg(matches, "to-trade-id")?,
+    from_time: parse_arg::<DateTime<Utc>>(matches, "from")?,
+    to_time: parse_arg::<DateTime<Utc>>(matches, "to")?,
+  };
+
+  let mut trades = RestTrades::new(CoinbaseRestClient::production());
+  let writer = FileWriter::start(PathBuf::from(directory), WriterConfig::default())?;
+  let mut visitor = writer.visitor().blocking(true);
+  for product_id in matches.get_many::<String>("product").unwrap() {
+    let count = backfill::backfill_product(&mut trades, &mut visitor, product_id, &range)?;
+    log::info!("Backfilled {} trades for {}", count, product_id);
   }
+  drop(visitor);
+  writer.join();
+  Ok(())
 }
 
-impl CoinBaseWebSocketMessageHandler for WriteToFileVisitor {
-  fn on_ticker(&mut self, resp: &response::TickerResponse) -> Result<(), Terminate> {
-    let mut id = "ticker_".to_string();
-    id.push_str(resp.product_id.as_str());
-    self.write(resp, id);
-    Ok(())
+fn run_verify(matches: &ArgMatches) -> anyhow::Result<()> {
+  let directory = matches.get_one::<String>("directory").unwrap();
+  let report = verify::verify_directory(Path::new(directory))?;
+  print!("{}", report);
+  if !report.is_clean() {
+    anyhow::bail!("Dataset in {} is not continuous", directory);
   }
+  Ok(())
+}
 
-  fn on_l2_update(&mut self, resp: &response::L2UpdateResponse) -> Result<(), Terminate> {
-    let mut id = "l2update_".to_string();
-    id.push_str(resp.product_id.as_str());
-    self.write(resp, id);
-    Ok(())
+fn run_diff(matches: &ArgMatches) -> anyhow::Result<()> {
+  let (a, b) = (matches.get_one::<String>("a").unwrap(), matches.get_one::<String>("b").unwrap());
+  let report = diff::diff_directories(Path::new(a), Path::new(b))?;
+  print!("{}", report);
+  if !report.is_identical() {
+    anyhow::bail!("Datasets in {} and {} differ", a, b);
   }
+  Ok(())
 }
 
-fn main() -> anyhow::Result<()> {
-  env_logger::init();
+fn run_convert(matches: &ArgMatches) -> anyhow::Result<()> {
+  let format: convert::Format = matches.get_one::<String>("format").unwrap().parse()?;
+  let output = PathBuf::from(matches.get_one::<String>("output").unwrap());
+  std::fs::create_dir_all(&output)?;
+  for input in matches.get_many::<String>("files").unwrap().map(PathBuf::from) {
+    let mut file_name = input.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(format.extension());
+    let rows = convert::convert_file(&input, &output.join(file_name), format)?;
+    log::info!("Converted {} into {} rows", input.display(), rows);
+  }
+  Ok(())
+}
+
+fn run_watch(matches: &ArgMatches) -> anyhow::Result<()> {
+  let refresh = Duration::from_millis(parse_arg(matches, "refresh-ms")?.unwrap());
+  let dashboard = Arc::new(Dashboard::default());
+  watch::show(dashboard.clone(), refresh)?;
 
-  let directory = std::env::args().nth(1).unwrap();
   let mut client = CoinbaseWebSocketClient::production();
-  let visitor = WriteToFileVisitor::new(
-    PathBuf::from(directory)
-  );
-
-
-  let product_ids = vec![
-    "GNT-USDC",
-    "BAT-ETH",
-    "XRP-EUR",
-    "BCH-GBP",
-    "XTZ-USD",
-    "XLM-EUR",
-    "ETC-BTC",
-    "ETC-GBP",
-    "EOS-USD",
-    "LINK-USD",
-    "LTC-GBP",
-    "BAND-BTC",
-    "MKR-BTC",
-    "ETH-BTC",
-    "KNC-USD",
-    "XTZ-BTC",
-    "NMR-EUR",
-    "BAT-USDC",
-    "ALGO-USD",
-    "LTC-USD",
-    "BCH-USD",
-    "CGLD-USD",
-    "OXT-USD",
-    "ATOM-BTC",
-    "DAI-USDC",
-    "ETH-EUR",
-    "REP-BTC",
-    "XLM-USD",
-    "EOS-BTC",
-    "ZEC-BTC",
-    "ATOM-USD",
-    "OMG-EUR",
-    "LTC-BTC",
-    "ETH-USD",
-    "DAI-USD",
-    "XTZ-GBP",
-    "LTC-EUR",
-    "BTC-USD",
-    "LINK-GBP",
-    "XLM-BTC",
-    "ALGO-GBP",
-    "ZRX-USD",
-    "OMG-USD",
-    "ETH-USDC",
-    "DNT-USDC",
-    "BAND-EUR",
-    "BTC-USDC",
-    "DASH-BTC",
-    "ALGO-EUR",
-    "LINK-EUR",
-    "DASH-USD",
-    "XRP-GBP",
-    "COMP-USD",
-    "XRP-USD",
-    "BTC-EUR",
-    "EOS-EUR",
-    "CGLD-GBP",
-    "XTZ-EUR",
-    "NMR-BTC",
-    "ZEC-USDC",
-    "ETC-USD",
-    "REP-USD",
-    "BAND-USD",
-    "BCH-BTC",
-    "KNC-BTC",
-    "MKR-USD",
-    "ETH-GBP",
-    "XRP-BTC",
-    "OMG-GBP",
-    "OMG-BTC",
-    "ETC-EUR",
-    "CVC-USDC",
-    "ZRX-EUR",
-    "CGLD-BTC",
-    "BTC-GBP",
-    "COMP-BTC",
-    "ZRX-BTC",
-    "BCH-EUR",
-    "ETH-DAI",
-    "LINK-ETH",
-    "NMR-USD",
-    "BAND-GBP",
-    "NMR-GBP",
-    "CGLD-EUR",
-    "MANA-USDC",
-    "LOOM-USDC",
-  ];
-  let product_ids = product_ids.into_iter().map(|name| name.to_string()).collect();
-
-  client.start(visitor);
+  client.start(WatchHandler::new(dashboard))?;
+  let channels = Channel::from_names(&[Channels::Heartbeat, Channels::Ticker, Channels::Matches]);
   let controller = client.controller();
-  controller.subscribe(
-    product_ids, Channel::from_names(&[Channels::Ticker]),
-  );
+  match matches.get_many::<String>("product") {
+    Some(products) => controller.subscribe(products.cloned().collect(), channels),
+    None => controller.subscribe_all(channels)?,
+  }
   client.wait();
+  Ok(())
+}
 
-  return Ok(());
-}
\ No newline at end of file
+fn parse_arg<T>(matches: &ArgMatches, name: &str) -> anyhow::Result<Option<T>>
+  where T: std::str::FromStr, T::Err: std::error::Error + Send + Sync + 'static {
+  match matches.get_one::<String>(name) {
+    Some(value) => Ok(Some(value.parse::<T>()?)),
+    None => Ok(None),
+  }
+}
@@ -0,0 +1,174 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// Last data recorded for a product, across all sessions.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ProductProgress {
+  pub last_trade_id: Option<i64>,
+  /// Sequence of the last ticker.
+  pub last_sequence: Option<i64>,
+  pub last_time: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileEntry {
+  pub session: u64,
+  pub records: u64,
+  pub bytes: u64,
+  /// FNV-1a 64 of the file content.
+  pub checksum: String,
+  #[serde(skip)]
+  hash: u64,
+}
+
+/// Record of everything the scraper has written into a directory, kept in `manifest.json`
+/// next to the data files.
+///
+/// Every start opens a new session and writes into new files suffixed with its number, so
+/// files of finished sessions never change and can be verified against their checksums.
+/// The manifest is saved after the files are flushed, after a crash the entries of the last
+/// session may lag behind its files by one flush.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Manifest {
+  pub session: u64,
+  pub products: BTreeMap<String, ProductProgress>,
+  pub files: BTreeMap<String, FileEntry>,
+  #[serde(skip)]
+  directory: PathBuf,
+}
+
+impl Manifest {
+  /// Loads the manifest of the directory, an empty one if there is none yet.
+  pub fn load(directory: &Path) -> io::Result<Self> {
+    let path = directory.join(MANIFEST_FILE);
+    let mut manifest: Manifest = match fs::read(&path) {
+      Ok(content) => serde_json::from_slice(&content).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+      Err(err) if err.kind() == io::ErrorKind::NotFound => Manifest::default(),
+      Err(err) => return Err(err),
+    };
+    manifest.directory = directory.to_path_buf();
+    for entry in manifest.files.values_mut() {
+      entry.hash = u64::from_str_radix(&entry.checksum, 16).unwrap_or(FNV_OFFSET);
+    }
+    Ok(manifest)
+  }
+
+  /// Starts a new session and returns its number.
+  pub fn begin_session(&mut self) -> u64 {
+    self.session += 1;
+    self.session
+  }
+
+  /// Name of the session's file with records of the given id, e.g. `trades_BTC-USD.3`.
+  pub fn file_name(&self, id: &str) -> String {
+    format!("{}.{}", id, self.session)
+  }
+
+  /// Accounts for a line appended to the file, the line terminator included.
+  pub fn record_line(&mut self, file_name: &str, line: &[u8]) {
+    let session = self.session;
+    let entry = self.files.entry(file_name.into()).or_insert_with(|| FileEntry {
+      session,
+      records: 0,
+      bytes: 0,
+      checksum: String::new(),
+      hash: FNV_OFFSET,
+    });
+    entry.hash = fnv1a(fnv1a(entry.hash, line), b"\n");
+    entry.checksum = format!("{:016x}", entry.hash);
+    entry.records += 1;
+    entry.bytes += line.len() as u64 + 1;
+  }
+
+  pub fn track(&mut self, product_id: &str, trade_id: Option<i64>, sequence: Option<i64>, time: Option<DateTime<Utc>>) {
+    let progress = self.products.entry(product_id.into()).or_default();
+    progress.last_trade_id = progress.last_trade_id.max(trade_id);
+    progress.last_sequence = progress.last_sequence.max(sequence);
+    progress.last_time = progress.last_time.max(time);
+  }
+
+  /// Trades to resume from, see `CoinbaseWebSocketClient::resume_trades`.
+  pub fn last_trade_ids(&self) -> HashMap<String, i64> {
+    self.products.iter()
+      .filter_map(|(product_id, progress)| Some((product_id.clone(), progress.last_trade_id?)))
+      .collect()
+  }
+
+  /// Forgets the files of the oldest finished session and returns the session with the names
+  /// of its files, so they can be deleted. Files of the running session are kept.
+  pub fn remove_oldest_session(&mut self) -> Option<(u64, Vec<String>)> {
+    let oldest = self.files.values().map(|entry| entry.session).filter(|session| *session < self.session).min()?;
+    let file_names: Vec<String> = self.files.iter()
+      .filter(|(_, entry)| entry.session == oldest)
+      .map(|(file_name, _)| file_name.clone())
+      .collect();
+    for file_name in &file_names {
+      self.files.remove(file_name);
+    }
+    Some((oldest, file_names))
+  }
+
+  /// Checksum of the content in the form kept in `FileEntry`.
+  pub fn checksum(content: &[u8]) -> String {
+    format!("{:016x}", fnv1a(FNV_OFFSET, content))
+  }
+
+  /// Writes the manifest into a temporary file and renames it, so a crash never leaves a
+  /// partially written manifest.
+  pub fn save(&self) -> io::Result<()> {
+    let tmp_path = self.directory.join(format!("{}.tmp", MANIFEST_FILE));
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&serde_json::to_vec_pretty(self)?)?;
+    file.sync_data()?;
+    fs::rename(tmp_path, self.directory.join(MANIFEST_FILE))
+  }
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+  for byte in bytes {
+    hash ^= *byte as u64;
+    hash = hash.wrapping_mul(0x0100_0000_01b3);
+  }
+  hash
+}
+
+#[cfg(test)]
+mod test {
+  use super::Manifest;
+
+  #[test]
+  fn resume_from_saved_manifest() {
+    let directory = std::env::temp_dir().join(format!("coinbase-manifest-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+
+    let mut manifest = Manifest::load(&directory).unwrap();
+    assert_eq!(manifest.begin_session(), 1);
+    let file_name = manifest.file_name("trades_BTC-USD");
+    manifest.record_line(&file_name, b"{\"trade_id\":10}");
+    manifest.record_line(&file_name, b"{\"trade_id\":11}");
+    manifest.track("BTC-USD", Some(11), None, None);
+    manifest.track("BTC-USD", Some(10), Some(500), None);
+    manifest.save().unwrap();
+
+    let mut manifest = Manifest::load(&directory).unwrap();
+    assert_eq!(manifest.last_trade_ids().get("BTC-USD"), Some(&11));
+    assert_eq!(manifest.products["BTC-USD"].last_sequence, Some(500));
+    assert_eq!(manifest.begin_session(), 2);
+    assert_eq!(manifest.file_name("trades_BTC-USD"), "trades_BTC-USD.2");
+    let entry = &manifest.files["trades_BTC-USD.1"];
+    assert_eq!((entry.session, entry.records, entry.bytes), (1, 2, 32));
+    let content = b"{\"trade_id\":10}\n{\"trade_id\":11}\n";
+    assert_eq!(entry.checksum, Manifest::checksum(content));
+
+    std::fs::remove_dir_all(&directory).unwrap();
+  }
+}
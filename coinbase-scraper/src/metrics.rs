@@ -0,0 +1,191 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Instant;
+
+use coinbase::web_socket::{response, CoinBaseWebSocketMessageHandler, MessageContext, Terminate};
+
+use crate::writer::WriterStats;
+
+const METRICS_ID: &str = "Metrics";
+
+#[derive(Default)]
+struct FeedState {
+  messages: BTreeMap<&'static str, u64>,
+  last_message: HashMap<String, Instant>,
+}
+
+/// Counters shared between the web socket thread, which updates them through
+/// `MetricsHandler`, and the HTTP thread which renders them.
+#[derive(Default)]
+pub struct Metrics {
+  state: Mutex<FeedState>,
+  connection_id: AtomicU64,
+}
+
+impl Metrics {
+  fn record(&self, channel: &'static str, product_id: &str) {
+    let mut state = self.state.lock().unwrap();
+    *state.messages.entry(channel).or_default() += 1;
+    match state.last_message.get_mut(product_id) {
+      Some(last) => *last = Instant::now(),
+      None => {
+        state.last_message.insert(product_id.into(), Instant::now());
+      }
+    }
+  }
+
+  /// Renders metrics in the Prometheus text exposition format.
+  fn render(&self, writer_stats: &WriterStats) -> String {
+    let mut out = String::new();
+    let state = self.state.lock().unwrap();
+
+    out.push_str("# HELP coinbase_scraper_messages_total Messages received per channel.\n");
+    out.push_str("# TYPE coinbase_scraper_messages_total counter\n");
+    for (channel, count) in state.messages.iter() {
+      let _ = writeln!(out, "coinbase_scraper_messages_total{{channel=\"{}\"}} {}", channel, count);
+    }
+
+    out.push_str("# HELP coinbase_scraper_last_message_age_seconds Time since the last message per product.\n");
+    out.push_str("# TYPE coinbase_scraper_last_message_age_seconds gauge\n");
+    let mut last_message: Vec<_> = state.last_message.iter().collect();
+    last_message.sort();
+    for (product_id, instant) in last_message {
+      let _ = writeln!(
+        out,
+        "coinbase_scraper_last_message_age_seconds{{product_id=\"{}\"}} {:.3}",
+        product_id,
+        instant.elapsed().as_secs_f64()
+      );
+    }
+    drop(state);
+
+    // Connection id starts at 1 for the initial connection.
+    let reconnects = self.connection_id.load(Ordering::Relaxed).saturating_sub(1);
+    out.push_str("# HELP coinbase_scraper_reconnects_total Reconnects to the web socket feed.\n");
+    out.push_str("# TYPE coinbase_scraper_reconnects_total counter\n");
+    let _ = writeln!(out, "coinbase_scraper_reconnects_total {}", reconnects);
+
+    out.push_str("# HELP coinbase_scraper_records_written_total Records written to files.\n");
+    out.push_str("# TYPE coinbase_scraper_records_written_total counter\n");
+    let _ = writeln!(out, "coinbase_scraper_records_written_total {}", writer_stats.written());
+
+    out.push_str("# HELP coinbase_scraper_records_dropped_total Records dropped because the writer could not keep up.\n");
+    out.push_str("# TYPE coinbase_scraper_records_dropped_total counter\n");
+    let _ = writeln!(out, "coinbase_scraper_records_dropped_total {}", writer_stats.dropped());
+
+    out.push_str("# HELP coinbase_scraper_bytes_written_total Bytes written to files.\n");
+    out.push_str("# TYPE coinbase_scraper_bytes_written_total counter\n");
+    let _ = writeln!(out, "coinbase_scraper_bytes_written_total {}", writer_stats.bytes_written());
+    out
+  }
+}
+
+/// Serves the metrics on `0.0.0.0:<port>` from a background thread. Every request,
+/// regardless of the path, gets the metrics page.
+pub fn serve(port: u16, metrics: Arc<Metrics>, writer_stats: Arc<WriterStats>) -> std::io::Result<()> {
+  let listener = TcpListener::bind(("0.0.0.0", port))?;
+  log::info!(target: METRICS_ID, "Serving metrics on port {}", port);
+  thread::Builder::new()
+    .name(METRICS_ID.into())
+    .spawn(move || {
+      for stream in listener.incoming() {
+        let result = stream.and_then(|stream| respond(stream, &metrics, &writer_stats));
+        if let Err(err) = result {
+          log::warn!(target: METRICS_ID, "Could not serve metrics request: {}", err);
+        }
+      }
+    })?;
+  Ok(())
+}
+
+fn respond(mut stream: TcpStream, metrics: &Metrics, writer_stats: &WriterStats) -> std::io::Result<()> {
+  // Consume the request head, body is never expected.
+  let mut reader = BufReader::new(stream.try_clone()?);
+  let mut line = String::new();
+  while reader.read_line(&mut line)? > 2 {
+    line.clear();
+  }
+
+  let body = metrics.render(writer_stats);
+  write!(
+    stream,
+    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+    body.len(),
+    body
+  )?;
+  stream.flush()
+}
+
+/// Handler that counts received messages for the metrics endpoint.
+pub struct MetricsHandler {
+  metrics: Arc<Metrics>,
+}
+
+impl MetricsHandler {
+  pub fn new(metrics: Arc<Metrics>) -> Self {
+    MetricsHandler { metrics }
+  }
+}
+
+impl CoinBaseWebSocketMessageHandler for MetricsHandler {
+  fn on_message_context(&mut self, ctx: &MessageContext) -> Result<(), Terminate> {
+    self.metrics.connection_id.store(ctx.connection_id, Ordering::Relaxed);
+    Ok(())
+  }
+
+  fn on_heartbeat(&mut self, resp: &response::HeartBeatResponse) -> Result<(), Terminate> {
+    self.metrics.record("heartbeat", &resp.product_id);
+    Ok(())
+  }
+
+  fn on_ticker(&mut self, resp: &response::TickerResponse) -> Result<(), Terminate> {
+    self.metrics.record("ticker", &resp.product_id);
+    Ok(())
+  }
+
+  fn on_snapshot(&mut self, resp: &response::SnapshotResponse) -> Result<(), Terminate> {
+    self.metrics.record("snapshot", &resp.product_id);
+    Ok(())
+  }
+
+  fn on_l2_update(&mut self, resp: &response::L2UpdateResponse) -> Result<(), Terminate> {
+    self.metrics.record("l2update", &resp.product_id);
+    Ok(())
+  }
+
+  fn on_match(&mut self, resp: &response::MatchResponse) -> Result<(), Terminate> {
+    self.metrics.record("match", &resp.product_id);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::sync::Arc;
+
+  use super::{Metrics, MetricsHandler};
+  use crate::writer::WriterStats;
+  use coinbase::web_socket::CoinBaseWebSocketMessageHandler;
+
+  #[test]
+  fn render_counters() -> Result<(), serde_json::error::Error> {
+    let metrics = Arc::new(Metrics::default());
+    let mut handler = MetricsHandler::new(metrics.clone());
+    let heartbeat = serde_json::from_str(r#"{
+      "sequence": 90, "last_trade_id": 20, "product_id": "BTC-USD", "time": "2014-11-07T08:19:28.464459Z"
+    }"#)?;
+    handler.on_heartbeat(&heartbeat).unwrap();
+    handler.on_heartbeat(&heartbeat).unwrap();
+
+    let rendered = metrics.render(&WriterStats::default());
+    assert!(rendered.contains("coinbase_scraper_messages_total{channel=\"heartbeat\"} 2\n"));
+    assert!(rendered.contains("coinbase_scraper_last_message_age_seconds{product_id=\"BTC-USD\"}"));
+    assert!(rendered.contains("coinbase_scraper_reconnects_total 0\n"));
+    Ok(())
+  }
+}
@@ -0,0 +1,180 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use coinbase::web_socket::{CoinBaseWebSocketMessageHandler, MessageContext, Terminate};
+
+use crate::manifest::Manifest;
+use crate::writer::WriterStats;
+
+const RETENTION_ID: &str = "Retention";
+
+/// What happens once the output directory reaches its size cap or the disk its free space
+/// threshold.
+#[derive(Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RetentionPolicy {
+  /// Delete files of the oldest finished sessions, needs the manifest.
+  DeleteOldest,
+  /// Stop recording, files are flushed and the scraper exits.
+  Stop,
+}
+
+impl FromStr for RetentionPolicy {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "delete_oldest" => Ok(RetentionPolicy::DeleteOldest),
+      "stop" => Ok(RetentionPolicy::Stop),
+      _ => Err(anyhow::anyhow!("Unknown retention policy {}, expected delete_oldest or stop", s)),
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiskLimits {
+  /// Cap on the size of all files in the output directory.
+  pub max_total_bytes: Option<u64>,
+  /// Free space the disk of the output directory must keep.
+  pub min_free_bytes: Option<u64>,
+  pub policy: RetentionPolicy,
+}
+
+impl Default for DiskLimits {
+  fn default() -> Self {
+    DiskLimits { max_total_bytes: None, min_free_bytes: None, policy: RetentionPolicy::Stop }
+  }
+}
+
+impl DiskLimits {
+  pub fn is_enabled(&self) -> bool {
+    self.max_total_bytes.is_some() || self.min_free_bytes.is_some()
+  }
+
+  /// Whether the directory is within the limits.
+  pub fn check(&self, directory: &Path) -> io::Result<bool> {
+    if let Some(max_total_bytes) = self.max_total_bytes {
+      if directory_size(directory)? > max_total_bytes {
+        return Ok(false);
+      }
+    }
+    if let Some(min_free_bytes) = self.min_free_bytes {
+      if fs2::available_space(directory)? < min_free_bytes {
+        return Ok(false);
+      }
+    }
+    Ok(true)
+  }
+
+  /// Applies the policy when the directory is over the limits. Returns `false` once recording
+  /// must stop, because of the policy or since nothing is left to delete.
+  pub fn enforce(&self, directory: &Path, manifest: Option<&mut Manifest>) -> io::Result<bool> {
+    if self.check(directory)? {
+      return Ok(true);
+    }
+    let manifest = match (self.policy, manifest) {
+      (RetentionPolicy::DeleteOldest, Some(manifest)) => manifest,
+      _ => {
+        log::error!(target: RETENTION_ID, "Disk limits of {} reached, stopping.", directory.display());
+        return Ok(false);
+      }
+    };
+    loop {
+      let (session, file_names) = match manifest.remove_oldest_session() {
+        Some(removed) => removed,
+        None => {
+          log::error!(target: RETENTION_ID, "Disk limits of {} reached with no earlier sessions left to delete, stopping.", directory.display());
+          return Ok(false);
+        }
+      };
+      for file_name in &file_names {
+        match fs::remove_file(directory.join(file_name)) {
+          Ok(()) => {}
+          Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+          Err(err) => return Err(err),
+        }
+      }
+      manifest.save()?;
+      log::warn!(target: RETENTION_ID, "Deleted {} files of session {} to stay within the disk limits.", file_names.len(), session);
+      if self.check(directory)? {
+        return Ok(true);
+      }
+    }
+  }
+}
+
+fn directory_size(directory: &Path) -> io::Result<u64> {
+  let mut size = 0;
+  for entry in fs::read_dir(directory)? {
+    let metadata = entry?.metadata()?;
+    if metadata.is_file() {
+      size += metadata.len();
+    }
+  }
+  Ok(size)
+}
+
+/// Terminates the client once the writer stopped recording because of the disk limits.
+pub struct DiskGuardHandler {
+  stats: Arc<WriterStats>,
+}
+
+impl DiskGuardHandler {
+  pub fn new(stats: Arc<WriterStats>) -> Self {
+    DiskGuardHandler { stats }
+  }
+}
+
+impl CoinBaseWebSocketMessageHandler for DiskGuardHandler {
+  fn on_message_context(&mut self, _ctx: &MessageContext) -> Result<(), Terminate> {
+    if self.stats.disk_full() {
+      return Err(Terminate);
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::fs;
+
+  use super::{DiskLimits, RetentionPolicy};
+  use crate::manifest::Manifest;
+
+  #[test]
+  fn delete_oldest_sessions_over_cap() {
+    let directory = std::env::temp_dir().join(format!("coinbase-retention-{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+
+    let mut manifest = Manifest::load(&directory).unwrap();
+    for _ in 0..3 {
+      manifest.begin_session();
+      let file_name = manifest.file_name("trades_BTC-USD");
+      let line = vec![b'x'; 999];
+      manifest.record_line(&file_name, &line);
+      fs::write(directory.join(&file_name), [line, b"\n".to_vec()].concat()).unwrap();
+    }
+    manifest.save().unwrap();
+
+    let manifest_size = fs::metadata(directory.join("manifest.json")).unwrap().len();
+    let limits = DiskLimits { max_total_bytes: Some(2_000 + manifest_size), min_free_bytes: None, policy: RetentionPolicy::DeleteOldest };
+    assert!(limits.enforce(&directory, Some(&mut manifest)).unwrap());
+    assert!(!directory.join("trades_BTC-USD.1").exists());
+    assert!(directory.join("trades_BTC-USD.2").exists());
+    assert_eq!(Manifest::load(&directory).unwrap().files.len(), 2);
+
+    // The running session is never deleted.
+    let limits = DiskLimits { max_total_bytes: Some(10), ..limits };
+    assert!(!limits.enforce(&directory, Some(&mut manifest)).unwrap());
+    assert!(directory.join("trades_BTC-USD.3").exists());
+    let stop = DiskLimits { policy: RetentionPolicy::Stop, ..limits };
+    assert!(!stop.enforce(&directory, None).unwrap());
+
+    fs::remove_dir_all(&directory).unwrap();
+  }
+}
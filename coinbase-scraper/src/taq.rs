@@ -0,0 +1,77 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use coinbase::decimal::Decimal;
+use coinbase::order_book::TopOfBook;
+use coinbase::rest::Trade;
+use coinbase::web_socket::response::Side;
+
+/// Quote record of the TAQ (trade and quote) output, written whenever the best bid or ask or
+/// their sizes change. Sides without orders are null.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TaqQuote {
+  pub timestamp: DateTime<Utc>,
+  pub product: String,
+  pub bid: Option<Decimal>,
+  pub ask: Option<Decimal>,
+  pub bidsize: Option<Decimal>,
+  pub asksize: Option<Decimal>,
+}
+
+impl From<&TopOfBook> for TaqQuote {
+  fn from(top: &TopOfBook) -> Self {
+    TaqQuote {
+      timestamp: top.time,
+      product: top.product_id.clone(),
+      bid: top.bid.as_ref().map(|level| level.price.clone()),
+      ask: top.ask.as_ref().map(|level| level.price.clone()),
+      bidsize: top.bid.as_ref().map(|level| level.size.clone()),
+      asksize: top.ask.as_ref().map(|level| level.size.clone()),
+    }
+  }
+}
+
+/// Trade record of the TAQ output, `side` is the side of the maker order like in the feed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TaqTrade {
+  pub timestamp: DateTime<Utc>,
+  pub product: String,
+  pub price: Decimal,
+  pub size: Decimal,
+  pub side: Side,
+}
+
+impl TaqTrade {
+  pub fn new(product_id: &str, trade: &Trade) -> Self {
+    TaqTrade {
+      timestamp: trade.time,
+      product: product_id.into(),
+      price: trade.price.clone(),
+      size: trade.size.clone(),
+      side: trade.side,
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use coinbase::order_book::{Level, TopOfBook};
+
+  use super::TaqQuote;
+
+  #[test]
+  fn quote_from_top_of_book() {
+    let top = TopOfBook {
+      product_id: "BTC-USD".into(),
+      time: "2020-08-31T15:00:00Z".parse().unwrap(),
+      bid: Some(Level { price: "100.5".parse().unwrap(), size: "2".parse().unwrap() }),
+      ask: None,
+      coalesced: 0,
+    };
+    let line = serde_json::to_string(&TaqQuote::from(&top)).unwrap();
+    assert_eq!(
+      line,
+      r#"{"timestamp":"2020-08-31T15:00:00Z","product":"BTC-USD","bid":"100.5","ask":null,"bidsize":"2","asksize":null}"#
+    );
+  }
+}
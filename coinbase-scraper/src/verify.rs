@@ -0,0 +1,249 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::manifest::Manifest;
+
+/// Fields of the recorded records that continuity is checked on.
+#[derive(Deserialize)]
+pub(crate) struct Line {
+  pub(crate) trade_id: Option<i64>,
+  pub(crate) sequence: Option<i64>,
+  // TAQ records name it `timestamp`.
+  #[serde(alias = "timestamp")]
+  time: Option<DateTime<Utc>>,
+  // Bars are timed by their start.
+  start: Option<DateTime<Utc>>,
+}
+
+impl Line {
+  pub(crate) fn time(&self) -> Option<DateTime<Utc>> {
+    self.time.or(self.start)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Issue {
+  /// Trades `from..=to` are missing.
+  TradeGap { from: i64, to: i64 },
+  TradeIdRegression { previous: i64, trade_id: i64 },
+  SequenceRegression { previous: i64, sequence: i64 },
+  TimeRegression { previous: DateTime<Utc>, time: DateTime<Utc> },
+  Unparsable { error: String },
+  /// File differs from its manifest entry, expected for the last flush of a crashed session.
+  ManifestMismatch { records: u64, checksum: String, expected_records: u64, expected_checksum: String },
+  MissingFile,
+}
+
+impl Display for Issue {
+  fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+    match self {
+      Issue::TradeGap { from, to } => write!(f, "missing {} trades {}..={}", to - from + 1, from, to),
+      Issue::TradeIdRegression { previous, trade_id } => write!(f, "trade id {} after {}", trade_id, previous),
+      Issue::SequenceRegression { previous, sequence } => write!(f, "sequence {} after {}", sequence, previous),
+      Issue::TimeRegression { previous, time } => write!(f, "time {} after {}", time.to_rfc3339(), previous.to_rfc3339()),
+      Issue::Unparsable { error } => write!(f, "unparsable record: {}", error),
+      Issue::ManifestMismatch { records, checksum, expected_records, expected_checksum } => write!(
+        f, "{} records with checksum {}, manifest has {} records with checksum {}",
+        records, checksum, expected_records, expected_checksum,
+      ),
+      Issue::MissingFile => write!(f, "file listed in the manifest does not exist"),
+    }
+  }
+}
+
+/// Issue at a line of a file, line 0 stands for the whole file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+  pub file: String,
+  pub line: u64,
+  pub issue: Issue,
+}
+
+/// Records of one kind for one product, e.g. `trades_BTC-USD`, over all its session files.
+#[derive(Debug, Default)]
+pub struct Stream {
+  pub files: Vec<String>,
+  pub records: u64,
+  pub first_time: Option<DateTime<Utc>>,
+  pub last_time: Option<DateTime<Utc>>,
+  pub missing_trades: u64,
+  last_trade_id: Option<i64>,
+  last_sequence: Option<i64>,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+  pub streams: BTreeMap<String, Stream>,
+  pub findings: Vec<Finding>,
+}
+
+impl Report {
+  pub fn is_clean(&self) -> bool {
+    self.findings.is_empty()
+  }
+}
+
+impl Display for Report {
+  fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+    let time = |time: Option<DateTime<Utc>>| time.map_or("-".to_string(), |time| time.to_rfc3339());
+    for (id, stream) in &self.streams {
+      writeln!(
+        f, "{}: {} records in {} files, {} .. {}, {} missing trades",
+        id, stream.records, stream.files.len(), time(stream.first_time), time(stream.last_time), stream.missing_trades,
+      )?;
+    }
+    for finding in &self.findings {
+      writeln!(f, "{}:{}: {}", finding.file, finding.line, finding.issue)?;
+    }
+    writeln!(f, "{} problems found", self.findings.len())
+  }
+}
+
+/// Splits `trades_BTC-USD.3` into the stream id and the session, files written without a
+/// manifest belong to session 0.
+pub(crate) fn stream_of(file_name: &str) -> (&str, u64) {
+  match file_name.rsplit_once('.') {
+    Some((id, session)) => match session.parse() {
+      Ok(session) => (id, session),
+      Err(_) => (file_name, 0),
+    },
+    None => (file_name, 0),
+  }
+}
+
+/// Stream id, session and name of the directory's data files, in session order per stream.
+pub(crate) fn recorded_files(directory: &Path) -> io::Result<Vec<(String, u64, String)>> {
+  let mut files: Vec<(String, u64, String)> = Vec::new();
+  for entry in fs::read_dir(directory)? {
+    let file_name = entry?.file_name().to_string_lossy().into_owned();
+    let (id, session) = stream_of(&file_name);
+    let prefixes = ["ticker_", "l2update_", "trades_", "depth_", "bars_", "taq_quotes_", "taq_trades_"];
+    if prefixes.iter().any(|prefix| id.starts_with(prefix)) {
+      files.push((id.to_string(), session, file_name));
+    }
+  }
+  files.sort();
+  Ok(files)
+}
+
+/// Checks files written by the scraper into the directory: trade ids must be consecutive,
+/// ticker sequences and record times must not go back, across all session files of a stream.
+/// Files are also compared with the manifest when there is one.
+pub fn verify_directory(directory: &Path) -> io::Result<Report> {
+  let files = recorded_files(directory)?;
+
+  let manifest = match directory.join("manifest.json").exists() {
+    true => Some(Manifest::load(directory)?),
+    false => None,
+  };
+  let mut report = Report::default();
+  for (id, _, file_name) in &files {
+    let content = fs::read(directory.join(file_name))?;
+    if let Some(expected) = manifest.as_ref().and_then(|manifest| manifest.files.get(file_name)) {
+      let records = content.iter().filter(|byte| **byte == b'\n').count() as u64;
+      let checksum = Manifest::checksum(&content);
+      if records != expected.records || checksum != expected.checksum {
+        let issue = Issue::ManifestMismatch {
+          records,
+          checksum,
+          expected_records: expected.records,
+          expected_checksum: expected.checksum.clone(),
+        };
+        report.findings.push(Finding { file: file_name.clone(), line: 0, issue });
+      }
+    }
+    let stream = report.streams.entry(id.clone()).or_default();
+    stream.files.push(file_name.clone());
+    check_file(file_name, &content, stream, &mut report.findings);
+  }
+  if let Some(manifest) = &manifest {
+    for file_name in manifest.files.keys().filter(|file_name| !directory.join(file_name).exists()) {
+      report.findings.push(Finding { file: file_name.clone(), line: 0, issue: Issue::MissingFile });
+    }
+  }
+  Ok(report)
+}
+
+fn check_file(file_name: &str, content: &[u8], stream: &mut Stream, findings: &mut Vec<Finding>) {
+  let mut report = |line: usize, issue: Issue| findings.push(Finding { file: file_name.into(), line: line as u64 + 1, issue });
+  for (index, line) in content.split(|byte| *byte == b'\n').enumerate() {
+    if line.is_empty() {
+      continue;
+    }
+    let record: Line = match serde_json::from_slice(line) {
+      Ok(record) => record,
+      Err(err) => {
+        report(index, Issue::Unparsable { error: err.to_string() });
+        continue;
+      }
+    };
+    stream.records += 1;
+
+    if let Some(time) = record.time() {
+      match stream.last_time {
+        Some(previous) if time < previous => report(index, Issue::TimeRegression { previous, time }),
+        _ => stream.last_time = Some(time),
+      }
+      stream.first_time = Some(stream.first_time.map_or(time, |first| first.min(time)));
+    }
+    if let Some(sequence) = record.sequence {
+      match stream.last_sequence {
+        Some(previous) if sequence <= previous => report(index, Issue::SequenceRegression { previous, sequence }),
+        _ => stream.last_sequence = Some(sequence),
+      }
+    }
+    // Tickers carry the id of the last trade but are not sent for every trade.
+    if let (Some(trade_id), None) = (record.trade_id, record.sequence) {
+      match stream.last_trade_id {
+        Some(previous) if trade_id <= previous => report(index, Issue::TradeIdRegression { previous, trade_id }),
+        Some(previous) => {
+          if trade_id > previous + 1 {
+            stream.missing_trades += (trade_id - previous - 1) as u64;
+            report(index, Issue::TradeGap { from: previous + 1, to: trade_id - 1 });
+          }
+          stream.last_trade_id = Some(trade_id);
+        }
+        None => stream.last_trade_id = Some(trade_id),
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::fs;
+
+  use super::{verify_directory, Issue};
+
+  #[test]
+  fn report_gaps_across_sessions() {
+    let directory = std::env::temp_dir().join(format!("coinbase-verify-{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let trade = |trade_id: i64, time: &str| format!(
+      r#"{{"time":"{}","trade_id":{},"price":"100","size":"1","side":"buy"}}"#, time, trade_id,
+    ) + "\n";
+    let first = trade(1, "2020-08-31T15:00:00Z") + &trade(2, "2020-08-31T15:00:01Z");
+    let second = trade(5, "2020-08-31T15:00:05Z") + &trade(6, "2020-08-31T15:00:04Z") + "{\n";
+    fs::write(directory.join("trades_BTC-USD.1"), first).unwrap();
+    fs::write(directory.join("trades_BTC-USD.2"), second).unwrap();
+    fs::write(directory.join("notes.txt"), "ignored").unwrap();
+
+    let report = verify_directory(&directory).unwrap();
+    let stream = &report.streams["trades_BTC-USD"];
+    assert_eq!((stream.records, stream.files.len(), stream.missing_trades), (4, 2, 2));
+    let issues: Vec<_> = report.findings.iter().map(|finding| (finding.file.as_str(), finding.line, &finding.issue)).collect();
+    assert_eq!(issues.len(), 3);
+    assert_eq!(issues[0], ("trades_BTC-USD.2", 1, &Issue::TradeGap { from: 3, to: 4 }));
+    assert!(matches!(issues[1], ("trades_BTC-USD.2", 2, Issue::TimeRegression { .. })));
+    assert!(matches!(issues[2], ("trades_BTC-USD.2", 3, Issue::Unparsable { .. })));
+    assert!(!report.is_clean());
+
+    fs::remove_dir_all(&directory).unwrap();
+  }
+}
@@ -0,0 +1,159 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use coinbase::decimal::Decimal;
+use coinbase::web_socket::{response, CoinBaseWebSocketMessageHandler, MessageContext, Terminate};
+
+const WATCH_ID: &str = "Watch";
+
+#[derive(Default)]
+struct ProductRow {
+  last_price: Option<Decimal>,
+  best_bid: Option<Decimal>,
+  best_ask: Option<Decimal>,
+  messages: u64,
+  // Messages counted at the previous render, for the rate.
+  rendered_messages: u64,
+  last_message: Option<Instant>,
+}
+
+#[derive(Default)]
+struct DashboardState {
+  products: BTreeMap<String, ProductRow>,
+  connection_id: u64,
+  last_render: Option<Instant>,
+}
+
+/// Feed state shown by `watch`, updated by `WatchHandler` on the web socket thread.
+#[derive(Default)]
+pub struct Dashboard {
+  state: Mutex<DashboardState>,
+}
+
+impl Dashboard {
+  fn update<F: FnOnce(&mut ProductRow)>(&self, product_id: &str, update: F) {
+    let mut state = self.state.lock().unwrap();
+    if !state.products.contains_key(product_id) {
+      state.products.insert(product_id.into(), ProductRow::default());
+    }
+    let row = state.products.get_mut(product_id).unwrap();
+    row.messages += 1;
+    row.last_message = Some(Instant::now());
+    update(row);
+  }
+
+  /// Renders the table of products, message rates are averaged since the previous render.
+  fn render(&self, now: Instant) -> String {
+    let mut state = self.state.lock().unwrap();
+    let elapsed = state.last_render.map(|last| now.duration_since(last).as_secs_f64()).unwrap_or_default();
+    state.last_render = Some(now);
+    let mut out = String::new();
+    // Connection id starts at 1 for the initial connection.
+    let _ = writeln!(out, "coinbase feed, {} products, {} reconnects", state.products.len(), state.connection_id.saturating_sub(1));
+    let _ = writeln!(out);
+    let _ = writeln!(out, "{:<12} {:>14} {:>14} {:>14} {:>10} {:>8} {:>9}", "PRODUCT", "LAST", "BID", "ASK", "SPREAD", "MSG/S", "AGE");
+    let price = |price: &Option<Decimal>| price.as_ref().map_or("-".to_string(), |price| price.to_string());
+    for (product_id, row) in state.products.iter_mut() {
+      let spread = match (&row.best_bid, &row.best_ask) {
+        (Some(bid), Some(ask)) => (ask - bid).to_string(),
+        _ => "-".to_string(),
+      };
+      let rate = if elapsed > 0.0 { (row.messages - row.rendered_messages) as f64 / elapsed } else { 0.0 };
+      row.rendered_messages = row.messages;
+      let age = row.last_message.map_or("-".to_string(), |last| format!("{:.1}s", now.saturating_duration_since(last).as_secs_f64()));
+      let _ = writeln!(
+        out, "{:<12} {:>14} {:>14} {:>14} {:>10} {:>8.1} {:>9}",
+        product_id, price(&row.last_price), price(&row.best_bid), price(&row.best_ask), spread, rate, age,
+      );
+    }
+    out
+  }
+}
+
+/// Redraws the dashboard on the terminal every `refresh` from a background thread.
+pub fn show(dashboard: Arc<Dashboard>, refresh: Duration) -> io::Result<()> {
+  thread::Builder::new()
+    .name(WATCH_ID.into())
+    .spawn(move || loop {
+      let screen = dashboard.render(Instant::now());
+      let mut stdout = io::stdout();
+      // Clear the screen and move the cursor home.
+      if write!(stdout, "\x1b[2J\x1b[H{}", screen).and_then(|_| stdout.flush()).is_err() {
+        log::warn!(target: WATCH_ID, "Could not draw the dashboard.");
+      }
+      thread::sleep(refresh);
+    })?;
+  Ok(())
+}
+
+/// Handler that keeps the dashboard up to date.
+pub struct WatchHandler {
+  dashboard: Arc<Dashboard>,
+}
+
+impl WatchHandler {
+  pub fn new(dashboard: Arc<Dashboard>) -> Self {
+    WatchHandler { dashboard }
+  }
+}
+
+impl CoinBaseWebSocketMessageHandler for WatchHandler {
+  fn on_message_context(&mut self, ctx: &MessageContext) -> Result<(), Terminate> {
+    self.dashboard.state.lock().unwrap().connection_id = ctx.connection_id;
+    Ok(())
+  }
+
+  fn on_heartbeat(&mut self, resp: &response::HeartBeatResponse) -> Result<(), Terminate> {
+    self.dashboard.update(&resp.product_id, |_| {});
+    Ok(())
+  }
+
+  fn on_ticker(&mut self, resp: &response::TickerResponse) -> Result<(), Terminate> {
+    self.dashboard.update(&resp.product_id, |row| {
+      row.last_price = Some(resp.price.clone());
+      row.best_bid = Some(resp.best_bid.clone());
+      row.best_ask = Some(resp.best_ask.clone());
+    });
+    Ok(())
+  }
+
+  fn on_match(&mut self, resp: &response::MatchResponse) -> Result<(), Terminate> {
+    self.dashboard.update(&resp.product_id, |row| row.last_price = Some(resp.price.clone()));
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::sync::Arc;
+  use std::time::{Duration, Instant};
+
+  use coinbase::web_socket::CoinBaseWebSocketMessageHandler;
+
+  use super::{Dashboard, WatchHandler};
+
+  #[test]
+  fn render_products() -> Result<(), serde_json::error::Error> {
+    let dashboard = Arc::new(Dashboard::default());
+    let mut handler = WatchHandler::new(dashboard.clone());
+    let start = Instant::now();
+    dashboard.render(start);
+    let ticker = serde_json::from_str(r#"{
+      "type": "ticker", "trade_id": 20153558, "sequence": 3262786978, "time": "2020-08-31T15:05:14.336755Z",
+      "product_id": "ETH-USD", "price": "425.00", "side": "buy", "last_size": "1.0", "best_bid": "424.99", "best_ask": "425.01"
+    }"#)?;
+    handler.on_ticker(&ticker).unwrap();
+    handler.on_ticker(&ticker).unwrap();
+
+    let screen = dashboard.render(start + Duration::from_secs(1));
+    assert!(screen.starts_with("coinbase feed, 1 products, 0 reconnects\n"));
+    let row = screen.lines().last().unwrap();
+    let columns: Vec<&str> = row.split_whitespace().collect();
+    assert_eq!(&columns[..6], &["ETH-USD", "425.00", "424.99", "425.01", "0.02", "2.0"]);
+    Ok(())
+  }
+}
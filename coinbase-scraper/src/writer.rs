@@ -0,0 +1,564 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use crossbeam::{RecvTimeoutError, Sender, TrySendError};
+
+use coinbase::analytics::{Candle, CandleSink, DailySummary, DailySummarySink};
+use coinbase::order_book::{DepthSnapshot, DepthSnapshotSink, TopOfBook, TopOfBookSink};
+use coinbase::rest::Trade;
+use coinbase::sinks::{Codec, JsonCodec, SinkError, WriteAheadLog};
+use coinbase::web_socket::response;
+use coinbase::web_socket::{CoinBaseWebSocketMessageHandler, Terminate};
+
+use crate::manifest::Manifest;
+use crate::retention::DiskLimits;
+use crate::taq::{TaqQuote, TaqTrade};
+
+const FILE_WRITER_ID: &str = "FileWriter";
+const DISK_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Message that should be appended to the per-product file.
+/// Serialization happens on the writer thread, off the web socket thread.
+enum Record {
+  Ticker(response::TickerResponse),
+  L2Update(response::L2UpdateResponse),
+  Trade { product_id: String, trade: Trade },
+  Depth(DepthSnapshot),
+  Bar(Candle),
+  Quote(TaqQuote),
+  TaqTrade(TaqTrade),
+  Summary(DailySummary),
+}
+
+impl Record {
+  fn file_id(&self) -> String {
+    let (prefix, product_id) = match self {
+      Record::Ticker(resp) => ("ticker_", resp.product_id.as_str()),
+      Record::L2Update(resp) => ("l2update_", resp.product_id.as_str()),
+      // Trades coming from the live `matches` channel and from the REST backfill end up in the
+      // same file, so that gaps in the live data can be filled later on.
+      Record::Trade { product_id, .. } => ("trades_", product_id.as_str()),
+      Record::Depth(snapshot) => ("depth_", snapshot.product_id.as_str()),
+      Record::Bar(candle) => ("bars_", candle.product_id.as_str()),
+      Record::Quote(quote) => ("taq_quotes_", quote.product.as_str()),
+      Record::TaqTrade(trade) => ("taq_trades_", trade.product.as_str()),
+      Record::Summary(summary) => ("summary_", summary.product_id.as_str()),
+    };
+    let mut id = prefix.to_string();
+    id.push_str(product_id);
+    id
+  }
+
+  /// Product of the record with its trade id, ticker sequence and time, for the manifest.
+  fn progress(&self) -> (&str, Option<i64>, Option<i64>, DateTime<Utc>) {
+    match self {
+      Record::Ticker(resp) => (&resp.product_id, None, Some(resp.sequence), resp.time),
+      Record::L2Update(resp) => (&resp.product_id, None, None, resp.time),
+      Record::Trade { product_id, trade } => (product_id, Some(trade.trade_id), None, trade.time),
+      Record::Depth(snapshot) => (&snapshot.product_id, None, None, snapshot.time),
+      Record::Bar(candle) => (&candle.product_id, None, None, candle.start),
+      Record::Quote(quote) => (&quote.product, None, None, quote.timestamp),
+      Record::TaqTrade(trade) => (&trade.product, None, None, trade.timestamp),
+      Record::Summary(summary) => (&summary.product_id, None, None, summary.date.and_hms_opt(0, 0, 0).unwrap().and_utc()),
+    }
+  }
+
+  fn encode<C: Codec>(&self, codec: &C) -> Result<Vec<u8>, SinkError> {
+    match self {
+      Record::Ticker(resp) => codec.encode(resp),
+      Record::L2Update(resp) => codec.encode(resp),
+      Record::Trade { trade, .. } => codec.encode(trade),
+      Record::Depth(snapshot) => codec.encode(snapshot),
+      Record::Bar(candle) => codec.encode(candle),
+      Record::Quote(quote) => codec.encode(quote),
+      Record::TaqTrade(trade) => codec.encode(trade),
+      Record::Summary(summary) => codec.encode(summary),
+    }
+  }
+}
+
+pub struct WriterConfig {
+  /// Number of messages that can wait for the writer thread before new ones are dropped.
+  pub queue_capacity: usize,
+  /// How often buffered data is flushed to the files.
+  pub flush_interval: Duration,
+  /// Whether flushed data is also synced to disk.
+  pub fsync: bool,
+  /// Write-ahead log holding records until they are flushed, written again after a crash.
+  pub wal: Option<(PathBuf, u64)>,
+  /// Whether files are written per session and recorded in the directory's `Manifest`.
+  pub manifest: bool,
+  /// Limits checked after flushes, at most every 10 seconds.
+  pub disk: DiskLimits,
+}
+
+impl Default for WriterConfig {
+  fn default() -> Self {
+    WriterConfig {
+      queue_capacity: 100_000,
+      flush_interval: Duration::from_secs(1),
+      fsync: false,
+      wal: None,
+      manifest: false,
+      disk: DiskLimits::default(),
+    }
+  }
+}
+
+#[derive(Default, Debug)]
+pub struct WriterStats {
+  written: AtomicU64,
+  dropped: AtomicU64,
+  bytes: AtomicU64,
+  disk_full: AtomicBool,
+}
+
+impl WriterStats {
+  pub fn written(&self) -> u64 {
+    self.written.load(Ordering::Relaxed)
+  }
+
+  pub fn dropped(&self) -> u64 {
+    self.dropped.load(Ordering::Relaxed)
+  }
+
+  pub fn bytes_written(&self) -> u64 {
+    self.bytes.load(Ordering::Relaxed)
+  }
+
+  /// Whether recording stopped because of the disk limits, later messages are dropped.
+  pub fn disk_full(&self) -> bool {
+    self.disk_full.load(Ordering::Relaxed)
+  }
+}
+
+/// Owns the writer thread. The thread exits, after writing all queued messages,
+/// once every `WriteToFileVisitor` created from it is dropped.
+pub struct FileWriter {
+  sender: Sender<Record>,
+  stats: Arc<WriterStats>,
+  last_trade_ids: HashMap<String, i64>,
+  join_handle: JoinHandle<()>,
+}
+
+impl FileWriter {
+  /// Fails only when the write-ahead log or the manifest can't be opened, or the log records
+  /// recovered.
+  pub fn start(directory: PathBuf, config: WriterConfig) -> std::io::Result<Self> {
+    let mut last_trade_ids = HashMap::new();
+    let manifest = if config.manifest {
+      let mut manifest = Manifest::load(&directory)?;
+      last_trade_ids = manifest.last_trade_ids();
+      let session = manifest.begin_session();
+      manifest.save()?;
+      log::info!(target: FILE_WRITER_ID, "Started session {} of {}.", session, directory.to_string_lossy());
+      Some(manifest)
+    } else {
+      None
+    };
+    let mut files = Files { directory, codec: JsonCodec, writers: HashMap::new(), wal: None, last_seq: None, manifest };
+    if let Some((path, capacity)) = &config.wal {
+      let mut wal = WriteAheadLog::open(path, *capacity)?;
+      let recovered = wal.unacknowledged()?;
+      for record in &recovered {
+        if let Err(err) = files.write_line(&record.channel, &record.payload) {
+          log::error!(target: FILE_WRITER_ID, "Could not write record recovered from the write-ahead log: {:#}", err);
+        }
+      }
+      files.flush(true);
+      if let Some(last) = recovered.last() {
+        wal.acknowledge(last.seq)?;
+        log::info!(target: FILE_WRITER_ID, "Written {} records recovered from the write-ahead log.", recovered.len());
+      }
+      files.wal = Some(wal);
+    }
+
+    let (sender, receiver) = crossbeam::bounded(config.queue_capacity);
+    let stats = Arc::new(WriterStats::default());
+    let thread_stats = stats.clone();
+    let join_handle = thread::Builder::new()
+      .name(FILE_WRITER_ID.into())
+      .spawn(move || {
+        let mut last_flush = Instant::now();
+        let mut last_disk_check: Option<Instant> = None;
+        let mut last_dropped = 0;
+        // Failures are logged once per flush interval, and counted as dropped messages.
+        let mut failure_logged = false;
+        loop {
+          let timeout = config.flush_interval.checked_sub(last_flush.elapsed()).unwrap_or_default();
+          match receiver.recv_timeout(timeout) {
+            Ok(_) if thread_stats.disk_full() => {
+              thread_stats.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(record) => match files.write(&record) {
+              Ok(bytes) => {
+                thread_stats.written.fetch_add(1, Ordering::Relaxed);
+                thread_stats.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+              }
+              Err(err) => {
+                thread_stats.dropped.fetch_add(1, Ordering::Relaxed);
+                if !failure_logged {
+                  log::error!(target: FILE_WRITER_ID, "Could not write {}: {:#}", record.file_id(), err);
+                  failure_logged = true;
+                }
+              }
+            },
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+          }
+
+          if last_flush.elapsed() >= config.flush_interval {
+            files.flush(config.fsync);
+            last_flush = Instant::now();
+            failure_logged = false;
+            let dropped = thread_stats.dropped();
+            if dropped != last_dropped {
+              log::warn!(target: FILE_WRITER_ID, "Dropped {} messages since the last flush.", dropped - last_dropped);
+              last_dropped = dropped;
+            }
+            let disk_check_due = last_disk_check.is_none_or(|last_check| last_check.elapsed() >= DISK_CHECK_INTERVAL);
+            if config.disk.is_enabled() && !thread_stats.disk_full() && disk_check_due {
+              last_disk_check = Some(Instant::now());
+              match config.disk.enforce(&files.directory, files.manifest.as_mut()) {
+                Ok(true) => {}
+                Ok(false) => thread_stats.disk_full.store(true, Ordering::Relaxed),
+                Err(err) => log::error!(target: FILE_WRITER_ID, "Could not check the disk limits: {}", err),
+              }
+            }
+          }
+        }
+        files.flush(config.fsync);
+      })
+      .expect("Could not spawn file writer thread.");
+
+    Ok(FileWriter { sender, stats, last_trade_ids, join_handle })
+  }
+
+  pub fn visitor(&self) -> WriteToFileVisitor {
+    WriteToFileVisitor {
+      sender: self.sender.clone(),
+      stats: self.stats.clone(),
+      write_l2_updates: true,
+      blocking: false,
+      taq: false,
+    }
+  }
+
+  pub fn stats(&self) -> Arc<WriterStats> {
+    self.stats.clone()
+  }
+
+  /// Last trade of every product written in earlier sessions, empty without a manifest.
+  pub fn last_trade_ids(&self) -> &HashMap<String, i64> {
+    &self.last_trade_ids
+  }
+
+  /// Waits until all queued messages are written and files flushed.
+  /// Visitors must be dropped before, otherwise this blocks forever.
+  pub fn join(self) {
+    drop(self.sender);
+    if self.join_handle.join().is_err() {
+      log::error!(target: FILE_WRITER_ID, "File writer thread panicked.");
+    }
+  }
+}
+
+struct Files {
+  directory: PathBuf,
+  // Files are JSON lines, the format `verify`, `convert` and the Flight server read.
+  codec: JsonCodec,
+  writers: HashMap<String, BufWriter<File>>,
+  wal: Option<WriteAheadLog>,
+  // Last record appended to the log, acknowledged by the next flush.
+  last_seq: Option<u64>,
+  manifest: Option<Manifest>,
+}
+
+impl Files {
+  /// Returns number of bytes written.
+  fn write(&mut self, record: &Record) -> anyhow::Result<usize> {
+    let id = record.file_id();
+    let line = record.encode(&self.codec)?;
+    if let Some(wal) = &mut self.wal {
+      match wal.append(&id, "", &line) {
+        Ok(seq) => self.last_seq = Some(seq),
+        Err(err) => log::error!(target: FILE_WRITER_ID, "Could not append to the write-ahead log: {}", err),
+      }
+    }
+    let bytes = self.write_line(&id, &line)?;
+    if let Some(manifest) = &mut self.manifest {
+      let (product_id, trade_id, sequence, time) = record.progress();
+      manifest.track(product_id, trade_id, sequence, Some(time));
+    }
+    Ok(bytes)
+  }
+
+  fn write_line(&mut self, id: &str, line: &[u8]) -> anyhow::Result<usize> {
+    let file_name = match &self.manifest {
+      Some(manifest) => manifest.file_name(id),
+      None => id.to_string(),
+    };
+    let writer = match self.writers.entry(file_name.clone()) {
+      Entry::Occupied(entry) => entry.into_mut(),
+      Entry::Vacant(entry) => {
+        let file_path = self.directory.join(&file_name);
+        let file = OpenOptions::new()
+          .create(true)
+          .append(true)
+          .open(&file_path)
+          .with_context(|| format!("Could not open file {}", file_path.to_string_lossy()))?;
+        entry.insert(BufWriter::new(file))
+      }
+    };
+
+    writer.write_all(line).and_then(|_| writer.write_all(b"\n"))
+      .with_context(|| format!("Could not write to file {}", file_name))?;
+    // Only lines that made it into the buffer are counted.
+    if let Some(manifest) = &mut self.manifest {
+      manifest.record_line(&file_name, line);
+    }
+    Ok(line.len() + 1)
+  }
+
+  fn flush(&mut self, fsync: bool) {
+    let mut flushed = true;
+    for writer in self.writers.values_mut() {
+      if let Err(err) = writer.flush() {
+        log::error!(target: FILE_WRITER_ID, "Could not flush file: {}", err);
+        flushed = false;
+        continue;
+      }
+      if fsync {
+        if let Err(err) = writer.get_ref().sync_data() {
+          log::error!(target: FILE_WRITER_ID, "Could not sync file: {}", err);
+        }
+      }
+    }
+    if let (Some(manifest), true) = (&self.manifest, flushed) {
+      if let Err(err) = manifest.save() {
+        log::error!(target: FILE_WRITER_ID, "Could not save the manifest: {}", err);
+      }
+    }
+    // Records stay in the log until every file they went to is flushed.
+    if let (Some(wal), true) = (&mut self.wal, flushed) {
+      if let Some(seq) = self.last_seq.take() {
+        if let Err(err) = wal.acknowledge(seq) {
+          log::error!(target: FILE_WRITER_ID, "Could not acknowledge the write-ahead log: {}", err);
+        }
+      }
+    }
+  }
+}
+
+/// Handler that hands messages over to the writer thread. When the writer queue
+/// is full messages are dropped and counted instead of stalling the feed.
+#[derive(Clone)]
+pub struct WriteToFileVisitor {
+  sender: Sender<Record>,
+  stats: Arc<WriterStats>,
+  write_l2_updates: bool,
+  blocking: bool,
+  taq: bool,
+}
+
+impl WriteToFileVisitor {
+
+  /// Raw l2update messages can be skipped when only sampled depth snapshots are recorded.
+  pub fn write_l2_updates(mut self, enabled: bool) -> Self {
+    self.write_l2_updates = enabled;
+    self
+  }
+
+  /// Blocking visitor waits for space in the writer queue instead of dropping messages.
+  /// Useful for offline jobs like the backfill where nothing must be lost.
+  pub fn blocking(mut self, enabled: bool) -> Self {
+    self.blocking = enabled;
+    self
+  }
+
+  /// TAQ visitor writes trades as `TaqTrade`s into `taq_trades_` files and skips tickers and
+  /// level2 updates, quotes come through its `TopOfBookSink`.
+  pub fn taq(mut self, enabled: bool) -> Self {
+    self.taq = enabled;
+    self
+  }
+
+  pub fn write_trade(&mut self, trade: &Trade, product_id: &str) {
+    if self.taq {
+      self.send(Record::TaqTrade(TaqTrade::new(product_id, trade)));
+    } else {
+      self.send(Record::Trade { product_id: product_id.into(), trade: trade.clone() });
+    }
+  }
+
+  fn send(&self, record: Record) {
+    if self.blocking {
+      if self.sender.send(record).is_err() {
+        log::error!(target: FILE_WRITER_ID, "File writer thread is not running.");
+      }
+      return;
+    }
+    match self.sender.try_send(record) {
+      Ok(()) => {}
+      Err(TrySendError::Full(_)) => {
+        self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+      }
+      Err(TrySendError::Disconnected(_)) => {
+        log::error!(target: FILE_WRITER_ID, "File writer thread is not running.");
+      }
+    }
+  }
+}
+
+impl CoinBaseWebSocketMessageHandler for WriteToFileVisitor {
+  fn on_ticker(&mut self, resp: &response::TickerResponse) -> Result<(), Terminate> {
+    if !self.taq {
+      self.send(Record::Ticker(resp.clone()));
+    }
+    Ok(())
+  }
+
+  fn on_l2_update(&mut self, resp: &response::L2UpdateResponse) -> Result<(), Terminate> {
+    if self.write_l2_updates && !self.taq {
+      self.send(Record::L2Update(resp.clone()));
+    }
+    Ok(())
+  }
+
+  fn on_match(&mut self, resp: &response::MatchResponse) -> Result<(), Terminate> {
+    self.write_trade(&Trade::from(resp), resp.product_id.as_str());
+    Ok(())
+  }
+
+  fn on_backfilled_trade(&mut self, product_id: &str, trade: &Trade) -> Result<(), Terminate> {
+    self.write_trade(trade, product_id);
+    Ok(())
+  }
+}
+
+impl DepthSnapshotSink for WriteToFileVisitor {
+  fn on_depth_snapshot(&mut self, snapshot: &DepthSnapshot) -> Result<(), Terminate> {
+    self.send(Record::Depth(snapshot.clone()));
+    Ok(())
+  }
+}
+
+impl TopOfBookSink for WriteToFileVisitor {
+  fn on_top_of_book(&mut self, top: &TopOfBook) -> Result<(), Terminate> {
+    self.send(Record::Quote(TaqQuote::from(top)));
+    Ok(())
+  }
+}
+
+impl DailySummarySink for WriteToFileVisitor {
+  fn on_daily_summary(&mut self, summary: &DailySummary) -> Result<(), Terminate> {
+    self.send(Record::Summary(summary.clone()));
+    Ok(())
+  }
+}
+
+impl CandleSink for WriteToFileVisitor {
+  fn on_candle(&mut self, candle: &Candle) -> Result<(), Terminate> {
+    self.send(Record::Bar(candle.clone()));
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::fs;
+  use std::sync::Arc;
+  use std::sync::atomic::Ordering;
+  use std::time::Duration;
+
+  use coinbase::rest::Trade;
+
+  use super::{FileWriter, Record, WriteToFileVisitor, WriterConfig, WriterStats};
+
+  fn trade(trade_id: i64) -> Trade {
+    serde_json::from_str(&format!(
+      r#"{{"time":"2020-08-31T15:00:00Z","trade_id":{},"price":"100","size":"1","side":"buy"}}"#, trade_id
+    )).unwrap()
+  }
+
+  #[test]
+  fn count_written_records_and_bytes() {
+    let directory = std::env::temp_dir().join(format!("coinbase-writer-stats-{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let writer = FileWriter::start(directory.clone(), WriterConfig::default()).unwrap();
+    let stats = writer.stats();
+    let mut visitor = writer.visitor().blocking(true);
+    for trade_id in 1..=3 {
+      visitor.write_trade(&trade(trade_id), "BTC-USD");
+    }
+    drop(visitor);
+    writer.join();
+
+    let content = fs::read_to_string(directory.join("trades_BTC-USD")).unwrap();
+    let trade_ids: Vec<i64> = content.lines().map(|line| serde_json::from_str::<Trade>(line).unwrap().trade_id).collect();
+    assert_eq!(trade_ids, vec![1, 2, 3]);
+    assert_eq!((stats.written(), stats.dropped(), stats.bytes_written()), (3, 0, content.len() as u64));
+    fs::remove_dir_all(&directory).unwrap();
+  }
+
+  #[test]
+  fn drop_records_when_queue_is_full() {
+    let (sender, receiver) = crossbeam::bounded(1);
+    let stats = Arc::new(WriterStats::default());
+    let mut visitor = WriteToFileVisitor { sender, stats: stats.clone(), write_l2_updates: true, blocking: false, taq: false };
+    for trade_id in 1..=3 {
+      visitor.write_trade(&trade(trade_id), "BTC-USD");
+    }
+    assert_eq!(stats.dropped(), 2);
+    match receiver.try_recv() {
+      Ok(Record::Trade { trade, .. }) => assert_eq!(trade.trade_id, 1),
+      _ => panic!("Expected the first trade in the queue"),
+    }
+  }
+
+  #[test]
+  fn drop_records_once_disk_is_full() {
+    let directory = std::env::temp_dir().join(format!("coinbase-writer-disk-{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let writer = FileWriter::start(directory.clone(), WriterConfig::default()).unwrap();
+    let stats = writer.stats();
+    stats.disk_full.store(true, Ordering::Relaxed);
+    let mut visitor = writer.visitor().blocking(true);
+    visitor.write_trade(&trade(1), "BTC-USD");
+    drop(visitor);
+    writer.join();
+
+    assert_eq!((stats.written(), stats.dropped()), (0, 1));
+    assert!(!directory.join("trades_BTC-USD").exists());
+    fs::remove_dir_all(&directory).unwrap();
+  }
+
+  #[test]
+  fn keep_writing_after_failed_write() {
+    let directory = std::env::temp_dir().join(format!("coinbase-writer-failure-{}", std::process::id()));
+    // Directory in place of the file, which can't be opened then.
+    fs::create_dir_all(directory.join("trades_BTC-USD")).unwrap();
+    let config = WriterConfig { flush_interval: Duration::from_millis(10), ..WriterConfig::default() };
+    let writer = FileWriter::start(directory.clone(), config).unwrap();
+    let stats = writer.stats();
+    let mut visitor = writer.visitor().blocking(true);
+    visitor.write_trade(&trade(1), "BTC-USD");
+    visitor.write_trade(&trade(2), "ETH-USD");
+    drop(visitor);
+    writer.join();
+
+    assert_eq!((stats.written(), stats.dropped()), (1, 1));
+    assert_eq!(fs::read_to_string(directory.join("trades_ETH-USD")).unwrap().lines().count(), 1);
+    fs::remove_dir_all(&directory).unwrap();
+  }
+}